@@ -0,0 +1,87 @@
+//! RESP parsing and serialization on representative frames, without the
+//! network. Run with `cargo bench --bench resp`.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use rudis::RespValue;
+use std::hint::black_box;
+
+fn bulk(data: &[u8]) -> RespValue {
+    RespValue::BulkString(Some(Bytes::copy_from_slice(data)))
+}
+
+fn command(args: &[&[u8]]) -> RespValue {
+    RespValue::Array(Some(args.iter().map(|arg| bulk(arg)).collect()))
+}
+
+/// Frames a server typically reads or writes, by name
+fn frames() -> Vec<(&'static str, RespValue)> {
+    let value = vec![b'x'; 64];
+    let large = vec![b'x'; 16 * 1024];
+    let keys: Vec<String> = (0..100).map(|n| format!("key:{}", n)).collect();
+    let mget: Vec<&[u8]> = std::iter::once(&b"MGET"[..])
+        .chain(keys.iter().map(|key| key.as_bytes()))
+        .collect();
+    vec![
+        ("ping", command(&[b"PING"])),
+        ("get", command(&[b"GET", b"key:12345"])),
+        ("set_64b", command(&[b"SET", b"key:12345", &value])),
+        ("set_16kb", command(&[b"SET", b"key:12345", &large])),
+        ("mget_100", command(&mget)),
+        ("ok", RespValue::SimpleString("OK".to_string())),
+        ("integer", RespValue::Integer(1_234_567)),
+        ("bulk_64b", bulk(&value)),
+        (
+            "array_100x64b",
+            RespValue::Array(Some((0..100).map(|_| bulk(&value)).collect())),
+        ),
+    ]
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, frame) in frames() {
+        let wire = BytesMut::from(&frame.serialize()[..]);
+        group.throughput(Throughput::Bytes(wire.len() as u64));
+        // Parsing consumes the buffer, so each iteration gets a fresh copy
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || wire.clone(),
+                |mut buffer| RespValue::parse(black_box(&mut buffer)).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    // A frame arriving in two reads: the first parse finds it incomplete
+    let wire = command(&[b"SET", b"key:12345", &[b'x'; 64]]).serialize();
+    let half = BytesMut::from(&wire[..wire.len() / 2]);
+    group.throughput(Throughput::Bytes(half.len() as u64));
+    group.bench_function("incomplete", |b| {
+        b.iter_batched(
+            || half.clone(),
+            |mut buffer| RespValue::parse(black_box(&mut buffer)).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for (name, frame) in frames() {
+        let mut out = BytesMut::with_capacity(frame.serialize().len());
+        group.throughput(Throughput::Bytes(out.capacity() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                out.clear();
+                black_box(&frame).serialize_into(&mut out);
+                black_box(out.len())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, serialize);
+criterion_main!(benches);
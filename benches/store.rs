@@ -0,0 +1,92 @@
+//! Store operations in isolation, on each keyspace backend, without the
+//! network or command parsing. Run with `cargo bench --bench store`.
+
+use bytes::Bytes;
+use criterion::{Criterion, criterion_group, criterion_main};
+use rudis::{KeyspaceBackend, Store};
+use std::hint::black_box;
+use tokio::runtime::Runtime;
+
+/// Keys populated before each benchmark, `key:0` to `key:{KEYS - 1}`
+const KEYS: usize = 10_000;
+
+const SHARDS: usize = 16;
+
+fn backends() -> Vec<(&'static str, KeyspaceBackend)> {
+    vec![
+        ("sharded", KeyspaceBackend::Sharded),
+        #[cfg(feature = "dashmap")]
+        ("dashmap", KeyspaceBackend::DashMap),
+        ("owned", KeyspaceBackend::Owned),
+    ]
+}
+
+fn keys() -> Vec<Bytes> {
+    (0..KEYS)
+        .map(|n| Bytes::from(format!("key:{}", n)))
+        .collect()
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// A store on `backend` holding every key with a 64 byte value
+fn populated(runtime: &Runtime, backend: KeyspaceBackend, keys: &[Bytes]) -> Store {
+    let store = Store::with_backend(backend, SHARDS);
+    let value = Bytes::from(vec![b'x'; 64]);
+    runtime.block_on(async {
+        for key in keys {
+            store.set(key.clone(), value.clone()).await;
+        }
+    });
+    store
+}
+
+fn store(c: &mut Criterion) {
+    let runtime = runtime();
+    let keys = keys();
+    let missing = Bytes::from_static(b"missing");
+    let value = Bytes::from(vec![b'y'; 64]);
+
+    for (name, backend) in backends() {
+        let store = populated(&runtime, backend, &keys);
+        let mut group = c.benchmark_group(format!("store/{}", name));
+
+        // Walk the keyspace so every shard is hit, as real traffic would
+        let mut n = 0;
+        let mut next_key = move || {
+            n = (n + 1) % KEYS;
+            n
+        };
+
+        group.bench_function("get_hit", |b| {
+            b.to_async(&runtime)
+                .iter(|| store.get(black_box(&keys[next_key()])))
+        });
+        group.bench_function("get_miss", |b| {
+            b.to_async(&runtime).iter(|| store.get(black_box(&missing)))
+        });
+        group.bench_function("set", |b| {
+            b.to_async(&runtime)
+                .iter(|| store.set(keys[next_key()].clone(), value.clone()))
+        });
+        group.bench_function("incr", |b| {
+            b.to_async(&runtime)
+                .iter(|| async { store.incr(black_box(b"counter")).await.unwrap() })
+        });
+        group.bench_function("mget_10", |b| {
+            b.to_async(&runtime).iter(|| {
+                let start = next_key() % (KEYS - 10);
+                store.mget(black_box(&keys[start..start + 10]))
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, store);
+criterion_main!(benches);
@@ -0,0 +1,149 @@
+//! The ACL file: tenants, the server's users, kept on disk. With `aclfile`
+//! set, the server reads its users from the file at startup, in place of
+//! the `tenant` directives once the file exists, `ACL SAVE` writes the
+//! users it has to the file, and `ACL LOAD` reads them back in, replacing
+//! them all; so users are managed by editing the file and loading it,
+//! without a restart.
+//!
+//! Each line is a `tenant` directive as the config file takes it, with the
+//! password stored as `#` and its hex SHA-256, as Redis keeps passwords in
+//! its ACL file; `ACL SAVE` never writes one in the clear. Blank lines and
+//! lines starting with `#` are skipped.
+
+use crate::config::{parse_tenant, split_config_line};
+use crate::tenant::TenantSpec;
+use anyhow::{Result, anyhow};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Read the users in the ACL file at `path`
+pub fn load(path: &Path) -> Result<Vec<TenantSpec>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+    parse(&contents).map_err(|e| anyhow!("'{}' {}", path.display(), e))
+}
+
+/// Parse the users in an ACL file; a user defined again replaces the first
+pub fn parse(contents: &str) -> Result<Vec<TenantSpec>> {
+    let mut tenants: Vec<TenantSpec> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tenant = split_config_line(line)
+            .ok_or_else(|| anyhow!("unbalanced quotes"))
+            .and_then(|words| match words.as_slice() {
+                [directive, name, password, prefix, limits @ ..] if directive == "tenant" => {
+                    parse_tenant(name, password, prefix, limits)
+                }
+                _ => Err(anyhow!("expected 'tenant <name> <password> <prefix>'")),
+            })
+            .map_err(|e| anyhow!("line {}: {}", index + 1, e))?;
+        tenants.retain(|existing| existing.name != tenant.name);
+        tenants.push(tenant);
+    }
+    Ok(tenants)
+}
+
+/// Write `tenants` to the ACL file at `path`, by way of a temporary file so
+/// a crash mid-write leaves the old one in place
+pub fn save(path: &Path, tenants: &[TenantSpec]) -> Result<()> {
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, render(tenants))
+        .and_then(|()| std::fs::rename(&partial, path))
+        .map_err(|e| anyhow!("Can't write '{}': {}", path.display(), e))
+}
+
+fn render(tenants: &[TenantSpec]) -> String {
+    let mut file = String::new();
+    for tenant in tenants {
+        let _ = write!(
+            file,
+            "tenant {} {} {}",
+            quote(&tenant.name),
+            tenant.hashed_password(),
+            quote(&tenant.prefix)
+        );
+        if let Some(keys) = tenant.max_keys {
+            let _ = write!(file, " max-keys {}", keys);
+        }
+        if let Some(bytes) = tenant.max_memory {
+            let _ = write!(file, " max-memory {}", bytes);
+        }
+        if let Some(ops) = tenant.max_ops {
+            let _ = write!(file, " max-ops {}", ops);
+        }
+        file.push('\n');
+    }
+    file
+}
+
+/// `word` in double quotes, as the config parser reads it back
+fn quote(word: &str) -> String {
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::hash_password;
+
+    fn tenant(name: &str, password: &str, prefix: &str) -> TenantSpec {
+        TenantSpec {
+            name: name.to_string(),
+            password: password.to_string(),
+            prefix: prefix.to_string(),
+            max_keys: None,
+            max_memory: None,
+            max_ops: None,
+        }
+    }
+
+    #[test]
+    fn saves_hashed_and_loads_back() {
+        let mut billing = tenant("billing", "s3cret", "billing:");
+        billing.max_keys = Some(1000);
+        billing.max_memory = Some(64 * 1024 * 1024);
+        let odd = tenant("odd \"one\"", "pw", "");
+        let path = std::env::temp_dir().join(format!("rudis-acl-{}.acl", std::process::id()));
+        save(&path, &[billing.clone(), odd.clone()]).unwrap();
+
+        let file = std::fs::read_to_string(&path).unwrap();
+        assert!(!file.contains("s3cret"), "{}", file);
+        assert!(file.contains(&hash_password("s3cret")), "{}", file);
+        let loaded = load(&path).unwrap();
+        assert_eq!(
+            loaded,
+            [
+                TenantSpec {
+                    password: hash_password("s3cret"),
+                    ..billing
+                },
+                TenantSpec {
+                    password: hash_password("pw"),
+                    ..odd
+                },
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(load(&path).is_err());
+    }
+
+    #[test]
+    fn parses_files_written_by_hand() {
+        let tenants = parse(
+            "# Teams\n\n\
+             tenant search hunter2 search: max-ops 50\n\
+             tenant search other search:\n",
+        )
+        .unwrap();
+        assert_eq!(tenants, [tenant("search", "other", "search:")]);
+
+        let error = |contents| parse(contents).unwrap_err().to_string();
+        assert!(error("tenant a pw a:\nport 6379").starts_with("line 2:"));
+        assert!(error("tenant a pw").contains("expected"));
+        assert!(error("tenant a pw a: max-keys").contains("needs a value"));
+        assert!(error("tenant \"a pw a:").contains("unbalanced quotes"));
+    }
+}
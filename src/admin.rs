@@ -0,0 +1,204 @@
+//! Read-only admin API over HTTP, for dashboards and scripts that don't
+//! speak RESP. With `admin-port` set, `GET /info`, `/clients` and `/config`
+//! return JSON views of INFO, the connected clients and the effective
+//! config. Nothing can be changed through it.
+
+use crate::command::Command;
+use crate::http::{self, Request, Response, json_string};
+use crate::log::notice;
+use crate::resp::RespValue;
+use crate::server::Context;
+use anyhow::{Result, anyhow};
+use std::fmt::Write as _;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Start serving the admin API on the current runtime, if `admin-port` is set
+pub async fn spawn(context: &Context) -> Result<Option<JoinHandle<()>>> {
+    let Some(addr) = context.config.admin_addr() else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|e| anyhow!("Can't bind admin listener on {}: {}", addr, e))?;
+    notice!("Serving the admin API on http://{}", listener.local_addr()?);
+
+    let context = context.clone();
+    let handle = move |request: Request| {
+        let context = context.clone();
+        async move { respond(request, &context).await }
+    };
+    Ok(Some(tokio::spawn(http::serve(listener, handle))))
+}
+
+async fn respond(request: Request, context: &Context) -> Response {
+    match request.path.as_str() {
+        "/info" => {
+            // Sections as INFO takes them, e.g. ?section=memory,stats
+            let sections = request
+                .param("section")
+                .map(|sections| sections.split(',').map(str::to_lowercase).collect())
+                .unwrap_or_default();
+            match Command::Info(sections).execute(&context.store).await {
+                RespValue::BulkString(Some(info)) => {
+                    (200, info_json(&String::from_utf8_lossy(&info)))
+                }
+                _ => (500, r#"{"error":"INFO failed"}"#.to_string()),
+            }
+        }
+        "/clients" => (200, clients_json(context)),
+        "/config" => (200, config_json(context)),
+        _ => http::not_found(),
+    }
+}
+
+/// INFO's `# Section` headers and `field:value` lines as an object of
+/// objects, with numeric values as JSON numbers
+fn info_json(info: &str) -> String {
+    let mut json = String::from("{");
+    let mut first_field = true;
+    for line in info.split("\r\n") {
+        if let Some(title) = line.strip_prefix("# ") {
+            if json.len() > 1 {
+                json.push_str("},");
+            }
+            let _ = write!(json, "{}:{{", json_string(&title.to_lowercase()));
+            first_field = true;
+        } else if let Some((field, value)) = line.split_once(':') {
+            if !first_field {
+                json.push(',');
+            }
+            first_field = false;
+            let numeric = !value.is_empty()
+                && value.parse::<f64>().is_ok()
+                && value
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || b == b'.' || b == b'-');
+            let value = if numeric {
+                value.to_string()
+            } else {
+                json_string(value)
+            };
+            let _ = write!(json, "{}:{}", json_string(field), value);
+        }
+    }
+    if json.len() > 1 {
+        json.push('}');
+    }
+    json.push('}');
+    json
+}
+
+fn clients_json(context: &Context) -> String {
+    let clients: Vec<String> = context
+        .client_list()
+        .iter()
+        .map(|client| {
+            format!(
+                r#"{{"id":{},"addr":{},"age_seconds":{}}}"#,
+                client.id,
+                json_string(&client.addr.to_string()),
+                client.since.elapsed().as_secs()
+            )
+        })
+        .collect();
+    format!("[{}]", clients.join(","))
+}
+
+fn config_json(context: &Context) -> String {
+    let directives: Vec<String> = context
+        .config
+        .directives()
+        .iter()
+        .map(|(directive, value)| format!("{}:{}", json_string(directive), json_string(value)))
+        .collect();
+    format!("{{{}}}", directives.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: rudis\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn converts_info() {
+        let info = "# Memory\r\nkeyspace_keys:3\r\nkeyspace_fragmentation_ratio:1.50\r\n\r\n\
+                    # Commandstats\r\ncmdstat_get:calls=2,usec=10\r\n";
+        assert_eq!(
+            info_json(info),
+            r#"{"memory":{"keyspace_keys":3,"keyspace_fragmentation_ratio":1.50},"commandstats":{"cmdstat_get":"calls=2,usec=10"}}"#
+        );
+        assert_eq!(info_json(""), "{}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn serves_json_views() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = Server::builder()
+            .port(0)
+            .config(|config| config.admin_port = port)
+            .bind()
+            .await
+            .unwrap();
+        server.store().set(Bytes::from("k"), Bytes::from("v")).await;
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+        let admin = format!("127.0.0.1:{}", port);
+
+        // The admin API starts with the server
+        let mut client = None;
+        for _ in 0..100 {
+            if TcpStream::connect(&admin).await.is_ok() {
+                client = Some(TcpStream::connect(addr).await.unwrap());
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut client = client.unwrap();
+        client.write_all(b"PING\r\n").await.unwrap();
+        let mut pong = [0; 7];
+        client.read_exact(&mut pong).await.unwrap();
+
+        let info = get(&admin, "/info?section=memory").await;
+        assert!(info.starts_with("HTTP/1.1 200 OK\r\n"), "{}", info);
+        assert!(info.ends_with("}}"), "{}", info);
+        assert!(
+            info.contains(r#"{"memory":{"keyspace_keys":1,"#),
+            "{}",
+            info
+        );
+
+        let clients = get(&admin, "/clients").await;
+        let local = client.local_addr().unwrap().to_string();
+        assert!(
+            clients.contains(&format!(r#""addr":"{}","#, local)),
+            "{}",
+            clients
+        );
+
+        let config = get(&admin, "/config").await;
+        assert!(
+            config.contains(&format!(r#""admin-port":"{}""#, port)),
+            "{}",
+            config
+        );
+        assert!(get(&admin, "/keys").await.starts_with("HTTP/1.1 404 "));
+    }
+}
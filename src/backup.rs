@@ -0,0 +1,701 @@
+//! Scheduled backups. With `backup-schedule` set to a cron expression, the
+//! server takes a snapshot of the keyspace at each time it names, as
+//! BGSAVE does on demand, and writes it to `dir` as
+//! `rudis-backup-<UTC time>.snapshot`, in the format `Store::export` gives.
+//! After each backup, old ones are pruned: the newest backup of each of the
+//! last `backup-keep-daily` days and of each of the last
+//! `backup-keep-weekly` weeks are kept, along with the newest overall.
+//!
+//! Schedules are read in UTC, with cron's five fields (minute, hour, day of
+//! month, month, day of week from 0 for Sunday), each `*`, a number, a
+//! range `a-b` or a comma-separated list of them, any of which may take a
+//! `/step`; or one of `@hourly`, `@daily`, `@weekly` and `@monthly`.
+//!
+//! With `encryption-key` set, backups are written encrypted; read one back
+//! with `encryption::decrypt` before `Snapshot::from_bytes`.
+
+use crate::encryption::{self, EncryptionKey};
+use crate::log::{notice, warning};
+use crate::store::Store;
+use anyhow::{Result, anyhow, bail};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+const PREFIX: &str = "rudis-backup-";
+const SUFFIX: &str = ".snapshot";
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// A cron schedule; each field is a bit set of the values it matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// As written, for CONFIG GET and INFO
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month and day of week fields are `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let expanded = match text {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            text => text,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            bail!(
+                "Invalid schedule '{}', expected minute, hour, day of month, month and day of week",
+                text
+            );
+        };
+        let field = |field: &str, min, max| {
+            parse_field(field, min, max).map_err(|e| anyhow!("Invalid schedule '{}': {}", text, e))
+        };
+        let mut weekdays_set = field(weekdays, 0, 7)?;
+        // 7 is Sunday too
+        if weekdays_set & (1 << 7) != 0 {
+            weekdays_set = (weekdays_set | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            text: text.to_string(),
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekdays_set,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Schedule {
+    /// Whether a backup is due in the minute starting at `time`
+    fn matches(&self, time: &Civil) -> bool {
+        let has = |set: u64, value: u32| set & (1 << value) != 0;
+        // Like cron, when both day fields are restricted either may match
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => has(self.days, time.day) || has(self.weekdays, time.weekday),
+            _ => has(self.days, time.day) && has(self.weekdays, time.weekday),
+        };
+        day && has(self.minutes, time.minute)
+            && has(self.hours, time.hour)
+            && has(self.months, time.month)
+    }
+}
+
+/// One cron field: `*`, `n` or `a-b`, each with an optional `/step`, or a
+/// comma-separated list of them
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut set = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| anyhow!("invalid step '{}'", step))?;
+                (range, step)
+            }
+            None => (item, 1),
+        };
+        let value = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| anyhow!("'{}' is not from {} to {}", value, min, max))
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // A single value with a step runs to the end, as in cron
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            bail!("range '{}' runs backwards", range);
+        }
+        for value in (first..=last).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// A UTC date and time, as schedules and backup names read it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Civil {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    /// From 0 for Sunday
+    weekday: u32,
+}
+
+impl Civil {
+    fn from_unix(secs: u64) -> Self {
+        let days = (secs / SECS_PER_DAY) as i64;
+        let time = secs % SECS_PER_DAY;
+        // Howard Hinnant's civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        Self {
+            year: yoe + era * 400 + i64::from(month <= 2),
+            month,
+            day,
+            hour: (time / 3600) as u32,
+            minute: (time / 60 % 60) as u32,
+            second: (time % 60) as u32,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4) % 7) as u32,
+        }
+    }
+
+    fn to_unix(self) -> u64 {
+        // Howard Hinnant's days_from_civil
+        let year = self.year - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let month = i64::from(self.month);
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+            + i64::from(self.day)
+            - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        days.max(0) as u64 * SECS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+/// File name of a backup taken at `secs` since the Unix epoch
+fn backup_name(secs: u64) -> String {
+    let t = Civil::from_unix(secs);
+    format!(
+        "{}{:04}{:02}{:02}T{:02}{:02}{:02}Z{}",
+        PREFIX, t.year, t.month, t.day, t.hour, t.minute, t.second, SUFFIX
+    )
+}
+
+/// When the backup named `name` was taken, if it is one
+fn backup_time(name: &str) -> Option<u64> {
+    let stamp = name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+    let (date, time) = stamp.strip_suffix('Z')?.split_once('T')?;
+    if date.len() != 8 || time.len() != 6 {
+        return None;
+    }
+    let number = |digits: &str| digits.parse::<u32>().ok();
+    let civil = Civil {
+        year: i64::from(number(&date[..4])?),
+        month: number(&date[4..6])?,
+        day: number(&date[6..])?,
+        hour: number(&time[..2])?,
+        minute: number(&time[2..4])?,
+        second: number(&time[4..])?,
+        weekday: 0,
+    };
+    Some(civil.to_unix())
+}
+
+/// How many old backups to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Days to keep the day's newest backup for
+    pub daily: usize,
+    /// Weeks, from Monday, to keep the week's newest backup for
+    pub weekly: usize,
+}
+
+impl Retention {
+    /// Which backups taken at `times`, newest first, to keep. With both
+    /// counts 0 every backup is kept.
+    fn kept(&self, times: &[u64]) -> Vec<bool> {
+        if self.daily == 0 && self.weekly == 0 {
+            return vec![true; times.len()];
+        }
+        let mut kept = vec![false; times.len()];
+        if let Some(newest) = kept.first_mut() {
+            *newest = true;
+        }
+        let day = |secs: u64| secs / SECS_PER_DAY;
+        // 1970-01-05 was the first Monday
+        let week = |secs: u64| (secs / SECS_PER_DAY + 3) / 7;
+        for (count, period) in [
+            (self.daily, &day as &dyn Fn(u64) -> u64),
+            (self.weekly, &week),
+        ] {
+            let mut periods = Vec::new();
+            for (index, time) in times.iter().enumerate() {
+                if periods.len() == count {
+                    break;
+                }
+                if periods.last() != Some(&period(*time)) {
+                    periods.push(period(*time));
+                    kept[index] = true;
+                }
+            }
+        }
+        kept
+    }
+}
+
+/// How backups have gone, for INFO persistence
+#[derive(Debug)]
+pub struct BackupStatus {
+    schedule: Mutex<Option<String>>,
+    in_progress: AtomicBool,
+    saves: AtomicU64,
+    /// When the last backup finished, in seconds since the Unix epoch; 0 if
+    /// none has
+    last_save: AtomicU64,
+    last_failed: AtomicBool,
+    /// How long the last backup took, in seconds; -1 if none has been taken
+    last_duration: AtomicI64,
+    last_file: Mutex<Option<PathBuf>>,
+    pruned: AtomicU64,
+}
+
+impl Default for BackupStatus {
+    fn default() -> Self {
+        Self {
+            schedule: Mutex::default(),
+            in_progress: AtomicBool::new(false),
+            saves: AtomicU64::new(0),
+            last_save: AtomicU64::new(0),
+            last_failed: AtomicBool::new(false),
+            last_duration: AtomicI64::new(-1),
+            last_file: Mutex::default(),
+            pruned: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BackupStatus {
+    /// Whether a backup is being taken
+    pub fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::Relaxed)
+    }
+
+    /// Backups taken since startup
+    pub fn saves(&self) -> u64 {
+        self.saves.load(Ordering::Relaxed)
+    }
+
+    /// INFO persistence fields, named like Redis' where they mean the same
+    pub fn info(&self) -> String {
+        let path = |path: &Option<PathBuf>| {
+            path.as_deref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        };
+        format!(
+            "rdb_bgsave_in_progress:{}\r\n\
+             rdb_saves:{}\r\n\
+             rdb_last_save_time:{}\r\n\
+             rdb_last_bgsave_status:{}\r\n\
+             rdb_last_bgsave_time_sec:{}\r\n\
+             backup_schedule:{}\r\n\
+             backup_last_file:{}\r\n\
+             backups_pruned:{}\r\n",
+            u8::from(self.in_progress()),
+            self.saves(),
+            self.last_save.load(Ordering::Relaxed),
+            if self.last_failed.load(Ordering::Relaxed) {
+                "err"
+            } else {
+                "ok"
+            },
+            self.last_duration.load(Ordering::Relaxed),
+            self.schedule.lock().unwrap().as_deref().unwrap_or_default(),
+            path(&self.last_file.lock().unwrap()),
+            self.pruned.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Takes backups of a store into a directory, on a schedule or on demand
+#[derive(Debug)]
+pub struct Backups {
+    store: Store,
+    status: BackupStatus,
+    dir: PathBuf,
+    schedule: Option<Schedule>,
+    retention: Retention,
+    encryption_key: Option<EncryptionKey>,
+}
+
+impl Backups {
+    pub fn new(
+        store: Store,
+        dir: PathBuf,
+        schedule: Option<Schedule>,
+        retention: Retention,
+        encryption_key: Option<EncryptionKey>,
+    ) -> Self {
+        let status = BackupStatus::default();
+        *status.schedule.lock().unwrap() = schedule.as_ref().map(Schedule::to_string);
+        Self {
+            store,
+            status,
+            dir,
+            schedule,
+            retention,
+            encryption_key,
+        }
+    }
+
+    /// Start the background task taking backups on the schedule, if there is
+    /// one
+    pub fn start(backups: Arc<Self>) -> Option<JoinHandle<()>> {
+        let schedule = backups.schedule.clone()?;
+        Some(tokio::spawn(async move {
+            // The minute under way when the server started isn't due
+            let mut last = unix_now().as_secs() / 60;
+            loop {
+                let now = unix_now();
+                let next = Duration::from_secs((now.as_secs() / 60 + 1) * 60);
+                tokio::time::sleep(next.saturating_sub(now)).await;
+                // Sleeps can end early, so only count a minute once
+                let minute = unix_now().as_secs() / 60;
+                if minute == last {
+                    continue;
+                }
+                last = minute;
+                if schedule.matches(&Civil::from_unix(minute * 60)) && backups.claim() {
+                    backups.take().await;
+                }
+            }
+        }))
+    }
+
+    /// Mark a backup as under way, unless one already is
+    pub fn claim(&self) -> bool {
+        !self.status().in_progress.swap(true, Ordering::Relaxed)
+    }
+
+    /// Backups taken by BGSAVE and `backup-schedule`, and how they went
+    pub fn status(&self) -> &BackupStatus {
+        &self.status
+    }
+
+    /// Take a backup now, as SHUTDOWN SAVE does, once any backup under way
+    /// is done. Returns whether it was written.
+    pub async fn take_now(&self) -> bool {
+        while !self.claim() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.take().await
+    }
+
+    /// Take a backup claimed with `claim`, then prune old ones. Returns
+    /// whether it was written.
+    pub async fn take(&self) -> bool {
+        let timer = Instant::now();
+        let result = self.save().await;
+        let status = self.status();
+        let saved = result.is_ok();
+        status.last_failed.store(!saved, Ordering::Relaxed);
+        status
+            .last_duration
+            .store(timer.elapsed().as_secs() as i64, Ordering::Relaxed);
+        match result {
+            Ok((path, keys)) => {
+                notice!("Backed up {} keys to {}", keys, path.display());
+                status.saves.fetch_add(1, Ordering::Relaxed);
+                status
+                    .last_save
+                    .store(unix_now().as_secs(), Ordering::Relaxed);
+                *status.last_file.lock().unwrap() = Some(path);
+                match prune(&self.dir, self.retention) {
+                    Ok(pruned) => {
+                        status.pruned.fetch_add(pruned, Ordering::Relaxed);
+                    }
+                    Err(e) => warning!("Can't prune old backups: {}", e),
+                }
+            }
+            Err(e) => warning!("Backup failed: {}", e),
+        }
+        status.in_progress.store(false, Ordering::Relaxed);
+        saved
+    }
+
+    /// Write a snapshot of the store, returning where and how many keys
+    async fn save(&self) -> Result<(PathBuf, usize)> {
+        tokio::time::sleep(self.store.chaos().save_delay()).await;
+        let snapshot = self.store.export().await;
+        let keys = snapshot.entries.len();
+        let path = self.dir.join(backup_name(unix_now().as_secs()));
+        let written = path.clone();
+        let key = self.encryption_key.clone();
+        tokio::task::spawn_blocking(move || {
+            let data = snapshot.to_bytes();
+            let data = match &key {
+                Some(key) => encryption::encrypt(key, &data).into(),
+                None => data,
+            };
+            write_atomically(&written, &data)
+        })
+        .await??;
+        Ok((path, keys))
+    }
+}
+
+/// Write `data` to `path` by way of a temporary file, so a crash mid-write
+/// never leaves a partial backup under a backup's name
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let partial = path.with_extension("snapshot.partial");
+    std::fs::write(&partial, data)
+        .and_then(|()| std::fs::rename(&partial, path))
+        .map_err(|e| anyhow!("Can't write '{}': {}", path.display(), e))
+}
+
+/// Delete the backups in `dir` that `retention` doesn't keep, returning how
+/// many were deleted
+fn prune(dir: &Path, retention: Retention) -> Result<u64> {
+    let mut backups: Vec<(u64, PathBuf)> = std::fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let time = backup_time(entry.file_name().to_str()?)?;
+            Some((time, entry.path()))
+        })
+        .collect();
+    backups.sort_by(|a, b| b.cmp(a));
+    let times: Vec<u64> = backups.iter().map(|(time, _)| *time).collect();
+    let mut pruned = 0;
+    for ((_, path), kept) in backups.iter().zip(retention.kept(&times)) {
+        if !kept {
+            std::fs::remove_file(path)?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::Snapshot;
+    use bytes::Bytes;
+
+    /// 2026-10-18 03:00:00 UTC, a Sunday
+    const SUNDAY_3AM: u64 = 1_792_292_400;
+
+    #[test]
+    fn converts_unix_time_to_civil_and_back() {
+        let sunday = Civil::from_unix(SUNDAY_3AM);
+        assert_eq!(
+            sunday,
+            Civil {
+                year: 2026,
+                month: 10,
+                day: 18,
+                hour: 3,
+                minute: 0,
+                second: 0,
+                weekday: 0,
+            }
+        );
+        for secs in [0, 951_782_400, SUNDAY_3AM + 59, 4_107_542_399] {
+            assert_eq!(Civil::from_unix(secs).to_unix(), secs);
+        }
+        // 2000-02-29, a leap day
+        let leap = Civil::from_unix(951_782_400);
+        assert_eq!((leap.year, leap.month, leap.day), (2000, 2, 29));
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let nightly: Schedule = "30 3 * * *".parse().unwrap();
+        assert!(nightly.matches(&Civil::from_unix(SUNDAY_3AM + 30 * 60)));
+        assert!(!nightly.matches(&Civil::from_unix(SUNDAY_3AM)));
+
+        let quarter_hours: Schedule = "*/15 * * * *".parse().unwrap();
+        assert_eq!(quarter_hours.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        let weekdays: Schedule = "0 3 * * 1-5".parse().unwrap();
+        assert!(!weekdays.matches(&Civil::from_unix(SUNDAY_3AM)));
+        assert!(weekdays.matches(&Civil::from_unix(SUNDAY_3AM + SECS_PER_DAY)));
+        let sundays: Schedule = "0 3 * * 7".parse().unwrap();
+        assert!(sundays.matches(&Civil::from_unix(SUNDAY_3AM)));
+        // With both days restricted, either matches: the 1st, or a Sunday
+        let either: Schedule = "0 3 1 * 0".parse().unwrap();
+        assert!(either.matches(&Civil::from_unix(SUNDAY_3AM)));
+        assert_eq!(
+            "@weekly".parse::<Schedule>().unwrap().to_string(),
+            "@weekly"
+        );
+        assert!("@weekly".parse::<Schedule>().unwrap().weekdays == 1);
+
+        for invalid in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "x * * * *",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn names_backups_by_time() {
+        let name = backup_name(SUNDAY_3AM + 7);
+        assert_eq!(name, "rudis-backup-20261018T030007Z.snapshot");
+        assert_eq!(backup_time(&name), Some(SUNDAY_3AM + 7));
+        assert_eq!(backup_time("rudis-backup-2026.snapshot"), None);
+        assert_eq!(backup_time("dump.rdb"), None);
+    }
+
+    #[test]
+    fn keeps_the_newest_backup_of_recent_days_and_weeks() {
+        let hour = 3600;
+        let day = SECS_PER_DAY;
+        // Newest first: two today, one a day for the week before, then one
+        // three and five weeks back
+        let mut times = vec![SUNDAY_3AM + hour, SUNDAY_3AM];
+        times.extend((1..=7).map(|days| SUNDAY_3AM - days * day));
+        times.extend([SUNDAY_3AM - 21 * day, SUNDAY_3AM - 35 * day]);
+
+        let kept = Retention {
+            daily: 3,
+            weekly: 2,
+        }
+        .kept(&times);
+        let kept: Vec<u64> = times
+            .iter()
+            .zip(kept)
+            .filter(|(_, kept)| *kept)
+            .map(|(time, _)| (SUNDAY_3AM + hour - time) / hour)
+            .collect();
+        // Hours ago: the newest of today, yesterday and the day before, and
+        // the newest of last week, as Sunday ends a week
+        assert_eq!(kept, [0, 25, 49, 169]);
+
+        // Weeks without a backup don't count
+        let kept = Retention {
+            daily: 1,
+            weekly: 3,
+        }
+        .kept(&times);
+        assert_eq!(kept.iter().filter(|kept| **kept).count(), 3);
+        assert!(kept[0] && kept[8] && kept[9]);
+
+        let keep_all = Retention {
+            daily: 0,
+            weekly: 0,
+        };
+        assert!(keep_all.kept(&times).iter().all(|kept| *kept));
+        assert!(
+            Retention {
+                daily: 1,
+                weekly: 0
+            }
+            .kept(&[])
+            .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn takes_and_prunes_backups() {
+        let dir = std::env::temp_dir().join(format!("rudis-backups-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let old = dir.join(backup_name(SUNDAY_3AM - 30 * SECS_PER_DAY));
+        std::fs::write(&old, b"").unwrap();
+        let unrelated = dir.join("dump.rdb");
+        std::fs::write(&unrelated, b"").unwrap();
+
+        let store = Store::new();
+        store.set(Bytes::from("k"), Bytes::from("v")).await;
+        let retention = Retention {
+            daily: 1,
+            weekly: 0,
+        };
+        let backups = Backups::new(store.clone(), dir.clone(), None, retention, None);
+        assert!(backups.claim());
+        assert!(!backups.claim(), "one backup at a time");
+        backups.take().await;
+
+        let status = backups.status();
+        assert!(!status.in_progress());
+        assert_eq!(status.saves(), 1);
+        let info = status.info();
+        assert!(info.contains("rdb_last_bgsave_status:ok\r\n"), "{}", info);
+        assert!(info.contains("backups_pruned:1\r\n"), "{}", info);
+        let file = status.last_file.lock().unwrap().clone().unwrap();
+        let snapshot = Snapshot::from_bytes(&std::fs::read(&file).unwrap()).unwrap();
+        assert_eq!(snapshot.entries.len(), 1);
+        assert!(!old.exists());
+        assert!(unrelated.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypts_backups() {
+        let dir = std::env::temp_dir().join(format!("rudis-backups-enc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = Store::new();
+        store.set(Bytes::from("secret"), Bytes::from("v")).await;
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let retention = Retention {
+            daily: 1,
+            weekly: 0,
+        };
+        let backups = Backups::new(
+            store.clone(),
+            dir.clone(),
+            None,
+            retention,
+            Some(key.clone()),
+        );
+        assert!(backups.claim());
+        backups.take().await;
+
+        let file = backups.status().last_file.lock().unwrap().clone().unwrap();
+        let data = std::fs::read(&file).unwrap();
+        assert!(encryption::is_encrypted(&data));
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+        let snapshot = Snapshot::from_bytes(&encryption::decrypt(&key, &data).unwrap()).unwrap();
+        assert_eq!(snapshot.entries.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,598 @@
+//! rudis-benchmark: drives any RESP server, rudis or Redis, with a mix of
+//! commands from many connections for a fixed time, then reports throughput
+//! and latency per command. With `--compare` it runs the same workload
+//! against a second, reference server and reports the two side by side.
+//! With `--replay` it re-drives the server from a recording instead, and
+//! with `--soak` it runs checked traffic for hours.
+
+mod replay;
+mod report;
+mod soak;
+mod workload;
+
+use anyhow::{Result, anyhow, bail};
+use report::Report;
+use rudis::RespValue;
+use rudis::client::{Client, Pipeline};
+use rudis::config::parse_memory;
+use rudis::encryption::EncryptionKey;
+use rudis::record::Recording;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use workload::{KeyDist, Mix, Rng, ValueSize, Workload};
+
+const USAGE: &str = "\
+Usage: rudis-benchmark [OPTIONS]
+  -h <hostname>       Server hostname (default: 127.0.0.1)
+  -p <port>           Server port (default: 6379)
+  -c <clients>        Parallel connections (default: 50)
+  -P <depth>          Commands each connection writes before reading their
+                      replies (default: 1, no pipelining)
+  --threads <n>       Client runtime threads (default: one per core)
+  --duration <secs>   How long to run, fractions allowed (default: 10)
+  --keys <n>          Size of the keyspace, key:0 to key:n-1 (default: 100000)
+  --key-dist <dist>   How keys are picked: uniform, zipf[:<s>] with 0 < s < 1
+                      (default s: 0.99), or hot:<% of keys>:<% of traffic>
+                      (default: uniform)
+  --value-size <size> SET value size in bytes: a size, a range like 16-4096,
+                      or weighted sizes like 64=9,4096=1 (default: 64)
+  --mix <spec>        Commands and weights, from ping, get, set, incr, mget
+                      and del (default: get=1,set=1)
+  --no-populate       Don't SET every key before a run that reads keys
+  --csv <path>        Also append the results to this CSV file
+  --compare <addr>    Then run the same workload against this reference
+                      server, e.g. a real Redis at 127.0.0.1:6380, and report
+                      both side by side
+  --replay <file>     Instead of a generated workload, replay the commands
+                      recorded to this file by the server's record-file
+  --speed <x>         Replay at x times the recorded pace; 0 sends each
+                      command as soon as the last reply is in (default: 1)
+  --encryption-key <source>
+                      Key an encrypted recording is replayed with, given as
+                      for the server's encryption-key
+  --soak              Instead of benchmarking, churn keys for the duration
+                      (give hours) with SET, SETEX, GET, DEL and INCR, plus
+                      large values, checking every reply and the dataset;
+                      use a server of the soak's own
+  --checkpoint <secs> How often a soak pauses to check the whole dataset,
+                      DBSIZE and RSS and take a BGSAVE (default: 60)
+  --wipe-every <n>    Delete every key each n checkpoints of a soak; 0
+                      never does (default: 5)
+  --max-rss <size>    Fail a soak once the server's RSS is over this, e.g.
+                      2gb
+  --help              Show this help
+";
+
+/// SETs sent per pipeline while populating the keyspace
+const POPULATE_BATCH: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    /// Commands in flight per connection
+    pipeline: usize,
+    threads: usize,
+    duration: Duration,
+    keys: u64,
+    key_dist: KeyDist,
+    value_size: ValueSize,
+    mix: Mix,
+    populate: bool,
+    csv: Option<PathBuf>,
+    /// Reference server to run the same workload against
+    compare: Option<String>,
+    /// Recording to replay instead of generating a workload
+    replay: Option<PathBuf>,
+    /// Pace of a replay relative to the recording; 0 for as fast as possible
+    speed: f64,
+    /// Key to decrypt the recording with
+    encryption_key: Option<EncryptionKey>,
+    /// Run a soak instead of a benchmark
+    soak: bool,
+    /// Time between a soak's checkpoints
+    checkpoint: Duration,
+    /// Checkpoints between a soak's wipes of the keyspace; 0 for none
+    wipe_every: u64,
+    /// Most RSS the server may report during a soak
+    max_rss: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            clients: 50,
+            pipeline: 1,
+            threads: std::thread::available_parallelism().map_or(1, usize::from),
+            duration: Duration::from_secs(10),
+            keys: 100_000,
+            key_dist: KeyDist::Uniform,
+            value_size: ValueSize::default(),
+            mix: Mix::default(),
+            populate: true,
+            csv: None,
+            compare: None,
+            replay: None,
+            speed: 1.0,
+            encryption_key: None,
+            soak: false,
+            checkpoint: Duration::from_secs(60),
+            wipe_every: 5,
+            max_rss: None,
+        }
+    }
+}
+
+impl Options {
+    fn target(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Parse the command line; None asks for the usage text
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>> {
+    fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T> {
+        value
+            .parse()
+            .map_err(|_| anyhow!("Invalid value '{}' for {}", value, flag))
+    }
+
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        if flag == "--help" {
+            return Ok(None);
+        }
+        if flag == "--no-populate" {
+            options.populate = false;
+            continue;
+        }
+        if flag == "--soak" {
+            options.soak = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("Option '{}' needs a value", flag))?;
+        match flag.as_str() {
+            "-h" => options.host = value,
+            "-p" => options.port = number(&flag, &value)?,
+            "-c" => options.clients = number(&flag, &value)?,
+            "-P" => options.pipeline = number(&flag, &value)?,
+            "--threads" => options.threads = number(&flag, &value)?,
+            "--duration" => {
+                let secs: f64 = number(&flag, &value)?;
+                options.duration = Duration::try_from_secs_f64(secs)
+                    .map_err(|_| anyhow!("Invalid duration '{}'", value))?;
+            }
+            "--keys" => options.keys = number(&flag, &value)?,
+            "--key-dist" => options.key_dist = KeyDist::parse(&value)?,
+            "--value-size" => options.value_size = ValueSize::parse(&value)?,
+            "--mix" => options.mix = Mix::parse(&value)?,
+            "--csv" => options.csv = Some(PathBuf::from(value)),
+            "--compare" => options.compare = Some(value),
+            "--replay" => options.replay = Some(PathBuf::from(value)),
+            "--speed" => {
+                options.speed = number(&flag, &value)?;
+                if !options.speed.is_finite() || options.speed < 0.0 {
+                    bail!("Invalid value '{}' for --speed", value);
+                }
+            }
+            "--encryption-key" => options.encryption_key = Some(EncryptionKey::load(&value)?),
+            "--checkpoint" => {
+                let secs: f64 = number(&flag, &value)?;
+                options.checkpoint = Duration::try_from_secs_f64(secs)
+                    .ok()
+                    .filter(|checkpoint| !checkpoint.is_zero())
+                    .ok_or_else(|| anyhow!("Invalid checkpoint interval '{}'", value))?;
+            }
+            "--wipe-every" => options.wipe_every = number(&flag, &value)?,
+            "--max-rss" => options.max_rss = Some(parse_memory(&value)? as u64),
+            _ => bail!("Unrecognized option '{}'", flag),
+        }
+    }
+    if options.clients == 0 || options.pipeline == 0 || options.threads == 0 {
+        bail!("-c, -P and --threads must be at least 1");
+    }
+    if options.replay.is_some() && (options.compare.is_some() || options.csv.is_some()) {
+        bail!("--replay can't be combined with --compare or --csv");
+    }
+    if options.soak
+        && (options.replay.is_some() || options.compare.is_some() || options.csv.is_some())
+    {
+        bail!("--soak can't be combined with --replay, --compare or --csv");
+    }
+    Ok(Some(options))
+}
+
+fn main() -> Result<()> {
+    let Some(options) = parse_args(std::env::args().skip(1))? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(options.threads)
+        .enable_all()
+        .build()?;
+    if let Some(path) = &options.replay {
+        return runtime.block_on(run_replay(&options, path));
+    }
+    // Both runs draw the same keys, values and commands
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    if options.soak {
+        return runtime.block_on(run_soak(&options, seed));
+    }
+
+    println!(
+        "{}: {} clients, pipeline {}, on {} threads for {:?}, {} {} keys, {} values",
+        options.target(),
+        options.clients,
+        options.pipeline,
+        options.threads,
+        options.duration,
+        options.keys,
+        options.key_dist,
+        options.value_size
+    );
+    let target = options.target();
+    let report = runtime.block_on(run(&options, &target, seed))?;
+    print!("{}", report.table());
+    println!("(latencies in microseconds)");
+    if let Some(path) = &options.csv {
+        report.append_csv(path, &target, options.clients, options.pipeline)?;
+    }
+
+    let Some(reference) = &options.compare else {
+        return Ok(());
+    };
+    println!("\n{}: same workload", reference);
+    let baseline = runtime.block_on(run(&options, reference, seed))?;
+    print!("{}", baseline.table());
+    if let Some(path) = &options.csv {
+        baseline.append_csv(path, reference, options.clients, options.pipeline)?;
+    }
+    println!("\n{} against {}:", target, reference);
+    print!("{}", report.compare(&baseline));
+    println!(
+        "(latencies in microseconds; deltas relative to {})",
+        reference
+    );
+    Ok(())
+}
+
+/// Replay the recording at `path` against the target
+async fn run_replay(options: &Options, path: &Path) -> Result<()> {
+    let recording = Recording::load_with(path, options.encryption_key.as_ref())?;
+    let target = options.target();
+    println!(
+        "{}: replaying {} commands from {} at {}",
+        target,
+        recording.entries.len(),
+        path.display(),
+        match options.speed {
+            0.0 => "full speed".to_string(),
+            speed => format!("{}x", speed),
+        }
+    );
+    let replay = replay::replay(&target, recording, options.speed).await?;
+    print!("{}", replay.table());
+    println!(
+        "(latencies in microseconds; {} connections, {} commands skipped)",
+        replay.connections, replay.skipped
+    );
+    Ok(())
+}
+
+/// Soak the target, reporting each checkpoint as it passes
+async fn run_soak(options: &Options, seed: u64) -> Result<()> {
+    println!(
+        "{}: soaking with {} clients for {:?}, {} keys, {} values and some of 512 KiB, \
+         checkpoint every {:?} (seed {})",
+        options.target(),
+        options.clients,
+        options.duration,
+        options.keys,
+        options.value_size,
+        options.checkpoint,
+        seed
+    );
+    let soak = soak::soak(options, seed).await?;
+    println!(
+        "Passed: {} commands in {:.1}s, {} checkpoints, {} backups, {} wipes{}",
+        soak.ops,
+        soak.elapsed.as_secs_f64(),
+        soak.checkpoints,
+        soak.saves,
+        soak.wipes,
+        soak.peak_rss
+            .map_or_else(String::new, |rss| format!(", peak RSS {} bytes", rss))
+    );
+    Ok(())
+}
+
+/// Connect every client to `target`, populate the keyspace if the mix reads
+/// it, then send commands from all clients until the duration is up. The
+/// same `seed` sends the same commands.
+async fn run(options: &Options, target: &str, seed: u64) -> Result<Report> {
+    let mut clients = Vec::with_capacity(options.clients);
+    for _ in 0..options.clients {
+        let client = Client::connect(target)
+            .await
+            .map_err(|e| anyhow!("Could not connect to {}: {}", target, e))?;
+        clients.push(client);
+    }
+
+    let workload = Workload::new(
+        options.mix.clone(),
+        options.keys,
+        options.key_dist,
+        options.value_size.clone(),
+    );
+    if options.populate && workload.needs_population() {
+        eprintln!("Populating {} keys...", workload.keys);
+        populate(&mut clients[0], &workload, &mut Rng::new(seed)).await?;
+    }
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let tasks: Vec<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(index, client)| {
+            let workload = workload.clone();
+            let rng = Rng::new(seed.wrapping_add(index as u64));
+            tokio::spawn(drive(client, workload, options.pipeline, rng, deadline))
+        })
+        .collect();
+
+    let mut report = Report::default();
+    for task in tasks {
+        report.merge(&task.await??);
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// SET every key of the workload, in pipelined batches
+async fn populate(client: &mut Client, workload: &Workload, rng: &mut Rng) -> Result<()> {
+    for batch_start in (0..workload.keys).step_by(POPULATE_BATCH as usize) {
+        let mut pipeline = Pipeline::new();
+        for n in batch_start..(batch_start + POPULATE_BATCH).min(workload.keys) {
+            let key = format!("key:{}", n);
+            pipeline.cmd(&[&b"SET"[..], key.as_bytes(), &workload.next_value(rng)]);
+        }
+        for reply in pipeline.execute(client).await? {
+            if let RespValue::Error(e) = reply {
+                bail!("Populating failed: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// One client's loop until `deadline`: write `depth` commands, read their
+/// replies, repeat. Each command's latency is the round trip of its batch,
+/// as redis-benchmark counts it.
+async fn drive(
+    mut client: Client,
+    workload: Workload,
+    depth: usize,
+    mut rng: Rng,
+    deadline: Instant,
+) -> Result<Report> {
+    let mut report = Report::default();
+    let mut kinds = Vec::with_capacity(depth);
+    while Instant::now() < deadline {
+        if depth == 1 {
+            let (kind, args) = workload.next_command(&mut rng);
+            let sent = Instant::now();
+            let reply = client.command(&args).await?;
+            report.record(kind, sent.elapsed(), matches!(reply, RespValue::Error(_)));
+            continue;
+        }
+
+        let mut pipeline = Pipeline::new();
+        kinds.clear();
+        for _ in 0..depth {
+            let (kind, args) = workload.next_command(&mut rng);
+            pipeline.cmd(&args);
+            kinds.push(kind);
+        }
+        let sent = Instant::now();
+        let replies = pipeline.execute(&mut client).await?;
+        let latency = sent.elapsed();
+        for (kind, reply) in kinds.iter().zip(&replies) {
+            report.record(*kind, latency, matches!(reply, RespValue::Error(_)));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rudis::Server;
+    use workload::Kind;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse_args(args(&[
+            "-p",
+            "7000",
+            "-c",
+            "4",
+            "-P",
+            "16",
+            "--duration",
+            "0.5",
+            "--keys",
+            "10",
+            "--key-dist",
+            "zipf",
+            "--value-size",
+            "16-32",
+            "--mix",
+            "incr",
+            "--no-populate",
+            "--csv",
+            "out.csv",
+            "--compare",
+            "127.0.0.1:6380",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.port, 7000);
+        assert_eq!(options.clients, 4);
+        assert_eq!(options.pipeline, 16);
+        assert_eq!(options.duration, Duration::from_millis(500));
+        assert_eq!(options.keys, 10);
+        assert_eq!(options.key_dist, KeyDist::Zipf(0.99));
+        assert_eq!(options.value_size, ValueSize::Uniform { min: 16, max: 32 });
+        assert_eq!(options.mix, Mix::parse("incr").unwrap());
+        assert!(!options.populate);
+        assert_eq!(options.csv, Some(PathBuf::from("out.csv")));
+        assert_eq!(options.target(), "127.0.0.1:7000");
+        assert_eq!(options.compare.as_deref(), Some("127.0.0.1:6380"));
+
+        let soak = parse_args(args(&[
+            "--soak",
+            "--duration",
+            "7200",
+            "--checkpoint",
+            "30",
+            "--wipe-every",
+            "0",
+            "--max-rss",
+            "2gb",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert!(soak.soak);
+        assert_eq!(soak.checkpoint, Duration::from_secs(30));
+        assert_eq!(soak.wipe_every, 0);
+        assert_eq!(soak.max_rss, Some(2 << 30));
+
+        assert_eq!(parse_args(args(&["--help"])).unwrap(), None);
+        for bad in [
+            &["-c", "0"][..],
+            &["-P", "0"],
+            &["--duration", "-1"],
+            &["--key-dist", "zipf:2"],
+            &["--value-size", "big"],
+            &["--speed", "-1"],
+            &["--encryption-key", "not-a-key"],
+            &["--replay", "x.resp", "--csv", "out.csv"],
+            &["--soak", "--compare", "127.0.0.1:6380"],
+            &["--checkpoint", "0"],
+            &["--max-rss", "lots"],
+            &["-p"],
+            &["--bogus", "1"],
+        ] {
+            assert!(parse_args(args(bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn benchmarks_a_server() {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let store = server.store().clone();
+        tokio::spawn(async move { server.run().await });
+
+        let options = Options {
+            port,
+            clients: 3,
+            duration: Duration::from_millis(200),
+            keys: 50,
+            key_dist: KeyDist::Zipf(0.9),
+            value_size: ValueSize::Uniform { min: 8, max: 256 },
+            mix: Mix::parse("get=2,set=1,incr=1,mget=1").unwrap(),
+            ..Options::default()
+        };
+        let report = run(&options, &options.target(), 1).await.unwrap();
+
+        // Populated before the run, and every GET found its key
+        let keys = store.keys(b"key:*").await;
+        assert_eq!(keys.len(), 50);
+        for key in keys {
+            let len = store.get(&key).await.unwrap().len();
+            assert!((8..=256).contains(&len), "{}", len);
+        }
+        assert!(store.keyspace_stats().misses == 0);
+        for kind in [Kind::Get, Kind::Set, Kind::Incr, Kind::Mget] {
+            let stat = report.stat(kind);
+            assert!(stat.ops > 0, "no {}", kind.name());
+            assert_eq!(stat.errors, 0);
+        }
+        assert_eq!(report.stat(Kind::Ping).ops, 0);
+        assert!(report.elapsed >= Duration::from_millis(200));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pipelines_commands() {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let store = server.store().clone();
+        tokio::spawn(async move { server.run().await });
+
+        let options = Options {
+            port,
+            clients: 2,
+            pipeline: 32,
+            duration: Duration::from_millis(200),
+            keys: 1,
+            mix: Mix::parse("incr").unwrap(),
+            ..Options::default()
+        };
+        let report = run(&options, &options.target(), 1).await.unwrap();
+
+        // Whole batches were sent, and every INCR landed on the one counter
+        let incrs = report.stat(Kind::Incr).ops;
+        assert!(incrs > 0);
+        assert_eq!(incrs % 32, 0);
+        assert_eq!(
+            store.get(b"counter:0").await,
+            Some(incrs.to_string().into())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn compares_two_servers() {
+        let mut targets = Vec::new();
+        for _ in 0..2 {
+            let server = Server::builder().port(0).bind().await.unwrap();
+            targets.push(server.local_addr().unwrap().to_string());
+            tokio::spawn(async move { server.run().await });
+        }
+
+        let options = Options {
+            clients: 2,
+            duration: Duration::from_millis(100),
+            keys: 10,
+            mix: Mix::parse("get,set").unwrap(),
+            ..Options::default()
+        };
+        let report = run(&options, &targets[0], 7).await.unwrap();
+        let baseline = run(&options, &targets[1], 7).await.unwrap();
+        let comparison = report.compare(&baseline);
+        let lines: Vec<&str> = comparison.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", comparison);
+        assert!(lines[1].starts_with("GET"));
+        assert!(lines[3].starts_with("TOTAL"));
+        assert!(lines[3].contains('%'), "{}", lines[3]);
+    }
+}
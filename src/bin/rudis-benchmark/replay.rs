@@ -0,0 +1,207 @@
+//! Re-drives a server from a recording made with `record-file`. Every
+//! recorded connection gets a connection of its own that sends its commands
+//! in their recorded order, each at its recorded time scaled by the replay
+//! speed, or as soon as the previous reply is back at speed 0.
+
+use crate::report::{Stat, table};
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use rudis::RespValue;
+use rudis::client::Client;
+use rudis::record::{Entry, Recording};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Commands not replayed, as they would take the target server down
+const SKIPPED: [&str; 1] = ["SHUTDOWN"];
+
+/// Results of a replay, per command name
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    stats: BTreeMap<String, Stat>,
+    /// Recorded connections, each replayed over a connection of its own
+    pub connections: usize,
+    /// Commands left out, see `SKIPPED`
+    pub skipped: u64,
+    pub elapsed: Duration,
+}
+
+impl Replay {
+    fn merge(&mut self, other: Replay) {
+        for (name, stat) in other.stats {
+            self.stats.entry(name).or_default().add(&stat);
+        }
+        self.skipped += other.skipped;
+    }
+
+    pub fn total(&self) -> Stat {
+        let mut total = Stat::default();
+        for stat in self.stats.values() {
+            total.add(stat);
+        }
+        total
+    }
+
+    /// Throughput and latency per command replayed, then the total
+    pub fn table(&self) -> String {
+        let rows = self
+            .stats
+            .iter()
+            .map(|(name, stat)| (name.as_str(), stat.clone()))
+            .chain([("TOTAL", self.total())]);
+        table(rows, self.elapsed)
+    }
+}
+
+/// Replay `recording` against `target`. `speed` scales the recorded timing,
+/// 2.0 replaying twice as fast; 0 ignores it.
+pub async fn replay(target: &str, recording: Recording, speed: f64) -> Result<Replay> {
+    let mut connections: BTreeMap<u64, Vec<Entry>> = BTreeMap::new();
+    for entry in recording.entries {
+        connections.entry(entry.client).or_default().push(entry);
+    }
+    // Time is counted from the first command rather than the recording's start
+    let first = connections
+        .values()
+        .map(|entries| entries[0].at)
+        .min()
+        .unwrap_or_default();
+
+    let mut clients = Vec::with_capacity(connections.len());
+    for _ in 0..connections.len() {
+        let client = Client::connect(target)
+            .await
+            .map_err(|e| anyhow!("Could not connect to {}: {}", target, e))?;
+        clients.push(client);
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = connections
+        .into_values()
+        .zip(clients)
+        .map(|(entries, client)| {
+            let schedule = move |at: Duration| {
+                (speed > 0.0).then(|| start + at.saturating_sub(first).div_f64(speed))
+            };
+            tokio::spawn(replay_connection(client, entries, schedule))
+        })
+        .collect();
+
+    let mut replay = Replay {
+        connections: tasks.len(),
+        ..Replay::default()
+    };
+    for task in tasks {
+        replay.merge(task.await??);
+    }
+    replay.elapsed = start.elapsed();
+    Ok(replay)
+}
+
+/// Send one recorded connection's commands, each once `schedule` says it's
+/// due, timing the replies
+async fn replay_connection(
+    mut client: Client,
+    entries: Vec<Entry>,
+    schedule: impl Fn(Duration) -> Option<Instant>,
+) -> Result<Replay> {
+    let mut replay = Replay::default();
+    for entry in entries {
+        let Some(args) = arguments(entry.command) else {
+            continue;
+        };
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        if SKIPPED.contains(&name.as_str()) {
+            replay.skipped += 1;
+            continue;
+        }
+        if let Some(due) = schedule(entry.at) {
+            tokio::time::sleep_until(due).await;
+        }
+
+        let sent = Instant::now();
+        let reply = client.command(&args).await?;
+        let failed = matches!(reply, RespValue::Error(_));
+        replay
+            .stats
+            .entry(name.clone())
+            .or_default()
+            .record(sent.elapsed(), failed);
+        // The server closes the connection after replying to QUIT
+        if name == "QUIT" {
+            break;
+        }
+    }
+    Ok(replay)
+}
+
+/// A command's arguments; the server only records arrays of bulk strings
+fn arguments(command: RespValue) -> Option<Vec<Bytes>> {
+    let RespValue::Array(Some(args)) = command else {
+        return None;
+    };
+    let args: Option<Vec<Bytes>> = args
+        .into_iter()
+        .map(|arg| match arg {
+            RespValue::BulkString(Some(arg)) => Some(arg),
+            _ => None,
+        })
+        .collect();
+    args.filter(|args| !args.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rudis::Server;
+    use rudis::record::Recording;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn replays_a_recording() {
+        let path = std::env::temp_dir().join(format!("rudis-replay-{}.resp", std::process::id()));
+        let recorded = Server::builder()
+            .port(0)
+            .config(|config| config.record_file = Some(path.clone()))
+            .bind()
+            .await
+            .unwrap();
+        let addr = recorded.local_addr().unwrap();
+        tokio::spawn(async move { recorded.run().await });
+
+        let mut first = Client::connect(addr).await.unwrap();
+        let mut second = Client::connect(addr).await.unwrap();
+        first.set("a", "1").await.unwrap();
+        second.incr("n").await.unwrap();
+        first.incr("n").await.unwrap();
+        assert!(first.command(&["INCR", "a", "extra"]).await.is_ok());
+        second.command(&["SHUTDOWN", "NOSAVE"]).await.ok();
+
+        // The recorder writes in the background
+        let mut recording = Recording::load(&path).unwrap();
+        for _ in 0..100 {
+            if recording.entries.len() == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            recording = Recording::load(&path).unwrap();
+        }
+        assert_eq!(recording.entries.len(), 5);
+        std::fs::remove_file(&path).unwrap();
+
+        let target = Server::builder().port(0).bind().await.unwrap();
+        let addr = target.local_addr().unwrap();
+        let store = target.store().clone();
+        tokio::spawn(async move { target.run().await });
+
+        let replay = replay(&addr.to_string(), recording, 0.0).await.unwrap();
+        assert_eq!(replay.connections, 2);
+        assert_eq!(replay.skipped, 1);
+        assert_eq!(replay.stats["INCR"].ops, 3);
+        // The malformed INCR fails in the replay as it did when recorded
+        assert_eq!(replay.stats["INCR"].errors, 1);
+        assert_eq!(replay.total().ops, 4);
+        assert_eq!(store.get(b"a").await, Some(Bytes::from("1")));
+        assert_eq!(store.get(b"n").await, Some(Bytes::from("2")));
+    }
+}
@@ -0,0 +1,381 @@
+//! Per-command results of a run, printed as a table and optionally appended
+//! to a CSV file. Each client records latencies into HDR histograms of its
+//! own, merged once the run is over, so recording never contends.
+
+use crate::workload::Kind;
+use anyhow::{Result, anyhow};
+use hdrhistogram::Histogram;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::Path;
+use std::time::Duration;
+
+/// Percentiles reported, like redis-benchmark's
+const PERCENTILES: [(&str, f64); 4] = [("p50", 50.0), ("p95", 95.0), ("p99", 99.0), ("p999", 99.9)];
+
+/// Percentiles in the side-by-side comparison, which has room for fewer
+const COMPARED_PERCENTILES: [(&str, f64); 2] = [("p50", 50.0), ("p99", 99.0)];
+
+/// Significant digits kept by the latency histograms; 3 keeps every
+/// recorded value within 0.1%
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+/// Counts for one command
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub ops: u64,
+    /// Replies that were errors
+    pub errors: u64,
+    /// Summed round-trip time of every call
+    pub latency: Duration,
+    /// Round-trip times in nanoseconds; resizes to fit the slowest call
+    pub latencies: Histogram<u64>,
+}
+
+impl Default for Stat {
+    fn default() -> Self {
+        Self {
+            ops: 0,
+            errors: 0,
+            latency: Duration::ZERO,
+            latencies: Histogram::new(SIGNIFICANT_DIGITS).expect("valid significant digits"),
+        }
+    }
+}
+
+impl Stat {
+    pub fn record(&mut self, latency: Duration, failed: bool) {
+        self.ops += 1;
+        self.errors += u64::from(failed);
+        self.latency += latency;
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        // Zero can't be recorded; a sub-nanosecond round trip doesn't happen.
+        // Recording grows the histogram to fit; saturate if it can't grow.
+        if self.latencies.record(nanos.max(1)).is_err() {
+            self.latencies.saturating_record(nanos);
+        }
+    }
+
+    pub fn add(&mut self, other: &Stat) {
+        self.ops += other.ops;
+        self.errors += other.errors;
+        self.latency += other.latency;
+        self.latencies
+            .add(&other.latencies)
+            .expect("auto-resizing histograms accept any value");
+    }
+
+    /// The latency at `percentile`, in microseconds
+    pub fn percentile_usec(&self, percentile: f64) -> f64 {
+        self.latencies.value_at_percentile(percentile) as f64 / 1e3
+    }
+
+    fn avg_usec(&self) -> f64 {
+        if self.ops == 0 {
+            return 0.0;
+        }
+        self.latency.as_secs_f64() * 1e6 / self.ops as f64
+    }
+}
+
+/// Results of a run, or of one client's share of it
+#[derive(Debug, Clone)]
+pub struct Report {
+    stats: [Stat; Kind::ALL.len()],
+    /// Wall-clock length of the run
+    pub elapsed: Duration,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            stats: std::array::from_fn(|_| Stat::default()),
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl Report {
+    pub fn record(&mut self, kind: Kind, latency: Duration, failed: bool) {
+        self.stats[kind.index()].record(latency, failed);
+    }
+
+    /// Add another client's results to these
+    pub fn merge(&mut self, other: &Report) {
+        for (stat, other) in self.stats.iter_mut().zip(&other.stats) {
+            stat.add(other);
+        }
+        self.elapsed = self.elapsed.max(other.elapsed);
+    }
+
+    pub fn stat(&self, kind: Kind) -> &Stat {
+        &self.stats[kind.index()]
+    }
+
+    /// Commands that were sent, with their counts, then the total
+    fn rows(&self) -> impl Iterator<Item = (&'static str, Stat)> + '_ {
+        Kind::ALL
+            .into_iter()
+            .map(|kind| (kind.name(), self.stat(kind).clone()))
+            .filter(|(_, stat)| stat.ops > 0)
+            .chain([("TOTAL", self.total())])
+    }
+
+    pub fn total(&self) -> Stat {
+        let mut total = Stat::default();
+        for stat in &self.stats {
+            total.add(stat);
+        }
+        total
+    }
+
+    fn ops_per_sec(&self, stat: &Stat) -> f64 {
+        ops_per_sec(stat, self.elapsed)
+    }
+
+    /// A table of throughput and latency per command, then the total.
+    /// Latencies are in microseconds.
+    pub fn table(&self) -> String {
+        table(self.rows(), self.elapsed)
+    }
+
+    /// These results side by side with `reference`'s for the same workload:
+    /// per command, throughput and latency of each, then the change from
+    /// the reference. Latencies are in microseconds.
+    pub fn compare(&self, reference: &Report) -> String {
+        let reference_rows: Vec<_> = reference.rows().collect();
+        let mut table = format!(
+            "{:<8} {:>12} {:>12} {:>8}",
+            "command", "ops/sec", "ref", "delta"
+        );
+        for (name, _) in COMPARED_PERCENTILES {
+            let _ = write!(table, " {:>9} {:>9} {:>8}", name, "ref", "delta");
+        }
+        table.push('\n');
+        for (name, stat) in self.rows() {
+            let Some((_, base)) = reference_rows.iter().find(|(other, _)| *other == name) else {
+                continue;
+            };
+            let (ops, base_ops) = (self.ops_per_sec(&stat), reference.ops_per_sec(base));
+            let _ = write!(
+                table,
+                "{:<8} {:>12.1} {:>12.1} {:>8}",
+                name,
+                ops,
+                base_ops,
+                delta(ops, base_ops)
+            );
+            for (_, percentile) in COMPARED_PERCENTILES {
+                let (usec, base_usec) = (
+                    stat.percentile_usec(percentile),
+                    base.percentile_usec(percentile),
+                );
+                let _ = write!(
+                    table,
+                    " {:>9.1} {:>9.1} {:>8}",
+                    usec,
+                    base_usec,
+                    delta(usec, base_usec)
+                );
+            }
+            table.push('\n');
+        }
+        table
+    }
+
+    /// Append a line per command to the CSV file at `path`, writing the
+    /// header first if the file is new. `target`, `clients` and `pipeline`
+    /// identify the run.
+    pub fn append_csv(
+        &self,
+        path: &Path,
+        target: &str,
+        clients: usize,
+        pipeline: usize,
+    ) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("Can't open '{}': {}", path.display(), e))?;
+        let mut lines = String::new();
+        if file.metadata()?.len() == 0 {
+            lines.push_str("target,command,clients,pipeline,duration_s,ops,ops_per_sec,avg_usec");
+            for (name, _) in PERCENTILES {
+                let _ = write!(lines, ",{}_usec", name);
+            }
+            lines.push_str(",errors\n");
+        }
+        for (name, stat) in self.rows() {
+            let _ = write!(
+                lines,
+                "{},{},{},{},{:.3},{},{:.1},{:.1}",
+                target,
+                name,
+                clients,
+                pipeline,
+                self.elapsed.as_secs_f64(),
+                stat.ops,
+                self.ops_per_sec(&stat),
+                stat.avg_usec()
+            );
+            for (_, percentile) in PERCENTILES {
+                let _ = write!(lines, ",{:.1}", stat.percentile_usec(percentile));
+            }
+            let _ = writeln!(lines, ",{}", stat.errors);
+        }
+        file.write_all(lines.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn ops_per_sec(stat: &Stat, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return 0.0;
+    }
+    stat.ops as f64 / secs
+}
+
+/// A table of throughput and latency for each named row of a run that took
+/// `elapsed`. Latencies are in microseconds.
+pub fn table<'a>(rows: impl Iterator<Item = (&'a str, Stat)>, elapsed: Duration) -> String {
+    let mut table = format!(
+        "{:<8} {:>12} {:>12} {:>9}",
+        "command", "ops", "ops/sec", "avg"
+    );
+    for (name, _) in PERCENTILES {
+        let _ = write!(table, " {:>9}", name);
+    }
+    table.push_str("   errors\n");
+    for (name, stat) in rows {
+        let _ = write!(
+            table,
+            "{:<8} {:>12} {:>12.1} {:>9.1}",
+            name,
+            stat.ops,
+            ops_per_sec(&stat, elapsed),
+            stat.avg_usec()
+        );
+        for (_, percentile) in PERCENTILES {
+            let _ = write!(table, " {:>9.1}", stat.percentile_usec(percentile));
+        }
+        let _ = writeln!(table, " {:>8}", stat.errors);
+    }
+    table
+}
+
+/// The change from `reference` to `value` as a signed percentage
+fn delta(value: f64, reference: f64) -> String {
+    if reference == 0.0 {
+        return "-".to_string();
+    }
+    format!("{:+.1}%", (value - reference) / reference * 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_and_tabulates() {
+        let mut first = Report::default();
+        first.record(Kind::Get, Duration::from_micros(10), false);
+        first.record(Kind::Get, Duration::from_micros(30), true);
+        first.elapsed = Duration::from_secs(2);
+        let mut second = Report::default();
+        second.record(Kind::Set, Duration::from_micros(50), false);
+        second.elapsed = Duration::from_secs(1);
+
+        first.merge(&second);
+        assert_eq!(first.elapsed, Duration::from_secs(2));
+        assert_eq!(first.stat(Kind::Get).errors, 1);
+        assert_eq!(first.total().ops, 3);
+
+        let table = first.table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", table);
+        assert!(lines[0].ends_with("p50       p95       p99      p999   errors"));
+        assert!(lines[1].starts_with("GET"));
+        assert!(lines[1].contains(" 20.0 "), "{}", lines[1]);
+        assert!(lines[2].starts_with("SET"));
+        assert!(lines[3].starts_with("TOTAL"));
+        assert!(lines[3].contains(" 1.5 "), "{}", lines[3]);
+    }
+
+    #[test]
+    fn reports_tail_latency() {
+        let mut report = Report::default();
+        for micros in 1..=1000 {
+            report.record(Kind::Get, Duration::from_micros(micros), false);
+        }
+        let stat = report.stat(Kind::Get);
+        let near = |percentile: f64, expected: f64| {
+            let usec = stat.percentile_usec(percentile);
+            assert!((usec - expected).abs() <= expected / 500.0, "{}", usec);
+        };
+        near(50.0, 500.0);
+        near(95.0, 950.0);
+        near(99.0, 990.0);
+        near(99.9, 999.0);
+
+        // Merging keeps the tail of each client
+        let mut slow = Report::default();
+        slow.record(Kind::Get, Duration::from_millis(50), false);
+        report.merge(&slow);
+        assert!(report.stat(Kind::Get).percentile_usec(100.0) >= 49_900.0);
+    }
+
+    #[test]
+    fn compares_with_a_reference() {
+        let mut report = Report::default();
+        let mut reference = Report::default();
+        for _ in 0..150 {
+            report.record(Kind::Get, Duration::from_micros(10), false);
+        }
+        for _ in 0..100 {
+            reference.record(Kind::Get, Duration::from_micros(20), false);
+        }
+        // Only in one run, so not compared
+        reference.record(Kind::Set, Duration::from_micros(20), false);
+        report.elapsed = Duration::from_secs(1);
+        reference.elapsed = Duration::from_secs(1);
+
+        let comparison = report.compare(&reference);
+        let lines: Vec<&str> = comparison.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", comparison);
+        assert!(lines[0].starts_with("command       ops/sec          ref    delta"));
+        let get: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(
+            get,
+            [
+                "GET", "150.0", "100.0", "+50.0%", "10.0", "20.0", "-50.0%", "10.0", "20.0",
+                "-50.0%"
+            ]
+        );
+        assert!(lines[2].starts_with("TOTAL"));
+        assert_eq!(delta(1.0, 0.0), "-");
+    }
+
+    #[test]
+    fn appends_csv_with_one_header() {
+        let path = std::env::temp_dir().join(format!("rudis-bench-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut report = Report::default();
+        report.record(Kind::Ping, Duration::from_micros(4), false);
+        report.elapsed = Duration::from_secs(1);
+        report.append_csv(&path, "127.0.0.1:6379", 2, 1).unwrap();
+        report.append_csv(&path, "127.0.0.1:6379", 2, 1).unwrap();
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with("avg_usec,p50_usec,p95_usec,p99_usec,p999_usec,errors"));
+        assert_eq!(
+            lines[1],
+            "127.0.0.1:6379,PING,2,1,1.000,1,1.0,4.0,4.0,4.0,4.0,4.0,0"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
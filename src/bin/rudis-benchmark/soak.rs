@@ -0,0 +1,573 @@
+//! Soak mode: hours of mixed traffic, checked as it goes, to build trust in
+//! a server before production. Each connection owns a slice of the keyspace
+//! and mirrors what it wrote there, so every reply can be checked against
+//! what it must be. Connections churn keys with SET, SETEX with TTLs of a
+//! few seconds, GET, DEL and INCR, with values drawn from `--value-size`
+//! and now and then a large one.
+//!
+//! At every checkpoint the connections pause: each checks its whole slice
+//! against its mirror, `DBSIZE` is checked against the keys written, and
+//! the server's RSS against `--max-rss`. Then a `BGSAVE` is taken and waited
+//! for, and every `--wipe-every` checkpoints all keys are deleted and the
+//! churn starts again from an empty keyspace. The first broken invariant,
+//! or the server going away, ends the run with an error.
+//!
+//! The server must be one of the soak's own, without `ttl-jitter`, as any
+//! other keys throw off `DBSIZE` and jitter throws off the expiry checks.
+
+use crate::Options;
+use crate::workload::{Rng, ValueSize};
+use anyhow::{Result, anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use rudis::RespValue;
+use rudis::client::Client;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Size of the large values mixed in, one SET in `LARGE_ONE_IN`
+const LARGE_VALUE: usize = 512 * 1024;
+const LARGE_ONE_IN: u64 = 50;
+/// Longest TTL given, in seconds, short enough for keys to expire between
+/// checkpoints
+const MAX_TTL_SECS: u64 = 5;
+/// Leeway around a TTL's end, within which a key may be there or gone
+const EXPIRY_SLACK: Duration = Duration::from_millis(100);
+/// Keys per MGET or DEL when checking or wiping a slice
+const BATCH: usize = 500;
+/// Longest a checkpoint's BGSAVE may take
+const BGSAVE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How a soak went
+#[derive(Debug, Clone, Default)]
+pub struct Soak {
+    pub ops: u64,
+    pub checkpoints: u64,
+    pub saves: u64,
+    pub wipes: u64,
+    /// Highest RSS the server reported, if it reports one
+    pub peak_rss: Option<u64>,
+    pub elapsed: Duration,
+}
+
+/// Soak the target of `options` until its duration is up, checkpointing
+/// every `options.checkpoint`. The same `seed` sends the same commands.
+pub async fn soak(options: &Options, seed: u64) -> Result<Soak> {
+    let target = options.target();
+    let connect = || async {
+        Client::connect(&target)
+            .await
+            .map_err(|e| anyhow!("Could not connect to {}: {}", target, e))
+    };
+    let mut control = connect().await?;
+    let keys = (options.keys / options.clients as u64).max(1);
+    let filler: Bytes = (0..options.value_size.max().max(LARGE_VALUE))
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let mut workers = Vec::with_capacity(options.clients);
+    for id in 0..options.clients {
+        workers.push(Worker {
+            client: connect().await?,
+            rng: Rng::new(seed.wrapping_add(id as u64)),
+            id,
+            keys,
+            value_size: options.value_size.clone(),
+            filler: filler.clone(),
+            mirror: Mirror::default(),
+            ops: 0,
+        });
+    }
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let mut soak = Soak::default();
+    while Instant::now() < deadline {
+        let until = (Instant::now() + options.checkpoint).min(deadline);
+        let tasks: Vec<_> = workers
+            .into_iter()
+            .map(|worker| tokio::spawn(worker.stretch(until)))
+            .collect();
+        workers = Vec::with_capacity(tasks.len());
+        let mut tally = Tally::default();
+        for task in tasks {
+            let (worker, checked) = task.await??;
+            workers.push(worker);
+            tally.add(&checked);
+        }
+
+        // Every connection is paused, so the key count holds still
+        let count = dbsize(&mut control).await?;
+        if count < tally.present || count > tally.present + tally.expiring {
+            bail!(
+                "DBSIZE is {}, with {} keys written and {} more that may not have expired",
+                count,
+                tally.present,
+                tally.expiring
+            );
+        }
+        let rss = info_field(&mut control, "memory", "used_memory_rss")
+            .await?
+            .and_then(|rss| rss.parse::<u64>().ok());
+        if let (Some(rss), Some(max)) = (rss, options.max_rss)
+            && rss > max
+        {
+            bail!("The server's RSS is {} bytes, over --max-rss {}", rss, max);
+        }
+        soak.peak_rss = soak.peak_rss.max(rss);
+        bgsave(&mut control).await?;
+        soak.saves += 1;
+        soak.checkpoints += 1;
+
+        let wipe = options.wipe_every > 0 && soak.checkpoints % options.wipe_every == 0;
+        if wipe {
+            for worker in &mut workers {
+                worker.wipe().await?;
+            }
+            let left = dbsize(&mut control).await?;
+            if left != 0 {
+                bail!("DBSIZE is {} after deleting every key", left);
+            }
+            soak.wipes += 1;
+        }
+        println!(
+            "{:>9.1}s  {} ops  {} keys  rss {}  checksum {:016x}{}",
+            start.elapsed().as_secs_f64(),
+            workers.iter().map(|worker| worker.ops).sum::<u64>(),
+            count,
+            rss.map_or_else(|| "-".to_string(), |rss| rss.to_string()),
+            tally.checksum,
+            if wipe { "  wiped" } else { "" }
+        );
+    }
+    soak.ops = workers.iter().map(|worker| worker.ops).sum();
+    soak.elapsed = start.elapsed();
+    Ok(soak)
+}
+
+/// What a connection has written to its slice of the keyspace
+#[derive(Debug, Default)]
+struct Mirror {
+    /// Checksums of the values of `soak:<id>:<n>`, by `n`
+    values: HashMap<u64, u64>,
+    /// The keys `soak:<id>:ttl:<n>` were set with, by `n`
+    expiring: HashMap<u64, Expiring>,
+    /// Value of `soak:<id>:counter`, 0 while it doesn't exist
+    counter: i64,
+}
+
+/// A value set with a TTL, and the window its TTL ends in
+#[derive(Debug, Clone, Copy)]
+struct Expiring {
+    checksum: u64,
+    /// The TTL counted from when the SETEX was sent
+    earliest: Instant,
+    /// The TTL counted from when its reply came back
+    latest: Instant,
+}
+
+/// What a connection found checking its slice
+#[derive(Debug, Clone, Copy, Default)]
+struct Tally {
+    /// Keys that must exist
+    present: u64,
+    /// Keys with a TTL, which may or may not have expired
+    expiring: u64,
+    /// Checksum over the keys without a TTL and their values
+    checksum: u64,
+}
+
+impl Tally {
+    fn add(&mut self, other: &Tally) {
+        self.present += other.present;
+        self.expiring += other.expiring;
+        self.checksum = self.checksum.wrapping_add(other.checksum);
+    }
+}
+
+/// One connection and the slice of the keyspace it owns
+struct Worker {
+    client: Client,
+    rng: Rng,
+    id: usize,
+    /// Keys in the slice, with and without a TTL each
+    keys: u64,
+    value_size: ValueSize,
+    /// Bytes values are cut from, after a random tag
+    filler: Bytes,
+    mirror: Mirror,
+    ops: u64,
+}
+
+impl Worker {
+    fn key(&self, n: u64) -> String {
+        format!("soak:{}:{}", self.id, n)
+    }
+
+    fn ttl_key(&self, n: u64) -> String {
+        format!("soak:{}:ttl:{}", self.id, n)
+    }
+
+    fn counter_key(&self) -> String {
+        format!("soak:{}:counter", self.id)
+    }
+
+    /// A value of a drawn size, or now and then a large one, that starts
+    /// with a random tag so no two are alike
+    fn value(&mut self) -> Bytes {
+        let size = if self.rng.below(LARGE_ONE_IN) == 0 {
+            LARGE_VALUE
+        } else {
+            self.value_size.pick(&mut self.rng)
+        };
+        let mut value = BytesMut::with_capacity(size);
+        value.extend_from_slice(&self.rng.next_u64().to_le_bytes()[..size.min(8)]);
+        value.extend_from_slice(&self.filler[..size.saturating_sub(8)]);
+        value.freeze()
+    }
+
+    /// Churn until `until`, then check the slice
+    async fn stretch(mut self, until: Instant) -> Result<(Self, Tally)> {
+        let id = self.id;
+        let run = async {
+            while Instant::now() < until {
+                self.step().await?;
+                self.ops += 1;
+            }
+            self.check().await
+        };
+        match run.await {
+            Ok(tally) => Ok((self, tally)),
+            Err(e) => Err(anyhow!("Connection {}: {}", id, e)),
+        }
+    }
+
+    /// Send one command and check its reply against the mirror
+    async fn step(&mut self) -> Result<()> {
+        let n = self.rng.below(self.keys);
+        match self.rng.below(100) {
+            0..=29 => {
+                let key = self.key(n);
+                let reply = self.client.command(&["GET", &key]).await?;
+                expect_value(&key, checksum(reply)?, self.mirror.values.get(&n).copied())
+            }
+            30..=54 => {
+                let key = self.key(n);
+                let value = self.value();
+                expect_ok(
+                    self.client
+                        .command(&[&b"SET"[..], key.as_bytes(), &value])
+                        .await?,
+                )?;
+                self.mirror.values.insert(n, fnv(&value));
+                Ok(())
+            }
+            55..=69 => {
+                let key = self.ttl_key(n);
+                let value = self.value();
+                let ttl = Duration::from_secs(1 + self.rng.below(MAX_TTL_SECS));
+                let secs = ttl.as_secs().to_string();
+                let sent = Instant::now();
+                let reply = self
+                    .client
+                    .command(&[&b"SETEX"[..], key.as_bytes(), secs.as_bytes(), &value])
+                    .await?;
+                expect_ok(reply)?;
+                let expiring = Expiring {
+                    checksum: fnv(&value),
+                    earliest: sent + ttl,
+                    latest: Instant::now() + ttl,
+                };
+                self.mirror.expiring.insert(n, expiring);
+                Ok(())
+            }
+            70..=79 => {
+                let key = self.ttl_key(n);
+                let sent = Instant::now();
+                let reply = self.client.command(&["GET", &key]).await?;
+                let expiring = self.mirror.expiring.get(&n);
+                expect_expiring(&key, checksum(reply)?, expiring, sent, Instant::now())
+            }
+            80..=89 => {
+                let key = self.key(n);
+                let existed = self.mirror.values.remove(&n).is_some();
+                match self.client.command(&["DEL", &key]).await? {
+                    RespValue::Integer(deleted) if deleted == i64::from(existed) => Ok(()),
+                    reply => bail!(
+                        "DEL {} replied {:?}, expected {}",
+                        key,
+                        reply,
+                        i64::from(existed)
+                    ),
+                }
+            }
+            _ => {
+                let key = self.counter_key();
+                match self.client.command(&["INCR", &key]).await? {
+                    RespValue::Integer(n) if n == self.mirror.counter + 1 => {
+                        self.mirror.counter = n;
+                        Ok(())
+                    }
+                    reply => bail!(
+                        "INCR {} replied {:?}, expected {}",
+                        key,
+                        reply,
+                        self.mirror.counter + 1
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Check every key of the slice against the mirror
+    async fn check(&mut self) -> Result<Tally> {
+        let mut tally = Tally::default();
+        let numbers: Vec<u64> = (0..self.keys).collect();
+        for chunk in numbers.chunks(BATCH) {
+            let keys: Vec<String> = chunk.iter().map(|&n| self.key(n)).collect();
+            let (found, _, _) = self.mget(&keys).await?;
+            for ((n, key), found) in chunk.iter().zip(&keys).zip(found) {
+                let expected = self.mirror.values.get(n).copied();
+                expect_value(key, found, expected)?;
+                if let Some(sum) = expected {
+                    tally.present += 1;
+                    tally.checksum = tally.checksum.wrapping_add(fnv(key.as_bytes()) ^ sum);
+                }
+            }
+        }
+
+        let mut expiring: Vec<u64> = self.mirror.expiring.keys().copied().collect();
+        expiring.sort_unstable();
+        for chunk in expiring.chunks(BATCH) {
+            let keys: Vec<String> = chunk.iter().map(|&n| self.ttl_key(n)).collect();
+            let (found, sent, replied) = self.mget(&keys).await?;
+            for ((n, key), found) in chunk.iter().zip(&keys).zip(found) {
+                expect_expiring(key, found, self.mirror.expiring.get(n), sent, replied)?;
+            }
+        }
+        tally.expiring = expiring.len() as u64;
+
+        let key = self.counter_key();
+        let found = checksum(self.client.command(&["GET", &key]).await?)?;
+        let counter = self.mirror.counter;
+        let expected = (counter > 0).then(|| fnv(counter.to_string().as_bytes()));
+        expect_value(&key, found, expected)?;
+        if let Some(sum) = expected {
+            tally.present += 1;
+            tally.checksum = tally.checksum.wrapping_add(fnv(key.as_bytes()) ^ sum);
+        }
+        Ok(tally)
+    }
+
+    /// Checksums of the values of `keys`, and when the MGET was sent and
+    /// answered
+    async fn mget(&mut self, keys: &[String]) -> Result<(Vec<Option<u64>>, Instant, Instant)> {
+        let args: Vec<&str> = std::iter::once("MGET")
+            .chain(keys.iter().map(String::as_str))
+            .collect();
+        let sent = Instant::now();
+        let reply = self.client.command(&args).await?;
+        let replied = Instant::now();
+        match reply {
+            RespValue::Array(Some(values)) if values.len() == keys.len() => {
+                let found = values.into_iter().map(checksum).collect::<Result<_>>()?;
+                Ok((found, sent, replied))
+            }
+            reply => bail!("MGET of {} keys replied {:?}", keys.len(), reply),
+        }
+    }
+
+    /// Delete every key of the slice, starting over from an empty one
+    async fn wipe(&mut self) -> Result<()> {
+        let mut keys: Vec<String> = (0..self.keys).map(|n| self.key(n)).collect();
+        keys.extend(self.mirror.expiring.keys().map(|&n| self.ttl_key(n)));
+        keys.push(self.counter_key());
+        for chunk in keys.chunks(BATCH) {
+            let args: Vec<&str> = std::iter::once("DEL")
+                .chain(chunk.iter().map(String::as_str))
+                .collect();
+            if let RespValue::Error(e) = self.client.command(&args).await? {
+                bail!("Connection {}: DEL failed: {}", self.id, e);
+            }
+        }
+        self.mirror = Mirror::default();
+        Ok(())
+    }
+}
+
+/// FNV-1a, to compare values without keeping them
+fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The checksum of a GET reply's value, None for a missing key
+fn checksum(reply: RespValue) -> Result<Option<u64>> {
+    match reply {
+        RespValue::BulkString(Some(value)) => Ok(Some(fnv(&value))),
+        RespValue::BulkString(None) => Ok(None),
+        reply => bail!("expected a value, got {:?}", reply),
+    }
+}
+
+fn expect_ok(reply: RespValue) -> Result<()> {
+    match reply {
+        RespValue::SimpleString(s) if s == "OK" => Ok(()),
+        reply => bail!("expected OK, got {:?}", reply),
+    }
+}
+
+/// Check a key without a TTL holds what was last written to it
+fn expect_value(key: &str, found: Option<u64>, expected: Option<u64>) -> Result<()> {
+    match (found, expected) {
+        (Some(found), Some(expected)) if found != expected => {
+            bail!("{} holds a value other than the one written", key)
+        }
+        (Some(_), None) => bail!("{} exists, but was never written or was deleted", key),
+        (None, Some(_)) => bail!("{} is missing", key),
+        _ => Ok(()),
+    }
+}
+
+/// Check a key with a TTL, read between `sent` and `replied`: it must hold
+/// its value until its TTL can have run out and be gone once it must have
+fn expect_expiring(
+    key: &str,
+    found: Option<u64>,
+    expiring: Option<&Expiring>,
+    sent: Instant,
+    replied: Instant,
+) -> Result<()> {
+    match (found, expiring) {
+        (None, None) => Ok(()),
+        (Some(_), None) => bail!("{} exists, but was never written", key),
+        (Some(found), Some(expiring)) if found != expiring.checksum => {
+            bail!("{} holds a value other than the one written", key)
+        }
+        (Some(_), Some(expiring)) if sent > expiring.latest + EXPIRY_SLACK => {
+            bail!("{} outlived its TTL", key)
+        }
+        (None, Some(expiring)) if replied + EXPIRY_SLACK < expiring.earliest => {
+            bail!("{} expired before its TTL ran out", key)
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn dbsize(client: &mut Client) -> Result<u64> {
+    match client.command(&["DBSIZE"]).await? {
+        RespValue::Integer(n) if n >= 0 => Ok(n as u64),
+        reply => bail!("DBSIZE replied {:?}", reply),
+    }
+}
+
+/// A field of an INFO section, if the server reports it
+async fn info_field(client: &mut Client, section: &str, field: &str) -> Result<Option<String>> {
+    let info = client.info(Some(section)).await?;
+    Ok(info.lines().find_map(|line| {
+        line.strip_prefix(field)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .map(str::to_string)
+    }))
+}
+
+/// Take a backup and wait for it to be written
+async fn bgsave(client: &mut Client) -> Result<()> {
+    let before = saves(client).await?;
+    match client.command(&["BGSAVE"]).await? {
+        RespValue::Error(e) if !e.contains("in progress") => bail!("BGSAVE failed: {}", e),
+        _ => {}
+    }
+    let started = Instant::now();
+    loop {
+        let in_progress = info_field(client, "persistence", "rdb_bgsave_in_progress").await?;
+        if in_progress.as_deref() != Some("1") && saves(client).await? != before {
+            break;
+        }
+        if started.elapsed() > BGSAVE_TIMEOUT {
+            bail!("BGSAVE didn't finish in {:?}", BGSAVE_TIMEOUT);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    match info_field(client, "persistence", "rdb_last_bgsave_status").await? {
+        Some(status) if status != "ok" => bail!("BGSAVE failed, see the server's log"),
+        _ => Ok(()),
+    }
+}
+
+/// Backups the server has taken since it started
+async fn saves(client: &mut Client) -> Result<Option<u64>> {
+    let saves = info_field(client, "persistence", "rdb_saves").await?;
+    Ok(saves.and_then(|saves| saves.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rudis::Server;
+
+    async fn server(dir: &str) -> (Options, rudis::Store) {
+        let dir = std::env::temp_dir().join(format!("rudis-soak-{}-{}", dir, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = Server::builder()
+            .port(0)
+            .config(move |config| config.dir = dir)
+            .bind()
+            .await
+            .unwrap();
+        let options = Options {
+            port: server.local_addr().unwrap().port(),
+            clients: 2,
+            keys: 200,
+            value_size: ValueSize::Uniform { min: 1, max: 300 },
+            duration: Duration::from_millis(700),
+            checkpoint: Duration::from_millis(200),
+            wipe_every: 2,
+            ..Options::default()
+        };
+        let store = server.store().clone();
+        tokio::spawn(async move { server.run().await });
+        (options, store)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn soaks_a_server() {
+        let (options, _) = server("pass").await;
+        let soak = soak(&options, 1).await.unwrap();
+        // How many checkpoints fit depends on how long each one takes
+        assert!(soak.checkpoints >= 2, "{:?}", soak);
+        assert_eq!(soak.saves, soak.checkpoints);
+        assert_eq!(soak.wipes, soak.checkpoints / 2);
+        assert!(soak.ops > 0);
+        #[cfg(target_os = "linux")]
+        assert!(soak.peak_rss.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn catches_a_lost_write() {
+        let (options, store) = server("fail").await;
+        // Values changed behind the soak's back
+        tokio::spawn(async move {
+            loop {
+                for n in 0..100 {
+                    let key = Bytes::from(format!("soak:0:{}", n));
+                    store.set(key, Bytes::from("tampered")).await;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+        let error = soak(&options, 1).await.unwrap_err().to_string();
+        assert!(error.starts_with("Connection 0:"), "{}", error);
+        assert!(error.contains("soak:0:"), "{}", error);
+
+        let (options, _) = server("rss").await;
+        let tight = Options {
+            max_rss: Some(1),
+            ..options
+        };
+        if cfg!(target_os = "linux") {
+            let error = soak(&tight, 1).await.unwrap_err().to_string();
+            assert!(error.contains("--max-rss"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,565 @@
+//! What the benchmark clients send: a weighted mix of commands over a
+//! fixed-size keyspace, with keys and value sizes drawn from configurable
+//! distributions.
+
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use std::fmt;
+
+/// Keys fetched by each MGET
+const MGET_KEYS: usize = 10;
+
+/// A benchmarked command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Ping,
+    Get,
+    Set,
+    Incr,
+    Mget,
+    Del,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 6] = [
+        Kind::Ping,
+        Kind::Get,
+        Kind::Set,
+        Kind::Incr,
+        Kind::Mget,
+        Kind::Del,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Ping => "PING",
+            Kind::Get => "GET",
+            Kind::Set => "SET",
+            Kind::Incr => "INCR",
+            Kind::Mget => "MGET",
+            Kind::Del => "DEL",
+        }
+    }
+
+    /// Position in `ALL`, for per-command tables
+    pub fn index(self) -> usize {
+        Kind::ALL.iter().position(|&kind| kind == self).unwrap()
+    }
+
+    /// Whether the command reads the `key:N` keys, which are then worth
+    /// populating before the run
+    pub fn reads_keys(self) -> bool {
+        matches!(self, Kind::Get | Kind::Mget)
+    }
+}
+
+/// Parse `name=weight` pairs separated by commas; a bare name weighs 1.
+/// Zero weights are dropped.
+fn parse_weights<T>(spec: &str, mut item: impl FnMut(&str) -> Result<T>) -> Result<Vec<(T, u32)>> {
+    let mut weights = Vec::new();
+    for part in spec.split(',') {
+        let (name, weight) = part.split_once('=').unwrap_or((part, "1"));
+        let item = item(name.trim())?;
+        let weight: u32 = weight
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid weight '{}' for {}", weight, name.trim()))?;
+        if weight > 0 {
+            weights.push((item, weight));
+        }
+    }
+    Ok(weights)
+}
+
+/// Pick from `weights`, summing to `total`, with probability proportional
+/// to each weight
+fn pick_weighted<T: Copy>(weights: &[(T, u32)], total: u32, rng: &mut Rng) -> T {
+    let mut roll = rng.below(total as u64) as u32;
+    for &(item, weight) in weights {
+        if roll < weight {
+            return item;
+        }
+        roll -= weight;
+    }
+    unreachable!("roll is below the total weight")
+}
+
+/// Commands to send and their relative weights, e.g. `get=80,set=20`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mix {
+    weights: Vec<(Kind, u32)>,
+    total: u32,
+}
+
+impl Mix {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let weights = parse_weights(spec, |name| {
+            Kind::ALL
+                .into_iter()
+                .find(|kind| kind.name().eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow!("Unknown command '{}' in the mix", name))
+        })?;
+        let total = weights.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            bail!("The command mix is empty");
+        }
+        Ok(Self { weights, total })
+    }
+
+    pub fn kinds(&self) -> impl Iterator<Item = Kind> + '_ {
+        self.weights.iter().map(|(kind, _)| *kind)
+    }
+
+    /// Pick a command with probability proportional to its weight
+    pub fn pick(&self, rng: &mut Rng) -> Kind {
+        pick_weighted(&self.weights, self.total, rng)
+    }
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self::parse("get=1,set=1").unwrap()
+    }
+}
+
+/// xorshift64*, plenty for picking keys and commands
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        // Multiply-shift rather than modulo, which would favor low values
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+
+    /// Uniform in `[0, 1)`
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Sizes of SET values: `64`, a uniform range `16-4096`, or weighted sizes
+/// like `64=90,4096=10`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueSize {
+    Fixed(usize),
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+    Weighted {
+        sizes: Vec<(usize, u32)>,
+        total: u32,
+    },
+}
+
+impl ValueSize {
+    pub fn parse(spec: &str) -> Result<Self> {
+        fn size(value: &str) -> Result<usize> {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value size '{}'", value))
+        }
+
+        if spec.contains('=') || spec.contains(',') {
+            let sizes = parse_weights(spec, size)?;
+            let total = sizes.iter().map(|(_, weight)| weight).sum();
+            if total == 0 {
+                bail!("The value size distribution is empty");
+            }
+            return Ok(ValueSize::Weighted { sizes, total });
+        }
+        if let Some((min, max)) = spec.split_once('-') {
+            let (min, max) = (size(min)?, size(max)?);
+            if min > max {
+                bail!("Invalid value size range '{}'", spec);
+            }
+            return Ok(ValueSize::Uniform { min, max });
+        }
+        Ok(ValueSize::Fixed(size(spec)?))
+    }
+
+    /// The largest size this can pick
+    pub fn max(&self) -> usize {
+        match self {
+            ValueSize::Fixed(size) => *size,
+            ValueSize::Uniform { max, .. } => *max,
+            ValueSize::Weighted { sizes, .. } => {
+                sizes.iter().map(|(size, _)| *size).max().unwrap_or(0)
+            }
+        }
+    }
+
+    pub fn pick(&self, rng: &mut Rng) -> usize {
+        match self {
+            ValueSize::Fixed(size) => *size,
+            ValueSize::Uniform { min, max } => min + rng.below((max - min) as u64 + 1) as usize,
+            ValueSize::Weighted { sizes, total } => pick_weighted(sizes, *total, rng),
+        }
+    }
+}
+
+impl Default for ValueSize {
+    fn default() -> Self {
+        ValueSize::Fixed(64)
+    }
+}
+
+impl fmt::Display for ValueSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueSize::Fixed(size) => write!(f, "{} byte", size),
+            ValueSize::Uniform { min, max } => write!(f, "{}-{} byte", min, max),
+            ValueSize::Weighted { sizes, .. } => {
+                let sizes: Vec<String> = sizes
+                    .iter()
+                    .map(|(size, weight)| format!("{}={}", size, weight))
+                    .collect();
+                write!(f, "{} byte", sizes.join(","))
+            }
+        }
+    }
+}
+
+/// How keys are drawn from the keyspace
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum KeyDist {
+    /// Every key equally likely
+    #[default]
+    Uniform,
+    /// Key `n` drawn with probability proportional to `1 / (n + 1)^s`, the
+    /// skew of real cache traffic; YCSB uses s = 0.99
+    Zipf(f64),
+    /// `traffic` percent of requests go to the first `keys` percent of the
+    /// keyspace, the rest spread over the remainder
+    Hot { keys: f64, traffic: f64 },
+}
+
+impl KeyDist {
+    /// `uniform`, `zipf`, `zipf:<s>` with 0 < s < 1, or
+    /// `hot:<percent of keys>:<percent of traffic>`
+    pub fn parse(spec: &str) -> Result<Self> {
+        fn percent(value: &str) -> Result<f64> {
+            match value.trim().parse() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+                _ => bail!("Invalid percentage '{}'", value),
+            }
+        }
+
+        let mut parts = spec.split(':');
+        let dist = match (parts.next(), parts.next(), parts.next()) {
+            (Some("uniform"), None, None) => KeyDist::Uniform,
+            (Some("zipf"), None, None) => KeyDist::Zipf(0.99),
+            (Some("zipf"), Some(s), None) => match s.parse() {
+                Ok(s) if s > 0.0 && s < 1.0 => KeyDist::Zipf(s),
+                _ => bail!("The zipf exponent must be between 0 and 1, not '{}'", s),
+            },
+            (Some("hot"), Some(keys), Some(traffic)) => KeyDist::Hot {
+                keys: percent(keys)?,
+                traffic: percent(traffic)?,
+            },
+            _ => bail!("Unknown key distribution '{}'", spec),
+        };
+        if parts.next().is_some() {
+            bail!("Unknown key distribution '{}'", spec);
+        }
+        Ok(dist)
+    }
+}
+
+impl fmt::Display for KeyDist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyDist::Uniform => write!(f, "uniform"),
+            KeyDist::Zipf(s) => write!(f, "zipf:{}", s),
+            KeyDist::Hot { keys, traffic } => write!(f, "hot:{}:{}", keys, traffic),
+        }
+    }
+}
+
+/// Draws key numbers in `0..keys` following a `KeyDist`
+#[derive(Debug, Clone)]
+enum KeyPicker {
+    Uniform,
+    /// Gray et al.'s constant-time Zipfian generator, as in YCSB
+    Zipf {
+        theta: f64,
+        zetan: f64,
+        alpha: f64,
+        eta: f64,
+    },
+    /// Keys below `hot` take `traffic` of the requests
+    Hot {
+        hot: u64,
+        traffic: f64,
+    },
+}
+
+impl KeyPicker {
+    fn new(dist: KeyDist, keys: u64) -> Self {
+        match dist {
+            KeyDist::Uniform => KeyPicker::Uniform,
+            KeyDist::Zipf(theta) => {
+                let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+                let zetan = zeta(keys);
+                let eta = (1.0 - (2.0 / keys as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan);
+                KeyPicker::Zipf {
+                    theta,
+                    zetan,
+                    alpha: 1.0 / (1.0 - theta),
+                    eta,
+                }
+            }
+            KeyDist::Hot {
+                keys: percent,
+                traffic,
+            } => KeyPicker::Hot {
+                hot: ((keys as f64 * percent / 100.0).ceil() as u64).clamp(1, keys),
+                traffic: traffic / 100.0,
+            },
+        }
+    }
+
+    fn pick(&self, keys: u64, rng: &mut Rng) -> u64 {
+        match *self {
+            KeyPicker::Uniform => rng.below(keys),
+            KeyPicker::Zipf {
+                theta,
+                zetan,
+                alpha,
+                eta,
+            } => {
+                let u = rng.unit();
+                let uz = u * zetan;
+                if uz < 1.0 {
+                    return 0;
+                }
+                if uz < 1.0 + 0.5f64.powf(theta) {
+                    return 1.min(keys - 1);
+                }
+                ((keys as f64 * (eta * u - eta + 1.0).powf(alpha)) as u64).min(keys - 1)
+            }
+            KeyPicker::Hot { hot, traffic } => {
+                if hot == keys || rng.unit() < traffic {
+                    rng.below(hot)
+                } else {
+                    hot + rng.below(keys - hot)
+                }
+            }
+        }
+    }
+}
+
+/// Builds the arguments of each command sent
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub mix: Mix,
+    /// Keys are `key:0` to `key:{keys - 1}`
+    pub keys: u64,
+    key_picker: KeyPicker,
+    value_size: ValueSize,
+    /// A value of the largest size; smaller values are slices of it
+    values: Bytes,
+}
+
+impl Workload {
+    pub fn new(mix: Mix, keys: u64, key_dist: KeyDist, value_size: ValueSize) -> Self {
+        let keys = keys.max(1);
+        Self {
+            mix,
+            keys,
+            key_picker: KeyPicker::new(key_dist, keys),
+            values: Bytes::from(vec![b'x'; value_size.max()]),
+            value_size,
+        }
+    }
+
+    /// A key number drawn from the key distribution
+    pub fn next_key(&self, rng: &mut Rng) -> u64 {
+        self.key_picker.pick(self.keys, rng)
+    }
+
+    /// A value of a size drawn from the value size distribution
+    pub fn next_value(&self, rng: &mut Rng) -> Bytes {
+        self.values.slice(..self.value_size.pick(rng))
+    }
+
+    /// Pick the next command and build its arguments
+    pub fn next_command(&self, rng: &mut Rng) -> (Kind, Vec<Bytes>) {
+        let kind = self.mix.pick(rng);
+        let key = |rng: &mut Rng| Bytes::from(format!("key:{}", self.next_key(rng)));
+        let args = match kind {
+            Kind::Ping => vec![Bytes::from_static(b"PING")],
+            Kind::Get => vec![Bytes::from_static(b"GET"), key(rng)],
+            Kind::Set => vec![Bytes::from_static(b"SET"), key(rng), self.next_value(rng)],
+            // Separate keys, as INCR fails on the `key:N` string values
+            Kind::Incr => vec![
+                Bytes::from_static(b"INCR"),
+                Bytes::from(format!("counter:{}", self.next_key(rng))),
+            ],
+            Kind::Mget => std::iter::once(Bytes::from_static(b"MGET"))
+                .chain((0..MGET_KEYS).map(|_| key(rng)))
+                .collect(),
+            Kind::Del => vec![Bytes::from_static(b"DEL"), key(rng)],
+        };
+        (kind, args)
+    }
+
+    /// Whether any command in the mix reads keys that should exist first
+    pub fn needs_population(&self) -> bool {
+        self.mix.kinds().any(Kind::reads_keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mixes() {
+        let mix = Mix::parse("GET=3, set=1,ping,del=0").unwrap();
+        assert_eq!(
+            mix.kinds().collect::<Vec<_>>(),
+            [Kind::Get, Kind::Set, Kind::Ping]
+        );
+        assert!(Mix::parse("get=x").is_err());
+        assert!(Mix::parse("flushall").is_err());
+        assert!(Mix::parse("get=0").is_err());
+    }
+
+    #[test]
+    fn picks_by_weight() {
+        let mix = Mix::parse("get=3,set=1").unwrap();
+        let mut rng = Rng::new(7);
+        let gets = (0..10_000)
+            .filter(|_| mix.pick(&mut rng) == Kind::Get)
+            .count();
+        assert!((7_000..8_000).contains(&gets), "{}", gets);
+    }
+
+    #[test]
+    fn builds_commands_over_the_keyspace() {
+        let workload = Workload::new(
+            Mix::parse("set").unwrap(),
+            5,
+            KeyDist::Uniform,
+            ValueSize::Fixed(3),
+        );
+        let mut rng = Rng::new(1);
+        for _ in 0..100 {
+            let (kind, args) = workload.next_command(&mut rng);
+            assert_eq!(kind, Kind::Set);
+            let key = std::str::from_utf8(&args[1]).unwrap();
+            let n: u64 = key.strip_prefix("key:").unwrap().parse().unwrap();
+            assert!(n < 5);
+            assert_eq!(&args[2][..], b"xxx");
+        }
+        assert!(!workload.needs_population());
+        let workload = Workload::new(Mix::default(), 5, KeyDist::Uniform, ValueSize::Fixed(3));
+        assert!(workload.needs_population());
+    }
+
+    #[test]
+    fn parses_value_sizes() {
+        assert_eq!(ValueSize::parse("128").unwrap(), ValueSize::Fixed(128));
+        assert_eq!(
+            ValueSize::parse("16-4096").unwrap(),
+            ValueSize::Uniform { min: 16, max: 4096 }
+        );
+        let weighted = ValueSize::parse("64=9,4096=1").unwrap();
+        assert_eq!(weighted.max(), 4096);
+        assert_eq!(weighted.to_string(), "64=9,4096=1 byte");
+        for bad in ["x", "10-5", "64=x", "64=0"] {
+            assert!(ValueSize::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn draws_value_sizes() {
+        let mut rng = Rng::new(3);
+        let uniform = ValueSize::Uniform { min: 10, max: 20 };
+        let sizes: Vec<usize> = (0..1000).map(|_| uniform.pick(&mut rng)).collect();
+        assert!(sizes.iter().all(|size| (10..=20).contains(size)));
+        assert!(sizes.contains(&10) && sizes.contains(&20));
+
+        let workload = Workload::new(
+            Mix::parse("set").unwrap(),
+            5,
+            KeyDist::Uniform,
+            ValueSize::parse("1=3,100=1").unwrap(),
+        );
+        let large = (0..10_000)
+            .filter(|_| workload.next_value(&mut rng).len() == 100)
+            .count();
+        assert!((2_000..3_000).contains(&large), "{}", large);
+    }
+
+    #[test]
+    fn parses_key_distributions() {
+        assert_eq!(KeyDist::parse("uniform").unwrap(), KeyDist::Uniform);
+        assert_eq!(KeyDist::parse("zipf").unwrap(), KeyDist::Zipf(0.99));
+        assert_eq!(KeyDist::parse("zipf:0.5").unwrap(), KeyDist::Zipf(0.5));
+        assert_eq!(
+            KeyDist::parse("hot:1:90").unwrap(),
+            KeyDist::Hot {
+                keys: 1.0,
+                traffic: 90.0
+            }
+        );
+        for bad in [
+            "zipf:1.2",
+            "zipf:x",
+            "hot:1",
+            "hot:1:101",
+            "gauss",
+            "uniform:1",
+        ] {
+            assert!(KeyDist::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    /// How many of `draws` keys out of 1000 fall in the 10 lowest
+    fn top_ten(dist: KeyDist, draws: usize) -> usize {
+        let workload = Workload::new(Mix::default(), 1000, dist, ValueSize::default());
+        let mut rng = Rng::new(11);
+        (0..draws)
+            .map(|_| workload.next_key(&mut rng))
+            .inspect(|&key| assert!(key < 1000))
+            .filter(|&key| key < 10)
+            .count()
+    }
+
+    #[test]
+    fn skews_keys() {
+        // Uniform: 1% of the keys get about 1% of the traffic
+        let uniform = top_ten(KeyDist::Uniform, 100_000);
+        assert!((800..1_200).contains(&uniform), "{}", uniform);
+
+        // Zipf 0.99 over 1000 keys: the 10 hottest get about 39%
+        let zipf = top_ten(KeyDist::Zipf(0.99), 100_000);
+        assert!((36_000..42_000).contains(&zipf), "{}", zipf);
+
+        let hot = top_ten(
+            KeyDist::Hot {
+                keys: 1.0,
+                traffic: 90.0,
+            },
+            100_000,
+        );
+        assert!((89_000..91_000).contains(&hot), "{}", hot);
+    }
+}
@@ -0,0 +1,518 @@
+//! rudis-cli, a small redis-cli for rudis (or Redis). Runs the command given
+//! on the command line, or an interactive prompt with history, or with
+//! `--pipe` sends the raw protocol read from stdin, for mass insertion.
+
+use anyhow::{Result, anyhow, bail};
+use bytes::BytesMut;
+use rudis::RespValue;
+use rudis::client::Client;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use std::fmt::Write as _;
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+const USAGE: &str = "\
+Usage: rudis-cli [OPTIONS] [cmd [arg [arg ...]]]
+  -h <hostname>  Server hostname (default: 127.0.0.1)
+  -p <port>      Server port (default: 6379)
+  --raw          Print replies as they are, even on a terminal
+  --no-raw       Format replies for reading, even when not on a terminal
+  --pipe         Send raw RESP read from stdin to the server
+  --help         Show this help
+
+Without a command, starts an interactive prompt. Its history is kept in
+~/.rudiscli_history.
+";
+
+/// History file in the home directory, like redis-cli's ~/.rediscli_history
+const HISTORY_FILE: &str = ".rudiscli_history";
+
+/// How replies are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    /// Like redis-cli on a terminal: `(integer) 1`, quoted strings, numbered arrays
+    Pretty,
+    /// Like redis-cli in a pipe: values only, one per line
+    Raw,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    host: String,
+    port: u16,
+    /// None picks by whether stdout is a terminal
+    output: Option<Output>,
+    pipe: bool,
+    command: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            output: None,
+            pipe: false,
+            command: Vec::new(),
+        }
+    }
+}
+
+/// Parse the command line; None asks for the usage text
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| anyhow!("Option '{}' needs a value", flag))
+        };
+        match arg.as_str() {
+            "-h" => options.host = value("-h")?,
+            "-p" => {
+                let port = value("-p")?;
+                options.port = port
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid port '{}'", port))?;
+            }
+            "--raw" => options.output = Some(Output::Raw),
+            "--no-raw" => options.output = Some(Output::Pretty),
+            "--pipe" => options.pipe = true,
+            "--help" => return Ok(None),
+            flag if flag.starts_with('-') && options.command.is_empty() => {
+                bail!("Unrecognized option '{}'", flag)
+            }
+            _ => {
+                options.command.push(arg);
+                options.command.extend(args.by_ref());
+            }
+        }
+    }
+    Ok(Some(options))
+}
+
+fn main() -> Result<()> {
+    let Some(options) = parse_args(std::env::args().skip(1))? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    let addr = if options.host.contains(':') {
+        format!("[{}]:{}", options.host, options.port)
+    } else {
+        format!("{}:{}", options.host, options.port)
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    if options.pipe {
+        let mut input = Vec::new();
+        std::io::stdin().read_to_end(&mut input)?;
+        let (replies, errors) = runtime.block_on(pipe(&addr, input))?;
+        println!("errors: {}, replies: {}", errors, replies);
+        if errors > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let output = options
+        .output
+        .unwrap_or(if std::io::stdout().is_terminal() {
+            Output::Pretty
+        } else {
+            Output::Raw
+        });
+    if options.command.is_empty() {
+        return repl(&runtime, &addr, output);
+    }
+    let mut client = runtime
+        .block_on(Client::connect(&addr))
+        .map_err(|e| anyhow!("Could not connect to {}: {}", addr, e))?;
+    match runtime.block_on(client.command(&options.command)) {
+        Ok(reply) => println!("{}", format_reply(&reply, output)),
+        Err(_) if is_shutdown(options.command[0].as_bytes()) => {}
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// SHUTDOWN succeeds by closing the connection without a reply
+fn is_shutdown(command: &[u8]) -> bool {
+    command.eq_ignore_ascii_case(b"shutdown")
+}
+
+/// Read commands from the prompt until `quit`, `exit`, Ctrl-C or Ctrl-D,
+/// reconnecting whenever the connection was lost
+fn repl(runtime: &Runtime, addr: &str, output: Output) -> Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(path) = &history {
+        // Missing on first use
+        let _ = editor.load_history(path);
+    }
+
+    let connect = || match runtime.block_on(Client::connect(addr)) {
+        Ok(client) => Some(client),
+        Err(e) => {
+            eprintln!("Could not connect to {}: {}", addr, e);
+            None
+        }
+    };
+    let mut client = connect();
+    loop {
+        let prompt = match client {
+            Some(_) => format!("{}> ", addr),
+            None => "not connected> ".to_string(),
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let args = match split_args(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("Invalid argument(s): {}", e);
+                continue;
+            }
+        };
+        editor.add_history_entry(line.as_str())?;
+        if args[0].eq_ignore_ascii_case(b"quit") || args[0].eq_ignore_ascii_case(b"exit") {
+            break;
+        }
+
+        if client.is_none() {
+            client = connect();
+        }
+        let Some(connection) = client.as_mut() else {
+            continue;
+        };
+        match runtime.block_on(connection.command(&args)) {
+            Ok(reply) => println!("{}", format_reply(&reply, output)),
+            Err(_) if is_shutdown(&args[0]) => client = None,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                client = None;
+            }
+        }
+    }
+
+    if let Some(path) = &history
+        && let Err(e) = editor.save_history(path)
+    {
+        eprintln!("Could not save history to {}: {}", path.display(), e);
+    }
+    Ok(())
+}
+
+/// Send `input`, raw protocol, followed by an ECHO of a unique marker, and
+/// count the replies until the marker comes back, like `redis-cli --pipe`.
+/// Returns the replies and how many of them were errors, which are printed.
+async fn pipe(addr: &str, mut input: Vec<u8>) -> Result<(usize, usize)> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let marker = format!("rudis-cli-pipe-{}-{}", std::process::id(), nanos);
+    let echo = RespValue::Array(Some(vec![
+        RespValue::BulkString(Some("ECHO".into())),
+        RespValue::BulkString(Some(marker.clone().into())),
+    ]));
+    input.extend_from_slice(&echo.serialize());
+
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|e| anyhow!("Could not connect to {}: {}", addr, e))?;
+    let (mut reader, mut writer) = stream.into_split();
+    // Write and read at once, so neither side's buffers fill up and stall
+    let send = async move {
+        writer.write_all(&input).await?;
+        eprintln!("All data transferred. Waiting for the last reply...");
+        // Dropping the writer would close the connection before the replies
+        Ok::<_, anyhow::Error>(writer)
+    };
+    let receive = async {
+        let mut buffer = BytesMut::with_capacity(16 * 1024);
+        let (mut replies, mut errors) = (0, 0);
+        loop {
+            while let Some((reply, _)) = RespValue::parse(&mut buffer)? {
+                match reply {
+                    RespValue::BulkString(Some(echoed)) if echoed == marker.as_bytes() => {
+                        eprintln!("Last reply received from server.");
+                        return Ok((replies, errors));
+                    }
+                    RespValue::Error(message) => {
+                        eprintln!("{}", message);
+                        errors += 1;
+                    }
+                    _ => {}
+                }
+                replies += 1;
+            }
+            if reader.read_buf(&mut buffer).await? == 0 {
+                bail!("Connection closed before the last reply");
+            }
+        }
+    };
+    let (sent, received) = tokio::join!(send, receive);
+    sent?;
+    received
+}
+
+/// Split a prompt line into arguments like redis-cli: whitespace separates
+/// them, double quotes allow `\n`, `\t`, `\xHH` and similar escapes, single
+/// quotes only `\'`
+fn split_args(line: &str) -> Result<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut bytes = line.bytes().peekable();
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        let Some(&first) = bytes.peek() else {
+            return Ok(args);
+        };
+        let mut arg = Vec::new();
+        match first {
+            b'"' => {
+                bytes.next();
+                loop {
+                    match bytes.next().ok_or_else(|| anyhow!("unbalanced quotes"))? {
+                        b'"' => break,
+                        b'\\' => {
+                            let escaped =
+                                bytes.next().ok_or_else(|| anyhow!("unbalanced quotes"))?;
+                            arg.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'a' => 0x07,
+                                b'b' => 0x08,
+                                b'x' => {
+                                    let hex = [bytes.next(), bytes.next()];
+                                    let hex = hex.map(|digit| digit.unwrap_or_default() as char);
+                                    let hex: String = hex.iter().collect();
+                                    u8::from_str_radix(&hex, 16)
+                                        .map_err(|_| anyhow!("invalid \\x escape"))?
+                                }
+                                other => other,
+                            });
+                        }
+                        byte => arg.push(byte),
+                    }
+                }
+            }
+            b'\'' => {
+                bytes.next();
+                loop {
+                    match bytes.next().ok_or_else(|| anyhow!("unbalanced quotes"))? {
+                        b'\'' => break,
+                        b'\\' if bytes.peek() == Some(&b'\'') => {
+                            bytes.next();
+                            arg.push(b'\'');
+                        }
+                        byte => arg.push(byte),
+                    }
+                }
+            }
+            _ => {
+                while let Some(byte) = bytes.next_if(|byte| !byte.is_ascii_whitespace()) {
+                    arg.push(byte);
+                }
+            }
+        }
+        if matches!(first, b'"' | b'\'') && bytes.peek().is_some_and(|b| !b.is_ascii_whitespace()) {
+            bail!("closing quote must be followed by a space");
+        }
+        args.push(arg);
+    }
+}
+
+fn format_reply(reply: &RespValue, output: Output) -> String {
+    let mut text = String::new();
+    match output {
+        Output::Pretty => write_pretty(&mut text, reply, 0),
+        Output::Raw => write_raw(&mut text, reply),
+    }
+    text
+}
+
+/// Write `reply` as redis-cli does on a terminal; nested array items line up
+/// under their parent's first item, `indent` columns in
+fn write_pretty(out: &mut String, reply: &RespValue, indent: usize) {
+    match reply {
+        RespValue::SimpleString(s) => out.push_str(s),
+        RespValue::Error(e) => {
+            let _ = write!(out, "(error) {}", e);
+        }
+        RespValue::Integer(n) => {
+            let _ = write!(out, "(integer) {}", n);
+        }
+        RespValue::BulkString(None) | RespValue::Array(None) => out.push_str("(nil)"),
+        RespValue::BulkString(Some(bytes)) => write_quoted(out, bytes),
+        RespValue::Array(Some(items)) if items.is_empty() => out.push_str("(empty array)"),
+        RespValue::Array(Some(items)) => {
+            let width = items.len().to_string().len();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                let label = format!("{:>width$}) ", i + 1);
+                out.push_str(&label);
+                write_pretty(out, item, indent + label.len());
+            }
+        }
+    }
+}
+
+/// Write a bulk string in double quotes, escaping anything unprintable
+fn write_quoted(out: &mut String, bytes: &[u8]) {
+    out.push('"');
+    for &byte in bytes {
+        match byte {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\x{:02x}", byte);
+            }
+        }
+    }
+    out.push('"');
+}
+
+/// Write `reply` as redis-cli does into a pipe: bare values, one per line
+fn write_raw(out: &mut String, reply: &RespValue) {
+    match reply {
+        RespValue::SimpleString(s) | RespValue::Error(s) => out.push_str(s),
+        RespValue::Integer(n) => {
+            let _ = write!(out, "{}", n);
+        }
+        RespValue::BulkString(None) | RespValue::Array(None) => {}
+        RespValue::BulkString(Some(bytes)) => out.push_str(&String::from_utf8_lossy(bytes)),
+        RespValue::Array(Some(items)) => {
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                }
+                write_raw(out, item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rudis::Server;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn bulk(value: &str) -> RespValue {
+        RespValue::BulkString(Some(value.to_string().into()))
+    }
+
+    #[test]
+    fn parses_options_then_command() {
+        let options = parse_args(args(&[
+            "-h", "::1", "-p", "6380", "--raw", "SET", "k", "-1",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.host, "::1");
+        assert_eq!(options.port, 6380);
+        assert_eq!(options.output, Some(Output::Raw));
+        assert_eq!(options.command, ["SET", "k", "-1"]);
+
+        assert_eq!(parse_args(args(&[])).unwrap(), Some(Options::default()));
+        assert_eq!(parse_args(args(&["--help"])).unwrap(), None);
+        assert!(parse_args(args(&["-p", "http"])).is_err());
+        assert!(parse_args(args(&["-p"])).is_err());
+        assert!(parse_args(args(&["--bogus"])).is_err());
+    }
+
+    #[test]
+    fn splits_quoted_arguments() {
+        let split = |line: &str| split_args(line).unwrap();
+        assert_eq!(split("  set  key value "), [&b"set"[..], b"key", b"value"]);
+        assert_eq!(
+            split(r#"set "hello world" 'it\'s'"#),
+            [&b"set"[..], b"hello world", b"it's"]
+        );
+        assert_eq!(split(r#""a\n\"b\"\x41""#), [&b"a\n\"b\"A"[..]]);
+        assert_eq!(split(r#"'no\nescape'"#), [&br"no\nescape"[..]]);
+        assert!(split("").is_empty());
+
+        assert!(split_args(r#"get "unterminated"#).is_err());
+        assert!(split_args(r#"get "a"b"#).is_err());
+        assert!(split_args(r#""\xZZ""#).is_err());
+    }
+
+    #[test]
+    fn formats_like_redis_cli() {
+        let nested = RespValue::Array(Some(vec![
+            bulk("a"),
+            RespValue::Array(Some(vec![
+                RespValue::Integer(1),
+                RespValue::BulkString(None),
+            ])),
+            RespValue::Array(Some(vec![])),
+        ]));
+        assert_eq!(
+            format_reply(&nested, Output::Pretty),
+            "1) \"a\"\n2) 1) (integer) 1\n   2) (nil)\n3) (empty array)"
+        );
+        assert_eq!(format_reply(&nested, Output::Raw), "a\n1\n\n");
+
+        let ten = RespValue::Array(Some((0..10).map(|_| bulk("x")).collect()));
+        let pretty = format_reply(&ten, Output::Pretty);
+        assert!(pretty.starts_with(" 1) \"x\"\n 2)"));
+        assert!(pretty.ends_with("\n10) \"x\""));
+
+        assert_eq!(
+            format_reply(&bulk("tab\there \u{1}"), Output::Pretty),
+            r#""tab\there \x01""#
+        );
+        let error = RespValue::Error("ERR nope".to_string());
+        assert_eq!(format_reply(&error, Output::Pretty), "(error) ERR nope");
+        assert_eq!(format_reply(&error, Output::Raw), "ERR nope");
+        let ok = RespValue::SimpleString("OK".to_string());
+        assert_eq!(format_reply(&ok, Output::Pretty), "OK");
+    }
+
+    #[tokio::test]
+    async fn pipes_raw_protocol() {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let store = server.store().clone();
+        tokio::spawn(async move { server.run().await });
+
+        let mut input = Vec::new();
+        for i in 0..1000 {
+            let set = RespValue::Array(Some(vec![
+                bulk("SET"),
+                bulk(&format!("key:{}", i)),
+                bulk("value"),
+            ]));
+            input.extend_from_slice(&set.serialize());
+        }
+        input.extend_from_slice(b"INCR key:0\r\n");
+
+        let (replies, errors) = pipe(&addr.to_string(), input).await.unwrap();
+        assert_eq!((replies, errors), (1001, 1));
+        assert_eq!(store.keys(b"key:*").await.len(), 1000);
+    }
+}
@@ -0,0 +1,209 @@
+//! Parking for blocking commands. A command that has to wait parks its
+//! client on the keys it waits for; every write to a key wakes the client
+//! that has waited longest on it, which re-checks and either is served or
+//! goes back to sleep without losing its place. Clients parked on no keys
+//! only wait for their timeout.
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Clients parked by blocking commands, in arrival order per key
+#[derive(Debug, Default)]
+pub struct Waiters {
+    queues: Mutex<HashMap<Bytes, VecDeque<Arc<Waiter>>>>,
+    /// Clients parked, so writes skip the lock while there are none
+    parked: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Waiter {
+    notify: Notify,
+    /// Key this waiter was woken for and hasn't gone back to sleep since.
+    /// While set it gets no other wakeups; if it leaves without going back
+    /// to sleep, the wakeup is handed on to the next waiter for the key.
+    woken_on: Mutex<Option<Bytes>>,
+}
+
+impl Waiters {
+    /// Park a client on `keys`. Park before checking whether the command
+    /// can be served, so a write made in between isn't missed.
+    pub fn park(&self, keys: &[Bytes]) -> Parked<'_> {
+        let waiter = Arc::new(Waiter::default());
+        let mut queues = self.queues.lock().unwrap();
+        for key in keys {
+            queues
+                .entry(key.clone())
+                .or_default()
+                .push_back(waiter.clone());
+        }
+        self.parked.fetch_add(1, Ordering::Relaxed);
+        Parked {
+            waiters: self,
+            waiter,
+            keys: keys.to_vec(),
+            awake: false,
+        }
+    }
+
+    /// Wake the longest parked client waiting on `key`, unless one it
+    /// already woke is still handling the write
+    pub fn wake(&self, key: &[u8]) {
+        if self.parked.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let queues = self.queues.lock().unwrap();
+        let Some(queue) = queues.get(key) else {
+            return;
+        };
+        for waiter in queue {
+            let mut woken_on = waiter.woken_on.lock().unwrap();
+            match &*woken_on {
+                Some(woken) if woken == key => return,
+                Some(_) => continue,
+                None => {
+                    *woken_on = Some(Bytes::copy_from_slice(key));
+                    waiter.notify.notify_one();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Clients currently parked
+    pub fn parked(&self) -> usize {
+        self.parked.load(Ordering::Relaxed)
+    }
+}
+
+/// A client parked on some keys; dropping it unparks the client
+#[derive(Debug)]
+pub struct Parked<'a> {
+    waiters: &'a Waiters,
+    waiter: Arc<Waiter>,
+    keys: Vec<Bytes>,
+    /// Whether `woken` last returned a key, which the client then handled
+    awake: bool,
+}
+
+impl Parked<'_> {
+    /// Sleep until one of the keys is written, returning it, or until
+    /// `deadline` passes, returning None. No deadline waits forever.
+    pub async fn woken(&mut self, deadline: Option<Instant>) -> Option<Bytes> {
+        if std::mem::take(&mut self.awake) {
+            // Back to sleep: the last wakeup didn't serve the command
+            self.waiter.woken_on.lock().unwrap().take();
+        }
+        let notified = self.waiter.notify.notified();
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, notified).await.ok()?,
+            None => notified.await,
+        }
+        let woken_on = self.waiter.woken_on.lock().unwrap().clone();
+        self.awake = woken_on.is_some();
+        woken_on
+    }
+}
+
+impl Drop for Parked<'_> {
+    fn drop(&mut self) {
+        {
+            let mut queues = self.waiters.queues.lock().unwrap();
+            for key in &self.keys {
+                if let Some(queue) = queues.get_mut(key) {
+                    queue.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+                    if queue.is_empty() {
+                        queues.remove(key);
+                    }
+                }
+            }
+        }
+        self.waiters.parked.fetch_sub(1, Ordering::Relaxed);
+        // Served or gave up while holding a wakeup: whatever was written
+        // may be for the next client in line
+        let woken_on = self.waiter.woken_on.lock().unwrap().take();
+        if let Some(key) = woken_on {
+            self.waiters.wake(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn keys(names: &[&str]) -> Vec<Bytes> {
+        names
+            .iter()
+            .map(|name| Bytes::from(name.to_string()))
+            .collect()
+    }
+
+    fn soon() -> Option<Instant> {
+        Some(Instant::now() + Duration::from_millis(50))
+    }
+
+    #[tokio::test]
+    async fn wakes_on_a_write_to_any_key() {
+        let waiters = Waiters::default();
+        let mut parked = waiters.park(&keys(&["a", "b"]));
+        assert_eq!(waiters.parked(), 1);
+
+        waiters.wake(b"other");
+        assert_eq!(parked.woken(soon()).await, None);
+        // A write made before the client sleeps still wakes it
+        waiters.wake(b"b");
+        assert_eq!(parked.woken(None).await, Some(Bytes::from("b")));
+
+        drop(parked);
+        assert_eq!(waiters.parked(), 0);
+        assert!(waiters.queues.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn serves_clients_in_arrival_order() {
+        let waiters = Waiters::default();
+        let mut first = waiters.park(&keys(&["list"]));
+        let mut second = waiters.park(&keys(&["list"]));
+
+        waiters.wake(b"list");
+        // Only the first is woken, and further writes wait for it
+        waiters.wake(b"list");
+        assert_eq!(second.woken(soon()).await, None);
+        assert_eq!(first.woken(soon()).await, Some(Bytes::from("list")));
+
+        // Once served, the first hands the wakeup on
+        drop(first);
+        assert_eq!(second.woken(soon()).await, Some(Bytes::from("list")));
+    }
+
+    #[tokio::test]
+    async fn a_client_going_back_to_sleep_keeps_its_place() {
+        let waiters = Waiters::default();
+        let mut first = waiters.park(&keys(&["list"]));
+        let mut second = waiters.park(&keys(&["list"]));
+
+        waiters.wake(b"list");
+        assert!(first.woken(soon()).await.is_some());
+        // Not served: back to sleep, and the next write is still its own
+        let (again, ()) = tokio::join!(first.woken(None), async {
+            tokio::task::yield_now().await;
+            waiters.wake(b"list");
+        });
+        assert_eq!(again, Some(Bytes::from("list")));
+        assert_eq!(second.woken(soon()).await, None);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_keys() {
+        let waiters = Waiters::default();
+        let mut parked = waiters.park(&[]);
+        let start = Instant::now();
+        assert_eq!(parked.woken(soon()).await, None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,155 @@
+//! Connection read buffers, pooled per thread like Redis's reusable query
+//! buffer. A connection holds a buffer only while its socket has data to
+//! read, and hands it back once it has run every command in it and finds
+//! nothing more waiting, so idle connections, however many, hold none.
+//! Buffers that grew large for a big request or a bulk load are dropped
+//! rather than pooled, and so are ones worn too small by the frames split
+//! off them.
+//!
+//! How much a connection reads at a time adapts to it: from
+//! `client-read-buffer-low`, it doubles while reads keep filling the
+//! buffer, up to `client-read-buffer-high`, and falls back once the
+//! connection has been idle for `SHRINK_AFTER`.
+
+use bytes::BytesMut;
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// Idle time after which a connection's read size falls back to the low mark
+pub(crate) const SHRINK_AFTER: Duration = Duration::from_secs(1);
+
+/// Buffers with less room than this left are dropped instead of pooled
+const MIN_POOLED_CAPACITY: usize = 1024;
+
+/// Buffers that grew past this are dropped instead of pooled
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+/// Most buffers pooled on each thread
+const POOL_DEPTH: usize = 256;
+
+thread_local! {
+    static POOL: RefCell<Vec<BytesMut>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A buffer to read into: a pooled one, or a new one of `capacity` if none
+/// is free
+pub(crate) fn acquire(capacity: usize) -> BytesMut {
+    POOL.with_borrow_mut(|pool| pool.pop())
+        .unwrap_or_else(|| BytesMut::with_capacity(capacity))
+}
+
+/// Return an emptied buffer to this thread's pool, leaving an unallocated
+/// one in its place. Buffers still holding unparsed data are kept.
+pub(crate) fn release(buffer: &mut BytesMut) {
+    if !buffer.is_empty() || buffer.capacity() == 0 {
+        return;
+    }
+    let buffer = std::mem::take(buffer);
+    if (MIN_POOLED_CAPACITY..=MAX_POOLED_CAPACITY).contains(&buffer.capacity()) {
+        POOL.with_borrow_mut(|pool| {
+            if pool.len() < POOL_DEPTH {
+                pool.push(buffer);
+            }
+        });
+    }
+}
+
+/// How much one connection reads at a time
+#[derive(Debug, Clone)]
+pub(crate) struct ReadSize {
+    low: usize,
+    high: usize,
+    current: usize,
+}
+
+impl ReadSize {
+    /// Start at `low`; a `high` below it is raised to it
+    pub(crate) fn new(low: usize, high: usize) -> Self {
+        Self {
+            low,
+            high: high.max(low),
+            current: low,
+        }
+    }
+
+    /// Bytes of room to have in the buffer before the next read
+    pub(crate) fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Note a read of `n` bytes into `room` bytes of space, growing the
+    /// next read if this one filled it
+    pub(crate) fn read(&mut self, n: usize, room: usize) {
+        if n >= room {
+            self.current = self.current.saturating_mul(2).min(self.high);
+        }
+    }
+
+    /// Note the connection was idle for `idle`, shrinking the next read
+    /// back to the low mark if that was long enough
+    pub(crate) fn idled(&mut self, idle: Duration) {
+        if idle >= SHRINK_AFTER {
+            self.current = self.low;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    fn pooled() -> usize {
+        POOL.with_borrow(Vec::len)
+    }
+
+    #[test]
+    fn buffers_are_reused_once_empty() {
+        let mut buffer = acquire(4096);
+        let ptr = buffer.as_ptr();
+        buffer.put_slice(b"*1\r\n$4\r\nPING\r\n");
+
+        // Not while a partial command is waiting for the rest
+        release(&mut buffer);
+        assert_eq!(pooled(), 0);
+        assert!(!buffer.is_empty());
+
+        buffer.clear();
+        release(&mut buffer);
+        assert_eq!(buffer.capacity(), 0);
+        assert_eq!(pooled(), 1);
+        assert_eq!(acquire(4096).as_ptr(), ptr);
+        assert_eq!(pooled(), 0);
+    }
+
+    #[test]
+    fn outsized_buffers_are_dropped() {
+        let mut grown = BytesMut::with_capacity(MAX_POOLED_CAPACITY + 1);
+        release(&mut grown);
+        let mut worn = BytesMut::with_capacity(4096);
+        worn.put_bytes(0, 4000);
+        let _ = worn.split();
+        release(&mut worn);
+        assert_eq!(pooled(), 0);
+    }
+
+    #[test]
+    fn read_size_grows_under_load_and_shrinks_when_idle() {
+        let mut size = ReadSize::new(4096, 20_000);
+        size.read(100, 4096);
+        assert_eq!(size.current(), 4096);
+        size.read(4096, 4096);
+        assert_eq!(size.current(), 8192);
+        size.read(8192, 8192);
+        size.read(16_384, 16_384);
+        assert_eq!(size.current(), 20_000);
+
+        size.idled(Duration::from_millis(10));
+        assert_eq!(size.current(), 20_000);
+        size.idled(SHRINK_AFTER);
+        assert_eq!(size.current(), 4096);
+
+        // The high mark never sits below the low one
+        assert_eq!(ReadSize::new(8192, 1024).high, 8192);
+    }
+}
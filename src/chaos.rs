@@ -0,0 +1,191 @@
+//! Fault injection, so client retry and timeout handling can be tested
+//! against a misbehaving server. `DEBUG CHAOS`, accepted only in builds with
+//! the `chaos` feature, turns faults on; while all are off the server pays a
+//! single atomic load per command.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Drop probabilities are kept in millionths
+const PPM: f64 = 1_000_000.0;
+
+/// The faults currently injected, shared by every connection
+#[derive(Debug, Default)]
+pub struct Chaos {
+    /// Whether any fault is set, checked first so the rest costs nothing
+    active: AtomicBool,
+    /// Delay added before each command, drawn from this range in microseconds
+    latency_min: AtomicU64,
+    latency_max: AtomicU64,
+    /// Chance per command of dropping the connection, in millionths
+    drop_ppm: AtomicU32,
+    /// Most bytes handed to the socket per write, 0 for no limit
+    write_limit: AtomicU64,
+    /// Added to every save, in microseconds
+    save_delay: AtomicU64,
+    /// Weyl sequence state for the random draws
+    seed: AtomicU64,
+}
+
+impl Chaos {
+    /// Add between `min` and `max` before each command; zero turns it off
+    pub fn set_latency(&self, min: Duration, max: Duration) {
+        self.latency_min.store(micros(min), Ordering::Relaxed);
+        self.latency_max
+            .store(micros(max.max(min)), Ordering::Relaxed);
+        self.update_active();
+    }
+
+    /// Drop the connection before a command with this probability, from 0 to 1
+    pub fn set_drop(&self, probability: f64) {
+        let ppm = (probability.clamp(0.0, 1.0) * PPM).round() as u32;
+        self.drop_ppm.store(ppm, Ordering::Relaxed);
+        self.update_active();
+    }
+
+    /// Write replies at most `bytes` at a time; zero turns it off
+    pub fn set_write_limit(&self, bytes: usize) {
+        self.write_limit.store(bytes as u64, Ordering::Relaxed);
+        self.update_active();
+    }
+
+    /// Make every save take `delay` longer
+    pub fn set_save_delay(&self, delay: Duration) {
+        self.save_delay.store(micros(delay), Ordering::Relaxed);
+        self.update_active();
+    }
+
+    /// Turn every fault off
+    pub fn reset(&self) {
+        self.set_latency(Duration::ZERO, Duration::ZERO);
+        self.set_drop(0.0);
+        self.set_write_limit(0);
+        self.set_save_delay(Duration::ZERO);
+    }
+
+    fn update_active(&self) {
+        let active = self.latency_max.load(Ordering::Relaxed) > 0
+            || self.drop_ppm.load(Ordering::Relaxed) > 0
+            || self.write_limit.load(Ordering::Relaxed) > 0
+            || self.save_delay.load(Ordering::Relaxed) > 0;
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Delay to add before the next command, if any
+    pub fn latency(&self) -> Option<Duration> {
+        if !self.active() {
+            return None;
+        }
+        let (min, max) = (
+            self.latency_min.load(Ordering::Relaxed),
+            self.latency_max.load(Ordering::Relaxed),
+        );
+        if max == 0 {
+            return None;
+        }
+        let spread = max - min + 1;
+        Some(Duration::from_micros(min + self.next_u64() % spread))
+    }
+
+    /// Whether to drop the connection instead of running the next command
+    pub fn should_drop(&self) -> bool {
+        if !self.active() {
+            return false;
+        }
+        let ppm = self.drop_ppm.load(Ordering::Relaxed);
+        ppm > 0 && (self.next_u64() % PPM as u64) < ppm as u64
+    }
+
+    /// Most bytes to write at once, if writes are being split
+    pub fn write_limit(&self) -> Option<usize> {
+        if !self.active() {
+            return None;
+        }
+        match self.write_limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit as usize),
+        }
+    }
+
+    /// Extra time every save takes
+    pub fn save_delay(&self) -> Duration {
+        if !self.active() {
+            return Duration::ZERO;
+        }
+        Duration::from_micros(self.save_delay.load(Ordering::Relaxed))
+    }
+
+    /// The faults set, one `name:value` line each, like an INFO section
+    pub fn describe(&self) -> String {
+        let ms = |micros: u64| micros as f64 / 1e3;
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "latency_ms:{}-{}\r\ndrop_probability:{}\r\npartial_writes_bytes:{}\r\nsave_delay_ms:{}\r\n",
+            ms(self.latency_min.load(Ordering::Relaxed)),
+            ms(self.latency_max.load(Ordering::Relaxed)),
+            self.drop_ppm.load(Ordering::Relaxed) as f64 / PPM,
+            self.write_limit.load(Ordering::Relaxed),
+            ms(self.save_delay.load(Ordering::Relaxed)),
+        );
+        out
+    }
+
+    /// SplitMix64 over a shared Weyl sequence: lock-free and good enough to
+    /// decide which commands fail
+    fn next_u64(&self) -> u64 {
+        let mut z = self
+            .seed
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_are_off_until_set() {
+        let chaos = Chaos::default();
+        assert_eq!(chaos.latency(), None);
+        assert!(!chaos.should_drop());
+        assert_eq!(chaos.write_limit(), None);
+        assert_eq!(chaos.save_delay(), Duration::ZERO);
+
+        chaos.set_latency(Duration::from_millis(2), Duration::from_millis(4));
+        for _ in 0..100 {
+            let latency = chaos.latency().unwrap();
+            assert!(latency >= Duration::from_millis(2) && latency <= Duration::from_millis(4));
+        }
+        chaos.set_write_limit(3);
+        assert_eq!(chaos.write_limit(), Some(3));
+        chaos.set_save_delay(Duration::from_millis(5));
+        assert_eq!(chaos.save_delay(), Duration::from_millis(5));
+        assert!(chaos.describe().contains("latency_ms:2-4\r\n"));
+
+        chaos.set_drop(0.25);
+        let drops = (0..10_000).filter(|_| chaos.should_drop()).count();
+        assert!((2_000..3_000).contains(&drops), "{}", drops);
+        chaos.set_drop(1.0);
+        assert!(chaos.should_drop());
+
+        chaos.reset();
+        assert_eq!(chaos.latency(), None);
+        assert!(!chaos.should_drop());
+        assert_eq!(chaos.write_limit(), None);
+        assert_eq!(chaos.save_delay(), Duration::ZERO);
+    }
+}
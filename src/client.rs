@@ -0,0 +1,421 @@
+//! Async client for rudis, or any Redis server, built on the same RESP
+//! encoder and parser the server uses.
+//!
+//! `Client::command` sends any command and returns the raw reply, error
+//! replies included. The typed helpers (`get`, `set`, `incr`, ...) turn error
+//! replies into `Err` and unwrap the reply into a Rust type. A `Pipeline`
+//! writes several commands at once and then reads all their replies.
+//!
+//! ```no_run
+//! use rudis::client::{Client, Pipeline};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let mut client = Client::connect("127.0.0.1:6379").await?;
+//! client.set("counter", "41").await?;
+//! assert_eq!(client.incr("counter").await?, 42);
+//!
+//! let replies = Pipeline::new()
+//!     .cmd(&["INCR", "counter"])
+//!     .cmd(&["GET", "counter"])
+//!     .execute(&mut client)
+//!     .await?;
+//! assert_eq!(replies.len(), 2);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::resp::{ParseLimits, RespValue};
+use crate::tls::PeerTls;
+use anyhow::{Result, anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Replies come from a server we chose to talk to, so unlike requests they
+/// may be as large as it likes; only nesting stays bounded
+const REPLY_LIMITS: ParseLimits = ParseLimits {
+    max_bulk_len: usize::MAX,
+    max_array_len: usize::MAX,
+    max_depth: 8,
+};
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+/// A connection to a server
+#[derive(Debug)]
+pub struct Client {
+    stream: Stream,
+    /// Bytes read but not yet parsed into replies
+    input: BytesMut,
+    /// Encoded commands not yet written
+    output: BytesMut,
+}
+
+impl Client {
+    /// Connect to the server at `addr`
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self::over(Stream::Tcp(stream)))
+    }
+
+    /// Connect to the active-active peer at `addr` over TLS, verifying its
+    /// certificate and presenting this node's
+    pub async fn connect_tls(addr: &str, tls: &PeerTls) -> Result<Self> {
+        let stream = tls.connect(addr).await?;
+        Ok(Self::over(Stream::Tls(Box::new(stream))))
+    }
+
+    fn over(stream: Stream) -> Self {
+        Self {
+            stream,
+            input: BytesMut::with_capacity(4096),
+            output: BytesMut::with_capacity(4096),
+        }
+    }
+
+    /// Send one command and wait for its reply. Error replies are returned
+    /// as `RespValue::Error`; `Err` means the connection failed.
+    pub async fn command<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<RespValue> {
+        self.send(args).await?;
+        self.reply().await
+    }
+
+    /// Send a command without waiting for its reply; read it later with
+    /// `reply`, in the order the commands were sent
+    pub async fn send<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<()> {
+        encode_command(&mut self.output, args);
+        self.flush().await
+    }
+
+    /// Read the next reply
+    pub async fn reply(&mut self) -> Result<RespValue> {
+        loop {
+            if let Some((value, _)) = RespValue::parse_with_limits(&mut self.input, &REPLY_LIMITS)?
+            {
+                return Ok(value);
+            }
+            let read = match &mut self.stream {
+                Stream::Tcp(stream) => stream.read_buf(&mut self.input).await?,
+                Stream::Tls(stream) => stream.read_buf(&mut self.input).await?,
+            };
+            if read == 0 {
+                bail!("connection closed by server");
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => stream.write_all(&self.output).await?,
+            Stream::Tls(stream) => stream.write_all(&self.output).await?,
+        }
+        self.output.clear();
+        Ok(())
+    }
+
+    /// Send a command and fail on an error reply
+    async fn call<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<RespValue> {
+        match self.command(args).await? {
+            RespValue::Error(message) => Err(anyhow!(message)),
+            reply => Ok(reply),
+        }
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        expect_ok(self.call(&["PING"]).await?, "PONG")
+    }
+
+    pub async fn echo(&mut self, message: impl AsRef<[u8]>) -> Result<Bytes> {
+        let reply = self.call(&[b"ECHO", message.as_ref()]).await?;
+        Ok(into_bulk(reply)?.unwrap_or_default())
+    }
+
+    /// The value of `key`, None if it doesn't exist
+    pub async fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>> {
+        into_bulk(self.call(&[b"GET", key.as_ref()]).await?)
+    }
+
+    pub async fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let reply = self.call(&[b"SET", key.as_ref(), value.as_ref()]).await?;
+        expect_ok(reply, "OK")
+    }
+
+    /// Set `key` to expire after `seconds`
+    pub async fn set_ex(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        seconds: u64,
+        value: impl AsRef<[u8]>,
+    ) -> Result<()> {
+        let seconds = seconds.to_string();
+        let reply = self
+            .call(&[b"SETEX", key.as_ref(), seconds.as_bytes(), value.as_ref()])
+            .await?;
+        expect_ok(reply, "OK")
+    }
+
+    /// Set `key` only if it doesn't exist, returning whether it was set
+    pub async fn set_nx(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        let reply = self.call(&[b"SETNX", key.as_ref(), value.as_ref()]).await?;
+        Ok(into_int(reply)? == 1)
+    }
+
+    /// Delete `keys`, returning how many existed
+    pub async fn del<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<i64> {
+        into_int(self.call(&with_name(b"DEL", keys)).await?)
+    }
+
+    pub async fn incr(&mut self, key: impl AsRef<[u8]>) -> Result<i64> {
+        into_int(self.call(&[b"INCR", key.as_ref()]).await?)
+    }
+
+    pub async fn decr(&mut self, key: impl AsRef<[u8]>) -> Result<i64> {
+        into_int(self.call(&[b"DECR", key.as_ref()]).await?)
+    }
+
+    pub async fn incr_by(&mut self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64> {
+        let delta = delta.to_string();
+        into_int(
+            self.call(&[b"INCRBY", key.as_ref(), delta.as_bytes()])
+                .await?,
+        )
+    }
+
+    /// Values of `keys` in order, None for missing keys
+    pub async fn mget<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Vec<Option<Bytes>>> {
+        into_array(self.call(&with_name(b"MGET", keys)).await?)?
+            .into_iter()
+            .map(into_bulk)
+            .collect()
+    }
+
+    pub async fn mset<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, pairs: &[(K, V)]) -> Result<()> {
+        let mut args: Vec<&[u8]> = vec![b"MSET"];
+        for (key, value) in pairs {
+            args.push(key.as_ref());
+            args.push(value.as_ref());
+        }
+        expect_ok(self.call(&args).await?, "OK")
+    }
+
+    /// Set `key` to expire after `seconds`, returning whether it exists
+    pub async fn expire(&mut self, key: impl AsRef<[u8]>, seconds: i64) -> Result<bool> {
+        let seconds = seconds.to_string();
+        let reply = self
+            .call(&[b"EXPIRE", key.as_ref(), seconds.as_bytes()])
+            .await?;
+        Ok(into_int(reply)? == 1)
+    }
+
+    /// Seconds until `key` expires; -1 without an expiry, -2 if missing
+    pub async fn ttl(&mut self, key: impl AsRef<[u8]>) -> Result<i64> {
+        into_int(self.call(&[b"TTL", key.as_ref()]).await?)
+    }
+
+    /// Remove the expiry of `key`, returning whether it had one
+    pub async fn persist(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(into_int(self.call(&[b"PERSIST", key.as_ref()]).await?)? == 1)
+    }
+
+    /// Keys matching the glob `pattern`
+    pub async fn keys(&mut self, pattern: impl AsRef<[u8]>) -> Result<Vec<Bytes>> {
+        into_array(self.call(&[b"KEYS", pattern.as_ref()]).await?)?
+            .into_iter()
+            .map(|value| into_bulk(value)?.ok_or_else(|| anyhow!("unexpected nil key")))
+            .collect()
+    }
+
+    /// The INFO text for `section`, or the default sections if None
+    pub async fn info(&mut self, section: Option<&str>) -> Result<String> {
+        let reply = match section {
+            Some(section) => self.call(&["INFO", section]).await?,
+            None => self.call(&["INFO"]).await?,
+        };
+        let text = into_bulk(reply)?.unwrap_or_default();
+        String::from_utf8(text.to_vec()).map_err(|_| anyhow!("INFO reply is not UTF-8"))
+    }
+}
+
+/// Commands encoded up front and written to a client together by `execute`
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    commands: BytesMut,
+    len: usize,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a command
+    pub fn cmd<A: AsRef<[u8]>>(&mut self, args: &[A]) -> &mut Self {
+        encode_command(&mut self.commands, args);
+        self.len += 1;
+        self
+    }
+
+    /// Commands queued so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Write every queued command to `client` at once, then read their
+    /// replies in order. Error replies are returned in place, as with
+    /// `Client::command`. The pipeline is left as it was, so it can be run again.
+    pub async fn execute(&self, client: &mut Client) -> Result<Vec<RespValue>> {
+        client.output.extend_from_slice(&self.commands);
+        client.flush().await?;
+        let mut replies = Vec::with_capacity(self.len);
+        for _ in 0..self.len {
+            replies.push(client.reply().await?);
+        }
+        Ok(replies)
+    }
+}
+
+/// Append a command to `out` as an array of bulk strings
+fn encode_command<A: AsRef<[u8]>>(out: &mut BytesMut, args: &[A]) {
+    let command = RespValue::Array(Some(
+        args.iter()
+            .map(|arg| RespValue::BulkString(Some(Bytes::copy_from_slice(arg.as_ref()))))
+            .collect(),
+    ));
+    command.serialize_into(out);
+}
+
+/// `name` followed by `args`
+fn with_name<'a, A: AsRef<[u8]>>(name: &'a [u8], args: &'a [A]) -> Vec<&'a [u8]> {
+    std::iter::once(name)
+        .chain(args.iter().map(AsRef::as_ref))
+        .collect()
+}
+
+fn unexpected(reply: RespValue) -> anyhow::Error {
+    anyhow!("unexpected reply: {:?}", reply)
+}
+
+fn expect_ok(reply: RespValue, status: &str) -> Result<()> {
+    match reply {
+        RespValue::SimpleString(s) if s == status => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn into_int(reply: RespValue) -> Result<i64> {
+    match reply {
+        RespValue::Integer(n) => Ok(n),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn into_bulk(reply: RespValue) -> Result<Option<Bytes>> {
+    match reply {
+        RespValue::BulkString(value) => Ok(value),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn into_array(reply: RespValue) -> Result<Vec<RespValue>> {
+    match reply {
+        RespValue::Array(Some(values)) => Ok(values),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::store::Store;
+
+    async fn connect_to(store: Store) -> Client {
+        let server = Server::builder().port(0).store(store).bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+        Client::connect(addr).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn typed_helpers() {
+        let mut client = connect_to(Store::new()).await;
+        client.ping().await.unwrap();
+        assert_eq!(&client.echo("hi").await.unwrap()[..], b"hi");
+
+        client.set("a", "1").await.unwrap();
+        assert_eq!(client.get("a").await.unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(client.get("missing").await.unwrap(), None);
+        assert!(!client.set_nx("a", "2").await.unwrap());
+        assert_eq!(client.incr("a").await.unwrap(), 2);
+        assert_eq!(client.incr_by("a", 10).await.unwrap(), 12);
+        assert_eq!(client.decr("a").await.unwrap(), 11);
+
+        client.mset(&[("b", "x"), ("c", "y")]).await.unwrap();
+        let values = client.mget(&["b", "missing", "c"]).await.unwrap();
+        assert_eq!(values, [Some("x".into()), None, Some("y".into())]);
+        let mut keys = client.keys("*").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "c"]);
+
+        client.set_ex("t", 100, "v").await.unwrap();
+        assert!((99..=100).contains(&client.ttl("t").await.unwrap()));
+        assert!(client.persist("t").await.unwrap());
+        assert!(client.expire("t", 50).await.unwrap());
+        assert!(!client.expire("missing", 50).await.unwrap());
+        assert_eq!(client.del(&["a", "b", "missing"]).await.unwrap(), 2);
+        assert!(
+            client
+                .info(Some("stats"))
+                .await
+                .unwrap()
+                .starts_with("# Stats")
+        );
+
+        // Error replies become errors, and the connection stays usable
+        client.set("s", "text").await.unwrap();
+        let error = client.incr("s").await.unwrap_err();
+        assert!(error.to_string().starts_with("ERR value is not an integer"));
+        assert_eq!(
+            client.command(&["INCR", "s"]).await.unwrap(),
+            RespValue::Error("ERR value is not an integer or out of range".to_string())
+        );
+        client.ping().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipelines_replies_in_order() {
+        let mut client = connect_to(Store::new()).await;
+        let mut pipeline = Pipeline::new();
+        for _ in 0..100 {
+            pipeline.cmd(&["INCR", "n"]);
+        }
+        pipeline.cmd(&["NOSUCHCOMMAND"]);
+        assert_eq!(pipeline.len(), 101);
+        let replies = pipeline.execute(&mut client).await.unwrap();
+        assert_eq!(replies.len(), 101);
+        assert_eq!(replies[0], RespValue::Integer(1));
+        assert_eq!(replies[99], RespValue::Integer(100));
+        assert!(matches!(replies[100], RespValue::Error(_)));
+
+        // A pipeline can be run again, and on any connection
+        let mut other = connect_to(Store::new()).await;
+        let replies = Pipeline::new()
+            .cmd(&["SET", "k", "v"])
+            .cmd(&["GET", "k"])
+            .execute(&mut other)
+            .await
+            .unwrap();
+        assert_eq!(replies[1], RespValue::BulkString(Some("v".into())));
+        let replies = pipeline.execute(&mut client).await.unwrap();
+        assert_eq!(replies[99], RespValue::Integer(200));
+    }
+}
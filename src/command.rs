@@ -1,26 +1,383 @@
+use crate::backup::BackupStatus;
+use crate::crdt::CrdtStats;
+use crate::error::CommandError;
+use crate::lolwut;
 use crate::resp::RespValue;
-use crate::store::Store;
-use anyhow::{anyhow, Result};
+use crate::store::{LockStats, Store};
+use crate::tenant::TenantStats;
+use crate::upstream::UpstreamStats;
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Represents a Redis command
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Ping(Option<String>),
-    Get(String),
-    Set(String, Vec<u8>),
-    Del(Vec<String>),
-    SetNx(String, Vec<u8>),
-    SetEx(String, u64, Vec<u8>),
-    Incr(String),
-    Decr(String),
-    IncrBy(String, i64),
-    DecrBy(String, i64),
-    MGet(Vec<String>),
-    MSet(Vec<(String, Vec<u8>)>),
-    Expire(String, i64),
-    Ttl(String),
-    Persist(String),
-    Keys(String),
+    Echo(Bytes),
+    Time,
+    Lolwut(Option<i64>, Vec<i64>),
+    Get(Bytes),
+    Set(Bytes, Bytes),
+    Del(Vec<Bytes>),
+    SetNx(Bytes, Bytes),
+    SetEx(Bytes, u64, Bytes),
+    Incr(Bytes),
+    Decr(Bytes),
+    IncrBy(Bytes, i64),
+    DecrBy(Bytes, i64),
+    MGet(Vec<Bytes>),
+    MSet(Vec<(Bytes, Bytes)>),
+    Expire(Bytes, i64),
+    Ttl(Bytes),
+    Persist(Bytes),
+    Keys(Bytes),
+    Scan(ScanArgs),
+    Type(Bytes),
+    Strlen(Bytes),
+    DbSize,
+    MemoryUsage(Bytes),
+    /// SELECT; there is only database 0
+    Select(i64),
+    /// WAIT numreplicas timeout (in milliseconds); there are no replicas to
+    /// wait for, so it only ever times out
+    Wait(i64, i64),
+    /// CLUSTER with its subcommand, uppercased; there is no cluster mode,
+    /// so every subcommand is refused like a Redis running without one
+    Cluster(String),
+    /// READONLY (true) or READWRITE (false), which cluster clients send to
+    /// read from replicas; refused like CLUSTER, as there are no replicas
+    ReadOnly(bool),
+    /// AUTH [username] password, answered by the server, which knows the tenants
+    Auth(Option<String>, String),
+    /// CONFIG GET with its patterns, lowercased
+    ConfigGet(Vec<String>),
+    /// BGSAVE, with or without SCHEDULE, which makes no difference as saves
+    /// never wait for one another
+    Bgsave,
+    /// CRDT MERGE, a change sent by an active-active peer
+    CrdtMerge(crate::crdt::Op),
+    /// CRDT AUTH, with which a peer proves itself by `crdt-secret`
+    CrdtAuth(String),
+    /// ACL SAVE, writing the tenants to `aclfile`
+    AclSave,
+    /// ACL LOAD, replacing the tenants with those in `aclfile`
+    AclLoad,
+    Debug(DebugSubcommand),
+    Shutdown(ShutdownMode),
+    Quit,
+    Introspect(CommandQuery),
+    /// INFO with the requested sections, lowercased; empty means the defaults
+    Info(Vec<String>),
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanArgs {
+    pub cursor: u64,
+    pub pattern: Option<Bytes>,
+    /// Keys to visit for this page, before MATCH and TYPE filter them
+    pub count: usize,
+    /// Only keys holding this type, lowercased
+    pub kind: Option<String>,
+}
+
+/// Introspection requested through COMMAND
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandQuery {
+    /// COMMAND or COMMAND INFO without names: every command
+    All,
+    Count,
+    List,
+    Info(Vec<String>),
+    /// COMMAND DOCS; we have no docs to return
+    Docs,
+}
+
+/// Persistence behaviour requested by SHUTDOWN
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownMode {
+    Default,
+    Save,
+    NoSave,
+}
+
+/// Subcommands of DEBUG, used by client test suites to create controlled conditions
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugSubcommand {
+    Sleep(Duration),
+    Object(Bytes),
+    SetActiveExpire(bool),
+    /// Per-shard lock contention of the keyspace
+    LockStats,
+    /// JMAP, STRINGMATCH-LEN and similar internals we accept but do nothing for
+    NoOp,
+    /// Fault injection, accepted only in builds with the `chaos` feature
+    Chaos(ChaosFault),
+}
+
+/// Faults set by DEBUG CHAOS
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChaosFault {
+    /// Delay each command by between `min` and `max`
+    Latency { min: Duration, max: Duration },
+    /// Drop the connection before a command with this probability
+    Drop(f64),
+    /// Write replies at most this many bytes at a time
+    PartialWrites(usize),
+    /// Make saves this much slower
+    SaveDelay(Duration),
+    /// Turn every fault off
+    Off,
+    /// Report the faults set
+    Status,
+}
+
+/// Command names rewritten or disabled through `rename-command`
+#[derive(Debug, Clone, Default)]
+pub struct CommandRenames {
+    /// Uppercase name clients must send, mapped to the original command name.
+    /// Disabled commands (renamed to "") are mapped to None.
+    names: HashMap<String, Option<String>>,
+}
+
+impl CommandRenames {
+    /// Build from `rename-command` pairs of original name to new name
+    pub fn new(renames: &HashMap<String, String>) -> Self {
+        let mut names = HashMap::new();
+        for original in renames.keys() {
+            // The original name stops resolving unless another rename reuses it
+            names.insert(original.to_uppercase(), None);
+        }
+        for (original, renamed) in renames {
+            if !renamed.is_empty() {
+                names.insert(renamed.to_uppercase(), Some(original.to_uppercase()));
+            }
+        }
+        Self { names }
+    }
+
+    /// Rewrite the command name of a request to its original name, failing
+    /// with the usual unknown-command error if it was renamed away or disabled
+    pub fn resolve(&self, value: RespValue) -> Result<RespValue> {
+        if self.names.is_empty() {
+            return Ok(value);
+        }
+        match value {
+            RespValue::Array(Some(mut elements)) if !elements.is_empty() => {
+                let name = extract_bulk_string(&elements[0])?;
+                match self.names.get(&name.to_uppercase()) {
+                    Some(Some(original)) => {
+                        elements[0] = RespValue::BulkString(Some(Bytes::copy_from_slice(
+                            original.as_bytes(),
+                        )));
+                    }
+                    Some(None) => return Err(CommandError::UnknownCommand(name).into()),
+                    None => {}
+                }
+                Ok(RespValue::Array(Some(elements)))
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+/// Properties of a command, used for validation before dispatch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandFlags(u8);
+
+impl CommandFlags {
+    /// May modify the keyspace
+    pub const WRITE: Self = Self(1);
+    /// Only reads data
+    pub const READONLY: Self = Self(1 << 1);
+    pub const NONE: Self = Self(0);
+    /// Administrative command, the first to restrict to trusted clients
+    pub const ADMIN: Self = Self(1 << 2);
+    /// Runs in O(1) or O(log N)
+    pub const FAST: Self = Self(1 << 3);
+    /// May grow the keyspace, so is refused to a tenant over its quota
+    pub const DENYOOM: Self = Self(1 << 4);
+    /// May park the client until a key is written or a timeout passes
+    pub const BLOCKING: Self = Self(1 << 5);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Flag names as reported by COMMAND INFO
+    pub fn names(self) -> Vec<&'static str> {
+        [
+            (Self::WRITE, "write"),
+            (Self::READONLY, "readonly"),
+            (Self::ADMIN, "admin"),
+            (Self::FAST, "fast"),
+            (Self::DENYOOM, "denyoom"),
+            (Self::BLOCKING, "blocking"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.contains(*flag))
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// One entry of the command table
+#[derive(Debug)]
+pub struct CommandSpec {
+    /// Lowercase name, as reported in errors
+    pub name: &'static str,
+    /// Number of arguments including the command name; negative means "at least -arity"
+    pub arity: i32,
+    pub flags: CommandFlags,
+    /// Position of the first key argument (0 for commands without keys)
+    pub first_key: i32,
+    /// Position of the last key argument, negative counts from the end
+    pub last_key: i32,
+    /// Distance between key arguments
+    pub key_step: i32,
+    parse: fn(&[RespValue]) -> Result<Command>,
+}
+
+impl CommandSpec {
+    /// COMMAND INFO reply: name, arity, flags, first key, last key, step
+    pub fn info(&self) -> RespValue {
+        let flags = self
+            .flags
+            .names()
+            .into_iter()
+            .map(|f| RespValue::SimpleString(f.to_string()))
+            .collect();
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Bytes::from_static(self.name.as_bytes()))),
+            RespValue::Integer(self.arity as i64),
+            RespValue::Array(Some(flags)),
+            RespValue::Integer(self.first_key as i64),
+            RespValue::Integer(self.last_key as i64),
+            RespValue::Integer(self.key_step as i64),
+        ]))
+    }
+
+    /// Whether `argc` arguments (including the name) satisfy the arity
+    pub fn arity_matches(&self, argc: usize) -> bool {
+        if self.arity >= 0 {
+            argc == self.arity as usize
+        } else {
+            argc >= self.arity.unsigned_abs() as usize
+        }
+    }
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i32,
+    flags: CommandFlags,
+    (first_key, last_key, key_step): (i32, i32, i32),
+    parse: fn(&[RespValue]) -> Result<Command>,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        key_step,
+        parse,
+    }
+}
+
+const NO_KEYS: (i32, i32, i32) = (0, 0, 0);
+const ONE_KEY: (i32, i32, i32) = (1, 1, 1);
+const ALL_KEYS: (i32, i32, i32) = (1, -1, 1);
+
+const READ_FAST: CommandFlags = CommandFlags::READONLY.union(CommandFlags::FAST);
+const WRITE_FAST: CommandFlags = CommandFlags::WRITE.union(CommandFlags::FAST);
+const WRITE_DENYOOM: CommandFlags = CommandFlags::WRITE.union(CommandFlags::DENYOOM);
+const WRITE_FAST_DENYOOM: CommandFlags = WRITE_FAST.union(CommandFlags::DENYOOM);
+
+/// Every supported command. Arity and key positions follow Redis' COMMAND INFO.
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    spec("ping", -1, CommandFlags::FAST, NO_KEYS, parse_ping),
+    spec("echo", 2, CommandFlags::FAST, NO_KEYS, parse_echo),
+    spec("time", 1, CommandFlags::FAST, NO_KEYS, parse_time),
+    spec("lolwut", -1, READ_FAST, NO_KEYS, parse_lolwut),
+    spec("get", 2, READ_FAST, ONE_KEY, parse_get),
+    spec("set", 3, WRITE_DENYOOM, ONE_KEY, parse_set),
+    spec("del", -2, CommandFlags::WRITE, ALL_KEYS, parse_del),
+    spec("setnx", 3, WRITE_FAST_DENYOOM, ONE_KEY, parse_setnx),
+    spec("setex", 4, WRITE_DENYOOM, ONE_KEY, parse_setex),
+    spec("incr", 2, WRITE_FAST_DENYOOM, ONE_KEY, parse_incr),
+    spec("decr", 2, WRITE_FAST_DENYOOM, ONE_KEY, parse_decr),
+    spec("incrby", 3, WRITE_FAST_DENYOOM, ONE_KEY, parse_incrby),
+    spec("decrby", 3, WRITE_FAST_DENYOOM, ONE_KEY, parse_decrby),
+    spec("mget", -2, READ_FAST, ALL_KEYS, parse_mget),
+    spec("mset", -3, WRITE_DENYOOM, (1, -1, 2), parse_mset),
+    spec("expire", 3, WRITE_FAST, ONE_KEY, parse_expire),
+    spec("ttl", 2, READ_FAST, ONE_KEY, parse_ttl),
+    spec("persist", 2, WRITE_FAST, ONE_KEY, parse_persist),
+    spec("keys", 2, CommandFlags::READONLY, NO_KEYS, parse_keys),
+    spec("scan", -2, CommandFlags::READONLY, NO_KEYS, parse_scan),
+    spec("type", 2, READ_FAST, ONE_KEY, parse_type),
+    spec("strlen", 2, READ_FAST, ONE_KEY, parse_strlen),
+    spec("dbsize", 1, READ_FAST, NO_KEYS, parse_dbsize),
+    spec("memory", -2, CommandFlags::READONLY, NO_KEYS, parse_memory),
+    spec("select", 2, CommandFlags::FAST, NO_KEYS, parse_select),
+    spec("wait", 3, CommandFlags::BLOCKING, NO_KEYS, parse_wait),
+    spec("cluster", -2, CommandFlags::NONE, NO_KEYS, parse_cluster),
+    spec("readonly", 1, CommandFlags::FAST, NO_KEYS, parse_readonly),
+    spec("readwrite", 1, CommandFlags::FAST, NO_KEYS, parse_readwrite),
+    spec("auth", -2, CommandFlags::FAST, NO_KEYS, parse_auth),
+    spec("config", -2, CommandFlags::ADMIN, NO_KEYS, parse_config),
+    spec("debug", -2, CommandFlags::ADMIN, NO_KEYS, parse_debug),
+    spec("bgsave", -1, CommandFlags::ADMIN, NO_KEYS, parse_bgsave),
+    spec("crdt", -2, CommandFlags::ADMIN, NO_KEYS, parse_crdt),
+    spec("acl", -2, CommandFlags::ADMIN, NO_KEYS, parse_acl),
+    spec("shutdown", -1, CommandFlags::ADMIN, NO_KEYS, parse_shutdown),
+    spec("quit", -1, CommandFlags::FAST, NO_KEYS, parse_quit),
+    spec("command", -1, CommandFlags::NONE, NO_KEYS, parse_command),
+    spec("info", -1, CommandFlags::NONE, NO_KEYS, parse_info),
+];
+
+/// Longest command name in the table; anything longer can't match
+const MAX_COMMAND_NAME_LEN: usize = 16;
+
+/// COMMAND_TABLE indexed by lowercase name
+static COMMAND_INDEX: LazyLock<HashMap<&'static [u8], &'static CommandSpec>> =
+    LazyLock::new(|| {
+        COMMAND_TABLE
+            .iter()
+            .map(|spec| (spec.name.as_bytes(), spec))
+            .collect()
+    });
+
+/// Find a command's table entry by name, ignoring case. The name is
+/// lowercased into a stack buffer, so lookups never allocate.
+pub fn lookup_command(name: impl AsRef<[u8]>) -> Option<&'static CommandSpec> {
+    let name = name.as_ref();
+    let mut lowercase = [0; MAX_COMMAND_NAME_LEN];
+    let lowercase = lowercase.get_mut(..name.len())?;
+    for (lower, byte) in lowercase.iter_mut().zip(name) {
+        *lower = byte.to_ascii_lowercase();
+    }
+    COMMAND_INDEX.get(&*lowercase).copied()
+}
+
+/// The table entry and argument count of a request, if it names a known
+/// command, whether or not its arguments are valid
+pub fn request_spec(value: &RespValue) -> Option<(&'static CommandSpec, usize)> {
+    match value {
+        RespValue::Array(Some(elements)) => {
+            let name = extract_bulk_bytes(elements.first()?).ok()?;
+            Some((lookup_command(name)?, elements.len()))
+        }
+        _ => None,
+    }
 }
 
 impl Command {
@@ -28,38 +385,136 @@ impl Command {
     pub fn from_resp(value: RespValue) -> Result<Self> {
         match value {
             RespValue::Array(Some(elements)) if !elements.is_empty() => {
-                let cmd_name = extract_bulk_string(&elements[0])?;
-                let args = &elements[1..];
-
-                match cmd_name.to_uppercase().as_str() {
-                    "PING" => parse_ping(args),
-                    "GET" => parse_get(args),
-                    "SET" => parse_set(args),
-                    "DEL" => parse_del(args),
-                    "SETNX" => parse_setnx(args),
-                    "SETEX" => parse_setex(args),
-                    "INCR" => parse_incr(args),
-                    "DECR" => parse_decr(args),
-                    "INCRBY" => parse_incrby(args),
-                    "DECRBY" => parse_decrby(args),
-                    "MGET" => parse_mget(args),
-                    "MSET" => parse_mset(args),
-                    "EXPIRE" => parse_expire(args),
-                    "TTL" => parse_ttl(args),
-                    "PERSIST" => parse_persist(args),
-                    "KEYS" => parse_keys(args),
-                    _ => Err(anyhow!("ERR unknown command '{}'", cmd_name)),
+                let cmd_name = extract_bulk_bytes(&elements[0])?;
+                let spec = lookup_command(&cmd_name).ok_or_else(|| {
+                    CommandError::UnknownCommand(String::from_utf8_lossy(&cmd_name).into_owned())
+                })?;
+                if !spec.arity_matches(elements.len()) {
+                    return Err(CommandError::WrongArity(spec.name.to_string()).into());
                 }
+                (spec.parse)(&elements[1..])
             }
-            _ => Err(anyhow!("ERR expected array")),
+            _ => Err(CommandError::Other("expected array".to_string()).into()),
+        }
+    }
+
+    /// Lowercase name of the command, as in the command table
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping(_) => "ping",
+            Command::Echo(_) => "echo",
+            Command::Time => "time",
+            Command::Lolwut(..) => "lolwut",
+            Command::Get(_) => "get",
+            Command::Set(..) => "set",
+            Command::Del(_) => "del",
+            Command::SetNx(..) => "setnx",
+            Command::SetEx(..) => "setex",
+            Command::Incr(_) => "incr",
+            Command::Decr(_) => "decr",
+            Command::IncrBy(..) => "incrby",
+            Command::DecrBy(..) => "decrby",
+            Command::MGet(_) => "mget",
+            Command::MSet(_) => "mset",
+            Command::Expire(..) => "expire",
+            Command::Ttl(_) => "ttl",
+            Command::Persist(_) => "persist",
+            Command::Keys(_) => "keys",
+            Command::Scan(_) => "scan",
+            Command::Type(_) => "type",
+            Command::Strlen(_) => "strlen",
+            Command::DbSize => "dbsize",
+            Command::MemoryUsage(_) => "memory",
+            Command::Select(_) => "select",
+            Command::Cluster(_) => "cluster",
+            Command::ReadOnly(true) => "readonly",
+            Command::ReadOnly(false) => "readwrite",
+            Command::Auth(..) => "auth",
+            Command::Wait(..) => "wait",
+            Command::ConfigGet(_) => "config",
+            Command::Bgsave => "bgsave",
+            Command::CrdtMerge(_) | Command::CrdtAuth(_) => "crdt",
+            Command::AclSave | Command::AclLoad => "acl",
+            Command::Debug(_) => "debug",
+            Command::Shutdown(_) => "shutdown",
+            Command::Quit => "quit",
+            Command::Introspect(_) => "command",
+            Command::Info(_) => "info",
+        }
+    }
+
+    /// Number of keys the command touches
+    pub fn key_count(&self) -> usize {
+        self.keys().len()
+    }
+
+    /// Hold the keys and values a write may create to the store's size
+    /// limits; MSET is refused whole if any pair is over
+    fn check_sizes(&self, store: &Store) -> Result<(), CommandError> {
+        match self {
+            Command::Set(key, value)
+            | Command::SetNx(key, value)
+            | Command::SetEx(key, _, value) => store.check_size(key, Some(value)),
+            Command::Incr(key)
+            | Command::Decr(key)
+            | Command::IncrBy(key, _)
+            | Command::DecrBy(key, _) => store.check_size(key, None),
+            Command::MSet(pairs) => pairs
+                .iter()
+                .try_for_each(|(key, value)| store.check_size(key, Some(value))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The keys the command reads or writes
+    pub fn keys(&self) -> Vec<&Bytes> {
+        match self {
+            Command::Get(key)
+            | Command::Set(key, _)
+            | Command::SetNx(key, _)
+            | Command::SetEx(key, ..)
+            | Command::Incr(key)
+            | Command::Decr(key)
+            | Command::IncrBy(key, _)
+            | Command::DecrBy(key, _)
+            | Command::Expire(key, _)
+            | Command::Ttl(key)
+            | Command::Persist(key)
+            | Command::Type(key)
+            | Command::Strlen(key)
+            | Command::MemoryUsage(key) => vec![key],
+            Command::Del(keys) | Command::MGet(keys) => keys.iter().collect(),
+            Command::MSet(pairs) => pairs.iter().map(|(key, _)| key).collect(),
+            _ => Vec::new(),
         }
     }
 
     /// Execute the command and return a RESP response
     pub async fn execute(&self, store: &Store) -> RespValue {
+        if let Err(e) = self.check_sizes(store) {
+            return e.into();
+        }
         match self {
             Command::Ping(None) => RespValue::SimpleString("PONG".to_string()),
-            Command::Ping(Some(msg)) => RespValue::BulkString(Some(msg.as_bytes().to_vec())),
+            Command::Ping(Some(msg)) => {
+                RespValue::BulkString(Some(Bytes::copy_from_slice(msg.as_bytes())))
+            }
+
+            Command::Echo(msg) => RespValue::BulkString(Some(msg.clone())),
+
+            Command::Time => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                RespValue::Array(Some(vec![
+                    RespValue::BulkString(Some(Bytes::from(now.as_secs().to_string()))),
+                    RespValue::BulkString(Some(Bytes::from(now.subsec_micros().to_string()))),
+                ]))
+            }
+
+            Command::Lolwut(version, params) => {
+                RespValue::BulkString(Some(Bytes::from(lolwut::render(*version, params))))
+            }
 
             Command::Get(key) => match store.get(key).await {
                 Some(value) => RespValue::BulkString(Some(value)),
@@ -88,22 +543,22 @@ impl Command {
 
             Command::Incr(key) => match store.incr(key).await {
                 Ok(value) => RespValue::Integer(value),
-                Err(e) => RespValue::Error(e),
+                Err(e) => e.into(),
             },
 
             Command::Decr(key) => match store.decr(key).await {
                 Ok(value) => RespValue::Integer(value),
-                Err(e) => RespValue::Error(e),
+                Err(e) => e.into(),
             },
 
             Command::IncrBy(key, delta) => match store.incr_by(key, *delta).await {
                 Ok(value) => RespValue::Integer(value),
-                Err(e) => RespValue::Error(e),
+                Err(e) => e.into(),
             },
 
             Command::DecrBy(key, delta) => match store.incr_by(key, -*delta).await {
                 Ok(value) => RespValue::Integer(value),
-                Err(e) => RespValue::Error(e),
+                Err(e) => e.into(),
             },
 
             Command::MGet(keys) => {
@@ -137,30 +592,361 @@ impl Command {
                 let keys = store.keys(pattern).await;
                 let resp_values: Vec<RespValue> = keys
                     .into_iter()
-                    .map(|k| RespValue::BulkString(Some(k.into_bytes())))
+                    .map(|k| RespValue::BulkString(Some(k)))
                     .collect();
                 RespValue::Array(Some(resp_values))
             }
+
+            Command::Scan(args) => {
+                // Strings are the only type there is
+                let (cursor, keys) = match args.kind.as_deref() {
+                    Some(kind) if kind != "string" => (0, Vec::new()),
+                    _ => {
+                        store
+                            .scan(args.cursor, args.count, args.pattern.as_deref())
+                            .await
+                    }
+                };
+                RespValue::Array(Some(vec![
+                    RespValue::BulkString(Some(Bytes::from(cursor.to_string()))),
+                    RespValue::Array(Some(
+                        keys.into_iter()
+                            .map(|k| RespValue::BulkString(Some(k)))
+                            .collect(),
+                    )),
+                ]))
+            }
+
+            Command::Type(key) => {
+                let kind = match store.object_info(key).await {
+                    Some(_) => "string",
+                    None => "none",
+                };
+                RespValue::SimpleString(kind.to_string())
+            }
+
+            Command::Strlen(key) => RespValue::Integer(store.strlen(key).await as i64),
+
+            Command::DbSize => RespValue::Integer(store.dbsize().await as i64),
+
+            Command::MemoryUsage(key) => match store.memory_usage(key).await {
+                Some(bytes) => RespValue::Integer(bytes as i64),
+                None => RespValue::BulkString(None),
+            },
+
+            Command::Select(0) => RespValue::SimpleString("OK".to_string()),
+            Command::Select(_) => CommandError::DbIndexOutOfRange.into(),
+            Command::Cluster(_) | Command::ReadOnly(_) => CommandError::ClusterDisabled.into(),
+            // The server answers AUTH itself when tenants are configured
+            Command::Auth(..) => CommandError::Other(
+                "AUTH <password> called without any password configured for the default user. \
+                 Are you sure your configuration is correct?"
+                    .to_string(),
+            )
+            .into(),
+
+            // No replica ever acknowledges, so none are waited for
+            Command::Wait(replicas, _) if *replicas <= 0 => RespValue::Integer(0),
+            Command::Wait(_, timeout) => {
+                // No replica will ever acknowledge, so like Redis without
+                // replicas, wait out the timeout; 0 waits forever
+                let deadline = (*timeout > 0)
+                    .then(|| tokio::time::Instant::now() + Duration::from_millis(*timeout as u64));
+                let mut parked = store.waiters().park(&[]);
+                while parked.woken(deadline).await.is_some() {}
+                RespValue::Integer(0)
+            }
+
+            // The config belongs to the server, see server::Context::execute
+            Command::ConfigGet(_) => CommandError::ServerOnly("CONFIG").into(),
+
+            // Backups go to the server's `dir`, see server::Context::execute
+            Command::Bgsave => CommandError::ServerOnly("BGSAVE").into(),
+
+            // Merges go through the server's replication state, see server::Context::execute
+            Command::CrdtMerge(_) | Command::CrdtAuth(_) => CommandError::ServerOnly("CRDT").into(),
+
+            // The tenants belong to the server, see server::Context::execute
+            Command::AclSave | Command::AclLoad => CommandError::ServerOnly("ACL").into(),
+
+            Command::Debug(subcommand) => execute_debug(subcommand, store).await,
+
+            // Stopping the server is connection-level state, see server::handle_connection
+            Command::Shutdown(_) => CommandError::ServerOnly("SHUTDOWN").into(),
+
+            Command::Introspect(query) => match query {
+                CommandQuery::All => {
+                    RespValue::Array(Some(COMMAND_TABLE.iter().map(CommandSpec::info).collect()))
+                }
+                CommandQuery::Count => RespValue::Integer(COMMAND_TABLE.len() as i64),
+                CommandQuery::List => RespValue::Array(Some(
+                    COMMAND_TABLE
+                        .iter()
+                        .map(|spec| {
+                            RespValue::BulkString(Some(Bytes::from_static(spec.name.as_bytes())))
+                        })
+                        .collect(),
+                )),
+                CommandQuery::Info(names) => RespValue::Array(Some(
+                    names
+                        .iter()
+                        .map(|name| match lookup_command(name) {
+                            Some(spec) => spec.info(),
+                            None => RespValue::Array(None),
+                        })
+                        .collect(),
+                )),
+                CommandQuery::Docs => RespValue::Array(Some(Vec::new())),
+            },
+
+            // The server closes the connection after sending this reply
+            Command::Quit => RespValue::SimpleString("OK".to_string()),
+
+            Command::Info(sections) => {
+                let reply = info(sections, store, InfoSources::default()).await;
+                RespValue::BulkString(Some(Bytes::from(reply)))
+            }
+        }
+    }
+}
+
+async fn execute_debug(subcommand: &DebugSubcommand, store: &Store) -> RespValue {
+    match subcommand {
+        DebugSubcommand::Sleep(duration) => {
+            // Only this connection sleeps; other clients keep being served
+            tokio::time::sleep(*duration).await;
+            RespValue::SimpleString("OK".to_string())
+        }
+        DebugSubcommand::Object(key) => match store.object_info(key).await {
+            Some((encoding, len)) => RespValue::SimpleString(format!(
+                "Value at:0x0 refcount:1 encoding:{} serializedlength:{} lru:0 lru_seconds_idle:0",
+                encoding, len
+            )),
+            None => CommandError::NoSuchKey.into(),
+        },
+        DebugSubcommand::SetActiveExpire(enabled) => {
+            store.set_active_expire(*enabled);
+            RespValue::SimpleString("OK".to_string())
+        }
+        DebugSubcommand::LockStats => match store.lock_stats() {
+            Some(shards) => {
+                let mut reply = String::new();
+                for (shard, stats) in shards.iter().enumerate() {
+                    let average = if stats.contended == 0 {
+                        0.0
+                    } else {
+                        stats.wait.as_micros() as f64 / stats.contended as f64
+                    };
+                    reply.push_str(&format!(
+                        "shard_{}:acquisitions={},contended={},contended_pct={:.2},\
+                         wait_usec={},avg_wait_usec={:.2}\r\n",
+                        shard,
+                        stats.acquisitions,
+                        stats.contended,
+                        stats.contended_percent(),
+                        stats.wait.as_micros(),
+                        average
+                    ));
+                }
+                RespValue::BulkString(Some(Bytes::from(reply)))
+            }
+            None => CommandError::Other(
+                "LOCKSTATS needs the sharded keyspace backend; this one takes no shard locks"
+                    .to_string(),
+            )
+            .into(),
+        },
+        DebugSubcommand::NoOp => RespValue::SimpleString("OK".to_string()),
+        DebugSubcommand::Chaos(fault) => {
+            let chaos = store.chaos();
+            match fault {
+                ChaosFault::Latency { min, max } => chaos.set_latency(*min, *max),
+                ChaosFault::Drop(probability) => chaos.set_drop(*probability),
+                ChaosFault::PartialWrites(bytes) => chaos.set_write_limit(*bytes),
+                ChaosFault::SaveDelay(delay) => chaos.set_save_delay(*delay),
+                ChaosFault::Off => chaos.reset(),
+                ChaosFault::Status => {
+                    return RespValue::BulkString(Some(Bytes::from(chaos.describe())));
+                }
+            }
+            RespValue::SimpleString("OK".to_string())
         }
     }
 }
 
+/// Sections INFO reports, in order, and whether plain INFO includes them
+const INFO_SECTIONS: &[(&str, bool)] = &[
+    ("memory", true),
+    ("persistence", true),
+    ("stats", true),
+    ("commandstats", false),
+    ("latencystats", false),
+    ("runtime", false),
+    ("upstream", false),
+    ("tenants", false),
+    ("crdt", false),
+];
+
+/// Resident set size of the server process, on systems that report it in
+/// /proc
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kb: u64 = kb.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// INFO sections kept by the server's subsystems rather than the store.
+/// Those missing, as on a bare store, are reported as of a subsystem that
+/// hasn't done anything.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InfoSources<'a> {
+    pub backups: Option<&'a BackupStatus>,
+    pub upstream: Option<&'a UpstreamStats>,
+    pub tenants: Option<&'a TenantStats>,
+    pub crdt: Option<&'a CrdtStats>,
+}
+
+/// Build the INFO reply: each requested section as a `# Title` header
+/// followed by `field:value` lines. Unknown sections are skipped, like Redis.
+pub async fn info(sections: &[String], store: &Store, sources: InfoSources<'_>) -> String {
+    let wanted = |(section, default): &&(&str, bool)| {
+        if sections.is_empty() {
+            return *default;
+        }
+        sections.iter().any(|s| {
+            s == section || s == "all" || s == "everything" || (s == "default" && *default)
+        })
+    };
+
+    let mut reply = String::new();
+    for (section, _) in INFO_SECTIONS.iter().filter(wanted) {
+        if !reply.is_empty() {
+            reply.push_str("\r\n");
+        }
+        match *section {
+            "memory" => {
+                let stats = store.memory_stats().await;
+                reply.push_str("# Memory\r\n");
+                reply.push_str(&format!("keyspace_keys:{}\r\n", stats.keys));
+                reply.push_str(&format!("keyspace_capacity:{}\r\n", stats.capacity));
+                reply.push_str(&format!(
+                    "keyspace_fragmentation_ratio:{:.2}\r\n",
+                    stats.fragmentation_ratio()
+                ));
+                reply.push_str(&format!("keyspace_compactions:{}\r\n", stats.compactions));
+                if let Some(rss) = rss_bytes() {
+                    reply.push_str(&format!("used_memory_rss:{}\r\n", rss));
+                }
+            }
+            "persistence" => {
+                reply.push_str("# Persistence\r\n");
+                reply.push_str(&match sources.backups {
+                    Some(status) => status.info(),
+                    None => BackupStatus::default().info(),
+                });
+            }
+            "stats" => {
+                let stats = store.keyspace_stats();
+                reply.push_str("# Stats\r\n");
+                reply.push_str(&format!("expired_keys:{}\r\n", stats.expired_keys));
+                // There is no maxmemory, so nothing is ever evicted
+                reply.push_str("evicted_keys:0\r\n");
+                reply.push_str(&format!("keyspace_hits:{}\r\n", stats.hits));
+                reply.push_str(&format!("keyspace_misses:{}\r\n", stats.misses));
+                if let Some(shards) = store.lock_stats() {
+                    let locks = shards
+                        .iter()
+                        .fold(LockStats::default(), |sum, shard| sum.add(shard));
+                    reply.push_str(&format!(
+                        "keyspace_lock_acquisitions:{}\r\n\
+                         keyspace_lock_contended:{}\r\n\
+                         keyspace_lock_wait_usec:{}\r\n",
+                        locks.acquisitions,
+                        locks.contended,
+                        locks.wait.as_micros()
+                    ));
+                }
+                reply.push_str(&format!("blocked_clients:{}\r\n", store.waiters().parked()));
+                reply.push_str(&format!(
+                    "client_frame_timeout_disconnections:{}\r\n",
+                    store.frame_timeouts()
+                ));
+                reply.push_str(&store.load_shedder().info());
+            }
+            "commandstats" => {
+                reply.push_str("# Commandstats\r\n");
+                reply.push_str(&store.command_stats().commandstats());
+            }
+            "latencystats" => {
+                reply.push_str("# Latencystats\r\n");
+                reply.push_str(&store.command_stats().latencystats());
+            }
+            "runtime" => {
+                reply.push_str("# Runtime\r\n");
+                reply.push_str(&crate::watchdog::info());
+            }
+            "upstream" => {
+                reply.push_str("# Upstream\r\n");
+                reply.push_str(&match sources.upstream {
+                    Some(stats) => stats.info(),
+                    None => UpstreamStats::default().info(),
+                });
+            }
+            "tenants" => {
+                reply.push_str("# Tenants\r\n");
+                reply.push_str(&match sources.tenants {
+                    Some(stats) => stats.info(),
+                    None => TenantStats::default().info(),
+                });
+            }
+            "crdt" => {
+                reply.push_str("# CRDT\r\n");
+                reply.push_str(&match sources.crdt {
+                    Some(stats) => stats.info(),
+                    None => CrdtStats::default().info(),
+                });
+            }
+            _ => unreachable!("unhandled INFO section {}", section),
+        }
+    }
+    reply
+}
+
+/// An error with no variant of its own, for parsers returning `anyhow::Error`
+fn other(message: &str) -> anyhow::Error {
+    CommandError::Other(message.to_string()).into()
+}
+
 // Helper function to extract a string from a bulk string RESP value
 fn extract_bulk_string(value: &RespValue) -> Result<String> {
     match value {
-        RespValue::BulkString(Some(bytes)) => {
-            String::from_utf8(bytes.clone()).map_err(|e| anyhow!("Invalid UTF-8: {}", e))
-        }
+        RespValue::BulkString(Some(bytes)) => String::from_utf8(bytes.to_vec())
+            .map_err(|e| CommandError::Other(format!("invalid UTF-8: {}", e)).into()),
         RespValue::SimpleString(s) => Ok(s.clone()),
-        _ => Err(anyhow!("Expected bulk string or simple string")),
+        _ => Err(CommandError::Other("expected bulk string or simple string".to_string()).into()),
     }
 }
 
-fn extract_bulk_bytes(value: &RespValue) -> Result<Vec<u8>> {
+/// Keys are binary safe, so unlike command names they are not required to be UTF-8
+fn extract_key(value: &RespValue) -> Result<Bytes> {
+    extract_bulk_bytes(value)
+}
+
+/// Copy an argument that is going to be stored. Arguments are views into the
+/// request frame, and storing one as-is would keep the whole frame alive.
+fn extract_owned(value: &RespValue) -> Result<Bytes> {
+    extract_bulk_bytes(value).map(|bytes| Bytes::copy_from_slice(&bytes))
+}
+
+/// Bulk strings are views into the request frame, so cloning one is cheap
+fn extract_bulk_bytes(value: &RespValue) -> Result<Bytes> {
     match value {
         RespValue::BulkString(Some(bytes)) => Ok(bytes.clone()),
-        RespValue::SimpleString(s) => Ok(s.as_bytes().to_vec()),
-        _ => Err(anyhow!("Expected bulk string or simple string")),
+        RespValue::SimpleString(s) => Ok(Bytes::copy_from_slice(s.as_bytes())),
+        _ => Err(CommandError::Other("expected bulk string or simple string".to_string()).into()),
     }
 }
 
@@ -168,14 +954,14 @@ fn extract_integer(value: &RespValue) -> Result<i64> {
     match value {
         RespValue::Integer(i) => Ok(*i),
         RespValue::BulkString(Some(bytes)) => {
-            let s = String::from_utf8(bytes.clone())?;
+            let s = std::str::from_utf8(bytes)?;
             s.parse::<i64>()
-                .map_err(|_| anyhow!("ERR value is not an integer or out of range"))
+                .map_err(|_| CommandError::NotInteger.into())
         }
         RespValue::SimpleString(s) => s
             .parse::<i64>()
-            .map_err(|_| anyhow!("ERR value is not an integer or out of range")),
-        _ => Err(anyhow!("ERR value is not an integer or out of range")),
+            .map_err(|_| CommandError::NotInteger.into()),
+        _ => Err(CommandError::NotInteger.into()),
     }
 }
 
@@ -186,153 +972,424 @@ fn parse_ping(args: &[RespValue]) -> Result<Command> {
             let message = extract_bulk_string(&args[0])?;
             Ok(Command::Ping(Some(message)))
         }
-        _ => Err(anyhow!("ERR wrong number of arguments for 'ping' command")),
+        _ => Err(CommandError::WrongArity("ping".to_string()).into()),
     }
 }
 
-fn parse_get(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 1 {
-        return Err(anyhow!("ERR wrong number of arguments for 'get' command"));
+fn parse_echo(args: &[RespValue]) -> Result<Command> {
+    let message = extract_bulk_bytes(&args[0])?;
+    Ok(Command::Echo(message))
+}
+
+fn parse_time(_args: &[RespValue]) -> Result<Command> {
+    Ok(Command::Time)
+}
+
+fn parse_lolwut(args: &[RespValue]) -> Result<Command> {
+    let mut version = None;
+    let mut args = args;
+    if let Some(first) = args.first()
+        && extract_bulk_string(first)?.eq_ignore_ascii_case("VERSION")
+    {
+        let Some(value) = args.get(1) else {
+            return Err(CommandError::Syntax.into());
+        };
+        version = Some(extract_integer(value)?);
+        args = &args[2..];
     }
-    let key = extract_bulk_string(&args[0])?;
+    let params: Result<Vec<i64>> = args.iter().map(extract_integer).collect();
+    Ok(Command::Lolwut(version, params?))
+}
+
+fn parse_get(args: &[RespValue]) -> Result<Command> {
+    let key = extract_key(&args[0])?;
     Ok(Command::Get(key))
 }
 
 fn parse_set(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 2 {
-        return Err(anyhow!("ERR wrong number of arguments for 'set' command"));
-    }
-    let key = extract_bulk_string(&args[0])?;
-    let value = extract_bulk_bytes(&args[1])?;
+    let key = extract_owned(&args[0])?;
+    let value = extract_owned(&args[1])?;
     Ok(Command::Set(key, value))
 }
 
 fn parse_del(args: &[RespValue]) -> Result<Command> {
-    if args.is_empty() {
-        return Err(anyhow!("ERR wrong number of arguments for 'del' command"));
-    }
-    let keys: Result<Vec<String>> = args.iter().map(extract_bulk_string).collect();
+    let keys: Result<Vec<Bytes>> = args.iter().map(extract_key).collect();
     Ok(Command::Del(keys?))
 }
 
 fn parse_setnx(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 2 {
-        return Err(anyhow!("ERR wrong number of arguments for 'setnx' command"));
-    }
-    let key = extract_bulk_string(&args[0])?;
-    let value = extract_bulk_bytes(&args[1])?;
+    let key = extract_owned(&args[0])?;
+    let value = extract_owned(&args[1])?;
     Ok(Command::SetNx(key, value))
 }
 
 fn parse_setex(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 3 {
-        return Err(anyhow!("ERR wrong number of arguments for 'setex' command"));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_owned(&args[0])?;
     let seconds = extract_integer(&args[1])?;
     if seconds <= 0 {
-        return Err(anyhow!("ERR invalid expire time in 'setex' command"));
+        return Err(CommandError::InvalidExpireTime("setex").into());
     }
-    let value = extract_bulk_bytes(&args[2])?;
+    let value = extract_owned(&args[2])?;
     Ok(Command::SetEx(key, seconds as u64, value))
 }
 
 fn parse_incr(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 1 {
-        return Err(anyhow!("ERR wrong number of arguments for 'incr' command"));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_key(&args[0])?;
     Ok(Command::Incr(key))
 }
 
 fn parse_decr(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 1 {
-        return Err(anyhow!("ERR wrong number of arguments for 'decr' command"));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_key(&args[0])?;
     Ok(Command::Decr(key))
 }
 
 fn parse_incrby(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 2 {
-        return Err(anyhow!(
-            "ERR wrong number of arguments for 'incrby' command"
-        ));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_key(&args[0])?;
     let delta = extract_integer(&args[1])?;
     Ok(Command::IncrBy(key, delta))
 }
 
 fn parse_decrby(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 2 {
-        return Err(anyhow!(
-            "ERR wrong number of arguments for 'decrby' command"
-        ));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_key(&args[0])?;
     let delta = extract_integer(&args[1])?;
     Ok(Command::DecrBy(key, delta))
 }
 
 fn parse_mget(args: &[RespValue]) -> Result<Command> {
-    if args.is_empty() {
-        return Err(anyhow!("ERR wrong number of arguments for 'mget' command"));
-    }
-    let keys: Result<Vec<String>> = args.iter().map(extract_bulk_string).collect();
+    let keys: Result<Vec<Bytes>> = args.iter().map(extract_key).collect();
     Ok(Command::MGet(keys?))
 }
 
 fn parse_mset(args: &[RespValue]) -> Result<Command> {
-    if args.is_empty() || !args.len().is_multiple_of(2) {
-        return Err(anyhow!("ERR wrong number of arguments for 'mset' command"));
+    if !args.len().is_multiple_of(2) {
+        return Err(CommandError::WrongArity("mset".to_string()).into());
     }
     let mut pairs = Vec::new();
     for chunk in args.chunks(2) {
-        let key = extract_bulk_string(&chunk[0])?;
-        let value = extract_bulk_bytes(&chunk[1])?;
+        let key = extract_owned(&chunk[0])?;
+        let value = extract_owned(&chunk[1])?;
         pairs.push((key, value));
     }
     Ok(Command::MSet(pairs))
 }
 
 fn parse_expire(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 2 {
-        return Err(anyhow!(
-            "ERR wrong number of arguments for 'expire' command"
-        ));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_key(&args[0])?;
     let seconds = extract_integer(&args[1])?;
     Ok(Command::Expire(key, seconds))
 }
 
 fn parse_ttl(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 1 {
-        return Err(anyhow!("ERR wrong number of arguments for 'ttl' command"));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_key(&args[0])?;
     Ok(Command::Ttl(key))
 }
 
 fn parse_persist(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 1 {
-        return Err(anyhow!(
-            "ERR wrong number of arguments for 'persist' command"
-        ));
-    }
-    let key = extract_bulk_string(&args[0])?;
+    let key = extract_key(&args[0])?;
     Ok(Command::Persist(key))
 }
 
 fn parse_keys(args: &[RespValue]) -> Result<Command> {
-    if args.len() != 1 {
-        return Err(anyhow!("ERR wrong number of arguments for 'keys' command"));
-    }
-    let pattern = extract_bulk_string(&args[0])?;
+    let pattern = extract_key(&args[0])?;
     Ok(Command::Keys(pattern))
 }
 
+fn parse_scan(args: &[RespValue]) -> Result<Command> {
+    let cursor = extract_bulk_string(&args[0])?
+        .parse::<u64>()
+        .map_err(|_| CommandError::InvalidCursor)?;
+    let mut scan = ScanArgs {
+        cursor,
+        pattern: None,
+        count: 10,
+        kind: None,
+    };
+    for option in args[1..].chunks(2) {
+        let [name, value] = option else {
+            return Err(CommandError::Syntax.into());
+        };
+        match extract_bulk_string(name)?.to_uppercase().as_str() {
+            "MATCH" => scan.pattern = Some(extract_key(value)?),
+            "COUNT" => {
+                let count = extract_integer(value)?;
+                if count < 1 {
+                    return Err(CommandError::Syntax.into());
+                }
+                scan.count = count as usize;
+            }
+            "TYPE" => scan.kind = Some(extract_bulk_string(value)?.to_lowercase()),
+            _ => return Err(CommandError::Syntax.into()),
+        }
+    }
+    Ok(Command::Scan(scan))
+}
+
+fn parse_type(args: &[RespValue]) -> Result<Command> {
+    let key = extract_key(&args[0])?;
+    Ok(Command::Type(key))
+}
+
+fn parse_strlen(args: &[RespValue]) -> Result<Command> {
+    let key = extract_key(&args[0])?;
+    Ok(Command::Strlen(key))
+}
+
+fn parse_dbsize(_args: &[RespValue]) -> Result<Command> {
+    Ok(Command::DbSize)
+}
+
+/// MEMORY USAGE key [SAMPLES count]; there are no nested values to sample,
+/// so the count is checked and otherwise ignored
+fn parse_memory(args: &[RespValue]) -> Result<Command> {
+    let subcommand = extract_bulk_string(&args[0])?;
+    match (subcommand.to_uppercase().as_str(), &args[1..]) {
+        ("USAGE", [key]) => Ok(Command::MemoryUsage(extract_key(key)?)),
+        ("USAGE", [key, option, samples])
+            if extract_bulk_string(option)?.eq_ignore_ascii_case("SAMPLES") =>
+        {
+            extract_integer(samples)?;
+            Ok(Command::MemoryUsage(extract_key(key)?))
+        }
+        ("USAGE", [_, ..]) => Err(CommandError::Syntax.into()),
+        _ => Err(CommandError::UnknownSubcommand {
+            command: "MEMORY",
+            subcommand,
+        }
+        .into()),
+    }
+}
+
+fn parse_select(args: &[RespValue]) -> Result<Command> {
+    let index = extract_integer(&args[0])?;
+    Ok(Command::Select(index))
+}
+
+fn parse_wait(args: &[RespValue]) -> Result<Command> {
+    let replicas = extract_integer(&args[0])?;
+    let timeout = extract_integer(&args[1])?;
+    if timeout < 0 {
+        return Err(CommandError::NegativeTimeout.into());
+    }
+    Ok(Command::Wait(replicas, timeout))
+}
+
+fn parse_cluster(args: &[RespValue]) -> Result<Command> {
+    Ok(Command::Cluster(
+        extract_bulk_string(&args[0])?.to_uppercase(),
+    ))
+}
+
+fn parse_readonly(_args: &[RespValue]) -> Result<Command> {
+    Ok(Command::ReadOnly(true))
+}
+
+fn parse_readwrite(_args: &[RespValue]) -> Result<Command> {
+    Ok(Command::ReadOnly(false))
+}
+
+fn parse_auth(args: &[RespValue]) -> Result<Command> {
+    match args {
+        [password] => Ok(Command::Auth(None, extract_bulk_string(password)?)),
+        [username, password] => Ok(Command::Auth(
+            Some(extract_bulk_string(username)?),
+            extract_bulk_string(password)?,
+        )),
+        _ => Err(CommandError::Syntax.into()),
+    }
+}
+
+fn parse_config(args: &[RespValue]) -> Result<Command> {
+    let subcommand = extract_bulk_string(&args[0])?;
+    match subcommand.to_uppercase().as_str() {
+        "GET" if args.len() > 1 => {
+            let patterns: Result<Vec<String>> = args[1..]
+                .iter()
+                .map(|arg| extract_bulk_string(arg).map(|p| p.to_lowercase()))
+                .collect();
+            Ok(Command::ConfigGet(patterns?))
+        }
+        _ => Err(CommandError::UnknownSubcommand {
+            command: "CONFIG",
+            subcommand,
+        }
+        .into()),
+    }
+}
+
+fn parse_debug(args: &[RespValue]) -> Result<Command> {
+    let subcommand = extract_bulk_string(&args[0])?;
+    let rest = &args[1..];
+
+    let parsed = match subcommand.to_uppercase().as_str() {
+        "SLEEP" if rest.len() == 1 => {
+            let seconds = extract_bulk_string(&rest[0])?
+                .parse::<f64>()
+                .ok()
+                .filter(|s| s.is_finite() && *s >= 0.0)
+                .ok_or(CommandError::NotFloat)?;
+            DebugSubcommand::Sleep(Duration::from_secs_f64(seconds))
+        }
+        "OBJECT" if rest.len() == 1 => DebugSubcommand::Object(extract_key(&rest[0])?),
+        "SET-ACTIVE-EXPIRE" if rest.len() == 1 => {
+            DebugSubcommand::SetActiveExpire(extract_integer(&rest[0])? != 0)
+        }
+        "LOCKSTATS" if rest.is_empty() => DebugSubcommand::LockStats,
+        "JMAP" | "STRINGMATCH-LEN" | "QUICKLIST-PACKED-THRESHOLD" => DebugSubcommand::NoOp,
+        #[cfg(feature = "chaos")]
+        "CHAOS" => DebugSubcommand::Chaos(parse_chaos(rest)?),
+        _ => {
+            return Err(CommandError::UnknownSubcommand {
+                command: "DEBUG",
+                subcommand,
+            }
+            .into());
+        }
+    };
+    Ok(Command::Debug(parsed))
+}
+
+/// DEBUG CHAOS LATENCY <min-ms> [<max-ms>] | DROP <probability> |
+/// PARTIAL-WRITES <bytes> | SAVE-DELAY <ms> | OFF, or no arguments for the
+/// faults currently set
+#[cfg(feature = "chaos")]
+fn parse_chaos(args: &[RespValue]) -> Result<ChaosFault> {
+    fn number(arg: &RespValue) -> Result<f64> {
+        extract_bulk_string(arg)?
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite() && *n >= 0.0)
+            .ok_or_else(|| CommandError::NotFloat.into())
+    }
+    let millis = |arg| number(arg).map(|ms| Duration::from_secs_f64(ms / 1e3));
+
+    let Some(fault) = args.first() else {
+        return Ok(ChaosFault::Status);
+    };
+    let values = &args[1..];
+    let fault = match (extract_bulk_string(fault)?.to_uppercase().as_str(), values) {
+        ("LATENCY", [min]) => {
+            let min = millis(min)?;
+            ChaosFault::Latency { min, max: min }
+        }
+        ("LATENCY", [min, max]) => ChaosFault::Latency {
+            min: millis(min)?,
+            max: millis(max)?,
+        },
+        ("DROP", [probability]) => match number(probability)? {
+            probability if probability <= 1.0 => ChaosFault::Drop(probability),
+            _ => return Err(other("the drop probability must be between 0 and 1")),
+        },
+        ("PARTIAL-WRITES", [bytes]) => ChaosFault::PartialWrites(
+            usize::try_from(extract_integer(bytes)?)
+                .map_err(|_| other("value is out of range, must be positive"))?,
+        ),
+        ("SAVE-DELAY", [delay]) => ChaosFault::SaveDelay(millis(delay)?),
+        ("OFF", []) => ChaosFault::Off,
+        _ => {
+            return Err(other(
+                "unknown DEBUG CHAOS fault or wrong number of arguments",
+            ));
+        }
+    };
+    if let ChaosFault::Latency { min, max } = fault
+        && max < min
+    {
+        return Err(other("the maximum latency is below the minimum"));
+    }
+    Ok(fault)
+}
+
+fn parse_command(args: &[RespValue]) -> Result<Command> {
+    let Some(subcommand) = args.first() else {
+        return Ok(Command::Introspect(CommandQuery::All));
+    };
+    let subcommand = extract_bulk_string(subcommand)?;
+    let rest = &args[1..];
+    let query = match subcommand.to_uppercase().as_str() {
+        "COUNT" if rest.is_empty() => CommandQuery::Count,
+        "LIST" if rest.is_empty() => CommandQuery::List,
+        "INFO" if rest.is_empty() => CommandQuery::All,
+        "INFO" => CommandQuery::Info(
+            rest.iter()
+                .map(extract_bulk_string)
+                .collect::<Result<_>>()?,
+        ),
+        "DOCS" => CommandQuery::Docs,
+        _ => {
+            return Err(CommandError::UnknownSubcommand {
+                command: "COMMAND",
+                subcommand,
+            }
+            .into());
+        }
+    };
+    Ok(Command::Introspect(query))
+}
+
+fn parse_info(args: &[RespValue]) -> Result<Command> {
+    let sections = args
+        .iter()
+        .map(|arg| extract_bulk_string(arg).map(|s| s.to_lowercase()))
+        .collect::<Result<_>>()?;
+    Ok(Command::Info(sections))
+}
+
+fn parse_quit(_args: &[RespValue]) -> Result<Command> {
+    // Redis accepts and ignores any arguments to QUIT
+    Ok(Command::Quit)
+}
+
+fn parse_crdt(args: &[RespValue]) -> Result<Command> {
+    match args {
+        [subcommand, op @ ..] if extract_bulk_string(subcommand)?.eq_ignore_ascii_case("MERGE") => {
+            Ok(Command::CrdtMerge(crate::crdt::Op::from_args(op)?))
+        }
+        [subcommand, secret] if extract_bulk_string(subcommand)?.eq_ignore_ascii_case("AUTH") => {
+            Ok(Command::CrdtAuth(extract_bulk_string(secret)?))
+        }
+        [subcommand, ..] => Err(other(&format!(
+            "unknown subcommand '{}'",
+            extract_bulk_string(subcommand)?
+        ))),
+        [] => Err(CommandError::WrongArity("crdt".to_string()).into()),
+    }
+}
+
+fn parse_acl(args: &[RespValue]) -> Result<Command> {
+    let subcommand = extract_bulk_string(&args[0])?;
+    match (subcommand.to_uppercase().as_str(), args.len()) {
+        ("SAVE", 1) => Ok(Command::AclSave),
+        ("LOAD", 1) => Ok(Command::AclLoad),
+        _ => Err(CommandError::UnknownSubcommand {
+            command: "ACL",
+            subcommand,
+        }
+        .into()),
+    }
+}
+
+fn parse_bgsave(args: &[RespValue]) -> Result<Command> {
+    match args {
+        [] => Ok(Command::Bgsave),
+        [schedule] if extract_bulk_string(schedule)?.eq_ignore_ascii_case("SCHEDULE") => {
+            Ok(Command::Bgsave)
+        }
+        _ => Err(CommandError::Syntax.into()),
+    }
+}
+
+fn parse_shutdown(args: &[RespValue]) -> Result<Command> {
+    match args {
+        [] => Ok(Command::Shutdown(ShutdownMode::Default)),
+        [mode] => match extract_bulk_string(mode)?.to_uppercase().as_str() {
+            "SAVE" => Ok(Command::Shutdown(ShutdownMode::Save)),
+            "NOSAVE" => Ok(Command::Shutdown(ShutdownMode::NoSave)),
+            _ => Err(CommandError::Syntax.into()),
+        },
+        _ => Err(CommandError::Syntax.into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -340,7 +1397,7 @@ mod tests {
     fn make_cmd(args: &[&[u8]]) -> RespValue {
         RespValue::Array(Some(
             args.iter()
-                .map(|a| RespValue::BulkString(Some(a.to_vec())))
+                .map(|a| RespValue::BulkString(Some(Bytes::copy_from_slice(a))))
                 .collect(),
         ))
     }
@@ -362,7 +1419,9 @@ mod tests {
     #[test]
     fn ping_is_case_insensitive() {
         for variant in &[b"ping".as_slice(), b"PING", b"Ping", b"PiNg"] {
-            let resp = RespValue::Array(Some(vec![RespValue::BulkString(Some(variant.to_vec()))]));
+            let resp = RespValue::Array(Some(vec![RespValue::BulkString(Some(
+                Bytes::copy_from_slice(variant),
+            ))]));
             let cmd = Command::from_resp(resp).unwrap();
             assert_eq!(cmd, Command::Ping(None));
         }
@@ -373,10 +1432,95 @@ mod tests {
         let resp = make_cmd(&[b"PING", b"arg1", b"arg2"]);
         let result = Command::from_resp(resp);
         assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("wrong number of arguments"));
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("wrong number of arguments")
+        );
+    }
+
+    #[test]
+    fn parse_errors_downcast_to_command_errors() {
+        let error = Command::from_resp(make_cmd(&[b"PING", b"arg1", b"arg2"])).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&CommandError::WrongArity("ping".to_string()))
+        );
+    }
+
+    #[test]
+    fn command_table_names_are_unique_and_lowercase() {
+        for (i, spec) in COMMAND_TABLE.iter().enumerate() {
+            assert_eq!(spec.name, spec.name.to_lowercase());
+            assert!(spec.name.len() <= MAX_COMMAND_NAME_LEN);
+            assert!(
+                COMMAND_TABLE[i + 1..].iter().all(|s| s.name != spec.name),
+                "duplicate entry for {}",
+                spec.name
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_command_flags_and_keys() {
+        let get = lookup_command("GeT").unwrap();
+        assert!(get.flags.contains(CommandFlags::READONLY));
+        assert!(!get.flags.contains(CommandFlags::WRITE));
+        assert_eq!((get.first_key, get.last_key, get.key_step), (1, 1, 1));
+
+        let mset = lookup_command("mset").unwrap();
+        assert!(mset.flags.contains(CommandFlags::WRITE));
+        assert_eq!(mset.key_step, 2);
+
+        assert!(lookup_command("nosuchcommand").is_none());
+        assert!(lookup_command("persistpersistpersist").is_none());
+        assert!(lookup_command(b"\xffget").is_none());
+        assert_eq!(lookup_command(b"PERSIST").unwrap().name, "persist");
+    }
+
+    #[tokio::test]
+    async fn execute_command_info() {
+        let store = Store::new();
+        let cmd = Command::from_resp(make_cmd(&[b"COMMAND", b"INFO", b"get", b"nope"])).unwrap();
+        assert_eq!(
+            cmd.execute(&store).await,
+            RespValue::Array(Some(vec![
+                RespValue::Array(Some(vec![
+                    RespValue::BulkString(Some(Bytes::from_static(b"get"))),
+                    RespValue::Integer(2),
+                    RespValue::Array(Some(vec![
+                        RespValue::SimpleString("readonly".to_string()),
+                        RespValue::SimpleString("fast".to_string()),
+                    ])),
+                    RespValue::Integer(1),
+                    RespValue::Integer(1),
+                    RespValue::Integer(1),
+                ])),
+                RespValue::Array(None),
+            ]))
+        );
+
+        let cmd = Command::from_resp(make_cmd(&[b"COMMAND", b"COUNT"])).unwrap();
+        assert_eq!(
+            cmd.execute(&store).await,
+            RespValue::Integer(COMMAND_TABLE.len() as i64)
+        );
+    }
+
+    #[test]
+    fn arity_errors_use_lowercase_name() {
+        let err = Command::from_resp(make_cmd(&[b"GET", b"a", b"b"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        let err = Command::from_resp(make_cmd(&[b"MGET"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR wrong number of arguments for 'mget' command"
+        );
+        assert!(Command::from_resp(make_cmd(&[b"TIME", b"x"])).is_err());
     }
 
     #[test]
@@ -385,6 +1529,11 @@ mod tests {
         let result = Command::from_resp(resp);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("unknown command"));
+        let error = Command::from_resp(make_cmd(&[b"UNKNOWN"])).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&CommandError::UnknownCommand("UNKNOWN".to_string()))
+        );
     }
 
     #[test]
@@ -409,11 +1558,71 @@ mod tests {
         assert!(result.unwrap_err().to_string().contains("expected array"));
     }
 
+    #[test]
+    fn parse_echo_command() {
+        let cmd = Command::from_resp(make_cmd(&[b"ECHO", b"hello"])).unwrap();
+        assert_eq!(cmd, Command::Echo(Bytes::from_static(b"hello")));
+        assert!(Command::from_resp(make_cmd(&[b"ECHO"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"ECHO", b"a", b"b"])).is_err());
+    }
+
+    #[test]
+    fn parse_time_command() {
+        let cmd = Command::from_resp(make_cmd(&[b"TIME"])).unwrap();
+        assert_eq!(cmd, Command::Time);
+        assert!(Command::from_resp(make_cmd(&[b"TIME", b"extra"])).is_err());
+    }
+
+    #[test]
+    fn parse_lolwut_command() {
+        let cmd = Command::from_resp(make_cmd(&[b"LOLWUT"])).unwrap();
+        assert_eq!(cmd, Command::Lolwut(None, vec![]));
+
+        let cmd =
+            Command::from_resp(make_cmd(&[b"LOLWUT", b"VERSION", b"5", b"40", b"4"])).unwrap();
+        assert_eq!(cmd, Command::Lolwut(Some(5), vec![40, 4]));
+
+        assert!(Command::from_resp(make_cmd(&[b"LOLWUT", b"VERSION"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"LOLWUT", b"wide"])).is_err());
+    }
+
     #[test]
     fn parse_get_command() {
         let resp = make_cmd(&[b"GET", b"mykey"]);
         let cmd = Command::from_resp(resp).unwrap();
-        assert_eq!(cmd, Command::Get("mykey".to_string()));
+        assert_eq!(cmd, Command::Get(Bytes::from("mykey")));
+    }
+
+    #[test]
+    fn only_stored_arguments_are_copied() {
+        let bulk_ptr = |resp: &RespValue, index: usize| match resp {
+            RespValue::Array(Some(items)) => match &items[index] {
+                RespValue::BulkString(Some(bytes)) => bytes.as_ptr(),
+                other => panic!("unexpected element: {:?}", other),
+            },
+            other => panic!("unexpected request: {:?}", other),
+        };
+
+        let get = make_cmd(&[b"GET", b"key"]);
+        let key_ptr = bulk_ptr(&get, 1);
+        let Command::Get(key) = Command::from_resp(get).unwrap() else {
+            panic!("expected GET");
+        };
+        assert_eq!(key.as_ptr(), key_ptr);
+
+        let set = make_cmd(&[b"SET", b"key", b"value"]);
+        let value_ptr = bulk_ptr(&set, 2);
+        let Command::Set(_, value) = Command::from_resp(set).unwrap() else {
+            panic!("expected SET");
+        };
+        assert_ne!(value.as_ptr(), value_ptr);
+    }
+
+    #[test]
+    fn parse_binary_key() {
+        let resp = make_cmd(&[b"GET", b"\xff\x00key"]);
+        let cmd = Command::from_resp(resp).unwrap();
+        assert_eq!(cmd, Command::Get(Bytes::from_static(b"\xff\x00key")));
     }
 
     #[test]
@@ -427,7 +1636,10 @@ mod tests {
     fn parse_set_command() {
         let resp = make_cmd(&[b"SET", b"mykey", b"myvalue"]);
         let cmd = Command::from_resp(resp).unwrap();
-        assert_eq!(cmd, Command::Set("mykey".to_string(), b"myvalue".to_vec()));
+        assert_eq!(
+            cmd,
+            Command::Set(Bytes::from("mykey"), Bytes::from_static(b"myvalue"))
+        );
     }
 
     #[test]
@@ -441,7 +1653,7 @@ mod tests {
     fn parse_del_single_key() {
         let resp = make_cmd(&[b"DEL", b"key1"]);
         let cmd = Command::from_resp(resp).unwrap();
-        assert_eq!(cmd, Command::Del(vec!["key1".to_string()]));
+        assert_eq!(cmd, Command::Del(vec![Bytes::from("key1")]));
     }
 
     #[test]
@@ -451,9 +1663,9 @@ mod tests {
         assert_eq!(
             cmd,
             Command::Del(vec![
-                "key1".to_string(),
-                "key2".to_string(),
-                "key3".to_string()
+                Bytes::from("key1"),
+                Bytes::from("key2"),
+                Bytes::from("key3")
             ])
         );
     }
@@ -471,7 +1683,7 @@ mod tests {
         let cmd = Command::from_resp(resp).unwrap();
         assert_eq!(
             cmd,
-            Command::SetNx("mykey".to_string(), b"myvalue".to_vec())
+            Command::SetNx(Bytes::from("mykey"), Bytes::from_static(b"myvalue"))
         );
     }
 
@@ -481,7 +1693,7 @@ mod tests {
         let cmd = Command::from_resp(resp).unwrap();
         assert_eq!(
             cmd,
-            Command::SetEx("mykey".to_string(), 60, b"myvalue".to_vec())
+            Command::SetEx(Bytes::from("mykey"), 60, Bytes::from_static(b"myvalue"))
         );
     }
 
@@ -496,28 +1708,28 @@ mod tests {
     fn parse_incr_command() {
         let resp = make_cmd(&[b"INCR", b"counter"]);
         let cmd = Command::from_resp(resp).unwrap();
-        assert_eq!(cmd, Command::Incr("counter".to_string()));
+        assert_eq!(cmd, Command::Incr(Bytes::from("counter")));
     }
 
     #[test]
     fn parse_decr_command() {
         let resp = make_cmd(&[b"DECR", b"counter"]);
         let cmd = Command::from_resp(resp).unwrap();
-        assert_eq!(cmd, Command::Decr("counter".to_string()));
+        assert_eq!(cmd, Command::Decr(Bytes::from("counter")));
     }
 
     #[test]
     fn parse_incrby_command() {
         let resp = make_cmd(&[b"INCRBY", b"counter", b"5"]);
         let cmd = Command::from_resp(resp).unwrap();
-        assert_eq!(cmd, Command::IncrBy("counter".to_string(), 5));
+        assert_eq!(cmd, Command::IncrBy(Bytes::from("counter"), 5));
     }
 
     #[test]
     fn parse_decrby_command() {
         let resp = make_cmd(&[b"DECRBY", b"counter", b"5"]);
         let cmd = Command::from_resp(resp).unwrap();
-        assert_eq!(cmd, Command::DecrBy("counter".to_string(), 5));
+        assert_eq!(cmd, Command::DecrBy(Bytes::from("counter"), 5));
     }
 
     #[test]
@@ -527,9 +1739,9 @@ mod tests {
         assert_eq!(
             cmd,
             Command::MGet(vec![
-                "key1".to_string(),
-                "key2".to_string(),
-                "key3".to_string()
+                Bytes::from("key1"),
+                Bytes::from("key2"),
+                Bytes::from("key3")
             ])
         );
     }
@@ -548,8 +1760,8 @@ mod tests {
         assert_eq!(
             cmd,
             Command::MSet(vec![
-                ("key1".to_string(), b"value1".to_vec()),
-                ("key2".to_string(), b"value2".to_vec()),
+                (Bytes::from("key1"), Bytes::from_static(b"value1")),
+                (Bytes::from("key2"), Bytes::from_static(b"value2")),
             ])
         );
     }
@@ -585,41 +1797,72 @@ mod tests {
         let cmd = Command::Ping(Some("hello".to_string()));
         assert_eq!(
             cmd.execute(&store).await,
-            RespValue::BulkString(Some(b"hello".to_vec()))
+            RespValue::BulkString(Some(Bytes::from_static(b"hello")))
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_echo_binary() {
+        let store = Store::new();
+        let cmd = Command::Echo(Bytes::from_static(&[0, 0xff, b'\r', b'\n']));
+        assert_eq!(
+            cmd.execute(&store).await,
+            RespValue::BulkString(Some(Bytes::from_static(&[0, 0xff, b'\r', b'\n'])))
         );
     }
 
+    #[tokio::test]
+    async fn execute_time() {
+        let store = Store::new();
+        let RespValue::Array(Some(parts)) = Command::Time.execute(&store).await else {
+            panic!("TIME should return an array");
+        };
+        assert_eq!(parts.len(), 2);
+        let parse = |v: &RespValue| match v {
+            RespValue::BulkString(Some(b)) => {
+                std::str::from_utf8(b).unwrap().parse::<u64>().unwrap()
+            }
+            other => panic!("unexpected element: {:?}", other),
+        };
+        assert!(parse(&parts[0]) > 1_600_000_000);
+        assert!(parse(&parts[1]) < 1_000_000);
+    }
+
     #[tokio::test]
     async fn execute_set_get() {
         let store = Store::new();
 
-        let set_cmd = Command::Set("key".to_string(), b"value".to_vec());
+        let set_cmd = Command::Set(Bytes::from("key"), Bytes::from_static(b"value"));
         assert_eq!(
             set_cmd.execute(&store).await,
             RespValue::SimpleString("OK".to_string())
         );
 
-        let get_cmd = Command::Get("key".to_string());
+        let get_cmd = Command::Get(Bytes::from("key"));
         assert_eq!(
             get_cmd.execute(&store).await,
-            RespValue::BulkString(Some(b"value".to_vec()))
+            RespValue::BulkString(Some(Bytes::from_static(b"value")))
         );
     }
 
     #[tokio::test]
     async fn execute_get_nonexistent() {
         let store = Store::new();
-        let cmd = Command::Get("nonexistent".to_string());
+        let cmd = Command::Get(Bytes::from("nonexistent"));
         assert_eq!(cmd.execute(&store).await, RespValue::BulkString(None));
     }
 
     #[tokio::test]
     async fn execute_del() {
         let store = Store::new();
-        store.set("key1".to_string(), b"value1".to_vec()).await;
-        store.set("key2".to_string(), b"value2".to_vec()).await;
-
-        let cmd = Command::Del(vec!["key1".to_string(), "key3".to_string()]);
+        store
+            .set(Bytes::from("key1"), Bytes::from_static(b"value1"))
+            .await;
+        store
+            .set(Bytes::from("key2"), Bytes::from_static(b"value2"))
+            .await;
+
+        let cmd = Command::Del(vec![Bytes::from("key1"), Bytes::from("key3")]);
         assert_eq!(cmd.execute(&store).await, RespValue::Integer(1));
     }
 
@@ -627,10 +1870,10 @@ mod tests {
     async fn execute_setnx() {
         let store = Store::new();
 
-        let cmd = Command::SetNx("key".to_string(), b"value1".to_vec());
+        let cmd = Command::SetNx(Bytes::from("key"), Bytes::from_static(b"value1"));
         assert_eq!(cmd.execute(&store).await, RespValue::Integer(1));
 
-        let cmd = Command::SetNx("key".to_string(), b"value2".to_vec());
+        let cmd = Command::SetNx(Bytes::from("key"), Bytes::from_static(b"value2"));
         assert_eq!(cmd.execute(&store).await, RespValue::Integer(0));
     }
 
@@ -638,16 +1881,16 @@ mod tests {
     async fn execute_incr_decr() {
         let store = Store::new();
 
-        let cmd = Command::Incr("counter".to_string());
+        let cmd = Command::Incr(Bytes::from("counter"));
         assert_eq!(cmd.execute(&store).await, RespValue::Integer(1));
 
-        let cmd = Command::IncrBy("counter".to_string(), 5);
+        let cmd = Command::IncrBy(Bytes::from("counter"), 5);
         assert_eq!(cmd.execute(&store).await, RespValue::Integer(6));
 
-        let cmd = Command::Decr("counter".to_string());
+        let cmd = Command::Decr(Bytes::from("counter"));
         assert_eq!(cmd.execute(&store).await, RespValue::Integer(5));
 
-        let cmd = Command::DecrBy("counter".to_string(), 3);
+        let cmd = Command::DecrBy(Bytes::from("counter"), 3);
         assert_eq!(cmd.execute(&store).await, RespValue::Integer(2));
     }
 
@@ -656,8 +1899,8 @@ mod tests {
         let store = Store::new();
 
         let cmd = Command::MSet(vec![
-            ("key1".to_string(), b"value1".to_vec()),
-            ("key2".to_string(), b"value2".to_vec()),
+            (Bytes::from("key1"), Bytes::from_static(b"value1")),
+            (Bytes::from("key2"), Bytes::from_static(b"value2")),
         ]);
         assert_eq!(
             cmd.execute(&store).await,
@@ -665,17 +1908,514 @@ mod tests {
         );
 
         let cmd = Command::MGet(vec![
-            "key1".to_string(),
-            "key2".to_string(),
-            "key3".to_string(),
+            Bytes::from("key1"),
+            Bytes::from("key2"),
+            Bytes::from("key3"),
         ]);
         assert_eq!(
             cmd.execute(&store).await,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"value1".to_vec())),
-                RespValue::BulkString(Some(b"value2".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"value1"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"value2"))),
                 RespValue::BulkString(None),
             ]))
         );
     }
+
+    #[test]
+    fn parse_debug_subcommands() {
+        let cmd = Command::from_resp(make_cmd(&[b"DEBUG", b"sleep", b"0.5"])).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Debug(DebugSubcommand::Sleep(Duration::from_millis(500)))
+        );
+
+        let cmd = Command::from_resp(make_cmd(&[b"DEBUG", b"OBJECT", b"key"])).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Debug(DebugSubcommand::Object(Bytes::from("key")))
+        );
+
+        let cmd = Command::from_resp(make_cmd(&[b"DEBUG", b"SET-ACTIVE-EXPIRE", b"0"])).unwrap();
+        assert_eq!(cmd, Command::Debug(DebugSubcommand::SetActiveExpire(false)));
+
+        let cmd = Command::from_resp(make_cmd(&[b"DEBUG", b"lockstats"])).unwrap();
+        assert_eq!(cmd, Command::Debug(DebugSubcommand::LockStats));
+
+        let cmd = Command::from_resp(make_cmd(&[b"DEBUG", b"JMAP"])).unwrap();
+        assert_eq!(cmd, Command::Debug(DebugSubcommand::NoOp));
+    }
+
+    #[test]
+    fn parse_debug_invalid_returns_error() {
+        assert!(Command::from_resp(make_cmd(&[b"DEBUG"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"DEBUG", b"NOPE"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"DEBUG", b"SLEEP", b"-1"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"DEBUG", b"OBJECT"])).is_err());
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn parse_debug_chaos() {
+        let chaos = |args: &[&[u8]]| {
+            let mut cmd: Vec<&[u8]> = vec![b"DEBUG", b"CHAOS"];
+            cmd.extend(args);
+            Command::from_resp(make_cmd(&cmd))
+        };
+        let fault = |fault| Command::Debug(DebugSubcommand::Chaos(fault));
+
+        assert_eq!(
+            chaos(&[b"latency", b"5"]).unwrap(),
+            fault(ChaosFault::Latency {
+                min: Duration::from_millis(5),
+                max: Duration::from_millis(5)
+            })
+        );
+        assert_eq!(
+            chaos(&[b"LATENCY", b"1", b"2.5"]).unwrap(),
+            fault(ChaosFault::Latency {
+                min: Duration::from_millis(1),
+                max: Duration::from_micros(2500)
+            })
+        );
+        assert_eq!(
+            chaos(&[b"drop", b"0.1"]).unwrap(),
+            fault(ChaosFault::Drop(0.1))
+        );
+        assert_eq!(
+            chaos(&[b"partial-writes", b"3"]).unwrap(),
+            fault(ChaosFault::PartialWrites(3))
+        );
+        assert_eq!(
+            chaos(&[b"save-delay", b"100"]).unwrap(),
+            fault(ChaosFault::SaveDelay(Duration::from_millis(100)))
+        );
+        assert_eq!(chaos(&[b"off"]).unwrap(), fault(ChaosFault::Off));
+        assert_eq!(chaos(&[]).unwrap(), fault(ChaosFault::Status));
+
+        for bad in [
+            &[&b"latency"[..]][..],
+            &[b"latency", b"5", b"1"],
+            &[b"drop", b"1.5"],
+            &[b"partial-writes", b"-1"],
+            &[b"off", b"now"],
+            &[b"meteor"],
+        ] {
+            assert!(chaos(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    #[test]
+    fn debug_chaos_needs_the_feature() {
+        let cmd = make_cmd(&[b"DEBUG", b"CHAOS", b"DROP", b"1"]);
+        assert!(Command::from_resp(cmd).is_err());
+    }
+
+    #[test]
+    fn command_names_match_the_table() {
+        for args in [
+            &[b"GET".as_slice(), b"k"][..],
+            &[b"mset", b"a", b"1", b"b", b"2"],
+            &[b"Del", b"a", b"b", b"c"],
+            &[b"command", b"count"],
+            &[b"info"],
+        ] {
+            let cmd = Command::from_resp(make_cmd(args)).unwrap();
+            assert_eq!(lookup_command(args[0]).unwrap().name, cmd.name());
+        }
+        let del = Command::from_resp(make_cmd(&[b"DEL", b"a", b"b", b"c"])).unwrap();
+        assert_eq!(del.key_count(), 3);
+        let mset = Command::from_resp(make_cmd(&[b"MSET", b"a", b"1", b"b", b"2"])).unwrap();
+        assert_eq!(mset.key_count(), 2);
+        assert_eq!(Command::Time.key_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn execute_info_memory() {
+        let store = Store::new();
+        store.set(Bytes::from("a"), Bytes::from("1")).await;
+
+        let cmd = Command::from_resp(make_cmd(&[b"INFO", b"MEMORY"])).unwrap();
+        assert_eq!(cmd, Command::Info(vec!["memory".to_string()]));
+        match cmd.execute(&store).await {
+            RespValue::BulkString(Some(reply)) => {
+                let reply = String::from_utf8(reply.to_vec()).unwrap();
+                assert!(reply.starts_with("# Memory\r\n"));
+                assert!(reply.contains("keyspace_keys:1\r\n"));
+                assert!(reply.contains("keyspace_fragmentation_ratio:"));
+                #[cfg(target_os = "linux")]
+                assert!(reply.contains("used_memory_rss:"), "{}", reply);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let cmd = Command::from_resp(make_cmd(&[b"GET", b"a"])).unwrap();
+        cmd.execute(&store).await;
+        let cmd = Command::from_resp(make_cmd(&[b"MGET", b"a", b"b"])).unwrap();
+        cmd.execute(&store).await;
+        let cmd = Command::from_resp(make_cmd(&[b"INFO", b"stats"])).unwrap();
+        match cmd.execute(&store).await {
+            RespValue::BulkString(Some(reply)) => {
+                let reply = String::from_utf8(reply.to_vec()).unwrap();
+                assert!(reply.starts_with(
+                    "# Stats\r\nexpired_keys:0\r\nevicted_keys:0\r\n\
+                     keyspace_hits:2\r\nkeyspace_misses:1\r\nkeyspace_lock_acquisitions:"
+                ));
+                assert!(reply.contains("\r\nkeyspace_lock_contended:0\r\n"));
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // Command statistics are only included on request
+        store
+            .command_stats()
+            .record("get", Duration::from_micros(3), false, true);
+        let cmd = Command::from_resp(make_cmd(&[b"INFO"])).unwrap();
+        match cmd.execute(&store).await {
+            RespValue::BulkString(Some(reply)) => {
+                assert!(!String::from_utf8_lossy(&reply).contains("cmdstat_"))
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let cmd =
+            Command::from_resp(make_cmd(&[b"INFO", b"commandstats", b"latencystats"])).unwrap();
+        match cmd.execute(&store).await {
+            RespValue::BulkString(Some(reply)) => {
+                let reply = String::from_utf8(reply.to_vec()).unwrap();
+                assert!(reply.starts_with("# Commandstats\r\ncmdstat_get:calls=1,"));
+                assert!(reply.contains("\r\n\r\n# Latencystats\r\nlatency_percentiles_usec_get:"));
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let cmd = Command::from_resp(make_cmd(&[b"INFO", b"nosuchsection"])).unwrap();
+        assert_eq!(
+            cmd.execute(&store).await,
+            RespValue::BulkString(Some(Bytes::new()))
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_debug_object() {
+        let store = Store::new();
+        store
+            .set(Bytes::from("num"), Bytes::from_static(b"12345"))
+            .await;
+        store
+            .set(Bytes::from("long"), Bytes::from(vec![b'x'; 100]))
+            .await;
+
+        let cmd = Command::Debug(DebugSubcommand::Object(Bytes::from("num")));
+        match cmd.execute(&store).await {
+            RespValue::SimpleString(s) => assert!(s.contains("encoding:int")),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let cmd = Command::Debug(DebugSubcommand::Object(Bytes::from("long")));
+        match cmd.execute(&store).await {
+            RespValue::SimpleString(s) => {
+                assert!(s.contains("encoding:raw"));
+                assert!(s.contains("serializedlength:100"));
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let cmd = Command::Debug(DebugSubcommand::Object(Bytes::from("missing")));
+        assert_eq!(cmd.execute(&store).await, CommandError::NoSuchKey.into());
+    }
+
+    #[tokio::test]
+    async fn execute_debug_set_active_expire() {
+        let store = Store::new();
+        let cmd = Command::Debug(DebugSubcommand::SetActiveExpire(false));
+        assert_eq!(
+            cmd.execute(&store).await,
+            RespValue::SimpleString("OK".to_string())
+        );
+        assert!(!store.active_expire_enabled());
+    }
+
+    #[tokio::test]
+    async fn execute_debug_lockstats() {
+        let store = Store::with_shards(2);
+        store.set(Bytes::from("a"), Bytes::from("1")).await;
+        let cmd = Command::Debug(DebugSubcommand::LockStats);
+        match cmd.execute(&store).await {
+            RespValue::BulkString(Some(reply)) => {
+                let reply = String::from_utf8(reply.to_vec()).unwrap();
+                let lines: Vec<&str> = reply.lines().collect();
+                assert_eq!(lines.len(), 2);
+                assert!(lines[0].starts_with("shard_0:acquisitions="));
+                assert!(reply.contains(",contended=0,contended_pct=0.00,wait_usec=0,"));
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let store = Store::with_backend(crate::store::KeyspaceBackend::Owned, 2);
+        assert!(matches!(
+            cmd.execute(&store).await,
+            RespValue::Error(e) if e.contains("sharded")
+        ));
+    }
+
+    #[test]
+    fn parse_scan_options() {
+        let cmd = Command::from_resp(make_cmd(&[b"SCAN", b"0"])).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Scan(ScanArgs {
+                cursor: 0,
+                pattern: None,
+                count: 10,
+                kind: None,
+            })
+        );
+        let cmd = Command::from_resp(make_cmd(&[
+            b"scan", b"42", b"match", b"user:*", b"COUNT", b"100", b"TYPE", b"String",
+        ]))
+        .unwrap();
+        assert_eq!(
+            cmd,
+            Command::Scan(ScanArgs {
+                cursor: 42,
+                pattern: Some(Bytes::from_static(b"user:*")),
+                count: 100,
+                kind: Some("string".to_string()),
+            })
+        );
+
+        let err = Command::from_resp(make_cmd(&[b"SCAN", b"-1"])).unwrap_err();
+        assert_eq!(err.to_string(), "ERR invalid cursor");
+        assert!(Command::from_resp(make_cmd(&[b"SCAN", b"0", b"COUNT", b"0"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"SCAN", b"0", b"MATCH"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"SCAN", b"0", b"LIMIT", b"1"])).is_err());
+    }
+
+    #[test]
+    fn parse_memory_usage() {
+        let cmd = Command::from_resp(make_cmd(&[b"MEMORY", b"USAGE", b"k"])).unwrap();
+        assert_eq!(cmd, Command::MemoryUsage(Bytes::from_static(b"k")));
+        let cmd =
+            Command::from_resp(make_cmd(&[b"memory", b"usage", b"k", b"SAMPLES", b"0"])).unwrap();
+        assert_eq!(cmd, Command::MemoryUsage(Bytes::from_static(b"k")));
+
+        assert!(Command::from_resp(make_cmd(&[b"MEMORY", b"USAGE"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"MEMORY", b"USAGE", b"k", b"SAMPLES"])).is_err());
+        let err = Command::from_resp(make_cmd(&[b"MEMORY", b"DOCTOR"])).unwrap_err();
+        assert!(err.to_string().contains("Try MEMORY HELP"));
+    }
+
+    #[tokio::test]
+    async fn execute_keyspace_inspection() {
+        let store = Store::new();
+        store.set(Bytes::from("k"), Bytes::from("hello")).await;
+
+        let scan = |kind: Option<&str>| {
+            Command::Scan(ScanArgs {
+                cursor: 0,
+                pattern: None,
+                count: 10,
+                kind: kind.map(str::to_string),
+            })
+        };
+        let page = |keys: Vec<RespValue>| {
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(Bytes::from_static(b"0"))),
+                RespValue::Array(Some(keys)),
+            ]))
+        };
+        assert_eq!(
+            scan(None).execute(&store).await,
+            page(vec![RespValue::BulkString(Some(Bytes::from_static(b"k")))])
+        );
+        assert_eq!(scan(Some("hash")).execute(&store).await, page(Vec::new()));
+
+        let kind = |key: &'static [u8]| Command::Type(Bytes::from_static(key));
+        assert_eq!(
+            kind(b"k").execute(&store).await,
+            RespValue::SimpleString("string".to_string())
+        );
+        assert_eq!(
+            kind(b"nope").execute(&store).await,
+            RespValue::SimpleString("none".to_string())
+        );
+        assert_eq!(
+            Command::Strlen(Bytes::from("k")).execute(&store).await,
+            RespValue::Integer(5)
+        );
+        assert_eq!(Command::DbSize.execute(&store).await, RespValue::Integer(1));
+        assert!(matches!(
+            Command::MemoryUsage(Bytes::from("k")).execute(&store).await,
+            RespValue::Integer(bytes) if bytes > 5
+        ));
+        assert_eq!(
+            Command::MemoryUsage(Bytes::from("nope"))
+                .execute(&store)
+                .await,
+            RespValue::BulkString(None)
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_select_and_wait() {
+        let store = Store::new();
+        let run = |args: &[&[u8]]| Command::from_resp(make_cmd(args));
+        assert_eq!(
+            run(&[b"SELECT", b"0"]).unwrap().execute(&store).await,
+            RespValue::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            run(&[b"SELECT", b"3"]).unwrap().execute(&store).await,
+            CommandError::DbIndexOutOfRange.into()
+        );
+        assert!(run(&[b"SELECT", b"one"]).is_err());
+
+        assert_eq!(
+            run(&[b"WAIT", b"0", b"0"]).unwrap().execute(&store).await,
+            RespValue::Integer(0)
+        );
+        // Waiting for a replica times out, as there are none
+        let start = std::time::Instant::now();
+        assert_eq!(
+            run(&[b"WAIT", b"1", b"100"]).unwrap().execute(&store).await,
+            RespValue::Integer(0)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        let err = run(&[b"WAIT", b"1", b"-1"]).unwrap_err();
+        assert_eq!(err.to_string(), "ERR timeout is negative");
+
+        // No cluster mode: even a manual failover is refused, as by Redis
+        let failover = run(&[b"CLUSTER", b"failover", b"TAKEOVER"]).unwrap();
+        assert_eq!(failover, Command::Cluster("FAILOVER".to_string()));
+        assert_eq!(
+            failover.execute(&store).await,
+            CommandError::ClusterDisabled.into()
+        );
+        assert!(run(&[b"CLUSTER"]).is_err());
+        // Nor replica reads, so cluster clients fall back to the primary
+        for (name, readonly) in [(&b"READONLY"[..], true), (b"readwrite", false)] {
+            let cmd = run(&[name]).unwrap();
+            assert_eq!(cmd, Command::ReadOnly(readonly));
+            assert_eq!(
+                cmd.execute(&store).await,
+                CommandError::ClusterDisabled.into()
+            );
+        }
+        assert!(run(&[b"READONLY", b"extra"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn execute_refuses_oversized_writes() {
+        let store = Store::new();
+        store.set_size_limits(8, 4);
+        let run = |args: &[&[u8]]| Command::from_resp(make_cmd(args)).unwrap();
+        let refused =
+            |reply: RespValue| matches!(reply, RespValue::Error(e) if e.contains("larger than"));
+
+        assert!(refused(
+            run(&[b"SET", b"key", b"too long"]).execute(&store).await
+        ));
+        assert!(refused(
+            run(&[b"SETEX", b"key", b"10", b"too long"])
+                .execute(&store)
+                .await
+        ));
+        assert!(refused(
+            run(&[b"INCR", b"very long key"]).execute(&store).await
+        ));
+        // MSET is refused whole
+        let mset = run(&[b"MSET", b"a", b"1", b"b", b"too long"]);
+        assert!(refused(mset.execute(&store).await));
+        assert_eq!(store.get(b"a").await, None);
+
+        assert_eq!(
+            run(&[b"SET", b"key", b"fits"]).execute(&store).await,
+            RespValue::SimpleString("OK".to_string())
+        );
+        // Reads of keys too long to have been written just miss
+        assert_eq!(
+            run(&[b"GET", b"very long key"]).execute(&store).await,
+            RespValue::BulkString(None)
+        );
+    }
+
+    #[test]
+    fn parse_config_get() {
+        let cmd = Command::from_resp(make_cmd(&[b"config", b"get", b"SAVE", b"max*"])).unwrap();
+        assert_eq!(
+            cmd,
+            Command::ConfigGet(vec!["save".to_string(), "max*".to_string()])
+        );
+        assert!(Command::from_resp(make_cmd(&[b"CONFIG", b"GET"])).is_err());
+        let err = Command::from_resp(make_cmd(&[b"CONFIG", b"SET", b"port", b"1"])).unwrap_err();
+        assert!(err.to_string().contains("Try CONFIG HELP"));
+    }
+
+    #[test]
+    fn parse_acl_save_and_load() {
+        let cmd = Command::from_resp(make_cmd(&[b"acl", b"save"])).unwrap();
+        assert_eq!(cmd, Command::AclSave);
+        let cmd = Command::from_resp(make_cmd(&[b"ACL", b"LOAD"])).unwrap();
+        assert_eq!(cmd, Command::AclLoad);
+        assert!(Command::from_resp(make_cmd(&[b"ACL"])).is_err());
+        assert!(Command::from_resp(make_cmd(&[b"ACL", b"SAVE", b"now"])).is_err());
+        let err = Command::from_resp(make_cmd(&[b"ACL", b"SETUSER", b"app"])).unwrap_err();
+        assert!(err.to_string().contains("Try ACL HELP"));
+    }
+
+    #[test]
+    fn parse_quit_ignores_arguments() {
+        // Redis accepts and ignores any arguments to QUIT
+        let cmd = Command::from_resp(make_cmd(&[b"QUIT"])).unwrap();
+        assert_eq!(cmd, Command::Quit);
+        let cmd = Command::from_resp(make_cmd(&[b"quit", b"now"])).unwrap();
+        assert_eq!(cmd, Command::Quit);
+    }
+
+    #[test]
+    fn renamed_command_resolves_to_original() {
+        let renames = CommandRenames::new(&HashMap::from([(
+            "DEBUG".to_string(),
+            "SECRET-DEBUG".to_string(),
+        )]));
+
+        let value = renames
+            .resolve(make_cmd(&[b"secret-debug", b"JMAP"]))
+            .unwrap();
+        let cmd = Command::from_resp(value).unwrap();
+        assert_eq!(cmd, Command::Debug(DebugSubcommand::NoOp));
+
+        let result = renames.resolve(make_cmd(&[b"debug", b"JMAP"]));
+        assert!(result.unwrap_err().to_string().contains("unknown command"));
+    }
+
+    #[test]
+    fn disabled_command_is_unknown() {
+        let renames = CommandRenames::new(&HashMap::from([("DEBUG".to_string(), String::new())]));
+        assert!(renames.resolve(make_cmd(&[b"DEBUG", b"JMAP"])).is_err());
+        assert!(renames.resolve(make_cmd(&[b"PING"])).is_ok());
+    }
+
+    #[test]
+    fn parse_shutdown_modes() {
+        let cmd = Command::from_resp(make_cmd(&[b"SHUTDOWN"])).unwrap();
+        assert_eq!(cmd, Command::Shutdown(ShutdownMode::Default));
+
+        let cmd = Command::from_resp(make_cmd(&[b"shutdown", b"nosave"])).unwrap();
+        assert_eq!(cmd, Command::Shutdown(ShutdownMode::NoSave));
+
+        let cmd = Command::from_resp(make_cmd(&[b"SHUTDOWN", b"SAVE"])).unwrap();
+        assert_eq!(cmd, Command::Shutdown(ShutdownMode::Save));
+
+        assert!(Command::from_resp(make_cmd(&[b"SHUTDOWN", b"LATER"])).is_err());
+    }
+
+    #[test]
+    fn parse_bgsave_schedule() {
+        for args in [&[&b"BGSAVE"[..]][..], &[b"bgsave", b"schedule"]] {
+            assert_eq!(Command::from_resp(make_cmd(args)).unwrap(), Command::Bgsave);
+        }
+        assert!(Command::from_resp(make_cmd(&[b"BGSAVE", b"NOW"])).is_err());
+    }
 }
@@ -0,0 +1,1709 @@
+use crate::backup::Schedule;
+use crate::encryption::EncryptionKey;
+use crate::log::Rotation;
+use crate::otlp::Endpoint;
+use crate::ratelimit::RateLimitMode;
+use crate::resp::ParseLimits;
+use crate::server::IoBackend;
+use crate::store::{DEFAULT_SHARDS, KeyspaceBackend, glob_match};
+use crate::systemd::Supervised;
+use crate::tenant::TenantSpec;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_BIND: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 6379;
+const DEFAULT_QUERY_BUFFER_LIMIT: usize = 1024 * 1024 * 1024;
+const DEFAULT_MAX_CLIENTS: usize = 10_000;
+/// Most `ttl-jitter` may move an expiration, so no TTL is jittered to zero
+const MAX_TTL_JITTER: u32 = 50;
+
+/// Server configuration, loaded redis.conf-style from a file and/or
+/// `--directive value` command-line arguments (the latter take precedence)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    /// Original command name (uppercase) to its new name; an empty name disables the command
+    pub rename_commands: HashMap<String, String>,
+    /// Refuse non-loopback clients while no password is configured
+    pub protected_mode: bool,
+    /// If non-empty, only clients inside one of these networks may connect
+    pub allowlist: Vec<IpNet>,
+    /// Close client connections idle for longer than this (zero disables)
+    pub timeout: Duration,
+    /// Close client connections that take longer than this to finish sending
+    /// a frame they have started, however much they trickle in (zero disables)
+    pub client_frame_timeout: Duration,
+    /// Most clients connected at once; more are refused with an error
+    pub maxclients: usize,
+    /// Directory snapshots are written to, by BGSAVE and `backup-schedule`
+    pub dir: PathBuf,
+    /// File name of the snapshot inside `dir`
+    pub dbfilename: String,
+    /// When to back the keyspace up to `dir`; None for never
+    pub backup_schedule: Option<Schedule>,
+    /// Days to keep the day's newest backup for
+    pub backup_keep_daily: usize,
+    /// Weeks to keep the week's newest backup for
+    pub backup_keep_weekly: usize,
+    /// Key backups and recordings are encrypted with; None writes them in
+    /// the clear
+    pub encryption_key: Option<EncryptionKey>,
+    /// Size limits enforced while parsing client requests
+    pub proto_limits: ParseLimits,
+    /// Most unparsed bytes a single client may have buffered before it is disconnected
+    pub client_query_buffer_limit: usize,
+    /// Commands a connection runs from one pipeline before yielding to other
+    /// connections; 0 never yields
+    pub client_pipeline_budget: usize,
+    /// Bytes a connection reads at a time to start with, and again once idle
+    pub client_read_buffer_low: usize,
+    /// Most bytes a connection reads at a time, reached by doubling while
+    /// its reads keep filling the buffer
+    pub client_read_buffer_high: usize,
+    /// TCP keepalive idle time for client sockets (zero disables keepalive)
+    pub tcp_keepalive: Duration,
+    /// Disable Nagle's algorithm on client sockets
+    pub tcp_nodelay: bool,
+    /// SO_LINGER timeout applied on close; None keeps the OS default
+    pub so_linger: Option<Duration>,
+    /// Bind one SO_REUSEPORT listener per worker thread so the kernel spreads accepts
+    pub reuseport: bool,
+    /// Commands per second allowed per client address, with burst size; None disables
+    pub client_rate_limit: Option<(u32, u32)>,
+    /// Whether clients over their rate limit are delayed or get an error
+    pub client_rate_limit_mode: RateLimitMode,
+    /// Number of independently locked keyspace partitions
+    pub keyspace_shards: usize,
+    /// Map implementation holding the keyspace
+    pub keyspace_backend: KeyspaceBackend,
+    /// Network I/O implementation serving clients
+    pub io_backend: IoBackend,
+    /// File the log is appended to; None logs to stdout
+    pub logfile: Option<PathBuf>,
+    /// When the log file is rotated
+    pub log_rotation: Rotation,
+    /// OTLP/HTTP collector that command spans are exported to; None disables tracing
+    pub otlp_endpoint: Option<Endpoint>,
+    /// `service.name` reported with exported spans
+    pub otlp_service_name: String,
+    /// Record per-command latency histograms for INFO latencystats
+    pub latency_tracking: bool,
+    /// File every inbound command is recorded to, for replay; None disables recording
+    pub record_file: Option<PathBuf>,
+    /// Recording whose writes are replayed into the store at startup
+    pub restore_file: Option<PathBuf>,
+    /// Replay only the commands `restore_file` recorded before this time
+    pub restore_until: Option<SystemTime>,
+    /// Replay only the first this many commands `restore_file` recorded
+    pub restore_until_offset: Option<usize>,
+    /// Fixture of commands or redis-dump JSON loaded into the store at startup
+    pub seed_file: Option<PathBuf>,
+    /// Whether to send readiness and shutdown notifications to systemd
+    pub supervised: Supervised,
+    /// How long clients may take to receive their pending replies on shutdown
+    pub shutdown_drain_timeout: Duration,
+    /// Port answering HTTP health probes on the bind address; 0 disables them
+    pub probe_port: u16,
+    /// Port serving the read-only admin HTTP API on the bind address; 0 disables it
+    pub admin_port: u16,
+    /// Redis server to read through to and write behind to; None disables both
+    pub upstream: Option<String>,
+    /// Fetch GET misses from `upstream`
+    pub upstream_read_through: bool,
+    /// Longest a value read through from upstream is kept locally
+    pub upstream_ttl: Duration,
+    /// Send writes that succeeded locally on to `upstream`
+    pub upstream_write_behind: bool,
+    /// Most writes held for `upstream` before new ones are dropped
+    pub upstream_write_behind_queue: usize,
+    /// Most bytes of writes sent to `upstream` a second; 0 for no limit
+    pub upstream_write_behind_rate: usize,
+    /// Instances to replicate to and merge from, active-active; empty for off
+    pub crdt_peers: Vec<String>,
+    /// This instance's id among its peers; 0 picks one at random
+    pub crdt_node_id: u64,
+    /// Secret peers send with CRDT AUTH before merging on the client port;
+    /// None takes merges only over `crdt-tls-port`
+    pub crdt_secret: Option<String>,
+    /// Connect to `crdt-peers` over TLS, verifying their certificates
+    pub crdt_tls: bool,
+    /// Port taking links from `crdt-peers` over TLS on the bind address;
+    /// 0 disables it
+    pub crdt_tls_port: u16,
+    /// PEM certificate chain this node presents to its peers, both ways
+    pub crdt_tls_cert_file: Option<PathBuf>,
+    /// PEM private key of `crdt_tls_cert_file`
+    pub crdt_tls_key_file: Option<PathBuf>,
+    /// PEM certificates of the CAs peers' certificates must be issued by
+    pub crdt_tls_ca_cert_file: Option<PathBuf>,
+    /// Largest key write commands may store, in bytes; 0 for no limit
+    pub max_key_size: usize,
+    /// Largest value write commands may store, in bytes; 0 for no limit
+    pub max_value_size: usize,
+    /// Percentage SETEX and EXPIRE move each expiration by at random, either way
+    pub ttl_jitter: u32,
+    /// Longest a read command that isn't O(1), like KEYS or SCAN, may run
+    /// before it is aborted; zero for no limit
+    pub command_time_budget: Duration,
+    /// p99 command latency past which slow reads and new connections are
+    /// refused; zero turns it off
+    pub shed_latency: Duration,
+    /// Tasks waiting in the runtime's global queue past which slow reads and
+    /// new connections are refused; 0 turns it off
+    pub shed_queue_depth: usize,
+    /// Users AUTH can log in as, each confined to a key prefix
+    pub tenants: Vec<TenantSpec>,
+    /// Refuse commands from clients that haven't logged in as a tenant
+    pub tenant_required: bool,
+    /// File the tenants are saved to by ACL SAVE and read from at startup
+    /// and by ACL LOAD; None keeps them in memory only
+    pub aclfile: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: DEFAULT_BIND.to_string(),
+            port: DEFAULT_PORT,
+            rename_commands: HashMap::new(),
+            protected_mode: true,
+            allowlist: Vec::new(),
+            timeout: Duration::ZERO,
+            client_frame_timeout: Duration::ZERO,
+            maxclients: DEFAULT_MAX_CLIENTS,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            backup_schedule: None,
+            backup_keep_daily: 7,
+            backup_keep_weekly: 4,
+            encryption_key: None,
+            proto_limits: ParseLimits::default(),
+            client_query_buffer_limit: DEFAULT_QUERY_BUFFER_LIMIT,
+            client_pipeline_budget: 256,
+            client_read_buffer_low: 4 * 1024,
+            client_read_buffer_high: 256 * 1024,
+            tcp_keepalive: Duration::from_secs(300),
+            tcp_nodelay: true,
+            so_linger: None,
+            reuseport: false,
+            client_rate_limit: None,
+            client_rate_limit_mode: RateLimitMode::Delay,
+            keyspace_shards: DEFAULT_SHARDS,
+            keyspace_backend: KeyspaceBackend::Sharded,
+            io_backend: IoBackend::Tokio,
+            logfile: None,
+            log_rotation: Rotation::default(),
+            otlp_endpoint: None,
+            otlp_service_name: "rudis".to_string(),
+            latency_tracking: true,
+            record_file: None,
+            restore_file: None,
+            restore_until: None,
+            restore_until_offset: None,
+            seed_file: None,
+            supervised: Supervised::Auto,
+            shutdown_drain_timeout: Duration::from_secs(10),
+            probe_port: 0,
+            admin_port: 0,
+            upstream: None,
+            upstream_read_through: true,
+            upstream_ttl: Duration::from_secs(60),
+            upstream_write_behind: false,
+            upstream_write_behind_queue: 10_000,
+            upstream_write_behind_rate: 0,
+            crdt_peers: Vec::new(),
+            crdt_node_id: 0,
+            crdt_secret: None,
+            crdt_tls: false,
+            crdt_tls_port: 0,
+            crdt_tls_cert_file: None,
+            crdt_tls_key_file: None,
+            crdt_tls_ca_cert_file: None,
+            max_key_size: 0,
+            max_value_size: 0,
+            ttl_jitter: 0,
+            command_time_budget: Duration::ZERO,
+            shed_latency: Duration::ZERO,
+            shed_queue_depth: 0,
+            tenants: Vec::new(),
+            tenant_required: false,
+            aclfile: None,
+        }
+    }
+}
+
+impl Config {
+    /// Build a config from process arguments (without the program name):
+    /// `rudis [/path/to/rudis.conf] [--directive arg ...]...`
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut config = Config::default();
+        let mut args = args.into_iter().peekable();
+
+        if let Some(path) = args.next_if(|a| !a.starts_with("--")) {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Can't open config file '{}': {}", path, e))?;
+            config.load_str(&contents)?;
+        }
+
+        while let Some(arg) = args.next() {
+            let Some(directive) = arg.strip_prefix("--") else {
+                return Err(anyhow!("Unexpected argument '{}'", arg));
+            };
+            let mut values = Vec::new();
+            while let Some(value) = args.next_if(|a| !a.starts_with("--")) {
+                values.push(value);
+            }
+            config.apply(directive, &values)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Apply every directive in a redis.conf-style string
+    pub fn load_str(&mut self, contents: &str) -> Result<()> {
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = split_config_line(line)
+                .ok_or_else(|| anyhow!("line {}: unbalanced quotes", index + 1))?;
+            if let Some((directive, values)) = words.split_first() {
+                self.apply(directive, values)
+                    .map_err(|e| anyhow!("line {}: {}", index + 1, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a single directive
+    pub fn apply(&mut self, directive: &str, values: &[String]) -> Result<()> {
+        match (directive.to_lowercase().as_str(), values) {
+            ("bind", [addr]) => self.bind = addr.clone(),
+            ("port", [port]) => {
+                self.port = port
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid port '{}'", port))?
+            }
+            ("probe-port", [port]) => {
+                self.probe_port = port
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid port '{}'", port))?
+            }
+            ("admin-port", [port]) => {
+                self.admin_port = port
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid port '{}'", port))?
+            }
+            ("upstream", [addr]) => self.upstream = (!addr.is_empty()).then(|| addr.clone()),
+            ("upstream-read-through", [flag]) => self.upstream_read_through = parse_yes_no(flag)?,
+            ("upstream-write-behind", [flag]) => self.upstream_write_behind = parse_yes_no(flag)?,
+            ("upstream-write-behind-queue", [len]) => {
+                self.upstream_write_behind_queue = parse_count(len)?
+            }
+            ("upstream-write-behind-rate", [rate]) => {
+                self.upstream_write_behind_rate = parse_size_limit(rate)?
+            }
+            ("crdt-peers", [peers]) if peers.is_empty() => self.crdt_peers.clear(),
+            ("crdt-peers", peers) if !peers.is_empty() => self.crdt_peers = peers.to_vec(),
+            ("crdt-node-id", [id]) if id == "0" => self.crdt_node_id = 0,
+            ("crdt-node-id", [id]) => self.crdt_node_id = parse_count(id)?,
+            ("crdt-secret", [secret]) => {
+                self.crdt_secret = (!secret.is_empty()).then(|| secret.clone())
+            }
+            ("crdt-tls", [flag]) => self.crdt_tls = parse_yes_no(flag)?,
+            ("crdt-tls-port", [port]) => {
+                self.crdt_tls_port = port
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid port '{}'", port))?
+            }
+            ("crdt-tls-cert-file", [path]) => {
+                self.crdt_tls_cert_file = (!path.is_empty()).then(|| PathBuf::from(path))
+            }
+            ("crdt-tls-key-file", [path]) => {
+                self.crdt_tls_key_file = (!path.is_empty()).then(|| PathBuf::from(path))
+            }
+            ("crdt-tls-ca-cert-file", [path]) => {
+                self.crdt_tls_ca_cert_file = (!path.is_empty()).then(|| PathBuf::from(path))
+            }
+            ("upstream-ttl", [seconds]) => {
+                self.upstream_ttl = parse_seconds(seconds)?;
+                if self.upstream_ttl.is_zero() {
+                    return Err(anyhow!("upstream-ttl must be at least 1 second"));
+                }
+            }
+            ("max-key-size", [size]) => self.max_key_size = parse_size_limit(size)?,
+            ("max-value-size", [size]) => self.max_value_size = parse_size_limit(size)?,
+            ("ttl-jitter", [percent]) => {
+                self.ttl_jitter = percent
+                    .trim_end_matches('%')
+                    .parse()
+                    .ok()
+                    .filter(|percent| *percent <= MAX_TTL_JITTER)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "ttl-jitter must be a percentage from 0 to {}",
+                            MAX_TTL_JITTER
+                        )
+                    })?
+            }
+            ("command-time-budget", [millis]) => self.command_time_budget = parse_millis(millis)?,
+            ("shed-latency", [millis]) => self.shed_latency = parse_millis(millis)?,
+            ("shed-queue-depth", [depth]) if depth == "0" => self.shed_queue_depth = 0,
+            ("shed-queue-depth", [depth]) => self.shed_queue_depth = parse_count(depth)?,
+            ("tenant", [name, password, prefix, limits @ ..]) => {
+                let tenant = parse_tenant(name, password, prefix, limits)?;
+                // Defining a tenant again replaces it
+                self.tenants.retain(|existing| existing.name != tenant.name);
+                self.tenants.push(tenant);
+            }
+            ("tenant-required", [flag]) => self.tenant_required = parse_yes_no(flag)?,
+            ("aclfile", [path]) => self.aclfile = (!path.is_empty()).then(|| PathBuf::from(path)),
+            ("rename-command", [from, to]) => {
+                self.rename_commands
+                    .insert(from.to_uppercase(), to.to_uppercase());
+            }
+            ("protected-mode", [flag]) => self.protected_mode = parse_yes_no(flag)?,
+            ("allow-cidr", networks) if !networks.is_empty() => {
+                for network in networks {
+                    self.allowlist.push(network.parse()?);
+                }
+            }
+            ("timeout", [seconds]) => self.timeout = parse_seconds(seconds)?,
+            ("client-frame-timeout", [seconds]) => {
+                self.client_frame_timeout = parse_seconds(seconds)?
+            }
+            ("maxclients", [count]) => self.maxclients = parse_count(count)?,
+            ("dir", [path]) => self.dir = PathBuf::from(path),
+            ("dbfilename", [name]) => self.dbfilename = name.clone(),
+            // Unquoted on the command line, the fields come as separate values
+            ("backup-schedule", [schedule]) if schedule.is_empty() => self.backup_schedule = None,
+            ("backup-schedule", fields) if !fields.is_empty() => {
+                self.backup_schedule = Some(fields.join(" ").parse()?)
+            }
+            ("backup-keep-daily", [days]) => self.backup_keep_daily = parse_retention(days)?,
+            ("backup-keep-weekly", [weeks]) => self.backup_keep_weekly = parse_retention(weeks)?,
+            ("encryption-key", [source]) if source.is_empty() => self.encryption_key = None,
+            ("encryption-key", [source]) => {
+                self.encryption_key = Some(EncryptionKey::load(source)?)
+            }
+            ("proto-max-bulk-len", [size]) => self.proto_limits.max_bulk_len = parse_memory(size)?,
+            ("proto-max-multibulk-len", [len]) => {
+                self.proto_limits.max_array_len = parse_count(len)?
+            }
+            ("client-query-buffer-limit", [size]) => {
+                self.client_query_buffer_limit = parse_memory(size)?
+            }
+            ("client-pipeline-budget", [count]) => {
+                self.client_pipeline_budget = count
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid number of commands '{}'", count))?
+            }
+            ("client-read-buffer-low", [size]) => {
+                self.client_read_buffer_low = parse_memory(size)?;
+                if self.client_read_buffer_low == 0 {
+                    return Err(anyhow!("client-read-buffer-low must be at least 1 byte"));
+                }
+            }
+            ("client-read-buffer-high", [size]) => {
+                self.client_read_buffer_high = parse_memory(size)?;
+                if self.client_read_buffer_high == 0 {
+                    return Err(anyhow!("client-read-buffer-high must be at least 1 byte"));
+                }
+            }
+            ("tcp-keepalive", [seconds]) => self.tcp_keepalive = parse_seconds(seconds)?,
+            ("tcp-nodelay", [flag]) => self.tcp_nodelay = parse_yes_no(flag)?,
+            ("reuseport", [flag]) => {
+                self.reuseport = parse_yes_no(flag)?;
+                if self.reuseport && !cfg!(unix) {
+                    return Err(anyhow!("SO_REUSEPORT is not supported on this platform"));
+                }
+            }
+            ("so-linger", [seconds]) => {
+                self.so_linger = match seconds.as_str() {
+                    "-1" => None,
+                    seconds => Some(parse_seconds(seconds)?),
+                }
+            }
+            ("client-rate-limit", [ops]) if ops == "0" => self.client_rate_limit = None,
+            ("client-rate-limit", [ops]) => {
+                let ops = parse_count(ops)?;
+                self.client_rate_limit = Some((ops, ops));
+            }
+            ("client-rate-limit", [ops, burst]) => {
+                self.client_rate_limit = Some((parse_count(ops)?, parse_count(burst)?));
+            }
+            ("client-rate-limit-mode", [mode]) => {
+                self.client_rate_limit_mode = match mode.to_lowercase().as_str() {
+                    "delay" => RateLimitMode::Delay,
+                    "reject" => RateLimitMode::Reject,
+                    _ => return Err(anyhow!("argument must be 'delay' or 'reject'")),
+                }
+            }
+            ("keyspace-shards", [count]) => self.keyspace_shards = parse_count(count)?,
+            ("keyspace-backend", [backend]) => {
+                self.keyspace_backend = match backend.to_lowercase().as_str() {
+                    "sharded" => KeyspaceBackend::Sharded,
+                    #[cfg(feature = "dashmap")]
+                    "dashmap" => KeyspaceBackend::DashMap,
+                    #[cfg(not(feature = "dashmap"))]
+                    "dashmap" => {
+                        return Err(anyhow!("Rudis was built without the 'dashmap' feature"));
+                    }
+                    "owned" => KeyspaceBackend::Owned,
+                    _ => {
+                        return Err(anyhow!("argument must be 'sharded', 'dashmap' or 'owned'"));
+                    }
+                }
+            }
+            ("io-backend", [backend]) => {
+                self.io_backend = match backend.to_lowercase().as_str() {
+                    "tokio" => IoBackend::Tokio,
+                    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                    "io-uring" => IoBackend::IoUring,
+                    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+                    "io-uring" => {
+                        return Err(anyhow!(
+                            "Rudis was built without the 'io-uring' feature (Linux only)"
+                        ));
+                    }
+                    _ => return Err(anyhow!("argument must be 'tokio' or 'io-uring'")),
+                }
+            }
+            ("logfile", [path]) => {
+                self.logfile = (!path.is_empty()).then(|| PathBuf::from(path));
+            }
+            ("logfile-max-size", [size]) => {
+                self.log_rotation.max_size = match size.as_str() {
+                    "0" => None,
+                    size => Some(parse_memory(size)? as u64),
+                }
+            }
+            ("logfile-rotate-interval", [seconds]) => {
+                self.log_rotation.interval = Some(parse_seconds(seconds)?).filter(|d| !d.is_zero())
+            }
+            ("logfile-keep", [count]) => {
+                self.log_rotation.keep = count
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid number of files '{}'", count))?
+            }
+            ("otlp-endpoint", [url]) => {
+                self.otlp_endpoint = match url.as_str() {
+                    "" => None,
+                    url => Some(Endpoint::parse(url)?),
+                }
+            }
+            ("latency-tracking", [flag]) => self.latency_tracking = parse_yes_no(flag)?,
+            ("record-file", [path]) => {
+                self.record_file = (!path.is_empty()).then(|| PathBuf::from(path));
+            }
+            ("restore-file", [path]) => {
+                self.restore_file = (!path.is_empty()).then(|| PathBuf::from(path));
+            }
+            ("restore-until", [millis]) if millis.is_empty() => self.restore_until = None,
+            ("restore-until", [millis]) => self.restore_until = Some(parse_unix_millis(millis)?),
+            ("restore-until-offset", [count]) if count.is_empty() => {
+                self.restore_until_offset = None
+            }
+            ("restore-until-offset", [count]) => {
+                self.restore_until_offset = Some(parse_offset(count)?)
+            }
+            ("seed-file", [path]) => {
+                self.seed_file = (!path.is_empty()).then(|| PathBuf::from(path));
+            }
+            ("shutdown-drain-timeout", [seconds]) => {
+                self.shutdown_drain_timeout = parse_seconds(seconds)?
+            }
+            ("supervised", [mode]) => {
+                self.supervised = match mode.to_lowercase().as_str() {
+                    "no" => Supervised::No,
+                    "systemd" => Supervised::Systemd,
+                    "auto" => Supervised::Auto,
+                    _ => return Err(anyhow!("argument must be 'no', 'systemd' or 'auto'")),
+                }
+            }
+            ("otlp-service-name", [name]) => self.otlp_service_name = name.clone(),
+            ("proto-max-nesting", [depth]) => self.proto_limits.max_depth = parse_count(depth)?,
+            _ => {
+                return Err(anyhow!(
+                    "Bad directive or wrong number of arguments: '{}'",
+                    directive
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Address the listener binds to
+    pub fn addr(&self) -> String {
+        self.bind_port(self.port)
+    }
+
+    /// Address health probes are answered on, if enabled
+    pub fn probe_addr(&self) -> Option<String> {
+        (self.probe_port != 0).then(|| self.bind_port(self.probe_port))
+    }
+
+    /// Address the admin API is served on, if enabled
+    pub fn admin_addr(&self) -> Option<String> {
+        (self.admin_port != 0).then(|| self.bind_port(self.admin_port))
+    }
+
+    /// Address peers' TLS links are taken on, if enabled
+    pub fn crdt_tls_addr(&self) -> Option<String> {
+        (self.crdt_tls_port != 0).then(|| self.bind_port(self.crdt_tls_port))
+    }
+
+    /// Every setting as the directive and arguments that set it. Renamed
+    /// commands are left out, so disabled commands stay hidden, and so are
+    /// tenants and the encryption key, which they would give away.
+    pub fn directives(&self) -> Vec<(&'static str, String)> {
+        let yes_no = |flag: bool| if flag { "yes" } else { "no" }.to_string();
+        let seconds = |duration: Duration| duration.as_secs().to_string();
+        let path = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        };
+        let allowlist: Vec<String> = self.allowlist.iter().map(IpNet::to_string).collect();
+        vec![
+            ("bind", self.bind.clone()),
+            ("port", self.port.to_string()),
+            ("protected-mode", yes_no(self.protected_mode)),
+            ("allow-cidr", allowlist.join(" ")),
+            ("timeout", seconds(self.timeout)),
+            ("client-frame-timeout", seconds(self.client_frame_timeout)),
+            ("maxclients", self.maxclients.to_string()),
+            ("dir", self.dir.display().to_string()),
+            ("dbfilename", self.dbfilename.clone()),
+            (
+                "backup-schedule",
+                self.backup_schedule
+                    .as_ref()
+                    .map(Schedule::to_string)
+                    .unwrap_or_default(),
+            ),
+            ("backup-keep-daily", self.backup_keep_daily.to_string()),
+            ("backup-keep-weekly", self.backup_keep_weekly.to_string()),
+            (
+                "proto-max-bulk-len",
+                self.proto_limits.max_bulk_len.to_string(),
+            ),
+            (
+                "proto-max-multibulk-len",
+                self.proto_limits.max_array_len.to_string(),
+            ),
+            ("proto-max-nesting", self.proto_limits.max_depth.to_string()),
+            (
+                "client-query-buffer-limit",
+                self.client_query_buffer_limit.to_string(),
+            ),
+            (
+                "client-pipeline-budget",
+                self.client_pipeline_budget.to_string(),
+            ),
+            (
+                "client-read-buffer-low",
+                self.client_read_buffer_low.to_string(),
+            ),
+            (
+                "client-read-buffer-high",
+                self.client_read_buffer_high.to_string(),
+            ),
+            ("tcp-keepalive", seconds(self.tcp_keepalive)),
+            ("tcp-nodelay", yes_no(self.tcp_nodelay)),
+            (
+                "so-linger",
+                self.so_linger.map_or("-1".to_string(), seconds),
+            ),
+            ("reuseport", yes_no(self.reuseport)),
+            (
+                "client-rate-limit",
+                match self.client_rate_limit {
+                    Some((ops, burst)) => format!("{} {}", ops, burst),
+                    None => "0".to_string(),
+                },
+            ),
+            (
+                "client-rate-limit-mode",
+                match self.client_rate_limit_mode {
+                    RateLimitMode::Delay => "delay",
+                    RateLimitMode::Reject => "reject",
+                }
+                .to_string(),
+            ),
+            ("keyspace-shards", self.keyspace_shards.to_string()),
+            (
+                "keyspace-backend",
+                match self.keyspace_backend {
+                    KeyspaceBackend::Sharded => "sharded",
+                    #[cfg(feature = "dashmap")]
+                    KeyspaceBackend::DashMap => "dashmap",
+                    KeyspaceBackend::Owned => "owned",
+                }
+                .to_string(),
+            ),
+            (
+                "io-backend",
+                match self.io_backend {
+                    IoBackend::Tokio => "tokio",
+                    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                    IoBackend::IoUring => "io-uring",
+                }
+                .to_string(),
+            ),
+            ("logfile", path(&self.logfile)),
+            (
+                "logfile-max-size",
+                self.log_rotation.max_size.unwrap_or(0).to_string(),
+            ),
+            (
+                "logfile-rotate-interval",
+                seconds(self.log_rotation.interval.unwrap_or_default()),
+            ),
+            ("logfile-keep", self.log_rotation.keep.to_string()),
+            (
+                "otlp-endpoint",
+                self.otlp_endpoint
+                    .as_ref()
+                    .map(|e| format!("http://{}{}", e.authority, e.path))
+                    .unwrap_or_default(),
+            ),
+            ("otlp-service-name", self.otlp_service_name.clone()),
+            ("latency-tracking", yes_no(self.latency_tracking)),
+            ("record-file", path(&self.record_file)),
+            ("restore-file", path(&self.restore_file)),
+            (
+                "restore-until",
+                self.restore_until
+                    .map(|time| {
+                        let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                        millis.as_millis().to_string()
+                    })
+                    .unwrap_or_default(),
+            ),
+            (
+                "restore-until-offset",
+                self.restore_until_offset
+                    .map(|count| count.to_string())
+                    .unwrap_or_default(),
+            ),
+            ("seed-file", path(&self.seed_file)),
+            (
+                "shutdown-drain-timeout",
+                seconds(self.shutdown_drain_timeout),
+            ),
+            (
+                "supervised",
+                match self.supervised {
+                    Supervised::No => "no",
+                    Supervised::Systemd => "systemd",
+                    Supervised::Auto => "auto",
+                }
+                .to_string(),
+            ),
+            ("probe-port", self.probe_port.to_string()),
+            ("admin-port", self.admin_port.to_string()),
+            ("upstream", self.upstream.clone().unwrap_or_default()),
+            ("upstream-read-through", yes_no(self.upstream_read_through)),
+            ("upstream-ttl", seconds(self.upstream_ttl)),
+            ("max-key-size", self.max_key_size.to_string()),
+            ("max-value-size", self.max_value_size.to_string()),
+            ("ttl-jitter", self.ttl_jitter.to_string()),
+            (
+                "command-time-budget",
+                self.command_time_budget.as_millis().to_string(),
+            ),
+            ("shed-latency", self.shed_latency.as_millis().to_string()),
+            ("shed-queue-depth", self.shed_queue_depth.to_string()),
+            ("tenant-required", yes_no(self.tenant_required)),
+            ("aclfile", path(&self.aclfile)),
+            ("upstream-write-behind", yes_no(self.upstream_write_behind)),
+            (
+                "upstream-write-behind-queue",
+                self.upstream_write_behind_queue.to_string(),
+            ),
+            (
+                "upstream-write-behind-rate",
+                self.upstream_write_behind_rate.to_string(),
+            ),
+            ("crdt-peers", self.crdt_peers.join(" ")),
+            ("crdt-node-id", self.crdt_node_id.to_string()),
+            ("crdt-tls", yes_no(self.crdt_tls)),
+            ("crdt-tls-port", self.crdt_tls_port.to_string()),
+            ("crdt-tls-cert-file", path(&self.crdt_tls_cert_file)),
+            ("crdt-tls-key-file", path(&self.crdt_tls_key_file)),
+            ("crdt-tls-ca-cert-file", path(&self.crdt_tls_ca_cert_file)),
+        ]
+    }
+
+    /// CONFIG GET: parameters matching any of the glob `patterns`, each once.
+    /// Besides the directives, the Redis settings load generators such as
+    /// memtier_benchmark and redis-benchmark check are answered with what
+    /// rudis does: nothing saved, no AOF and a single database.
+    pub fn matching(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        let compatibility = [
+            ("save", String::new()),
+            ("appendonly", "no".to_string()),
+            ("databases", "1".to_string()),
+        ];
+        self.directives()
+            .into_iter()
+            .chain(compatibility)
+            .filter(|(name, _)| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+            })
+            .collect()
+    }
+
+    fn bind_port(&self, port: u16) -> String {
+        if self.bind.contains(':') && !self.bind.starts_with('[') {
+            // A bare IPv6 address needs brackets to be followed by a port
+            return format!("[{}]:{}", self.bind, port);
+        }
+        format!("{}:{}", self.bind, port)
+    }
+}
+
+fn parse_yes_no(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(anyhow!("argument must be 'yes' or 'no'")),
+    }
+}
+
+fn parse_seconds(value: &str) -> Result<Duration> {
+    value
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|_| anyhow!("Invalid number of seconds '{}'", value))
+}
+
+fn parse_millis(value: &str) -> Result<Duration> {
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| anyhow!("Invalid number of milliseconds '{}'", value))
+}
+
+/// A time given in milliseconds since the Unix epoch
+pub(crate) fn parse_unix_millis(value: &str) -> Result<SystemTime> {
+    value
+        .parse()
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+        .map_err(|_| {
+            anyhow!(
+                "Invalid time '{}', expected milliseconds since the Unix epoch",
+                value
+            )
+        })
+}
+
+/// A number of commands into a recording, 0 or more
+pub(crate) fn parse_offset(value: &str) -> Result<usize> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid offset '{}', expected a number of commands", value))
+}
+
+/// A number of backups to keep, 0 or more
+fn parse_retention(value: &str) -> Result<usize> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid number of backups '{}'", value))
+}
+
+fn parse_count<T: std::str::FromStr + PartialOrd + Default>(value: &str) -> Result<T> {
+    value
+        .parse::<T>()
+        .ok()
+        .filter(|n| *n > T::default())
+        .ok_or_else(|| anyhow!("Invalid positive number '{}'", value))
+}
+
+/// A memory size, or 0 for no limit
+fn parse_size_limit(value: &str) -> Result<usize> {
+    match value {
+        "0" => Ok(0),
+        size => parse_memory(size),
+    }
+}
+
+/// The `tenant` directive: name, password and key prefix, then any of
+/// `max-keys <count>`, `max-memory <size>` and `max-ops <per second>`
+pub(crate) fn parse_tenant(
+    name: &str,
+    password: &str,
+    prefix: &str,
+    limits: &[String],
+) -> Result<TenantSpec> {
+    if name.is_empty() || password.is_empty() {
+        return Err(anyhow!("tenant name and password must not be empty"));
+    }
+    let mut tenant = TenantSpec {
+        name: name.to_string(),
+        password: password.to_string(),
+        prefix: prefix.to_string(),
+        max_keys: None,
+        max_memory: None,
+        max_ops: None,
+    };
+    for pair in limits.chunks(2) {
+        match pair {
+            [limit, value] => match limit.to_lowercase().as_str() {
+                "max-keys" => tenant.max_keys = Some(parse_count(value)?),
+                "max-memory" => tenant.max_memory = Some(parse_memory(value)? as u64),
+                "max-ops" => tenant.max_ops = Some(parse_count(value)?),
+                _ => return Err(anyhow!("Unknown tenant limit '{}'", limit)),
+            },
+            _ => return Err(anyhow!("tenant limit '{}' needs a value", pair[0])),
+        }
+    }
+    Ok(tenant)
+}
+
+/// Parse a memory size like `512mb`, `64k` or `1048576` (Redis units:
+/// k/m/g are powers of 1000, kb/mb/gb powers of 1024)
+pub fn parse_memory(value: &str) -> Result<usize> {
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid memory size '{}'", value)),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| anyhow!("Invalid memory size '{}'", value))
+}
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `::1/128`.
+/// A bare address is treated as a single-host network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Whether `ip` falls inside this network. IPv4-mapped IPv6 addresses
+    /// are compared as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = bits - prefix_len;
+    shift >= bits || net >> shift == ip >> shift
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("Invalid network address '{}'", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| anyhow!("Invalid prefix length in '{}'", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Split a config line into words, honouring double and single quotes
+pub(crate) fn split_config_line(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Some(words);
+        };
+
+        let mut word = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    c if c == first => break,
+                    '\\' if first == '"' => word.push(chars.next()?),
+                    c => word.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let config = Config::from_args(Vec::new()).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.addr(), "127.0.0.1:6379");
+    }
+
+    #[test]
+    fn command_line_directives() {
+        let config =
+            Config::from_args(args(&["--port", "7000", "--rename-command", "debug", ""])).unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.rename_commands.get("DEBUG"), Some(&String::new()));
+    }
+
+    #[test]
+    fn config_file_syntax() {
+        let mut config = Config::default();
+        config
+            .load_str(
+                "# comment\n\nbind 0.0.0.0\nport 6380\nrename-command FLUSHALL \"\"\nrename-command CONFIG 'my config'\n",
+            )
+            .unwrap();
+        assert_eq!(config.bind, "0.0.0.0");
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.rename_commands.get("FLUSHALL"), Some(&String::new()));
+        assert_eq!(
+            config.rename_commands.get("CONFIG"),
+            Some(&"MY CONFIG".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_directives_are_rejected() {
+        let mut config = Config::default();
+        assert!(config.load_str("port notanumber").is_err());
+        assert!(config.load_str("nosuchdirective 1").is_err());
+        assert!(config.load_str("rename-command DEBUG").is_err());
+        assert!(config.load_str("bind \"unterminated").is_err());
+        assert!(Config::from_args(args(&["--port"])).is_err());
+        assert!(config.load_str("protected-mode maybe").is_err());
+        assert!(config.load_str("timeout -1").is_err());
+        assert!(config.load_str("allow-cidr 10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn timeout_directive() {
+        let mut config = Config::default();
+        assert!(config.timeout.is_zero());
+        config.load_str("timeout 300").unwrap();
+        assert_eq!(config.timeout, Duration::from_secs(300));
+        assert!(config.client_frame_timeout.is_zero());
+        config.load_str("client-frame-timeout 10").unwrap();
+        assert_eq!(config.client_frame_timeout, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn proto_limit_directives() {
+        let mut config = Config::default();
+        config
+            .load_str("proto-max-bulk-len 1mb\nproto-max-multibulk-len 1000\nproto-max-nesting 2")
+            .unwrap();
+        assert_eq!(config.proto_limits.max_bulk_len, 1024 * 1024);
+        assert_eq!(config.proto_limits.max_array_len, 1000);
+        assert_eq!(config.proto_limits.max_depth, 2);
+
+        config.load_str("client-query-buffer-limit 64mb").unwrap();
+        assert_eq!(config.client_query_buffer_limit, 64 * 1024 * 1024);
+
+        assert_eq!(config.client_pipeline_budget, 256);
+        config.load_str("client-pipeline-budget 0").unwrap();
+        assert_eq!(config.client_pipeline_budget, 0);
+        assert!(config.load_str("client-pipeline-budget lots").is_err());
+
+        config
+            .load_str("client-read-buffer-low 16kb\nclient-read-buffer-high 4mb")
+            .unwrap();
+        assert_eq!(config.client_read_buffer_low, 16 * 1024);
+        assert_eq!(config.client_read_buffer_high, 4 * 1024 * 1024);
+        assert!(config.load_str("client-read-buffer-low 0").is_err());
+    }
+
+    #[test]
+    fn memory_units() {
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("1KB").unwrap(), 1024);
+        assert_eq!(parse_memory("2gb").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_memory("12xb").is_err());
+        assert!(parse_memory("mb").is_err());
+        assert!(parse_memory("0").is_err());
+    }
+
+    #[test]
+    fn socket_directives() {
+        let config = Config::default();
+        assert_eq!(config.tcp_keepalive, Duration::from_secs(300));
+        assert!(config.tcp_nodelay);
+        assert_eq!(config.so_linger, None);
+        assert!(!config.reuseport);
+
+        let config = Config::from_args(args(&[
+            "--tcp-keepalive",
+            "0",
+            "--tcp-nodelay",
+            "no",
+            "--so-linger",
+            "5",
+        ]))
+        .unwrap();
+        assert!(config.tcp_keepalive.is_zero());
+        assert!(!config.tcp_nodelay);
+        assert_eq!(config.so_linger, Some(Duration::from_secs(5)));
+
+        #[cfg(unix)]
+        assert!(
+            Config::from_args(args(&["--reuseport", "yes"]))
+                .unwrap()
+                .reuseport
+        );
+    }
+
+    #[test]
+    fn rate_limit_directives() {
+        let mut config = Config::default();
+        assert_eq!(config.client_rate_limit, None);
+
+        config
+            .load_str("client-rate-limit 1000 50\nclient-rate-limit-mode reject")
+            .unwrap();
+        assert_eq!(config.client_rate_limit, Some((1000, 50)));
+        assert_eq!(config.client_rate_limit_mode, RateLimitMode::Reject);
+
+        config.load_str("client-rate-limit 200").unwrap();
+        assert_eq!(config.client_rate_limit, Some((200, 200)));
+        config.load_str("client-rate-limit 0").unwrap();
+        assert_eq!(config.client_rate_limit, None);
+        assert!(config.load_str("client-rate-limit-mode drop").is_err());
+    }
+
+    #[test]
+    fn keyspace_shards_directive() {
+        let mut config = Config::default();
+        assert_eq!(config.keyspace_shards, DEFAULT_SHARDS);
+        config.load_str("keyspace-shards 64").unwrap();
+        assert_eq!(config.keyspace_shards, 64);
+        assert!(config.load_str("keyspace-shards 0").is_err());
+    }
+
+    #[test]
+    fn keyspace_backend_directive() {
+        let mut config = Config::default();
+        assert_eq!(config.keyspace_backend, KeyspaceBackend::Sharded);
+        assert!(config.load_str("keyspace-backend btree").is_err());
+        #[cfg(feature = "dashmap")]
+        {
+            config.load_str("keyspace-backend DashMap").unwrap();
+            assert_eq!(config.keyspace_backend, KeyspaceBackend::DashMap);
+        }
+        #[cfg(not(feature = "dashmap"))]
+        assert!(config.load_str("keyspace-backend dashmap").is_err());
+        config.load_str("keyspace-backend owned").unwrap();
+        assert_eq!(config.keyspace_backend, KeyspaceBackend::Owned);
+        config.load_str("keyspace-backend sharded").unwrap();
+        assert_eq!(config.keyspace_backend, KeyspaceBackend::Sharded);
+    }
+
+    #[test]
+    fn logfile_directives() {
+        let config = Config::default();
+        assert_eq!(config.logfile, None);
+        assert_eq!(config.log_rotation, Rotation::default());
+
+        let config = Config::from_args(args(&[
+            "--logfile",
+            "/var/log/rudis.log",
+            "--logfile-max-size",
+            "100mb",
+            "--logfile-rotate-interval",
+            "86400",
+            "--logfile-keep",
+            "3",
+        ]))
+        .unwrap();
+        assert_eq!(config.logfile, Some(PathBuf::from("/var/log/rudis.log")));
+        assert_eq!(config.log_rotation.max_size, Some(100 * 1024 * 1024));
+        assert_eq!(
+            config.log_rotation.interval,
+            Some(Duration::from_secs(86400))
+        );
+        assert_eq!(config.log_rotation.keep, 3);
+
+        // An empty name logs to stdout and zero disables rotation, like Redis
+        let config = Config::from_args(args(&[
+            "--logfile",
+            "",
+            "--logfile-max-size",
+            "0",
+            "--logfile-rotate-interval",
+            "0",
+        ]))
+        .unwrap();
+        assert_eq!(config.logfile, None);
+        assert_eq!(config.log_rotation.max_size, None);
+        assert_eq!(config.log_rotation.interval, None);
+        assert!(Config::from_args(args(&["--logfile-keep", "-1"])).is_err());
+    }
+
+    #[test]
+    fn otlp_directives() {
+        let config = Config::default();
+        assert_eq!(config.otlp_endpoint, None);
+        assert_eq!(config.otlp_service_name, "rudis");
+
+        let config = Config::from_args(args(&[
+            "--otlp-endpoint",
+            "http://collector:4318",
+            "--otlp-service-name",
+            "session-cache",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.otlp_endpoint.unwrap().authority,
+            "collector:4318".to_string()
+        );
+        assert_eq!(config.otlp_service_name, "session-cache");
+        assert!(Config::from_args(args(&["--otlp-endpoint", "grpc://collector"])).is_err());
+    }
+
+    #[test]
+    fn record_file_directive() {
+        assert_eq!(Config::default().record_file, None);
+        let config = Config::from_args(args(&["--record-file", "/tmp/incident.resp"])).unwrap();
+        assert_eq!(
+            config.record_file,
+            Some(PathBuf::from("/tmp/incident.resp"))
+        );
+        let config = Config::from_args(args(&["--record-file", ""])).unwrap();
+        assert_eq!(config.record_file, None);
+    }
+
+    #[test]
+    fn backup_directives() {
+        let config = Config::default();
+        assert_eq!(config.backup_schedule, None);
+        assert_eq!(
+            (config.backup_keep_daily, config.backup_keep_weekly),
+            (7, 4)
+        );
+        let config = Config::from_args(args(&[
+            "--backup-schedule",
+            "30",
+            "3",
+            "*",
+            "*",
+            "*",
+            "--backup-keep-weekly",
+            "0",
+        ]))
+        .unwrap();
+        assert_eq!(config.backup_schedule.unwrap().to_string(), "30 3 * * *");
+        assert_eq!(config.backup_keep_weekly, 0);
+
+        let mut config = Config::default();
+        config
+            .load_str("backup-schedule \"0 */6 * * *\"\nbackup-keep-daily 14")
+            .unwrap();
+        assert_eq!(config.backup_schedule.unwrap().to_string(), "0 */6 * * *");
+        assert_eq!(config.backup_keep_daily, 14);
+        assert!(Config::from_args(args(&["--backup-schedule", "3am"])).is_err());
+        assert!(Config::from_args(args(&["--backup-keep-daily", "-1"])).is_err());
+    }
+
+    #[test]
+    fn restore_directives() {
+        let config = Config::default();
+        assert_eq!(config.restore_file, None);
+        assert_eq!(
+            (config.restore_until, config.restore_until_offset),
+            (None, None)
+        );
+        let config = Config::from_args(args(&[
+            "--restore-file",
+            "/tmp/incident.resp",
+            "--restore-until",
+            "1700000000250",
+            "--restore-until-offset",
+            "42",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.restore_file,
+            Some(PathBuf::from("/tmp/incident.resp"))
+        );
+        assert_eq!(
+            config.restore_until,
+            Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_250))
+        );
+        assert_eq!(config.restore_until_offset, Some(42));
+        assert!(Config::from_args(args(&["--restore-until", "yesterday"])).is_err());
+        assert!(Config::from_args(args(&["--restore-until-offset", "-1"])).is_err());
+    }
+
+    #[test]
+    fn seed_file_directive() {
+        let mut config = Config::default();
+        assert_eq!(config.seed_file, None);
+        config.load_str("seed-file fixtures/staging.json").unwrap();
+        assert_eq!(
+            config.seed_file,
+            Some(PathBuf::from("fixtures/staging.json"))
+        );
+        config.load_str("seed-file \"\"").unwrap();
+        assert_eq!(config.seed_file, None);
+    }
+
+    #[test]
+    fn shutdown_drain_timeout_directive() {
+        assert_eq!(
+            Config::default().shutdown_drain_timeout,
+            Duration::from_secs(10)
+        );
+        let config = Config::from_args(args(&["--shutdown-drain-timeout", "0"])).unwrap();
+        assert_eq!(config.shutdown_drain_timeout, Duration::ZERO);
+        assert!(Config::from_args(args(&["--shutdown-drain-timeout", "-1"])).is_err());
+    }
+
+    #[test]
+    fn upstream_directives() {
+        let config = Config::from_args(args(&["--upstream", "redis.internal:6379"])).unwrap();
+        assert_eq!(config.upstream.as_deref(), Some("redis.internal:6379"));
+        assert_eq!(config.upstream_ttl, Duration::from_secs(60));
+
+        let config = Config::from_args(args(&["--upstream", "", "--upstream-ttl", "5"])).unwrap();
+        assert_eq!(config.upstream, None);
+        assert_eq!(config.upstream_ttl, Duration::from_secs(5));
+        assert!(Config::from_args(args(&["--upstream-ttl", "0"])).is_err());
+
+        let config = Config::from_args(args(&[
+            "--upstream-read-through",
+            "no",
+            "--upstream-write-behind",
+            "yes",
+            "--upstream-write-behind-queue",
+            "100",
+            "--upstream-write-behind-rate",
+            "10mb",
+        ]))
+        .unwrap();
+        assert!(!config.upstream_read_through);
+        assert!(config.upstream_write_behind);
+        assert_eq!(config.upstream_write_behind_queue, 100);
+        assert_eq!(config.upstream_write_behind_rate, 10 * 1024 * 1024);
+        assert_eq!(Config::default().upstream_write_behind_rate, 0);
+        assert!(Config::from_args(args(&["--upstream-write-behind-queue", "0"])).is_err());
+    }
+
+    #[test]
+    fn crdt_directives() {
+        assert!(Config::default().crdt_peers.is_empty());
+        let config = Config::from_args(args(&[
+            "--crdt-peers",
+            "10.0.0.2:6379",
+            "10.0.0.3:6379",
+            "--crdt-node-id",
+            "7",
+        ]))
+        .unwrap();
+        assert_eq!(config.crdt_peers, ["10.0.0.2:6379", "10.0.0.3:6379"]);
+        assert_eq!(config.crdt_node_id, 7);
+        assert_eq!(config.crdt_secret, None);
+        let config = Config::from_args(args(&["--crdt-secret", "s3cret"])).unwrap();
+        assert_eq!(config.crdt_secret.as_deref(), Some("s3cret"));
+        // Never reported, like the encryption key
+        assert!(
+            config
+                .directives()
+                .iter()
+                .all(|(name, value)| *name != "crdt-secret" && value != "s3cret")
+        );
+        let config =
+            Config::from_args(args(&["--crdt-peers", "10.0.0.2:6379", "--crdt-peers", ""]))
+                .unwrap();
+        assert!(config.crdt_peers.is_empty());
+        assert!(Config::from_args(args(&["--crdt-node-id", "node"])).is_err());
+    }
+
+    #[test]
+    fn crdt_tls_directives() {
+        let config = Config::default();
+        assert!(!config.crdt_tls);
+        assert_eq!(config.crdt_tls_addr(), None);
+        let mut config = Config::default();
+        config
+            .load_str(
+                "crdt-tls yes\ncrdt-tls-port 16379\n\
+                 crdt-tls-cert-file /etc/rudis/node.crt\n\
+                 crdt-tls-key-file /etc/rudis/node.key\n\
+                 crdt-tls-ca-cert-file /etc/rudis/ca.crt",
+            )
+            .unwrap();
+        assert!(config.crdt_tls);
+        assert_eq!(config.crdt_tls_addr().unwrap(), "127.0.0.1:16379");
+        assert_eq!(
+            config.crdt_tls_ca_cert_file,
+            Some(PathBuf::from("/etc/rudis/ca.crt"))
+        );
+        assert!(
+            config
+                .directives()
+                .contains(&("crdt-tls-key-file", "/etc/rudis/node.key".to_string()))
+        );
+        config.load_str("crdt-tls-key-file \"\"").unwrap();
+        assert_eq!(config.crdt_tls_key_file, None);
+        assert!(config.load_str("crdt-tls-port 70000").is_err());
+        assert!(config.load_str("crdt-tls maybe").is_err());
+    }
+
+    #[test]
+    fn size_limit_directives() {
+        let config = Config::default();
+        assert_eq!((config.max_key_size, config.max_value_size), (0, 0));
+        let config =
+            Config::from_args(args(&["--max-key-size", "1kb", "--max-value-size", "10mb"]))
+                .unwrap();
+        assert_eq!(config.max_key_size, 1024);
+        assert_eq!(config.max_value_size, 10 * 1024 * 1024);
+        let config = Config::from_args(args(&["--max-value-size", "0"])).unwrap();
+        assert_eq!(config.max_value_size, 0);
+        assert!(Config::from_args(args(&["--max-key-size", "big"])).is_err());
+    }
+
+    #[test]
+    fn ttl_jitter_directive() {
+        assert_eq!(Config::default().ttl_jitter, 0);
+        let config = Config::from_args(args(&["--ttl-jitter", "5"])).unwrap();
+        assert_eq!(config.ttl_jitter, 5);
+        let config = Config::from_args(args(&["--ttl-jitter", "10%"])).unwrap();
+        assert_eq!(config.ttl_jitter, 10);
+        assert!(Config::from_args(args(&["--ttl-jitter", "51"])).is_err());
+        assert!(Config::from_args(args(&["--ttl-jitter", "-5"])).is_err());
+    }
+
+    #[test]
+    fn command_time_budget_directive() {
+        assert!(Config::default().command_time_budget.is_zero());
+        let config = Config::from_args(args(&["--command-time-budget", "250"])).unwrap();
+        assert_eq!(config.command_time_budget, Duration::from_millis(250));
+        assert!(Config::from_args(args(&["--command-time-budget", "-1"])).is_err());
+        assert!(Config::from_args(args(&["--command-time-budget", "1s"])).is_err());
+    }
+
+    #[test]
+    fn load_shedding_directives() {
+        let config = Config::default();
+        assert!(config.shed_latency.is_zero());
+        assert_eq!(config.shed_queue_depth, 0);
+        let config =
+            Config::from_args(args(&["--shed-latency", "20", "--shed-queue-depth", "512"]))
+                .unwrap();
+        assert_eq!(config.shed_latency, Duration::from_millis(20));
+        assert_eq!(config.shed_queue_depth, 512);
+        let config = Config::from_args(args(&["--shed-queue-depth", "0"])).unwrap();
+        assert_eq!(config.shed_queue_depth, 0);
+        assert!(Config::from_args(args(&["--shed-queue-depth", "-1"])).is_err());
+    }
+
+    #[test]
+    fn encryption_key_directive() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        assert_eq!(Config::default().encryption_key, None);
+        let config = Config::from_args(args(&["--encryption-key", hex])).unwrap();
+        assert_eq!(
+            config.encryption_key,
+            Some(EncryptionKey::from_hex(hex).unwrap())
+        );
+        // The key stays out of CONFIG GET
+        assert!(
+            config
+                .directives()
+                .iter()
+                .all(|(name, value)| *name != "encryption-key" && value != hex)
+        );
+
+        let mut config = Config::default();
+        config
+            .load_str(&format!("encryption-key {}\nencryption-key \"\"", hex))
+            .unwrap();
+        assert_eq!(config.encryption_key, None);
+        assert!(config.load_str("encryption-key 1234").is_err());
+        assert!(
+            config
+                .load_str("encryption-key env:RUDIS_TEST_NO_SUCH_KEY")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn tenant_directives() {
+        let config = Config::from_args(args(&[
+            "--tenant",
+            "billing",
+            "s3cret",
+            "billing:",
+            "max-keys",
+            "1000",
+            "max-memory",
+            "64mb",
+            "--tenant",
+            "search",
+            "hunter2",
+            "search:",
+            "--tenant-required",
+            "yes",
+        ]))
+        .unwrap();
+        assert!(config.tenant_required);
+        assert_eq!(config.tenants.len(), 2);
+        let billing = &config.tenants[0];
+        assert_eq!(billing.prefix, "billing:");
+        assert_eq!(billing.max_keys, Some(1000));
+        assert_eq!(billing.max_memory, Some(64 * 1024 * 1024));
+        assert_eq!(billing.max_ops, None);
+        // Passwords stay out of CONFIG GET
+        assert!(
+            config
+                .directives()
+                .iter()
+                .all(|(name, _)| *name != "tenant")
+        );
+
+        let mut config = Config::default();
+        config
+            .load_str("tenant app old app:\ntenant app new app: max-ops 50\n")
+            .unwrap();
+        assert_eq!(config.tenants.len(), 1);
+        assert_eq!(config.tenants[0].password, "new");
+        assert_eq!(config.tenants[0].max_ops, Some(50));
+
+        assert!(config.load_str("tenant app pw app: max-keys").is_err());
+        assert!(config.load_str("tenant app pw app: max-keys 0").is_err());
+        assert!(config.load_str("tenant app pw app: max-widgets 5").is_err());
+        assert!(config.load_str("tenant app pw").is_err());
+
+        assert_eq!(config.aclfile, None);
+        config.load_str("aclfile /etc/rudis/users.acl").unwrap();
+        assert_eq!(config.aclfile, Some(PathBuf::from("/etc/rudis/users.acl")));
+        assert!(
+            config
+                .directives()
+                .contains(&("aclfile", "/etc/rudis/users.acl".to_string()))
+        );
+    }
+
+    #[test]
+    fn matches_config_get_patterns() {
+        let config = Config::default();
+        let names = |patterns: &[&str]| -> Vec<&'static str> {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            config
+                .matching(&patterns)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        assert_eq!(names(&["maxclients"]), ["maxclients"]);
+        assert_eq!(names(&["save", "appendonly"]), ["save", "appendonly"]);
+        assert!(names(&["*"]).contains(&"databases"));
+        assert_eq!(names(&["proto-max-*"]).len(), 3);
+        assert!(names(&["nonexistent"]).is_empty());
+        // Each parameter once, however many patterns match it
+        assert_eq!(names(&["port", "p?rt"]), ["port"]);
+    }
+
+    #[test]
+    fn directives_reproduce_the_config() {
+        let config = Config::from_args(args(&[
+            "--port",
+            "6380",
+            "--allow-cidr",
+            "10.0.0.0/8",
+            "::1",
+            "--so-linger",
+            "5",
+            "--client-rate-limit",
+            "100",
+            "200",
+            "--logfile",
+            "/var/log/rudis.log",
+            "--logfile-max-size",
+            "1mb",
+            "--otlp-endpoint",
+            "http://collector:4318",
+            "--admin-port",
+            "8081",
+            "--backup-schedule",
+            "@daily",
+        ]))
+        .unwrap();
+        let mut copy = Config::default();
+        for (directive, value) in config.directives() {
+            let values: Vec<String> = match value.as_str() {
+                "" => vec![String::new()],
+                value => value.split(' ').map(str::to_string).collect(),
+            };
+            copy.apply(directive, &values).unwrap();
+        }
+        assert_eq!(copy, config);
+        assert_eq!(config.admin_addr(), Some("127.0.0.1:8081".to_string()));
+    }
+
+    #[test]
+    fn probe_port_directive() {
+        assert_eq!(Config::default().probe_addr(), None);
+        let config = Config::from_args(args(&["--bind", "::1", "--probe-port", "8080"])).unwrap();
+        assert_eq!(config.probe_addr(), Some("[::1]:8080".to_string()));
+        assert!(Config::from_args(args(&["--probe-port", "http"])).is_err());
+    }
+
+    #[test]
+    fn supervised_directive() {
+        assert_eq!(Config::default().supervised, Supervised::Auto);
+        let mut config = Config::default();
+        config.load_str("supervised SYSTEMD").unwrap();
+        assert_eq!(config.supervised, Supervised::Systemd);
+        config.load_str("supervised no").unwrap();
+        assert_eq!(config.supervised, Supervised::No);
+        assert!(config.load_str("supervised upstart").is_err());
+    }
+
+    #[test]
+    fn latency_tracking_directive() {
+        assert!(Config::default().latency_tracking);
+        let config = Config::from_args(args(&["--latency-tracking", "no"])).unwrap();
+        assert!(!config.latency_tracking);
+    }
+
+    #[test]
+    fn io_backend_directive() {
+        let mut config = Config::default();
+        assert_eq!(config.io_backend, IoBackend::Tokio);
+        assert!(config.load_str("io-backend epoll").is_err());
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            config.load_str("io-backend IO-URING").unwrap();
+            assert_eq!(config.io_backend, IoBackend::IoUring);
+        }
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        assert!(config.load_str("io-backend io-uring").is_err());
+        config.load_str("io-backend tokio").unwrap();
+        assert_eq!(config.io_backend, IoBackend::Tokio);
+    }
+
+    #[test]
+    fn client_and_persistence_directives() {
+        let mut config = Config::default();
+        assert_eq!(config.maxclients, 10_000);
+        assert_eq!(config.dir, PathBuf::from("."));
+        assert_eq!(config.dbfilename, "dump.rdb");
+        config
+            .load_str("maxclients 2\ndir /var/lib/rudis\ndbfilename rudis.rdb")
+            .unwrap();
+        assert_eq!(config.maxclients, 2);
+        assert_eq!(config.dir, PathBuf::from("/var/lib/rudis"));
+        assert_eq!(config.dbfilename, "rudis.rdb");
+        assert!(config.load_str("maxclients 0").is_err());
+    }
+
+    #[test]
+    fn ipv6_bind_address() {
+        let mut config = Config::default();
+        config.load_str("bind ::1\nport 6380").unwrap();
+        assert_eq!(config.addr(), "[::1]:6380");
+        config.load_str("bind [::1]").unwrap();
+        assert_eq!(config.addr(), "[::1]:6380");
+    }
+
+    #[test]
+    fn access_directives() {
+        let config = Config::from_args(args(&[
+            "--protected-mode",
+            "no",
+            "--allow-cidr",
+            "10.0.0.0/8",
+            "::1",
+        ]))
+        .unwrap();
+        assert!(!config.protected_mode);
+        assert_eq!(config.allowlist.len(), 2);
+    }
+
+    #[test]
+    fn ipnet_contains() {
+        let net: IpNet = "192.168.1.0/24".parse().unwrap();
+        assert!(net.contains("192.168.1.42".parse().unwrap()));
+        assert!(!net.contains("192.168.2.1".parse().unwrap()));
+        assert!(net.contains("::ffff:192.168.1.7".parse().unwrap()));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+        assert!(!any.contains("2001:db8::1".parse().unwrap()));
+
+        let host: IpNet = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+    }
+}
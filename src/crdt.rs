@@ -0,0 +1,869 @@
+//! Active-active replication between rudis instances, for edge deployments
+//! with no single primary to write to. With `crdt-peers` set, every write a
+//! client makes is applied locally, then sent on to each peer as a
+//! `CRDT MERGE`, and peers merge what they receive so that all instances
+//! converge on the same keyspace whatever order writes arrive in.
+//!
+//! Each key is a last-writer-wins register: SET, DEL, EXPIRE and the rest
+//! replace its whole state, stamped by a hybrid logical clock, and the
+//! highest stamp wins everywhere. The clock moves past every stamp it has
+//! seen, so a write made after seeing another always wins over it, and
+//! writes made concurrently are ordered by time, then node id. INCR and
+//! friends instead count into a PN-counter kept against the register they
+//! started from: each node's increments and decrements are summed, so
+//! concurrent INCRs on two nodes both count. A register written after them
+//! starts the count over, taking the place of increments it didn't see.
+//!
+//! Writes to the same key run one at a time, so what is sent matches what
+//! was applied. TTLs travel as the time the key expires at, and each node
+//! expires keys on its own. Deleted and expired keys leave a tombstone
+//! behind, dropped once `TOMBSTONE_MILLIS` old.
+
+use crate::command::Command;
+use crate::error::CommandError;
+use crate::resp::RespValue;
+use crate::store::Store;
+use crate::tls::PeerTls;
+use crate::upstream::{UpstreamStats, WriteBehind};
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// Merges held for each peer while it is behind or unreachable
+const PEER_QUEUE: usize = 100_000;
+/// Furthest a peer's stamp may run ahead of this node's clock. A stamp from
+/// far in the future would win over every write until then, so it's refused.
+const MAX_AHEAD_MILLIS: u64 = 60_000;
+/// Partitions of the key states, each locked on its own
+const KEY_SHARDS: usize = 64;
+/// How long a deleted or expired key's state is kept, so that writes to it
+/// stamped before but arriving after are still known to be superseded
+const TOMBSTONE_MILLIS: u64 = 10 * 60_000;
+/// How often key states are swept for tombstones to drop
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A point on the hybrid logical clock: wall-clock milliseconds, a counter
+/// for stamps made within the same millisecond, and the node that made it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp {
+    pub millis: u64,
+    pub counter: u32,
+    pub node: u64,
+}
+
+/// Hybrid logical clock: follows the system clock, but never goes back and
+/// never falls behind a stamp it has seen
+#[derive(Debug)]
+struct Hlc {
+    node: u64,
+    last: Mutex<(u64, u32)>,
+}
+
+/// Milliseconds since the unix epoch by the system clock
+fn wall_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+impl Hlc {
+    fn now(&self) -> Stamp {
+        let wall = wall_millis();
+        let mut last = self.last.lock().unwrap();
+        *last = match wall > last.0 {
+            true => (wall, 0),
+            false => (last.0, last.1 + 1),
+        };
+        Stamp {
+            millis: last.0,
+            counter: last.1,
+            node: self.node,
+        }
+    }
+
+    fn observe(&self, stamp: Stamp) {
+        let mut last = self.last.lock().unwrap();
+        *last = (*last).max((stamp.millis, stamp.counter));
+    }
+}
+
+/// A change to one key, as sent between peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// The key's whole state, or its deletion for no value
+    Register {
+        key: Bytes,
+        stamp: Stamp,
+        value: Option<Bytes>,
+        /// Unix time in milliseconds it expires at
+        expires_at: Option<u64>,
+    },
+    /// A node's increments and decrements of the key since the register
+    /// stamped `epoch`, both running totals
+    Counter {
+        key: Bytes,
+        epoch: Stamp,
+        node: u64,
+        increments: u64,
+        decrements: u64,
+    },
+}
+
+impl Op {
+    /// The `CRDT MERGE` command carrying it
+    fn to_resp(&self) -> RespValue {
+        let bulk = |arg: Bytes| RespValue::BulkString(Some(arg));
+        let number = |n: u64| bulk(Bytes::from(n.to_string()));
+        let stamp = |stamp: &Stamp| {
+            [
+                number(stamp.millis),
+                number(stamp.counter as u64),
+                number(stamp.node),
+            ]
+        };
+        let mut args = vec![
+            bulk(Bytes::from_static(b"CRDT")),
+            bulk(Bytes::from_static(b"MERGE")),
+        ];
+        match self {
+            Op::Register {
+                key,
+                stamp: at,
+                value,
+                expires_at,
+            } => {
+                args.extend([bulk(Bytes::from_static(b"REGISTER")), bulk(key.clone())]);
+                args.extend(stamp(at));
+                args.push(number(u64::from(value.is_some())));
+                args.push(bulk(value.clone().unwrap_or_default()));
+                args.push(bulk(Bytes::from(
+                    expires_at.map_or(-1, |at| at as i64).to_string(),
+                )));
+            }
+            Op::Counter {
+                key,
+                epoch,
+                node,
+                increments,
+                decrements,
+            } => {
+                args.extend([bulk(Bytes::from_static(b"COUNTER")), bulk(key.clone())]);
+                args.extend(stamp(epoch));
+                args.extend([number(*node), number(*increments), number(*decrements)]);
+            }
+        }
+        RespValue::Array(Some(args))
+    }
+
+    /// Parse the arguments of `CRDT MERGE`
+    pub fn from_args(args: &[RespValue]) -> Result<Self> {
+        let arg = |index: usize| match args.get(index) {
+            Some(RespValue::BulkString(Some(arg))) => Ok(arg.clone()),
+            _ => Err(CommandError::Syntax),
+        };
+        let number = |index: usize| -> Result<u64> {
+            let arg = arg(index)?;
+            std::str::from_utf8(&arg)
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| CommandError::NotInteger.into())
+        };
+        let stamp = |index: usize| -> Result<Stamp> {
+            Ok(Stamp {
+                millis: number(index)?,
+                counter: u32::try_from(number(index + 1)?).map_err(|_| CommandError::NotInteger)?,
+                node: number(index + 2)?,
+            })
+        };
+        let kind = arg(0)?;
+        match (kind.to_ascii_uppercase().as_slice(), args.len()) {
+            (b"REGISTER", 8) => Ok(Op::Register {
+                key: arg(1)?,
+                stamp: stamp(2)?,
+                value: (number(5)? == 1).then(|| arg(6)).transpose()?,
+                expires_at: match arg(7)?.as_ref() {
+                    b"-1" => None,
+                    _ => Some(number(7)?),
+                },
+            }),
+            (b"COUNTER", 8) => Ok(Op::Counter {
+                key: arg(1)?,
+                epoch: stamp(2)?,
+                node: number(5)?,
+                increments: number(6)?,
+                decrements: number(7)?,
+            }),
+            _ => bail!(CommandError::Syntax),
+        }
+    }
+}
+
+/// The last register written to a key
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Register {
+    stamp: Stamp,
+    value: Option<Bytes>,
+    /// Unix time in milliseconds
+    expires_at: Option<u64>,
+}
+
+/// What a node knows of a key
+#[derive(Debug, Default)]
+struct KeyState {
+    register: Register,
+    /// (increments, decrements) by epoch and node. Counts for an epoch
+    /// newer than the register are kept for when its register arrives.
+    counts: BTreeMap<(Stamp, u64), (u64, u64)>,
+}
+
+impl KeyState {
+    fn counted(&self) -> bool {
+        self.counts
+            .keys()
+            .any(|(epoch, _)| *epoch == self.register.stamp)
+    }
+
+    /// The counter's value: the register's plus every node's count since
+    fn total(&self) -> i64 {
+        let base = self
+            .register
+            .value
+            .as_ref()
+            .and_then(|value| std::str::from_utf8(value).ok()?.parse::<i64>().ok())
+            .unwrap_or(0);
+        self.counts
+            .iter()
+            .filter(|((epoch, _), _)| *epoch == self.register.stamp)
+            .fold(base, |total, (_, (up, down))| {
+                total.wrapping_add(*up as i64).wrapping_sub(*down as i64)
+            })
+    }
+
+    /// Replace the register if `register` is newer, dropping the counts
+    /// against older ones; returns whether it was
+    fn adopt(&mut self, register: Register) -> bool {
+        if register.stamp <= self.register.stamp {
+            return false;
+        }
+        self.counts.retain(|(epoch, _), _| *epoch >= register.stamp);
+        self.register = register;
+        true
+    }
+
+    /// Whether the key is deleted or expired by `now`, long enough ago to
+    /// forget, with no counts waiting for a newer register
+    fn prunable(&self, now: u64) -> bool {
+        let register = &self.register;
+        let deleted = register.value.is_none() && !self.counted();
+        let expired = register.expires_at.is_some_and(|at| at <= now);
+        (deleted || expired)
+            && register.stamp.millis.saturating_add(TOMBSTONE_MILLIS) <= now
+            && self
+                .counts
+                .keys()
+                .all(|(epoch, _)| *epoch <= register.stamp)
+    }
+
+    /// Write the key's merged value into `store`
+    async fn apply(&self, store: &Store, key: &Bytes) {
+        let value = match self.counted() {
+            true => Some(Bytes::from(self.total().to_string())),
+            false => self.register.value.clone(),
+        };
+        let now = wall_millis();
+        match (value, self.register.expires_at) {
+            (Some(value), None) => store.set(key.clone(), value).await,
+            (Some(value), Some(at)) if at > now => store.set_px(key.clone(), value, at - now).await,
+            // Deleted, or expired before it got here
+            _ => {
+                store.del(std::slice::from_ref(key)).await;
+            }
+        }
+    }
+}
+
+/// Counters for INFO crdt
+#[derive(Debug, Default)]
+pub struct CrdtStats {
+    node: AtomicU64,
+    /// Changes made here and sent to peers
+    sent: AtomicU64,
+    /// Changes from peers that took effect here
+    merged: AtomicU64,
+    /// Changes from peers already superseded here
+    superseded: AtomicU64,
+    /// Each peer and its outgoing queue
+    peers: Mutex<Vec<(String, Arc<UpstreamStats>)>>,
+}
+
+impl CrdtStats {
+    /// INFO crdt lines
+    pub fn info(&self) -> String {
+        let peers = self.peers.lock().unwrap();
+        let mut info = format!(
+            "crdt_enabled:{}\r\ncrdt_node_id:{}\r\ncrdt_sent:{}\r\ncrdt_merged:{}\r\ncrdt_superseded:{}\r\ncrdt_peers:{}\r\n",
+            u8::from(!peers.is_empty()),
+            self.node.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+            self.merged.load(Ordering::Relaxed),
+            self.superseded.load(Ordering::Relaxed),
+            peers.len()
+        );
+        for (index, (addr, stats)) in peers.iter().enumerate() {
+            let _ = write!(info, "peer{}:addr={},{}\r\n", index, addr, stats.summary());
+        }
+        info
+    }
+}
+
+/// This node's side of active-active replication
+#[derive(Debug)]
+pub struct Crdt {
+    clock: Hlc,
+    /// What is known of each key, in `KEY_SHARDS` partitions by `hasher`
+    keys: Vec<tokio::sync::Mutex<HashMap<Bytes, KeyState>>>,
+    hasher: RandomState,
+    peers: Vec<WriteBehind>,
+    stats: CrdtStats,
+}
+
+impl Crdt {
+    /// Start replicating to `peers` as node `node`, or a random id for 0,
+    /// over TLS if `tls` is given, and otherwise proving each link with
+    /// `secret`. Must be called inside the runtime, which runs the senders.
+    pub fn new(node: u64, peers: &[String], tls: Option<PeerTls>, secret: Option<&str>) -> Self {
+        let stats = CrdtStats::default();
+        let node = match node {
+            0 => RandomState::new().hash_one(std::process::id()) | 1,
+            node => node,
+        };
+        stats.node.store(node, Ordering::Relaxed);
+        let hello = secret.filter(|_| tls.is_none()).map(|secret| {
+            vec![
+                Bytes::from_static(b"CRDT"),
+                Bytes::from_static(b"AUTH"),
+                Bytes::copy_from_slice(secret.as_bytes()),
+            ]
+        });
+        let mut listed = stats.peers.lock().unwrap();
+        let peers = peers
+            .iter()
+            .map(|addr| {
+                let peer_stats = Arc::new(UpstreamStats::default());
+                listed.push((addr.clone(), peer_stats.clone()));
+                WriteBehind::spawn(
+                    addr.clone(),
+                    PEER_QUEUE,
+                    0,
+                    tls.clone(),
+                    hello.clone(),
+                    peer_stats,
+                )
+            })
+            .collect();
+        drop(listed);
+        Self {
+            clock: Hlc {
+                node,
+                last: Mutex::new((0, 0)),
+            },
+            keys: (0..KEY_SHARDS)
+                .map(|_| tokio::sync::Mutex::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+            peers,
+            stats,
+        }
+    }
+
+    /// Start the background task dropping tombstones once they're old
+    /// enough, as nothing else visits keys that were deleted or expired
+    pub fn start_pruning(crdt: Arc<Crdt>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                crdt.prune().await;
+            }
+        })
+    }
+
+    /// Forget keys deleted or expired long enough ago, a partition at a time
+    async fn prune(&self) {
+        for shard in &self.keys {
+            let now = wall_millis();
+            shard.lock().await.retain(|_, state| !state.prunable(now));
+        }
+    }
+
+    /// Index of the partition of `keys` holding `key`
+    fn shard(&self, key: &[u8]) -> usize {
+        self.hasher.hash_one(key) as usize % self.keys.len()
+    }
+
+    /// Changes sent to and merged from peers, and each peer's queue
+    pub fn stats(&self) -> &CrdtStats {
+        &self.stats
+    }
+
+    fn send(&self, op: &Op) {
+        let request = op.to_resp();
+        for peer in &self.peers {
+            peer.forward(&request);
+        }
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Run a client's write command `cmd` with `run`, then send what it did
+    /// to the peers. Writes to the same keys wait for each other, so each is
+    /// sent as made.
+    pub async fn write(
+        &self,
+        store: &Store,
+        cmd: &Command,
+        run: impl Future<Output = RespValue>,
+    ) -> RespValue {
+        let mut shards: Vec<usize> = cmd.keys().iter().map(|key| self.shard(key)).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        // In ascending order, so writes to overlapping keys can't deadlock
+        let mut locked = Vec::with_capacity(shards.len());
+        for &index in &shards {
+            locked.push(self.keys[index].lock().await);
+        }
+        let held = |key: &[u8]| shards.binary_search(&self.shard(key)).unwrap();
+        let response = run.await;
+        if matches!(response, RespValue::Error(_)) {
+            return response;
+        }
+        let delta = match cmd {
+            Command::Incr(_) => Some(1),
+            Command::Decr(_) => Some(-1),
+            Command::IncrBy(_, n) => Some(*n),
+            Command::DecrBy(_, n) => Some(n.wrapping_neg()),
+            _ => None,
+        };
+        match (delta, &response) {
+            (Some(delta), RespValue::Integer(value)) => {
+                let key = cmd.keys()[0];
+                let state = locked[held(key)].entry(key.clone()).or_default();
+                self.count(key, state, *value, delta);
+            }
+            _ => {
+                for key in cmd.keys() {
+                    let left = u64::try_from(store.pttl(key).await).ok();
+                    let register = Register {
+                        stamp: self.clock.now(),
+                        value: store.get(key).await,
+                        expires_at: left.map(|left| wall_millis() + left),
+                    };
+                    locked[held(key)]
+                        .entry(key.clone())
+                        .or_default()
+                        .adopt(register.clone());
+                    self.send(&Op::Register {
+                        key: key.clone(),
+                        stamp: register.stamp,
+                        value: register.value,
+                        expires_at: register.expires_at,
+                    });
+                }
+            }
+        }
+        response
+    }
+
+    /// Count `delta` into `key`, which it took to `value` here
+    fn count(&self, key: &Bytes, state: &mut KeyState, value: i64, delta: i64) {
+        if state.total().wrapping_add(delta) != value {
+            // The key expired, or was here before replication started:
+            // start over from what it held before
+            let register = Register {
+                stamp: self.clock.now(),
+                value: Some(Bytes::from(value.wrapping_sub(delta).to_string())),
+                expires_at: None,
+            };
+            state.adopt(register.clone());
+            self.send(&Op::Register {
+                key: key.clone(),
+                stamp: register.stamp,
+                value: register.value,
+                expires_at: None,
+            });
+        }
+        let epoch = state.register.stamp;
+        let (increments, decrements) = state.counts.entry((epoch, self.clock.node)).or_default();
+        match delta >= 0 {
+            true => *increments += delta.unsigned_abs(),
+            false => *decrements += delta.unsigned_abs(),
+        }
+        self.send(&Op::Counter {
+            key: key.clone(),
+            epoch,
+            node: self.clock.node,
+            increments: *increments,
+            decrements: *decrements,
+        });
+    }
+
+    /// Merge a change sent by a peer into `store`
+    pub async fn merge(&self, store: &Store, op: Op) -> Result<(), CommandError> {
+        let (key, stamp) = match &op {
+            Op::Register { key, stamp, .. } => (key.clone(), *stamp),
+            Op::Counter { key, epoch, .. } => (key.clone(), *epoch),
+        };
+        if stamp.millis > wall_millis().saturating_add(MAX_AHEAD_MILLIS) {
+            return Err(CommandError::CrdtStampAhead(MAX_AHEAD_MILLIS / 1000));
+        }
+        let mut keys = self.keys[self.shard(&key)].lock().await;
+        self.clock.observe(stamp);
+        let state = keys.entry(key.clone()).or_default();
+        let changed = match op {
+            Op::Register {
+                stamp,
+                value,
+                expires_at,
+                ..
+            } => state.adopt(Register {
+                stamp,
+                value,
+                expires_at,
+            }),
+            Op::Counter {
+                epoch,
+                node,
+                increments,
+                decrements,
+                ..
+            } if epoch >= state.register.stamp => {
+                let counts = state.counts.entry((epoch, node)).or_default();
+                let merged = (counts.0.max(increments), counts.1.max(decrements));
+                let grew = merged != *counts;
+                *counts = merged;
+                grew && epoch == state.register.stamp
+            }
+            Op::Counter { .. } => false,
+        };
+        match changed {
+            true => {
+                state.apply(store, &key).await;
+                self.stats.merged.fetch_add(1, Ordering::Relaxed);
+            }
+            false => {
+                self.stats.superseded.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if state.prunable(wall_millis()) {
+            keys.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<RespValue> {
+        list.iter()
+            .map(|arg| RespValue::BulkString(Some(Bytes::from(arg.to_string()))))
+            .collect()
+    }
+
+    fn node(id: u64) -> Crdt {
+        Crdt::new(id, &[], None, None)
+    }
+
+    fn at(millis: u64, node: u64) -> Stamp {
+        Stamp {
+            millis,
+            counter: 0,
+            node,
+        }
+    }
+
+    fn set(key: &str, value: &str, stamp: Stamp) -> Op {
+        Op::Register {
+            key: Bytes::from(key.to_string()),
+            stamp,
+            value: Some(Bytes::from(value.to_string())),
+            expires_at: None,
+        }
+    }
+
+    fn count(key: &str, epoch: Stamp, node: u64, increments: u64, decrements: u64) -> Op {
+        Op::Counter {
+            key: Bytes::from(key.to_string()),
+            epoch,
+            node,
+            increments,
+            decrements,
+        }
+    }
+
+    #[tokio::test]
+    async fn registers_converge_whatever_the_order() {
+        let ops = [
+            set("k", "first", at(10, 1)),
+            set("k", "concurrent", at(10, 2)),
+            Op::Register {
+                key: Bytes::from("k"),
+                stamp: at(20, 1),
+                value: None,
+                expires_at: None,
+            },
+            set("k", "last", at(30, 2)),
+        ];
+        for order in [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2]] {
+            let (crdt, store) = (node(9), Store::new());
+            for index in order {
+                crdt.merge(&store, ops[index].clone()).await.unwrap();
+            }
+            assert_eq!(
+                store.get(b"k").await,
+                Some(Bytes::from("last")),
+                "{:?}",
+                order
+            );
+        }
+
+        // A tie in time goes to the higher node id; a delete that wins leaves nothing
+        let (crdt, store) = (node(9), Store::new());
+        crdt.merge(&store, set("k", "concurrent", at(10, 2)))
+            .await
+            .unwrap();
+        crdt.merge(&store, set("k", "first", at(10, 1)))
+            .await
+            .unwrap();
+        assert_eq!(store.get(b"k").await, Some(Bytes::from("concurrent")));
+        crdt.merge(&store, ops[2].clone()).await.unwrap();
+        assert_eq!(store.get(b"k").await, None);
+        assert!(crdt.stats.info().contains("crdt_superseded:1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn counters_sum_every_node() {
+        let (crdt, store) = (node(9), Store::new());
+        let epoch = Stamp::default();
+        crdt.merge(&store, count("n", epoch, 1, 2, 0))
+            .await
+            .unwrap();
+        crdt.merge(&store, count("n", epoch, 2, 5, 1))
+            .await
+            .unwrap();
+        // Totals, so a repeat or an older one changes nothing
+        crdt.merge(&store, count("n", epoch, 2, 5, 1))
+            .await
+            .unwrap();
+        crdt.merge(&store, count("n", epoch, 1, 1, 0))
+            .await
+            .unwrap();
+        assert_eq!(store.get(b"n").await, Some(Bytes::from("6")));
+
+        // Counts made here add to the same total
+        let incr = Command::Incr(Bytes::from("n"));
+        let response = crdt.write(&store, &incr, incr.execute(&store)).await;
+        assert_eq!(response, RespValue::Integer(7));
+        let keys = crdt.keys[crdt.shard(b"n")].lock().await;
+        assert_eq!(keys[&Bytes::from("n")].total(), 7);
+        drop(keys);
+
+        // A SET starts the count over; counts against the old register are
+        // dropped, those against the new one kept even if they come first
+        crdt.merge(&store, count("n", at(50, 2), 2, 3, 0))
+            .await
+            .unwrap();
+        assert_eq!(store.get(b"n").await, Some(Bytes::from("7")));
+        crdt.merge(&store, set("n", "100", at(50, 2)))
+            .await
+            .unwrap();
+        crdt.merge(&store, count("n", epoch, 1, 10, 0))
+            .await
+            .unwrap();
+        assert_eq!(store.get(b"n").await, Some(Bytes::from("103")));
+    }
+
+    #[tokio::test]
+    async fn refuses_stamps_far_ahead_of_the_clock() {
+        let (crdt, store) = (node(9), Store::new());
+        let ahead = wall_millis() + MAX_AHEAD_MILLIS + 60_000;
+        assert_eq!(
+            crdt.merge(&store, set("k", "future", at(ahead, 2))).await,
+            Err(CommandError::CrdtStampAhead(MAX_AHEAD_MILLIS / 1000))
+        );
+        assert_eq!(
+            crdt.merge(&store, count("k", at(ahead, 2), 2, 1, 0)).await,
+            Err(CommandError::CrdtStampAhead(MAX_AHEAD_MILLIS / 1000))
+        );
+        assert_eq!(store.get(b"k").await, None);
+        // Nor did the clock move on to it, so writes here still win
+        assert!(crdt.clock.now().millis < ahead);
+
+        // Within the bound, a peer's clock just running fast is taken
+        let fast = wall_millis() + MAX_AHEAD_MILLIS / 2;
+        crdt.merge(&store, set("k", "soon", at(fast, 2)))
+            .await
+            .unwrap();
+        assert_eq!(store.get(b"k").await, Some(Bytes::from("soon")));
+    }
+
+    #[tokio::test]
+    async fn counting_a_key_from_before_starts_over_from_it() {
+        let (crdt, store) = (node(9), Store::new());
+        store.set("n".into(), "10".into()).await;
+        let incr = Command::IncrBy(Bytes::from("n"), 5);
+        let response = crdt.write(&store, &incr, incr.execute(&store)).await;
+        assert_eq!(response, RespValue::Integer(15));
+        let keys = crdt.keys[crdt.shard(b"n")].lock().await;
+        let state = &keys[&Bytes::from("n")];
+        assert_eq!(state.register.value, Some(Bytes::from("10")));
+        assert_eq!(state.total(), 15);
+    }
+
+    async fn known(crdt: &Crdt) -> usize {
+        let mut known = 0;
+        for shard in &crdt.keys {
+            known += shard.lock().await.len();
+        }
+        known
+    }
+
+    #[tokio::test]
+    async fn forgets_keys_once_deleted_or_expired() {
+        let (crdt, store) = (node(9), Store::new());
+        crdt.merge(&store, set("deleted", "v", at(10, 2)))
+            .await
+            .unwrap();
+        assert_eq!(known(&crdt).await, 1);
+        let delete = Op::Register {
+            key: Bytes::from("deleted"),
+            stamp: at(20, 2),
+            value: None,
+            expires_at: None,
+        };
+        crdt.merge(&store, delete).await.unwrap();
+        assert_eq!(known(&crdt).await, 0);
+
+        // A tombstone is kept while writes from before it may still arrive
+        for cmd in [
+            Command::Set(Bytes::from("fresh"), Bytes::from("v")),
+            Command::Del(vec![Bytes::from("fresh")]),
+        ] {
+            crdt.write(&store, &cmd, cmd.execute(&store)).await;
+        }
+        assert_eq!(known(&crdt).await, 1);
+        crdt.prune().await;
+        assert_eq!(known(&crdt).await, 1);
+
+        // Expiring is forgotten alike, by the sweep as nothing writes to it
+        let expiring = Op::Register {
+            key: Bytes::from("expiring"),
+            stamp: at(30, 2),
+            value: Some(Bytes::from("v")),
+            expires_at: Some(wall_millis() + 50),
+        };
+        crdt.merge(&store, expiring).await.unwrap();
+        assert_eq!(store.get(b"expiring").await, Some(Bytes::from("v")));
+        assert_eq!(known(&crdt).await, 2);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        crdt.prune().await;
+        assert_eq!(known(&crdt).await, 1);
+        assert_eq!(store.get(b"expiring").await, None);
+    }
+
+    #[tokio::test]
+    async fn sends_the_time_a_key_expires_at() {
+        let (crdt, store) = (node(9), Store::new());
+        let cmd = Command::Set(Bytes::from("k"), Bytes::from("v"));
+        crdt.write(&store, &cmd, async {
+            store.set_ex(Bytes::from("k"), Bytes::from("v"), 30).await;
+            RespValue::SimpleString("OK".to_string())
+        })
+        .await;
+        let keys = crdt.keys[crdt.shard(b"k")].lock().await;
+        let expires_at = keys[&Bytes::from("k")].register.expires_at.unwrap();
+        let left = expires_at - wall_millis();
+        assert!((29_000..=30_000).contains(&left), "{}", left);
+        drop(keys);
+
+        // One that expired on its way over isn't brought back
+        let late = Op::Register {
+            key: Bytes::from("late"),
+            stamp: at(wall_millis(), 2),
+            value: Some(Bytes::from("v")),
+            expires_at: Some(wall_millis() - 1),
+        };
+        crdt.merge(&store, late).await.unwrap();
+        assert_eq!(store.get(b"late").await, None);
+    }
+
+    #[tokio::test]
+    async fn writes_to_other_keys_go_ahead() {
+        let (crdt, store) = (node(9), Store::new());
+        let blocked = Bytes::from("blocked");
+        let other = (0..)
+            .map(|n| Bytes::from(format!("other{}", n)))
+            .find(|key| crdt.shard(key) != crdt.shard(&blocked))
+            .unwrap();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let slow = Command::Set(blocked.clone(), Bytes::from("v"));
+        let slow = crdt.write(&store, &slow, async {
+            let _ = released.await;
+            slow.execute(&store).await
+        });
+        let fast = Command::Set(other.clone(), Bytes::from("v"));
+        let fast = async {
+            crdt.write(&store, &fast, fast.execute(&store)).await;
+            // Done while the slow write still holds its key
+            release.send(()).unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(2), async { tokio::join!(slow, fast) })
+            .await
+            .expect("a write to one key held up a write to another");
+    }
+
+    #[test]
+    fn ops_round_trip() {
+        let stamp = Stamp {
+            millis: 1_700_000_000_000,
+            counter: 3,
+            node: 7,
+        };
+        let ops = [
+            Op::Register {
+                key: Bytes::from("k"),
+                stamp,
+                value: Some(Bytes::from("v")),
+                expires_at: Some(1_700_000_030_000),
+            },
+            Op::Register {
+                key: Bytes::from("k"),
+                stamp,
+                value: None,
+                expires_at: None,
+            },
+            Op::Counter {
+                key: Bytes::from("n"),
+                epoch: stamp,
+                node: 2,
+                increments: 10,
+                decrements: 4,
+            },
+        ];
+        for op in ops {
+            let RespValue::Array(Some(args)) = op.to_resp() else {
+                panic!("not an array");
+            };
+            assert_eq!(Op::from_args(&args[2..]).unwrap(), op);
+        }
+        assert!(Op::from_args(&args(&["REGISTER", "k"])).is_err());
+        assert!(Op::from_args(&args(&["COUNTER", "k", "1", "x", "1", "1", "1", "1"])).is_err());
+    }
+}
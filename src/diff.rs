@@ -0,0 +1,501 @@
+//! `rudis diff`: compare two datasets key by key, for validating a
+//! migration, a restore or replication between active-active peers.
+//!
+//! Each side is a snapshot file, such as a backup from `BGSAVE`, or a live
+//! server, Redis or rudis, walked with SCAN and read with pipelined TYPE,
+//! TTL and GET as `rudis import` reads its source. The report names keys
+//! missing from either side, values that differ and TTLs further apart
+//! than `--ttl-tolerance`, and the command fails if there are any. Values
+//! of types rudis doesn't hold are compared by type only.
+//!
+//! Snapshots keep the time each key had left when they were taken, so a
+//! snapshot compared with a live server shows TTL drift as old as the
+//! snapshot; raise the tolerance to match. Live servers are read with TTL,
+//! so their TTLs are only good to the second.
+
+use crate::encryption::EncryptionKey;
+use crate::import::{Source, connect, fetch, scan};
+use crate::snapshot::{Snapshot, Value};
+use crate::store::glob_match;
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: rudis diff <first> <second> [OPTIONS]
+  <first>, <second>   A snapshot file, such as a backup, or the host:port of a
+                      live server to read with SCAN
+  --db <n>            Database of live servers to compare (default: 0)
+  --match <pattern>   Only compare keys matching this glob pattern
+  --count <n>         Keys asked for per SCAN page (default: 1000)
+  --ttl-tolerance <secs>
+                      Most two TTLs of a key may differ by (default: 2)
+  --encryption-key <source>
+                      Key encrypted snapshots are read with, given as for the
+                      server's encryption-key
+  --help              Show this help
+";
+
+/// What `rudis diff` compares
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffOptions {
+    pub first: String,
+    pub second: String,
+    pub db: u32,
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub ttl_tolerance: Duration,
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+/// A key as one side holds it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    /// The type's name, as TYPE gives it
+    pub kind: String,
+    /// The value, for strings
+    pub value: Option<Bytes>,
+    pub ttl: Option<Duration>,
+}
+
+/// Every key one side holds
+pub type Dataset = BTreeMap<Bytes, Key>;
+
+/// One way the two sides differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// Held by the second side only
+    MissingFromFirst(Bytes),
+    /// Held by the first side only
+    MissingFromSecond(Bytes),
+    Type(Bytes, String, String),
+    /// The sizes of the two values
+    Value(Bytes, usize, usize),
+    Ttl(Bytes, Option<Duration>, Option<Duration>),
+}
+
+impl Difference {
+    /// The key that differs
+    pub fn key(&self) -> &Bytes {
+        match self {
+            Difference::MissingFromFirst(key)
+            | Difference::MissingFromSecond(key)
+            | Difference::Type(key, ..)
+            | Difference::Value(key, ..)
+            | Difference::Ttl(key, ..) => key,
+        }
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = |key: &Bytes| format!("{:?}", String::from_utf8_lossy(key));
+        let ttl = |ttl: &Option<Duration>| {
+            ttl.map_or_else(
+                || "none".to_string(),
+                |ttl| format!("{:.1}s", ttl.as_secs_f64()),
+            )
+        };
+        match self {
+            Difference::MissingFromFirst(k) => write!(f, "missing from first: {}", key(k)),
+            Difference::MissingFromSecond(k) => write!(f, "missing from second: {}", key(k)),
+            Difference::Type(k, a, b) => write!(f, "type differs: {} ({} vs {})", key(k), a, b),
+            Difference::Value(k, a, b) => {
+                write!(f, "value differs: {} ({} bytes vs {} bytes)", key(k), a, b)
+            }
+            Difference::Ttl(k, a, b) => {
+                write!(f, "TTL drift: {} ({} vs {})", key(k), ttl(a), ttl(b))
+            }
+        }
+    }
+}
+
+/// The outcome of a comparison
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    pub first_keys: usize,
+    pub second_keys: usize,
+    /// In key order
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let count = |matches: fn(&Difference) -> bool| {
+            self.differences.iter().filter(|d| matches(d)).count()
+        };
+        write!(
+            f,
+            "{} keys in first, {} in second: {} missing from first, {} missing from second, \
+             {} types differ, {} values differ, {} TTLs drift",
+            self.first_keys,
+            self.second_keys,
+            count(|d| matches!(d, Difference::MissingFromFirst(_))),
+            count(|d| matches!(d, Difference::MissingFromSecond(_))),
+            count(|d| matches!(d, Difference::Type(..))),
+            count(|d| matches!(d, Difference::Value(..))),
+            count(|d| matches!(d, Difference::Ttl(..)))
+        )
+    }
+}
+
+/// Compare two datasets; TTLs within `tolerance` of each other count as
+/// the same
+pub fn diff(first: &Dataset, second: &Dataset, tolerance: Duration) -> Diff {
+    let mut differences = Vec::new();
+    for (key, a) in first {
+        let Some(b) = second.get(key) else {
+            differences.push(Difference::MissingFromSecond(key.clone()));
+            continue;
+        };
+        if a.kind != b.kind {
+            differences.push(Difference::Type(
+                key.clone(),
+                a.kind.clone(),
+                b.kind.clone(),
+            ));
+            continue;
+        }
+        if let (Some(x), Some(y)) = (&a.value, &b.value)
+            && x != y
+        {
+            differences.push(Difference::Value(key.clone(), x.len(), y.len()));
+        }
+        let drifted = match (a.ttl, b.ttl) {
+            (Some(x), Some(y)) => x.abs_diff(y) > tolerance,
+            (x, y) => x != y,
+        };
+        if drifted {
+            differences.push(Difference::Ttl(key.clone(), a.ttl, b.ttl));
+        }
+    }
+    differences.extend(
+        second
+            .keys()
+            .filter(|key| !first.contains_key(*key))
+            .map(|key| Difference::MissingFromFirst(key.clone())),
+    );
+    differences.sort_by(|a, b| a.key().cmp(b.key()));
+    Diff {
+        first_keys: first.len(),
+        second_keys: second.len(),
+        differences,
+    }
+}
+
+/// The keys of a snapshot matching `pattern`
+pub fn from_snapshot(snapshot: Snapshot, pattern: Option<&str>) -> Dataset {
+    snapshot
+        .entries
+        .into_iter()
+        .filter(|entry| pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), &entry.key)))
+        .map(|entry| {
+            let kind = entry.value.type_name().to_string();
+            let Value::String(value) = entry.value;
+            let key = Key {
+                kind,
+                value: Some(value),
+                ttl: entry.ttl,
+            };
+            (entry.key, key)
+        })
+        .collect()
+}
+
+/// Read every key of a live server, as `options` narrows them
+pub async fn from_server(addr: &str, options: &DiffOptions) -> Result<Dataset> {
+    let mut client = connect(addr, options.db).await?;
+    let mut dataset = Dataset::new();
+    let mut cursor = "0".to_string();
+    loop {
+        let keys;
+        (cursor, keys) = scan(
+            &mut client,
+            &cursor,
+            options.pattern.as_deref(),
+            options.count,
+        )
+        .await?;
+        let found = fetch(&mut client, &keys).await?;
+        for (key, found) in keys.into_iter().zip(found) {
+            let found = match found {
+                Source::String(value, ttl) => Key {
+                    kind: "string".to_string(),
+                    value: Some(value),
+                    ttl: ttl.map(Duration::from_secs),
+                },
+                // Gone since SCAN named it
+                Source::Missing => continue,
+                Source::Other(kind) => Key {
+                    kind,
+                    value: None,
+                    ttl: None,
+                },
+            };
+            dataset.insert(key, found);
+        }
+        if cursor == "0" {
+            return Ok(dataset);
+        }
+    }
+}
+
+/// Read one side: a snapshot if `side` names a file, else a live server
+async fn load(side: &str, options: &DiffOptions) -> Result<Dataset> {
+    let path = Path::new(side);
+    if path.is_file() {
+        let snapshot = Snapshot::load_with(path, options.encryption_key.as_ref())?;
+        Ok(from_snapshot(snapshot, options.pattern.as_deref()))
+    } else {
+        from_server(side, options).await
+    }
+}
+
+/// Parse `rudis diff` arguments; None asks for the usage text
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<DiffOptions>> {
+    let mut sides = Vec::new();
+    let mut options = DiffOptions {
+        first: String::new(),
+        second: String::new(),
+        db: 0,
+        pattern: None,
+        count: 1000,
+        ttl_tolerance: Duration::from_secs(2),
+        encryption_key: None,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("Option '{}' needs a value", arg))
+        };
+        match arg.as_str() {
+            "--help" => return Ok(None),
+            "--db" => {
+                let db = value()?;
+                options.db = db
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid database '{}'", db))?;
+            }
+            "--match" => options.pattern = Some(value()?),
+            "--count" => {
+                let count = value()?;
+                options.count = count
+                    .parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| anyhow!("Invalid count '{}'", count))?;
+            }
+            "--ttl-tolerance" => {
+                let secs = value()?;
+                options.ttl_tolerance = secs
+                    .parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or_else(|| anyhow!("Invalid TTL tolerance '{}'", secs))?;
+            }
+            "--encryption-key" => {
+                options.encryption_key = Some(EncryptionKey::load(&value()?)?);
+            }
+            _ if arg.starts_with("--") => bail!("Unknown option '{}'", arg),
+            _ => sides.push(arg),
+        }
+    }
+    let [first, second] = <[String; 2]>::try_from(sides)
+        .map_err(|_| anyhow!("Two snapshots or servers to compare are required"))?;
+    options.first = first;
+    options.second = second;
+    Ok(Some(options))
+}
+
+/// Run `rudis diff` with its arguments, printing every difference, then a
+/// summary; fails if the two sides differ
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<()> {
+    let Some(options) = parse_args(args)? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    let diff = tokio::runtime::Runtime::new()?.block_on(async {
+        let first = load(&options.first, &options).await?;
+        let second = load(&options.second, &options).await?;
+        Ok::<_, anyhow::Error>(diff(&first, &second, options.ttl_tolerance))
+    })?;
+    for difference in &diff.differences {
+        println!("{}", difference);
+    }
+    println!("{}", diff);
+    if !diff.differences.is_empty() {
+        bail!("{} and {} differ", options.first, options.second);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::snapshot::Entry;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn string(value: &str, ttl: Option<u64>) -> Key {
+        Key {
+            kind: "string".to_string(),
+            value: Some(Bytes::copy_from_slice(value.as_bytes())),
+            ttl: ttl.map(Duration::from_secs),
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse_args(args(&[
+            "backup.snapshot",
+            "10.0.0.5:6379",
+            "--match",
+            "user:*",
+            "--ttl-tolerance",
+            "60",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.first, "backup.snapshot");
+        assert_eq!(options.second, "10.0.0.5:6379");
+        assert_eq!(options.pattern.as_deref(), Some("user:*"));
+        assert_eq!(options.ttl_tolerance, Duration::from_secs(60));
+
+        assert_eq!(parse_args(args(&["--help"])).unwrap(), None);
+        for bad in [
+            &[][..],
+            &["a:1"],
+            &["a:1", "b:1", "c:1"],
+            &["a:1", "b:1", "--count", "0"],
+            &["a:1", "b:1", "--ttl-tolerance", "-1"],
+            &["a:1", "b:1", "--bogus"],
+        ] {
+            assert!(parse_args(args(bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn reports_each_kind_of_difference() {
+        let first: Dataset = [
+            ("same", string("1", None)),
+            ("gone", string("1", None)),
+            ("changed", string("1", None)),
+            ("close", string("1", Some(300))),
+            ("drifted", string("1", Some(300))),
+            ("persisted", string("1", Some(300))),
+            ("retyped", string("1", None)),
+        ]
+        .into_iter()
+        .map(|(key, value)| (Bytes::from(key), value))
+        .collect();
+        let mut second = first.clone();
+        second.remove(&Bytes::from("gone"));
+        second.insert("new".into(), string("1", None));
+        second.insert("changed".into(), string("22", None));
+        second.insert("close".into(), string("1", Some(299)));
+        second.insert("drifted".into(), string("1", Some(100)));
+        second.insert("persisted".into(), string("1", None));
+        second.insert(
+            "retyped".into(),
+            Key {
+                kind: "hash".to_string(),
+                value: None,
+                ttl: None,
+            },
+        );
+
+        let diff = diff(&first, &second, Duration::from_secs(2));
+        let lines: Vec<String> = diff.differences.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "value differs: \"changed\" (1 bytes vs 2 bytes)",
+                "TTL drift: \"drifted\" (300.0s vs 100.0s)",
+                "missing from second: \"gone\"",
+                "missing from first: \"new\"",
+                "TTL drift: \"persisted\" (300.0s vs none)",
+                "type differs: \"retyped\" (string vs hash)",
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "7 keys in first, 7 in second: 1 missing from first, 1 missing from second, \
+             1 types differ, 1 values differ, 2 TTLs drift"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn compares_a_backup_with_a_live_server() {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let store = server.store().clone();
+        tokio::spawn(async move { server.run().await });
+        for i in 0..1500 {
+            store
+                .set(format!("key:{}", i).into(), i.to_string().into())
+                .await;
+        }
+        store.set_ex("session".into(), "abc".into(), 300).await;
+        let path = std::env::temp_dir().join(format!("rudis-diff-{}.snapshot", std::process::id()));
+        std::fs::write(&path, store.export().await.to_bytes()).unwrap();
+        let options = parse_args(args(&[path.to_str().unwrap(), &addr]))
+            .unwrap()
+            .unwrap();
+
+        let backup = load(&options.first, &options).await.unwrap();
+        let live = load(&options.second, &options).await.unwrap();
+        let same = diff(&backup, &live, options.ttl_tolerance);
+        assert_eq!(same.first_keys, 1501);
+        assert_eq!(same.differences, []);
+
+        store.del(&[Bytes::from("key:7")]).await;
+        store.set("key:8".into(), "changed".into()).await;
+        store.persist(b"session").await;
+        let live = load(&options.second, &options).await.unwrap();
+        let changed = diff(&backup, &live, options.ttl_tolerance);
+        let keys: Vec<&Bytes> = changed.differences.iter().map(Difference::key).collect();
+        assert_eq!(keys, ["key:7", "key:8", "session"]);
+        assert_eq!(
+            changed.differences[..2],
+            [
+                Difference::MissingFromSecond("key:7".into()),
+                Difference::Value("key:8".into(), 1, 7),
+            ]
+        );
+        assert!(matches!(
+            changed.differences[2],
+            Difference::Ttl(_, Some(_), None)
+        ));
+
+        // Narrowed to the keys a pattern matches, on both sides
+        let options = DiffOptions {
+            pattern: Some("key:1?".to_string()),
+            ..options
+        };
+        let backup = load(&options.first, &options).await.unwrap();
+        let live = load(&options.second, &options).await.unwrap();
+        assert_eq!(backup.len(), 10);
+        assert_eq!(diff(&backup, &live, options.ttl_tolerance).differences, []);
+        std::fs::remove_file(&path).unwrap();
+
+        let entry = Entry {
+            key: "a".into(),
+            value: Value::String("1".into()),
+            ttl: None,
+        };
+        let snapshot = Snapshot {
+            entries: vec![entry],
+        };
+        assert_eq!(
+            from_snapshot(snapshot, None),
+            Dataset::from([("a".into(), string("1", None))])
+        );
+    }
+}
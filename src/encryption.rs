@@ -0,0 +1,272 @@
+//! Encryption at rest for the files the server writes: backups and command
+//! recordings. With `encryption-key` set, each file is sealed with
+//! XChaCha20-Poly1305 and can only be read back, or altered undetected, with
+//! the same key.
+//!
+//! An encrypted file opens with `RUDISENC`, a format version and a random
+//! 16-byte file id. Segments follow, each a random 24-byte nonce, the
+//! ciphertext's length as a big-endian u32, then the ciphertext and its tag.
+//! Every segment is authenticated together with the file id and its
+//! position, so segments can't be reordered, dropped from the middle or
+//! moved between files. Recordings are sealed a segment per batch of
+//! commands written; one cut short by the server stopping mid-write is
+//! ignored, as a partly written command is in a plain recording.
+
+use anyhow::{Result, anyhow, bail};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+const MAGIC: &[u8] = b"RUDISENC";
+const VERSION: u8 = 1;
+const FILE_ID_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + FILE_ID_LEN;
+const NONCE_LEN: usize = 24;
+/// Nonce and length ahead of each segment's ciphertext
+const SEGMENT_HEADER_LEN: usize = NONCE_LEN + 4;
+/// Most plaintext `encrypt` seals into one segment
+const SEGMENT_LEN: usize = 1024 * 1024;
+
+/// A 256-bit key for the files written at rest
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never log the key itself
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    /// A key given as 64 hex digits
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        let mut key = [0; 32];
+        if hex.len() != 2 * key.len() || !hex.is_ascii() {
+            bail!("an encryption key must be 64 hex digits");
+        }
+        for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| anyhow!("an encryption key must be 64 hex digits"))?;
+        }
+        Ok(Self(key))
+    }
+
+    /// The key from `source`: 64 hex digits, `env:NAME` for an environment
+    /// variable holding them, `file:PATH` for a file holding them, or
+    /// `command:PROGRAM [ARG ...]` for a program that prints them, such as
+    /// a KMS client. The program is run directly, without a shell.
+    pub fn load(source: &str) -> Result<Self> {
+        if let Some(name) = source.strip_prefix("env:") {
+            let hex = std::env::var(name)
+                .map_err(|_| anyhow!("environment variable {} is not set", name))?;
+            Self::from_hex(&hex).map_err(|e| anyhow!("{}: {}", name, e))
+        } else if let Some(path) = source.strip_prefix("file:") {
+            let hex = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Can't read '{}': {}", path, e))?;
+            Self::from_hex(&hex).map_err(|e| anyhow!("'{}': {}", path, e))
+        } else if let Some(command) = source.strip_prefix("command:") {
+            let mut words = command.split_whitespace();
+            let program = words
+                .next()
+                .ok_or_else(|| anyhow!("command: needs a program to run"))?;
+            let output = std::process::Command::new(program)
+                .args(words)
+                .output()
+                .map_err(|e| anyhow!("Can't run '{}': {}", program, e))?;
+            if !output.status.success() {
+                bail!("'{}' failed with {}", program, output.status);
+            }
+            let hex = String::from_utf8_lossy(&output.stdout);
+            Self::from_hex(&hex).map_err(|e| anyhow!("'{}' printed no key: {}", program, e))
+        } else {
+            Self::from_hex(source)
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+/// Whether `data` is an encrypted file
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Seal `plaintext` as an encrypted file
+pub fn encrypt(key: &EncryptionKey, plaintext: &[u8]) -> Vec<u8> {
+    let mut sealer = Sealer::new(key);
+    let mut out = sealer.header();
+    for segment in plaintext.chunks(SEGMENT_LEN) {
+        out.extend(sealer.seal(segment));
+    }
+    out
+}
+
+/// Open an encrypted file, failing if any of it isn't as `encrypt` wrote
+/// it with this key
+pub fn decrypt(key: &EncryptionKey, data: &[u8]) -> Result<Vec<u8>> {
+    open(key, data, false)
+}
+
+/// Open an encrypted file; with `partial_tail` a segment cut short at the
+/// end is ignored rather than an error
+pub(crate) fn open(key: &EncryptionKey, data: &[u8], partial_tail: bool) -> Result<Vec<u8>> {
+    if !is_encrypted(data) || data.len() < HEADER_LEN {
+        bail!("not an encrypted file");
+    }
+    if data[MAGIC.len()] != VERSION {
+        bail!("unsupported encryption version {}", data[MAGIC.len()]);
+    }
+    let file_id = &data[MAGIC.len() + 1..HEADER_LEN];
+    let cipher = key.cipher();
+    let mut rest = &data[HEADER_LEN..];
+    let mut plaintext = Vec::with_capacity(rest.len());
+    let mut index = 0;
+    while !rest.is_empty() {
+        let complete = rest.len() >= SEGMENT_HEADER_LEN && {
+            let len = u32::from_be_bytes(rest[NONCE_LEN..SEGMENT_HEADER_LEN].try_into().unwrap());
+            rest.len() >= SEGMENT_HEADER_LEN + len as usize
+        };
+        if !complete {
+            if partial_tail {
+                break;
+            }
+            bail!("encrypted file is truncated");
+        }
+        let len = u32::from_be_bytes(rest[NONCE_LEN..SEGMENT_HEADER_LEN].try_into().unwrap());
+        let (segment, next) = rest.split_at(SEGMENT_HEADER_LEN + len as usize);
+        let nonce = XNonce::from_slice(&segment[..NONCE_LEN]);
+        let opened = cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &segment[SEGMENT_HEADER_LEN..],
+                    aad: &aad(file_id, index),
+                },
+            )
+            .map_err(|_| anyhow!("wrong encryption key, or the file was altered"))?;
+        plaintext.extend(opened);
+        rest = next;
+        index += 1;
+    }
+    Ok(plaintext)
+}
+
+/// Seals a file's contents a segment at a time, for files written as they go
+pub(crate) struct Sealer {
+    cipher: XChaCha20Poly1305,
+    file_id: [u8; FILE_ID_LEN],
+    next: u64,
+}
+
+impl Sealer {
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        let mut file_id = [0; FILE_ID_LEN];
+        OsRng.fill_bytes(&mut file_id);
+        Self {
+            cipher: key.cipher(),
+            file_id,
+            next: 0,
+        }
+    }
+
+    /// The file header, written ahead of the first segment
+    pub(crate) fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&self.file_id);
+        header
+    }
+
+    /// Seal `plaintext` as the file's next segment
+    pub(crate) fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &aad(&self.file_id, self.next),
+        };
+        // Sealing only fails for plaintexts far beyond any segment's size
+        let ciphertext = self.cipher.encrypt(&nonce, payload).unwrap();
+        self.next += 1;
+        let mut segment = Vec::with_capacity(SEGMENT_HEADER_LEN + ciphertext.len());
+        segment.extend_from_slice(&nonce);
+        segment.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        segment.extend(ciphertext);
+        segment
+    }
+}
+
+/// Data each segment is authenticated with besides its contents
+fn aad(file_id: &[u8], index: u64) -> Vec<u8> {
+    [file_id, &index.to_be_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key() -> EncryptionKey {
+        EncryptionKey::from_hex(HEX).unwrap()
+    }
+
+    #[test]
+    fn round_trips_and_detects_tampering() {
+        let plaintext: Vec<u8> = (0..3 * SEGMENT_LEN + 10).map(|i| i as u8).collect();
+        let sealed = encrypt(&key(), &plaintext);
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.windows(64).any(|w| w == &plaintext[..64]));
+        assert_eq!(decrypt(&key(), &sealed).unwrap(), plaintext);
+
+        let other = EncryptionKey::from_hex(&HEX.replace('0', "f")).unwrap();
+        assert!(decrypt(&other, &sealed).is_err());
+        let mut flipped = sealed.clone();
+        flipped[HEADER_LEN + SEGMENT_HEADER_LEN + 5] ^= 1;
+        assert!(decrypt(&key(), &flipped).is_err());
+        // Segments only open in their place in their own file
+        let segment = SEGMENT_HEADER_LEN + SEGMENT_LEN + 16;
+        let mut swapped = sealed[..HEADER_LEN].to_vec();
+        swapped.extend_from_slice(&sealed[HEADER_LEN + segment..HEADER_LEN + 2 * segment]);
+        swapped.extend_from_slice(&sealed[HEADER_LEN..HEADER_LEN + segment]);
+        assert!(decrypt(&key(), &swapped).is_err());
+        let mut moved = encrypt(&key(), b"")[..HEADER_LEN].to_vec();
+        moved.extend_from_slice(&sealed[HEADER_LEN..HEADER_LEN + segment]);
+        assert!(decrypt(&key(), &moved).is_err());
+
+        // A segment cut short is an error, unless it's an unfinished tail
+        let truncated = &sealed[..sealed.len() - 1];
+        assert!(decrypt(&key(), truncated).is_err());
+        assert_eq!(
+            open(&key(), truncated, true).unwrap(),
+            &plaintext[..3 * SEGMENT_LEN]
+        );
+    }
+
+    #[test]
+    fn keys_load_from_their_sources() {
+        assert_eq!(EncryptionKey::load(HEX).unwrap(), key());
+        assert!(EncryptionKey::load("abc").is_err());
+        assert!(EncryptionKey::load(&HEX.replace('0', "g")).is_err());
+        assert!(EncryptionKey::load("env:RUDIS_TEST_NO_SUCH_KEY").is_err());
+
+        let path = std::env::temp_dir().join(format!("rudis-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", HEX)).unwrap();
+        let file = format!("file:{}", path.display());
+        assert_eq!(EncryptionKey::load(&file).unwrap(), key());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            EncryptionKey::load(&format!("command:echo {}", HEX)).unwrap(),
+            key()
+        );
+        assert!(EncryptionKey::load("command:false").is_err());
+        assert_eq!(format!("{:?}", key()), "EncryptionKey(..)");
+    }
+}
@@ -0,0 +1,258 @@
+//! The errors commands reply with. Each displays as the error reply Redis
+//! sends in its place, prefix and all, so clients that branch on the prefix
+//! (`NOAUTH`, `NOPERM`, `BUSY`...) see what they expect, and tests can
+//! match on the variant rather than the text.
+//!
+//! Parsing returns these wrapped in `anyhow::Error`, from which they can be
+//! taken back with `downcast_ref`; the store returns them as they are.
+
+use crate::resp::RespValue;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// A frame that isn't valid RESP, after which the connection closes
+    Protocol(String),
+    /// Arguments that don't fit the command's syntax
+    Syntax,
+    /// The wrong number of arguments for the named command
+    WrongArity(String),
+    UnknownCommand(String),
+    /// A subcommand `command` doesn't have, or one given the wrong number of
+    /// arguments
+    UnknownSubcommand {
+        command: &'static str,
+        subcommand: String,
+    },
+    NotInteger,
+    NotFloat,
+    /// An INCR or DECR past the range of a 64-bit integer
+    Overflow,
+    /// A non-positive expire time given to the named command
+    InvalidExpireTime(&'static str),
+    InvalidCursor,
+    NegativeTimeout,
+    DbIndexOutOfRange,
+    NoSuchKey,
+    /// A key longer than `max-key-size`
+    KeyTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A value longer than `max-value-size`
+    ValueTooLarge {
+        len: usize,
+        max: usize,
+    },
+    ClusterDisabled,
+    /// A command the server answers itself, run against a bare store
+    ServerOnly(&'static str),
+    /// A command run before AUTH where clients must log in
+    NoAuth,
+    /// AUTH with a name or password no tenant has
+    WrongPass,
+    /// An admin command run by a tenant
+    NoPermCommand {
+        user: String,
+        command: &'static str,
+    },
+    /// A key outside the tenant's prefix
+    NoPermKey,
+    /// A write by a tenant over one of its quotas
+    Oom {
+        tenant: String,
+        quota: &'static str,
+    },
+    /// A command shed while the server is overloaded
+    Busy,
+    /// A blocking command cut short by the server shutting down
+    Unblocked,
+    /// A command from a client over its `rate-limit`
+    RateLimited,
+    /// SHUTDOWN SAVE that couldn't write its backup, so the server stays up
+    ShutdownSaveFailed,
+    /// A read that ran longer than `command-time-budget`, in milliseconds
+    TimeBudgetExceeded(u128),
+    /// BGSAVE while a backup is already being taken
+    BackupInProgress,
+    /// CRDT MERGE without `crdt-peers`
+    CrdtDisabled,
+    /// CRDT MERGE from a client, where peers link in over `crdt-tls-port`
+    CrdtMergeOverTlsOnly,
+    /// CRDT MERGE on a connection that hasn't sent CRDT AUTH
+    CrdtPeerUnproven,
+    /// Any command but CRDT MERGE sent on `crdt-tls-port`
+    CrdtTlsPortMergeOnly,
+    /// CRDT MERGE stamped further ahead of this node's clock than the
+    /// given seconds
+    CrdtStampAhead(u64),
+    /// ACL SAVE or ACL LOAD without an `aclfile`
+    NoAclFile,
+    /// ACL SAVE that couldn't write the `aclfile`
+    AclSaveFailed,
+    /// ACL LOAD of an `aclfile` that couldn't be read or parsed, and why
+    AclLoadFailed(String),
+    /// Any other error, given without its `ERR` prefix
+    Other(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Protocol(message) => write!(f, "ERR Protocol error: {}", message),
+            CommandError::Syntax => f.write_str("ERR syntax error"),
+            CommandError::WrongArity(command) => {
+                write!(f, "ERR wrong number of arguments for '{}' command", command)
+            }
+            CommandError::UnknownCommand(command) => write!(f, "ERR unknown command '{}'", command),
+            CommandError::UnknownSubcommand {
+                command,
+                subcommand,
+            } => write!(
+                f,
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try {} HELP.",
+                subcommand, command
+            ),
+            CommandError::NotInteger => f.write_str("ERR value is not an integer or out of range"),
+            CommandError::NotFloat => f.write_str("ERR value is not a valid float"),
+            CommandError::Overflow => f.write_str("ERR increment or decrement would overflow"),
+            CommandError::InvalidExpireTime(command) => {
+                write!(f, "ERR invalid expire time in '{}' command", command)
+            }
+            CommandError::InvalidCursor => f.write_str("ERR invalid cursor"),
+            CommandError::NegativeTimeout => f.write_str("ERR timeout is negative"),
+            CommandError::DbIndexOutOfRange => f.write_str("ERR DB index is out of range"),
+            CommandError::NoSuchKey => f.write_str("ERR no such key"),
+            CommandError::KeyTooLarge { len, max } => write!(
+                f,
+                "ERR key is {} bytes, larger than max-key-size ({} bytes)",
+                len, max
+            ),
+            CommandError::ValueTooLarge { len, max } => write!(
+                f,
+                "ERR value is {} bytes, larger than max-value-size ({} bytes)",
+                len, max
+            ),
+            CommandError::ClusterDisabled => {
+                f.write_str("ERR This instance has cluster support disabled")
+            }
+            CommandError::ServerOnly(command) => {
+                write!(f, "ERR {} must be handled by the server", command)
+            }
+            CommandError::NoAuth => f.write_str("NOAUTH Authentication required."),
+            CommandError::WrongPass => {
+                f.write_str("WRONGPASS invalid username-password pair or user is disabled.")
+            }
+            CommandError::NoPermCommand { user, command } => write!(
+                f,
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user, command
+            ),
+            CommandError::NoPermKey => f.write_str("NOPERM No permissions to access a key"),
+            CommandError::Oom { tenant, quota } => write!(
+                f,
+                "OOM command not allowed when tenant '{}' is over its {} quota",
+                tenant, quota
+            ),
+            CommandError::Busy => f.write_str("BUSY server is overloaded, try again later"),
+            CommandError::Unblocked => f.write_str("UNBLOCKED the server is shutting down"),
+            CommandError::RateLimited => f.write_str("ERR rate limit exceeded"),
+            CommandError::ShutdownSaveFailed => {
+                f.write_str("ERR Errors trying to SHUTDOWN. Check logs.")
+            }
+            CommandError::TimeBudgetExceeded(millis) => write!(
+                f,
+                "ERR command aborted after running longer than command-time-budget ({} ms)",
+                millis
+            ),
+            CommandError::BackupInProgress => {
+                f.write_str("ERR Background save already in progress")
+            }
+            CommandError::CrdtDisabled => {
+                f.write_str("ERR CRDT replication is off, see crdt-peers")
+            }
+            CommandError::CrdtMergeOverTlsOnly => {
+                f.write_str("NOPERM CRDT MERGE is only taken from peers on crdt-tls-port")
+            }
+            CommandError::CrdtPeerUnproven => {
+                f.write_str("NOPERM CRDT MERGE is only taken from peers after CRDT AUTH")
+            }
+            CommandError::CrdtTlsPortMergeOnly => {
+                f.write_str("ERR only CRDT MERGE is taken on crdt-tls-port")
+            }
+            CommandError::CrdtStampAhead(seconds) => write!(
+                f,
+                "ERR CRDT MERGE stamp is more than {}s ahead of this node's clock",
+                seconds
+            ),
+            CommandError::NoAclFile => {
+                f.write_str("ERR This instance is not configured to use an ACL file")
+            }
+            CommandError::AclSaveFailed => f.write_str(
+                "ERR There was an error trying to save the ACLs. \
+                 Please check the server logs for more information",
+            ),
+            CommandError::AclLoadFailed(reason) => write!(f, "ERR {}", reason),
+            CommandError::Other(message) => write!(f, "ERR {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<CommandError> for RespValue {
+    fn from(error: CommandError) -> Self {
+        RespValue::Error(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displays_as_redis_replies() {
+        let prefix = |error: CommandError| error.to_string().split(' ').next().unwrap().to_string();
+        assert_eq!(prefix(CommandError::Syntax), "ERR");
+        assert_eq!(prefix(CommandError::NoAuth), "NOAUTH");
+        assert_eq!(prefix(CommandError::WrongPass), "WRONGPASS");
+        assert_eq!(prefix(CommandError::NoPermKey), "NOPERM");
+        assert_eq!(prefix(CommandError::Busy), "BUSY");
+        assert_eq!(prefix(CommandError::Unblocked), "UNBLOCKED");
+        assert_eq!(
+            CommandError::WrongArity("get".to_string()).to_string(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            CommandError::UnknownSubcommand {
+                command: "CONFIG",
+                subcommand: "set".to_string()
+            }
+            .to_string(),
+            "ERR unknown subcommand or wrong number of arguments for 'set'. Try CONFIG HELP."
+        );
+        assert_eq!(
+            CommandError::Oom {
+                tenant: "billing".to_string(),
+                quota: "max-keys"
+            }
+            .to_string(),
+            "OOM command not allowed when tenant 'billing' is over its max-keys quota"
+        );
+        assert_eq!(
+            CommandError::RateLimited.to_string(),
+            "ERR rate limit exceeded"
+        );
+        assert_eq!(
+            RespValue::from(CommandError::Other("timeout".to_string())),
+            RespValue::Error("ERR timeout".to_string())
+        );
+
+        // Taken back out of the anyhow errors parsing returns
+        let error = anyhow::Error::from(CommandError::NotInteger);
+        assert_eq!(
+            error.downcast_ref::<CommandError>(),
+            Some(&CommandError::NotInteger)
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! Key events for embedders. A host running rudis as a library registers
+//! async callbacks on a `Store` with `Store::on_key_event`, and hears about
+//! every key that expires, to clean up after it without polling. Or it
+//! subscribes to a key prefix with `Store::subscribe`, and reads every
+//! change to the keys under it off a `Changes` stream, to keep a copy of
+//! them or invalidate a cache.
+//!
+//! Each callback runs on a task of its own and is handed events in the order
+//! they happened, one at a time. The store never waits for a callback or a
+//! subscriber: one that falls `QUEUE_DEPTH` events behind misses the ones
+//! after, which are counted by `Store::dropped_key_events` and
+//! `Changes::missed`.
+//!
+//! Inside the crate, expiry hooks are told about expired keys in line, before
+//! the deletion returns, so a server can write a DEL for each into its
+//! recording and upstream in step with the commands around it.
+
+use bytes::Bytes;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Events queued for a callback before further ones are dropped
+const QUEUE_DEPTH: usize = 65536;
+
+/// Why a key went away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEventReason {
+    /// Its TTL passed and it was deleted, on access or by active expiration
+    Expired,
+    /// Deleted to stay under a memory limit. Reserved: there is no
+    /// `maxmemory` yet, so no key is evicted
+    Evicted,
+}
+
+/// A key that went away, as handed to `Store::on_key_event` callbacks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Bytes,
+    /// Type of the value the key held, as TYPE names it
+    pub value_type: &'static str,
+    pub reason: KeyEventReason,
+}
+
+/// What a write did to a key, see `Change`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Given a new value, by SET, SETEX, SETNX, MSET or INCR and friends
+    Set,
+    /// Deleted by DEL, or by EXPIRE with a TTL that has already passed
+    Del,
+    /// Deleted because its TTL passed
+    Expired,
+}
+
+/// A change to a key, read off a `Store::subscribe` stream. TTL changes
+/// that leave the value alone, by EXPIRE or PERSIST, aren't reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub key: Bytes,
+    /// The new value, for `ChangeKind::Set`
+    pub value: Option<Bytes>,
+}
+
+/// Changes to the keys under a prefix, in the order they were made; see
+/// `Store::subscribe`. Dropping it unsubscribes.
+#[derive(Debug)]
+pub struct Changes {
+    receiver: mpsc::Receiver<Change>,
+    missed: Arc<AtomicU64>,
+}
+
+impl Changes {
+    /// The next change, waiting for one to be made. None once the store
+    /// has been dropped and every change made before has been read.
+    pub async fn next(&mut self) -> Option<Change> {
+        self.receiver.recv().await
+    }
+
+    /// The next change if one has been made already, without waiting
+    pub fn try_next(&mut self) -> Option<Change> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Changes left out of the stream because it fell `QUEUE_DEPTH` behind.
+    /// Once this moves, a copy kept from the stream is stale.
+    pub fn missed(&self) -> u64 {
+        self.missed.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    prefix: Bytes,
+    sender: mpsc::Sender<Change>,
+    missed: Arc<AtomicU64>,
+}
+
+/// Called in line with the deletion of each key that expires
+type ExpiryHook = Box<dyn Fn(&Bytes) + Send + Sync>;
+
+#[derive(Default)]
+struct ExpiryHooks {
+    next_id: u64,
+    hooks: Vec<(u64, ExpiryHook)>,
+}
+
+impl fmt::Debug for ExpiryHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExpiryHooks")
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+/// Callbacks and change subscribers registered on a store, each fed
+/// through a queue of its own, and expiry hooks, which aren't
+#[derive(Debug, Default)]
+pub struct KeyEvents {
+    /// Whether any callback is registered, so expiry skips the lock if not
+    listening: AtomicBool,
+    listeners: Mutex<Vec<mpsc::Sender<KeyEvent>>>,
+    /// Events not delivered because a callback's queue was full
+    dropped: AtomicU64,
+    /// Subscribers, counted so writes skip the lock while there are none
+    subscribed: AtomicUsize,
+    subscribers: Mutex<Vec<Subscriber>>,
+    /// Whether any expiry hook is registered, so expiry skips the lock if not
+    hooked: AtomicBool,
+    expiry_hooks: Mutex<ExpiryHooks>,
+}
+
+impl KeyEvents {
+    /// Register `callback`, spawning the task that runs it on the current
+    /// tokio runtime
+    pub fn listen<F, Fut>(&self, mut callback: F)
+    where
+        F: FnMut(KeyEvent) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel(QUEUE_DEPTH);
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                callback(event).await;
+            }
+        });
+        self.listeners.lock().unwrap().push(sender);
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    /// Queue an event for each of `keys` to every callback
+    pub fn emit(&self, reason: KeyEventReason, keys: impl IntoIterator<Item = Bytes>) {
+        if !self.listening.load(Ordering::Relaxed) {
+            return;
+        }
+        let listeners = self.listeners.lock().unwrap();
+        for key in keys {
+            let event = KeyEvent {
+                key,
+                // Strings are the only type there is
+                value_type: "string",
+                reason,
+            };
+            for listener in listeners.iter() {
+                if listener.try_send(event.clone()).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Events dropped since startup because a callback fell behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Subscribe to changes to the keys starting with `prefix`
+    pub fn subscribe(&self, prefix: Bytes) -> Changes {
+        let (sender, receiver) = mpsc::channel(QUEUE_DEPTH);
+        let missed = Arc::new(AtomicU64::new(0));
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(Subscriber {
+            prefix,
+            sender,
+            missed: missed.clone(),
+        });
+        self.subscribed.store(subscribers.len(), Ordering::Relaxed);
+        Changes { receiver, missed }
+    }
+
+    /// Queue `kind` of change to `key` to the subscribers whose prefix it
+    /// has, forgetting those that have gone. `value` is only called, and
+    /// `key` only copied, if someone has subscribed.
+    pub fn changed(&self, kind: ChangeKind, key: &[u8], value: impl FnOnce() -> Option<Bytes>) {
+        if self.subscribed.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let change = Change {
+            kind,
+            key: Bytes::copy_from_slice(key),
+            value: value(),
+        };
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| {
+            if !key.starts_with(&subscriber.prefix) {
+                return !subscriber.sender.is_closed();
+            }
+            let change = change.clone();
+            match subscriber.sender.try_send(change) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    subscriber.missed.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+        self.subscribed.store(subscribers.len(), Ordering::Relaxed);
+    }
+
+    /// Register `hook` to be called with each key deleted as expired, on
+    /// the task deleting it. Returns an id to unregister it by.
+    pub(crate) fn hook_expiry(&self, hook: impl Fn(&Bytes) + Send + Sync + 'static) -> u64 {
+        let mut hooks = self.expiry_hooks.lock().unwrap();
+        let id = hooks.next_id;
+        hooks.next_id += 1;
+        hooks.hooks.push((id, Box::new(hook)));
+        self.hooked.store(true, Ordering::Relaxed);
+        id
+    }
+
+    pub(crate) fn unhook_expiry(&self, id: u64) {
+        let mut hooks = self.expiry_hooks.lock().unwrap();
+        hooks.hooks.retain(|(hook, _)| *hook != id);
+        self.hooked
+            .store(!hooks.hooks.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Call every expiry hook with each of `keys`
+    pub(crate) fn expire_hooked(&self, keys: &[Bytes]) {
+        if !self.hooked.load(Ordering::Relaxed) {
+            return;
+        }
+        let hooks = self.expiry_hooks.lock().unwrap();
+        for key in keys {
+            for (_, hook) in &hooks.hooks {
+                hook(key);
+            }
+        }
+    }
+}
+
+/// An expiry hook registered by `Store::hook_expiry`, unregistered when
+/// this is dropped
+#[derive(Debug)]
+pub(crate) struct ExpiryHookGuard {
+    pub(crate) events: Arc<KeyEvents>,
+    pub(crate) id: u64,
+}
+
+impl Drop for ExpiryHookGuard {
+    fn drop(&mut self) {
+        self.events.unhook_expiry(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn expired(key: &str) -> KeyEvent {
+        KeyEvent {
+            key: Bytes::from(key.to_string()),
+            value_type: "string",
+            reason: KeyEventReason::Expired,
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_events_in_order_to_every_callback() {
+        let events = KeyEvents::default();
+        let (first_tx, mut first) = mpsc::unbounded_channel();
+        let (second_tx, mut second) = mpsc::unbounded_channel();
+        events.listen(move |event| {
+            let first_tx = first_tx.clone();
+            async move { first_tx.send(event).unwrap() }
+        });
+        events.listen(move |event| {
+            let second_tx = second_tx.clone();
+            async move {
+                // A slow callback holds up only its own queue
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                second_tx.send(event).unwrap()
+            }
+        });
+
+        events.emit(KeyEventReason::Expired, ["a", "b"].map(Bytes::from));
+        for received in [&mut first, &mut second] {
+            assert_eq!(received.recv().await, Some(expired("a")));
+            assert_eq!(received.recv().await, Some(expired("b")));
+        }
+        assert_eq!(events.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn streams_changes_under_the_prefix() {
+        let events = KeyEvents::default();
+        let mut changes = events.subscribe(Bytes::from("user:"));
+        let mut everything = events.subscribe(Bytes::new());
+
+        let value = Bytes::from("v");
+        events.changed(ChangeKind::Set, b"user:1", || Some(value.clone()));
+        events.changed(ChangeKind::Set, b"order:1", || Some(value.clone()));
+        events.changed(ChangeKind::Del, b"user:1", || None);
+
+        let set = changes.next().await.unwrap();
+        assert_eq!(
+            (set.kind, set.key, set.value),
+            (ChangeKind::Set, "user:1".into(), Some(value))
+        );
+        let del = changes.next().await.unwrap();
+        assert_eq!((del.kind, del.value), (ChangeKind::Del, None));
+        assert_eq!(changes.try_next(), None);
+        assert_eq!(everything.next().await.unwrap().key, "user:1");
+        assert_eq!(everything.next().await.unwrap().key, "order:1");
+
+        // Dropped subscribers are forgotten on the next change
+        drop(everything);
+        events.changed(ChangeKind::Del, b"order:1", || None);
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn counts_changes_a_subscriber_missed() {
+        let events = KeyEvents::default();
+        let mut changes = events.subscribe(Bytes::new());
+        for _ in 0..QUEUE_DEPTH + 3 {
+            events.changed(ChangeKind::Del, b"k", || None);
+        }
+        assert_eq!(changes.missed(), 3);
+        assert!(changes.try_next().is_some());
+    }
+
+    #[tokio::test]
+    async fn drops_events_a_callback_has_no_room_for() {
+        let events = KeyEvents::default();
+        // Without a callback nothing is queued, so nothing is dropped
+        events.emit(KeyEventReason::Expired, [Bytes::from("a")]);
+        assert_eq!(events.dropped(), 0);
+
+        events.listen(|_| std::future::pending::<()>());
+        let keys = (0..QUEUE_DEPTH + 2).map(|n| Bytes::from(n.to_string()));
+        events.emit(KeyEventReason::Expired, keys);
+        // The callback's task hasn't run yet, so only QUEUE_DEPTH fit
+        assert_eq!(events.dropped(), 2);
+    }
+}
@@ -0,0 +1,155 @@
+//! The little HTTP/1.1 the server speaks: one GET per connection, answered
+//! with JSON, for the health probes and the admin API. Plus JSON string
+//! escaping, shared with the OTLP exporter.
+
+use crate::log::warning;
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Longest request head read before giving up on it
+const MAX_REQUEST: usize = 8 * 1024;
+/// Clients that don't send a request in time are disconnected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A GET request's target
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub path: String,
+    /// What follows the `?`, if anything
+    pub query: String,
+}
+
+impl Request {
+    /// Value of `name` in the query string
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+    }
+}
+
+/// Status code and JSON body
+pub type Response = (u16, String);
+
+pub fn not_found() -> Response {
+    (404, r#"{"error":"not found"}"#.to_string())
+}
+
+/// Answer every connection accepted from `listener` with `handle`, spawning
+/// a task for each on the current runtime
+pub async fn serve<H, F>(listener: TcpListener, handle: H)
+where
+    H: Fn(Request) -> F + Clone + Send + 'static,
+    F: Future<Output = Response> + Send,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // Out of descriptors, most likely; back off rather than spin
+                warning!("Error accepting HTTP connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let _ = answer(stream, handle).await;
+        });
+    }
+}
+
+/// Read one request and answer it, then close
+async fn answer<H, F>(mut stream: TcpStream, handle: H) -> Result<()>
+where
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let mut head = Vec::with_capacity(512);
+    let read = async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+            if stream.read_buf(&mut head).await? == 0 {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read).await??;
+
+    let line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => {
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            handle(Request {
+                path: path.to_string(),
+                query: query.to_string(),
+            })
+            .await
+        }
+        (Some(_), Some(_)) => (405, r#"{"error":"method not allowed"}"#.to_string()),
+        _ => (400, r#"{"error":"bad request"}"#.to_string()),
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Quote and escape `s` as a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_query_parameters() {
+        let request = Request {
+            path: "/info".to_string(),
+            query: "sections=memory,stats&x".to_string(),
+        };
+        assert_eq!(request.param("sections"), Some("memory,stats"));
+        assert_eq!(request.param("section"), None);
+        assert_eq!(request.param("x"), None);
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\u000a""#);
+    }
+}
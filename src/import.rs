@@ -0,0 +1,594 @@
+//! `rudis import`: copy the keyspace of a running Redis (or rudis) into a
+//! rudis server, for cutting over from one to the other.
+//!
+//! The source is walked with SCAN, and each page read with pipelined TYPE,
+//! TTL and GET, then re-SET on the target, with SETEX for keys that expire.
+//! Values are copied rather than DUMPed, as rudis has no RESTORE and holds
+//! nothing but strings; keys of other types are skipped and counted. With
+//! `--follow`, keyspace notifications from the source are subscribed to
+//! before the walk starts, and each key they name is copied again afterwards,
+//! until interrupted, so the target stays roughly in sync during a cutover.
+
+use crate::client::Client;
+use crate::config::parse_memory;
+use crate::ratelimit::ByteThrottle;
+use crate::resp::RespValue;
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt;
+
+const USAGE: &str = "\
+Usage: rudis import --from <host:port> [OPTIONS]
+  --from <host:port>  Server to copy keys from
+  --to <host:port>    Server to copy keys to (default: 127.0.0.1:6379)
+  --db <n>            Database of the source to copy (default: 0)
+  --match <pattern>   Only copy keys matching this glob pattern
+  --count <n>         Keys asked for per SCAN page (default: 1000)
+  --rate <size>       Most bytes of values read from the source a second,
+                      e.g. 20mb, to leave its network to its clients
+                      (default: no limit)
+  --follow            Then keep copying keys as they change, until interrupted;
+                      the source needs notify-keyspace-events to include K
+  --help              Show this help
+";
+
+/// What to import from where
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportOptions {
+    pub from: String,
+    pub to: String,
+    pub db: u32,
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub follow: bool,
+    /// Most bytes of values copied a second
+    pub rate: Option<usize>,
+}
+
+/// Keys copied and skipped by an import
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Imported {
+    pub copied: u64,
+    /// Copied keys that had a TTL, kept to the second
+    pub expiring: u64,
+    /// Keys that disappeared between SCAN and reading them
+    pub vanished: u64,
+    /// Keys left out per type, as rudis only holds strings
+    pub skipped: BTreeMap<String, u64>,
+}
+
+impl fmt::Display for Imported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} keys copied ({} with a TTL), {} gone before they were read",
+            self.copied, self.expiring, self.vanished
+        )?;
+        for (kind, count) in &self.skipped {
+            write!(f, ", {} {} keys skipped", count, kind)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse `rudis import` arguments; None asks for the usage text
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<ImportOptions>> {
+    let mut from = None;
+    let mut options = ImportOptions {
+        from: String::new(),
+        to: "127.0.0.1:6379".to_string(),
+        db: 0,
+        pattern: None,
+        count: 1000,
+        follow: false,
+        rate: None,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("Option '{}' needs a value", arg))
+        };
+        match arg.as_str() {
+            "--help" => return Ok(None),
+            "--follow" => options.follow = true,
+            "--from" => from = Some(value()?),
+            "--to" => options.to = value()?,
+            "--db" => {
+                let db = value()?;
+                options.db = db
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid database '{}'", db))?;
+            }
+            "--match" => options.pattern = Some(value()?),
+            "--count" => {
+                let count = value()?;
+                options.count = count
+                    .parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| anyhow!("Invalid count '{}'", count))?;
+            }
+            "--rate" => options.rate = Some(parse_memory(&value()?)?),
+            _ => bail!("Unknown option '{}'", arg),
+        }
+    }
+    options.from = from.ok_or_else(|| anyhow!("--from is required"))?;
+    Ok(Some(options))
+}
+
+/// Run `rudis import` with its arguments, printing what was copied
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<()> {
+    let Some(options) = parse_args(args)? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let mut source = connect(&options.from, options.db).await?;
+        let mut target = Client::connect(&options.to)
+            .await
+            .map_err(|e| anyhow!("Can't connect to {}: {}", options.to, e))?;
+        // Subscribed first, so changes made during the walk are caught up on
+        let events = match options.follow {
+            true => Some(subscribe(&options).await?),
+            false => None,
+        };
+
+        let imported = import(&mut source, &mut target, &options).await?;
+        println!("{}", imported);
+        if let Some(mut events) = events {
+            println!("Following changes to {}, Ctrl-C to stop", options.from);
+            let throttle = options.rate.map(ByteThrottle::new);
+            tokio::select! {
+                result = follow(&mut events, &mut source, &mut target, throttle.as_ref()) => result?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Ok(())
+    })
+}
+
+pub(crate) async fn connect(addr: &str, db: u32) -> Result<Client> {
+    let mut client = Client::connect(addr)
+        .await
+        .map_err(|e| anyhow!("Can't connect to {}: {}", addr, e))?;
+    if db != 0
+        && let RespValue::Error(e) = client.command(&["SELECT", &db.to_string()]).await?
+    {
+        bail!("Can't select database {} on {}: {}", db, addr, e);
+    }
+    Ok(client)
+}
+
+/// Copy every key `options` asks for from `source` to `target`
+pub async fn import(
+    source: &mut Client,
+    target: &mut Client,
+    options: &ImportOptions,
+) -> Result<Imported> {
+    let mut imported = Imported::default();
+    let throttle = options.rate.map(ByteThrottle::new);
+    let mut cursor = "0".to_string();
+    loop {
+        let keys;
+        (cursor, keys) = scan(source, &cursor, options.pattern.as_deref(), options.count).await?;
+        copy(source, target, &keys, throttle.as_ref(), &mut imported).await?;
+        if cursor == "0" {
+            return Ok(imported);
+        }
+    }
+}
+
+/// One page of a SCAN walk from `cursor`: the next cursor, "0" at the end,
+/// and the keys on the page
+pub(crate) async fn scan(
+    source: &mut Client,
+    cursor: &str,
+    pattern: Option<&str>,
+    count: usize,
+) -> Result<(String, Vec<Bytes>)> {
+    let mut scan = vec!["SCAN".to_string(), cursor.to_string()];
+    if let Some(pattern) = pattern {
+        scan.extend(["MATCH".to_string(), pattern.to_string()]);
+    }
+    scan.extend(["COUNT".to_string(), count.to_string()]);
+    let (next, keys) = match source.command(&scan).await? {
+        RespValue::Array(Some(reply)) => match <[RespValue; 2]>::try_from(reply) {
+            Ok(
+                [
+                    RespValue::BulkString(Some(next)),
+                    RespValue::Array(Some(keys)),
+                ],
+            ) => (next, keys),
+            Ok(reply) => bail!("Unexpected SCAN reply {:?}", reply),
+            Err(reply) => bail!("Unexpected SCAN reply {:?}", reply),
+        },
+        RespValue::Error(e) => bail!("SCAN failed: {}", e),
+        reply => bail!("Unexpected SCAN reply {:?}", reply),
+    };
+    let keys = keys
+        .into_iter()
+        .filter_map(|key| match key {
+            RespValue::BulkString(Some(key)) => Some(key),
+            _ => None,
+        })
+        .collect();
+    Ok((String::from_utf8_lossy(&next).into_owned(), keys))
+}
+
+/// How a key looked on the source
+#[derive(Debug, PartialEq)]
+pub(crate) enum Source {
+    /// A string, with its TTL in seconds if it expires
+    String(Bytes, Option<u64>),
+    /// Gone since it was named
+    Missing,
+    /// A type rudis can't hold
+    Other(String),
+}
+
+/// Read `keys` from the source in one pipeline, then write them to the
+/// target in another. With a `throttle`, the next page isn't read until
+/// the bytes of this one's values are allowed.
+async fn copy(
+    source: &mut Client,
+    target: &mut Client,
+    keys: &[Bytes],
+    throttle: Option<&ByteThrottle>,
+    imported: &mut Imported,
+) -> Result<()> {
+    let found = fetch(source, keys).await?;
+    if let Some(throttle) = throttle {
+        throttle.take(found.iter().map(Source::len).sum()).await;
+    }
+
+    let mut writes = 0;
+    for (key, found) in keys.iter().zip(found) {
+        match found {
+            Source::String(value, ttl) => {
+                write(target, key, Some((value, ttl))).await?;
+                writes += 1;
+                imported.copied += 1;
+                if ttl.is_some() {
+                    imported.expiring += 1;
+                }
+            }
+            Source::Missing => imported.vanished += 1,
+            Source::Other(kind) => *imported.skipped.entry(kind).or_default() += 1,
+        }
+    }
+    for _ in 0..writes {
+        if let RespValue::Error(e) = target.reply().await? {
+            bail!("Target refused a key: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Read `keys` from the source in one pipeline of TYPE, TTL and GET
+pub(crate) async fn fetch(source: &mut Client, keys: &[Bytes]) -> Result<Vec<Source>> {
+    for key in keys {
+        source.send(&[&b"TYPE"[..], key]).await?;
+        source.send(&[&b"TTL"[..], key]).await?;
+        source.send(&[&b"GET"[..], key]).await?;
+    }
+    let mut found = Vec::with_capacity(keys.len());
+    for _ in keys {
+        let (kind, ttl, value) = (
+            source.reply().await?,
+            source.reply().await?,
+            source.reply().await?,
+        );
+        found.push(read(kind, ttl, value)?);
+    }
+    Ok(found)
+}
+
+impl Source {
+    /// Bytes of value read
+    fn len(&self) -> usize {
+        match self {
+            Source::String(value, _) => value.len(),
+            Source::Missing | Source::Other(_) => 0,
+        }
+    }
+}
+
+/// Make sense of the TYPE, TTL and GET replies for one key
+fn read(kind: RespValue, ttl: RespValue, value: RespValue) -> Result<Source> {
+    let kind = match kind {
+        RespValue::SimpleString(kind) => kind,
+        RespValue::Error(e) => bail!("TYPE failed: {}", e),
+        reply => bail!("Unexpected TYPE reply {:?}", reply),
+    };
+    match (kind.as_str(), value) {
+        ("none", _) | ("string", RespValue::BulkString(None)) => Ok(Source::Missing),
+        ("string", RespValue::BulkString(Some(value))) => {
+            let ttl = match ttl {
+                // Under a second left reads as 0; keep it for the last second
+                RespValue::Integer(seconds) if seconds >= 0 => Some(seconds.max(1) as u64),
+                // -1: no expiry; -2: expired since TYPE
+                RespValue::Integer(-2) => return Ok(Source::Missing),
+                _ => None,
+            };
+            Ok(Source::String(value, ttl))
+        }
+        ("string", reply) => bail!("Unexpected GET reply {:?}", reply),
+        (kind, _) => Ok(Source::Other(kind.to_string())),
+    }
+}
+
+/// Send the write for one key without waiting for the reply: SET or SETEX
+/// when it has a value, DEL when it doesn't
+async fn write(target: &mut Client, key: &[u8], value: Option<(Bytes, Option<u64>)>) -> Result<()> {
+    match value {
+        Some((value, None)) => target.send(&[&b"SET"[..], key, &value]).await,
+        Some((value, Some(ttl))) => {
+            let ttl = ttl.to_string();
+            target
+                .send(&[&b"SETEX"[..], key, ttl.as_bytes(), &value])
+                .await
+        }
+        None => target.send(&[&b"DEL"[..], key]).await,
+    }
+}
+
+/// A connection subscribed to the source's keyspace notifications
+async fn subscribe(options: &ImportOptions) -> Result<Client> {
+    let mut events = Client::connect(&options.from).await?;
+    // Without K in notify-keyspace-events nothing would ever arrive
+    if let RespValue::Array(Some(reply)) = events
+        .command(&["CONFIG", "GET", "notify-keyspace-events"])
+        .await?
+        && let Some(RespValue::BulkString(Some(flags))) = reply.get(1)
+        && !flags.contains(&b'K')
+    {
+        bail!(
+            "{} doesn't publish keyspace notifications; run CONFIG SET notify-keyspace-events KA on it first",
+            options.from
+        );
+    }
+    let channel = format!(
+        "__keyspace@{}__:{}",
+        options.db,
+        options.pattern.as_deref().unwrap_or("*")
+    );
+    match events.command(&["PSUBSCRIBE", &channel]).await? {
+        RespValue::Error(e) => bail!("Can't subscribe to keyspace notifications: {}", e),
+        _ => Ok(events),
+    }
+}
+
+/// Copy each key named by a keyspace notification again, as it is now:
+/// whatever the event, the key is re-read, and deleted from the target if
+/// it no longer exists. Runs until the notifications stop.
+async fn follow(
+    events: &mut Client,
+    source: &mut Client,
+    target: &mut Client,
+    throttle: Option<&ByteThrottle>,
+) -> Result<()> {
+    loop {
+        let message = events.reply().await?;
+        // ["pmessage", pattern, "__keyspace@<db>__:<key>", event]
+        let RespValue::Array(Some(parts)) = message else {
+            continue;
+        };
+        let Some(RespValue::BulkString(Some(channel))) = parts.get(2) else {
+            continue;
+        };
+        let Some(colon) = channel.iter().position(|&b| b == b':') else {
+            continue;
+        };
+        let key = channel.slice(colon + 1..);
+
+        let Some(found) = fetch(source, std::slice::from_ref(&key)).await?.pop() else {
+            continue;
+        };
+        if let Some(throttle) = throttle {
+            throttle.take(found.len()).await;
+        }
+        match found {
+            Source::String(value, ttl) => write(target, &key, Some((value, ttl))).await?,
+            Source::Missing => write(target, &key, None).await?,
+            Source::Other(_) => continue,
+        }
+        if let RespValue::Error(e) = target.reply().await? {
+            bail!("Target refused a key: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::store::Store;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    async fn serve() -> (String, Store) {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let store = server.store().clone();
+        tokio::spawn(async move { server.run().await });
+        (addr, store)
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse_args(args(&[
+            "--from",
+            "10.0.0.5:6379",
+            "--db",
+            "2",
+            "--match",
+            "user:*",
+            "--follow",
+            "--rate",
+            "20mb",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.from, "10.0.0.5:6379");
+        assert_eq!(options.to, "127.0.0.1:6379");
+        assert_eq!(options.db, 2);
+        assert_eq!(options.pattern.as_deref(), Some("user:*"));
+        assert!(options.follow);
+        assert_eq!(options.rate, Some(20 * 1024 * 1024));
+
+        assert_eq!(parse_args(args(&["--help"])).unwrap(), None);
+        for bad in [
+            &[][..],
+            &["--from"],
+            &["--from", "a:1", "--count", "0"],
+            &["--from", "a:1", "--db", "x"],
+            &["--from", "a:1", "--rate", "0"],
+            &["--from", "a:1", "--bogus"],
+        ] {
+            assert!(parse_args(args(bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn reads_source_replies() {
+        let bulk =
+            |value: &str| RespValue::BulkString(Some(Bytes::copy_from_slice(value.as_bytes())));
+        let kind = |kind: &str| RespValue::SimpleString(kind.to_string());
+        assert_eq!(
+            read(kind("string"), RespValue::Integer(-1), bulk("v")).unwrap(),
+            Source::String(Bytes::from("v"), None)
+        );
+        assert_eq!(
+            read(kind("string"), RespValue::Integer(0), bulk("v")).unwrap(),
+            Source::String(Bytes::from("v"), Some(1))
+        );
+        assert_eq!(
+            read(kind("string"), RespValue::Integer(-2), bulk("v")).unwrap(),
+            Source::Missing
+        );
+        assert_eq!(
+            read(
+                kind("none"),
+                RespValue::Integer(-2),
+                RespValue::BulkString(None)
+            )
+            .unwrap(),
+            Source::Missing
+        );
+        let wrongtype = RespValue::Error("WRONGTYPE".to_string());
+        assert_eq!(
+            read(kind("hash"), RespValue::Integer(-1), wrongtype).unwrap(),
+            Source::Other("hash".to_string())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn imports_every_key() {
+        let (from, source_store) = serve().await;
+        let (to, target_store) = serve().await;
+        for i in 0..2500 {
+            source_store
+                .set(format!("key:{}", i).into(), format!("{}", i).into())
+                .await;
+        }
+        source_store
+            .set_ex("session".into(), "abc".into(), 300)
+            .await;
+        target_store.set("key:7".into(), "stale".into()).await;
+
+        let options = parse_args(args(&["--from", &from, "--to", &to]))
+            .unwrap()
+            .unwrap();
+        let mut source = connect(&options.from, 0).await.unwrap();
+        let mut target = Client::connect(&options.to).await.unwrap();
+        let imported = import(&mut source, &mut target, &options).await.unwrap();
+        assert_eq!(imported.copied, 2501);
+        assert_eq!(imported.expiring, 1);
+        assert_eq!(target_store.dbsize().await, 2501);
+        assert_eq!(target_store.get(b"key:7").await, Some(Bytes::from("7")));
+        // TTL rounds down on both sides
+        assert!((298..=300).contains(&target_store.ttl(b"session").await));
+
+        let options = ImportOptions {
+            pattern: Some("key:1?".to_string()),
+            ..options
+        };
+        let imported = import(&mut source, &mut target, &options).await.unwrap();
+        assert_eq!(imported.copied, 10);
+
+        // 4000 bytes of values at 2000 a second: the first second's worth
+        // goes at once, the rest a second later
+        let options = ImportOptions {
+            pattern: Some("key:1???".to_string()),
+            rate: Some(2000),
+            ..options
+        };
+        let started = std::time::Instant::now();
+        let imported = import(&mut source, &mut target, &options).await.unwrap();
+        assert_eq!(imported.copied, 1000);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn follows_keyspace_notifications() {
+        let (from, source_store) = serve().await;
+        let (to, target_store) = serve().await;
+        source_store.set("changed".into(), "new".into()).await;
+        target_store.set("changed".into(), "old".into()).await;
+        target_store.set("deleted".into(), "old".into()).await;
+
+        // rudis publishes no notifications, so a stand-in sends two
+        let notifier = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let notifier_addr = notifier.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = notifier.accept().await.unwrap();
+            let mut request = [0; 256];
+            let _ = stream.read(&mut request).await.unwrap();
+            let mut messages = RespValue::Array(Some(
+                ["psubscribe", "__keyspace@0__:*"]
+                    .iter()
+                    .map(|s| RespValue::BulkString(Some(Bytes::copy_from_slice(s.as_bytes()))))
+                    .chain([RespValue::Integer(1)])
+                    .collect(),
+            ))
+            .serialize();
+            for (key, event) in [("changed", "set"), ("deleted", "del")] {
+                let message = RespValue::Array(Some(
+                    [
+                        "pmessage",
+                        "__keyspace@0__:*",
+                        &format!("__keyspace@0__:{}", key),
+                        event,
+                    ]
+                    .iter()
+                    .map(|s| RespValue::BulkString(Some(Bytes::copy_from_slice(s.as_bytes()))))
+                    .collect(),
+                ));
+                messages.extend(message.serialize());
+            }
+            stream.write_all(&messages).await.unwrap();
+        });
+
+        let mut events = Client::connect(notifier_addr).await.unwrap();
+        events
+            .command(&["PSUBSCRIBE", "__keyspace@0__:*"])
+            .await
+            .unwrap();
+        let mut source = Client::connect(&from).await.unwrap();
+        let mut target = Client::connect(&to).await.unwrap();
+        // Ends with an error once the stand-in hangs up
+        assert!(
+            follow(&mut events, &mut source, &mut target, None)
+                .await
+                .is_err()
+        );
+        assert_eq!(target_store.get(b"changed").await, Some(Bytes::from("new")));
+        assert_eq!(target_store.get(b"deleted").await, None);
+    }
+}
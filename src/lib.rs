@@ -0,0 +1,88 @@
+//! Rudis, a Redis clone, as a library: run the server inside another Rust
+//! service, or drive a `Store` directly.
+//!
+//! The types re-exported here are the supported API. The modules behind them
+//! are public so their helper types can be named, but may change more freely.
+//!
+//! ```no_run
+//! use rudis::{Server, Store};
+//!
+//! # async fn embed() -> anyhow::Result<()> {
+//! let store = Store::new();
+//! store.set("greeting".into(), "hi".into()).await;
+//! let server = Server::builder().port(0).store(store).bind().await?;
+//! let addr = server.local_addr()?;
+//! tokio::spawn(async move { server.run().await });
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod acl;
+mod admin;
+pub mod backup;
+pub mod blocking;
+mod bufpool;
+mod chaos;
+pub mod client;
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod crdt;
+pub mod diff;
+pub mod encryption;
+pub mod error;
+pub mod events;
+mod http;
+pub mod import;
+mod log;
+mod lolwut;
+mod otlp;
+mod overload;
+mod probe;
+mod ratelimit;
+pub mod record;
+pub mod resp;
+pub mod restore;
+pub mod seed;
+pub mod server;
+pub mod snapshot;
+mod stats;
+pub mod store;
+mod systemd;
+pub mod tenant;
+pub mod tls;
+mod upstream;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod watchdog;
+
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use command::Command;
+pub use config::Config;
+pub use events::{Change, ChangeKind, Changes, KeyEvent, KeyEventReason};
+pub use resp::RespValue;
+pub use server::{IoBackend, Server, ServerConfig};
+pub use snapshot::Snapshot;
+pub use store::{KeyspaceBackend, Store};
+
+use anyhow::Result;
+
+#[cfg(all(feature = "console", not(tokio_unstable)))]
+compile_error!("the console feature needs RUSTFLAGS=\"--cfg tokio_unstable\"");
+
+/// Run a server with `config` until it shuts down, the way the `rudis`
+/// binary does: set up logging, then serve on the configured I/O backend
+pub fn run(config: Config) -> Result<()> {
+    log::init(&config)?;
+    // Serve tokio-console, on 127.0.0.1:6669 unless TOKIO_CONSOLE_BIND says otherwise
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+    match config.io_backend {
+        IoBackend::Tokio => tokio::runtime::Runtime::new()?.block_on(async {
+            let server = Server::new(config).await?;
+            server.run().await
+        }),
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        IoBackend::IoUring => uring::run(config),
+    }
+}
@@ -0,0 +1,289 @@
+//! Server log. Lines go to stdout/stderr unless a `logfile` is configured, in
+//! which case they are appended to it, rotating by size and/or age. SIGUSR1
+//! reopens the file, so external tools like logrotate can move it away.
+
+use crate::config::Config;
+use anyhow::{Result, anyhow};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Severity of a log line, shown with Redis' markers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Notice,
+    Warning,
+}
+
+impl Level {
+    fn marker(self) -> char {
+        match self {
+            Level::Notice => '*',
+            Level::Warning => '#',
+        }
+    }
+}
+
+/// Log a notice, formatted like `println!`
+macro_rules! notice {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Notice, format_args!($($arg)*))
+    };
+}
+
+/// Log a warning, formatted like `println!`
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warning, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {notice, warning};
+
+/// When the log file is moved aside and a fresh one started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    /// Rotate once the file reaches this many bytes
+    pub max_size: Option<u64>,
+    /// Rotate once the file has been written to for this long
+    pub interval: Option<Duration>,
+    /// Rotated files kept as `logfile.1` (newest) to `logfile.N`
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            interval: None,
+            keep: 5,
+        }
+    }
+}
+
+/// The configured log file; None logs to stdout/stderr
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Send the log to the configured `logfile`, if any
+pub fn init(config: &Config) -> Result<()> {
+    if let Some(path) = &config.logfile {
+        let file = LogFile::open(path, config.log_rotation)
+            .map_err(|e| anyhow!("Can't open the log file '{}': {}", path.display(), e))?;
+        *LOG_FILE.lock().unwrap() = Some(file);
+    }
+    Ok(())
+}
+
+/// Write one line to the log
+pub fn write(level: Level, message: std::fmt::Arguments<'_>) {
+    let line = format_line(level, SystemTime::now(), message);
+    let mut log_file = LOG_FILE.lock().unwrap();
+    match log_file.as_mut() {
+        Some(file) => {
+            if let Err(e) = file.write_line(&line) {
+                eprint!("{}", line);
+                eprintln!("Failed writing to the log file: {}", e);
+            }
+        }
+        None if level == Level::Warning => eprint!("{}", line),
+        None => print!("{}", line),
+    }
+}
+
+/// Reopen the log file at its configured path, picking up a new file if the
+/// old one was moved away
+pub fn reopen() -> std::io::Result<()> {
+    match LOG_FILE.lock().unwrap().as_mut() {
+        Some(file) => file.reopen(),
+        None => Ok(()),
+    }
+}
+
+/// Reopen the log file whenever the process gets SIGUSR1
+pub fn spawn_reopen_on_signal() -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            let Ok(mut usr1) = signal(SignalKind::user_defined1()) else {
+                warning!("Can't listen for SIGUSR1, log reopening disabled");
+                return;
+            };
+            while usr1.recv().await.is_some() {
+                match reopen() {
+                    Ok(()) => notice!("Received SIGUSR1, log file reopened"),
+                    Err(e) => warning!("Failed to reopen the log file: {}", e),
+                }
+            }
+        }
+    })
+}
+
+/// An open log file and its rotation state
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the current file
+    size: u64,
+    opened_at: Instant,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Rotation) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            opened_at: Instant::now(),
+            rotation,
+        })
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        *self = Self::open(&self.path, self.rotation)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.rotation_due() {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotation_due(&self) -> bool {
+        let too_big = self.rotation.max_size.is_some_and(|max| self.size >= max);
+        let too_old = self
+            .rotation
+            .interval
+            .is_some_and(|interval| self.opened_at.elapsed() >= interval);
+        self.size > 0 && (too_big || too_old)
+    }
+
+    /// Shift `path.N-1` to `path.N` down to `path` to `path.1`, dropping the
+    /// oldest, then start a fresh file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.rotation.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                match std::fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        }
+        self.reopen()
+    }
+}
+
+/// Format a line the way Redis does, `pid:M 18 Oct 2026 09:30:00.123 * message`.
+/// Times are UTC, as we have no time zone database.
+fn format_line(level: Level, now: SystemTime, message: std::fmt::Arguments<'_>) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let seconds_of_day = secs % 86_400;
+    format!(
+        "{}:M {:02} {} {} {:02}:{:02}:{:02}.{:03} {} {}\n",
+        std::process::id(),
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis(),
+        level.marker(),
+        message
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date in the
+/// proleptic Gregorian calendar (Howard Hinnant's algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_lines_like_redis() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let line = format_line(Level::Warning, at, format_args!("disk {}", "full"));
+        let expected = format!(
+            "{}:M 14 Nov 2023 22:13:20.123 # disk full\n",
+            std::process::id()
+        );
+        assert_eq!(line, expected);
+
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn rotates_by_size_keeping_n_files() {
+        let dir = std::env::temp_dir().join(format!("rudis-log-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rudis.log");
+
+        let rotation = Rotation {
+            max_size: Some(10),
+            interval: None,
+            keep: 2,
+        };
+        let mut log = LogFile::open(&path, rotation).unwrap();
+        for line in ["first line\n", "second line\n", "third line\n", "fourth\n"] {
+            log.write_line(line).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("rudis.log"), "fourth\n");
+        assert_eq!(read("rudis.log.1"), "third line\n");
+        assert_eq!(read("rudis.log.2"), "second line\n");
+        assert!(!dir.join("rudis.log.3").exists());
+
+        // Reopening after an external move starts a new file
+        std::fs::rename(&path, dir.join("moved.log")).unwrap();
+        log.reopen().unwrap();
+        log.write_line("after reopen\n").unwrap();
+        assert_eq!(read("rudis.log"), "after reopen\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
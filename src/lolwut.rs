@@ -0,0 +1,171 @@
+/// LOLWUT art generators, selected by version like in Redis.
+///
+/// Version 5 draws Georg Nees' "Schotter": a grid of squares that get more
+/// displaced and rotated the further down the canvas they are.
+const DEFAULT_COLS: usize = 66;
+const DEFAULT_SQUARES_PER_ROW: usize = 8;
+const DEFAULT_SQUARES_PER_COL: usize = 12;
+
+/// Render the LOLWUT output for the requested version and parameters
+pub fn render(version: Option<i64>, params: &[i64]) -> String {
+    let rudis_version = env!("CARGO_PKG_VERSION");
+    match version {
+        Some(5) | None => {
+            let cols = clamp_param(params.first(), DEFAULT_COLS, 1, 1000);
+            let per_row = clamp_param(params.get(1), DEFAULT_SQUARES_PER_ROW, 1, 200);
+            let per_col = clamp_param(params.get(2), DEFAULT_SQUARES_PER_COL, 1, 200);
+            let mut out = schotter(cols, per_row, per_col);
+            out.push_str(&format!(
+                "\nGeorg Nees - schotter, plotter on paper, 1968. Rudis ver. {}\n",
+                rudis_version
+            ));
+            out
+        }
+        Some(_) => format!("Rudis ver. {}\n", rudis_version),
+    }
+}
+
+fn clamp_param(value: Option<&i64>, default: usize, min: usize, max: usize) -> usize {
+    value
+        .map(|&v| (v.max(0) as usize).clamp(min, max))
+        .unwrap_or(default)
+}
+
+/// Character canvas the squares are plotted onto
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![false; width * height],
+        }
+    }
+
+    fn set(&mut self, x: i64, y: i64) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            self.pixels[y as usize * self.width + x as usize] = true;
+        }
+    }
+
+    /// Bresenham line between two points
+    fn line(&mut self, (mut x0, mut y0): (i64, i64), (x1, y1): (i64, i64)) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        loop {
+            self.set(x0, y0);
+            if x0 == x1 && y0 == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            out.extend(row.iter().map(|&p| if p { '#' } else { ' ' }));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Small deterministic PRNG so the art is stable across calls
+struct Lcg(u64);
+
+impl Lcg {
+    fn next_unit(&mut self) -> f64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform value in [-1, 1)
+    fn next_signed(&mut self) -> f64 {
+        self.next_unit() * 2.0 - 1.0
+    }
+}
+
+fn schotter(cols: usize, per_row: usize, per_col: usize) -> String {
+    let side = (cols / per_row).max(2);
+    // Terminal cells are roughly twice as tall as wide
+    let rows = (side * per_col).div_ceil(2) + 1;
+    let mut canvas = Canvas::new(cols, rows);
+    let mut rng = Lcg(0x5eed);
+
+    for row in 0..per_col {
+        let disorder = row as f64 / per_col as f64;
+        for col in 0..per_row {
+            let half = side as f64 / 2.0;
+            let cx = col as f64 * side as f64 + half + rng.next_signed() * disorder * half;
+            let cy = row as f64 * side as f64 + half + rng.next_signed() * disorder * half;
+            let angle = rng.next_signed() * disorder * std::f64::consts::FRAC_PI_4;
+            let (sin, cos) = angle.sin_cos();
+
+            let corners: Vec<(i64, i64)> = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+                .iter()
+                .map(|(dx, dy)| {
+                    let (dx, dy) = (dx * (half - 0.5), dy * (half - 0.5));
+                    let x = cx + dx * cos - dy * sin;
+                    let y = cy + dx * sin + dy * cos;
+                    (x.round() as i64, (y / 2.0).round() as i64)
+                })
+                .collect();
+            for i in 0..4 {
+                canvas.line(corners[i], corners[(i + 1) % 4]);
+            }
+        }
+    }
+
+    canvas.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_version_draws_schotter() {
+        let out = render(None, &[]);
+        assert!(out.contains("Georg Nees - schotter"));
+        assert!(out.contains('#'));
+        let width = out.lines().next().unwrap().len();
+        assert_eq!(width, DEFAULT_COLS);
+    }
+
+    #[test]
+    fn output_is_deterministic() {
+        assert_eq!(render(Some(5), &[40, 4, 4]), render(Some(5), &[40, 4, 4]));
+    }
+
+    #[test]
+    fn custom_columns_are_respected() {
+        let out = render(Some(5), &[30, 3, 2]);
+        assert_eq!(out.lines().next().unwrap().len(), 30);
+    }
+
+    #[test]
+    fn unknown_version_prints_only_version() {
+        let out = render(Some(1), &[]);
+        assert_eq!(out, format!("Rudis ver. {}\n", env!("CARGO_PKG_VERSION")));
+    }
+}
@@ -1,14 +1,17 @@
-mod command;
-mod resp;
-mod server;
-mod store;
-
 use anyhow::Result;
-use server::Server;
+use rudis::Config;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let server = Server::new().await?;
-    server.run().await?;
-    Ok(())
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().is_some_and(|arg| arg == "import") {
+        return rudis::import::run(args.skip(1));
+    }
+    if args.peek().is_some_and(|arg| arg == "restore") {
+        return rudis::restore::run(args.skip(1));
+    }
+    if args.peek().is_some_and(|arg| arg == "diff") {
+        return rudis::diff::run(args.skip(1));
+    }
+    let config = Config::from_args(args)?;
+    rudis::run(config)
 }
@@ -0,0 +1,390 @@
+//! OpenTelemetry trace export. When `otlp-endpoint` is set, every executed
+//! command becomes a SERVER span, batched and posted to an OTLP/HTTP collector
+//! as JSON. Export never blocks clients: spans are dropped when the queue is
+//! full or the collector is unreachable.
+
+use crate::http::json_string;
+use crate::log::warning;
+use crate::server::RequestId;
+use anyhow::{Result, anyhow};
+use std::fmt::Write as _;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Spans buffered between clients and the exporter before new ones are dropped
+const SPAN_QUEUE_DEPTH: usize = 8192;
+/// Most spans sent in one export request
+const MAX_BATCH: usize = 512;
+/// How long a span may wait before a partial batch is sent
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Give up on a collector that doesn't answer in time
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Path OTLP/HTTP collectors receive traces on
+const DEFAULT_TRACES_PATH: &str = "/v1/traces";
+
+/// A finished command execution
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    /// Command name, lowercase
+    pub command: &'static str,
+    pub client: IpAddr,
+    /// The connection and the command's number on it, as in the log
+    pub request: RequestId,
+    pub key_count: usize,
+    pub start: SystemTime,
+    pub duration: Duration,
+    /// Whether the command replied with an error
+    pub failed: bool,
+}
+
+impl Span {
+    /// A span for a command, with fresh random trace and span IDs. Clients
+    /// can't pass trace context over RESP, so each command is its own trace.
+    pub fn new(
+        command: &'static str,
+        client: IpAddr,
+        request: RequestId,
+        key_count: usize,
+        start: SystemTime,
+        duration: Duration,
+        failed: bool,
+    ) -> Self {
+        let [a, b, c] = [random_id(), random_id(), random_id()];
+        let mut trace_id = [0; 16];
+        trace_id[..8].copy_from_slice(&a.to_be_bytes());
+        trace_id[8..].copy_from_slice(&b.to_be_bytes());
+        Self {
+            trace_id,
+            span_id: c.to_be_bytes(),
+            command,
+            client,
+            request,
+            key_count,
+            start,
+            duration,
+            failed,
+        }
+    }
+}
+
+/// A non-zero 64-bit ID, unpredictable across processes
+fn random_id() -> u64 {
+    static SEED: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    SEED.hash_one(COUNTER.fetch_add(1, Ordering::Relaxed))
+        .max(1)
+}
+
+/// Where and as what spans are exported
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    /// `host:port` of the collector
+    pub authority: String,
+    pub path: String,
+}
+
+impl Endpoint {
+    /// Parse `http://host:port[/path]`; the path defaults to `/v1/traces`
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            anyhow!(
+                "OTLP endpoint '{}' must start with http:// (TLS is not supported)",
+                url
+            )
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) if slash + 1 < rest.len() => (&rest[..slash], &rest[slash..]),
+            Some(slash) => (&rest[..slash], DEFAULT_TRACES_PATH),
+            None => (rest, DEFAULT_TRACES_PATH),
+        };
+        if authority.is_empty() {
+            return Err(anyhow!("OTLP endpoint '{}' has no host", url));
+        }
+        let authority = if authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+        {
+            authority.to_string()
+        } else {
+            // The standard OTLP/HTTP port
+            format!("{}:4318", authority)
+        };
+        Ok(Self {
+            authority,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Handle for recording spans, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    spans: mpsc::Sender<Span>,
+}
+
+impl Tracer {
+    /// Start exporting to `endpoint` in a background task
+    pub fn spawn(endpoint: Endpoint, service_name: String) -> Self {
+        let (spans, queue) = mpsc::channel(SPAN_QUEUE_DEPTH);
+        tokio::spawn(export_spans(queue, endpoint, service_name));
+        Self { spans }
+    }
+
+    /// Queue a span for export, dropping it if the exporter is behind
+    pub fn record(&self, span: Span) {
+        let _ = self.spans.try_send(span);
+    }
+}
+
+/// Send spans in batches until every `Tracer` is gone. A batch goes out
+/// when full, on each flush tick, or when the last tracer is dropped.
+async fn export_spans(mut queue: mpsc::Receiver<Span>, endpoint: Endpoint, service_name: String) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    // Only the first failure of a streak is logged
+    let mut failing = false;
+
+    loop {
+        let room = MAX_BATCH - batch.len();
+        let closed = tokio::select! {
+            received = queue.recv_many(&mut batch, room) => {
+                if received > 0 && batch.len() < MAX_BATCH {
+                    continue;
+                }
+                received == 0
+            }
+            _ = flush.tick() => false,
+        };
+
+        if !batch.is_empty() {
+            let body = encode_spans(&batch, &service_name);
+            batch.clear();
+            match tokio::time::timeout(EXPORT_TIMEOUT, post(&endpoint, &body)).await {
+                Ok(Ok(())) => failing = false,
+                Ok(Err(e)) if !failing => {
+                    warning!("OTLP export to {} failed: {}", endpoint.authority, e);
+                    failing = true;
+                }
+                Err(_) if !failing => {
+                    warning!("OTLP export to {} timed out", endpoint.authority);
+                    failing = true;
+                }
+                _ => {}
+            }
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+/// POST a JSON body to the collector, failing on anything but a 2xx reply
+async fn post(endpoint: &Endpoint, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(&endpoint.authority).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.authority,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status_line = response.split(|&b| b == b'\r').next().unwrap_or_default();
+    let status = String::from_utf8_lossy(status_line);
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(anyhow!("collector replied '{}'", status)),
+    }
+}
+
+/// Encode spans as an OTLP/JSON `ExportTraceServiceRequest`
+fn encode_spans(spans: &[Span], service_name: &str) -> String {
+    let mut json = String::new();
+    json.push_str(r#"{"resourceSpans":[{"resource":{"attributes":["#);
+    push_attribute(&mut json, "service.name", &json_string(service_name));
+    json.push_str(r#"]},"scopeSpans":[{"scope":{"name":"rudis","version":"#);
+    json.push_str(&json_string(env!("CARGO_PKG_VERSION")));
+    json.push_str(r#"},"spans":["#);
+
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let start = span.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let end = start + span.duration;
+        let _ = write!(
+            json,
+            r#"{{"traceId":"{}","spanId":"{}","name":"{}","kind":2,"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+            hex(&span.trace_id),
+            hex(&span.span_id),
+            span.command.to_uppercase(),
+            start.as_nanos(),
+            end.as_nanos()
+        );
+        push_attribute(&mut json, "db.system.name", r#""redis""#);
+        json.push(',');
+        push_attribute(&mut json, "db.operation.name", &json_string(span.command));
+        json.push(',');
+        push_attribute(
+            &mut json,
+            "client.address",
+            &json_string(&span.client.to_string()),
+        );
+        json.push(',');
+        push_int_attribute(&mut json, "rudis.client.id", span.request.client);
+        json.push(',');
+        push_int_attribute(&mut json, "rudis.request.id", span.request.command);
+        json.push(',');
+        push_int_attribute(&mut json, "db.operation.key_count", span.key_count as u64);
+        // Status codes: 0 unset, 2 error
+        let _ = write!(
+            json,
+            r#"],"status":{{"code":{}}}}}"#,
+            if span.failed { 2 } else { 0 }
+        );
+    }
+    json.push_str("]}]}]}");
+    json
+}
+
+/// Append a string-valued attribute; `value` must already be a JSON string
+fn push_attribute(json: &mut String, key: &str, value: &str) {
+    let _ = write!(
+        json,
+        r#"{{"key":"{}","value":{{"stringValue":{}}}}}"#,
+        key, value
+    );
+}
+
+fn push_int_attribute(json: &mut String, key: &str, value: u64) {
+    let _ = write!(
+        json,
+        r#"{{"key":"{}","value":{{"intValue":"{}"}}}}"#,
+        key, value
+    );
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{:02x}", b);
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn sample_span() -> Span {
+        Span {
+            trace_id: [0xab; 16],
+            span_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            command: "mget",
+            client: "10.0.0.7".parse().unwrap(),
+            request: RequestId {
+                client: 12,
+                command: 345,
+            },
+            key_count: 3,
+            start: UNIX_EPOCH + Duration::from_secs(2),
+            duration: Duration::from_micros(15),
+            failed: false,
+        }
+    }
+
+    #[test]
+    fn parse_endpoints() {
+        let endpoint = Endpoint::parse("http://collector:4318").unwrap();
+        assert_eq!(endpoint.authority, "collector:4318");
+        assert_eq!(endpoint.path, "/v1/traces");
+        let endpoint = Endpoint::parse("http://127.0.0.1:9000/otlp/traces").unwrap();
+        assert_eq!(endpoint.authority, "127.0.0.1:9000");
+        assert_eq!(endpoint.path, "/otlp/traces");
+        assert_eq!(
+            Endpoint::parse("http://collector/").unwrap().authority,
+            "collector:4318"
+        );
+        assert!(Endpoint::parse("https://collector:4318").is_err());
+        assert!(Endpoint::parse("http://").is_err());
+    }
+
+    #[test]
+    fn encode_otlp_json() {
+        let json = encode_spans(&[sample_span()], "cache-\"eu\"");
+        assert!(json.starts_with(r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"cache-\"eu\""}}]}"#));
+        assert!(json.contains(&format!(r#""traceId":"{}""#, "ab".repeat(16))));
+        assert!(json.contains(r#""spanId":"0102030405060708","name":"MGET","kind":2"#));
+        assert!(
+            json.contains(r#""startTimeUnixNano":"2000000000","endTimeUnixNano":"2000015000""#)
+        );
+        assert!(json.contains(r#"{"key":"client.address","value":{"stringValue":"10.0.0.7"}}"#));
+        assert!(json.contains(r#"{"key":"rudis.client.id","value":{"intValue":"12"}}"#));
+        assert!(json.contains(r#"{"key":"rudis.request.id","value":{"intValue":"345"}}"#));
+        assert!(json.contains(r#"{"key":"db.operation.key_count","value":{"intValue":"3"}}"#));
+        assert!(json.ends_with(r#""status":{"code":0}}]}]}]}"#));
+    }
+
+    #[test]
+    fn span_ids_are_unique() {
+        let a = Span::new(
+            "get",
+            "::1".parse().unwrap(),
+            RequestId::default(),
+            1,
+            SystemTime::now(),
+            Duration::ZERO,
+            false,
+        );
+        let b = Span::new(
+            "get",
+            "::1".parse().unwrap(),
+            RequestId::default(),
+            1,
+            SystemTime::now(),
+            Duration::ZERO,
+            false,
+        );
+        assert_ne!(a.trace_id, b.trace_id);
+        assert_ne!(a.span_id, b.span_id);
+    }
+
+    #[tokio::test]
+    async fn export_to_collector() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", collector.local_addr().unwrap());
+        let tracer = Tracer::spawn(Endpoint::parse(&url).unwrap(), "rudis".to_string());
+        tracer.record(sample_span());
+        drop(tracer);
+
+        let (mut socket, _) = collector.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !request.ends_with(b"]}]}]}") {
+            let n = socket.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed early");
+            request.extend_from_slice(&buf[..n]);
+        }
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert!(request.contains(r#""name":"MGET""#));
+    }
+}
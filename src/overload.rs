@@ -0,0 +1,195 @@
+//! Load shedding. The server counts as overloaded once the commands finished
+//! in the last sample interval have a p99 latency past `shed-latency`, or
+//! once more tasks than `shed-queue-depth` wait in the runtime's global
+//! queue, and stays so until a sample finds it back under both. Rather than
+//! let latency collapse for every client, it then refuses the work that can
+//! wait: reads that aren't O(1), like KEYS and SCAN, fail with `-BUSY`, and
+//! new connections are sent the same error and closed. Writes, O(1) reads
+//! and commands like INFO always run.
+
+use crate::command::CommandFlags;
+use crate::log::{notice, warning};
+use crate::stats::{LatencyHistogram, percentile_of};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+/// How often the load is judged
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Fewest commands an interval needs for its p99 to count, so a few slow
+/// commands on an otherwise idle server don't trip shedding
+const MIN_SAMPLES: u64 = 100;
+
+/// Overload state and thresholds, fed the latency of every command
+#[derive(Debug, Default)]
+pub struct LoadShedder {
+    /// p99 latency past which to shed, in microseconds; 0 turns it off
+    latency_limit: AtomicU64,
+    /// Global queue depth past which to shed; 0 turns it off
+    queue_limit: AtomicUsize,
+    latency: LatencyHistogram,
+    /// `latency`'s counts when it was last sampled
+    sampled: Mutex<Vec<u64>>,
+    overloaded: AtomicBool,
+    shed_commands: AtomicU64,
+    shed_connections: AtomicU64,
+}
+
+impl LoadShedder {
+    /// Shed past a p99 latency of `latency` or a global queue depth of
+    /// `queue_depth`; zero turns either off
+    pub fn set_limits(&self, latency: Duration, queue_depth: usize) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.latency_limit.store(micros, Ordering::Relaxed);
+        self.queue_limit.store(queue_depth, Ordering::Relaxed);
+    }
+
+    fn enabled(&self) -> bool {
+        self.latency_limit.load(Ordering::Relaxed) > 0
+            || self.queue_limit.load(Ordering::Relaxed) > 0
+    }
+
+    /// Count a command that took `elapsed`
+    pub fn record(&self, elapsed: Duration) {
+        if self.latency_limit.load(Ordering::Relaxed) > 0 {
+            self.latency.record(elapsed);
+        }
+    }
+
+    /// Whether the latest sample found the server overloaded
+    pub fn overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    /// Whether to refuse a command with `flags`, counting it if so
+    pub fn shed_command(&self, flags: CommandFlags) -> bool {
+        let low_priority =
+            flags.contains(CommandFlags::READONLY) && !flags.contains(CommandFlags::FAST);
+        if !low_priority || !self.overloaded() {
+            return false;
+        }
+        self.shed_commands.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Whether to refuse a new connection, counting it if so
+    pub fn shed_connection(&self) -> bool {
+        if !self.overloaded() {
+            return false;
+        }
+        self.shed_connections.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Judge the interval since the last sample, with `queue_depth` tasks
+    /// in the global queue now, and return whether the server is overloaded
+    fn sample(&self, queue_depth: usize) -> bool {
+        let counts = self.latency.counts();
+        let mut sampled = self.sampled.lock().unwrap();
+        let interval: Vec<u64> = counts
+            .iter()
+            .zip(sampled.iter().chain(std::iter::repeat(&0)))
+            .map(|(now, before)| now - before)
+            .collect();
+        *sampled = counts;
+        drop(sampled);
+
+        let p99 = if interval.iter().sum::<u64>() >= MIN_SAMPLES {
+            percentile_of(&interval, 99.0)
+        } else {
+            Duration::ZERO
+        };
+        let latency_limit = self.latency_limit.load(Ordering::Relaxed);
+        let queue_limit = self.queue_limit.load(Ordering::Relaxed);
+        let overloaded = (latency_limit > 0 && p99.as_micros() > latency_limit as u128)
+            || (queue_limit > 0 && queue_depth > queue_limit);
+
+        let was_overloaded = self.overloaded.swap(overloaded, Ordering::Relaxed);
+        if overloaded && !was_overloaded {
+            warning!(
+                "Overloaded (p99 latency {:?}, global queue depth {}), shedding load",
+                p99,
+                queue_depth
+            );
+        } else if !overloaded && was_overloaded {
+            notice!("No longer overloaded, stopped shedding load");
+        }
+        overloaded
+    }
+
+    /// Start the background task judging the load of the runtime it runs on
+    pub fn start(shedder: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if shedder.enabled() {
+                    shedder.sample(Handle::current().metrics().global_queue_depth());
+                }
+            }
+        })
+    }
+
+    /// INFO stats fields
+    pub fn info(&self) -> String {
+        format!(
+            "load_shedding:{}\r\nshed_commands:{}\r\nshed_connections:{}\r\n",
+            u8::from(self.overloaded()),
+            self.shed_commands.load(Ordering::Relaxed),
+            self.shed_connections.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCAN: CommandFlags = CommandFlags::READONLY;
+    const GET: CommandFlags = CommandFlags::READONLY.union(CommandFlags::FAST);
+
+    #[test]
+    fn sheds_slow_reads_while_p99_latency_is_high() {
+        let shedder = LoadShedder::default();
+        shedder.set_limits(Duration::from_millis(5), 0);
+        // Too few commands to judge by
+        for _ in 0..MIN_SAMPLES - 1 {
+            shedder.record(Duration::from_millis(50));
+        }
+        assert!(!shedder.sample(0));
+
+        for _ in 0..MIN_SAMPLES {
+            shedder.record(Duration::from_millis(50));
+        }
+        assert!(shedder.sample(0));
+        assert!(shedder.shed_command(SCAN));
+        assert!(!shedder.shed_command(GET));
+        assert!(!shedder.shed_command(CommandFlags::WRITE));
+        assert!(shedder.shed_connection());
+        assert_eq!(
+            shedder.info(),
+            "load_shedding:1\r\nshed_commands:1\r\nshed_connections:1\r\n"
+        );
+
+        // Each sample only judges the commands finished since the last
+        for _ in 0..MIN_SAMPLES {
+            shedder.record(Duration::from_micros(100));
+        }
+        assert!(!shedder.sample(0));
+        assert!(!shedder.shed_command(SCAN));
+        assert!(!shedder.shed_connection());
+    }
+
+    #[test]
+    fn sheds_while_the_global_queue_is_deep() {
+        let shedder = LoadShedder::default();
+        assert!(!shedder.sample(1000), "no limits, no shedding");
+        shedder.set_limits(Duration::ZERO, 64);
+        assert!(!shedder.sample(64));
+        assert!(shedder.sample(65));
+        assert!(!shedder.sample(3));
+    }
+}
@@ -0,0 +1,186 @@
+//! HTTP health probes for orchestrators such as Kubernetes. With `probe-port`
+//! set, a thread running a runtime of its own answers `GET /healthz`
+//! (liveness) and `GET /readyz` (readiness), so probes are answered promptly
+//! even while the workers serving clients are saturated.
+
+use crate::http::{self, Request, Response};
+use crate::log::{notice, warning};
+use crate::server::Context;
+use crate::watchdog;
+use anyhow::{Result, anyhow};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// The probe listener, stopped when dropped
+#[derive(Debug)]
+pub struct Probe {
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Start answering probes about the server behind `context`, if
+/// `probe-port` is set
+pub fn spawn(context: &Context) -> Result<Option<Probe>> {
+    let Some(addr) = context.config.probe_addr() else {
+        return Ok(None);
+    };
+    let listener = std::net::TcpListener::bind(&addr)
+        .map_err(|e| anyhow!("Can't bind probe listener on {}: {}", addr, e))?;
+    listener.set_nonblocking(true)?;
+    notice!(
+        "Answering health probes on http://{}",
+        listener.local_addr()?
+    );
+
+    let (stop, stopped) = oneshot::channel();
+    let context = context.clone();
+    std::thread::Builder::new()
+        .name("rudis-probe".to_string())
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    warning!("Can't start the probe runtime: {}", e);
+                    return;
+                }
+            };
+            runtime.block_on(async move {
+                let listener = match TcpListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        warning!("Can't serve health probes: {}", e);
+                        return;
+                    }
+                };
+                let started = Instant::now();
+                let handle = move |request: Request| {
+                    let response = respond(&request.path, &context, started.elapsed());
+                    async move { response }
+                };
+                tokio::select! {
+                    _ = http::serve(listener, handle) => {}
+                    _ = stopped => {}
+                }
+            });
+        })?;
+    Ok(Some(Probe { stop: Some(stop) }))
+}
+
+/// Status code and JSON body for a GET of `path`. Liveness holds as long as
+/// this thread answers; readiness also needs the server to be accepting
+/// clients and not shutting down.
+fn respond(path: &str, context: &Context, uptime: Duration) -> Response {
+    let state = if *context.shutdown.borrow() {
+        "stopping"
+    } else if context.ready() {
+        "ready"
+    } else {
+        "starting"
+    };
+    let status = match path {
+        "/healthz" | "/livez" => 200,
+        "/readyz" if state == "ready" => 200,
+        "/readyz" => 503,
+        _ => return http::not_found(),
+    };
+    // Nothing is persisted, so there is never a dataset to load
+    let body = format!(
+        "{{\"status\":\"{}\",\"loading\":false,\"connected_clients\":{},\
+         \"uptime_seconds\":{},\"stalled_workers\":{}}}",
+        state,
+        context.connected_clients(),
+        uptime.as_secs(),
+        watchdog::stalled()
+    );
+    (status, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::Server;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn get(addr: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_the_server() {
+        let context = Context::new(Config::default());
+        let (status, body) = respond("/readyz", &context, Duration::from_secs(3));
+        assert_eq!(status, 503);
+        assert!(body.starts_with("{\"status\":\"starting\",\"loading\":false,"));
+        assert!(body.contains("\"uptime_seconds\":3,"));
+        assert_eq!(respond("/healthz", &context, Duration::ZERO).0, 200);
+
+        context.set_ready(true);
+        assert_eq!(respond("/readyz", &context, Duration::ZERO).0, 200);
+        context.shutdown.send_replace(true);
+        let (status, body) = respond("/readyz", &context, Duration::ZERO);
+        assert_eq!(status, 503);
+        assert!(body.contains("\"status\":\"stopping\""));
+        assert_eq!(respond("/healthz", &context, Duration::ZERO).0, 200);
+        assert_eq!(respond("/metrics", &context, Duration::ZERO).0, 404);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn answers_over_http() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = Server::builder()
+            .port(0)
+            .config(|config| config.probe_port = port)
+            .bind()
+            .await
+            .unwrap();
+        tokio::spawn(async move { server.run().await });
+        let addr = format!("127.0.0.1:{}", port);
+
+        // The probe starts with the server, which may not be ready yet
+        let mut response = String::new();
+        for _ in 0..100 {
+            if let Ok(mut stream) = TcpStream::connect(&addr).await {
+                stream
+                    .write_all(b"GET /readyz HTTP/1.1\r\n\r\n")
+                    .await
+                    .unwrap();
+                response.clear();
+                stream.read_to_string(&mut response).await.unwrap();
+                if response.starts_with("HTTP/1.1 200 OK\r\n") {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\"status\":\"ready\""), "{}", response);
+
+        let response = get(&addr, "GET /healthz HTTP/1.1\r\nHost: rudis\r\n\r\n").await;
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        let response = get(&addr, "POST /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 "), "{}", response);
+        let response = get(&addr, "GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+    }
+}
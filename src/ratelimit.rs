@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets kept before idle, fully refilled ones are pruned
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// What to do with a command once its client has run out of tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitMode {
+    /// Hold the command until the bucket refills
+    Delay,
+    /// Fail the command with an error
+    Reject,
+}
+
+/// Outcome of checking a command against the limiter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allow,
+    Delay(Duration),
+    Reject,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token-bucket command rate limiter keyed by client IP address, shared by
+/// every connection so opening more sockets doesn't buy more throughput.
+/// Tenants key theirs by nothing, sharing one bucket.
+#[derive(Debug)]
+pub struct RateLimiter<K = IpAddr> {
+    ops_per_sec: f64,
+    burst: f64,
+    mode: RateLimitMode,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(ops_per_sec: u32, burst: u32, mode: RateLimitMode) -> Self {
+        Self {
+            ops_per_sec: ops_per_sec as f64,
+            burst: burst.max(1) as f64,
+            mode,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for one command from `client`
+    pub fn check(&self, client: K) -> Decision {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: K, now: Instant) -> Decision {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            self.prune(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(client).or_insert(TokenBucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.ops_per_sec).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Decision::Allow;
+        }
+        match self.mode {
+            RateLimitMode::Reject => Decision::Reject,
+            RateLimitMode::Delay => {
+                // Borrow the token now; the debt is repaid by the wait
+                let wait = (1.0 - bucket.tokens) / self.ops_per_sec;
+                bucket.tokens -= 1.0;
+                Decision::Delay(Duration::from_secs_f64(wait))
+            }
+        }
+    }
+
+    /// Drop buckets that would be full by now, they carry no state
+    fn prune(&self, buckets: &mut HashMap<K, TokenBucket>, now: Instant) {
+        buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.last_refill);
+            bucket.tokens + elapsed.as_secs_f64() * self.ops_per_sec < self.burst
+        });
+    }
+}
+
+/// Byte-rate limit on a stream sent to another server, such as an import
+/// or writes behind to upstream, so that copying a large dataset leaves
+/// the network to client traffic. Up to a second's worth may go at once;
+/// after that, the sender waits for the bucket to refill.
+#[derive(Debug)]
+pub struct ByteThrottle {
+    bytes_per_sec: f64,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl ByteThrottle {
+    pub fn new(bytes_per_sec: usize) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1) as f64,
+            bucket: Mutex::new(TokenBucket {
+                tokens: bytes_per_sec.max(1) as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` more may be sent
+    pub async fn take(&self, bytes: usize) {
+        let wait = self.reserve_at(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before
+    /// sending them. A chunk larger than the bucket is let through in
+    /// debt, which the waits after it pay off.
+    fn reserve_at(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.bytes_per_sec).min(self.bytes_per_sec);
+        bucket.last_refill = now;
+        let wait = (bytes as f64 - bucket.tokens).max(0.0) / self.bytes_per_sec;
+        bucket.tokens -= bytes as f64;
+        Duration::from_secs_f64(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn allows_burst_then_rejects() {
+        let limiter = RateLimiter::new(10, 3, RateLimitMode::Reject);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip(1), now), Decision::Allow);
+        }
+        assert_eq!(limiter.check_at(ip(1), now), Decision::Reject);
+
+        // Other clients have their own bucket
+        assert_eq!(limiter.check_at(ip(2), now), Decision::Allow);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(10, 1, RateLimitMode::Reject);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(ip(1), now), Decision::Allow);
+        assert_eq!(limiter.check_at(ip(1), now), Decision::Reject);
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.check_at(ip(1), later), Decision::Allow);
+    }
+
+    #[test]
+    fn delay_mode_spaces_out_commands() {
+        let limiter = RateLimiter::new(10, 1, RateLimitMode::Delay);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(ip(1), now), Decision::Allow);
+        let Decision::Delay(first) = limiter.check_at(ip(1), now) else {
+            panic!("expected a delay");
+        };
+        let Decision::Delay(second) = limiter.check_at(ip(1), now) else {
+            panic!("expected a delay");
+        };
+        assert!((first.as_secs_f64() - 0.1).abs() < 1e-9);
+        assert!((second.as_secs_f64() - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn prune_drops_full_buckets() {
+        let limiter = RateLimiter::new(10, 1, RateLimitMode::Reject);
+        let now = Instant::now();
+        limiter.check_at(ip(1), now);
+        let mut buckets = limiter.buckets.lock().unwrap();
+        limiter.prune(&mut buckets, now + Duration::from_secs(1));
+        assert!(buckets.is_empty());
+    }
+
+    #[test]
+    fn throttle_spaces_out_bytes() {
+        let throttle = ByteThrottle::new(1000);
+        let now = Instant::now();
+        // A second's worth goes at once
+        assert_eq!(throttle.reserve_at(600, now), Duration::ZERO);
+        assert_eq!(throttle.reserve_at(400, now), Duration::ZERO);
+        assert_eq!(throttle.reserve_at(500, now), Duration::from_millis(500));
+        // Later sends wait behind the debt
+        assert_eq!(throttle.reserve_at(500, now), Duration::from_secs(1));
+        let later = now + Duration::from_secs(3);
+        assert_eq!(throttle.reserve_at(1000, later), Duration::ZERO);
+        assert_eq!(throttle.reserve_at(2000, later), Duration::from_secs(2));
+    }
+}
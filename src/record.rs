@@ -0,0 +1,331 @@
+//! Recording of the inbound command stream, for reproducing incidents and
+//! regression benchmarking with `rudis-benchmark --replay`.
+//!
+//! A recording is a stream of RESP values, so the protocol parser reads it
+//! back. It opens with `["rudis-record", version, start]`, the start in
+//! milliseconds since the Unix epoch, followed by one `[at, client, command]`
+//! array per command: `at` in microseconds since the start, `client` the
+//! connection's id and `command` the frame exactly as parsed.
+//!
+//! Keys that expire are recorded too, as a `DEL` from client 0, the server
+//! itself, so a restore deletes them at the point they expired rather than
+//! restarting their TTLs and keeping them around.
+//!
+//! With `encryption-key` set, the stream is sealed as it is written, a
+//! segment per batch of entries; see `encryption`.
+
+use crate::encryption::{self, EncryptionKey, Sealer};
+use crate::log::warning;
+use crate::resp::{ParseLimits, RespValue};
+use anyhow::{Result, anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &str = "rudis-record";
+const VERSION: i64 = 1;
+
+/// Client id of the commands the server records itself
+pub const SERVER_CLIENT: u64 = 0;
+
+/// One recorded command
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// When the command arrived, from the start of the recording
+    pub at: Duration,
+    /// Id of the connection that sent it, unique while the server runs
+    pub client: u64,
+    pub command: RespValue,
+}
+
+impl Entry {
+    fn to_resp(&self) -> RespValue {
+        RespValue::Array(Some(vec![
+            RespValue::Integer(self.at.as_micros().min(i64::MAX as u128) as i64),
+            RespValue::Integer(self.client as i64),
+            self.command.clone(),
+        ]))
+    }
+
+    fn from_resp(value: RespValue) -> Result<Self> {
+        let RespValue::Array(Some(parts)) = value else {
+            bail!("Expected a recorded command");
+        };
+        match <[RespValue; 3]>::try_from(parts) {
+            Ok([RespValue::Integer(at), RespValue::Integer(client), command]) => Ok(Self {
+                at: Duration::from_micros(at.max(0) as u64),
+                client: client as u64,
+                command,
+            }),
+            _ => bail!("Expected a recorded command"),
+        }
+    }
+}
+
+/// A recording read back from a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    /// Wall-clock time recording started
+    pub started: SystemTime,
+    /// Commands in the order they arrived
+    pub entries: Vec<Entry>,
+}
+
+impl Recording {
+    /// Read a recording written by `record-file`. A command cut short by the
+    /// server stopping mid-write is ignored.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_with(path, None)
+    }
+
+    /// Read a recording as `load` does, decrypting it with `key` if it was
+    /// written encrypted
+    pub fn load_with(path: &Path, key: Option<&EncryptionKey>) -> Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+        if encryption::is_encrypted(&data) {
+            let key = key
+                .ok_or_else(|| anyhow!("'{}' is encrypted; set encryption-key", path.display()))?;
+            data = encryption::open(key, &data, true)
+                .map_err(|e| anyhow!("Can't decrypt '{}': {}", path.display(), e))?;
+        }
+        Self::parse(BytesMut::from(&data[..]))
+            .map_err(|e| anyhow!("'{}' is not a recording: {}", path.display(), e))
+    }
+
+    fn parse(mut data: BytesMut) -> Result<Self> {
+        // Recorded frames are as large as the server accepted, and nest once more
+        let limits = ParseLimits {
+            max_depth: ParseLimits::default().max_depth + 1,
+            ..ParseLimits::default()
+        };
+        let mut next = || RespValue::parse_with_limits(&mut data, &limits);
+
+        let header = match next()? {
+            Some((RespValue::Array(Some(header)), _)) => header,
+            _ => bail!("missing header"),
+        };
+        let started = match header.as_slice() {
+            [
+                RespValue::SimpleString(magic),
+                RespValue::Integer(VERSION),
+                RespValue::Integer(start),
+            ] if magic == MAGIC => UNIX_EPOCH + Duration::from_millis(*start as u64),
+            [
+                RespValue::SimpleString(magic),
+                RespValue::Integer(version),
+                ..,
+            ] if magic == MAGIC => {
+                bail!("unsupported version {}", version)
+            }
+            _ => bail!("missing header"),
+        };
+
+        let mut entries = Vec::new();
+        while let Some((value, _)) = next()? {
+            entries.push(Entry::from_resp(value)?);
+        }
+        Ok(Self { started, entries })
+    }
+
+    /// Write the recording to a new file at `path`, replacing any old one
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.save_with(path, None)
+    }
+
+    /// Write the recording as `save` does, encrypted with `key` if given
+    pub fn save_with(&self, path: &Path, key: Option<&EncryptionKey>) -> Result<()> {
+        let started = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut out = BytesMut::new();
+        header(started).serialize_into(&mut out);
+        for entry in &self.entries {
+            entry.to_resp().serialize_into(&mut out);
+        }
+        let out = match key {
+            Some(key) => encryption::encrypt(key, &out),
+            None => out.to_vec(),
+        };
+        std::fs::write(path, out).map_err(|e| anyhow!("Can't write '{}': {}", path.display(), e))
+    }
+}
+
+/// First value of a recording started `started` after the Unix epoch
+fn header(started: Duration) -> RespValue {
+    RespValue::Array(Some(vec![
+        RespValue::SimpleString(MAGIC.to_string()),
+        RespValue::Integer(VERSION),
+        RespValue::Integer(started.as_millis() as i64),
+    ]))
+}
+
+/// Appends every command a server receives to a file. Connections hand
+/// encoded entries to a writer thread, so a slow disk doesn't hold them up.
+#[derive(Debug)]
+pub struct Recorder {
+    start: Instant,
+    entries: mpsc::Sender<Bytes>,
+}
+
+impl Recorder {
+    /// Start a recording in a new file at `path`, replacing any old one,
+    /// encrypted with `key` if given
+    pub fn create(path: &Path, key: Option<&EncryptionKey>) -> Result<Self> {
+        let file =
+            File::create(path).map_err(|e| anyhow!("Can't create '{}': {}", path.display(), e))?;
+        let mut out = BufWriter::new(file);
+        let mut sealer = key.map(Sealer::new);
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let header = header(started).serialize();
+        match &mut sealer {
+            Some(sealer) => {
+                out.write_all(&sealer.header())?;
+                out.write_all(&sealer.seal(&header))?;
+            }
+            None => out.write_all(&header)?,
+        }
+        out.flush()?;
+
+        let (entries, received) = mpsc::channel::<Bytes>();
+        let path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("rudis-recorder".to_string())
+            .spawn(move || {
+                // Write whatever has queued up, flushing whenever the queue
+                // runs dry, until every connection and the server are gone
+                while let Ok(entry) = received.recv() {
+                    let mut batch = std::iter::once(entry).chain(received.try_iter());
+                    let written = match &mut sealer {
+                        // Sealed a batch at a time, so each flush ends a segment
+                        Some(sealer) => {
+                            let batch = batch.collect::<Vec<_>>().concat();
+                            out.write_all(&sealer.seal(&batch))
+                        }
+                        None => batch.try_for_each(|entry| out.write_all(&entry)),
+                    }
+                    .and_then(|_| out.flush());
+                    if let Err(e) = written {
+                        warning!("Stopped recording to '{}': {}", path.display(), e);
+                        return;
+                    }
+                }
+            })?;
+        Ok(Self {
+            start: Instant::now(),
+            entries,
+        })
+    }
+
+    /// Append a command received from `client`
+    pub fn record(&self, client: u64, command: &RespValue) {
+        let entry = Entry {
+            at: self.start.elapsed(),
+            client,
+            command: command.clone(),
+        };
+        let mut out = BytesMut::new();
+        entry.to_resp().serialize_into(&mut out);
+        // The writer only stops after a write error it has already logged
+        let _ = self.entries.send(out.freeze());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> RespValue {
+        RespValue::Array(Some(
+            args.iter()
+                .map(|arg| RespValue::BulkString(Some(Bytes::copy_from_slice(arg.as_bytes()))))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn records_and_loads() {
+        let path = std::env::temp_dir().join(format!("rudis-record-{}.resp", std::process::id()));
+        let recorder = Recorder::create(&path, None).unwrap();
+        recorder.record(1, &command(&["SET", "k", "v"]));
+        recorder.record(2, &command(&["GET", "k"]));
+        recorder.record(1, &command(&["INCR", "n"]));
+        // Dropping the recorder lets the writer finish
+        drop(recorder);
+        let mut recording = Recording::load(&path);
+        for _ in 0..100 {
+            if recording.as_ref().is_ok_and(|r| r.entries.len() == 3) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            recording = Recording::load(&path);
+        }
+        let recording = recording.unwrap();
+
+        let clients: Vec<u64> = recording.entries.iter().map(|e| e.client).collect();
+        assert_eq!(clients, [1, 2, 1]);
+        assert_eq!(recording.entries[1].command, command(&["GET", "k"]));
+        assert!(recording.entries[0].at <= recording.entries[2].at);
+        assert!(recording.started <= SystemTime::now());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn records_and_loads_encrypted() {
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let path = std::env::temp_dir().join(format!("rudis-record-{}.enc", std::process::id()));
+        let recorder = Recorder::create(&path, Some(&key)).unwrap();
+        recorder.record(1, &command(&["SET", "secret", "v"]));
+        recorder.record(2, &command(&["GET", "secret"]));
+        drop(recorder);
+        let mut recording = Recording::load_with(&path, Some(&key));
+        for _ in 0..100 {
+            if recording.as_ref().is_ok_and(|r| r.entries.len() == 2) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            recording = Recording::load_with(&path, Some(&key));
+        }
+        let recording = recording.unwrap();
+        assert_eq!(recording.entries[1].command, command(&["GET", "secret"]));
+        let data = std::fs::read(&path).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+        assert!(Recording::load(&path).is_err());
+        let other = EncryptionKey::from_hex(&"cd".repeat(32)).unwrap();
+        assert!(Recording::load_with(&path, Some(&other)).is_err());
+
+        // Saved recordings round trip too, and plain ones still load with a key
+        recording.save_with(&path, Some(&key)).unwrap();
+        assert_eq!(Recording::load_with(&path, Some(&key)).unwrap(), recording);
+        recording.save(&path).unwrap();
+        assert_eq!(Recording::load_with(&path, Some(&key)).unwrap(), recording);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Recording::parse(BytesMut::from("+OK\r\n")).is_err());
+        assert!(Recording::parse(BytesMut::from("*3\r\n+rudis-record\r\n:9\r\n:0\r\n")).is_err());
+
+        // A truncated last entry is dropped
+        let mut data = BytesMut::from("*3\r\n+rudis-record\r\n:1\r\n:0\r\n");
+        data.extend_from_slice(
+            &Entry {
+                at: Duration::from_micros(5),
+                client: 3,
+                command: command(&["PING"]),
+            }
+            .to_resp()
+            .serialize(),
+        );
+        data.extend_from_slice(b"*3\r\n:9\r\n");
+        let recording = Recording::parse(data).unwrap();
+        assert_eq!(recording.entries.len(), 1);
+        assert_eq!(recording.entries[0].at, Duration::from_micros(5));
+    }
+}
@@ -1,203 +1,390 @@
-use anyhow::{Result, anyhow};
-use bytes::{Buf, BytesMut};
+use crate::error::CommandError;
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
 
 /// Maximum length for an inline command line (64KB, matching Redis)
 const MAX_INLINE_SIZE: usize = 64 * 1024;
 
+/// Upper bounds on attacker-controlled lengths in incoming frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseLimits {
+    /// Largest accepted bulk string (proto-max-bulk-len, 512MB like Redis)
+    pub max_bulk_len: usize,
+    /// Largest accepted array element count
+    pub max_array_len: usize,
+    /// Deepest accepted array nesting; requests are a single flat array
+    pub max_depth: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 8,
+        }
+    }
+}
+
 /// RESP (REdis Serialization Protocol) data types
 #[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(Option<Vec<u8>>),   // None represents null bulk string
+    BulkString(Option<Bytes>),     // None represents null bulk string
     Array(Option<Vec<RespValue>>), // None represents null array
 }
 
 impl RespValue {
     /// Serialize RESP value to bytes
     pub fn serialize(&self) -> Vec<u8> {
+        let mut out = BytesMut::new();
+        self.serialize_into(&mut out);
+        out.into()
+    }
+
+    /// Append the serialized value to `out`, e.g. a connection's output
+    /// buffer, without allocating anything per value
+    pub fn serialize_into(&self, out: &mut BytesMut) {
         match self {
-            RespValue::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
-            RespValue::Error(e) => format!("-{}\r\n", e).into_bytes(),
-            RespValue::Integer(i) => format!(":{}\r\n", i).into_bytes(),
-            RespValue::BulkString(None) => b"$-1\r\n".to_vec(),
+            RespValue::SimpleString(s) => put_line(out, b'+', s),
+            RespValue::Error(e) => put_line(out, b'-', e),
+            RespValue::Integer(i) => put_line(out, b':', i),
+            RespValue::BulkString(None) => out.extend_from_slice(b"$-1\r\n"),
             RespValue::BulkString(Some(bytes)) => {
-                let mut result = format!("${}\r\n", bytes.len()).into_bytes();
-                result.extend_from_slice(bytes);
-                result.extend_from_slice(b"\r\n");
-                result
+                put_line(out, b'$', bytes.len());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
             }
-            RespValue::Array(None) => b"*-1\r\n".to_vec(),
+            RespValue::Array(None) => out.extend_from_slice(b"*-1\r\n"),
             RespValue::Array(Some(values)) => {
-                let mut result = format!("*{}\r\n", values.len()).into_bytes();
+                put_line(out, b'*', values.len());
                 for value in values {
-                    result.extend_from_slice(&value.serialize());
+                    value.serialize_into(out);
                 }
-                result
             }
         }
     }
 
-    /// Attempt to parse a RESP value from a buffer
+    /// Attempt to parse a RESP value from the front of a buffer
     /// Returns Ok(Some(value, bytes_consumed)) if successful
     /// Returns Ok(None) if more data is needed
     /// Returns Err if the data is invalid
     ///
+    /// A successfully parsed frame is split off the front of `buffer`, and its
+    /// bulk strings are reference-counted views into it rather than copies.
+    ///
     /// This also handles inline commands (plain text commands like "PING\r\n")
     /// which are converted to RESP arrays for uniform command processing.
     pub fn parse(buffer: &mut BytesMut) -> Result<Option<(RespValue, usize)>> {
-        if buffer.is_empty() {
-            return Ok(None);
-        }
+        Self::parse_with_limits(buffer, &ParseLimits::default())
+    }
 
-        match buffer[0] {
-            b'+' => parse_simple_string(buffer),
-            b'-' => parse_error(buffer),
-            b':' => parse_integer(buffer),
-            b'$' => parse_bulk_string(buffer),
-            b'*' => parse_array(buffer),
-            // Any other byte indicates an inline command
-            _ => parse_inline_command(buffer),
-        }
+    /// Like `parse`, rejecting frames whose declared sizes exceed `limits`
+    /// with a protocol error before anything is allocated for them
+    pub fn parse_with_limits(
+        buffer: &mut BytesMut,
+        limits: &ParseLimits,
+    ) -> Result<Option<(RespValue, usize)>> {
+        let Some(end) = frame_end(buffer, 0, limits, 0)? else {
+            return Ok(None);
+        };
+        let frame = buffer.split_to(end).freeze();
+        let (value, _) = build_value(&frame, 0)?;
+        Ok(Some((value, end)))
     }
 }
 
-fn find_crlf(buffer: &[u8]) -> Option<usize> {
-    buffer.windows(2).position(|w| w == b"\r\n")
+/// Bulk strings at least this long are written from the value itself rather
+/// than copied into the reply buffer
+const VECTORED_MIN_LEN: usize = 1024;
+
+/// Arrays with at least this many elements are serialized as they are
+/// written rather than all at once when pushed
+const STREAMED_MIN_LEN: usize = 1024;
+
+/// Most bytes of a streamed array serialized for each `ReplyBuffer::take`
+const STREAM_CHUNK: usize = 64 * 1024;
+
+/// Replies waiting to be written. Small replies are serialized into one
+/// contiguous buffer, while large bulk strings become separate chunks sharing
+/// the stored value, so an MGET of big values goes out in a vectored write
+/// without being copied. Long arrays, such as KEYS over a large keyspace, are
+/// kept as values and serialized a batch at a time as they are taken, so a
+/// reply is never held twice over, as values and serialized.
+#[derive(Debug, Default)]
+pub struct ReplyBuffer {
+    chunks: Vec<Bytes>,
+    pending: BytesMut,
+    /// Bytes in `chunks`
+    len: usize,
+    /// Replies from the first long array on, still to serialize
+    queued: VecDeque<Queued>,
 }
 
-/// Parse an inline command (plain text like "PING\r\n" or "SET foo bar\r\n")
-/// Converts it to a RESP array for uniform command processing
-fn parse_inline_command(buffer: &mut BytesMut) -> Result<Option<(RespValue, usize)>> {
-    if let Some(pos) = find_crlf(buffer) {
-        // Reject oversized inline commands
-        if pos > MAX_INLINE_SIZE {
-            return Err(anyhow!("ERR Protocol error: too big inline request"));
+/// A reply held back until the ones before it are taken
+#[derive(Debug)]
+enum Queued {
+    Value(RespValue),
+    /// The elements left of a long array whose header has been serialized
+    Elements(std::vec::IntoIter<RespValue>),
+}
+
+impl ReplyBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a reply
+    pub fn push(&mut self, value: RespValue) {
+        let long =
+            matches!(&value, RespValue::Array(Some(values)) if values.len() >= STREAMED_MIN_LEN);
+        if long || !self.queued.is_empty() {
+            self.queued.push_back(Queued::Value(value));
+        } else {
+            self.put(&value);
         }
+    }
 
-        let line = &buffer[..pos];
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.pending.is_empty() && self.queued.is_empty()
+    }
 
-        // Split the line by whitespace to get command and arguments
-        let parts: Vec<&[u8]> = line
-            .split(|&b| b == b' ' || b == b'\t')
-            .filter(|part| !part.is_empty())
-            .collect();
+    /// Take the next buffered replies as chunks to write in order. Long
+    /// arrays come out about `STREAM_CHUNK` bytes per call, so call until
+    /// the buffer is empty.
+    pub fn take(&mut self) -> Vec<Bytes> {
+        while self.len + self.pending.len() < STREAM_CHUNK
+            && let Some(queued) = self.queued.pop_front()
+        {
+            match queued {
+                Queued::Elements(mut values) => {
+                    if let Some(value) = values.next() {
+                        self.put(&value);
+                        self.queued.push_front(Queued::Elements(values));
+                    }
+                }
+                Queued::Value(RespValue::Array(Some(values)))
+                    if values.len() >= STREAMED_MIN_LEN =>
+                {
+                    put_line(&mut self.pending, b'*', values.len());
+                    self.queued.push_front(Queued::Elements(values.into_iter()));
+                }
+                Queued::Value(value) => self.put(&value),
+            }
+        }
+        self.seal();
+        self.len = 0;
+        std::mem::take(&mut self.chunks)
+    }
+
+    /// Drop every buffered reply
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
 
-        if parts.is_empty() {
-            // Empty command, consume the line and return empty array
-            return Ok(Some((RespValue::Array(Some(Vec::new())), pos + 2)));
+    /// Serialize a reply onto the end of the chunks
+    fn put(&mut self, value: &RespValue) {
+        match value {
+            RespValue::BulkString(Some(bytes)) if bytes.len() >= VECTORED_MIN_LEN => {
+                put_line(&mut self.pending, b'$', bytes.len());
+                self.seal();
+                self.len += bytes.len();
+                self.chunks.push(bytes.clone());
+                self.pending.extend_from_slice(b"\r\n");
+            }
+            RespValue::Array(Some(values)) => {
+                put_line(&mut self.pending, b'*', values.len());
+                for value in values {
+                    self.put(value);
+                }
+            }
+            other => other.serialize_into(&mut self.pending),
         }
+    }
 
-        // Convert each part to a bulk string
-        let elements: Vec<RespValue> = parts
-            .into_iter()
-            .map(|part| RespValue::BulkString(Some(part.to_vec())))
-            .collect();
-
-        let consumed = pos + 2; // line + \r\n
-        Ok(Some((RespValue::Array(Some(elements)), consumed)))
-    } else {
-        // No CRLF found - check if buffer is getting too large (potential slowloris)
-        if buffer.len() > MAX_INLINE_SIZE {
-            return Err(anyhow!("ERR Protocol error: too big inline request"));
+    /// End the current contiguous chunk
+    fn seal(&mut self) {
+        if !self.pending.is_empty() {
+            self.len += self.pending.len();
+            self.chunks.push(self.pending.split().freeze());
         }
-        Ok(None) // Need more data
     }
 }
 
-fn parse_simple_string(buffer: &mut BytesMut) -> Result<Option<(RespValue, usize)>> {
-    if let Some(pos) = find_crlf(&buffer[1..]) {
-        let line = &buffer[1..pos + 1];
-        let s = String::from_utf8(line.to_vec())?;
-        let consumed = pos + 3; // +1 for type byte, +2 for \r\n
-        Ok(Some((RespValue::SimpleString(s), consumed)))
-    } else {
-        Ok(None) // Need more data
-    }
+/// Write a type byte, `content` and CRLF
+fn put_line(out: &mut BytesMut, prefix: u8, content: impl std::fmt::Display) {
+    use std::fmt::Write;
+
+    out.put_u8(prefix);
+    // Writing into a BytesMut cannot fail
+    let _ = write!(out, "{}\r\n", content);
 }
 
-fn parse_error(buffer: &mut BytesMut) -> Result<Option<(RespValue, usize)>> {
-    if let Some(pos) = find_crlf(&buffer[1..]) {
-        let line = &buffer[1..pos + 1];
-        let s = String::from_utf8(line.to_vec())?;
-        let consumed = pos + 3;
-        Ok(Some((RespValue::Error(s), consumed)))
-    } else {
-        Ok(None)
-    }
+fn protocol_error(message: &str) -> anyhow::Error {
+    CommandError::Protocol(message.to_string()).into()
 }
 
-fn parse_integer(buffer: &mut BytesMut) -> Result<Option<(RespValue, usize)>> {
-    if let Some(pos) = find_crlf(&buffer[1..]) {
-        let line = &buffer[1..pos + 1];
-        let s = String::from_utf8(line.to_vec())?;
-        let num = s.parse::<i64>()?;
-        let consumed = pos + 3;
-        Ok(Some((RespValue::Integer(num), consumed)))
-    } else {
-        Ok(None)
-    }
+/// Parse the text between the type byte and CRLF, e.g. a length or integer
+fn parse_line<T: std::str::FromStr>(line: &[u8], message: &str) -> Result<T> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<T>().ok())
+        .ok_or_else(|| protocol_error(message))
 }
 
-fn parse_bulk_string(buffer: &mut BytesMut) -> Result<Option<(RespValue, usize)>> {
-    // First, parse the length
-    if let Some(pos) = find_crlf(&buffer[1..]) {
-        let line = &buffer[1..pos + 1];
-        let len_str = String::from_utf8(line.to_vec())?;
-        let len = len_str.parse::<i64>()?;
+/// Offset of the next CRLF at or after `from`
+fn find_crlf(buffer: &[u8], from: usize) -> Option<usize> {
+    buffer
+        .get(from..)?
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|pos| from + pos)
+}
 
-        if len == -1 {
-            // Null bulk string
-            return Ok(Some((RespValue::BulkString(None), pos + 3)));
-        }
+/// The line following the type byte at `start`, and the offset past its CRLF
+fn type_line(buffer: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = find_crlf(buffer, start + 1)?;
+    Some((&buffer[start + 1..end], end + 2))
+}
 
-        let len = len as usize;
-        let total_needed = pos + 3 + len + 2; // type + length + \r\n + data + \r\n
+/// The declared length of the bulk string or array at `start` (None for the
+/// -1 null marker) and the offset just past its header line
+fn length_header(
+    buffer: &[u8],
+    start: usize,
+    max: usize,
+    message: &str,
+) -> Result<Option<(Option<usize>, usize)>> {
+    let Some((line, end)) = type_line(buffer, start) else {
+        return Ok(None); // Need more data
+    };
+    match parse_line::<i64>(line, message)? {
+        -1 => Ok(Some((None, end))),
+        len if len < 0 || len as u64 > max as u64 => Err(protocol_error(message)),
+        len => Ok(Some((Some(len as usize), end))),
+    }
+}
 
-        if buffer.len() < total_needed {
-            return Ok(None); // Need more data
+/// Offset just past the complete value starting at `start`, or None if more
+/// data is needed. Nested values are measured in place at increasing offsets
+/// and nothing is built, so the input is never copied just to find where a
+/// frame ends. Declared sizes are checked against `limits` here, before the
+/// rest of the frame has arrived.
+fn frame_end(
+    buffer: &[u8],
+    start: usize,
+    limits: &ParseLimits,
+    depth: usize,
+) -> Result<Option<usize>> {
+    match buffer.get(start) {
+        None => Ok(None),
+        Some(b'+' | b'-' | b':') => Ok(type_line(buffer, start).map(|(_, end)| end)),
+        Some(b'$') => {
+            let header = length_header(buffer, start, limits.max_bulk_len, "invalid bulk length")?;
+            Ok(match header {
+                None => None,
+                Some((None, end)) => Some(end),
+                // Data plus its trailing CRLF
+                Some((Some(len), data_start)) => {
+                    Some(data_start + len + 2).filter(|&end| end <= buffer.len())
+                }
+            })
         }
-
-        let data_start = pos + 3;
-        let data = buffer[data_start..data_start + len].to_vec();
-        Ok(Some((RespValue::BulkString(Some(data)), total_needed)))
-    } else {
-        Ok(None) // Need more data
+        Some(b'*') => {
+            if depth >= limits.max_depth {
+                return Err(protocol_error("too deeply nested multibulk"));
+            }
+            let header = length_header(
+                buffer,
+                start,
+                limits.max_array_len,
+                "invalid multibulk length",
+            )?;
+            let Some((len, mut pos)) = header else {
+                return Ok(None);
+            };
+            for _ in 0..len.unwrap_or(0) {
+                match frame_end(buffer, pos, limits, depth + 1)? {
+                    Some(end) => pos = end,
+                    None => return Ok(None), // Need more data
+                }
+            }
+            Ok(Some(pos))
+        }
+        // Any other byte indicates an inline command
+        Some(_) => match find_crlf(buffer, start) {
+            // Reject oversized inline commands
+            Some(end) if end - start > MAX_INLINE_SIZE => {
+                Err(protocol_error("too big inline request"))
+            }
+            Some(end) => Ok(Some(end + 2)),
+            // No CRLF found - check if buffer is getting too large (potential slowloris)
+            None if buffer.len() - start > MAX_INLINE_SIZE => {
+                Err(protocol_error("too big inline request"))
+            }
+            None => Ok(None),
+        },
     }
 }
 
-fn parse_array(buffer: &mut BytesMut) -> Result<Option<(RespValue, usize)>> {
-    // First, parse the array length
-    if let Some(pos) = find_crlf(&buffer[1..]) {
-        let line = &buffer[1..pos + 1];
-        let len_str = String::from_utf8(line.to_vec())?;
-        let len = len_str.parse::<i64>()?;
-
-        if len == -1 {
-            // Null array
-            return Ok(Some((RespValue::Array(None), pos + 3)));
+/// Build the value starting at `start` of a frame already measured by
+/// `frame_end`, returning it with the offset just past its end
+fn build_value(frame: &Bytes, start: usize) -> Result<(RespValue, usize)> {
+    let truncated = || protocol_error("truncated frame");
+
+    match frame[start] {
+        b'+' => {
+            let (line, end) = type_line(frame, start).ok_or_else(truncated)?;
+            let s = String::from_utf8(line.to_vec())
+                .map_err(|_| protocol_error("invalid UTF-8 in simple string"))?;
+            Ok((RespValue::SimpleString(s), end))
         }
-
-        let mut consumed = pos + 3;
-        let mut elements = Vec::new();
-        let mut temp_buffer = buffer.clone();
-        temp_buffer.advance(consumed);
-
-        for _ in 0..len {
-            match RespValue::parse(&mut temp_buffer)? {
-                Some((value, bytes)) => {
+        b'-' => {
+            let (line, end) = type_line(frame, start).ok_or_else(truncated)?;
+            let s = String::from_utf8(line.to_vec())
+                .map_err(|_| protocol_error("invalid UTF-8 in error"))?;
+            Ok((RespValue::Error(s), end))
+        }
+        b':' => {
+            let (line, end) = type_line(frame, start).ok_or_else(truncated)?;
+            let num = parse_line::<i64>(line, "invalid integer")?;
+            Ok((RespValue::Integer(num), end))
+        }
+        b'$' => match length_header(frame, start, usize::MAX, "invalid bulk length")? {
+            Some((None, end)) => Ok((RespValue::BulkString(None), end)),
+            Some((Some(len), data_start)) => {
+                let data = frame.slice(data_start..data_start + len);
+                Ok((RespValue::BulkString(Some(data)), data_start + len + 2))
+            }
+            None => Err(truncated()),
+        },
+        b'*' => match length_header(frame, start, usize::MAX, "invalid multibulk length")? {
+            Some((None, end)) => Ok((RespValue::Array(None), end)),
+            Some((Some(len), mut pos)) => {
+                let mut elements = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let (value, end) = build_value(frame, pos)?;
                     elements.push(value);
-                    consumed += bytes;
-                    temp_buffer.advance(bytes);
+                    pos = end;
                 }
-                None => return Ok(None), // Need more data
+                Ok((RespValue::Array(Some(elements)), pos))
             }
+            None => Err(truncated()),
+        },
+        // An inline command: split the line by whitespace into a RESP array;
+        // an empty line becomes an empty array
+        _ => {
+            let end = find_crlf(frame, start).ok_or_else(truncated)?;
+            let elements = frame[start..end]
+                .split(|&b| b == b' ' || b == b'\t')
+                .filter(|part| !part.is_empty())
+                .map(|part| RespValue::BulkString(Some(frame.slice_ref(part))))
+                .collect();
+            Ok((RespValue::Array(Some(elements)), end + 2))
         }
-
-        Ok(Some((RespValue::Array(Some(elements)), consumed)))
-    } else {
-        Ok(None) // Need more data
     }
 }
 
@@ -253,14 +440,17 @@ mod tests {
     fn parse_bulk_string() {
         let mut buffer = BytesMut::from("$6\r\nfoobar\r\n");
         let result = RespValue::parse(&mut buffer).unwrap().unwrap();
-        assert_eq!(result.0, RespValue::BulkString(Some(b"foobar".to_vec())));
+        assert_eq!(
+            result.0,
+            RespValue::BulkString(Some(Bytes::from_static(b"foobar")))
+        );
     }
 
     #[test]
     fn parse_empty_bulk_string() {
         let mut buffer = BytesMut::from("$0\r\n\r\n");
         let result = RespValue::parse(&mut buffer).unwrap().unwrap();
-        assert_eq!(result.0, RespValue::BulkString(Some(Vec::new())));
+        assert_eq!(result.0, RespValue::BulkString(Some(Bytes::new())));
     }
 
     #[test]
@@ -276,7 +466,7 @@ mod tests {
         let result = RespValue::parse(&mut buffer).unwrap().unwrap();
         assert_eq!(
             result.0,
-            RespValue::BulkString(Some(vec![0, b'\r', b'\n', 1, 2]))
+            RespValue::BulkString(Some(Bytes::from_static(&[0, b'\r', b'\n', 1, 2])))
         );
     }
 
@@ -287,8 +477,8 @@ mod tests {
         assert_eq!(
             result.0,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"foo".to_vec())),
-                RespValue::BulkString(Some(b"bar".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"foo"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"bar"))),
             ]))
         );
     }
@@ -329,7 +519,7 @@ mod tests {
             RespValue::Array(Some(vec![
                 RespValue::Integer(1),
                 RespValue::SimpleString("OK".to_string()),
-                RespValue::BulkString(Some(b"foo".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"foo"))),
             ]))
         );
     }
@@ -355,6 +545,43 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[test]
+    fn parse_bulk_strings_share_the_frame() {
+        let mut buffer = BytesMut::from("*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+        let start = buffer.as_ptr() as usize;
+        let (value, consumed) = RespValue::parse(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+
+        let RespValue::Array(Some(items)) = value else {
+            panic!("expected an array");
+        };
+        let RespValue::BulkString(Some(key)) = &items[1] else {
+            panic!("expected a bulk string");
+        };
+        // "key\r\n" ends the frame
+        assert_eq!(key.as_ptr() as usize, start + consumed - 5);
+    }
+
+    #[test]
+    fn parse_pipelined_frames_by_offset() {
+        let frame = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let mut buffer = BytesMut::new();
+        for _ in 0..1000 {
+            buffer.extend_from_slice(frame);
+        }
+        // A truncated trailing frame must wait for more data
+        buffer.extend_from_slice(&frame[..frame.len() - 3]);
+
+        let mut parsed = 0;
+        while let Some((value, consumed)) = RespValue::parse(&mut buffer).unwrap() {
+            assert_eq!(consumed, frame.len());
+            assert!(matches!(value, RespValue::Array(Some(ref items)) if items.len() == 3));
+            parsed += 1;
+        }
+        assert_eq!(parsed, 1000);
+        assert_eq!(buffer.len(), frame.len() - 3);
+    }
+
     // Inline command parsing tests
     #[test]
     fn parse_inline_ping() {
@@ -362,7 +589,9 @@ mod tests {
         let result = RespValue::parse(&mut buffer).unwrap().unwrap();
         assert_eq!(
             result.0,
-            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec())),]))
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(Bytes::from_static(
+                b"PING"
+            ))),]))
         );
         assert_eq!(result.1, 6);
     }
@@ -374,9 +603,9 @@ mod tests {
         assert_eq!(
             result.0,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"SET".to_vec())),
-                RespValue::BulkString(Some(b"foo".to_vec())),
-                RespValue::BulkString(Some(b"bar".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"SET"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"foo"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"bar"))),
             ]))
         );
     }
@@ -388,9 +617,9 @@ mod tests {
         assert_eq!(
             result.0,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"SET".to_vec())),
-                RespValue::BulkString(Some(b"foo".to_vec())),
-                RespValue::BulkString(Some(b"bar".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"SET"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"foo"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"bar"))),
             ]))
         );
     }
@@ -416,9 +645,9 @@ mod tests {
         assert_eq!(
             result.0,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"SET".to_vec())),
-                RespValue::BulkString(Some(b"foo".to_vec())),
-                RespValue::BulkString(Some(b"bar".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"SET"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"foo"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"bar"))),
             ]))
         );
     }
@@ -432,9 +661,9 @@ mod tests {
         assert_eq!(
             result.0,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"NOTACOMMAND".to_vec())),
-                RespValue::BulkString(Some(b"arg1".to_vec())),
-                RespValue::BulkString(Some(b"arg2".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"NOTACOMMAND"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"arg1"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"arg2"))),
             ]))
         );
     }
@@ -446,9 +675,9 @@ mod tests {
         let result = RespValue::parse(&mut buffer).unwrap().unwrap();
         assert_eq!(
             result.0,
-            RespValue::Array(Some(vec![RespValue::BulkString(Some(
-                b"asdf1234!@#$".to_vec()
-            )),]))
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(Bytes::from_static(
+                b"asdf1234!@#$"
+            ))),]))
         );
     }
 
@@ -466,8 +695,8 @@ mod tests {
         assert_eq!(
             result.0,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"GET".to_vec())),
-                RespValue::BulkString(Some(b"key:with:colons".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"GET"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"key:with:colons"))),
             ]))
         );
     }
@@ -480,8 +709,8 @@ mod tests {
         assert_eq!(
             result.0,
             RespValue::Array(Some(vec![
-                RespValue::BulkString(Some(b"12345".to_vec())),
-                RespValue::BulkString(Some(b"arg".to_vec())),
+                RespValue::BulkString(Some(Bytes::from_static(b"12345"))),
+                RespValue::BulkString(Some(Bytes::from_static(b"arg"))),
             ]))
         );
     }
@@ -534,6 +763,61 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn parse_invalid_lengths_return_protocol_errors() {
+        for (input, expected) in [
+            ("$abc\r\n", "invalid bulk length"),
+            ("$-5\r\n", "invalid bulk length"),
+            ("*x\r\n", "invalid multibulk length"),
+            ("*-2\r\n", "invalid multibulk length"),
+            (":1.5\r\n", "invalid integer"),
+        ] {
+            let mut buffer = BytesMut::from(input);
+            let err = RespValue::parse(&mut buffer).unwrap_err().to_string();
+            assert_eq!(err, format!("ERR Protocol error: {}", expected));
+        }
+    }
+
+    #[test]
+    fn parse_rejects_oversized_declared_lengths() {
+        let limits = ParseLimits {
+            max_bulk_len: 4,
+            max_array_len: 2,
+            max_depth: 2,
+        };
+
+        // The header alone is enough to reject, no payload needs to arrive
+        let mut buffer = BytesMut::from("$9999999999\r\n");
+        assert!(RespValue::parse(&mut buffer).is_err());
+
+        let mut buffer = BytesMut::from("$5\r\n");
+        let err = RespValue::parse_with_limits(&mut buffer, &limits).unwrap_err();
+        assert!(err.to_string().contains("invalid bulk length"));
+
+        let mut buffer = BytesMut::from("*3\r\n");
+        let err = RespValue::parse_with_limits(&mut buffer, &limits).unwrap_err();
+        assert!(err.to_string().contains("invalid multibulk length"));
+
+        let mut buffer = BytesMut::from("*1\r\n*1\r\n*1\r\n:1\r\n");
+        let err = RespValue::parse_with_limits(&mut buffer, &limits).unwrap_err();
+        assert!(err.to_string().contains("too deeply nested"));
+    }
+
+    #[test]
+    fn parse_within_limits_succeeds() {
+        let limits = ParseLimits {
+            max_bulk_len: 4,
+            max_array_len: 2,
+            max_depth: 2,
+        };
+        let mut buffer = BytesMut::from("*2\r\n$4\r\nPING\r\n*1\r\n:1\r\n");
+        assert!(
+            RespValue::parse_with_limits(&mut buffer, &limits)
+                .unwrap()
+                .is_some()
+        );
+    }
+
     #[test]
     fn parse_empty_buffer_returns_none() {
         let mut buffer = BytesMut::new();
@@ -562,7 +846,7 @@ mod tests {
 
     #[test]
     fn serialize_bulk_string() {
-        let value = RespValue::BulkString(Some(b"foobar".to_vec()));
+        let value = RespValue::BulkString(Some(Bytes::from_static(b"foobar")));
         assert_eq!(value.serialize(), b"$6\r\nfoobar\r\n");
     }
 
@@ -575,8 +859,8 @@ mod tests {
     #[test]
     fn serialize_array() {
         let value = RespValue::Array(Some(vec![
-            RespValue::BulkString(Some(b"foo".to_vec())),
-            RespValue::BulkString(Some(b"bar".to_vec())),
+            RespValue::BulkString(Some(Bytes::from_static(b"foo"))),
+            RespValue::BulkString(Some(Bytes::from_static(b"bar"))),
         ]));
         assert_eq!(value.serialize(), b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n");
     }
@@ -587,6 +871,67 @@ mod tests {
         assert_eq!(value.serialize(), b"*-1\r\n");
     }
 
+    #[test]
+    fn serialize_into_appends() {
+        let mut out = BytesMut::from("+OK\r\n");
+        RespValue::Integer(-7).serialize_into(&mut out);
+        RespValue::Error("ERR bad".to_string()).serialize_into(&mut out);
+        assert_eq!(&out[..], b"+OK\r\n:-7\r\n-ERR bad\r\n");
+    }
+
+    #[test]
+    fn reply_buffer_keeps_large_values_as_chunks() {
+        let large = Bytes::from(vec![b'x'; VECTORED_MIN_LEN]);
+        let reply = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Bytes::from_static(b"small"))),
+            RespValue::BulkString(Some(large.clone())),
+            RespValue::BulkString(None),
+        ]));
+
+        let mut replies = ReplyBuffer::new();
+        replies.push(RespValue::Integer(1));
+        replies.push(reply.clone());
+        let chunks = replies.take();
+        assert!(replies.is_empty());
+
+        // Header chunk, the value itself, then the trailing CRLF and null
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].as_ptr(), large.as_ptr());
+        let mut expected = RespValue::Integer(1).serialize();
+        expected.extend_from_slice(&reply.serialize());
+        assert_eq!(chunks.concat(), expected);
+    }
+
+    #[test]
+    fn reply_buffer_streams_long_arrays() {
+        let element = RespValue::BulkString(Some(Bytes::from(vec![b'k'; 100])));
+        let long = RespValue::Array(Some(vec![element; 10 * STREAMED_MIN_LEN]));
+        let mut replies = ReplyBuffer::new();
+        replies.push(RespValue::Integer(1));
+        replies.push(long.clone());
+        replies.push(RespValue::Integer(2));
+
+        // Over a megabyte in all, but never serialized more than a chunk
+        // and an element ahead of the writer
+        let mut written = Vec::new();
+        let mut batches = 0;
+        while !replies.is_empty() {
+            let batch = replies.take().concat();
+            assert!(batch.len() < STREAM_CHUNK + 110, "{}", batch.len());
+            written.extend(batch);
+            batches += 1;
+        }
+        assert!(batches > 10, "{}", batches);
+        let mut expected = RespValue::Integer(1).serialize();
+        expected.extend(long.serialize());
+        expected.extend(RespValue::Integer(2).serialize());
+        assert_eq!(written, expected);
+
+        replies.push(long);
+        replies.clear();
+        assert!(replies.is_empty());
+    }
+
     // Round-trip tests
     #[test]
     fn roundtrip_simple_string() {
@@ -599,7 +944,7 @@ mod tests {
 
     #[test]
     fn roundtrip_bulk_string() {
-        let original = RespValue::BulkString(Some(b"hello world".to_vec()));
+        let original = RespValue::BulkString(Some(Bytes::from_static(b"hello world")));
         let serialized = original.serialize();
         let mut buffer = BytesMut::from(&serialized[..]);
         let (parsed, _) = RespValue::parse(&mut buffer).unwrap().unwrap();
@@ -611,11 +956,87 @@ mod tests {
         let original = RespValue::Array(Some(vec![
             RespValue::Integer(42),
             RespValue::SimpleString("OK".to_string()),
-            RespValue::BulkString(Some(b"test".to_vec())),
+            RespValue::BulkString(Some(Bytes::from_static(b"test"))),
         ]));
         let serialized = original.serialize();
         let mut buffer = BytesMut::from(&serialized[..]);
         let (parsed, _) = RespValue::parse(&mut buffer).unwrap().unwrap();
         assert_eq!(original, parsed);
     }
+
+    // Property-based round trips over arbitrary values
+
+    use proptest::prelude::*;
+
+    /// Any value the parser accepts from a client or server: simple strings
+    /// and errors can't contain CR or LF, and arrays nest at most
+    /// `ParseLimits::max_depth` deep
+    fn resp_value() -> impl Strategy<Value = RespValue> {
+        let leaf = prop_oneof![
+            "[^\r\n]*".prop_map(RespValue::SimpleString),
+            "[^\r\n]*".prop_map(RespValue::Error),
+            any::<i64>().prop_map(RespValue::Integer),
+            Just(RespValue::BulkString(None)),
+            proptest::collection::vec(any::<u8>(), 0..64)
+                .prop_map(|data| RespValue::BulkString(Some(Bytes::from(data)))),
+            Just(RespValue::Array(None)),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            proptest::collection::vec(inner, 0..8).prop_map(|values| RespValue::Array(Some(values)))
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_any_value(value in resp_value()) {
+            let serialized = value.serialize();
+            let mut buffer = BytesMut::from(&serialized[..]);
+            let (parsed, consumed) = RespValue::parse(&mut buffer).unwrap().unwrap();
+            prop_assert_eq!(parsed, value);
+            prop_assert_eq!(consumed, serialized.len());
+            prop_assert!(buffer.is_empty());
+        }
+
+        /// Frames arriving a few bytes at a time parse only once complete,
+        /// back to back, leaving nothing behind
+        #[test]
+        fn roundtrip_chunked(
+            values in proptest::collection::vec(resp_value(), 1..4),
+            chunk in 1..8usize,
+        ) {
+            let wire: Vec<u8> = values.iter().flat_map(|value| value.serialize()).collect();
+            let mut buffer = BytesMut::new();
+            let mut parsed = Vec::new();
+            for piece in wire.chunks(chunk) {
+                buffer.extend_from_slice(piece);
+                while let Some((value, _)) = RespValue::parse(&mut buffer).unwrap() {
+                    parsed.push(value);
+                }
+            }
+            prop_assert_eq!(parsed, values);
+            prop_assert!(buffer.is_empty());
+        }
+
+        /// A reply buffer writes exactly what `serialize` would, whether
+        /// values are copied in or kept as separate chunks
+        #[test]
+        fn reply_buffer_matches_serialize(
+            values in proptest::collection::vec(resp_value(), 0..4),
+            large in proptest::collection::vec(any::<u8>(), VECTORED_MIN_LEN..2 * VECTORED_MIN_LEN),
+        ) {
+            let mut values = values;
+            values.push(RespValue::BulkString(Some(Bytes::from(large))));
+            let mut replies = ReplyBuffer::new();
+            let mut expected = Vec::new();
+            for value in &values {
+                replies.push(value.clone());
+                expected.extend(value.serialize());
+            }
+            let mut written = Vec::new();
+            while !replies.is_empty() {
+                written.extend(replies.take().concat());
+            }
+            prop_assert_eq!(written, expected);
+        }
+    }
 }
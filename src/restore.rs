@@ -0,0 +1,319 @@
+//! Point-in-time recovery from a `record-file` recording, the nearest thing
+//! rudis has to an AOF: every command the server received, timestamped.
+//!
+//! With `restore-file` set, a server replays the write commands in a
+//! recording into its store before it accepts clients, stopping at
+//! `restore-until`, a wall-clock time, or `restore-until-offset`, a number
+//! of recorded commands. A mistaken DEL or overwrite is undone by restoring
+//! to just before it. `rudis restore` makes the same cut offline, writing
+//! the part of a recording before the point to a new file.
+//!
+//! Commands are run again rather than their effects read back, so TTLs
+//! restart from the time of the restore, and a write the server refused
+//! when it was sent, for want of AUTH or over a limit, is applied this time.
+//!
+//! Encrypted recordings are decrypted with `encryption-key`, and `rudis
+//! restore --encryption-key` reads them and encrypts its output the same way.
+
+use crate::command::{Command, CommandFlags, CommandRenames, request_spec};
+use crate::config::{parse_offset, parse_unix_millis};
+use crate::encryption::EncryptionKey;
+use crate::record::Recording;
+use crate::resp::RespValue;
+use crate::store::Store;
+use anyhow::{Result, anyhow, bail};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+const USAGE: &str = "\
+Usage: rudis restore <recording> [--until <ms>] [--until-offset <n>] --output <file>
+                     [--encryption-key <source>]
+  <recording>         File written by record-file
+  --until <ms>        Keep the commands received before this time, in
+                      milliseconds since the Unix epoch
+  --until-offset <n>  Keep at most the first n commands recorded
+  --output <file>     Where to write the commands kept, as a recording
+                      restore-file can replay
+  --encryption-key <source>
+                      Key to decrypt the recording and encrypt the output
+                      with, given as for the encryption-key directive
+  --help              Show this help
+";
+
+/// Drop the commands recorded at or after `until`, and those after the
+/// first `until_offset`; a restore stops at whichever comes first
+pub fn cut(recording: &mut Recording, until: Option<SystemTime>, until_offset: Option<usize>) {
+    let mut kept = recording.entries.len();
+    if let Some(time) = until {
+        let until = time.duration_since(recording.started).unwrap_or_default();
+        kept = recording.entries.partition_point(|entry| entry.at < until);
+    }
+    if let Some(count) = until_offset {
+        kept = kept.min(count);
+    }
+    recording.entries.truncate(kept);
+}
+
+/// Write commands run by a restore
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Restored {
+    pub replayed: u64,
+    /// Commands that failed again, or no longer parse
+    pub failed: u64,
+}
+
+impl fmt::Display for Restored {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} write commands replayed, {} failed",
+            self.replayed, self.failed
+        )
+    }
+}
+
+/// Run the write commands in `recording` against `store`, in order. Names
+/// are resolved through `renames`, as the recording has them as sent.
+pub async fn replay(store: &Store, renames: &CommandRenames, recording: &Recording) -> Restored {
+    let mut restored = Restored::default();
+    for entry in &recording.entries {
+        // A command renamed away never ran
+        let Ok(value) = renames.resolve(entry.command.clone()) else {
+            continue;
+        };
+        let write =
+            request_spec(&value).is_some_and(|(spec, _)| spec.flags.contains(CommandFlags::WRITE));
+        if !write {
+            continue;
+        }
+        let ran = match Command::from_resp(value) {
+            Ok(cmd) => !matches!(cmd.execute(store).await, RespValue::Error(_)),
+            Err(_) => false,
+        };
+        match ran {
+            true => restored.replayed += 1,
+            false => restored.failed += 1,
+        }
+    }
+    restored
+}
+
+/// What `rudis restore` cuts and where it writes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestoreOptions {
+    pub recording: PathBuf,
+    pub until: Option<SystemTime>,
+    pub until_offset: Option<usize>,
+    pub output: PathBuf,
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+/// Parse `rudis restore` arguments; None asks for the usage text
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<RestoreOptions>> {
+    let (mut recording, mut until, mut until_offset, mut output) = (None, None, None, None);
+    let mut encryption_key = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("Option '{}' needs a value", arg))
+        };
+        match arg.as_str() {
+            "--help" => return Ok(None),
+            "--until" => until = Some(parse_unix_millis(&value()?)?),
+            "--until-offset" => until_offset = Some(parse_offset(&value()?)?),
+            "--output" => output = Some(PathBuf::from(value()?)),
+            "--encryption-key" => encryption_key = Some(EncryptionKey::load(&value()?)?),
+            _ if arg.starts_with("--") => bail!("Unknown option '{}'", arg),
+            _ if recording.is_none() => recording = Some(PathBuf::from(arg)),
+            _ => bail!("Unexpected argument '{}'", arg),
+        }
+    }
+    if until.is_none() && until_offset.is_none() {
+        bail!("--until or --until-offset is required");
+    }
+    Ok(Some(RestoreOptions {
+        recording: recording.ok_or_else(|| anyhow!("A recording to restore from is required"))?,
+        until,
+        until_offset,
+        output: output.ok_or_else(|| anyhow!("--output is required"))?,
+        encryption_key,
+    }))
+}
+
+/// Run `rudis restore` with its arguments, printing what was kept
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<()> {
+    let Some(options) = parse_args(args)? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    let key = options.encryption_key.as_ref();
+    let mut recording = Recording::load_with(&options.recording, key)?;
+    let recorded = recording.entries.len();
+    cut(&mut recording, options.until, options.until_offset);
+    recording.save_with(&options.output, key)?;
+    println!(
+        "Kept {} of {} recorded commands in {}",
+        recording.entries.len(),
+        recorded,
+        options.output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Entry;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn recording(commands: &[(u64, &[&str])]) -> Recording {
+        Recording {
+            started: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            entries: commands
+                .iter()
+                .map(|(millis, command)| Entry {
+                    at: Duration::from_millis(*millis),
+                    client: 1,
+                    command: RespValue::Array(Some(
+                        command
+                            .iter()
+                            .map(|arg| RespValue::BulkString(Some(Bytes::from(arg.to_string()))))
+                            .collect(),
+                    )),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn cuts_at_a_time_or_offset() {
+        let full = recording(&[
+            (0, &["SET", "a", "1"]),
+            (10, &["DEL", "a"]),
+            (20, &["PING"]),
+        ]);
+
+        let before_del = Some(full.started + Duration::from_millis(10));
+        let kept = |until, until_offset| {
+            let mut recording = full.clone();
+            cut(&mut recording, until, until_offset);
+            recording.entries.len()
+        };
+
+        assert_eq!(kept(before_del, None), 1);
+        assert_eq!(kept(None, Some(2)), 2);
+        assert_eq!(kept(None, Some(10)), 3);
+        assert_eq!(kept(None, None), 3);
+        // Whichever point comes first
+        assert_eq!(kept(before_del, Some(2)), 1);
+        assert_eq!(kept(before_del, Some(0)), 0);
+        // A time before the recording started keeps nothing
+        assert_eq!(kept(Some(UNIX_EPOCH), None), 0);
+    }
+
+    #[tokio::test]
+    async fn replays_only_writes() {
+        let store = Store::new();
+        let renames = CommandRenames::new(&HashMap::from([("SET".to_string(), "PUT".to_string())]));
+        let recording = recording(&[
+            (0, &["PUT", "a", "1"]),
+            (1, &["SET", "b", "1"]),
+            (2, &["INCR", "a"]),
+            (3, &["GET", "a"]),
+            (4, &["DEBUG", "CHAOS", "OFF"]),
+            (5, &["INCRBY", "a", "x"]),
+        ]);
+
+        let restored = replay(&store, &renames, &recording).await;
+        assert_eq!(
+            restored,
+            Restored {
+                replayed: 2,
+                failed: 1
+            }
+        );
+        assert_eq!(store.get(b"a").await, Some(Bytes::from("2")));
+        // SET was renamed away, so the client's SET never ran
+        assert_eq!(store.get(b"b").await, None);
+    }
+
+    #[test]
+    fn parses_restore_arguments() {
+        let options = parse_args(args(&[
+            "in.resp",
+            "--until-offset",
+            "5",
+            "--output",
+            "out.resp",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.recording, PathBuf::from("in.resp"));
+        assert_eq!((options.until, options.until_offset), (None, Some(5)));
+        assert_eq!(options.output, PathBuf::from("out.resp"));
+        assert_eq!(options.encryption_key, None);
+        let options = parse_args(args(&[
+            "in.resp",
+            "--until",
+            "1700000000250",
+            "--output",
+            "o",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            options.until,
+            Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_250))
+        );
+
+        assert!(parse_args(args(&["--help"])).unwrap().is_none());
+        assert!(parse_args(args(&["in.resp", "--output", "o"])).is_err());
+        assert!(parse_args(args(&["--until-offset", "5", "--output", "o"])).is_err());
+        assert!(parse_args(args(&["in.resp", "--until", "yesterday", "--output", "o"])).is_err());
+
+        let hex = "ab".repeat(32);
+        let options = parse_args(args(&[
+            "in.resp",
+            "--until-offset",
+            "5",
+            "--output",
+            "o",
+            "--encryption-key",
+            &hex,
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            options.encryption_key,
+            Some(EncryptionKey::from_hex(&hex).unwrap())
+        );
+        let bad_key = [
+            "in.resp",
+            "--until-offset",
+            "5",
+            "--output",
+            "o",
+            "--encryption-key",
+            "1",
+        ];
+        assert!(parse_args(args(&bad_key)).is_err());
+    }
+
+    #[test]
+    fn saved_cut_loads_back() {
+        let path = std::env::temp_dir().join(format!("rudis-restore-{}.resp", std::process::id()));
+        let mut cut_recording = recording(&[(0, &["SET", "a", "1"]), (10, &["DEL", "a"])]);
+        cut(&mut cut_recording, None, Some(1));
+        cut_recording.save(&path).unwrap();
+        assert_eq!(Recording::load(&path).unwrap(), cut_recording);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,475 @@
+//! Startup seeding from a fixture file, so ephemeral test and staging
+//! instances boot with known data. With `seed-file` set, a server loads the
+//! fixture into its store before it accepts clients, ahead of any
+//! `restore-file` replay.
+//!
+//! The format is told from the first thing in the file:
+//!
+//! - `{` or `[`: JSON records as redis-dump writes them, one object per
+//!   line (`{"db":0,"key":"k","ttl":-1,"type":"string","value":"v"}`), or
+//!   an array of them. A positive `ttl` is in seconds. As rudis holds
+//!   nothing but strings, records of other types, and of databases other
+//!   than 0, are skipped and counted.
+//! - `*`: RESP commands, as `redis-cli --pipe` takes them.
+//! - Anything else: one command per line, quoted as in the config file,
+//!   skipping blank lines and lines starting with `#`.
+//!
+//! Commands are run as clients would send them. A command that fails, or a
+//! fixture that doesn't parse, stops the server from starting, naming the
+//! line, rather than leaving it up with part of its data.
+
+use crate::command::Command;
+use crate::config::split_config_line;
+use crate::resp::RespValue;
+use crate::store::Store;
+use anyhow::{Result, anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// What a seed loaded
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Seeded {
+    /// Commands run, or JSON records stored
+    pub loaded: u64,
+    /// Records left out per type, or per database
+    pub skipped: BTreeMap<String, u64>,
+}
+
+impl fmt::Display for Seeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} entries loaded", self.loaded)?;
+        for (kind, count) in &self.skipped {
+            write!(f, ", {} {} skipped", count, kind)?;
+        }
+        Ok(())
+    }
+}
+
+/// Load the fixture at `path` into `store`
+pub async fn load(store: &Store, path: &Path) -> Result<Seeded> {
+    let contents =
+        std::fs::read(path).map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+    seed(store, &contents)
+        .await
+        .map_err(|e| anyhow!("'{}' {}", path.display(), e))
+}
+
+/// Load a fixture's contents into `store`
+pub async fn seed(store: &Store, contents: &[u8]) -> Result<Seeded> {
+    match contents.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{' | b'[') => seed_json(store, contents).await,
+        Some(b'*') => seed_resp(store, contents).await,
+        _ => seed_lines(store, contents).await,
+    }
+}
+
+async fn run(store: &Store, command: RespValue) -> Result<()> {
+    let reply = match Command::from_resp(command) {
+        Ok(command) => command.execute(store).await,
+        Err(e) => bail!("{}", e),
+    };
+    match reply {
+        RespValue::Error(e) => bail!("{}", e),
+        _ => Ok(()),
+    }
+}
+
+async fn seed_lines(store: &Store, contents: &[u8]) -> Result<Seeded> {
+    let contents = std::str::from_utf8(contents).map_err(|_| anyhow!("is not UTF-8"))?;
+    let mut seeded = Seeded::default();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = split_config_line(line)
+            .ok_or_else(|| anyhow!("line {}: unbalanced quotes", index + 1))?;
+        let command = RespValue::Array(Some(
+            words
+                .into_iter()
+                .map(|word| RespValue::BulkString(Some(Bytes::from(word))))
+                .collect(),
+        ));
+        run(store, command)
+            .await
+            .map_err(|e| anyhow!("line {}: {}", index + 1, e))?;
+        seeded.loaded += 1;
+    }
+    Ok(seeded)
+}
+
+async fn seed_resp(store: &Store, contents: &[u8]) -> Result<Seeded> {
+    let mut buffer = BytesMut::from(contents);
+    let mut seeded = Seeded::default();
+    let mut lines = Lines::default();
+    let mut number = 1;
+    loop {
+        let line = lines.at(contents, contents.len() - buffer.len());
+        match RespValue::parse(&mut buffer).map_err(|e| anyhow!("line {}: {}", line, e))? {
+            Some((command, _)) => {
+                run(store, command)
+                    .await
+                    .map_err(|e| anyhow!("command {} (line {}): {}", number, line, e))?;
+                seeded.loaded += 1;
+                number += 1;
+            }
+            None if buffer.iter().all(u8::is_ascii_whitespace) => return Ok(seeded),
+            None => bail!("line {}: truncated command", line),
+        }
+    }
+}
+
+async fn seed_json(store: &Store, contents: &[u8]) -> Result<Seeded> {
+    let mut parser = Parser { contents, pos: 0 };
+    let mut seeded = Seeded::default();
+    let mut lines = Lines::default();
+    while parser.skip_whitespace() {
+        let line = lines.at(contents, parser.pos);
+        let records = match parser.value()? {
+            Json::Array(records) => records,
+            record => vec![record],
+        };
+        for record in records {
+            store_record(store, record, &mut seeded)
+                .await
+                .map_err(|e| anyhow!("line {}: {}", line, e))?;
+        }
+    }
+    Ok(seeded)
+}
+
+/// Store one redis-dump record
+async fn store_record(store: &Store, record: Json, seeded: &mut Seeded) -> Result<()> {
+    let Json::Object(fields) = record else {
+        bail!("expected a record object");
+    };
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, v)| v);
+    let Some(Json::String(key)) = field("key") else {
+        bail!("record has no \"key\" string");
+    };
+    let db = match field("db") {
+        None => "0",
+        Some(Json::Number(db)) => db.as_str(),
+        Some(_) => bail!("\"db\" is not a number"),
+    };
+    if db != "0" {
+        *seeded.skipped.entry(format!("db{}", db)).or_default() += 1;
+        return Ok(());
+    }
+    match field("type") {
+        None => {}
+        Some(Json::String(kind)) if kind == "string" => {}
+        Some(Json::String(kind)) => {
+            *seeded.skipped.entry(kind.clone()).or_default() += 1;
+            return Ok(());
+        }
+        Some(_) => bail!("\"type\" is not a string"),
+    }
+    let value = match field("value") {
+        Some(Json::String(value)) => value.clone(),
+        // Numbers stored as strings may be dumped bare
+        Some(Json::Number(value)) => value.clone(),
+        _ => bail!("record \"{}\" has no \"value\" string", key),
+    };
+    let (key, value) = (Bytes::from(key.clone()), Bytes::from(value));
+    match field("ttl") {
+        Some(Json::Number(ttl)) => match ttl.parse::<f64>() {
+            Ok(seconds) if seconds > 0.0 => store.set_ex(key, value, seconds.ceil() as u64).await,
+            _ => store.set(key, value).await,
+        },
+        None | Some(Json::Null) => store.set(key, value).await,
+        Some(_) => bail!("\"ttl\" is not a number"),
+    }
+    seeded.loaded += 1;
+    Ok(())
+}
+
+/// The 1-based line of the byte at `pos`
+fn line_at(contents: &[u8], pos: usize) -> usize {
+    contents[..pos].iter().filter(|&&b| b == b'\n').count() + 1
+}
+
+/// Line numbers of positions moving forward through a fixture, counted
+/// once rather than from the start each time
+#[derive(Default)]
+struct Lines {
+    pos: usize,
+    newlines: usize,
+}
+
+impl Lines {
+    fn at(&mut self, contents: &[u8], pos: usize) -> usize {
+        self.newlines += line_at(&contents[self.pos..pos], pos - self.pos) - 1;
+        self.pos = pos;
+        self.newlines + 1
+    }
+}
+
+/// Enough JSON for redis-dump's records
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    /// As written, so a bare number value is stored as it reads
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct Parser<'a> {
+    contents: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    /// Skip whitespace, returning whether anything is left
+    fn skip_whitespace(&mut self) -> bool {
+        while self
+            .contents
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+        self.pos < self.contents.len()
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        anyhow!("line {}: {}", line_at(self.contents, self.pos), message)
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if !self.contents[self.pos..].starts_with(token.as_bytes()) {
+            return Err(self.error(&format!("expected '{}'", token)));
+        }
+        self.pos += token.len();
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.skip_whitespace();
+        match self.contents.get(self.pos) {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'n') => self.expect("null").map(|()| Json::Null),
+            Some(b't') => self.expect("true").map(|()| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|()| Json::Bool(false)),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("expected a JSON value")),
+            None => Err(self.error("unexpected end of JSON")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.expect("{")?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.contents.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.contents.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.expect("[")?;
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.contents.get(self.pos) == Some(&b']') {
+            self.pos += 1;
+            return Ok(Json::Array(elements));
+        }
+        loop {
+            elements.push(self.value()?);
+            self.skip_whitespace();
+            match self.contents.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(elements));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        while self
+            .contents
+            .get(self.pos)
+            .is_some_and(|b| matches!(b, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.contents[start..self.pos])
+            .ok()
+            .filter(|text| text.parse::<f64>().is_ok())
+            .map(|text| Json::Number(text.to_string()))
+            .ok_or_else(|| self.error("bad number"))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect("\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&b) = self.contents.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.contents.get(self.pos).copied();
+                    self.pos += 1;
+                    match escape {
+                        Some(b'u') => {
+                            let c = self.unicode_escape()?;
+                            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        Some(b'n') => bytes.push(b'\n'),
+                        Some(b'r') => bytes.push(b'\r'),
+                        Some(b't') => bytes.push(b'\t'),
+                        Some(b'b') => bytes.push(0x08),
+                        Some(b'f') => bytes.push(0x0c),
+                        Some(c @ (b'"' | b'\\' | b'/')) => bytes.push(c),
+                        _ => return Err(self.error("bad escape in string")),
+                    }
+                }
+                b => bytes.push(b),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("string is not UTF-8"))
+    }
+
+    /// The character of a `\u` escape, past the `u`, joining surrogate pairs
+    fn unicode_escape(&mut self) -> Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            self.expect("\\u")?;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("unpaired surrogate in string"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("bad \\u escape in string"))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .contents
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("bad \\u escape in string"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn seeds_command_files() {
+        let store = Store::new();
+        let seeded = seed(
+            &store,
+            b"# Fixture\n\nSET greeting \"hello world\"\nINCR visits\nSETEX token 100 abc\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(seeded.loaded, 3);
+        assert_eq!(
+            store.get(b"greeting").await,
+            Some(Bytes::from("hello world"))
+        );
+        assert_eq!(store.get(b"visits").await, Some(Bytes::from("1")));
+        assert!(store.get(b"token").await.is_some());
+
+        let resp = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\nv1\r\n*2\r\n$4\r\nINCR\r\n$1\r\nn\r\n";
+        let store = Store::new();
+        assert_eq!(seed(&store, resp).await.unwrap().loaded, 2);
+        assert_eq!(store.get(b"k").await, Some(Bytes::from("v1")));
+
+        let error = |contents: &'static [u8]| async move {
+            seed(&Store::new(), contents).await.unwrap_err().to_string()
+        };
+        assert!(error(b"SET a 1\nINCR a b c\n").await.starts_with("line 2:"));
+        assert!(error(b"SET a \"1\n").await.contains("unbalanced quotes"));
+        assert!(error(b"SET a x\nINCR a\n").await.contains("not an integer"));
+        assert!(error(b"*2\r\n$3\r\nGET\r\n").await.contains("truncated"));
+    }
+
+    #[tokio::test]
+    async fn seeds_redis_dump_records() {
+        let store = Store::new();
+        let seeded = seed(
+            &store,
+            br#"{"db":0,"key":"user:1","ttl":-1,"type":"string","value":"Ada \u00e9\ud83d\ude00","size":7}
+{"db":0,"key":"count","ttl":50,"type":"string","value":42}
+{"db":0,"key":"profile","ttl":-1,"type":"hash","value":{"name":"ada"}}
+{"db":1,"key":"other","ttl":-1,"type":"string","value":"x"}
+"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(seeded.loaded, 2);
+        assert_eq!(
+            seeded.to_string(),
+            "2 entries loaded, 1 db1 skipped, 1 hash skipped"
+        );
+        assert_eq!(store.get(b"user:1").await, Some(Bytes::from("Ada é😀")));
+        assert_eq!(store.get(b"count").await, Some(Bytes::from("42")));
+        assert!(store.get(b"profile").await.is_none());
+
+        // An array of records, without the optional fields
+        let store = Store::new();
+        let seeded = seed(
+            &store,
+            br#" [{"key":"a","value":"1"}, {"key":"b","value":"2"}]"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(seeded.loaded, 2);
+        assert_eq!(store.get(b"b").await, Some(Bytes::from("2")));
+
+        let error = |contents: &'static [u8]| async move {
+            seed(&Store::new(), contents).await.unwrap_err().to_string()
+        };
+        assert!(
+            error(b"{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\" \"value\":1}")
+                .await
+                .starts_with("line 2:")
+        );
+        assert!(error(br#"{"value":"1"}"#).await.contains("no \"key\""));
+        assert!(
+            error(br#"{"key":"a","value":"1"#)
+                .await
+                .contains("unterminated")
+        );
+    }
+}
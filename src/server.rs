@@ -1,80 +1,739 @@
-use crate::command::Command;
-use crate::resp::RespValue;
+use crate::backup::{Backups, Retention};
+use crate::bufpool::ReadSize;
+use crate::chaos::Chaos;
+use crate::command::{
+    Command, CommandFlags, CommandRenames, CommandSpec, InfoSources, ShutdownMode, info,
+    lookup_command, request_spec,
+};
+use crate::config::Config;
+use crate::crdt::{Crdt, Op};
+use crate::error::CommandError;
+use crate::events::ExpiryHookGuard;
+use crate::log::{notice, warning};
+use crate::otlp::{Span, Tracer};
+use crate::overload::LoadShedder;
+use crate::ratelimit::{Decision, RateLimiter};
+use crate::record::{Recorder, Recording, SERVER_CLIENT};
+use crate::resp::{ReplyBuffer, RespValue};
 use crate::store::Store;
+use crate::tenant::{Tenant, Tenants};
+use crate::tls::PeerTls;
+use crate::upstream::{Upstream, UpstreamStats, WriteBehind};
+use crate::{acl, admin, bufpool, probe, restore, seed, systemd, tls, watchdog};
 use anyhow::Result;
-use bytes::{Buf, BytesMut};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(unix)]
+use socket2::{Domain, Socket, Type};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::BTreeMap;
+use std::io::IoSlice;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
 
-const REDIS_PORT: u16 = 6379;
+/// Sent to non-loopback clients rejected by protected mode
+pub const PROTECTED_MODE_ERROR: &str = "-DENIED Rudis is running in protected mode because protected \
+mode is enabled and no password is set. In this mode connections are only accepted from the \
+loopback interface. To accept external clients, restart the server with '--protected-mode no' \
+or set 'protected-mode no' in the config file, and make sure it is not publicly reachable.\r\n";
 
-pub struct Server {
-    listener: TcpListener,
-    store: Store,
+/// Sent to clients connecting while `maxclients` are already connected
+pub const MAX_CLIENTS_ERROR: &str = "-ERR max number of clients reached\r\n";
+
+/// Sent to clients connecting while the server sheds load
+pub const OVERLOADED_ERROR: &str = "-BUSY server is overloaded, try again later\r\n";
+
+/// Reply batches a connection may queue before it waits for its writer
+const REPLY_QUEUE_DEPTH: usize = 64;
+
+/// Pause between the pieces of a reply split by DEBUG CHAOS PARTIAL-WRITES
+const PARTIAL_WRITE_PAUSE: Duration = Duration::from_millis(1);
+
+/// Most chunks handed to one vectored write (Linux allows 1024)
+pub const MAX_IOVECS: usize = 64;
+
+/// Pending connection queue length for SO_REUSEPORT listeners (Redis' tcp-backlog default)
+#[cfg(unix)]
+const LISTEN_BACKLOG: i32 = 511;
+
+/// Network I/O implementation serving clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoBackend {
+    /// Tokio's epoll/kqueue reactor
+    #[default]
+    Tokio,
+    /// io_uring through tokio-uring, built with the `io-uring` feature on Linux
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    IoUring,
 }
 
-impl Server {
-    /// Create a new Redis server
-    pub async fn new() -> Result<Self> {
-        let addr = format!("127.0.0.1:{}", REDIS_PORT);
-        let listener = TcpListener::bind(&addr).await?;
-        println!("Rudis server listening on {}", addr);
-        Ok(Self {
-            listener,
-            store: Store::new(),
+/// Whether a newly accepted client may connect
+pub enum Admission {
+    /// Serve the client, which holds its slot until it disconnects
+    Accept(ClientSlot),
+    /// Drop the connection silently
+    Reject,
+    /// Send this error, then close
+    Deny(&'static str),
+}
+
+/// The clients currently connected
+#[derive(Default)]
+struct Clients {
+    connected: AtomicUsize,
+    /// Every connected client by id
+    list: Mutex<BTreeMap<u64, ClientInfo>>,
+    /// Woken when the last client disconnects
+    all_gone: Notify,
+}
+
+/// A connected client, as listed by the admin API
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    /// When the client connected
+    pub since: Instant,
+}
+
+/// A connected client's place under `maxclients`, given back when dropped
+pub struct ClientSlot {
+    clients: Arc<Clients>,
+    id: u64,
+}
+
+impl ClientSlot {
+    fn reserve(clients: &Arc<Clients>, max: usize, id: u64, addr: SocketAddr) -> Option<Self> {
+        clients
+            .connected
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |connected| {
+                (connected < max).then_some(connected + 1)
+            })
+            .ok()?;
+        let since = Instant::now();
+        let info = ClientInfo { id, addr, since };
+        clients.list.lock().unwrap().insert(id, info);
+        Some(Self {
+            clients: clients.clone(),
+            id,
         })
     }
 
-    /// Run the server, accepting connections and handling them
-    pub async fn run(&self) -> Result<()> {
-        // Start active expiration background task
-        let _expiration_handle = Store::start_active_expiration(self.store.clone());
+    /// The connection's id, unique while the server runs
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
 
-        loop {
-            let (socket, addr) = self.listener.accept().await?;
-            println!("Accepted connection from {}", addr);
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.clients.list.lock().unwrap().remove(&self.id);
+        if self.clients.connected.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.clients.all_gone.notify_waiters();
+        }
+    }
+}
 
-            // Clone the store handle for this connection
-            let store = self.store.clone();
+/// What a connection does once its buffered input has been processed
+pub enum Flow {
+    /// Wait for more input
+    Read,
+    /// Send the pending replies, then close
+    Close,
+    /// Send the pending replies, then shut the server down
+    Shutdown,
+    /// Close at once, abandoning unsent replies, as injected by DEBUG CHAOS
+    Drop,
+}
 
-            // Spawn a new task to handle this connection
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(socket, store).await {
-                    eprintln!("Error handling connection: {}", e);
-                }
-            });
+/// State of one client connection, carried from command to command and
+/// threaded through their execution. Connection-scoped commands (SELECT,
+/// AUTH) keep what they change here rather than in the shared `Context`.
+#[derive(Debug)]
+pub struct ConnectionContext {
+    /// Unique while the server runs, as logged when the client connects
+    id: u64,
+    peer: IpAddr,
+    /// Database selected with SELECT
+    db: u32,
+    /// Tenant the client logged in as with AUTH
+    tenant: Option<Arc<Tenant>>,
+    /// Whether the client proved itself an active-active peer with CRDT AUTH
+    crdt_peer: bool,
+    /// Commands received so far, so the last one's number
+    commands: u64,
+    /// When the frame the client has started sending must be complete by,
+    /// with `client-frame-timeout` set
+    frame_due: Option<Instant>,
+}
+
+impl ConnectionContext {
+    pub fn new(id: u64, peer: IpAddr) -> Self {
+        Self {
+            id,
+            peer,
+            db: 0,
+            tenant: None,
+            crdt_peer: false,
+            commands: 0,
+            frame_due: None,
+        }
+    }
+
+    /// The connection's client id
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The client's address
+    pub fn peer(&self) -> IpAddr {
+        self.peer
+    }
+
+    /// The database the client has selected
+    pub fn db(&self) -> u32 {
+        self.db
+    }
+
+    /// The tenant the client logged in as, if any
+    pub fn tenant(&self) -> Option<&Tenant> {
+        self.tenant.as_deref()
+    }
+
+    /// Commands received on the connection so far
+    pub fn commands(&self) -> u64 {
+        self.commands
+    }
+
+    /// The id of the last command received
+    pub fn request_id(&self) -> RequestId {
+        RequestId {
+            client: self.id,
+            command: self.commands,
+        }
+    }
+
+    /// Queue a command's reply to be sent to the client
+    fn reply(&self, response: RespValue, replies: &mut ReplyBuffer) {
+        replies.push(response);
+    }
+
+    /// How long the next read may wait: `idle` (zero for ever), or less if a
+    /// started frame is due sooner. None once that frame is overdue.
+    pub fn read_timeout(&self, idle: Duration) -> Option<Duration> {
+        let Some(due) = self.frame_due else {
+            return Some(idle);
+        };
+        let left = due.saturating_duration_since(Instant::now());
+        match left.is_zero() {
+            true => None,
+            false if idle.is_zero() => Some(left),
+            false => Some(idle.min(left)),
         }
     }
+
+    /// Whether the frame the client started is overdue
+    pub fn frame_overdue(&self) -> bool {
+        self.frame_due.is_some_and(|due| Instant::now() >= due)
+    }
+
+    /// Start or stop the clock on the frame left in the buffer after a read
+    /// was processed: `unparsed` bytes of it, with `progressed` set if the
+    /// read completed a frame, so what is left was started by this read
+    fn track_frame(&mut self, unparsed: usize, progressed: bool, timeout: Duration) {
+        self.frame_due = match self.frame_due {
+            _ if unparsed == 0 || timeout.is_zero() => None,
+            Some(due) if !progressed => Some(due),
+            _ => Some(Instant::now() + timeout),
+        };
+    }
 }
 
-// Handle a single client connection
-async fn handle_connection(mut socket: TcpStream, store: Store) -> Result<()> {
-    let mut buffer = BytesMut::with_capacity(4096);
+/// Identifies a command in the log and in trace spans, so a failure a
+/// client reports can be matched to them: the id of its connection, logged
+/// when the client connects, and its number on the connection, from 1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestId {
+    pub client: u64,
+    pub command: u64,
+}
 
-    loop {
-        // Read data from the socket
-        let n = socket.read_buf(&mut buffer).await?;
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "client {} command {}", self.client, self.command)
+    }
+}
 
-        if n == 0 {
-            // Connection closed
+/// State shared by every connection, independent of the I/O backend, along
+/// with the protocol handling on top of it
+#[derive(Clone)]
+pub struct Context {
+    pub store: Store,
+    pub config: Arc<Config>,
+    renames: Arc<CommandRenames>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    tracer: Option<Tracer>,
+    recorder: Option<Arc<Recorder>>,
+    upstream: Option<Arc<Upstream>>,
+    write_behind: Option<WriteBehind>,
+    crdt: Option<Arc<Crdt>>,
+    tenants: Arc<Tenants>,
+    backups: Arc<Backups>,
+    /// Clients currently connected
+    clients: Arc<Clients>,
+    /// Id given to the next client accepted
+    next_client_id: Arc<AtomicU64>,
+    /// Set while the server accepts clients, for readiness probes
+    ready: Arc<AtomicBool>,
+    pub shutdown: watch::Sender<bool>,
+}
+
+impl Context {
+    /// Shared state for a server; must be called inside the runtime, which
+    /// runs the trace exporter when `otlp-endpoint` is set, the writes to
+    /// `upstream` with `upstream-write-behind` and those to `crdt-peers`
+    pub fn new(config: Config) -> Self {
+        let store = Store::with_backend(config.keyspace_backend, config.keyspace_shards);
+        Self::with_store(config, store)
+    }
+
+    /// Shared state for a server holding an existing store
+    pub fn with_store(config: Config, store: Store) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        // Read-through and write-behind to the same server count together
+        let upstream_stats = Arc::new(UpstreamStats::default());
+        let write_behind = config
+            .upstream
+            .clone()
+            .filter(|_| config.upstream_write_behind)
+            .map(|addr| {
+                WriteBehind::spawn(
+                    addr,
+                    config.upstream_write_behind_queue,
+                    config.upstream_write_behind_rate,
+                    None,
+                    None,
+                    upstream_stats.clone(),
+                )
+            });
+        let tls = || config.crdt_tls.then(|| PeerTls::load(&config)).transpose();
+        let crdt = match (!config.crdt_peers.is_empty()).then(tls).transpose() {
+            Ok(None) => None,
+            Ok(Some(tls)) => {
+                if config.crdt_secret.is_none() && config.crdt_tls_port == 0 {
+                    warning!(
+                        "Neither crdt-secret nor crdt-tls-port is set, so no peer can merge in"
+                    );
+                }
+                let crdt = Crdt::new(
+                    config.crdt_node_id,
+                    &config.crdt_peers,
+                    tls,
+                    config.crdt_secret.as_deref(),
+                );
+                Some(Arc::new(crdt))
+            }
+            // Never falling back to plain TCP; `run` then refuses to start
+            Err(e) => {
+                warning!("Not replicating to crdt-peers: {}", e);
+                None
+            }
+        };
+        let tenants = Arc::new(Tenants::new(&config.tenants));
+        let backups = Arc::new(Backups::new(
+            store.clone(),
+            config.dir.clone(),
+            config.backup_schedule.clone(),
+            Retention {
+                daily: config.backup_keep_daily,
+                weekly: config.backup_keep_weekly,
+            },
+            config.encryption_key.clone(),
+        ));
+        store.set_ttl_jitter(config.ttl_jitter);
+        store.set_size_limits(config.max_key_size, config.max_value_size);
+        store
+            .load_shedder()
+            .set_limits(config.shed_latency, config.shed_queue_depth);
+        Self {
+            store,
+            renames: Arc::new(CommandRenames::new(&config.rename_commands)),
+            rate_limiter: config.client_rate_limit.map(|(ops, burst)| {
+                Arc::new(RateLimiter::new(ops, burst, config.client_rate_limit_mode))
+            }),
+            tracer: config
+                .otlp_endpoint
+                .clone()
+                .map(|endpoint| Tracer::spawn(endpoint, config.otlp_service_name.clone())),
+            recorder: config
+                .record_file
+                .as_deref()
+                .filter(|path| {
+                    // Recording starts by emptying the file, before the restore reads it
+                    let restoring = config.restore_file.as_deref() == Some(path);
+                    if restoring {
+                        warning!("Not recording commands: record-file is the restore-file");
+                    }
+                    !restoring
+                })
+                .and_then(
+                    |path| match Recorder::create(path, config.encryption_key.as_ref()) {
+                        Ok(recorder) => {
+                            notice!("Recording commands to {}", path.display());
+                            Some(Arc::new(recorder))
+                        }
+                        Err(e) => {
+                            warning!("Not recording commands: {}", e);
+                            None
+                        }
+                    },
+                ),
+            upstream: config
+                .upstream
+                .clone()
+                .filter(|_| config.upstream_read_through)
+                .map(|addr| {
+                    Arc::new(Upstream::new(
+                        addr,
+                        config.upstream_ttl,
+                        upstream_stats.clone(),
+                    ))
+                }),
+            write_behind,
+            crdt,
+            tenants,
+            backups,
+            clients: Arc::new(Clients::default()),
+            next_client_id: Arc::new(AtomicU64::new(1)),
+            ready: Arc::new(AtomicBool::new(false)),
+            config: Arc::new(config),
+            shutdown,
+        }
+    }
+
+    /// Apply the allowlist, protected mode, load shedding and `maxclients`
+    /// to a new client
+    pub fn admit(&self, addr: SocketAddr) -> Admission {
+        if !self.client_allowed(addr.ip()) {
+            notice!("Rejected connection from {} (not in allowlist)", addr);
+            return Admission::Reject;
+        }
+        // Clients must log in as a tenant, so there is a password
+        let password_required = self.config.tenant_required && !self.tenants.is_empty();
+        if self.config.protected_mode
+            && !password_required
+            && !addr.ip().to_canonical().is_loopback()
+        {
+            notice!("Rejected connection from {} (protected mode)", addr);
+            return Admission::Deny(PROTECTED_MODE_ERROR);
+        }
+        // Not logged: while overloaded that would be a line per connection
+        if self.store.load_shedder().shed_connection() {
+            return Admission::Deny(OVERLOADED_ERROR);
+        }
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let Some(slot) = ClientSlot::reserve(&self.clients, self.config.maxclients, id, addr)
+        else {
+            warning!(
+                "Rejected connection from {} (max number of clients reached)",
+                addr
+            );
+            return Admission::Deny(MAX_CLIENTS_ERROR);
+        };
+        notice!("Accepted connection from {} as client {}", addr, id);
+        Admission::Accept(slot)
+    }
+
+    /// Whether the server is accepting clients
+    pub fn ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Clients currently connected
+    pub fn connected_clients(&self) -> usize {
+        self.clients.connected.load(Ordering::Relaxed)
+    }
+
+    /// Every connected client, oldest first
+    pub fn client_list(&self) -> Vec<ClientInfo> {
+        self.clients
+            .list
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Wait for every client to disconnect, but no longer than
+    /// `shutdown-drain-timeout`. Returns how many are still connected.
+    pub async fn drain(&self) -> usize {
+        let clients = &self.clients;
+        let all_gone = async {
+            loop {
+                // Register before checking, so the last disconnect isn't missed
+                let notified = clients.all_gone.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if clients.connected.load(Ordering::Acquire) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(self.config.shutdown_drain_timeout, all_gone).await;
+        clients.connected.load(Ordering::Acquire)
+    }
+
+    /// Check a client address against the configured CIDR allowlist
+    fn client_allowed(&self, ip: IpAddr) -> bool {
+        self.config.allowlist.is_empty() || self.config.allowlist.iter().any(|net| net.contains(ip))
+    }
+
+    /// Take the tenants from `aclfile` in place of the `tenant` directives,
+    /// once it has been saved to
+    fn load_acl_file(&self) -> Result<()> {
+        let Some(path) = &self.config.aclfile else {
+            return Ok(());
+        };
+        if !path.exists() {
+            notice!("No users saved to {} yet", path.display());
+            return Ok(());
+        }
+        let specs = acl::load(path)?;
+        self.tenants.replace(&specs);
+        notice!("Loaded {} users from {}", specs.len(), path.display());
+        Ok(())
+    }
+
+    /// Merge a change sent by an active-active peer
+    async fn merge(&self, op: Op) -> RespValue {
+        match &self.crdt {
+            Some(crdt) => match crdt.merge(&self.store, op).await {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => e.into(),
+            },
+            None => CommandError::CrdtDisabled.into(),
+        }
+    }
+
+    /// Answer a request a peer sent on `crdt-tls-port`, which takes
+    /// `CRDT MERGE` and nothing else
+    pub(crate) async fn peer_request(&self, request: RespValue) -> RespValue {
+        match Command::from_resp(request) {
+            Ok(Command::CrdtMerge(op)) => self.merge(op).await,
+            Ok(_) => CommandError::CrdtTlsPortMergeOnly.into(),
+            Err(e) => RespValue::Error(e.to_string()),
+        }
+    }
+
+    /// Start the background task measuring each tenant's keys and memory
+    pub fn start_tenant_measurement(&self) -> JoinHandle<()> {
+        Tenants::start_measurement(self.tenants.clone(), self.store.clone())
+    }
+
+    /// Load the fixture in `seed-file` into the store
+    pub async fn seed(&self) -> Result<()> {
+        let Some(path) = &self.config.seed_file else {
+            return Ok(());
+        };
+        let seeded = seed::load(&self.store, path).await?;
+        notice!("Seeded from {}: {}", path.display(), seeded);
+        Ok(())
+    }
+
+    /// Replay the writes recorded in `restore-file` into the store, up to
+    /// `restore-until` and `restore-until-offset`
+    pub async fn restore(&self) -> Result<()> {
+        let Some(path) = &self.config.restore_file else {
             return Ok(());
+        };
+        let config = &self.config;
+        let mut recording = Recording::load_with(path, config.encryption_key.as_ref())?;
+        restore::cut(
+            &mut recording,
+            config.restore_until,
+            config.restore_until_offset,
+        );
+        let restored = restore::replay(&self.store, &self.renames, &recording).await;
+        notice!("Restored from {}: {}", path.display(), restored);
+        Ok(())
+    }
+
+    /// Send a DEL for each key that expires to the recording and upstream,
+    /// as Redis sends one to its replicas and AOF, until the guard is
+    /// dropped; a restore would otherwise restart the key's TTL. Upstream
+    /// only hears of it without read-through, as keys read through expire
+    /// here when their copy goes stale, not upstream.
+    pub(crate) fn propagate_expiry(&self) -> Option<ExpiryHookGuard> {
+        let write_behind = self
+            .write_behind
+            .clone()
+            .filter(|_| self.upstream.is_none());
+        if self.recorder.is_none() && write_behind.is_none() {
+            return None;
         }
+        let recorder = self.recorder.clone();
+        Some(self.store.hook_expiry(move |key| {
+            let del = RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(Bytes::from_static(b"DEL"))),
+                RespValue::BulkString(Some(key.clone())),
+            ]));
+            if let Some(recorder) = &recorder {
+                recorder.record(SERVER_CLIENT, &del);
+            }
+            if let Some(write_behind) = &write_behind {
+                write_behind.forward(&del);
+            }
+        }))
+    }
+
+    /// Start the background task taking backups on `backup-schedule`, if set
+    pub fn start_backups(&self) -> Option<JoinHandle<()>> {
+        Backups::start(self.backups.clone())
+    }
+
+    /// Start the background task dropping old tombstones of active-active
+    /// replication, if it's on
+    pub fn start_crdt_pruning(&self) -> Option<JoinHandle<()>> {
+        self.crdt.clone().map(Crdt::start_pruning)
+    }
+
+    /// Start the background task judging whether the server is overloaded
+    pub fn start_load_shedding(&self) -> JoinHandle<()> {
+        LoadShedder::start(self.store.load_shedder().clone())
+    }
 
-        // Try to parse RESP values from the buffer
+    /// Execute every complete frame in `buffer`, sent on `connection`,
+    /// collecting the replies so that a whole pipeline is answered with a
+    /// single write
+    pub async fn process(
+        &self,
+        connection: &mut ConnectionContext,
+        buffer: &mut BytesMut,
+        replies: &mut ReplyBuffer,
+    ) -> Flow {
+        let received = buffer.len();
+        let budget = self.config.client_pipeline_budget;
+        let mut ran = 0;
         while !buffer.is_empty() {
-            match RespValue::parse(&mut buffer)? {
-                Some((value, consumed)) => {
+            let parsed = match RespValue::parse_with_limits(buffer, &self.config.proto_limits) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    // A malformed frame leaves us unable to find the next command
+                    // boundary, so like Redis we report the error and close
+                    let request = RequestId {
+                        client: connection.id,
+                        command: connection.commands + 1,
+                    };
+                    notice!("Closing {} on protocol error: {}", request, e);
+                    connection.reply(RespValue::Error(e.to_string()), replies);
+                    return Flow::Close;
+                }
+            };
+
+            match parsed {
+                // The parser already split the frame off the buffer
+                Some((value, _)) => {
+                    // A giant pipeline would otherwise hold its worker
+                    // thread, and every connection queued behind it, until
+                    // the whole buffer is drained
+                    if budget > 0 && ran == budget {
+                        tokio::task::yield_now().await;
+                        ran = 0;
+                    }
+                    ran += 1;
+                    connection.commands += 1;
+                    if let Some(limiter) = &self.rate_limiter {
+                        match limiter.check(connection.peer) {
+                            Decision::Allow => {}
+                            Decision::Delay(wait) => tokio::time::sleep(wait).await,
+                            Decision::Reject => {
+                                connection.reply(CommandError::RateLimited.into(), replies);
+                                continue;
+                            }
+                        }
+                    }
+
                     // We got a complete RESP value
-                    let response = match Command::from_resp(value) {
-                        Ok(cmd) => cmd.execute(&store).await,
+                    if let Some(recorder) = &self.recorder {
+                        recorder.record(connection.id, &value);
+                    }
+                    // Writes to send upstream once they succeed here, as
+                    // resolved, since upstream knows no renames
+                    let mut write = None;
+                    let request = self.renames.resolve(value).and_then(|value| {
+                        let spec = request_spec(&value);
+                        if self.write_behind.is_some()
+                            && spec
+                                .is_some_and(|(spec, _)| spec.flags.contains(CommandFlags::WRITE))
+                        {
+                            write = Some(value.clone());
+                        }
+                        Command::from_resp(value)
+                            .inspect_err(|_| {
+                                if let Some((spec, argc)) = spec {
+                                    self.count_invalid_call(spec, argc);
+                                }
+                            })
+                            .and_then(|cmd| self.authorize(connection, cmd))
+                    });
+                    // Injected faults spare DEBUG, so they can always be turned off
+                    let chaos = self.store.chaos();
+                    if !matches!(request, Ok(Command::Debug(_))) {
+                        if chaos.should_drop() {
+                            replies.clear();
+                            return Flow::Drop;
+                        }
+                        if let Some(delay) = chaos.latency() {
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                    let response = match request {
+                        Ok(Command::Shutdown(mode)) => {
+                            // Like Redis, a successful SHUTDOWN never replies,
+                            // but earlier commands in the pipeline still do.
+                            // SAVE writes a backup first, and like Redis stays
+                            // up if it can't.
+                            if mode != ShutdownMode::Save || self.backups.take_now().await {
+                                return Flow::Shutdown;
+                            }
+                            CommandError::ShutdownSaveFailed.into()
+                        }
+                        Ok(Command::Quit) => {
+                            connection.reply(Command::Quit.execute(&self.store).await, replies);
+                            return Flow::Close;
+                        }
+                        Ok(Command::Auth(username, password)) if !self.tenants.is_empty() => {
+                            match self.tenants.authenticate(username.as_deref(), &password) {
+                                Ok(tenant) => {
+                                    connection.tenant = Some(tenant);
+                                    RespValue::SimpleString("OK".to_string())
+                                }
+                                Err(e) => RespValue::Error(e.to_string()),
+                            }
+                        }
+                        Ok(cmd) => self.execute(connection, cmd).await,
                         Err(e) => RespValue::Error(e.to_string()),
                     };
+                    if let (Some(write_behind), Some(write)) = (&self.write_behind, write)
+                        && !matches!(response, RespValue::Error(_))
+                    {
+                        write_behind.forward(&write);
+                    }
 
-                    // Send the response
-                    socket.write_all(&response.serialize()).await?;
-
-                    // Remove the consumed bytes from the buffer
-                    buffer.advance(consumed);
+                    connection.reply(response, replies);
                 }
                 None => {
                     // Need more data, break and read more
@@ -82,5 +741,1269 @@ async fn handle_connection(mut socket: TcpStream, store: Store) -> Result<()> {
                 }
             }
         }
+        connection.track_frame(
+            buffer.len(),
+            buffer.len() < received,
+            self.config.client_frame_timeout,
+        );
+        Flow::Read
+    }
+
+    /// Log and count the closing of a client that didn't finish the frame it
+    /// started within `client-frame-timeout`
+    pub fn close_unfinished_frame(&self, connection: &ConnectionContext, buffered: usize) {
+        self.store.count_frame_timeout();
+        warning!(
+            "Closing client {} after command {}, which left a frame unfinished for {:?} ({} bytes buffered)",
+            connection.id,
+            connection.commands,
+            self.config.client_frame_timeout,
+            buffered
+        );
+    }
+}
+
+impl Context {
+    /// Hold a command to the namespace and quotas of the tenant the client
+    /// logged in as, or refuse it if clients must log in and this one hasn't
+    fn authorize(&self, connection: &ConnectionContext, cmd: Command) -> Result<Command> {
+        match &connection.tenant {
+            Some(tenant) => tenant.admit(&cmd).map(|()| cmd),
+            None if connection.crdt_peer && matches!(cmd, Command::CrdtMerge(_)) => Ok(cmd),
+            None if self.config.tenant_required
+                && !matches!(
+                    cmd,
+                    Command::Auth(..) | Command::CrdtAuth(_) | Command::Quit
+                ) =>
+            {
+                Err(CommandError::NoAuth.into())
+            }
+            None => Ok(cmd),
+        }
+    }
+
+    /// Run a command, counting it in the command statistics and recording a
+    /// trace span for it when tracing is enabled
+    async fn execute(&self, connection: &mut ConnectionContext, cmd: Command) -> RespValue {
+        let flags = lookup_command(cmd.name()).map_or(CommandFlags::NONE, |spec| spec.flags);
+        let shedder = self.store.load_shedder();
+        if shedder.shed_command(flags) {
+            self.store.command_stats().reject(cmd.name());
+            return CommandError::Busy.into();
+        }
+        let (start, timer) = (SystemTime::now(), Instant::now());
+        let response = match &cmd {
+            Command::ConfigGet(patterns) => RespValue::Array(Some(
+                self.config
+                    .matching(patterns)
+                    .into_iter()
+                    .flat_map(|(name, value)| {
+                        [Bytes::from_static(name.as_bytes()), Bytes::from(value)]
+                    })
+                    .map(|value| RespValue::BulkString(Some(value)))
+                    .collect(),
+            )),
+            Command::Bgsave if self.backups.claim() => {
+                let backups = self.backups.clone();
+                tokio::spawn(async move { backups.take().await });
+                RespValue::SimpleString("Background saving started".to_string())
+            }
+            Command::Bgsave => CommandError::BackupInProgress.into(),
+            // With TLS between peers, only links verified on `crdt-tls-port`
+            // may merge; `peer_request` answers those
+            Command::CrdtMerge(_) if self.config.crdt_tls || self.config.crdt_tls_port != 0 => {
+                CommandError::CrdtMergeOverTlsOnly.into()
+            }
+            Command::CrdtMerge(_) if !connection.crdt_peer => CommandError::CrdtPeerUnproven.into(),
+            Command::CrdtMerge(op) => self.merge(op.clone()).await,
+            Command::CrdtAuth(secret) => match &self.config.crdt_secret {
+                Some(expected) if expected == secret => {
+                    connection.crdt_peer = true;
+                    RespValue::SimpleString("OK".to_string())
+                }
+                _ => CommandError::WrongPass.into(),
+            },
+            Command::Info(sections) => {
+                let sources = InfoSources {
+                    backups: Some(self.backups.status()),
+                    upstream: match (&self.upstream, &self.write_behind) {
+                        (Some(upstream), _) => Some(upstream.stats()),
+                        (None, Some(write_behind)) => Some(write_behind.stats()),
+                        (None, None) => None,
+                    },
+                    tenants: Some(self.tenants.stats()),
+                    crdt: self.crdt.as_deref().map(Crdt::stats),
+                };
+                let reply = info(sections, &self.store, sources).await;
+                RespValue::BulkString(Some(Bytes::from(reply)))
+            }
+            Command::Select(index) => {
+                let response = cmd.execute(&self.store).await;
+                if !matches!(response, RespValue::Error(_)) {
+                    connection.db = *index as u32;
+                }
+                response
+            }
+            Command::AclSave => match &self.config.aclfile {
+                Some(path) => match acl::save(path, &self.tenants.specs()) {
+                    Ok(()) => RespValue::SimpleString("OK".to_string()),
+                    Err(e) => {
+                        warning!("ACL SAVE failed: {}", e);
+                        CommandError::AclSaveFailed.into()
+                    }
+                },
+                None => CommandError::NoAclFile.into(),
+            },
+            Command::AclLoad => match &self.config.aclfile {
+                Some(path) => match acl::load(path) {
+                    Ok(specs) => {
+                        self.tenants.replace(&specs);
+                        RespValue::SimpleString("OK".to_string())
+                    }
+                    Err(e) => CommandError::AclLoadFailed(e.to_string()).into(),
+                },
+                None => CommandError::NoAclFile.into(),
+            },
+            _ => {
+                let tenant = connection.tenant.as_deref();
+                let run = async {
+                    match tenant {
+                        Some(tenant) => tenant.execute(&cmd, &self.store).await,
+                        None => cmd.execute(&self.store).await,
+                    }
+                };
+                let budget = self.config.command_time_budget;
+                if let Some(crdt) = &self.crdt
+                    && flags.contains(CommandFlags::WRITE)
+                {
+                    crdt.write(&self.store, &cmd, run).await
+                } else if flags.contains(CommandFlags::BLOCKING) {
+                    // A parked client would otherwise hold up the shutdown
+                    // drain until its command times out
+                    let mut shutdown = self.shutdown.subscribe();
+                    tokio::select! {
+                        response = run => response,
+                        _ = shutdown.wait_for(|shutdown| *shutdown) => CommandError::Unblocked.into(),
+                    }
+                } else if !budget.is_zero()
+                    && flags.contains(CommandFlags::READONLY)
+                    && !flags.contains(CommandFlags::FAST)
+                {
+                    // Slow reads yield as they go, so the budget can cut
+                    // them short; aborting one leaves nothing half written
+                    tokio::time::timeout(budget, run).await.unwrap_or_else(|_| {
+                        CommandError::TimeBudgetExceeded(budget.as_millis()).into()
+                    })
+                } else {
+                    run.await
+                }
+            }
+        };
+        let response = match (&cmd, &self.upstream) {
+            (Command::Get(key), Some(upstream)) if response == RespValue::BulkString(None) => {
+                upstream.read_through(&self.store, key).await
+            }
+            _ => response,
+        };
+        let elapsed = timer.elapsed();
+        let failed = matches!(response, RespValue::Error(_));
+        // Blocking commands are slow on purpose
+        if !flags.contains(CommandFlags::BLOCKING) {
+            shedder.record(elapsed);
+        }
+
+        self.store.command_stats().record(
+            cmd.name(),
+            elapsed,
+            failed,
+            self.config.latency_tracking,
+        );
+        if let Some(tracer) = &self.tracer {
+            let span = Span::new(
+                cmd.name(),
+                connection.peer,
+                connection.request_id(),
+                cmd.key_count(),
+                start,
+                elapsed,
+                failed,
+            );
+            tracer.record(span);
+        }
+        response
+    }
+
+    /// Count a call whose arguments didn't parse: like Redis, a wrong number
+    /// of arguments is a rejected call, any other bad argument a failed one
+    fn count_invalid_call(&self, spec: &CommandSpec, argc: usize) {
+        let stats = self.store.command_stats();
+        if spec.arity_matches(argc) {
+            stats.record(spec.name, Duration::ZERO, true, false);
+        } else {
+            stats.reject(spec.name);
+        }
+    }
+}
+
+/// Builds a `Server` from code rather than a config file:
+/// `Server::builder().port(0).store(store).bind().await`
+#[derive(Default)]
+pub struct ServerConfig {
+    config: Config,
+    store: Option<Store>,
+}
+
+impl ServerConfig {
+    /// Start from the default configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Interface to listen on, `127.0.0.1` by default
+    pub fn address(mut self, bind: impl Into<String>) -> Self {
+        self.config.bind = bind.into();
+        self
+    }
+
+    /// Port to listen on; 0 picks a free one, see `Server::local_addr`
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Refuse clients beyond this many connected at once
+    pub fn max_clients(mut self, max: usize) -> Self {
+        self.config.maxclients = max;
+        self
+    }
+
+    /// Close clients idle for longer than this (zero disables)
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// TCP keepalive idle time for client sockets (zero disables)
+    pub fn tcp_keepalive(mut self, keepalive: Duration) -> Self {
+        self.config.tcp_keepalive = keepalive;
+        self
+    }
+
+    /// Directory and file name of the snapshot
+    pub fn persistence(mut self, dir: impl Into<PathBuf>, dbfilename: impl Into<String>) -> Self {
+        self.config.dir = dir.into();
+        self.config.dbfilename = dbfilename.into();
+        self
+    }
+
+    /// Serve an existing store, e.g. one seeded with data, instead of a new one
+    pub fn store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Change any other setting
+    pub fn config(mut self, change: impl FnOnce(&mut Config)) -> Self {
+        change(&mut self.config);
+        self
+    }
+
+    /// Listen on the configured address
+    pub async fn bind(self) -> Result<Server> {
+        let addr = self.config.addr();
+        let listeners = if self.config.reuseport {
+            // Tokio starts one worker thread per core, so match that
+            let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let mut listeners = Vec::with_capacity(workers);
+            for _ in 0..workers {
+                listeners.push(Arc::new(bind_reuseport(&addr).await?));
+            }
+            notice!(
+                "Rudis server listening on {} ({} SO_REUSEPORT listeners)",
+                addr,
+                workers
+            );
+            listeners
+        } else {
+            let listener = TcpListener::bind(&addr).await?;
+            notice!("Rudis server listening on {}", listener.local_addr()?);
+            vec![Arc::new(listener)]
+        };
+        Ok(self.serve(listeners))
+    }
+
+    /// Serve clients accepted from an already bound listener
+    pub fn listener(self, listener: TcpListener) -> Server {
+        self.serve(vec![Arc::new(listener)])
+    }
+
+    fn serve(self, listeners: Vec<Arc<TcpListener>>) -> Server {
+        let store = self.store.unwrap_or_else(|| {
+            Store::with_backend(self.config.keyspace_backend, self.config.keyspace_shards)
+        });
+        Server {
+            listeners: Mutex::new(listeners),
+            context: Context::with_store(self.config, store),
+        }
+    }
+}
+
+impl From<Config> for ServerConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            config,
+            store: None,
+        }
+    }
+}
+
+pub struct Server {
+    /// Closed once the server shuts down
+    listeners: Mutex<Vec<Arc<TcpListener>>>,
+    context: Context,
+}
+
+impl Server {
+    /// Create a new Redis server listening on the configured address
+    pub async fn new(config: Config) -> Result<Self> {
+        ServerConfig::from(config).bind().await
+    }
+
+    /// Listen on `addr` with otherwise default settings
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        ServerConfig::new()
+            .address(addr.ip().to_string())
+            .port(addr.port())
+            .bind()
+            .await
+    }
+
+    /// Serve clients accepted from an already bound listener
+    pub fn from_listener(listener: TcpListener, config: Config) -> Self {
+        ServerConfig::from(config).listener(listener)
+    }
+
+    /// Configure a server from code
+    pub fn builder() -> ServerConfig {
+        ServerConfig::new()
+    }
+
+    /// Address the server is listening on, with the actual port when bound to port 0
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self.listeners.lock().unwrap().first() {
+            Some(listener) => listener.local_addr(),
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// The keyspace served to clients
+    pub fn store(&self) -> &Store {
+        &self.context.store
+    }
+
+    /// Run the server, accepting connections until SHUTDOWN or SIGTERM/SIGINT,
+    /// once `seed-file` is loaded and the writes recorded in `restore-file`
+    /// are replayed. Shutting down stops accepting, then gives connected clients up to
+    /// `shutdown-drain-timeout` to receive the replies they are owed.
+    pub async fn run(&self) -> Result<()> {
+        self.context.load_acl_file()?;
+        self.context.seed().await?;
+        self.context.restore().await?;
+        let _expiry = self.context.propagate_expiry();
+        let _probe = probe::spawn(&self.context)?;
+        let admin_handle = admin::spawn(&self.context).await?;
+        let peer_tls_handle = tls::spawn(&self.context).await?;
+        // Start active expiration background task
+        let expiration_handle = Store::start_active_expiration(self.context.store.clone());
+        let compaction_handle = Store::start_compaction(self.context.store.clone());
+        let tenants_handle = self.context.start_tenant_measurement();
+        let shedding_handle = self.context.start_load_shedding();
+        let backup_handle = self.context.start_backups();
+        let crdt_handle = self.context.start_crdt_pruning();
+        let log_reopen_handle = crate::log::spawn_reopen_on_signal();
+        let _watchdog = watchdog::spawn(tokio::runtime::Handle::current());
+        let mut shutdown_rx = self.context.shutdown.subscribe();
+
+        let mut acceptors = JoinSet::new();
+        for listener in self.listeners.lock().unwrap().iter() {
+            acceptors.spawn(accept_loop(listener.clone(), self.context.clone()));
+        }
+        self.context.set_ready(true);
+        systemd::notify(self.context.config.supervised, systemd::READY);
+
+        let result = tokio::select! {
+            // Accept loops only return when accepting fails
+            Some(joined) = acceptors.join_next() => joined?.map_err(Into::into),
+            _ = shutdown_rx.changed() => Ok(()),
+            signal = shutdown_signal() => {
+                notice!("Received {}, shutting down", signal);
+                self.context.shutdown.send_replace(true);
+                Ok(())
+            }
+        };
+
+        self.context.set_ready(false);
+        systemd::notify(self.context.config.supervised, systemd::STOPPING);
+        // Close the listeners, so new clients are refused rather than queued
+        acceptors.shutdown().await;
+        self.listeners.lock().unwrap().clear();
+        drain(&self.context).await;
+        expiration_handle.abort();
+        compaction_handle.abort();
+        tenants_handle.abort();
+        shedding_handle.abort();
+        log_reopen_handle.abort();
+        if let Some(backup_handle) = backup_handle {
+            backup_handle.abort();
+        }
+        if let Some(crdt_handle) = crdt_handle {
+            crdt_handle.abort();
+        }
+        if let Some(admin_handle) = admin_handle {
+            admin_handle.abort();
+        }
+        if let Some(peer_tls_handle) = peer_tls_handle {
+            peer_tls_handle.abort();
+        }
+        notice!("Rudis is now ready to exit, bye bye...");
+        result
+    }
+}
+
+/// Let connected clients finish after the listeners have closed, logging
+/// those that outstay the drain timeout
+pub async fn drain(context: &Context) {
+    let remaining = context.drain().await;
+    if remaining > 0 {
+        warning!(
+            "{} clients still connected after {:?}, closing them",
+            remaining,
+            context.config.shutdown_drain_timeout
+        );
+    }
+}
+
+/// Bind a listener with SO_REUSEPORT set, so several can share the address
+/// and the kernel balances incoming connections across them
+#[cfg(unix)]
+async fn bind_reuseport(addr: &str) -> Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Can't resolve bind address '{}'", addr))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(not(unix))]
+async fn bind_reuseport(_addr: &str) -> Result<TcpListener> {
+    Err(anyhow::anyhow!(
+        "SO_REUSEPORT is not supported on this platform"
+    ))
+}
+
+/// Accept clients from one listener, spawning a task per connection
+async fn accept_loop(listener: Arc<TcpListener>, context: Context) -> std::io::Result<()> {
+    loop {
+        let (mut socket, addr) = listener.accept().await?;
+
+        let slot = match context.admit(addr) {
+            Admission::Accept(slot) => slot,
+            Admission::Reject => continue,
+            Admission::Deny(error) => {
+                tokio::spawn(async move {
+                    let _ = socket.write_all(error.as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                });
+                continue;
+            }
+        };
+        if let Err(e) = configure_socket(SockRef::from(&socket), &context.config) {
+            warning!("Failed to set socket options for {}: {}", addr, e);
+        }
+
+        // Spawn a new task to handle this connection
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(socket, addr.ip(), slot.id(), context).await {
+                warning!("Error handling client {}: {}", slot.id(), e);
+            }
+            drop(slot);
+        });
+    }
+}
+
+/// Apply the configured TCP options to an accepted client socket
+pub fn configure_socket(sock: SockRef<'_>, config: &Config) -> std::io::Result<()> {
+    sock.set_tcp_nodelay(config.tcp_nodelay)?;
+
+    if !config.tcp_keepalive.is_zero() {
+        let keepalive = TcpKeepalive::new().with_time(config.tcp_keepalive);
+        // Like Redis, probe three times within one keepalive period
+        #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+        let keepalive =
+            keepalive.with_interval((config.tcp_keepalive / 3).max(Duration::from_secs(1)));
+        sock.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(linger) = config.so_linger {
+        sock.set_linger(Some(linger))?;
+    }
+    Ok(())
+}
+
+/// Wait for SIGINT (Ctrl-C) or, on Unix, SIGTERM
+pub async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                return "SIGINT";
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = sigterm.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+/// Read up to the connection's read size, and at most `max` more bytes, into
+/// the buffer, giving up with None once the connection has been idle for
+/// `timeout` (a zero timeout waits forever). An empty buffer goes back to the
+/// pool while the socket has nothing to read.
+async fn read_with_timeout(
+    reader: &mut OwnedReadHalf,
+    buffer: &mut BytesMut,
+    size: &mut ReadSize,
+    max: usize,
+    timeout: Duration,
+) -> std::io::Result<Option<usize>> {
+    let read = async {
+        loop {
+            let room = size.current().min(max);
+            if buffer.capacity() == 0 {
+                *buffer = bufpool::acquire(room);
+            }
+            buffer.reserve(room);
+            match reader.try_read_buf(&mut buffer.limit(room)) {
+                Ok(n) => {
+                    size.read(n, room);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    bufpool::release(buffer);
+                    let waiting = Instant::now();
+                    reader.readable().await?;
+                    size.idled(waiting.elapsed());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    if timeout.is_zero() {
+        return read.await.map(Some);
+    }
+    match tokio::time::timeout(timeout, read).await {
+        Ok(n) => n.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Write reply batches to the client in order until every sender is gone,
+/// then close the write half
+async fn write_replies(
+    mut writer: OwnedWriteHalf,
+    mut batches: mpsc::Receiver<Vec<Bytes>>,
+    chaos: Arc<Chaos>,
+) -> std::io::Result<()> {
+    while let Some(mut batch) = batches.recv().await {
+        match chaos.write_limit() {
+            Some(limit) => write_in_pieces(&mut writer, &batch, limit).await?,
+            None => write_all_vectored(&mut writer, &mut batch).await?,
+        }
+    }
+    writer.shutdown().await
+}
+
+/// Write the chunks `limit` bytes at a time, pausing between writes so each
+/// piece reaches the client on its own, for DEBUG CHAOS PARTIAL-WRITES
+async fn write_in_pieces(
+    writer: &mut OwnedWriteHalf,
+    chunks: &[Bytes],
+    limit: usize,
+) -> std::io::Result<()> {
+    for chunk in chunks {
+        for piece in chunk.chunks(limit) {
+            writer.write_all(piece).await?;
+            writer.flush().await?;
+            tokio::time::sleep(PARTIAL_WRITE_PAUSE).await;
+        }
+    }
+    Ok(())
+}
+
+/// Write every chunk, handing the kernel up to `MAX_IOVECS` of them per call
+async fn write_all_vectored(
+    writer: &mut OwnedWriteHalf,
+    chunks: &mut [Bytes],
+) -> std::io::Result<()> {
+    let mut first = 0;
+    while first < chunks.len() {
+        let mut slices = [IoSlice::new(&[]); MAX_IOVECS];
+        let count = (chunks.len() - first).min(MAX_IOVECS);
+        for (slice, chunk) in slices.iter_mut().zip(&chunks[first..first + count]) {
+            *slice = IoSlice::new(chunk);
+        }
+        let mut written = writer.write_vectored(&slices[..count]).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+
+        // Skip fully written chunks and trim a partially written one
+        while written > 0 {
+            let chunk = &mut chunks[first];
+            if written >= chunk.len() {
+                written -= chunk.len();
+                first += 1;
+            } else {
+                chunk.advance(written);
+                written = 0;
+            }
+        }
+        // Empty chunks need no write
+        while first < chunks.len() && chunks[first].is_empty() {
+            first += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Hand the accumulated replies to the writer task, a batch at a time, so
+/// a long reply is serialized no faster than the client reads it. Returns
+/// false if the writer has stopped because the client went away.
+async fn queue_replies(queue: &mpsc::Sender<Vec<Bytes>>, replies: &mut ReplyBuffer) -> bool {
+    while !replies.is_empty() {
+        if queue.send(replies.take()).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Close the reply queue and wait for the writer to flush it and close the socket
+async fn finish_writes(
+    queue: mpsc::Sender<Vec<Bytes>>,
+    writer: JoinHandle<std::io::Result<()>>,
+) -> Result<()> {
+    drop(queue);
+    writer.await??;
+    Ok(())
+}
+
+/// Flush the replies owed as the server shuts down, closing the connection
+/// without the rest if that takes longer than the drain timeout
+async fn drain_writes(
+    queue: mpsc::Sender<Vec<Bytes>>,
+    writer: JoinHandle<std::io::Result<()>>,
+    timeout: Duration,
+) -> Result<()> {
+    let abort = writer.abort_handle();
+    match tokio::time::timeout(timeout, finish_writes(queue, writer)).await {
+        Ok(finished) => finished,
+        Err(_) => {
+            abort.abort();
+            Ok(())
+        }
+    }
+}
+
+// Handle a single client connection. This task reads, parses and executes
+// commands while a writer task sends the replies, so a slow client draining
+// earlier replies doesn't hold up execution of its later commands.
+async fn handle_connection(
+    socket: TcpStream,
+    peer: IpAddr,
+    id: u64,
+    context: Context,
+) -> Result<()> {
+    let (mut reader, writer) = socket.into_split();
+    let (queue, batches) = mpsc::channel(REPLY_QUEUE_DEPTH);
+    let writer = tokio::spawn(write_replies(
+        writer,
+        batches,
+        context.store.chaos().clone(),
+    ));
+
+    let config = context.config.clone();
+    let mut buffer = BytesMut::new();
+    let mut size = ReadSize::new(
+        config.client_read_buffer_low,
+        config.client_read_buffer_high,
+    );
+    let mut replies = ReplyBuffer::new();
+    let mut connection = ConnectionContext::new(id, peer);
+    let mut shutdown_rx = context.shutdown.subscribe();
+
+    loop {
+        // Stop reading from a client that keeps sending data we can't parse
+        let room = config
+            .client_query_buffer_limit
+            .saturating_sub(buffer.len());
+        if room == 0 {
+            warning!(
+                "Closing client {} after command {}, which reached max query buffer length ({} bytes)",
+                id,
+                connection.commands(),
+                buffer.len()
+            );
+            return finish_writes(queue, writer).await;
+        }
+
+        // Read data from the socket, or stop once the server is shutting down
+        let Some(timeout) = connection.read_timeout(config.timeout) else {
+            context.close_unfinished_frame(&connection, buffer.len());
+            return finish_writes(queue, writer).await;
+        };
+        let n = tokio::select! {
+            n = read_with_timeout(&mut reader, &mut buffer, &mut size, room, timeout) => match n? {
+                Some(n) => n,
+                None if connection.frame_overdue() => {
+                    context.close_unfinished_frame(&connection, buffer.len());
+                    return finish_writes(queue, writer).await;
+                }
+                // Idle for longer than `timeout`, close like Redis does
+                None => return finish_writes(queue, writer).await,
+            },
+            _ = shutdown_rx.changed() => {
+                return drain_writes(queue, writer, config.shutdown_drain_timeout).await;
+            }
+        };
+
+        if n == 0 {
+            // The client closed its end; it may still read the replies owed
+            return finish_writes(queue, writer).await;
+        }
+
+        let flow = context
+            .process(&mut connection, &mut buffer, &mut replies)
+            .await;
+        let queued = queue_replies(&queue, &mut replies).await;
+        match flow {
+            // Keep reading unless the writer failed because the client is gone
+            Flow::Read if queued => {}
+            Flow::Read | Flow::Close => return finish_writes(queue, writer).await,
+            Flow::Shutdown => {
+                finish_writes(queue, writer).await?;
+                context.shutdown.send_replace(true);
+                return Ok(());
+            }
+            // Abandon the connection as a crashed server would
+            Flow::Drop => {
+                writer.abort();
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::Snapshot;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    async fn request(stream: &mut TcpStream, command: &[u8]) -> String {
+        stream.write_all(command).await.unwrap();
+        let mut reply = vec![0; 512];
+        let n = stream.read(&mut reply).await.unwrap();
+        String::from_utf8_lossy(&reply[..n]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn builder_serves_a_seeded_store_on_port_zero() {
+        let store = Store::new();
+        store.set(Bytes::from("greeting"), Bytes::from("hi")).await;
+        let server = Server::builder().port(0).store(store).bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        tokio::spawn(async move { server.run().await });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let reply = request(&mut client, b"*2\r\n$3\r\nGET\r\n$8\r\ngreeting\r\n").await;
+        assert_eq!(reply, "$2\r\nhi\r\n");
+    }
+
+    #[tokio::test]
+    async fn keeps_a_partial_command_across_reads() {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        // The read buffer holding the first half isn't pooled while the
+        // connection waits for the rest; other clients read meanwhile
+        let value = "v".repeat(2 * Config::default().client_read_buffer_low);
+        let command = format!(
+            "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n{}\r\n",
+            value.len(),
+            value
+        );
+        let (first, second) = command.as_bytes().split_at(command.len() / 2);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(first).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(request(&mut other, b"PING\r\n").await, "+PONG\r\n");
+        assert_eq!(request(&mut client, second).await, "+OK\r\n");
+        assert_eq!(
+            request(&mut other, b"STRLEN k\r\n").await,
+            format!(":{}\r\n", value.len())
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_clients_that_leave_a_frame_unfinished() {
+        let timeout = Duration::from_secs(1);
+        let server = Server::builder()
+            .port(0)
+            .config(|config| config.client_frame_timeout = timeout)
+            .bind()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let store = server.store().clone();
+        tokio::spawn(async move { server.run().await });
+        let mut patient = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(request(&mut patient, b"PING\r\n").await, "+PONG\r\n");
+
+        // A bulk string trickled in is cut off when the frame is due, however
+        // recently the last byte came, after the replies to the commands
+        // before it. Bytes keep coming for 4s, so a deadline each one pushed
+        // back would only pass at 5s, far from the 1s expected.
+        let (mut reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let started = Instant::now();
+        writer
+            .write_all(b"PING\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100\r\n")
+            .await
+            .unwrap();
+        let trickling = tokio::spawn(async move {
+            for _ in 0..16 {
+                tokio::time::sleep(timeout / 4).await;
+                // Fails once the server has closed the connection
+                if writer.write_all(b"v").await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"+PONG\r\n");
+        let elapsed = started.elapsed();
+        assert!(elapsed >= timeout && elapsed < timeout * 3, "{:?}", elapsed);
+        trickling.abort();
+        assert_eq!(store.frame_timeouts(), 1);
+
+        // Whole commands, however far apart, are fine
+        assert_eq!(request(&mut patient, b"PING\r\n").await, "+PONG\r\n");
+        assert_eq!(store.frame_timeouts(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn drains_clients_on_shutdown() {
+        let store = Store::new();
+        let big = Bytes::from(vec![b'x'; 1 << 20]);
+        store.set(Bytes::from("big"), big.clone()).await;
+        let server = Server::builder()
+            .port(0)
+            .store(store)
+            .config(|config| config.shutdown_drain_timeout = Duration::from_millis(300))
+            .bind()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = tokio::spawn(async move { server.run().await });
+
+        // One client reads the replies it's owed as the server stops...
+        let mut patient = TcpStream::connect(addr).await.unwrap();
+        patient.write_all(&b"GET big\r\n".repeat(4)).await.unwrap();
+        let mut received = vec![0; 4096];
+        let first = patient.read(&mut received).await.unwrap();
+        // ...the other is owed far more than the socket buffers hold and never reads
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        stalled.write_all(&b"GET big\r\n".repeat(64)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        admin.write_all(b"SHUTDOWN NOSAVE\r\n").await.unwrap();
+        let stopping = Instant::now();
+        let mut rest = Vec::new();
+        patient.read_to_end(&mut rest).await.unwrap();
+        let reply_len = format!("${}\r\n", big.len()).len() + big.len() + 2;
+        assert_eq!(first + rest.len(), 4 * reply_len);
+
+        // The stalled client is cut off once the drain timeout is up
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(stopping.elapsed() >= Duration::from_millis(250));
+        let mut unread = Vec::new();
+        let _ = stalled.read_to_end(&mut unread).await;
+        assert!(unread.len() < 64 * reply_len);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_save_writes_a_backup() {
+        let dir = std::env::temp_dir().join(format!("rudis-shutdown-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = Server::builder()
+            .port(0)
+            .config(|config| config.dir = dir.clone())
+            .bind()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = tokio::spawn(async move { server.run().await });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(request(&mut client, b"SET k v\r\n").await, "+OK\r\n");
+        client.write_all(b"SHUTDOWN SAVE\r\n").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        let backups: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "snapshot"))
+            .collect();
+        assert_eq!(backups.len(), 1, "{:?}", backups);
+        let snapshot = Snapshot::from_bytes(&std::fs::read(&backups[0]).unwrap()).unwrap();
+        assert_eq!(snapshot.entries.len(), 1);
+        assert_eq!(snapshot.entries[0].key, "k");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unblocks_parked_clients_on_shutdown() {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = tokio::spawn(async move { server.run().await });
+
+        // Waits forever for a replica that will never come
+        let mut blocked = TcpStream::connect(addr).await.unwrap();
+        blocked.write_all(b"WAIT 1 0\r\n").await.unwrap();
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let info = request(&mut admin, b"INFO stats\r\n").await;
+        assert!(info.contains("blocked_clients:1\r\n"), "{}", info);
+
+        admin.write_all(b"SHUTDOWN NOSAVE\r\n").await.unwrap();
+        let mut reply = String::new();
+        blocked.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "-UNBLOCKED the server is shutting down\r\n");
+        // Well before the 10s drain timeout
+        tokio::time::timeout(Duration::from_secs(2), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn numbers_commands_per_connection() {
+        let context = Context::new(Config::default());
+        let peer = IpAddr::from([127, 0, 0, 1]);
+        let mut connection = ConnectionContext::new(7, peer);
+        let mut replies = ReplyBuffer::new();
+
+        // Every frame counts, including ones that fail to parse as commands
+        let mut buffer = BytesMut::from("PING\r\nGET\r\nPING\r\n");
+        context
+            .process(&mut connection, &mut buffer, &mut replies)
+            .await;
+        assert_eq!(connection.commands(), 3);
+        let mut buffer = BytesMut::from("ECHO hi\r\n");
+        context
+            .process(&mut connection, &mut buffer, &mut replies)
+            .await;
+        assert_eq!(connection.commands(), 4);
+        assert_eq!(ConnectionContext::new(8, peer).commands(), 0);
+
+        let request = RequestId {
+            client: 7,
+            command: 4,
+        };
+        assert_eq!(request.to_string(), "client 7 command 4");
+        assert_eq!(connection.request_id(), request);
+    }
+
+    #[tokio::test]
+    async fn yields_to_other_tasks_during_giant_pipelines() {
+        let peer = IpAddr::from([127, 0, 0, 1]);
+        let pipeline = "PING\r\n".repeat(10);
+        for (budget, yields) in [(0, 0), (3, 3), (5, 1)] {
+            let context = Context::new(Config {
+                client_pipeline_budget: budget,
+                ..Config::default()
+            });
+            // Counts the times it gets to run while the pipeline does
+            let counted = Arc::new(AtomicU64::new(0));
+            let counter = counted.clone();
+            let other = tokio::spawn(async move {
+                loop {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            });
+            tokio::task::yield_now().await;
+            let before = counted.load(Ordering::Relaxed);
+
+            let mut connection = ConnectionContext::new(1, peer);
+            let mut buffer = BytesMut::from(pipeline.as_str());
+            let mut replies = ReplyBuffer::new();
+            context
+                .process(&mut connection, &mut buffer, &mut replies)
+                .await;
+            assert_eq!(connection.commands(), 10);
+            assert_eq!(
+                counted.load(Ordering::Relaxed) - before,
+                yields,
+                "budget {}",
+                budget
+            );
+            other.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn takes_merges_only_from_tls_peers_once_configured() {
+        let context = Context::new(Config {
+            crdt_peers: vec!["127.0.0.1:1".to_string()],
+            crdt_tls_port: 16379,
+            ..Config::default()
+        });
+        let merge = "CRDT MERGE REGISTER k 1 0 2 1 v -1\r\n";
+
+        let mut connection = ConnectionContext::new(1, IpAddr::from([10, 0, 0, 2]));
+        let mut replies = ReplyBuffer::new();
+        context
+            .process(&mut connection, &mut BytesMut::from(merge), &mut replies)
+            .await;
+        assert_eq!(
+            replies.take().concat(),
+            format!("-{}\r\n", CommandError::CrdtMergeOverTlsOnly).as_bytes()
+        );
+        assert_eq!(context.store.get(b"k").await, None);
+
+        let mut buffer = BytesMut::from(merge);
+        let (request, _) = RespValue::parse(&mut buffer).unwrap().unwrap();
+        assert_eq!(
+            context.peer_request(request).await,
+            RespValue::SimpleString("OK".to_string())
+        );
+        assert_eq!(context.store.get(b"k").await, Some(Bytes::from("v")));
+
+        let mut buffer = BytesMut::from("GET k\r\n");
+        let (request, _) = RespValue::parse(&mut buffer).unwrap().unwrap();
+        assert_eq!(
+            context.peer_request(request).await,
+            CommandError::CrdtTlsPortMergeOnly.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn takes_merges_only_from_peers_that_proved_the_secret() {
+        for tenant_required in [false, true] {
+            let context = Context::new(Config {
+                crdt_peers: vec!["127.0.0.1:1".to_string()],
+                crdt_secret: Some("shared".to_string()),
+                tenant_required,
+                ..Config::default()
+            });
+            let mut connection = ConnectionContext::new(1, IpAddr::from([10, 0, 0, 2]));
+            let mut replies = ReplyBuffer::new();
+            let mut run = async |connection: &mut ConnectionContext, commands: &str| {
+                let mut buffer = BytesMut::from(commands);
+                context.process(connection, &mut buffer, &mut replies).await;
+                replies.take().concat()
+            };
+            let merge = "CRDT MERGE REGISTER k 1 0 2 1 v -1\r\n";
+            let refused = match tenant_required {
+                false => format!("-{}\r\n", CommandError::CrdtPeerUnproven),
+                true => format!("-{}\r\n", CommandError::NoAuth),
+            };
+            assert_eq!(run(&mut connection, merge).await, refused.as_bytes());
+            assert_eq!(
+                run(&mut connection, "CRDT AUTH guess\r\n").await,
+                format!("-{}\r\n", CommandError::WrongPass).as_bytes()
+            );
+            assert_eq!(run(&mut connection, merge).await, refused.as_bytes());
+            assert_eq!(context.store.get(b"k").await, None);
+
+            // A proven peer merges without logging in as a tenant
+            assert_eq!(
+                run(&mut connection, "CRDT AUTH shared\r\n").await,
+                b"+OK\r\n"
+            );
+            assert_eq!(run(&mut connection, merge).await, b"+OK\r\n");
+            assert_eq!(context.store.get(b"k").await, Some(Bytes::from("v")));
+            if tenant_required {
+                // but can't do anything else without
+                assert_eq!(run(&mut connection, "GET k\r\n").await, refused.as_bytes());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_connection_state_between_commands() {
+        let context = Context::new(Config::default());
+        let mut connection = ConnectionContext::new(1, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(connection.db(), 0);
+        assert!(connection.tenant().is_none());
+
+        let mut replies = ReplyBuffer::new();
+        let mut run = async |connection: &mut ConnectionContext, commands: &str| {
+            let mut buffer = BytesMut::from(commands);
+            context.process(connection, &mut buffer, &mut replies).await;
+            replies.take().concat()
+        };
+        assert_eq!(run(&mut connection, "SELECT 0\r\n").await, b"+OK\r\n");
+        assert_eq!(connection.db(), 0);
+        assert!(
+            run(&mut connection, "SELECT 3\r\n")
+                .await
+                .starts_with(b"-ERR")
+        );
+        assert_eq!(connection.db(), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_over_maxclients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server::builder().max_clients(1).listener(listener);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(request(&mut first, b"PING\r\n").await, "+PONG\r\n");
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, MAX_CLIENTS_ERROR);
+
+        // The slot is given back once the first client leaves
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(request(&mut third, b"PING\r\n").await, "+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn flushes_replies_owed_to_a_client_that_stopped_sending() {
+        let store = Store::new();
+        let big = "x".repeat(64 * 1024);
+        store
+            .set(Bytes::from("big"), Bytes::from(big.clone()))
+            .await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server::builder()
+            .max_clients(1)
+            .store(store)
+            .listener(listener);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        // Owed more than the socket buffers hold, the client closes its
+        // sending side before it reads any
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&b"GET big\r\n".repeat(64)).await.unwrap();
+        client.shutdown().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Its slot is held until the replies are written...
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let mut reply = String::new();
+        tokio::time::timeout(Duration::from_secs(2), other.read_to_string(&mut reply))
+            .await
+            .expect("slot given back before the replies were written")
+            .unwrap();
+        assert_eq!(reply, MAX_CLIENTS_ERROR);
+
+        // ...and every one of them is
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let reply_len = format!("${}\r\n", big.len()).len() + big.len() + 2;
+        assert_eq!(received.len(), 64 * reply_len);
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn injects_faults() {
+        let server = Server::builder().port(0).bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        // Latency
+        request(&mut client, b"DEBUG CHAOS LATENCY 50\r\n").await;
+        let start = Instant::now();
+        assert_eq!(request(&mut client, b"PING\r\n").await, "+PONG\r\n");
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        request(&mut client, b"DEBUG CHAOS LATENCY 0\r\n").await;
+
+        // Partial writes: replies, this one's included, arrive a byte at a time
+        client
+            .write_all(b"DEBUG CHAOS PARTIAL-WRITES 1\r\nPING\r\n")
+            .await
+            .unwrap();
+        let (mut reply, mut reads) = (Vec::new(), 0);
+        while reply.len() < b"+OK\r\n+PONG\r\n".len() {
+            let mut piece = [0; 64];
+            let n = client.read(&mut piece).await.unwrap();
+            reply.extend_from_slice(&piece[..n]);
+            reads += 1;
+        }
+        assert_eq!(reply, b"+OK\r\n+PONG\r\n");
+        assert!(reads > 2, "{} reads", reads);
+        // Turning faults off takes effect before its own reply
+        assert_eq!(
+            request(&mut client, b"DEBUG CHAOS OFF\r\n").await,
+            "+OK\r\n"
+        );
+
+        // Dropped connections, while DEBUG itself is spared
+        assert_eq!(
+            request(&mut client, b"DEBUG CHAOS DROP 1\r\n").await,
+            "+OK\r\n"
+        );
+        client.write_all(b"PING\r\n").await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            request(&mut client, b"DEBUG CHAOS OFF\r\n").await,
+            "+OK\r\n"
+        );
+        assert_eq!(request(&mut client, b"PING\r\n").await, "+PONG\r\n");
     }
 }
@@ -0,0 +1,198 @@
+//! Snapshots of a store's keys, for embedders to checkpoint state, move it
+//! to another process or seed fixtures: `Store::export` takes one and
+//! `Store::import` writes one back.
+//!
+//! TTLs are kept as the time left when the snapshot was taken rather than a
+//! point in time, so a snapshot means the same in any process. Encoded, a
+//! snapshot is a stream of RESP values like a recording: a
+//! `["rudis-snapshot", version]` header, then one `[type, key, value, ttl]`
+//! array per key, `ttl` in milliseconds or -1 for none.
+
+use crate::encryption::{self, EncryptionKey};
+use crate::resp::RespValue;
+use anyhow::{Result, anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &str = "rudis-snapshot";
+const VERSION: i64 = 1;
+
+/// A key's value in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(Bytes),
+}
+
+impl Value {
+    /// The type's name, as TYPE gives it
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+        }
+    }
+}
+
+/// One key in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Bytes,
+    pub value: Value,
+    /// Time the key had left to live when the snapshot was taken
+    pub ttl: Option<Duration>,
+}
+
+impl Entry {
+    fn to_resp(&self) -> RespValue {
+        let Value::String(data) = &self.value;
+        let ttl = self
+            .ttl
+            .map_or(-1, |ttl| ttl.as_millis().min(i64::MAX as u128) as i64);
+        RespValue::Array(Some(vec![
+            RespValue::SimpleString(self.value.type_name().to_string()),
+            RespValue::BulkString(Some(self.key.clone())),
+            RespValue::BulkString(Some(data.clone())),
+            RespValue::Integer(ttl),
+        ]))
+    }
+
+    fn from_resp(value: RespValue) -> Result<Self> {
+        let RespValue::Array(Some(parts)) = value else {
+            bail!("expected a key");
+        };
+        let Ok(
+            [
+                RespValue::SimpleString(kind),
+                RespValue::BulkString(Some(key)),
+                RespValue::BulkString(Some(data)),
+                RespValue::Integer(ttl),
+            ],
+        ) = <[RespValue; 4]>::try_from(parts)
+        else {
+            bail!("expected a key");
+        };
+        let value = match kind.as_str() {
+            "string" => Value::String(data),
+            _ => bail!("unsupported type '{}'", kind),
+        };
+        Ok(Self {
+            key,
+            value,
+            ttl: u64::try_from(ttl).ok().map(Duration::from_millis),
+        })
+    }
+}
+
+/// Every live key in a store, with its value and TTL
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub entries: Vec<Entry>,
+}
+
+impl Snapshot {
+    /// Encode the snapshot, to keep or send elsewhere
+    pub fn to_bytes(&self) -> Bytes {
+        let mut out = BytesMut::new();
+        RespValue::Array(Some(vec![
+            RespValue::SimpleString(MAGIC.to_string()),
+            RespValue::Integer(VERSION),
+        ]))
+        .serialize_into(&mut out);
+        for entry in &self.entries {
+            entry.to_resp().serialize_into(&mut out);
+        }
+        out.freeze()
+    }
+
+    /// Read a snapshot from a file, such as a backup, decrypting it with
+    /// `key` if it was written encrypted
+    pub fn load_with(path: &Path, key: Option<&EncryptionKey>) -> Result<Self> {
+        let mut data =
+            std::fs::read(path).map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+        if encryption::is_encrypted(&data) {
+            let key = key
+                .ok_or_else(|| anyhow!("'{}' is encrypted; set encryption-key", path.display()))?;
+            data = encryption::decrypt(key, &data)
+                .map_err(|e| anyhow!("Can't decrypt '{}': {}", path.display(), e))?;
+        }
+        Self::from_bytes(&data).map_err(|e| anyhow!("'{}': {}", path.display(), e))
+    }
+
+    /// Decode a snapshot encoded by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut data = BytesMut::from(data);
+        let mut next = || match RespValue::parse(&mut data)? {
+            Some((value, _)) => Ok(Some(value)),
+            None if data.is_empty() => Ok(None),
+            None => bail!("snapshot is truncated"),
+        };
+
+        match next()? {
+            Some(RespValue::Array(Some(header))) => match header.as_slice() {
+                [RespValue::SimpleString(magic), RespValue::Integer(VERSION)] if magic == MAGIC => {
+                }
+                [RespValue::SimpleString(magic), RespValue::Integer(version)] if magic == MAGIC => {
+                    bail!("unsupported snapshot version {}", version)
+                }
+                _ => bail!("not a snapshot"),
+            },
+            _ => bail!("not a snapshot"),
+        }
+
+        let mut entries = Vec::new();
+        while let Some(value) = next()? {
+            entries.push(Entry::from_resp(value)?);
+        }
+        Ok(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            entries: vec![
+                Entry {
+                    key: Bytes::from("plain"),
+                    value: Value::String(Bytes::from("value")),
+                    ttl: None,
+                },
+                Entry {
+                    key: Bytes::from("binary\r\n"),
+                    value: Value::String(Bytes::from_static(&[0, 255, b'\n'])),
+                    ttl: Some(Duration::from_millis(1500)),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let encoded = snapshot().to_bytes();
+        assert_eq!(Snapshot::from_bytes(&encoded).unwrap(), snapshot());
+        let empty = Snapshot::default().to_bytes();
+        assert_eq!(Snapshot::from_bytes(&empty).unwrap(), Snapshot::default());
+    }
+
+    #[test]
+    fn rejects_what_it_did_not_write() {
+        let encoded = snapshot().to_bytes();
+        let error = |data: &[u8]| Snapshot::from_bytes(data).unwrap_err().to_string();
+
+        assert_eq!(error(b""), "not a snapshot");
+        assert_eq!(error(b"*2\r\n+rudis-record\r\n:1\r\n"), "not a snapshot");
+        assert_eq!(
+            error(b"*2\r\n+rudis-snapshot\r\n:2\r\n"),
+            "unsupported snapshot version 2"
+        );
+        assert_eq!(
+            error(&encoded[..encoded.len() - 3]),
+            "snapshot is truncated"
+        );
+        let mut other_type = Vec::from(&b"*2\r\n+rudis-snapshot\r\n:1\r\n"[..]);
+        other_type.extend_from_slice(b"*4\r\n+list\r\n$1\r\nk\r\n$1\r\nv\r\n:-1\r\n");
+        assert_eq!(error(&other_type), "unsupported type 'list'");
+    }
+}
@@ -0,0 +1,254 @@
+//! Per-command call counts and latency, reported by INFO commandstats and
+//! INFO latencystats. Updated lock-free from every connection.
+
+use crate::command::COMMAND_TABLE;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Percentiles INFO latencystats reports, like Redis' default
+/// `latency-tracking-info-percentiles`
+const INFO_PERCENTILES: [f64; 3] = [50.0, 99.0, 99.9];
+
+/// Sub-buckets per power of two, as bits; 3 bits keeps every bucket within
+/// 12.5% of the values it holds
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Enough buckets for any u64 nanosecond count
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Counters for every command in the table
+#[derive(Debug)]
+pub struct CommandStats {
+    commands: HashMap<&'static str, CommandStat>,
+}
+
+#[derive(Debug, Default)]
+struct CommandStat {
+    calls: AtomicU64,
+    usec: AtomicU64,
+    /// Calls refused before running, e.g. for a wrong number of arguments
+    rejected: AtomicU64,
+    /// Calls that ran and replied with an error
+    failed: AtomicU64,
+    latency: LatencyHistogram,
+}
+
+impl CommandStats {
+    pub fn new() -> Self {
+        Self {
+            commands: COMMAND_TABLE
+                .iter()
+                .map(|spec| (spec.name, CommandStat::default()))
+                .collect(),
+        }
+    }
+
+    /// Count a call of `command` that took `elapsed`; the latency histogram
+    /// is only fed when `track_latency` is set
+    pub fn record(&self, command: &str, elapsed: Duration, failed: bool, track_latency: bool) {
+        let Some(stat) = self.commands.get(command) else {
+            return;
+        };
+        stat.calls.fetch_add(1, Ordering::Relaxed);
+        stat.usec
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if failed {
+            stat.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        if track_latency {
+            stat.latency.record(elapsed);
+        }
+    }
+
+    /// Count a call of `command` refused before it ran
+    pub fn reject(&self, command: &str) {
+        if let Some(stat) = self.commands.get(command) {
+            stat.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// INFO commandstats fields, one line per command that has been called
+    pub fn commandstats(&self) -> String {
+        let mut section = String::new();
+        for spec in COMMAND_TABLE {
+            let stat = &self.commands[spec.name];
+            let calls = stat.calls.load(Ordering::Relaxed);
+            let rejected = stat.rejected.load(Ordering::Relaxed);
+            if calls == 0 && rejected == 0 {
+                continue;
+            }
+            let usec = stat.usec.load(Ordering::Relaxed);
+            let per_call = if calls == 0 {
+                0.0
+            } else {
+                usec as f64 / calls as f64
+            };
+            let _ = write!(
+                section,
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r\n",
+                spec.name,
+                calls,
+                usec,
+                per_call,
+                rejected,
+                stat.failed.load(Ordering::Relaxed)
+            );
+        }
+        section
+    }
+
+    /// INFO latencystats fields, one line per command with recorded latency
+    pub fn latencystats(&self) -> String {
+        let mut section = String::new();
+        for spec in COMMAND_TABLE {
+            let latency = &self.commands[spec.name].latency;
+            if latency.count() == 0 {
+                continue;
+            }
+            let _ = write!(section, "latency_percentiles_usec_{}:", spec.name);
+            for (i, percentile) in INFO_PERCENTILES.iter().enumerate() {
+                let usec = latency.percentile(*percentile).as_nanos() as f64 / 1000.0;
+                let separator = if i > 0 { "," } else { "" };
+                let _ = write!(section, "{}p{}={:.3}", separator, percentile, usec);
+            }
+            section.push_str("\r\n");
+        }
+        section
+    }
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Log-linear histogram of durations in nanoseconds: each power of two is
+/// split into `SUB_BUCKETS` equal buckets, like a small HdrHistogram
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket_of(nanos)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The smallest recorded duration at or above `percentile` percent of
+    /// samples, to bucket precision
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        percentile_of(&self.counts(), percentile)
+    }
+
+    /// Samples recorded in each bucket so far; subtracting an earlier copy
+    /// gives the samples recorded in between
+    pub fn counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// `LatencyHistogram::percentile` of bucket counts taken by `counts`
+pub fn percentile_of(counts: &[u64], percentile: f64) -> Duration {
+    let total: u64 = counts.iter().sum();
+    let rank = ((percentile / 100.0) * total as f64).ceil().max(1.0) as u64;
+
+    let mut seen = 0;
+    for (bucket, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return Duration::from_nanos(bucket_floor(bucket));
+        }
+    }
+    Duration::ZERO
+}
+
+/// Histogram bucket holding `nanos`
+fn bucket_of(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let exponent = 63 - nanos.leading_zeros();
+    let sub_bucket = (nanos >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    ((exponent - SUB_BUCKET_BITS + 1) as usize) * SUB_BUCKETS + sub_bucket
+}
+
+/// Smallest value that falls in `bucket`
+fn bucket_floor(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let exponent = (bucket / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub_bucket = (bucket % SUB_BUCKETS) as u64;
+    (SUB_BUCKETS as u64 + sub_bucket) << (exponent - SUB_BUCKET_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_round_trip() {
+        for nanos in [0, 7, 8, 15, 16, 17, 1000, 123_456, u64::MAX] {
+            let floor = bucket_floor(bucket_of(nanos));
+            assert!(floor <= nanos, "{}", nanos);
+            // Within 12.5% of the true value
+            assert!(nanos - floor <= nanos / 8, "{}", nanos);
+        }
+        assert_eq!(bucket_of(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        let histogram = LatencyHistogram::default();
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 100);
+        let p50 = histogram.percentile(50.0).as_micros();
+        assert!((44..=50).contains(&p50), "{}", p50);
+        let p99 = histogram.percentile(99.0).as_micros();
+        assert!((87..=99).contains(&p99), "{}", p99);
+    }
+
+    #[test]
+    fn commandstats_lines() {
+        let stats = CommandStats::new();
+        stats.record("get", Duration::from_micros(10), false, true);
+        stats.record("get", Duration::from_micros(20), true, true);
+        stats.reject("set");
+        stats.record("nosuchcommand", Duration::from_micros(1), false, true);
+
+        assert_eq!(
+            stats.commandstats(),
+            "cmdstat_get:calls=2,usec=30,usec_per_call=15.00,rejected_calls=0,failed_calls=1\r\n\
+             cmdstat_set:calls=0,usec=0,usec_per_call=0.00,rejected_calls=1,failed_calls=0\r\n"
+        );
+        let latency = stats.latencystats();
+        assert!(latency.starts_with("latency_percentiles_usec_get:p50="));
+        assert!(latency.contains(",p99="));
+        assert!(latency.contains(",p99.9="));
+        assert!(!latency.contains("usec_set"));
+    }
+}
@@ -1,23 +1,36 @@
+use crate::blocking::Waiters;
+use crate::chaos::Chaos;
+use crate::clock::{Clock, SystemClock};
+use crate::error::CommandError;
+use crate::events::{ChangeKind, Changes, ExpiryHookGuard, KeyEvent, KeyEventReason, KeyEvents};
+use crate::overload::LoadShedder;
+use crate::snapshot::{self, Snapshot};
+use crate::stats::CommandStats;
+use bytes::Bytes;
+#[cfg(feature = "dashmap")]
+use dashmap::DashMap;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::hash::{BuildHasher, RandomState};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Barrier, Mutex};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, oneshot};
 
-/// Simple glob pattern matching supporting * (any sequence) and ? (single char)
-fn glob_match(pattern: &str, text: &str) -> bool {
-    let pattern: Vec<char> = pattern.chars().collect();
-    let text: Vec<char> = text.chars().collect();
-    glob_match_recursive(&pattern, &text, 0, 0)
+/// Simple glob pattern matching supporting * (any sequence) and ? (single byte)
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    glob_match_recursive(pattern, text, 0, 0)
 }
 
-fn glob_match_recursive(pattern: &[char], text: &[char], pi: usize, ti: usize) -> bool {
+fn glob_match_recursive(pattern: &[u8], text: &[u8], pi: usize, ti: usize) -> bool {
     // Base case: pattern exhausted
     if pi == pattern.len() {
         return ti == text.len();
     }
 
     match pattern[pi] {
-        '*' => {
+        b'*' => {
             // Try matching * with 0 or more characters
             for i in ti..=text.len() {
                 if glob_match_recursive(pattern, text, pi + 1, i) {
@@ -26,7 +39,7 @@ fn glob_match_recursive(pattern: &[char], text: &[char], pi: usize, ti: usize) -
             }
             false
         }
-        '?' => {
+        b'?' => {
             // Match exactly one character
             if ti < text.len() {
                 glob_match_recursive(pattern, text, pi + 1, ti + 1)
@@ -45,287 +58,2190 @@ fn glob_match_recursive(pattern: &[char], text: &[char], pi: usize, ti: usize) -
     }
 }
 
+/// Longest string kept inline in a `StoredValue` instead of its own buffer
+const INLINE_MAX_LEN: usize = 22;
+/// Longest string Redis reports as `embstr`
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+/// A string value in the most compact form that round-trips exactly, like
+/// Redis' int/embstr/raw object encodings
+#[derive(Debug)]
+pub(crate) enum ValueData {
+    /// Canonical decimal integers, so INCR skips the parse/format round trip.
+    /// Atomic so INCR can update it in place under a shared lock.
+    Int(AtomicI64),
+    /// Short strings stored in place, without a heap allocation
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_MAX_LEN],
+    },
+    /// Everything else, sharing the buffer it was received in
+    Raw(Bytes),
+}
+
+impl ValueData {
+    pub fn from_bytes(data: Bytes) -> Self {
+        if let Some(n) = parse_canonical_int(&data) {
+            ValueData::Int(AtomicI64::new(n))
+        } else if data.len() <= INLINE_MAX_LEN {
+            let mut bytes = [0; INLINE_MAX_LEN];
+            bytes[..data.len()].copy_from_slice(&data);
+            ValueData::Inline {
+                len: data.len() as u8,
+                bytes,
+            }
+        } else {
+            ValueData::Raw(data)
+        }
+    }
+
+    /// The value as a string, sharing the buffer of raw values
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            ValueData::Int(n) => Bytes::from(n.load(Ordering::Relaxed).to_string()),
+            ValueData::Inline { len, bytes } => Bytes::copy_from_slice(&bytes[..*len as usize]),
+            ValueData::Raw(data) => data.clone(),
+        }
+    }
+
+    /// The value as an integer, if it parses as one
+    pub fn as_int(&self) -> Option<i64> {
+        let text = match self {
+            ValueData::Int(n) => return Some(n.load(Ordering::Relaxed)),
+            ValueData::Inline { len, bytes } => &bytes[..*len as usize],
+            ValueData::Raw(data) => data,
+        };
+        std::str::from_utf8(text).ok()?.parse().ok()
+    }
+
+    /// Length of the value as a string
+    pub fn len(&self) -> usize {
+        match self {
+            ValueData::Int(n) => decimal_len(n.load(Ordering::Relaxed)),
+            ValueData::Inline { len, .. } => *len as usize,
+            ValueData::Raw(data) => data.len(),
+        }
+    }
+
+    /// The encoding Redis would report for this value
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            ValueData::Int(_) => "int",
+            ValueData::Inline { .. } => "embstr",
+            ValueData::Raw(data) if data.len() <= EMBSTR_SIZE_LIMIT => "embstr",
+            ValueData::Raw(_) => "raw",
+        }
+    }
+
+    /// Add `delta` in place if the value is integer-encoded. None means the
+    /// value must be parsed and replaced instead; Some(None) is an overflow.
+    fn add_in_place(&self, delta: i64) -> Option<Option<i64>> {
+        let ValueData::Int(n) = self else {
+            return None;
+        };
+        // Each counter is independent, so no ordering with other memory is needed
+        let updated = n.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+            n.checked_add(delta)
+        });
+        Some(updated.ok().map(|old| old + delta))
+    }
+}
+
+impl Clone for ValueData {
+    fn clone(&self) -> Self {
+        match self {
+            ValueData::Int(n) => ValueData::Int(AtomicI64::new(n.load(Ordering::Relaxed))),
+            ValueData::Inline { len, bytes } => ValueData::Inline {
+                len: *len,
+                bytes: *bytes,
+            },
+            ValueData::Raw(data) => ValueData::Raw(data.clone()),
+        }
+    }
+}
+
+impl PartialEq for ValueData {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ValueData::Int(a), ValueData::Int(b)) => {
+                a.load(Ordering::Relaxed) == b.load(Ordering::Relaxed)
+            }
+            (ValueData::Inline { len: a, bytes: x }, ValueData::Inline { len: b, bytes: y }) => {
+                a == b && x == y
+            }
+            (ValueData::Raw(a), ValueData::Raw(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Parse `text` as an i64 only if formatting it back gives the same bytes,
+/// so values like "007" or "+1" keep their exact spelling
+fn parse_canonical_int(text: &[u8]) -> Option<i64> {
+    // i64::MIN is the longest at 20 bytes
+    if text.is_empty() || text.len() > 20 {
+        return None;
+    }
+    let n: i64 = std::str::from_utf8(text).ok()?.parse().ok()?;
+    (decimal_len(n) == text.len()).then_some(n)
+}
+
+/// Length of `n` formatted in decimal
+fn decimal_len(n: i64) -> usize {
+    let digits = n
+        .unsigned_abs()
+        .checked_ilog10()
+        .map_or(1, |d| d as usize + 1);
+    digits + usize::from(n < 0)
+}
+
 /// A stored value with optional expiration
 #[derive(Debug, Clone)]
-pub struct StoredValue {
-    pub data: Vec<u8>,
+pub(crate) struct StoredValue {
+    pub data: ValueData,
     pub expires_at: Option<Instant>,
 }
 
 impl StoredValue {
-    pub fn new(data: Vec<u8>) -> Self {
+    pub fn new(data: Bytes) -> Self {
         Self {
-            data,
+            data: ValueData::from_bytes(data),
             expires_at: None,
         }
     }
 
-    pub fn with_expiry(data: Vec<u8>, ttl: Duration) -> Self {
+    pub fn with_expiry(data: Bytes, expires_at: Instant) -> Self {
+        Self {
+            data: ValueData::from_bytes(data),
+            expires_at: Some(expires_at),
+        }
+    }
+
+    pub fn from_int(n: i64) -> Self {
         Self {
-            data,
-            expires_at: Some(Instant::now() + ttl),
+            data: ValueData::Int(AtomicI64::new(n)),
+            expires_at: None,
         }
     }
 
-    pub fn is_expired(&self) -> bool {
-        self.expires_at
-            .map(|exp| Instant::now() > exp)
-            .unwrap_or(false)
+    /// Whether the value's expiry has passed at `now`
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|exp| now > exp)
     }
 }
 
-/// Thread-safe key-value store
-#[derive(Debug, Clone)]
-pub struct Store {
-    data: Arc<RwLock<HashMap<String, StoredValue>>>,
+/// Number of keyspace partitions used by `Store::new`
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Keys sampled per active expiration round
+const EXPIRE_SAMPLE_SIZE: usize = 20;
+/// Keep sampling while more than this fraction of a sample was expired
+const EXPIRE_THRESHOLD: f64 = 0.25;
+
+/// Entries a pass over the keyspace visits between yields to the runtime
+const YIELD_EVERY: usize = 1024;
+
+/// How often the compaction task looks for oversized maps
+const COMPACT_INTERVAL: Duration = Duration::from_secs(10);
+/// Maps with fewer slots than this are never worth shrinking
+const COMPACT_MIN_CAPACITY: usize = 1024;
+/// Shrink once a map has this many slots per live key. Shrinking only to
+/// twice the live keys leaves headroom, so a map that refills after a burst
+/// of deletions doesn't bounce between growing and shrinking.
+const COMPACT_SLACK: usize = 4;
+
+/// Hashes keys for every keyspace map and for picking a key's shard:
+/// SipHash-1-3 keyed with seeds drawn at random when each map is created, so
+/// clients who choose key names can't predict buckets and pile keys into one
+/// (HashDoS). Never swap in an unkeyed hasher for speed.
+type KeyHasher = RandomState;
+type Map = HashMap<Bytes, StoredValue, KeyHasher>;
+/// Work sent to a shard owner thread, run against the map it owns
+type Job = Box<dyn FnOnce(&mut Map) + Send>;
+/// A key with its value and expiry, as copied out for a snapshot
+type Entry = (Bytes, Bytes, Option<Instant>);
+
+/// A keyspace partition behind its own lock, which keeps count of how often
+/// and how long callers had to wait for it
+#[derive(Debug)]
+struct Shard {
+    map: RwLock<Map>,
+    /// Held shared while a counter is bumped in place under the read lock,
+    /// and exclusively by multi-key reads, which mustn't see counters move
+    /// between one key and the next
+    counters: RwLock<()>,
+    /// Snapshots waiting for a copy of the shard as it was when they were
+    /// taken. Whoever next takes the write lock copies it for them first,
+    /// or else the snapshot copies it itself in turn.
+    copies: Mutex<Vec<oneshot::Sender<Vec<Entry>>>>,
+    /// Whether `copies` has any, checked without its lock; only changed
+    /// with the map locked and counters held
+    copy_pending: AtomicBool,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
 }
 
-impl Store {
-    pub fn new() -> Self {
+impl Shard {
+    fn new() -> Self {
         Self {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            map: RwLock::new(Map::with_hasher(KeyHasher::new())),
+            counters: RwLock::new(()),
+            copies: Mutex::new(Vec::new()),
+            copy_pending: AtomicBool::new(false),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
         }
     }
 
-    /// Get a value by key, returns None if key doesn't exist or is expired
-    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
-        let read_guard = self.data.read().await;
-        if let Some(value) = read_guard.get(key) {
-            if value.is_expired() {
-                drop(read_guard);
-                // Lazily delete expired key
-                self.data.write().await.remove(key);
-                None
-            } else {
-                Some(value.data.clone())
+    async fn read(&self) -> RwLockReadGuard<'_, Map> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        // Only a lock that's already held is worth timing
+        if let Ok(guard) = self.map.try_read() {
+            return guard;
+        }
+        let start = Instant::now();
+        let guard = self.map.read().await;
+        self.waited(start.elapsed());
+        guard
+    }
+
+    async fn write(&self) -> RwLockWriteGuard<'_, Map> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        let guard = match self.map.try_write() {
+            Ok(guard) => guard,
+            Err(_) => {
+                let start = Instant::now();
+                let guard = self.map.write().await;
+                self.waited(start.elapsed());
+                guard
             }
-        } else {
-            None
+        };
+        // Nothing may change before a waiting snapshot has its copy
+        if self.copy_pending.load(Ordering::Acquire) {
+            self.hand_over_copy(&guard);
         }
+        guard
     }
 
-    /// Set a key to a value
-    pub async fn set(&self, key: String, value: Vec<u8>) {
-        let stored = StoredValue::new(value);
-        self.data.write().await.insert(key, stored);
+    /// Copy `map`, this shard's locked map, to every snapshot waiting for it
+    fn hand_over_copy(&self, map: &Map) {
+        let mut waiting = std::mem::take(&mut *self.copies.lock().unwrap());
+        self.copy_pending.store(false, Ordering::Release);
+        // Snapshots given up on don't need it
+        waiting.retain(|copy| !copy.is_closed());
+        let Some(last) = waiting.pop() else {
+            return;
+        };
+        let entries: Vec<Entry> = map
+            .iter()
+            .map(|(key, value)| (key.clone(), value.data.to_bytes(), value.expires_at))
+            .collect();
+        for copy in waiting {
+            let _ = copy.send(entries.clone());
+        }
+        let _ = last.send(entries);
     }
 
-    /// Set a key with expiration (in seconds)
-    pub async fn set_ex(&self, key: String, value: Vec<u8>, seconds: u64) {
-        let stored = StoredValue::with_expiry(value, Duration::from_secs(seconds));
-        self.data.write().await.insert(key, stored);
+    fn waited(&self, wait: Duration) {
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
     }
 
-    /// Set a key only if it doesn't exist. Returns true if set, false if key already exists
-    pub async fn set_nx(&self, key: String, value: Vec<u8>) -> bool {
-        let mut write_guard = self.data.write().await;
+    fn lock_stats(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            wait: Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
 
-        // Check if key exists and is not expired
-        if let Some(existing) = write_guard.get(&key)
-            && !existing.is_expired()
-        {
-            return false;
+/// How contended one keyspace partition's lock has been since startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Times the lock was taken
+    pub acquisitions: u64,
+    /// Times it was already held and the caller had to wait
+    pub contended: u64,
+    /// Total time spent waiting
+    pub wait: Duration,
+}
+
+impl LockStats {
+    /// Percentage of acquisitions that had to wait
+    pub fn contended_percent(&self) -> f64 {
+        if self.acquisitions == 0 {
+            return 0.0;
         }
+        self.contended as f64 * 100.0 / self.acquisitions as f64
+    }
 
-        write_guard.insert(key, StoredValue::new(value));
-        true
+    /// Sum of `self` and `other`
+    pub fn add(&self, other: &LockStats) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions + other.acquisitions,
+            contended: self.contended + other.contended,
+            wait: self.wait + other.wait,
+        }
     }
+}
 
-    /// Delete one or more keys. Returns the number of keys deleted
-    pub async fn del(&self, keys: &[String]) -> i64 {
-        let mut write_guard = self.data.write().await;
-        let mut deleted = 0;
-        for key in keys {
-            if write_guard.remove(key).is_some() {
-                deleted += 1;
+/// Outcome of looking up a key
+#[derive(Debug, PartialEq)]
+enum Lookup<R> {
+    Found(R),
+    Missing,
+    /// The key had expired and the lookup deleted it
+    Expired,
+}
+
+/// Which map implementation holds the keyspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyspaceBackend {
+    /// `keyspace-shards` async `RwLock<HashMap>` partitions
+    #[default]
+    Sharded,
+    /// A `DashMap`, built with the `dashmap` feature
+    #[cfg(feature = "dashmap")]
+    DashMap,
+    /// `keyspace-shards` plain maps, each owned by a dedicated thread that
+    /// runs every operation on its keys, so no locks are taken
+    Owned,
+}
+
+/// The map holding the keyspace. Every `Store` operation goes through the
+/// handful of single-key primitives below, so backends only implement those.
+#[derive(Debug, Clone)]
+enum Keyspace {
+    Sharded {
+        shards: Arc<[Shard]>,
+        hasher: KeyHasher,
+    },
+    #[cfg(feature = "dashmap")]
+    Concurrent(Arc<DashMap<Bytes, StoredValue, KeyHasher>>),
+    Owned(Arc<ShardOwners>),
+}
+
+impl Keyspace {
+    fn new(backend: KeyspaceBackend, shards: usize) -> Self {
+        let shards = shards.max(1);
+        match backend {
+            KeyspaceBackend::Sharded => Keyspace::Sharded {
+                shards: (0..shards).map(|_| Shard::new()).collect(),
+                hasher: KeyHasher::new(),
+            },
+            // DashMap needs a power of two greater than one
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap => {
+                Keyspace::Concurrent(Arc::new(DashMap::with_hasher_and_shard_amount(
+                    KeyHasher::new(),
+                    shards.next_power_of_two().max(2),
+                )))
             }
+            KeyspaceBackend::Owned => Keyspace::Owned(Arc::new(ShardOwners::spawn(shards))),
         }
-        deleted
     }
 
-    /// Increment value by 1. Returns the new value or error if not an integer
-    pub async fn incr(&self, key: &str) -> Result<i64, String> {
-        self.incr_by(key, 1).await
+    /// Run `f` on the live value of `key`, lazily deleting it if expired
+    async fn get_live<R: Send + 'static>(
+        &self,
+        key: &[u8],
+        now: Instant,
+        f: impl FnOnce(&StoredValue) -> R + Send + 'static,
+    ) -> Lookup<R> {
+        match self {
+            Keyspace::Sharded { shards, hasher } => {
+                let shard = shard_for(shards, hasher, key);
+                let read_guard = shard.read().await;
+                match read_guard.get(key) {
+                    Some(value) if !value.is_expired(now) => Lookup::Found(f(value)),
+                    Some(_) => {
+                        drop(read_guard);
+                        expired_lookup(remove_expired(&mut *shard.write().await, key, now))
+                    }
+                    None => Lookup::Missing,
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                if let Some(value) = map.get(key) {
+                    if !value.is_expired(now) {
+                        return Lookup::Found(f(&value));
+                    }
+                } else {
+                    return Lookup::Missing;
+                }
+                expired_lookup(
+                    map.remove_if(key, |_, value| value.is_expired(now))
+                        .is_some(),
+                )
+            }
+            Keyspace::Owned(owners) => {
+                let key = Bytes::copy_from_slice(key);
+                owners
+                    .run(owners.owner_of(&key), move |map| match map.get(&key) {
+                        Some(value) if !value.is_expired(now) => Lookup::Found(f(value)),
+                        Some(_) => {
+                            map.remove(&key);
+                            Lookup::Expired
+                        }
+                        None => Lookup::Missing,
+                    })
+                    .await
+            }
+        }
     }
 
-    /// Decrement value by 1. Returns the new value or error if not an integer
-    pub async fn decr(&self, key: &str) -> Result<i64, String> {
-        self.incr_by(key, -1).await
+    /// Add `delta` to the live value of `key` in place, as
+    /// `ValueData::add_in_place` does, lazily deleting it if expired. Found
+    /// None as well while a multi-key read holds the key's shard, so the
+    /// caller takes the write lock instead and waits for the read to finish.
+    async fn add_in_place(
+        &self,
+        key: &[u8],
+        now: Instant,
+        delta: i64,
+    ) -> Lookup<Option<Option<i64>>> {
+        let Keyspace::Sharded { shards, hasher } = self else {
+            return self
+                .get_live(key, now, move |value| value.data.add_in_place(delta))
+                .await;
+        };
+        let shard = shard_for(shards, hasher, key);
+        let read_guard = shard.read().await;
+        match read_guard.get(key) {
+            Some(value) if !value.is_expired(now) => match shard.counters.try_read() {
+                // A shard waiting to be copied is only changed by the write
+                // path, which copies it first
+                Ok(_adding) if !shard.copy_pending.load(Ordering::Acquire) => {
+                    Lookup::Found(value.data.add_in_place(delta))
+                }
+                _ => Lookup::Found(None),
+            },
+            Some(_) => {
+                drop(read_guard);
+                expired_lookup(remove_expired(&mut *shard.write().await, key, now))
+            }
+            None => Lookup::Missing,
+        }
     }
 
-    /// Increment value by a specific amount. Returns the new value or error if not an integer
-    pub async fn incr_by(&self, key: &str, delta: i64) -> Result<i64, String> {
-        let mut write_guard = self.data.write().await;
+    /// Run `f` on the live value of `key` with write access, lazily deleting
+    /// it if expired
+    async fn modify_live<R: Send + 'static>(
+        &self,
+        key: &[u8],
+        now: Instant,
+        f: impl FnOnce(&mut StoredValue) -> R + Send + 'static,
+    ) -> Lookup<R> {
+        match self {
+            Keyspace::Sharded { shards, hasher } => {
+                let mut write_guard = shard_for(shards, hasher, key).write().await;
+                match write_guard.get_mut(key) {
+                    Some(value) if !value.is_expired(now) => Lookup::Found(f(value)),
+                    Some(_) => {
+                        write_guard.remove(key);
+                        Lookup::Expired
+                    }
+                    None => Lookup::Missing,
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                if let Some(mut value) = map.get_mut(key) {
+                    if !value.is_expired(now) {
+                        return Lookup::Found(f(&mut value));
+                    }
+                } else {
+                    return Lookup::Missing;
+                }
+                expired_lookup(
+                    map.remove_if(key, |_, value| value.is_expired(now))
+                        .is_some(),
+                )
+            }
+            Keyspace::Owned(owners) => {
+                let key = Bytes::copy_from_slice(key);
+                owners
+                    .run(owners.owner_of(&key), move |map| match map.get_mut(&key) {
+                        Some(value) if !value.is_expired(now) => Lookup::Found(f(value)),
+                        Some(_) => {
+                            map.remove(&key);
+                            Lookup::Expired
+                        }
+                        None => Lookup::Missing,
+                    })
+                    .await
+            }
+        }
+    }
 
-        let current = if let Some(value) = write_guard.get(key) {
-            if value.is_expired() {
-                0
-            } else {
-                let s = String::from_utf8(value.data.clone())
-                    .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
-                s.parse::<i64>()
-                    .map_err(|_| "ERR value is not an integer or out of range".to_string())?
+    /// Atomically read the live value of `key` (None if missing or expired)
+    /// and optionally replace it with the value `f` returns
+    async fn compute<R: Send + 'static>(
+        &self,
+        key: &[u8],
+        now: Instant,
+        f: impl FnOnce(Option<&StoredValue>) -> (R, Option<StoredValue>) + Send + 'static,
+    ) -> R {
+        match self {
+            Keyspace::Sharded { shards, hasher } => {
+                let mut write_guard = shard_for(shards, hasher, key).write().await;
+                let current = write_guard.get(key).filter(|value| !value.is_expired(now));
+                let (result, replacement) = f(current);
+                if let Some(value) = replacement {
+                    write_guard.insert(Bytes::copy_from_slice(key), value);
+                }
+                result
             }
-        } else {
-            0
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                use dashmap::mapref::entry::Entry;
+
+                match map.entry(Bytes::copy_from_slice(key)) {
+                    Entry::Occupied(mut entry) => {
+                        let current = Some(entry.get()).filter(|value| !value.is_expired(now));
+                        let (result, replacement) = f(current);
+                        if let Some(value) = replacement {
+                            entry.insert(value);
+                        }
+                        result
+                    }
+                    Entry::Vacant(entry) => {
+                        let (result, replacement) = f(None);
+                        if let Some(value) = replacement {
+                            entry.insert(value);
+                        }
+                        result
+                    }
+                }
+            }
+            Keyspace::Owned(owners) => {
+                let key = Bytes::copy_from_slice(key);
+                owners
+                    .run(owners.owner_of(&key), move |map| {
+                        let current = map.get(&key).filter(|value| !value.is_expired(now));
+                        let (result, replacement) = f(current);
+                        if let Some(value) = replacement {
+                            map.insert(key, value);
+                        }
+                        result
+                    })
+                    .await
+            }
+        }
+    }
+
+    async fn insert(&self, key: Bytes, value: StoredValue) {
+        match self {
+            Keyspace::Sharded { shards, hasher } => {
+                shard_for(shards, hasher, &key)
+                    .write()
+                    .await
+                    .insert(key, value);
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                map.insert(key, value);
+            }
+            Keyspace::Owned(owners) => {
+                owners
+                    .run(owners.owner_of(&key), move |map| {
+                        map.insert(key, value);
+                    })
+                    .await;
+            }
+        }
+    }
+
+    async fn remove(&self, key: &[u8]) -> Option<StoredValue> {
+        match self {
+            Keyspace::Sharded { shards, hasher } => {
+                shard_for(shards, hasher, key).write().await.remove(key)
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => map.remove(key).map(|(_, value)| value),
+            Keyspace::Owned(owners) => {
+                let key = Bytes::copy_from_slice(key);
+                owners
+                    .run(owners.owner_of(&key), move |map| map.remove(&key))
+                    .await
+            }
+        }
+    }
+
+    /// Live values of `keys` mapped through `f`, in order, all read at one
+    /// point in time: a multi-key write is seen whole or not at all. Shard
+    /// owners are all asked at once and their answers gathered, rather than
+    /// key by key.
+    async fn get_live_many<R: Send + 'static>(
+        &self,
+        keys: &[Bytes],
+        now: Instant,
+        f: impl Fn(&StoredValue) -> R + Clone + Send + 'static,
+    ) -> Vec<Lookup<R>> {
+        let owners = match self {
+            Keyspace::Sharded { shards, hasher } => {
+                let locked = LockedShards::read(shards, hasher, keys).await;
+                let mut expired = Vec::new();
+                let mut results: Vec<Lookup<R>> = keys
+                    .iter()
+                    .enumerate()
+                    .map(|(index, key)| match locked.map(key).get(key) {
+                        Some(value) if !value.is_expired(now) => Lookup::Found(f(value)),
+                        Some(_) => {
+                            expired.push(index);
+                            Lookup::Missing
+                        }
+                        None => Lookup::Missing,
+                    })
+                    .collect();
+                drop(locked);
+                for index in expired {
+                    let key = &keys[index];
+                    let deleted = remove_expired(
+                        &mut *shard_for(shards, hasher, key).write().await,
+                        key,
+                        now,
+                    );
+                    results[index] = expired_lookup(deleted);
+                }
+                return results;
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(_) => {
+                let mut results = Vec::with_capacity(keys.len());
+                for key in keys {
+                    results.push(self.get_live(key, now, f.clone()).await);
+                }
+                return results;
+            }
+            Keyspace::Owned(owners) => owners,
         };
 
-        let new_value = current
-            .checked_add(delta)
-            .ok_or_else(|| "ERR increment or decrement would overflow".to_string())?;
+        let pending = owners.submit_atomic(
+            owners.partition(keys.iter().cloned().enumerate()),
+            move |map, batch| {
+                batch
+                    .into_iter()
+                    .map(|(index, key)| {
+                        let value = match map.get(&key) {
+                            Some(value) if !value.is_expired(now) => Lookup::Found(f(value)),
+                            Some(_) => {
+                                map.remove(&key);
+                                Lookup::Expired
+                            }
+                            None => Lookup::Missing,
+                        };
+                        (index, value)
+                    })
+                    .collect::<Vec<_>>()
+            },
+        );
+
+        let mut results: Vec<Lookup<R>> = (0..keys.len()).map(|_| Lookup::Missing).collect();
+        for answer in pending {
+            for (index, value) in ShardOwners::gather(answer).await {
+                results[index] = value;
+            }
+        }
+        results
+    }
+
+    /// Insert every entry at once: no reader sees some of them written and
+    /// others not. Each shard owner is handed its batch at once.
+    async fn insert_many(&self, entries: Vec<(Bytes, StoredValue)>) {
+        let owners = match self {
+            Keyspace::Sharded { shards, hasher } => {
+                let keys: Vec<Bytes> = entries.iter().map(|(key, _)| key.clone()).collect();
+                let mut locked = LockedShards::write(shards, hasher, &keys).await;
+                for (key, value) in entries {
+                    locked.map_mut(&key).insert(key, value);
+                }
+                return;
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(_) => {
+                for (key, value) in entries {
+                    self.insert(key, value).await;
+                }
+                return;
+            }
+            Keyspace::Owned(owners) => owners,
+        };
+
+        let pending = owners.submit_atomic(
+            owners.partition(entries.into_iter().map(|(key, value)| (value, key))),
+            |map, batch| {
+                for (value, key) in batch {
+                    map.insert(key, value);
+                }
+            },
+        );
+        for done in pending {
+            ShardOwners::gather(done).await;
+        }
+    }
+
+    /// Remove `keys` at once, returning those that were present
+    async fn remove_many(&self, keys: &[Bytes]) -> Vec<Bytes> {
+        let owners = match self {
+            Keyspace::Sharded { shards, hasher } => {
+                let mut locked = LockedShards::write(shards, hasher, keys).await;
+                return keys
+                    .iter()
+                    .filter(|key| locked.map_mut(key).remove(*key).is_some())
+                    .cloned()
+                    .collect();
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(_) => {
+                let mut removed = Vec::new();
+                for key in keys {
+                    if self.remove(key).await.is_some() {
+                        removed.push(key.clone());
+                    }
+                }
+                return removed;
+            }
+            Keyspace::Owned(owners) => owners,
+        };
 
-        write_guard.insert(
-            key.to_string(),
-            StoredValue::new(new_value.to_string().into_bytes()),
+        let pending = owners.submit_atomic(
+            owners.partition(keys.iter().cloned().map(|key| ((), key))),
+            |map, batch| {
+                batch
+                    .into_iter()
+                    .filter_map(|(_, key)| map.remove(&key).map(|_| key))
+                    .collect::<Vec<_>>()
+            },
         );
+        let mut removed = Vec::new();
+        for keys in pending {
+            removed.extend(ShardOwners::gather(keys).await);
+        }
+        removed
+    }
 
-        Ok(new_value)
+    /// Live keys accepted by `filter`, and the expired keys met on the way
+    /// that were deleted
+    async fn matching_keys(
+        &self,
+        now: Instant,
+        mut filter: impl FnMut(&[u8]) -> bool,
+    ) -> (Vec<Bytes>, Vec<Bytes>) {
+        let mut matching_keys = Vec::new();
+        let mut expired = Vec::new();
+        match self {
+            Keyspace::Sharded { shards, .. } => {
+                for shard in shards.iter() {
+                    let (keys, shard_expired) = shard_matching_keys(shard, now, &mut filter).await;
+                    matching_keys.extend(keys);
+                    expired.extend(shard_expired);
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                let mut expired_keys = Vec::new();
+                for entry in map.iter() {
+                    if entry.value().is_expired(now) {
+                        expired_keys.push(entry.key().clone());
+                    } else if filter(entry.key()) {
+                        matching_keys.push(entry.key().clone());
+                    }
+                }
+                for key in expired_keys {
+                    if map
+                        .remove_if(&key, |_, value| value.is_expired(now))
+                        .is_some()
+                    {
+                        expired.push(key);
+                    }
+                }
+            }
+            Keyspace::Owned(owners) => {
+                // Scatter to every owner, then filter the live keys here so the
+                // pattern need not be sent
+                let pending: Vec<_> = (0..owners.len())
+                    .map(|shard| owners.submit(shard, move |map| live_map_keys(map, now)))
+                    .collect();
+                for answer in pending {
+                    let (keys, shard_expired) = ShardOwners::gather(answer).await;
+                    matching_keys.extend(keys.into_iter().filter(|key| filter(key)));
+                    expired.extend(shard_expired);
+                }
+            }
+        }
+        (matching_keys, expired)
     }
 
-    /// Get multiple keys at once
-    pub async fn mget(&self, keys: &[String]) -> Vec<Option<Vec<u8>>> {
-        let read_guard = self.data.read().await;
-        let mut results = Vec::with_capacity(keys.len());
-        let mut expired_keys = Vec::new();
+    /// Partitions SCAN walks one after another. DashMap doesn't expose its
+    /// shards, so it is walked as one.
+    fn partitions(&self) -> usize {
+        match self {
+            Keyspace::Sharded { shards, .. } => shards.len(),
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(_) => 1,
+            Keyspace::Owned(owners) => owners.len(),
+        }
+    }
 
-        for key in keys {
-            if let Some(value) = read_guard.get(key) {
-                if value.is_expired() {
-                    expired_keys.push(key.clone());
-                    results.push(None);
+    /// `matching_keys` of partition `index` alone
+    async fn partition_keys(
+        &self,
+        index: usize,
+        now: Instant,
+        mut filter: impl FnMut(&[u8]) -> bool,
+    ) -> (Vec<Bytes>, Vec<Bytes>) {
+        match self {
+            Keyspace::Sharded { shards, .. } => {
+                shard_matching_keys(&shards[index], now, &mut filter).await
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(_) => self.matching_keys(now, filter).await,
+            Keyspace::Owned(owners) => {
+                let (keys, expired) = owners.run(index, move |map| live_map_keys(map, now)).await;
+                (
+                    keys.into_iter().filter(|key| filter(key)).collect(),
+                    expired,
+                )
+            }
+        }
+    }
+
+    /// Shrink partitions left oversized by deletions, returning how many were shrunk
+    async fn compact(&self) -> usize {
+        match self {
+            Keyspace::Sharded { shards, .. } => {
+                let mut compacted = 0;
+                for shard in shards.iter() {
+                    let oversized = {
+                        let read_guard = shard.read().await;
+                        needs_compaction(read_guard.len(), read_guard.capacity())
+                    };
+                    if oversized && compact_map(&mut *shard.write().await) {
+                        compacted += 1;
+                    }
+                    // Rehashing holds the write lock, so let clients in between shards
+                    tokio::task::yield_now().await;
+                }
+                compacted
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                // DashMap only shrinks all of its shards at once
+                if needs_compaction(map.len(), map.capacity()) {
+                    map.shrink_to_fit();
+                    1
                 } else {
-                    results.push(Some(value.data.clone()));
+                    0
                 }
-            } else {
-                results.push(None);
+            }
+            Keyspace::Owned(owners) => {
+                let pending: Vec<_> = (0..owners.len())
+                    .map(|shard| owners.submit(shard, compact_map))
+                    .collect();
+                let mut compacted = 0;
+                for shrunk in pending {
+                    compacted += usize::from(ShardOwners::gather(shrunk).await);
+                }
+                compacted
             }
         }
+    }
 
-        drop(read_guard);
+    /// Live-or-expired keys and allocated slots across all partitions
+    async fn occupancy(&self) -> (usize, usize) {
+        match self {
+            Keyspace::Sharded { shards, .. } => {
+                let (mut len, mut capacity) = (0, 0);
+                for shard in shards.iter() {
+                    let read_guard = shard.read().await;
+                    len += read_guard.len();
+                    capacity += read_guard.capacity();
+                }
+                (len, capacity)
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => (map.len(), map.capacity()),
+            Keyspace::Owned(owners) => {
+                let pending: Vec<_> = (0..owners.len())
+                    .map(|shard| owners.submit(shard, |map| (map.len(), map.capacity())))
+                    .collect();
+                let (mut len, mut capacity) = (0, 0);
+                for answer in pending {
+                    let (shard_len, shard_capacity) = ShardOwners::gather(answer).await;
+                    len += shard_len;
+                    capacity += shard_capacity;
+                }
+                (len, capacity)
+            }
+        }
+    }
+
+    /// Live keys and their approximate size under each of `prefixes`; a key
+    /// is counted once for every prefix it starts with
+    async fn prefix_usage(&self, prefixes: Arc<[Bytes]>, now: Instant) -> Vec<PrefixUsage> {
+        let mut usage = vec![PrefixUsage::default(); prefixes.len()];
+        match self {
+            Keyspace::Sharded { shards, .. } => {
+                for shard in shards.iter() {
+                    let read_guard = shard.read().await;
+                    for (visited, (key, value)) in read_guard.iter().enumerate() {
+                        yield_periodically(visited).await;
+                        tally_prefixes(&mut usage, &prefixes, key, value, now);
+                    }
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                for entry in map.iter() {
+                    tally_prefixes(&mut usage, &prefixes, entry.key(), entry.value(), now);
+                }
+            }
+            Keyspace::Owned(owners) => {
+                let pending: Vec<_> = (0..owners.len())
+                    .map(|shard| {
+                        let prefixes = prefixes.clone();
+                        owners.submit(shard, move |map| {
+                            let mut usage = vec![PrefixUsage::default(); prefixes.len()];
+                            for (key, value) in map.iter() {
+                                tally_prefixes(&mut usage, &prefixes, key, value, now);
+                            }
+                            usage
+                        })
+                    })
+                    .collect();
+                for answer in pending {
+                    let shard_usage = ShardOwners::gather(answer).await;
+                    for (total, shard) in usage.iter_mut().zip(shard_usage) {
+                        total.keys += shard.keys;
+                        total.bytes += shard.bytes;
+                    }
+                }
+            }
+        }
+        usage
+    }
+
+    /// Every live key, with its value and expiry
+    async fn live_entries(&self, now: Instant) -> Vec<Entry> {
+        let entry = move |key: &Bytes, value: &StoredValue| {
+            (!value.is_expired(now)).then(|| (key.clone(), value.data.to_bytes(), value.expires_at))
+        };
+        let mut entries = Vec::new();
+        match self {
+            Keyspace::Sharded { shards, hasher } => {
+                // Every shard is held at once, counters too, just long enough
+                // to mark it as waiting for a copy, so the copy is of one
+                // instant. Each is then copied on its own: by the first write
+                // to it, or else here in turn, so a write waits for the copy
+                // of one shard at most, never for the whole keyspace.
+                let locked = LockedShards::read_all(shards, hasher).await;
+                let copies: Vec<_> = shards
+                    .iter()
+                    .map(|shard| {
+                        let (copy, copied) = oneshot::channel();
+                        shard.copies.lock().unwrap().push(copy);
+                        shard.copy_pending.store(true, Ordering::Release);
+                        copied
+                    })
+                    .collect();
+                drop(locked);
+                for (shard, copied) in shards.iter().zip(copies) {
+                    if shard.copy_pending.load(Ordering::Acquire) {
+                        let frozen = FrozenShard::lock(shard).await;
+                        if shard.copy_pending.load(Ordering::Acquire) {
+                            shard.hand_over_copy(&frozen);
+                        }
+                    }
+                    let copy = copied.await.expect("shard copy dropped");
+                    entries.extend(
+                        copy.into_iter()
+                            .filter(|(_, _, expires_at)| expires_at.is_none_or(|at| now <= at)),
+                    );
+                    // Let clients in between shards
+                    tokio::task::yield_now().await;
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => {
+                // DashMap can't hold all of its shards, so this copy isn't
+                // of one instant
+                entries.extend(
+                    map.iter()
+                        .filter_map(|item| entry(item.key(), item.value())),
+                );
+            }
+            Keyspace::Owned(owners) => {
+                // Copied by every owner as one atomic operation, so no write
+                // lands between two owners' copies
+                let batches = (0..owners.len()).map(|shard| (shard, Vec::<((), Bytes)>::new()));
+                let pending = owners.submit_atomic(batches, move |map, _| {
+                    map.iter()
+                        .filter_map(|(key, value)| entry(key, value))
+                        .collect::<Vec<_>>()
+                });
+                for answer in pending {
+                    entries.extend(ShardOwners::gather(answer).await);
+                }
+            }
+        }
+        entries
+    }
+
+    /// Sample keys and delete expired ones, returning the keys deleted.
+    /// Redis samples 20 keys per cycle and continues if >25% are expired.
+    async fn expire_sampled_keys(&self, now: Instant) -> Vec<Bytes> {
+        let mut expired = Vec::new();
+        match self {
+            Keyspace::Sharded { shards, .. } => {
+                for shard in shards.iter() {
+                    expired.extend(expire_shard_keys(shard, now).await);
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(map) => loop {
+                let sample: Vec<Bytes> = map
+                    .iter()
+                    .take(EXPIRE_SAMPLE_SIZE)
+                    .map(|entry| entry.key().clone())
+                    .collect();
+                if sample.is_empty() {
+                    break;
+                }
+
+                let sampled = sample.len();
+                let deleted: Vec<Bytes> = sample
+                    .into_iter()
+                    .filter(|key| {
+                        map.remove_if(key, |_, value| value.is_expired(now))
+                            .is_some()
+                    })
+                    .collect();
+                let ratio = deleted.len() as f64 / sampled as f64;
+                expired.extend(deleted);
+
+                if ratio < EXPIRE_THRESHOLD {
+                    break;
+                }
+            },
+            Keyspace::Owned(owners) => {
+                let pending: Vec<_> = (0..owners.len())
+                    .map(|shard| owners.submit(shard, move |map| expire_map_keys(map, now)))
+                    .collect();
+                for deleted in pending {
+                    expired.extend(ShardOwners::gather(deleted).await);
+                }
+            }
+        }
+        expired
+    }
+}
+
+/// Shards each owned by a dedicated thread, which is the only code that
+/// touches its map. Operations are shipped to the owner as closures over a
+/// channel and the result comes back on a oneshot; multi-key operations
+/// scatter one batch to each owner involved and gather the answers.
+///
+/// Connections share each owner's queue, so it is a multi-producer channel,
+/// but only the owner ever consumes from it.
+#[derive(Debug)]
+struct ShardOwners {
+    queues: Box<[mpsc::Sender<Job>]>,
+    hasher: KeyHasher,
+    /// Held while the jobs of an atomic operation are queued, so every
+    /// owner queues those of any two such operations in the same order
+    queueing: Mutex<()>,
+}
+
+impl ShardOwners {
+    fn spawn(count: usize) -> Self {
+        let queues = (0..count)
+            .map(|index| {
+                let (queue, jobs) = mpsc::channel::<Job>();
+                std::thread::Builder::new()
+                    .name(format!("rudis-shard-{}", index))
+                    .spawn(move || {
+                        let mut map = Map::with_hasher(KeyHasher::new());
+                        // Ends once every Store handle, and so every sender, is gone
+                        for job in jobs {
+                            job(&mut map);
+                        }
+                    })
+                    .expect("failed to spawn shard owner thread");
+                queue
+            })
+            .collect();
+        Self {
+            queues,
+            hasher: KeyHasher::new(),
+            queueing: Mutex::new(()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Index of the shard owning `key`
+    fn owner_of(&self, key: &[u8]) -> usize {
+        self.hasher.hash_one(key) as usize % self.queues.len()
+    }
+
+    /// Queue `job` on a shard's owner without waiting for it to run
+    fn submit<R: Send + 'static>(
+        &self,
+        shard: usize,
+        job: impl FnOnce(&mut Map) -> R + Send + 'static,
+    ) -> oneshot::Receiver<R> {
+        let (reply, answer) = oneshot::channel();
+        let job: Job = Box::new(move |map| {
+            let _ = reply.send(job(map));
+        });
+        self.queues[shard]
+            .send(job)
+            .expect("shard owner thread exited");
+        answer
+    }
+
+    /// Queue `job` on the owner of each of `batches` so that they all take
+    /// effect at once: each owner runs its part, then waits until the
+    /// others have run theirs before taking on any other work, so nothing
+    /// sees the operation half done. Two atomic operations are queued in
+    /// the same order on every owner, so their waits can't deadlock.
+    fn submit_atomic<T: Send + 'static, R: Send + 'static>(
+        &self,
+        batches: impl Iterator<Item = (usize, Vec<(T, Bytes)>)>,
+        job: impl Fn(&mut Map, Vec<(T, Bytes)>) -> R + Clone + Send + 'static,
+    ) -> Vec<oneshot::Receiver<R>> {
+        let batches: Vec<_> = batches.collect();
+        if let [(shard, _)] = batches[..] {
+            // One owner runs it whole anyway
+            let (_, batch) = batches.into_iter().next().unwrap();
+            return vec![self.submit(shard, move |map| job(map, batch))];
+        }
+        let barrier = Arc::new(Barrier::new(batches.len()));
+        let _queueing = self.queueing.lock().unwrap();
+        batches
+            .into_iter()
+            .map(|(shard, batch)| {
+                let job = job.clone();
+                let barrier = barrier.clone();
+                self.submit(shard, move |map| {
+                    let result = job(map, batch);
+                    barrier.wait();
+                    result
+                })
+            })
+            .collect()
+    }
+
+    /// Wait for the result of a submitted job
+    async fn gather<R>(answer: oneshot::Receiver<R>) -> R {
+        answer.await.expect("shard owner thread panicked")
+    }
+
+    /// Run `job` on a shard's owner and wait for its result
+    async fn run<R: Send + 'static>(
+        &self,
+        shard: usize,
+        job: impl FnOnce(&mut Map) -> R + Send + 'static,
+    ) -> R {
+        Self::gather(self.submit(shard, job)).await
+    }
 
-        // Clean up expired keys
+    /// Group `(payload, key)` items by owning shard
+    fn partition<T>(
+        &self,
+        items: impl Iterator<Item = (T, Bytes)>,
+    ) -> impl Iterator<Item = (usize, Vec<(T, Bytes)>)> {
+        let mut batches: Vec<Vec<(T, Bytes)>> = (0..self.len()).map(|_| Vec::new()).collect();
+        for (payload, key) in items {
+            batches[self.owner_of(&key)].push((payload, key));
+        }
+        batches
+            .into_iter()
+            .enumerate()
+            .filter(|(_, batch)| !batch.is_empty())
+    }
+}
+
+/// Approximate bytes a key and its value take up: the map entry, plus the
+/// control byte hashbrown keeps per slot, the key and any buffer of the
+/// value's own
+fn entry_size(key_len: usize, value: &StoredValue) -> usize {
+    let value_heap = match &value.data {
+        ValueData::Raw(data) => data.len(),
+        ValueData::Int(_) | ValueData::Inline { .. } => 0,
+    };
+    std::mem::size_of::<(Bytes, StoredValue)>() + 1 + key_len + value_heap
+}
+
+/// Count a live entry towards every prefix its key starts with
+fn tally_prefixes(
+    usage: &mut [PrefixUsage],
+    prefixes: &[Bytes],
+    key: &[u8],
+    value: &StoredValue,
+    now: Instant,
+) {
+    if value.is_expired(now) {
+        return;
+    }
+    for (usage, prefix) in usage.iter_mut().zip(prefixes) {
+        if key.starts_with(prefix) {
+            usage.keys += 1;
+            usage.bytes += entry_size(key.len(), value) as u64;
+        }
+    }
+}
+
+/// Whether a map holding `len` keys in `capacity` slots should be shrunk
+fn needs_compaction(len: usize, capacity: usize) -> bool {
+    capacity >= COMPACT_MIN_CAPACITY && capacity > len.saturating_mul(COMPACT_SLACK)
+}
+
+/// Shrink an oversized map, returning whether it was shrunk
+fn compact_map(map: &mut Map) -> bool {
+    if !needs_compaction(map.len(), map.capacity()) {
+        return false;
+    }
+    map.shrink_to(map.len() * 2);
+    true
+}
+
+/// Live keys of a shard that pass `filter`, and the expired keys met on the
+/// way, which are deleted
+async fn shard_matching_keys(
+    shard: &Shard,
+    now: Instant,
+    filter: &mut impl FnMut(&[u8]) -> bool,
+) -> (Vec<Bytes>, Vec<Bytes>) {
+    let mut matching_keys = Vec::new();
+    let read_guard = shard.read().await;
+    let mut expired_keys = Vec::new();
+
+    for (visited, (key, value)) in read_guard.iter().enumerate() {
+        yield_periodically(visited).await;
+        if value.is_expired(now) {
+            expired_keys.push(key.clone());
+        } else if filter(key) {
+            matching_keys.push(key.clone());
+        }
+    }
+
+    drop(read_guard);
+
+    // Clean up expired keys
+    let mut expired = Vec::new();
+    if !expired_keys.is_empty() {
+        let mut write_guard = shard.write().await;
+        for key in expired_keys {
+            if remove_expired(&mut write_guard, &key, now) {
+                expired.push(key);
+            }
+        }
+    }
+    (matching_keys, expired)
+}
+
+/// Every live key of an owned shard map, and the expired ones, which are
+/// deleted
+fn live_map_keys(map: &mut Map, now: Instant) -> (Vec<Bytes>, Vec<Bytes>) {
+    let mut expired = Vec::new();
+    map.retain(|key, value| {
+        let live = !value.is_expired(now);
+        if !live {
+            expired.push(key.clone());
+        }
+        live
+    });
+    let keys = map.keys().cloned().collect::<Vec<_>>();
+    (keys, expired)
+}
+
+/// Sample keys of an owned shard map and delete expired ones, with the same
+/// policy as `expire_shard_keys`, returning how many were deleted
+fn expire_map_keys(map: &mut Map, now: Instant) -> Vec<Bytes> {
+    let mut deleted = Vec::new();
+    loop {
+        let expired: Vec<Bytes> = map
+            .iter()
+            .take(EXPIRE_SAMPLE_SIZE)
+            .filter(|(_, value)| value.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let sampled = map.len().min(EXPIRE_SAMPLE_SIZE);
+        if sampled == 0 {
+            return deleted;
+        }
+        for key in &expired {
+            map.remove(key);
+        }
+        let ratio = expired.len() as f64 / sampled as f64;
+        deleted.extend(expired);
+        if ratio < EXPIRE_THRESHOLD {
+            return deleted;
+        }
+    }
+}
+
+/// Let other tasks run every `YIELD_EVERY` entries of a pass over the
+/// keyspace, so a long one doesn't hold up a runtime worker, and can be
+/// cut short by `command-time-budget`
+async fn yield_periodically(visited: usize) {
+    if visited % YIELD_EVERY == YIELD_EVERY - 1 {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// The shard owning `key`
+fn shard_for<'a>(shards: &'a [Shard], hasher: &KeyHasher, key: &[u8]) -> &'a Shard {
+    &shards[shard_index(shards, hasher, key)]
+}
+
+fn shard_index(shards: &[Shard], hasher: &KeyHasher, key: &[u8]) -> usize {
+    hasher.hash_one(key) as usize % shards.len()
+}
+
+/// The locks of every shard holding some of a set of keys, so a multi-key
+/// operation takes effect at once. Locks are taken in ascending shard
+/// order, and anything holding more than one shard lock takes them through
+/// here, so two such operations never each wait for a lock the other holds.
+struct LockedShards<'a, G> {
+    shards: &'a [Shard],
+    hasher: &'a KeyHasher,
+    /// Indexes of the locked shards, ascending, with their guards
+    indexes: Vec<usize>,
+    guards: Vec<G>,
+}
+
+impl<'a, G> LockedShards<'a, G> {
+    fn indexes(shards: &[Shard], hasher: &KeyHasher, keys: &[Bytes]) -> Vec<usize> {
+        let mut indexes: Vec<usize> = keys
+            .iter()
+            .map(|key| shard_index(shards, hasher, key))
+            .collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        indexes
+    }
+
+    fn guard(&self, key: &[u8]) -> usize {
+        let index = shard_index(self.shards, self.hasher, key);
+        self.indexes
+            .binary_search(&index)
+            .expect("key's shard isn't locked")
+    }
+}
+
+impl<'a> LockedShards<'a, FrozenShard<'a>> {
+    async fn read(shards: &'a [Shard], hasher: &'a KeyHasher, keys: &[Bytes]) -> Self {
+        let indexes = Self::indexes(shards, hasher, keys);
+        let mut guards = Vec::with_capacity(indexes.len());
+        for &index in &indexes {
+            guards.push(FrozenShard::lock(&shards[index]).await);
+        }
+        Self {
+            shards,
+            hasher,
+            indexes,
+            guards,
+        }
+    }
+
+    /// Read-lock every shard, to mark the whole keyspace for a copy at once
+    async fn read_all(shards: &'a [Shard], hasher: &'a KeyHasher) -> Self {
+        let indexes: Vec<usize> = (0..shards.len()).collect();
+        let mut guards = Vec::with_capacity(indexes.len());
+        for &index in &indexes {
+            guards.push(FrozenShard::lock(&shards[index]).await);
+        }
+        Self {
+            shards,
+            hasher,
+            indexes,
+            guards,
+        }
+    }
+
+    fn map(&self, key: &[u8]) -> &Map {
+        &self.guards[self.guard(key)]
+    }
+}
+
+impl<'a> LockedShards<'a, RwLockWriteGuard<'a, Map>> {
+    async fn write(shards: &'a [Shard], hasher: &'a KeyHasher, keys: &[Bytes]) -> Self {
+        let indexes = Self::indexes(shards, hasher, keys);
+        let mut guards = Vec::with_capacity(indexes.len());
+        for &index in &indexes {
+            guards.push(shards[index].write().await);
+        }
+        Self {
+            shards,
+            hasher,
+            indexes,
+            guards,
+        }
+    }
+
+    fn map_mut(&mut self, key: &[u8]) -> &mut Map {
+        let guard = self.guard(key);
+        &mut self.guards[guard]
+    }
+}
+
+/// A shard read-locked for a multi-key read, with its counters held still:
+/// INCRs bump integer-encoded values under the read lock, and would
+/// otherwise land between the keys such a read visits
+struct FrozenShard<'a> {
+    map: RwLockReadGuard<'a, Map>,
+    _counters: RwLockWriteGuard<'a, ()>,
+}
+
+impl<'a> FrozenShard<'a> {
+    async fn lock(shard: &'a Shard) -> Self {
+        let map = shard.read().await;
+        // Counters are only held for an atomic add, never across an await,
+        // so this wait is short
+        let counters = shard.counters.write().await;
+        Self {
+            map,
+            _counters: counters,
+        }
+    }
+}
+
+impl Deref for FrozenShard<'_> {
+    type Target = Map;
+
+    fn deref(&self) -> &Map {
+        &self.map
+    }
+}
+
+/// Delete `key` only if it is still expired; it may have been rewritten
+/// between dropping a read lock and taking the write lock. Returns whether
+/// it was deleted.
+fn remove_expired(map: &mut Map, key: &[u8], now: Instant) -> bool {
+    if map.get(key).is_some_and(|value| value.is_expired(now)) {
+        map.remove(key);
+        return true;
+    }
+    false
+}
+
+/// Lookup outcome for an expired key, which someone else may have deleted first
+fn expired_lookup<R>(deleted: bool) -> Lookup<R> {
+    if deleted {
+        Lookup::Expired
+    } else {
+        Lookup::Missing
+    }
+}
+
+/// Sample keys of one shard and delete expired ones, returning the keys deleted
+async fn expire_shard_keys(shard: &Shard, now: Instant) -> Vec<Bytes> {
+    let mut deleted = Vec::new();
+    loop {
+        let keys_to_check: Vec<Bytes> = {
+            let read_guard = shard.read().await;
+            if read_guard.is_empty() {
+                return deleted;
+            }
+            // Sample up to EXPIRE_SAMPLE_SIZE keys
+            read_guard
+                .keys()
+                .take(EXPIRE_SAMPLE_SIZE)
+                .cloned()
+                .collect()
+        };
+
+        if keys_to_check.is_empty() {
+            return deleted;
+        }
+
+        let mut expired_count = 0;
+        let mut expired_keys = Vec::new();
+
+        {
+            let read_guard = shard.read().await;
+            for key in &keys_to_check {
+                if let Some(value) = read_guard.get(key)
+                    && value.is_expired(now)
+                {
+                    expired_keys.push(key.clone());
+                    expired_count += 1;
+                }
+            }
+        }
+
+        // Delete expired keys
         if !expired_keys.is_empty() {
-            let mut write_guard = self.data.write().await;
+            let mut write_guard = shard.write().await;
             for key in expired_keys {
-                write_guard.remove(&key);
+                if remove_expired(&mut write_guard, &key, now) {
+                    deleted.push(key);
+                }
             }
         }
 
-        results
+        // If less than 25% were expired, stop
+        let ratio = expired_count as f64 / keys_to_check.len() as f64;
+        if ratio < EXPIRE_THRESHOLD {
+            return deleted;
+        }
+        // Otherwise, continue sampling (Redis behavior)
+    }
+}
+
+/// Thread-safe key-value store.
+///
+/// By default the keyspace is partitioned into shards, each behind its own
+/// lock, so writers to different keys rarely contend. Single-key operations
+/// lock only the owning shard; multi-key operations such as MSET, MGET and
+/// DEL lock every shard they touch at once, in ascending order, so they take
+/// effect at one instant without deadlocking each other.
+/// With the `dashmap` feature a `DashMap` can hold the keyspace instead, and
+/// the owned backend gives each shard to a thread of its own in place of a lock.
+#[derive(Debug, Clone)]
+pub struct Store {
+    keyspace: Keyspace,
+    active_expire: Arc<AtomicBool>,
+    /// Expirations set by SETEX and EXPIRE are moved by up to this percentage
+    ttl_jitter: Arc<AtomicU32>,
+    /// Draws of jitter made so far, hashed for the next draw
+    jitter_draws: Arc<AtomicU64>,
+    /// Largest key and value write commands may store, 0 for no limit
+    max_key_size: Arc<AtomicUsize>,
+    max_value_size: Arc<AtomicUsize>,
+    /// Partitions shrunk by the compaction task since startup
+    compactions: Arc<AtomicU64>,
+    /// Clients closed for not finishing a frame within `client-frame-timeout`
+    frame_timeouts: Arc<AtomicU64>,
+    /// Call counts and latencies of each command
+    command_stats: Arc<CommandStats>,
+    counters: Arc<KeyspaceCounters>,
+    /// Faults injected by DEBUG CHAOS
+    chaos: Arc<Chaos>,
+    /// Whether the server is overloaded, and what it refused meanwhile
+    load_shedder: Arc<LoadShedder>,
+    /// Clients parked by blocking commands, woken by writes to their keys
+    waiters: Arc<Waiters>,
+    /// Callbacks told about keys that expire, and subscribers to changes
+    events: Arc<KeyEvents>,
+    /// What expiration is judged against; the system clock outside tests
+    clock: Arc<dyn Clock>,
+    /// Orders each partition's keys for SCAN
+    scan_order: KeyHasher,
+}
+
+/// Lookup and expiration counters behind `KeyspaceStats`
+#[derive(Debug, Default)]
+struct KeyspaceCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+}
+
+/// Keyspace activity reported by INFO stats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyspaceStats {
+    /// Reads that found their key
+    pub hits: u64,
+    /// Reads of missing or expired keys
+    pub misses: u64,
+    /// Expired keys deleted, on access or by the active expiration cycle
+    pub expired_keys: u64,
+}
+
+/// Keys under a prefix and the bytes they take up, see `Store::prefix_usage`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefixUsage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+/// Keyspace memory figures reported by INFO memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryStats {
+    /// Keys held, including expired ones not yet deleted
+    pub keys: usize,
+    /// Hash table slots allocated for them
+    pub capacity: usize,
+    /// Partitions shrunk by the compaction task
+    pub compactions: u64,
+}
+
+impl MemoryStats {
+    /// Allocated slots per key held; well above the load factor's ~1.1 means
+    /// memory freed by deletions has not been given back yet
+    pub fn fragmentation_ratio(&self) -> f64 {
+        if self.keys == 0 {
+            return 1.0;
+        }
+        self.capacity as f64 / self.keys as f64
+    }
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Create a store with `count` shards (at least one)
+    pub fn with_shards(count: usize) -> Self {
+        Self::with_backend(KeyspaceBackend::Sharded, count)
+    }
+
+    /// Create a store on the given backend, split into about `shards` partitions
+    pub fn with_backend(backend: KeyspaceBackend, shards: usize) -> Self {
+        Self {
+            keyspace: Keyspace::new(backend, shards),
+            active_expire: Arc::new(AtomicBool::new(true)),
+            ttl_jitter: Arc::default(),
+            jitter_draws: Arc::default(),
+            max_key_size: Arc::default(),
+            max_value_size: Arc::default(),
+            compactions: Arc::new(AtomicU64::new(0)),
+            frame_timeouts: Arc::new(AtomicU64::new(0)),
+            command_stats: Arc::new(CommandStats::new()),
+            counters: Arc::default(),
+            chaos: Arc::default(),
+            load_shedder: Arc::default(),
+            waiters: Arc::default(),
+            events: Arc::default(),
+            clock: Arc::new(SystemClock),
+            scan_order: KeyHasher::new(),
+        }
+    }
+
+    /// Judge expiration by `clock` instead of the system clock, so tests can
+    /// move time forward rather than sleep
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The current time on the store's clock
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Unwrap a lookup of `key`, reporting the key if the lookup deleted it
+    /// as expired
+    fn live<R>(&self, key: &[u8], lookup: Lookup<R>) -> Option<R> {
+        match lookup {
+            Lookup::Found(value) => Some(value),
+            Lookup::Missing => None,
+            Lookup::Expired => {
+                self.expired(vec![Bytes::copy_from_slice(key)]);
+                None
+            }
+        }
+    }
+
+    /// Like `live`, also counting a keyspace hit or miss, for reads
+    fn read<R>(&self, key: &[u8], lookup: Lookup<R>) -> Option<R> {
+        let value = self.live(key, lookup);
+        let counter = match value {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Count keys deleted as expired, and tell any `on_key_event` callbacks
+    /// and subscribers
+    fn expired(&self, keys: Vec<Bytes>) {
+        self.events.expire_hooked(&keys);
+        self.counters
+            .expired
+            .fetch_add(keys.len() as u64, Ordering::Relaxed);
+        for key in &keys {
+            self.events.changed(ChangeKind::Expired, key, || None);
+        }
+        self.events.emit(KeyEventReason::Expired, keys);
+    }
+
+    /// Wake clients parked on `key` and tell subscribers it was set to `value`
+    fn written(&self, key: &Bytes, value: &Bytes) {
+        self.waiters.wake(key);
+        self.events
+            .changed(ChangeKind::Set, key, || Some(value.clone()));
+    }
+
+    /// Get a value by key, returns None if key doesn't exist or is expired
+    pub async fn get(&self, key: &[u8]) -> Option<Bytes> {
+        let lookup = self
+            .keyspace
+            .get_live(key, self.now(), |value| value.data.to_bytes())
+            .await;
+        self.read(key, lookup)
+    }
+
+    /// Set a key to a value
+    pub async fn set(&self, key: Bytes, value: Bytes) {
+        self.keyspace
+            .insert(key.clone(), StoredValue::new(value.clone()))
+            .await;
+        self.written(&key, &value);
+    }
+
+    /// Set a key with expiration (in seconds), jittered by `ttl-jitter`
+    pub async fn set_ex(&self, key: Bytes, value: Bytes, seconds: u64) {
+        let ttl = self.jittered(Duration::from_secs(seconds));
+        let stored = StoredValue::with_expiry(value.clone(), self.now() + ttl);
+        self.keyspace.insert(key.clone(), stored).await;
+        self.written(&key, &value);
+    }
+
+    /// Set a key with expiration (in milliseconds), jittered by `ttl-jitter`
+    pub async fn set_px(&self, key: Bytes, value: Bytes, millis: u64) {
+        let ttl = self.jittered(Duration::from_millis(millis));
+        let stored = StoredValue::with_expiry(value.clone(), self.now() + ttl);
+        self.keyspace.insert(key.clone(), stored).await;
+        self.written(&key, &value);
+    }
+
+    /// Set a key only if it doesn't exist. Returns true if set, false if key already exists
+    pub async fn set_nx(&self, key: Bytes, value: Bytes) -> bool {
+        let stored = StoredValue::new(value.clone());
+        let set = self
+            .keyspace
+            .compute(&key, self.now(), |existing| match existing {
+                Some(_) => (false, None),
+                None => (true, Some(stored)),
+            })
+            .await;
+        if set {
+            self.written(&key, &value);
+        }
+        set
+    }
+
+    /// Delete one or more keys. Returns the number of keys deleted
+    pub async fn del(&self, keys: &[Bytes]) -> i64 {
+        let deleted = self.keyspace.remove_many(keys).await;
+        for key in &deleted {
+            self.waiters.wake(key);
+            self.events.changed(ChangeKind::Del, key, || None);
+        }
+        deleted.len() as i64
+    }
+
+    /// Increment value by 1. Returns the new value or error if not an integer
+    pub async fn incr(&self, key: &[u8]) -> Result<i64, CommandError> {
+        self.incr_by(key, 1).await
+    }
+
+    /// Decrement value by 1. Returns the new value or error if not an integer
+    pub async fn decr(&self, key: &[u8]) -> Result<i64, CommandError> {
+        self.incr_by(key, -1).await
+    }
+
+    /// Increment value by a specific amount. Returns the new value or error if not an integer
+    pub async fn incr_by(&self, key: &[u8], delta: i64) -> Result<i64, CommandError> {
+        let result = self.add(key, delta).await;
+        if let Ok(value) = result {
+            self.waiters.wake(key);
+            self.events
+                .changed(ChangeKind::Set, key, || Some(value.to_string().into()));
+        }
+        result
+    }
+
+    async fn add(&self, key: &[u8], delta: i64) -> Result<i64, CommandError> {
+        // Integer-encoded counters are bumped atomically under the shared
+        // lock, so concurrent INCRs of hot keys don't serialize on a writer
+        let lookup = self.keyspace.add_in_place(key, self.now(), delta).await;
+        let in_place = self.live(key, lookup).flatten();
+        if let Some(result) = in_place {
+            return result.ok_or(CommandError::Overflow);
+        }
+
+        // Missing or string-encoded: parse and replace under the write lock
+        self.keyspace
+            .compute(key, self.now(), move |existing| {
+                let current = match existing {
+                    Some(value) => match value.data.as_int() {
+                        Some(n) => n,
+                        None => return (Err(CommandError::NotInteger), None),
+                    },
+                    None => 0,
+                };
+
+                match current.checked_add(delta) {
+                    Some(new_value) => {
+                        // Like Redis, incrementing keeps the key's TTL
+                        let replacement = StoredValue {
+                            expires_at: existing.and_then(|value| value.expires_at),
+                            ..StoredValue::from_int(new_value)
+                        };
+                        (Ok(new_value), Some(replacement))
+                    }
+                    None => (Err(CommandError::Overflow), None),
+                }
+            })
+            .await
+    }
+
+    /// Encoding and string length of a key's value, for DEBUG OBJECT
+    pub async fn object_info(&self, key: &[u8]) -> Option<(&'static str, usize)> {
+        let lookup = self
+            .keyspace
+            .get_live(key, self.now(), |value| {
+                (value.data.encoding(), value.data.len())
+            })
+            .await;
+        self.live(key, lookup)
+    }
+
+    /// Get multiple keys at once
+    pub async fn mget(&self, keys: &[Bytes]) -> Vec<Option<Bytes>> {
+        self.keyspace
+            .get_live_many(keys, self.now(), |value| value.data.to_bytes())
+            .await
+            .into_iter()
+            .zip(keys)
+            .map(|(lookup, key)| self.read(key, lookup))
+            .collect()
     }
 
     /// Set multiple keys at once
-    pub async fn mset(&self, pairs: Vec<(String, Vec<u8>)>) {
-        let mut write_guard = self.data.write().await;
-        for (key, value) in pairs {
-            write_guard.insert(key, StoredValue::new(value));
+    pub async fn mset(&self, pairs: Vec<(Bytes, Bytes)>) {
+        let entries = pairs
+            .iter()
+            .map(|(key, value)| (key.clone(), StoredValue::new(value.clone())))
+            .collect::<Vec<_>>();
+        self.keyspace.insert_many(entries).await;
+        for (key, value) in &pairs {
+            self.written(key, value);
         }
     }
 
-    /// Set expiration on an existing key.
+    /// Set expiration on an existing key, jittered by `ttl-jitter`.
     /// If seconds <= 0, deletes the key.
     /// Returns 1 if timeout was set/key was deleted, 0 if key doesn't exist.
-    pub async fn expire(&self, key: &str, seconds: i64) -> i64 {
-        let mut write_guard = self.data.write().await;
-
+    pub async fn expire(&self, key: &[u8], seconds: i64) -> i64 {
         // Handle negative/zero seconds - delete the key
         if seconds <= 0 {
-            if let Some(value) = write_guard.get(key)
-                && !value.is_expired()
-            {
-                write_guard.remove(key);
-                return 1;
-            }
-            write_guard.remove(key); // Clean up if expired
-            return 0;
+            return match self.keyspace.remove(key).await {
+                Some(value) if !value.is_expired(self.now()) => {
+                    self.waiters.wake(key);
+                    self.events.changed(ChangeKind::Del, key, || None);
+                    1
+                }
+                Some(_) => {
+                    self.expired(vec![Bytes::copy_from_slice(key)]);
+                    0
+                }
+                None => 0,
+            };
         }
 
         // Set expiration on existing non-expired key
-        if let Some(value) = write_guard.get_mut(key) {
-            if value.is_expired() {
-                write_guard.remove(key);
-                return 0;
-            }
-            value.expires_at = Some(Instant::now() + Duration::from_secs(seconds as u64));
-            1
-        } else {
-            0
+        let expires_at = self.now() + self.jittered(Duration::from_secs(seconds as u64));
+        let lookup = self
+            .keyspace
+            .modify_live(key, self.now(), move |value| {
+                value.expires_at = Some(expires_at)
+            })
+            .await;
+        let found = self.live(key, lookup).is_some();
+        if found {
+            self.waiters.wake(key);
         }
+        i64::from(found)
     }
 
     /// Get TTL of a key in seconds.
     /// Returns -2 if key doesn't exist, -1 if key has no expiry, or remaining seconds.
-    pub async fn ttl(&self, key: &str) -> i64 {
-        let read_guard = self.data.read().await;
-
-        if let Some(value) = read_guard.get(key) {
-            if value.is_expired() {
-                drop(read_guard);
-                self.data.write().await.remove(key);
-                return -2;
-            }
-            match value.expires_at {
-                Some(expires_at) => {
-                    let now = Instant::now();
-                    if expires_at > now {
-                        (expires_at - now).as_secs() as i64
-                    } else {
-                        -2 // Should not happen due to is_expired check
-                    }
+    pub async fn ttl(&self, key: &[u8]) -> i64 {
+        let lookup = self
+            .keyspace
+            .get_live(key, self.now(), |value| value.expires_at)
+            .await;
+        match self.read(key, lookup) {
+            Some(Some(expires_at)) => {
+                let now = self.now();
+                if expires_at > now {
+                    (expires_at - now).as_secs() as i64
+                } else {
+                    -2 // Should not happen due to is_expired check
                 }
-                None => -1, // Key exists but has no expiration
             }
-        } else {
-            -2 // Key doesn't exist
+            Some(None) => -1, // Key exists but has no expiration
+            None => -2,       // Key doesn't exist
+        }
+    }
+
+    /// Get TTL of a key in milliseconds, with `ttl`'s -2 and -1
+    pub async fn pttl(&self, key: &[u8]) -> i64 {
+        let lookup = self
+            .keyspace
+            .get_live(key, self.now(), |value| value.expires_at)
+            .await;
+        match self.read(key, lookup) {
+            Some(Some(expires_at)) => expires_at
+                .checked_duration_since(self.now())
+                .map_or(-2, |left| left.as_millis() as i64),
+            Some(None) => -1,
+            None => -2,
         }
     }
 
     /// Remove expiration from a key.
     /// Returns 1 if expiration was removed, 0 if key doesn't exist or had no expiry.
-    pub async fn persist(&self, key: &str) -> i64 {
-        let mut write_guard = self.data.write().await;
+    pub async fn persist(&self, key: &[u8]) -> i64 {
+        let lookup = self
+            .keyspace
+            .modify_live(key, self.now(), |value| value.expires_at.take().is_some())
+            .await;
+        let persisted = self.live(key, lookup) == Some(true);
+        if persisted {
+            self.waiters.wake(key);
+        }
+        i64::from(persisted)
+    }
 
-        if let Some(value) = write_guard.get_mut(key) {
-            if value.is_expired() {
-                write_guard.remove(key);
-                return 0;
+    /// Get all keys matching a glob pattern. Supports * and ? wildcards.
+    pub async fn keys(&self, pattern: &[u8]) -> Vec<Bytes> {
+        let (keys, expired) = self
+            .keyspace
+            .matching_keys(self.now(), |key| glob_match(pattern, key))
+            .await;
+        self.expired(expired);
+        keys
+    }
+
+    /// Walk the keyspace a page at a time, partition by partition, and
+    /// within each in the order of a hash of the keys fixed for the store's
+    /// lifetime. Returns the cursor for the next page, 0 once every key has
+    /// been visited, and this page's keys matching `pattern`. Like Redis, a
+    /// key present for the whole walk is returned at least once, and a page
+    /// holds about `count` keys before filtering.
+    ///
+    /// The cursor holds the partition in its top bits and the hash to go on
+    /// from in the rest, so a page costs a pass over the partitions it
+    /// draws from, not over the whole keyspace.
+    pub async fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> (u64, Vec<Bytes>) {
+        let partitions = self.keyspace.partitions();
+        // Bits taken by the partition index
+        let bits = usize::BITS - (partitions - 1).leading_zeros();
+        let position = |key: &[u8]| self.scan_order.hash_one(key).checked_shr(bits).unwrap_or(0);
+        let (mut partition, mut from) = match bits {
+            0 => (0, cursor),
+            bits => ((cursor >> (64 - bits)) as usize, cursor << bits >> bits),
+        };
+        let mut wanted = count.max(1);
+        let mut page = Vec::new();
+        while partition < partitions {
+            let (keys, expired) = self
+                .keyspace
+                .partition_keys(partition, self.now(), |key| position(key) >= from)
+                .await;
+            self.expired(expired);
+            let mut keys: Vec<(u64, Bytes)> =
+                keys.into_iter().map(|key| (position(&key), key)).collect();
+            // The `wanted` lowest positions, without sorting the rest
+            let mut next = None;
+            if keys.len() > wanted {
+                keys.select_nth_unstable_by_key(wanted - 1, |(position, _)| *position);
+                let last = keys[wanted - 1].0;
+                let rest = keys.split_off(wanted);
+                for (position, key) in rest {
+                    // Keys sharing the last position on the page go with it,
+                    // as the cursor can't point between them
+                    match position == last {
+                        true => keys.push((position, key)),
+                        false => next = Some(next.map_or(position, |next: u64| next.min(position))),
+                    }
+                }
             }
-            if value.expires_at.is_some() {
-                value.expires_at = None;
-                1
-            } else {
-                0 // No expiration to remove
+            wanted = wanted.saturating_sub(keys.len());
+            page.extend(keys.into_iter().map(|(_, key)| key));
+            match next {
+                Some(position) => {
+                    from = position;
+                    break;
+                }
+                None => (partition, from) = (partition + 1, 0),
+            }
+            if wanted == 0 {
+                break;
             }
-        } else {
-            0
         }
+        let next = match partition < partitions {
+            true => ((partition as u64).checked_shl(64 - bits).unwrap_or(0)) | from,
+            false => 0,
+        };
+        let page = page
+            .into_iter()
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .collect();
+        (next, page)
     }
 
-    /// Get all keys matching a glob pattern. Supports * and ? wildcards.
-    pub async fn keys(&self, pattern: &str) -> Vec<String> {
-        let read_guard = self.data.read().await;
-        let mut matching_keys = Vec::new();
-        let mut expired_keys = Vec::new();
+    /// Number of keys held, including expired ones not yet deleted (DBSIZE)
+    pub async fn dbsize(&self) -> usize {
+        self.keyspace.occupancy().await.0
+    }
 
-        for (key, value) in read_guard.iter() {
-            if value.is_expired() {
-                expired_keys.push(key.clone());
-            } else if glob_match(pattern, key) {
-                matching_keys.push(key.clone());
-            }
+    /// Length of a key's value, 0 if it doesn't exist (STRLEN)
+    pub async fn strlen(&self, key: &[u8]) -> usize {
+        let lookup = self
+            .keyspace
+            .get_live(key, self.now(), |value| value.data.len())
+            .await;
+        self.read(key, lookup).unwrap_or(0)
+    }
+
+    /// Approximate bytes a key and its value take up (MEMORY USAGE): the map
+    /// entry, the key and any buffer of the value's own
+    pub async fn memory_usage(&self, key: &[u8]) -> Option<usize> {
+        let key_len = key.len();
+        let lookup = self
+            .keyspace
+            .get_live(key, self.now(), move |value| entry_size(key_len, value))
+            .await;
+        self.live(key, lookup)
+    }
+
+    /// Live keys and their approximate size, as MEMORY USAGE counts it,
+    /// under each of `prefixes`, in order. Takes a full pass over the
+    /// keyspace, like KEYS.
+    pub async fn prefix_usage(&self, prefixes: &[Bytes]) -> Vec<PrefixUsage> {
+        self.keyspace
+            .prefix_usage(prefixes.into(), self.now())
+            .await
+    }
+
+    /// Every live key with its value and the time it has left, for an
+    /// embedder to keep and `import` later, here or in another process.
+    /// The keys are copied as of one instant: a write made meanwhile is
+    /// either wholly in it or not at all, MSET and INCRs of counters included.
+    /// Shards are copied one at a time, and a write waits for the copy of its
+    /// own shard at most. With the `dashmap` backend shards are copied one at
+    /// a time without that guarantee. Takes a full pass over the keyspace,
+    /// like KEYS.
+    pub async fn export(&self) -> Snapshot {
+        let now = self.now();
+        let entries = self
+            .keyspace
+            .live_entries(now)
+            .await
+            .into_iter()
+            .map(|(key, data, expires_at)| snapshot::Entry {
+                key,
+                value: snapshot::Value::String(data),
+                ttl: expires_at.map(|expires_at| expires_at.saturating_duration_since(now)),
+            })
+            .collect();
+        Snapshot { entries }
+    }
+
+    /// Write every key in `snapshot`, replacing any value a key already
+    /// has; keys it doesn't have are left alone, so importing into a new
+    /// store gives back the snapshot's state. TTLs count from now and
+    /// aren't jittered, and size limits don't apply.
+    pub async fn import(&self, snapshot: Snapshot) {
+        let now = self.now();
+        let entries: Vec<_> = snapshot
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let snapshot::Value::String(data) = entry.value;
+                (entry.key, data, entry.ttl)
+            })
+            .collect();
+        let stored = entries
+            .iter()
+            .map(|(key, data, ttl)| {
+                let value = match ttl {
+                    Some(ttl) => StoredValue::with_expiry(data.clone(), now + *ttl),
+                    None => StoredValue::new(data.clone()),
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        self.keyspace.insert_many(stored).await;
+        for (key, data, _) in &entries {
+            self.written(key, data);
         }
+    }
 
-        drop(read_guard);
+    /// Move each expiration SETEX and EXPIRE set by a random amount of up to
+    /// `percent` of its TTL either way, so keys written together with the
+    /// same TTL don't all expire, and get fetched again, at once. 0 turns it
+    /// off.
+    pub fn set_ttl_jitter(&self, percent: u32) {
+        self.ttl_jitter.store(percent, Ordering::Relaxed);
+    }
 
-        // Clean up expired keys
-        if !expired_keys.is_empty() {
-            let mut write_guard = self.data.write().await;
-            for key in expired_keys {
-                write_guard.remove(&key);
+    /// Refuse keys over `max_key` bytes and values over `max_value` bytes in
+    /// write commands, well below what the protocol accepts, so one stray
+    /// write can't fill a shared server; 0 lifts a limit
+    pub fn set_size_limits(&self, max_key: usize, max_value: usize) {
+        self.max_key_size.store(max_key, Ordering::Relaxed);
+        self.max_value_size.store(max_value, Ordering::Relaxed);
+    }
+
+    /// Check a key, and the value if one is written, against the size limits
+    pub fn check_size(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), CommandError> {
+        let max_key = self.max_key_size.load(Ordering::Relaxed);
+        if max_key > 0 && key.len() > max_key {
+            return Err(CommandError::KeyTooLarge {
+                len: key.len(),
+                max: max_key,
+            });
+        }
+        let max_value = self.max_value_size.load(Ordering::Relaxed);
+        match value {
+            Some(value) if max_value > 0 && value.len() > max_value => {
+                Err(CommandError::ValueTooLarge {
+                    len: value.len(),
+                    max: max_value,
+                })
             }
+            _ => Ok(()),
+        }
+    }
+
+    /// `ttl` moved by a random fraction of `ttl-jitter`, kept to at least 1ms
+    fn jittered(&self, ttl: Duration) -> Duration {
+        let percent = self.ttl_jitter.load(Ordering::Relaxed);
+        if percent == 0 {
+            return ttl;
         }
+        let draw = self
+            .scan_order
+            .hash_one(self.jitter_draws.fetch_add(1, Ordering::Relaxed));
+        // Uniform in [-1, 1]
+        let unit = draw as f64 / u64::MAX as f64 * 2.0 - 1.0;
+        ttl.mul_f64(1.0 + unit * f64::from(percent) / 100.0)
+            .max(Duration::from_millis(1))
+    }
+
+    /// Enable or disable the active expiration cycle (DEBUG SET-ACTIVE-EXPIRE).
+    /// Passive expiration on access is unaffected.
+    pub fn set_active_expire(&self, enabled: bool) {
+        self.active_expire.store(enabled, Ordering::Relaxed);
+    }
 
-        matching_keys
+    /// Whether the active expiration cycle is currently enabled
+    pub fn active_expire_enabled(&self) -> bool {
+        self.active_expire.load(Ordering::Relaxed)
     }
 
     /// Start background task for active expiration.
@@ -335,60 +2251,156 @@ impl Store {
             let mut interval = tokio::time::interval(Duration::from_millis(100));
             loop {
                 interval.tick().await;
-                store.expire_random_keys().await;
+                if store.active_expire_enabled() {
+                    store.expire_random_keys().await;
+                }
             }
         })
     }
 
-    /// Sample keys and delete expired ones.
-    /// Redis samples 20 keys per cycle and continues if >25% are expired.
+    /// Sample keys in every partition and delete expired ones.
     async fn expire_random_keys(&self) {
-        const SAMPLE_SIZE: usize = 20;
-        const EXPIRY_THRESHOLD: f64 = 0.25;
-
-        loop {
-            let keys_to_check: Vec<String> = {
-                let read_guard = self.data.read().await;
-                if read_guard.is_empty() {
-                    return;
-                }
-                // Sample up to SAMPLE_SIZE keys
-                read_guard.keys().take(SAMPLE_SIZE).cloned().collect()
-            };
+        let expired = self.keyspace.expire_sampled_keys(self.now()).await;
+        self.expired(expired);
+    }
 
-            if keys_to_check.is_empty() {
-                return;
+    /// Start the low-priority background task that gives memory back after
+    /// large deletions by shrinking oversized partitions.
+    pub fn start_compaction(store: Store) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(COMPACT_INTERVAL);
+            // The first tick completes immediately; nothing needs compacting at startup
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                store.compact().await;
             }
+        })
+    }
 
-            let mut expired_count = 0;
-            let mut expired_keys = Vec::new();
+    /// Shrink oversized partitions now, returning how many were shrunk
+    pub async fn compact(&self) -> usize {
+        let compacted = self.keyspace.compact().await;
+        self.compactions
+            .fetch_add(compacted as u64, Ordering::Relaxed);
+        compacted
+    }
 
-            {
-                let read_guard = self.data.read().await;
-                for key in &keys_to_check {
-                    if let Some(value) = read_guard.get(key)
-                        && value.is_expired()
-                    {
-                        expired_keys.push(key.clone());
-                        expired_count += 1;
-                    }
-                }
-            }
+    /// Call counts and latencies of each command
+    pub fn command_stats(&self) -> &CommandStats {
+        &self.command_stats
+    }
 
-            // Delete expired keys
-            if !expired_keys.is_empty() {
-                let mut write_guard = self.data.write().await;
-                for key in expired_keys {
-                    write_guard.remove(&key);
-                }
-            }
+    /// Faults injected into the server serving this store
+    pub(crate) fn chaos(&self) -> &Arc<Chaos> {
+        &self.chaos
+    }
 
-            // If less than 25% were expired, stop
-            let ratio = expired_count as f64 / keys_to_check.len() as f64;
-            if ratio < EXPIRY_THRESHOLD {
-                return;
+    /// Overload state, and the commands and clients refused because of it
+    pub(crate) fn load_shedder(&self) -> &Arc<LoadShedder> {
+        &self.load_shedder
+    }
+
+    /// Count a client closed for not finishing a frame in time
+    pub(crate) fn count_frame_timeout(&self) {
+        self.frame_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Clients closed for not finishing a frame in time, for INFO stats
+    pub(crate) fn frame_timeouts(&self) -> u64 {
+        self.frame_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Clients parked by blocking commands; every write through the store
+    /// wakes the longest parked on the written key
+    pub fn waiters(&self) -> &Waiters {
+        &self.waiters
+    }
+
+    /// Call `callback` with every key that expires from now on, whether it
+    /// is deleted on access or by active expiration. The callback runs on a
+    /// task of its own, spawned on the current tokio runtime, and sees
+    /// events one at a time in order; the store doesn't wait for it.
+    ///
+    /// ```no_run
+    /// # async fn embed(store: rudis::Store) {
+    /// store.on_key_event(|event| async move {
+    ///     println!("{:?} went away: {:?}", event.key, event.reason);
+    /// });
+    /// # }
+    /// ```
+    pub fn on_key_event<F, Fut>(&self, callback: F)
+    where
+        F: FnMut(KeyEvent) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.events.listen(callback);
+    }
+
+    /// Call `hook` with each key deleted as expired, lazily or actively,
+    /// before the deletion returns, until the guard is dropped
+    pub(crate) fn hook_expiry(
+        &self,
+        hook: impl Fn(&Bytes) + Send + Sync + 'static,
+    ) -> ExpiryHookGuard {
+        ExpiryHookGuard {
+            events: self.events.clone(),
+            id: self.events.hook_expiry(hook),
+        }
+    }
+
+    /// Changes to the keys starting with `prefix` from now on, an empty
+    /// prefix for every key: values set, keys deleted and keys expired, in
+    /// the order they were made, each set with the new value. This is for
+    /// embedders and goes around the RESP layer entirely.
+    ///
+    /// ```no_run
+    /// # async fn embed(store: rudis::Store) {
+    /// let mut changes = store.subscribe("session:");
+    /// while let Some(change) = changes.next().await {
+    ///     println!("{:?} {:?} = {:?}", change.kind, change.key, change.value);
+    /// }
+    /// # }
+    /// ```
+    pub fn subscribe(&self, prefix: impl Into<Bytes>) -> Changes {
+        self.events.subscribe(prefix.into())
+    }
+
+    /// Key events dropped because an `on_key_event` callback fell too far
+    /// behind
+    pub fn dropped_key_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
+    /// Per-shard lock contention, for DEBUG LOCKSTATS; None for backends
+    /// that don't lock partitions themselves (DashMap's locks are internal)
+    pub fn lock_stats(&self) -> Option<Vec<LockStats>> {
+        match &self.keyspace {
+            Keyspace::Sharded { shards, .. } => {
+                Some(shards.iter().map(Shard::lock_stats).collect())
             }
-            // Otherwise, continue sampling (Redis behavior)
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(_) => None,
+            Keyspace::Owned(_) => None,
+        }
+    }
+
+    /// Lookup and expiration counts since startup, for INFO stats
+    pub fn keyspace_stats(&self) -> KeyspaceStats {
+        KeyspaceStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            expired_keys: self.counters.expired.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Keyspace size and allocation, for INFO memory
+    pub async fn memory_stats(&self) -> MemoryStats {
+        let (keys, capacity) = self.keyspace.occupancy().await;
+        MemoryStats {
+            keys,
+            capacity,
+            compactions: self.compactions.load(Ordering::Relaxed),
         }
     }
 }
@@ -402,30 +2414,136 @@ impl Default for Store {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+
+    fn sharded(store: &Store) -> &[Shard] {
+        match &store.keyspace {
+            Keyspace::Sharded { shards, .. } => shards,
+            #[cfg(feature = "dashmap")]
+            Keyspace::Concurrent(_) => unreachable!("expected a sharded keyspace"),
+            Keyspace::Owned(_) => unreachable!("expected a sharded keyspace"),
+        }
+    }
 
     #[tokio::test]
     async fn test_get_set() {
         let store = Store::new();
-        store.set("key1".to_string(), b"value1".to_vec()).await;
-        assert_eq!(store.get("key1").await, Some(b"value1".to_vec()));
+        store
+            .set("key1".into(), Bytes::from_static(b"value1"))
+            .await;
+        assert_eq!(
+            store.get(b"key1").await,
+            Some(Bytes::from_static(b"value1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_keys() {
+        let store = Store::new();
+        let key = Bytes::from_static(b"\x00\xff\xfe");
+        store.set(key.clone(), Bytes::from_static(b"value")).await;
+        assert_eq!(store.get(&key).await, Some(Bytes::from_static(b"value")));
+        assert_eq!(store.keys(b"\x00?\xfe").await, vec![key]);
+    }
+
+    #[tokio::test]
+    async fn test_get_shares_value_buffer() {
+        let store = Store::new();
+        let value = Bytes::from(vec![b'x'; 4096]);
+        store.set("big".into(), value.clone()).await;
+        let read = store.get(b"big").await.unwrap();
+        assert_eq!(read.as_ptr(), value.as_ptr());
+    }
+
+    #[test]
+    fn test_value_encodings() {
+        let encode = |s: &'static str| ValueData::from_bytes(Bytes::from_static(s.as_bytes()));
+
+        let int = |n| ValueData::Int(AtomicI64::new(n));
+        assert_eq!(encode("12345"), int(12345));
+        assert_eq!(encode("-9223372036854775808"), int(i64::MIN));
+        assert_eq!(encode("0"), int(0));
+        // Non-canonical spellings must read back unchanged
+        for text in ["007", "+1", "-0", " 1", "99999999999999999999"] {
+            let value = encode(text);
+            assert_eq!(value.encoding(), "embstr", "{}", text);
+            assert_eq!(value.to_bytes(), text);
+        }
+        assert_eq!(encode("007").as_int(), Some(7));
+
+        let short = encode("hello");
+        assert!(matches!(short, ValueData::Inline { len: 5, .. }));
+        assert_eq!(short.to_bytes(), "hello");
+        assert_eq!(short.len(), 5);
+        assert_eq!(int(-42).to_bytes(), "-42");
+        assert_eq!(int(-42).len(), 3);
+
+        let long = ValueData::from_bytes(Bytes::from(vec![b'x'; 100]));
+        assert_eq!(long.encoding(), "raw");
+    }
+
+    #[tokio::test]
+    async fn test_incr_keeps_integer_encoding() {
+        let store = Store::new();
+        store.set("n".into(), "10".into()).await;
+        assert_eq!(store.incr(b"n").await, Ok(11));
+        assert_eq!(store.object_info(b"n").await, Some(("int", 2)));
+        assert_eq!(store.get(b"n").await, Some(Bytes::from("11")));
+    }
+
+    #[tokio::test]
+    async fn test_incr_keeps_ttl() {
+        let store = Store::new();
+        // Once through the in-place path and once through the replacing path
+        store.set_ex("n".into(), "1".into(), 100).await;
+        store.set_ex("s".into(), "007".into(), 100).await;
+        assert_eq!(store.incr(b"n").await, Ok(2));
+        assert_eq!(store.incr(b"s").await, Ok(8));
+        assert!(store.ttl(b"n").await > 0);
+        assert!(store.ttl(b"s").await > 0);
+
+        store.set("max".into(), i64::MAX.to_string().into()).await;
+        assert_eq!(store.incr(b"max").await, Err(CommandError::Overflow));
+        assert_eq!(store.get(b"max").await, Some(i64::MAX.to_string().into()));
+    }
+
+    #[test]
+    fn test_key_hashing_is_seeded_per_store() {
+        // With a fixed hash function every store would place keys identically,
+        // letting an attacker precompute colliding names
+        let placement = |store: &Store| match &store.keyspace {
+            Keyspace::Sharded { hasher, shards } => (0..64)
+                .map(|i| hasher.hash_one(format!("key:{}", i).as_bytes()) as usize % shards.len())
+                .collect::<Vec<_>>(),
+            _ => unreachable!("expected a sharded keyspace"),
+        };
+        let (a, b) = (Store::with_shards(16), Store::with_shards(16));
+        assert_ne!(placement(&a), placement(&b));
     }
 
     #[tokio::test]
     async fn test_get_nonexistent() {
         let store = Store::new();
-        assert_eq!(store.get("nonexistent").await, None);
+        assert_eq!(store.get(b"nonexistent").await, None);
     }
 
     #[tokio::test]
     async fn test_del() {
         let store = Store::new();
-        store.set("key1".to_string(), b"value1".to_vec()).await;
-        store.set("key2".to_string(), b"value2".to_vec()).await;
+        store
+            .set("key1".into(), Bytes::from_static(b"value1"))
+            .await;
+        store
+            .set("key2".into(), Bytes::from_static(b"value2"))
+            .await;
 
-        let deleted = store.del(&["key1".to_string(), "key3".to_string()]).await;
+        let deleted = store.del(&["key1".into(), "key3".into()]).await;
         assert_eq!(deleted, 1);
-        assert_eq!(store.get("key1").await, None);
-        assert_eq!(store.get("key2").await, Some(b"value2".to_vec()));
+        assert_eq!(store.get(b"key1").await, None);
+        assert_eq!(
+            store.get(b"key2").await,
+            Some(Bytes::from_static(b"value2"))
+        );
     }
 
     #[tokio::test]
@@ -433,222 +2551,322 @@ mod tests {
         let store = Store::new();
 
         // First set should succeed
-        assert!(store.set_nx("key1".to_string(), b"value1".to_vec()).await);
+        assert!(
+            store
+                .set_nx("key1".into(), Bytes::from_static(b"value1"))
+                .await
+        );
 
         // Second set should fail
-        assert!(!store.set_nx("key1".to_string(), b"value2".to_vec()).await);
+        assert!(
+            !store
+                .set_nx("key1".into(), Bytes::from_static(b"value2"))
+                .await
+        );
 
         // Value should be unchanged
-        assert_eq!(store.get("key1").await, Some(b"value1".to_vec()));
+        assert_eq!(
+            store.get(b"key1").await,
+            Some(Bytes::from_static(b"value1"))
+        );
     }
 
     #[tokio::test]
     async fn test_incr_new_key() {
         let store = Store::new();
-        assert_eq!(store.incr("counter").await, Ok(1));
-        assert_eq!(store.incr("counter").await, Ok(2));
+        assert_eq!(store.incr(b"counter").await, Ok(1));
+        assert_eq!(store.incr(b"counter").await, Ok(2));
     }
 
     #[tokio::test]
     async fn test_incr_existing_key() {
         let store = Store::new();
-        store.set("counter".to_string(), b"10".to_vec()).await;
-        assert_eq!(store.incr("counter").await, Ok(11));
+        store.set("counter".into(), Bytes::from_static(b"10")).await;
+        assert_eq!(store.incr(b"counter").await, Ok(11));
     }
 
     #[tokio::test]
     async fn test_incr_invalid_value() {
         let store = Store::new();
-        store.set("key".to_string(), b"not a number".to_vec()).await;
-        assert!(store.incr("key").await.is_err());
+        store
+            .set("key".into(), Bytes::from_static(b"not a number"))
+            .await;
+        assert_eq!(store.incr(b"key").await, Err(CommandError::NotInteger));
     }
 
     #[tokio::test]
     async fn test_decr() {
         let store = Store::new();
-        store.set("counter".to_string(), b"10".to_vec()).await;
-        assert_eq!(store.decr("counter").await, Ok(9));
+        store.set("counter".into(), Bytes::from_static(b"10")).await;
+        assert_eq!(store.decr(b"counter").await, Ok(9));
     }
 
     #[tokio::test]
     async fn test_incr_by() {
         let store = Store::new();
-        store.set("counter".to_string(), b"10".to_vec()).await;
-        assert_eq!(store.incr_by("counter", 5).await, Ok(15));
-        assert_eq!(store.incr_by("counter", -3).await, Ok(12));
+        store.set("counter".into(), Bytes::from_static(b"10")).await;
+        assert_eq!(store.incr_by(b"counter", 5).await, Ok(15));
+        assert_eq!(store.incr_by(b"counter", -3).await, Ok(12));
     }
 
     #[tokio::test]
     async fn test_mget_mset() {
         let store = Store::new();
 
-store
+        store
             .mset(vec![
-                ("key1".to_string(), b"value1".to_vec()),
-                ("key2".to_string(), b"value2".to_vec()),
+                ("key1".into(), Bytes::from_static(b"value1")),
+                ("key2".into(), Bytes::from_static(b"value2")),
             ])
-    .await;
+            .await;
 
         let results = store
-            .mget(&["key1".to_string(), "key2".to_string(), "key3".to_string()])
+            .mget(&["key1".into(), "key2".into(), "key3".into()])
             .await;
         assert_eq!(
             results,
-            vec![Some(b"value1".to_vec()), Some(b"value2".to_vec()), None,]
+            vec![
+                Some(Bytes::from_static(b"value1")),
+                Some(Bytes::from_static(b"value2")),
+                None,
+            ]
         );
     }
 
     #[tokio::test]
     async fn test_set_ex_expiry() {
-        let store = Store::new();
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::new().with_clock(clock.clone());
 
         // Set with 1 second expiry
-        store.set_ex("key".to_string(), b"value".to_vec(), 1).await;
+        store
+            .set_ex("key".into(), Bytes::from_static(b"value"), 1)
+            .await;
 
-        // Should exist immediately
-        assert_eq!(store.get("key").await, Some(b"value".to_vec()));
+        // Should exist immediately, and right up to the deadline
+        assert_eq!(store.get(b"key").await, Some(Bytes::from_static(b"value")));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.get(b"key").await, Some(Bytes::from_static(b"value")));
 
-        // Wait for expiry
-        tokio::time::sleep(Duration::from_secs(2)).await;
+        clock.advance(Duration::from_millis(1));
 
         // Should be expired now
-        assert_eq!(store.get("key").await, None);
+        assert_eq!(store.get(b"key").await, None);
     }
 
     // Glob matching tests
     #[test]
     fn test_glob_match_star() {
         // * matches any sequence including empty
-        assert!(glob_match("*", "anything"));
-        assert!(glob_match("*", ""));
-        assert!(glob_match("foo*", "foobar"));
-        assert!(glob_match("foo*", "foo"));
-        assert!(glob_match("*bar", "foobar"));
-        assert!(glob_match("*bar", "bar"));
-        assert!(glob_match("*oba*", "foobar"));
-        assert!(!glob_match("foo*", "bar"));
-        assert!(!glob_match("*foo", "foobar"));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"foo*", b"foobar"));
+        assert!(glob_match(b"foo*", b"foo"));
+        assert!(glob_match(b"*bar", b"foobar"));
+        assert!(glob_match(b"*bar", b"bar"));
+        assert!(glob_match(b"*oba*", b"foobar"));
+        assert!(!glob_match(b"foo*", b"bar"));
+        assert!(!glob_match(b"*foo", b"foobar"));
     }
 
     #[test]
     fn test_glob_match_question() {
         // ? matches exactly one character
-        assert!(glob_match("?", "a"));
-        assert!(!glob_match("?", ""));
-        assert!(!glob_match("?", "ab"));
-        assert!(glob_match("fo?", "foo"));
-        assert!(glob_match("f??", "foo"));
-        assert!(!glob_match("f?", "foo"));
-        assert!(glob_match("???", "abc"));
+        assert!(glob_match(b"?", b"a"));
+        assert!(!glob_match(b"?", b""));
+        assert!(!glob_match(b"?", b"ab"));
+        assert!(glob_match(b"fo?", b"foo"));
+        assert!(glob_match(b"f??", b"foo"));
+        assert!(!glob_match(b"f?", b"foo"));
+        assert!(glob_match(b"???", b"abc"));
     }
 
     #[test]
     fn test_glob_match_literal() {
         // Literal characters must match exactly
-        assert!(glob_match("exact", "exact"));
-        assert!(!glob_match("exact", "exactx"));
-        assert!(!glob_match("exactx", "exact"));
-        assert!(!glob_match("foo", "bar"));
+        assert!(glob_match(b"exact", b"exact"));
+        assert!(!glob_match(b"exact", b"exactx"));
+        assert!(!glob_match(b"exactx", b"exact"));
+        assert!(!glob_match(b"foo", b"bar"));
     }
 
     #[test]
     fn test_glob_match_combined() {
         // Combined patterns
-        assert!(glob_match("user:*:name", "user:123:name"));
-        assert!(glob_match("user:*:name", "user::name"));
-        assert!(!glob_match("user:*:name", "user:123:age"));
-        assert!(glob_match("key?_*", "key1_value"));
-        assert!(glob_match("key?_*", "key1_"));
-        assert!(!glob_match("key?_*", "key12_value"));
-        assert!(glob_match("*?*", "a"));
-        assert!(!glob_match("*?*", ""));
+        assert!(glob_match(b"user:*:name", b"user:123:name"));
+        assert!(glob_match(b"user:*:name", b"user::name"));
+        assert!(!glob_match(b"user:*:name", b"user:123:age"));
+        assert!(glob_match(b"key?_*", b"key1_value"));
+        assert!(glob_match(b"key?_*", b"key1_"));
+        assert!(!glob_match(b"key?_*", b"key12_value"));
+        assert!(glob_match(b"*?*", b"a"));
+        assert!(!glob_match(b"*?*", b""));
     }
 
     // EXPIRE tests
     #[tokio::test]
     async fn test_expire_existing_key() {
         let store = Store::new();
-        store.set("key".to_string(), b"value".to_vec()).await;
+        store.set("key".into(), Bytes::from_static(b"value")).await;
 
-        let result = store.expire("key", 10).await;
+        let result = store.expire(b"key", 10).await;
         assert_eq!(result, 1);
 
         // Key should still exist
-        assert_eq!(store.get("key").await, Some(b"value".to_vec()));
+        assert_eq!(store.get(b"key").await, Some(Bytes::from_static(b"value")));
     }
 
     #[tokio::test]
     async fn test_expire_nonexistent_key() {
         let store = Store::new();
-        let result = store.expire("nonexistent", 10).await;
+        let result = store.expire(b"nonexistent", 10).await;
         assert_eq!(result, 0);
     }
 
     #[tokio::test]
     async fn test_expire_negative_deletes_key() {
         let store = Store::new();
-        store.set("key".to_string(), b"value".to_vec()).await;
+        store.set("key".into(), Bytes::from_static(b"value")).await;
 
         // Negative seconds should delete the key
-        let result = store.expire("key", -1).await;
+        let result = store.expire(b"key", -1).await;
         assert_eq!(result, 1);
 
         // Key should be gone
-        assert_eq!(store.get("key").await, None);
+        assert_eq!(store.get(b"key").await, None);
     }
 
     #[tokio::test]
     async fn test_expire_zero_deletes_key() {
         let store = Store::new();
-        store.set("key".to_string(), b"value".to_vec()).await;
+        store.set("key".into(), Bytes::from_static(b"value")).await;
 
         // Zero seconds should delete the key
-        let result = store.expire("key", 0).await;
+        let result = store.expire(b"key", 0).await;
         assert_eq!(result, 1);
 
         // Key should be gone
-        assert_eq!(store.get("key").await, None);
+        assert_eq!(store.get(b"key").await, None);
     }
 
     #[tokio::test]
     async fn test_expire_causes_expiration() {
-        let store = Store::new();
-        store.set("key".to_string(), b"value".to_vec()).await;
-        store.expire("key", 1).await;
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::new().with_clock(clock.clone());
+        store.set("key".into(), Bytes::from_static(b"value")).await;
+        store.expire(b"key", 100).await;
 
-        // Should exist immediately
-        assert_eq!(store.get("key").await, Some(b"value".to_vec()));
+        // Should exist immediately, counting down with the clock
+        assert_eq!(store.get(b"key").await, Some(Bytes::from_static(b"value")));
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(store.ttl(b"key").await, 60);
 
-        // Wait for expiry
-        tokio::time::sleep(Duration::from_secs(2)).await;
+        clock.advance(Duration::from_secs(61));
 
         // Should be gone
-        assert_eq!(store.get("key").await, None);
+        assert_eq!(store.get(b"key").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_writes_wake_parked_clients() {
+        let store = Store::new();
+        let soon = || Some(tokio::time::Instant::now() + Duration::from_millis(20));
+        let mut parked = store.waiters().park(&[Bytes::from("k")]);
+
+        store.set("other".into(), "v".into()).await;
+        assert_eq!(parked.woken(soon()).await, None);
+        store.set("k".into(), "v".into()).await;
+        assert_eq!(parked.woken(soon()).await, Some(Bytes::from("k")));
+        // Failed writes and misses change nothing
+        store.incr(b"k").await.unwrap_err();
+        store.expire(b"missing", 10).await;
+        assert_eq!(parked.woken(soon()).await, None);
+        store.del(&[Bytes::from("k")]).await;
+        assert_eq!(parked.woken(soon()).await, Some(Bytes::from("k")));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let store = Store::new();
+        assert!(
+            store
+                .check_size(&[b'k'; 10_000], Some(&[0; 10_000_000]))
+                .is_ok()
+        );
+
+        store.set_size_limits(8, 16);
+        assert!(store.check_size(b"12345678", Some(&[0; 16])).is_ok());
+        assert!(store.check_size(b"12345678", None).is_ok());
+        assert_eq!(
+            store.check_size(b"123456789", None),
+            Err(CommandError::KeyTooLarge { len: 9, max: 8 })
+        );
+        assert_eq!(
+            store.check_size(b"key", Some(&[0; 17])),
+            Err(CommandError::ValueTooLarge { len: 17, max: 16 })
+        );
+
+        store.set_size_limits(0, 0);
+        assert!(store.check_size(b"123456789", Some(&[0; 17])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ttl_jitter_spreads_expirations() {
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::new().with_clock(clock.clone());
+        store.set_ttl_jitter(10);
+        for i in 0..200 {
+            store
+                .set_ex(format!("setex:{}", i).into(), "v".into(), 1000)
+                .await;
+            store.set(format!("expire:{}", i).into(), "v".into()).await;
+            store.expire(format!("expire:{}", i).as_bytes(), 1000).await;
+        }
+
+        let mut ttls = Vec::new();
+        for i in 0..200 {
+            ttls.push(store.ttl(format!("setex:{}", i).as_bytes()).await);
+            ttls.push(store.ttl(format!("expire:{}", i).as_bytes()).await);
+        }
+        // Within 10% either way, and spread over both sides
+        assert!(
+            ttls.iter().all(|ttl| (900..=1100).contains(ttl)),
+            "{:?}",
+            ttls
+        );
+        assert!(ttls.iter().any(|ttl| *ttl < 950) && ttls.iter().any(|ttl| *ttl >= 1050));
+
+        store.set_ttl_jitter(0);
+        store.set_ex("exact".into(), "v".into(), 1000).await;
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(store.ttl(b"exact").await, 999);
     }
 
     // TTL tests
     #[tokio::test]
     async fn test_ttl_with_expiration() {
         let store = Store::new();
-        store.set_ex("key".to_string(), b"value".to_vec(), 10).await;
+        store
+            .set_ex("key".into(), Bytes::from_static(b"value"), 10)
+            .await;
 
-        let ttl = store.ttl("key").await;
-        assert!(ttl >= 9 && ttl <= 10);
+        let ttl = store.ttl(b"key").await;
+        assert!((9..=10).contains(&ttl));
     }
 
     #[tokio::test]
     async fn test_ttl_no_expiration() {
         let store = Store::new();
-        store.set("key".to_string(), b"value".to_vec()).await;
+        store.set("key".into(), Bytes::from_static(b"value")).await;
 
-        let ttl = store.ttl("key").await;
+        let ttl = store.ttl(b"key").await;
         assert_eq!(ttl, -1);
     }
 
     #[tokio::test]
     async fn test_ttl_nonexistent_key() {
         let store = Store::new();
-        let ttl = store.ttl("nonexistent").await;
+        let ttl = store.ttl(b"nonexistent").await;
         assert_eq!(ttl, -2);
     }
 
@@ -656,29 +2874,31 @@ store
     #[tokio::test]
     async fn test_persist_removes_expiration() {
         let store = Store::new();
-        store.set_ex("key".to_string(), b"value".to_vec(), 10).await;
+        store
+            .set_ex("key".into(), Bytes::from_static(b"value"), 10)
+            .await;
 
-        let result = store.persist("key").await;
+        let result = store.persist(b"key").await;
         assert_eq!(result, 1);
 
         // TTL should now be -1 (no expiration)
-        let ttl = store.ttl("key").await;
+        let ttl = store.ttl(b"key").await;
         assert_eq!(ttl, -1);
     }
 
     #[tokio::test]
     async fn test_persist_key_without_expiration() {
         let store = Store::new();
-        store.set("key".to_string(), b"value".to_vec()).await;
+        store.set("key".into(), Bytes::from_static(b"value")).await;
 
-        let result = store.persist("key").await;
+        let result = store.persist(b"key").await;
         assert_eq!(result, 0); // No expiration to remove
     }
 
     #[tokio::test]
     async fn test_persist_nonexistent_key() {
         let store = Store::new();
-        let result = store.persist("nonexistent").await;
+        let result = store.persist(b"nonexistent").await;
         assert_eq!(result, 0);
     }
 
@@ -686,11 +2906,11 @@ store
     #[tokio::test]
     async fn test_keys_all() {
         let store = Store::new();
-        store.set("foo".to_string(), b"1".to_vec()).await;
-        store.set("bar".to_string(), b"2".to_vec()).await;
-        store.set("baz".to_string(), b"3".to_vec()).await;
+        store.set("foo".into(), Bytes::from_static(b"1")).await;
+        store.set("bar".into(), Bytes::from_static(b"2")).await;
+        store.set("baz".into(), Bytes::from_static(b"3")).await;
 
-        let mut keys = store.keys("*").await;
+        let mut keys = store.keys(b"*").await;
         keys.sort();
         assert_eq!(keys, vec!["bar", "baz", "foo"]);
     }
@@ -698,11 +2918,13 @@ store
     #[tokio::test]
     async fn test_keys_prefix_pattern() {
         let store = Store::new();
-        store.set("user:1".to_string(), b"a".to_vec()).await;
-        store.set("user:2".to_string(), b"b".to_vec()).await;
-        store.set("session:1".to_string(), b"c".to_vec()).await;
+        store.set("user:1".into(), Bytes::from_static(b"a")).await;
+        store.set("user:2".into(), Bytes::from_static(b"b")).await;
+        store
+            .set("session:1".into(), Bytes::from_static(b"c"))
+            .await;
 
-        let mut keys = store.keys("user:*").await;
+        let mut keys = store.keys(b"user:*").await;
         keys.sort();
         assert_eq!(keys, vec!["user:1", "user:2"]);
     }
@@ -710,27 +2932,749 @@ store
     #[tokio::test]
     async fn test_keys_single_char_wildcard() {
         let store = Store::new();
-        store.set("key1".to_string(), b"a".to_vec()).await;
-        store.set("key2".to_string(), b"b".to_vec()).await;
-        store.set("key10".to_string(), b"c".to_vec()).await;
+        store.set("key1".into(), Bytes::from_static(b"a")).await;
+        store.set("key2".into(), Bytes::from_static(b"b")).await;
+        store.set("key10".into(), Bytes::from_static(b"c")).await;
 
-        let mut keys = store.keys("key?").await;
+        let mut keys = store.keys(b"key?").await;
         keys.sort();
         assert_eq!(keys, vec!["key1", "key2"]);
     }
 
     #[tokio::test]
     async fn test_keys_excludes_expired() {
-        let store = Store::new();
-        store.set("good".to_string(), b"value".to_vec()).await;
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::new().with_clock(clock.clone());
+        store.set("good".into(), Bytes::from_static(b"value")).await;
         store
-            .set_ex("expired".to_string(), b"value".to_vec(), 1)
+            .set_ex("expired".into(), Bytes::from_static(b"value"), 1)
             .await;
 
-        // Wait for expiry
-        tokio::time::sleep(Duration::from_secs(2)).await;
+        clock.advance(Duration::from_secs(2));
 
-        let keys = store.keys("*").await;
+        let keys = store.keys(b"*").await;
         assert_eq!(keys, vec!["good"]);
     }
+
+    #[tokio::test]
+    async fn test_keys_spread_across_shards() {
+        let store = Store::with_shards(4);
+        for i in 0..100 {
+            store
+                .set(
+                    Bytes::from(format!("key:{}", i)),
+                    Bytes::from(i.to_string()),
+                )
+                .await;
+        }
+
+        let mut used_shards = 0;
+        for shard in sharded(&store).iter() {
+            if !shard.read().await.is_empty() {
+                used_shards += 1;
+            }
+        }
+        assert_eq!(used_shards, 4);
+
+        assert_eq!(store.keys(b"key:*").await.len(), 100);
+        assert_eq!(store.get(b"key:42").await, Some(Bytes::from_static(b"42")));
+        assert_eq!(
+            store
+                .del(&["key:1".into(), "key:2".into(), "missing".into()])
+                .await,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_single_shard_store() {
+        let store = Store::with_shards(0);
+        assert_eq!(sharded(&store).len(), 1);
+        store.set("a".into(), Bytes::from_static(b"1")).await;
+        assert_eq!(store.incr(b"a").await, Ok(2));
+    }
+
+    #[cfg(feature = "dashmap")]
+    #[tokio::test]
+    async fn test_dashmap_backend() {
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::with_backend(KeyspaceBackend::DashMap, 4).with_clock(clock.clone());
+        store.set("a".into(), Bytes::from_static(b"1")).await;
+        assert_eq!(store.incr(b"a").await, Ok(2));
+        assert!(!store.set_nx("a".into(), Bytes::from_static(b"x")).await);
+        assert_eq!(store.expire(b"a", 10).await, 1);
+        assert!((9..=10).contains(&store.ttl(b"a").await));
+        assert_eq!(store.persist(b"a").await, 1);
+        assert_eq!(store.ttl(b"a").await, -1);
+
+        store
+            .set_ex("gone".into(), Bytes::from_static(b"x"), 0)
+            .await;
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(store.keys(b"*").await, vec!["a"]);
+        assert_eq!(store.get(b"gone").await, None);
+        assert_eq!(store.del(&["a".into(), "gone".into()]).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_owned_backend() {
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::with_backend(KeyspaceBackend::Owned, 4).with_clock(clock.clone());
+        store.set("a".into(), Bytes::from_static(b"1")).await;
+        assert_eq!(store.incr(b"a").await, Ok(2));
+        assert!(!store.set_nx("a".into(), Bytes::from_static(b"x")).await);
+        assert_eq!(store.expire(b"a", 10).await, 1);
+        assert!((9..=10).contains(&store.ttl(b"a").await));
+        assert_eq!(store.persist(b"a").await, 1);
+        assert_eq!(store.ttl(b"a").await, -1);
+
+        // Multi-key commands scatter across owners and keep argument order
+        let pairs: Vec<(Bytes, Bytes)> = (0..20)
+            .map(|i| (format!("k{}", i).into(), format!("v{}", i).into()))
+            .collect();
+        store.mset(pairs.clone()).await;
+        let mut keys: Vec<Bytes> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        keys.push("missing".into());
+        let values = store.mget(&keys).await;
+        assert_eq!(values.len(), 21);
+        for (value, (_, expected)) in values.iter().zip(&pairs) {
+            assert_eq!(value.as_ref(), Some(expected));
+        }
+        assert_eq!(values[20], None);
+        assert_eq!(store.keys(b"k*").await.len(), 20);
+        assert_eq!(store.del(&keys).await, 20);
+
+        store
+            .set_ex("gone".into(), Bytes::from_static(b"x"), 0)
+            .await;
+        clock.advance(Duration::from_millis(1));
+        store.expire_random_keys().await;
+        assert_eq!(store.keys(b"*").await, vec!["a"]);
+        assert_eq!(store.get(b"gone").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_key_events_report_every_expiry_path() {
+        let backends = [
+            KeyspaceBackend::Sharded,
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap,
+            KeyspaceBackend::Owned,
+        ];
+        for backend in backends {
+            let clock = Arc::new(ManualClock::new());
+            let store = Store::with_backend(backend, 2).with_clock(clock.clone());
+            let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+            store.on_key_event(move |event| {
+                let sender = sender.clone();
+                async move { sender.send(event).unwrap() }
+            });
+            store.set("kept".into(), "v".into()).await;
+
+            // On access, by KEYS, which deletes every expired key it meets,
+            // then by the active expiration cycle
+            store.set_ex("read".into(), "v".into(), 1).await;
+            store.set_ex("listed".into(), "v".into(), 1).await;
+            clock.advance(Duration::from_secs(2));
+            assert_eq!(store.get(b"read").await, None);
+            store.keys(b"nothing*").await;
+            store.set_ex("sampled".into(), "v".into(), 1).await;
+            clock.advance(Duration::from_secs(2));
+            store.expire_random_keys().await;
+
+            let mut expired = Vec::new();
+            for _ in 0..3 {
+                let event = events.recv().await.unwrap();
+                assert_eq!(event.reason, KeyEventReason::Expired);
+                assert_eq!(event.value_type, "string");
+                expired.push(event.key);
+            }
+            assert_eq!(expired, ["read", "listed", "sampled"], "{:?}", backend);
+            assert_eq!(store.keyspace_stats().expired_keys, 3);
+            assert_eq!(store.dropped_key_events(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expiry_hooks_run_in_line() {
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::new().with_clock(clock.clone());
+        let hooked = Arc::new(Mutex::new(Vec::new()));
+        let guard = store.hook_expiry({
+            let hooked = hooked.clone();
+            move |key| hooked.lock().unwrap().push(key.clone())
+        });
+        store.set_ex("a".into(), "v".into(), 1).await;
+        store.set_ex("b".into(), "v".into(), 1).await;
+        clock.advance(Duration::from_secs(2));
+        // Heard of before the read that deleted it returns
+        assert_eq!(store.get(b"a").await, None);
+        assert_eq!(*hooked.lock().unwrap(), ["a"]);
+
+        drop(guard);
+        assert_eq!(store.get(b"b").await, None);
+        assert_eq!(*hooked.lock().unwrap(), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_streams_changes_under_a_prefix() {
+        let backends = [
+            KeyspaceBackend::Sharded,
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap,
+            KeyspaceBackend::Owned,
+        ];
+        for backend in backends {
+            let clock = Arc::new(ManualClock::new());
+            let store = Store::with_backend(backend, 2).with_clock(clock.clone());
+            let mut changes = store.subscribe("user:");
+
+            store.set("user:1".into(), "a".into()).await;
+            store.set("order:1".into(), "a".into()).await;
+            store.set_nx("user:1".into(), "b".into()).await;
+            store.incr(b"user:2").await.unwrap();
+            store
+                .mset(vec![
+                    ("user:3".into(), "c".into()),
+                    ("order:2".into(), "c".into()),
+                ])
+                .await;
+            store.del(&["user:1".into(), "user:9".into()]).await;
+            store.expire(b"user:2", 0).await;
+            store.persist(b"user:3").await;
+            store.set_ex("user:4".into(), "d".into(), 1).await;
+            clock.advance(Duration::from_secs(2));
+            assert_eq!(store.get(b"user:4").await, None);
+
+            let mut seen = Vec::new();
+            while let Some(change) = changes.try_next() {
+                seen.push((change.kind, change.key, change.value));
+            }
+            let set = |key: &str, value: &str| {
+                (
+                    ChangeKind::Set,
+                    Bytes::from(key.to_string()),
+                    Some(Bytes::from(value.to_string())),
+                )
+            };
+            let gone = |kind, key: &str| (kind, Bytes::from(key.to_string()), None);
+            assert_eq!(
+                seen,
+                [
+                    set("user:1", "a"),
+                    set("user:2", "1"),
+                    set("user:3", "c"),
+                    gone(ChangeKind::Del, "user:1"),
+                    gone(ChangeKind::Del, "user:2"),
+                    set("user:4", "d"),
+                    gone(ChangeKind::Expired, "user:4"),
+                ],
+                "{:?}",
+                backend
+            );
+            assert_eq!(changes.missed(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let backends = [
+            KeyspaceBackend::Sharded,
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap,
+            KeyspaceBackend::Owned,
+        ];
+        for backend in backends {
+            let clock = Arc::new(ManualClock::new());
+            let store = Store::with_backend(backend, 2).with_clock(clock.clone());
+            store.set("plain".into(), "value".into()).await;
+            store.incr(b"counter").await.unwrap();
+            store.set_ex("session".into(), "s".into(), 10).await;
+            store.set_ex("stale".into(), "s".into(), 1).await;
+            clock.advance(Duration::from_secs(2));
+
+            let mut snapshot = store.export().await;
+            snapshot.entries.sort_by(|a, b| a.key.cmp(&b.key));
+            let summary: Vec<_> = snapshot
+                .entries
+                .iter()
+                .map(|entry| (entry.key.clone(), entry.value.clone(), entry.ttl))
+                .collect();
+            let string = |value: &'static str| snapshot::Value::String(Bytes::from(value));
+            assert_eq!(
+                summary,
+                [
+                    ("counter".into(), string("1"), None),
+                    ("plain".into(), string("value"), None),
+                    ("session".into(), string("s"), Some(Duration::from_secs(8))),
+                ],
+                "{:?}",
+                backend
+            );
+
+            // Into another store, through the encoded form, on a later clock
+            let decoded = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+            let copy = Store::with_backend(backend, 3).with_clock(clock.clone());
+            copy.set("plain".into(), "old".into()).await;
+            copy.set("other".into(), "kept".into()).await;
+            clock.advance(Duration::from_secs(5));
+            copy.import(decoded).await;
+            assert_eq!(copy.get(b"plain").await, Some(Bytes::from("value")));
+            assert_eq!(copy.get(b"other").await, Some(Bytes::from("kept")));
+            assert_eq!(copy.incr(b"counter").await, Ok(2));
+            assert_eq!(copy.ttl(b"session").await, 8);
+            assert_eq!(copy.get(b"stale").await, None);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_multi_key_writes_are_seen_whole() {
+        // DashMap locks its shards one key at a time
+        for backend in [KeyspaceBackend::Sharded, KeyspaceBackend::Owned] {
+            let store = Store::with_backend(backend, 8);
+            let keys: Vec<Bytes> = (0..16).map(|i| format!("key:{}", i).into()).collect();
+            let writers: Vec<_> = (0..2)
+                .map(|writer| {
+                    let store = store.clone();
+                    let keys = keys.clone();
+                    tokio::spawn(async move {
+                        for round in 0..300 {
+                            let value = Bytes::from(format!("{}:{}", writer, round));
+                            let pairs = keys.iter().map(|key| (key.clone(), value.clone()));
+                            store.mset(pairs.collect()).await;
+                            if round % 3 == 0 {
+                                store.del(&keys).await;
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            for _ in 0..300 {
+                let values = store.mget(&keys).await;
+                assert!(
+                    values.iter().all(|value| *value == values[0]),
+                    "{:?}: {:?}",
+                    backend,
+                    values
+                );
+                tokio::task::yield_now().await;
+            }
+            for writer in writers {
+                writer.await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_mget_is_of_one_instant_while_counting() {
+        for backend in [KeyspaceBackend::Sharded, KeyspaceBackend::Owned] {
+            let store = Store::with_backend(backend, 8);
+            let keys: Vec<Bytes> = (0..256).map(|i| format!("key:{}", i).into()).collect();
+            let counting = spawn_counting(&store, &keys);
+
+            while !counting.is_finished() {
+                let counts: Vec<i64> = store
+                    .mget(&keys)
+                    .await
+                    .into_iter()
+                    .map(|value| {
+                        value.map_or(0, |data| {
+                            std::str::from_utf8(&data).unwrap().parse().unwrap()
+                        })
+                    })
+                    .collect();
+                assert_counted_in_turn(backend, &counts);
+            }
+            counting.await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_export_is_of_one_instant() {
+        // DashMap copies its shards one at a time
+        for backend in [KeyspaceBackend::Sharded, KeyspaceBackend::Owned] {
+            let store = Store::with_backend(backend, 8);
+            let keys: Vec<Bytes> = (0..16).map(|i| format!("key:{}", i).into()).collect();
+            let writer = {
+                let store = store.clone();
+                let keys = keys.clone();
+                tokio::spawn(async move {
+                    for round in 0..300 {
+                        let value = Bytes::from(round.to_string());
+                        let pairs = keys.iter().map(|key| (key.clone(), value.clone()));
+                        store.mset(pairs.collect()).await;
+                        if round % 3 == 0 {
+                            store.del(&keys).await;
+                        }
+                    }
+                })
+            };
+
+            for _ in 0..100 {
+                let snapshot = store.export().await;
+                assert!(
+                    snapshot.entries.is_empty() || snapshot.entries.len() == keys.len(),
+                    "{:?}: {} keys",
+                    backend,
+                    snapshot.entries.len()
+                );
+                assert!(
+                    snapshot
+                        .entries
+                        .iter()
+                        .all(|entry| entry.value == snapshot.entries[0].value),
+                    "{:?}: {:?}",
+                    backend,
+                    snapshot.entries
+                );
+                tokio::task::yield_now().await;
+            }
+            writer.await.unwrap();
+        }
+    }
+
+    /// Counters INCRed in turn, `key:0` first, as read at one instant: each
+    /// has been bumped as often as the next, or once more
+    fn assert_counted_in_turn(backend: KeyspaceBackend, counts: &[i64]) {
+        assert!(
+            counts.windows(2).all(|pair| pair[0] >= pair[1])
+                && counts[0] - counts[counts.len() - 1] <= 1,
+            "{:?}: {:?}",
+            backend,
+            counts
+        );
+    }
+
+    /// INCR each of `keys` in turn, round after round
+    fn spawn_counting(store: &Store, keys: &[Bytes]) -> tokio::task::JoinHandle<()> {
+        let store = store.clone();
+        let keys = keys.to_vec();
+        tokio::spawn(async move {
+            for _ in 0..200 {
+                for key in &keys {
+                    store.incr(key).await.unwrap();
+                }
+            }
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_export_is_of_one_instant_while_counting() {
+        // Counters bumped in place under the read lock mustn't move while
+        // the keyspace is copied
+        for backend in [KeyspaceBackend::Sharded, KeyspaceBackend::Owned] {
+            let store = Store::with_backend(backend, 8);
+            let keys: Vec<Bytes> = (0..256).map(|i| format!("key:{}", i).into()).collect();
+            let counting = spawn_counting(&store, &keys);
+
+            while !counting.is_finished() {
+                let snapshot = store.export().await;
+                let counted: HashMap<Bytes, i64> = snapshot
+                    .entries
+                    .into_iter()
+                    .map(|entry| match entry.value {
+                        snapshot::Value::String(data) => (
+                            entry.key,
+                            std::str::from_utf8(&data).unwrap().parse().unwrap(),
+                        ),
+                    })
+                    .collect();
+                let counts: Vec<i64> = keys
+                    .iter()
+                    .map(|key| counted.get(key).copied().unwrap_or(0))
+                    .collect();
+                assert_counted_in_turn(backend, &counts);
+            }
+            counting.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_go_ahead_while_exporting() {
+        let store = Store::with_backend(KeyspaceBackend::Sharded, 8);
+        let keys: Vec<Bytes> = (0..80_000).map(|i| format!("key:{}", i).into()).collect();
+        store
+            .mset(keys.iter().map(|key| (key.clone(), "v".into())).collect())
+            .await;
+        let Keyspace::Sharded { shards, hasher } = &store.keyspace else {
+            unreachable!()
+        };
+        let last = (0..)
+            .map(|i| Bytes::from(format!("new:{}", i)))
+            .find(|key| shard_index(shards, hasher, key) == shards.len() - 1)
+            .unwrap();
+
+        let exporting = tokio::spawn({
+            let store = store.clone();
+            async move { store.export().await }
+        });
+        // The export copies the first shard, then lets others in
+        tokio::task::yield_now().await;
+        assert!(
+            shards[shards.len() - 1]
+                .copy_pending
+                .load(Ordering::Acquire)
+        );
+
+        // A write copies only its own shard for the export, not every one
+        store.set(last.clone(), "v".into()).await;
+        assert!(!exporting.is_finished());
+        assert!(
+            !shards[shards.len() - 1]
+                .copy_pending
+                .load(Ordering::Acquire)
+        );
+
+        let snapshot = exporting.await.unwrap();
+        assert_eq!(snapshot.entries.len(), keys.len());
+        assert!(snapshot.entries.iter().all(|entry| entry.key != last));
+        assert_eq!(store.get(&last).await, Some(Bytes::from("v")));
+    }
+
+    #[tokio::test]
+    async fn test_compaction_after_mass_delete() {
+        let backends = [
+            KeyspaceBackend::Sharded,
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap,
+            KeyspaceBackend::Owned,
+        ];
+        for backend in backends {
+            let store = Store::with_backend(backend, 2);
+            let keys: Vec<Bytes> = (0..20_000).map(|i| format!("key:{}", i).into()).collect();
+            store
+                .mset(keys.iter().map(|key| (key.clone(), "v".into())).collect())
+                .await;
+            // Nothing to give back while the maps are full
+            assert_eq!(store.compact().await, 0, "{:?}", backend);
+
+            store.del(&keys[100..]).await;
+            let before = store.memory_stats().await;
+            assert!(before.fragmentation_ratio() > 10.0, "{:?}", backend);
+
+            assert!(store.compact().await > 0, "{:?}", backend);
+            let after = store.memory_stats().await;
+            assert_eq!(after.keys, 100);
+            assert!(after.capacity < before.capacity / 10, "{:?}", backend);
+            assert!(after.compactions > 0);
+            assert_eq!(store.get(b"key:42").await, Some(Bytes::from("v")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_visits_every_key_once() {
+        let backends = [
+            KeyspaceBackend::Sharded,
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap,
+            KeyspaceBackend::Owned,
+        ];
+        for backend in backends {
+            let store = Store::with_backend(backend, 4);
+            let keys: Vec<Bytes> = (0..500).map(|i| format!("key:{}", i).into()).collect();
+            store
+                .mset(keys.iter().map(|key| (key.clone(), "v".into())).collect())
+                .await;
+
+            let mut seen = Vec::new();
+            let mut cursor = 0;
+            let mut pages = 0;
+            loop {
+                let (next, page) = store.scan(cursor, 30, None).await;
+                assert!(page.len() <= 31, "{:?}", backend);
+                // Partition, then position within it, only ever move on
+                assert!(next == 0 || next > cursor, "{:?}", backend);
+                seen.extend(page);
+                pages += 1;
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+            seen.sort();
+            let mut expected = keys.clone();
+            expected.sort();
+            assert_eq!(seen, expected, "{:?}", backend);
+            assert!(pages >= 500 / 30, "{:?}", backend);
+
+            // A page that ends a partition goes on into the next
+            if backend == KeyspaceBackend::Sharded {
+                let (next, page) = store.scan(0, 200, None).await;
+                assert_eq!(page.len(), 200);
+                assert!(next >> 62 > 0, "{:x}", next);
+                let (next, page) = store.scan(3 << 62, 1000, None).await;
+                assert_eq!(next, 0);
+                assert!(!page.is_empty() && page.len() < 500);
+            }
+
+            // MATCH filters the page, it doesn't widen it
+            let (_, page) = store.scan(0, 1000, Some(b"key:1?")).await;
+            assert_eq!(page.len(), 10, "{:?}", backend);
+            assert_eq!(store.dbsize().await, 500);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strlen_and_memory_usage() {
+        let store = Store::new();
+        store.set(Bytes::from("short"), Bytes::from("abc")).await;
+        store
+            .set(Bytes::from("long"), Bytes::from(vec![b'x'; 1000]))
+            .await;
+        assert_eq!(store.strlen(b"short").await, 3);
+        assert_eq!(store.strlen(b"long").await, 1000);
+        assert_eq!(store.strlen(b"missing").await, 0);
+
+        let short = store.memory_usage(b"short").await.unwrap();
+        let long = store.memory_usage(b"long").await.unwrap();
+        assert!(long > short + 900, "{} vs {}", long, short);
+        assert_eq!(store.memory_usage(b"missing").await, None);
+    }
+
+    /// Contended INCR throughput of each backend; run with
+    /// `cargo test --release --features dashmap bench_contended_incr -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_contended_incr() {
+        const TASKS: usize = 64;
+        const OPS_PER_TASK: usize = 20_000;
+        const HOT_KEYS: usize = 8;
+
+        let backends = [
+            KeyspaceBackend::Sharded,
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap,
+            KeyspaceBackend::Owned,
+        ];
+        for backend in backends {
+            let store = Store::with_backend(backend, DEFAULT_SHARDS);
+            let start = Instant::now();
+            let tasks: Vec<_> = (0..TASKS)
+                .map(|task| {
+                    let store = store.clone();
+                    tokio::spawn(async move {
+                        for i in 0..OPS_PER_TASK {
+                            let key = format!("counter:{}", (task + i) % HOT_KEYS);
+                            store.incr(key.as_bytes()).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            let elapsed = start.elapsed();
+
+            let mut total = 0;
+            for key in 0..HOT_KEYS {
+                let value = store.get(format!("counter:{}", key).as_bytes()).await;
+                total += std::str::from_utf8(&value.unwrap())
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+            }
+            assert_eq!(total, TASKS * OPS_PER_TASK);
+            println!(
+                "{:?}: {:.0} INCR/s ({} tasks, {} hot keys)",
+                backend,
+                total as f64 / elapsed.as_secs_f64(),
+                TASKS,
+                HOT_KEYS
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_stats() {
+        let backends = [
+            KeyspaceBackend::Sharded,
+            #[cfg(feature = "dashmap")]
+            KeyspaceBackend::DashMap,
+            KeyspaceBackend::Owned,
+        ];
+        for backend in backends {
+            let store = Store::with_backend(backend, 2);
+            store.set(Bytes::from("live"), Bytes::from("v")).await;
+            for key in ["lazy", "swept", "listed"] {
+                let stored = StoredValue {
+                    expires_at: Some(Instant::now()),
+                    ..StoredValue::new(Bytes::from("v"))
+                };
+                store.keyspace.insert(Bytes::from(key), stored).await;
+            }
+
+            assert!(store.get(b"live").await.is_some());
+            assert!(store.get(b"missing").await.is_none());
+            // Reading an expired key is a miss that deletes it
+            assert!(store.get(b"lazy").await.is_none());
+            store
+                .mget(&[Bytes::from("live"), Bytes::from("lazy")])
+                .await;
+            // Writes and introspection don't count as hits or misses
+            store.incr(b"counter").await.unwrap();
+            store.object_info(b"live").await;
+
+            let stats = store.keyspace_stats();
+            assert_eq!((stats.hits, stats.misses), (2, 3), "{:?}", backend);
+            assert_eq!(stats.expired_keys, 1, "{:?}", backend);
+
+            // KEYS and the active cycle delete the rest
+            store.keys(b"l*").await;
+            store.expire_random_keys().await;
+            assert_eq!(store.keyspace_stats().expired_keys, 3, "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_stats() {
+        let store = Store::with_shards(2);
+        store.set(Bytes::from("a"), Bytes::from("1")).await;
+        store.get(b"a").await;
+        let stats = store.lock_stats().unwrap();
+        assert_eq!(stats.len(), 2);
+        let total = stats
+            .iter()
+            .fold(LockStats::default(), |sum, shard| sum.add(shard));
+        assert_eq!(total.acquisitions, 2);
+        assert_eq!(total.contended, 0);
+
+        // A writer waits while a reader holds the shard
+        let shard = &sharded(&store)[0];
+        let read_guard = shard.read().await;
+        let writer = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let shard = &sharded(&store)[0];
+                drop(shard.write().await);
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(read_guard);
+        writer.await.unwrap();
+
+        let stats = store.lock_stats().unwrap()[0];
+        assert_eq!(stats.contended, 1);
+        assert!(stats.wait >= Duration::from_millis(10), "{:?}", stats.wait);
+        assert!(stats.contended_percent() > 0.0);
+        assert!(
+            Store::with_backend(KeyspaceBackend::Owned, 2)
+                .lock_stats()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_active_expire_toggle() {
+        let store = Store::new();
+        assert!(store.active_expire_enabled());
+
+        store.set_active_expire(false);
+        assert!(!store.active_expire_enabled());
+
+        // Clones share the flag with the background task
+        let clone = store.clone();
+        clone.set_active_expire(true);
+        assert!(store.active_expire_enabled());
+    }
 }
@@ -0,0 +1,121 @@
+//! Readiness notification for systemd services with `Type=notify`. Like
+//! sd_notify(3), state changes are sent as a datagram to the socket named by
+//! `NOTIFY_SOCKET`, so systemd only routes traffic to a server that is
+//! listening and stops waiting on one that is shutting down.
+
+use crate::log::warning;
+use std::ffi::OsStr;
+
+/// Sent once the listeners are bound and the server accepts clients
+pub const READY: &str = "READY=1\nSTATUS=Ready to accept connections";
+/// Sent when a graceful shutdown begins
+pub const STOPPING: &str = "STOPPING=1\nSTATUS=Shutting down";
+
+/// Whether to tell a supervisor about state changes
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Supervised {
+    /// Never notify
+    No,
+    /// Notify systemd, warning when `NOTIFY_SOCKET` is missing
+    Systemd,
+    /// Notify systemd when started by it, i.e. `NOTIFY_SOCKET` is set
+    #[default]
+    Auto,
+}
+
+/// Send `state` to systemd if `supervised` asks for it. Failures are only
+/// logged: a server that can't notify still serves.
+pub fn notify(supervised: Supervised, state: &str) {
+    if supervised == Supervised::No {
+        return;
+    }
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => {
+            if let Err(e) = send(&socket, state) {
+                warning!("Can't notify systemd at {}: {}", socket.display(), e);
+            }
+        }
+        None if supervised == Supervised::Systemd => {
+            warning!("supervised systemd is set, but NOTIFY_SOCKET is not");
+        }
+        None => {}
+    }
+}
+
+/// Send one notification datagram to `socket`, a path or, prefixed with
+/// `@`, an abstract socket name
+#[cfg(unix)]
+fn send(socket: &OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            let addr = SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "abstract sockets need Linux",
+            ));
+        }
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "systemd notification needs Unix",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    fn received(socket: &UnixDatagram) -> String {
+        let mut buffer = [0; 256];
+        let len = socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..len]).into_owned()
+    }
+
+    #[test]
+    fn notifies_a_socket() {
+        let path = std::env::temp_dir().join(format!("rudis-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), READY).unwrap();
+        send(path.as_os_str(), STOPPING).unwrap();
+        assert_eq!(received(&socket), READY);
+        assert_eq!(received(&socket), STOPPING);
+        std::fs::remove_file(&path).unwrap();
+
+        // Nobody listening is an error for the caller to log
+        assert!(send(path.as_os_str(), READY).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifies_an_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let name = format!("rudis-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        send(OsStr::new(&format!("@{}", name)), READY).unwrap();
+        assert_eq!(received(&socket), READY);
+    }
+}
@@ -0,0 +1,460 @@
+//! Key namespaces for several teams sharing one server. Each `tenant`
+//! directive defines a user that AUTH binds a connection to; its commands
+//! may then only touch keys under the tenant's prefix, and are held to the
+//! tenant's quotas on keys, memory and commands per second.
+//!
+//! Key and memory usage is measured by a periodic pass over the keyspace,
+//! so those quotas are soft: a tenant can overshoot them by what it writes
+//! between two measurements.
+//!
+//! A tenant's password may be given as `#` and the hex SHA-256 of it, as
+//! the ACL file keeps them (see `acl`), rather than in the clear.
+
+use crate::command::{Command, CommandFlags, lookup_command};
+use crate::error::CommandError;
+use crate::ratelimit::{Decision, RateLimitMode, RateLimiter};
+use crate::resp::RespValue;
+use crate::store::Store;
+use anyhow::Result;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// How often each tenant's keys and memory are measured
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A `tenant` directive: name, password, key prefix and optional quotas
+#[derive(Debug, Clone, PartialEq)]
+pub struct TenantSpec {
+    pub name: String,
+    pub password: String,
+    pub prefix: String,
+    pub max_keys: Option<u64>,
+    pub max_memory: Option<u64>,
+    pub max_ops: Option<u32>,
+}
+
+impl TenantSpec {
+    /// The password hashed, as the ACL file keeps it; one given hashed
+    /// already is kept as it is
+    pub fn hashed_password(&self) -> String {
+        match is_hashed(&self.password) {
+            true => self.password.to_lowercase(),
+            false => hash_password(&self.password),
+        }
+    }
+
+    fn password_matches(&self, password: &str) -> bool {
+        match is_hashed(&self.password) {
+            true => hash_password(password).eq_ignore_ascii_case(&self.password),
+            false => self.password == password,
+        }
+    }
+}
+
+/// `#` and the hex SHA-256 of `password`
+pub fn hash_password(password: &str) -> String {
+    format!("#{:x}", Sha256::digest(password))
+}
+
+fn is_hashed(password: &str) -> bool {
+    password
+        .strip_prefix('#')
+        .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// What a tenant holds and has done, for INFO tenants
+#[derive(Debug, Default)]
+pub struct TenantUsage {
+    /// Keys under the prefix at the last measurement
+    keys: AtomicU64,
+    /// Bytes those keys took up, as MEMORY USAGE counts them
+    bytes: AtomicU64,
+    /// Commands run
+    commands: AtomicU64,
+    /// Commands refused for a key outside the prefix or an exceeded quota
+    rejected: AtomicU64,
+}
+
+/// Every tenant's usage by name, for INFO tenants
+#[derive(Debug, Default)]
+pub struct TenantStats {
+    tenants: Mutex<BTreeMap<String, Arc<TenantUsage>>>,
+}
+
+impl TenantStats {
+    /// The usage counters of tenant `name`, created on first use
+    pub fn register(&self, name: &str) -> Arc<TenantUsage> {
+        self.tenants
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// `tenant_<name>:keys=..,memory=..,commands=..,rejected=..` per tenant
+    pub fn info(&self) -> String {
+        self.tenants
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, usage)| {
+                format!(
+                    "tenant_{}:keys={},memory={},commands={},rejected={}\r\n",
+                    name,
+                    usage.keys.load(Ordering::Relaxed),
+                    usage.bytes.load(Ordering::Relaxed),
+                    usage.commands.load(Ordering::Relaxed),
+                    usage.rejected.load(Ordering::Relaxed)
+                )
+            })
+            .collect()
+    }
+}
+
+/// A tenant as the server enforces it
+#[derive(Debug)]
+pub struct Tenant {
+    spec: TenantSpec,
+    prefix: Bytes,
+    usage: Arc<TenantUsage>,
+    limiter: Option<RateLimiter<()>>,
+}
+
+impl Tenant {
+    fn new(spec: TenantSpec, usage: Arc<TenantUsage>) -> Self {
+        Self {
+            prefix: Bytes::from(spec.prefix.clone()),
+            limiter: spec
+                .max_ops
+                .map(|ops| RateLimiter::new(ops, ops, RateLimitMode::Reject)),
+            spec,
+            usage,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    /// Check a command against the tenant's namespace and quotas, counting
+    /// it as run or rejected
+    pub fn admit(&self, cmd: &Command) -> Result<()> {
+        let admitted = self.check(cmd);
+        let counter = match admitted {
+            Ok(()) => &self.usage.commands,
+            Err(_) => &self.usage.rejected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        admitted
+    }
+
+    fn check(&self, cmd: &Command) -> Result<()> {
+        let flags = lookup_command(cmd.name()).map_or(CommandFlags::NONE, |spec| spec.flags);
+        if flags.contains(CommandFlags::ADMIN) {
+            return Err(CommandError::NoPermCommand {
+                user: self.spec.name.clone(),
+                command: cmd.name(),
+            }
+            .into());
+        }
+        if let Some(limiter) = &self.limiter
+            && limiter.check(()) != Decision::Allow
+        {
+            return Err(CommandError::Other(format!(
+                "tenant '{}' is over its max-ops limit",
+                self.spec.name
+            ))
+            .into());
+        }
+        if !cmd.keys().iter().all(|key| key.starts_with(&self.prefix)) {
+            return Err(CommandError::NoPermKey.into());
+        }
+        if flags.contains(CommandFlags::DENYOOM) {
+            let over = |limit: Option<u64>, usage: &AtomicU64| {
+                limit.is_some_and(|limit| usage.load(Ordering::Relaxed) >= limit)
+            };
+            let quota = if over(self.spec.max_keys, &self.usage.keys) {
+                "max-keys"
+            } else if over(self.spec.max_memory, &self.usage.bytes) {
+                "max-memory"
+            } else {
+                return Ok(());
+            };
+            return Err(CommandError::Oom {
+                tenant: self.spec.name.clone(),
+                quota,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Run a command on the tenant's behalf: keyspace-wide commands only
+    /// see keys under the prefix
+    pub async fn execute(&self, cmd: &Command, store: &Store) -> RespValue {
+        match cmd {
+            Command::Keys(_) => self.confine(cmd.execute(store).await),
+            Command::Scan(_) => match cmd.execute(store).await {
+                RespValue::Array(Some(mut reply)) if reply.len() == 2 => {
+                    let page = reply.pop().unwrap();
+                    reply.push(self.confine(page));
+                    RespValue::Array(Some(reply))
+                }
+                reply => reply,
+            },
+            Command::DbSize => {
+                let usage = store.prefix_usage(std::slice::from_ref(&self.prefix)).await;
+                RespValue::Integer(usage[0].keys as i64)
+            }
+            _ => cmd.execute(store).await,
+        }
+    }
+
+    /// Drop keys outside the prefix from an array of keys
+    fn confine(&self, keys: RespValue) -> RespValue {
+        match keys {
+            RespValue::Array(Some(keys)) => RespValue::Array(Some(
+                keys.into_iter()
+                    .filter(|key| {
+                        matches!(key, RespValue::BulkString(Some(key)) if key.starts_with(&self.prefix))
+                    })
+                    .collect(),
+            )),
+            reply => reply,
+        }
+    }
+}
+
+/// Every configured tenant; ACL LOAD swaps them for those in the ACL file
+#[derive(Debug, Default)]
+pub struct Tenants {
+    tenants: RwLock<Vec<Arc<Tenant>>>,
+    /// Usage of every tenant there has been, for INFO tenants
+    stats: TenantStats,
+}
+
+impl Tenants {
+    /// The tenants in `specs`
+    pub fn new(specs: &[TenantSpec]) -> Self {
+        let tenants = Self::default();
+        tenants.replace(specs);
+        tenants
+    }
+
+    /// Swap every tenant for those in `specs`. Clients logged in stay
+    /// logged in as the tenant they were until they AUTH again.
+    pub fn replace(&self, specs: &[TenantSpec]) {
+        *self.tenants.write().unwrap() = specs
+            .iter()
+            .map(|spec| Arc::new(Tenant::new(spec.clone(), self.stats.register(&spec.name))))
+            .collect();
+    }
+
+    /// Keys, memory and commands of each tenant
+    pub fn stats(&self) -> &TenantStats {
+        &self.stats
+    }
+
+    /// The tenants' definitions, as ACL SAVE writes them
+    pub fn specs(&self) -> Vec<TenantSpec> {
+        let tenants = self.tenants.read().unwrap();
+        tenants.iter().map(|tenant| tenant.spec.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.read().unwrap().is_empty()
+    }
+
+    /// The tenant AUTH logs in as; a password alone logs in as `default`,
+    /// like Redis
+    pub fn authenticate(&self, username: Option<&str>, password: &str) -> Result<Arc<Tenant>> {
+        let username = username.unwrap_or("default");
+        self.tenants
+            .read()
+            .unwrap()
+            .iter()
+            .find(|tenant| tenant.spec.name == username && tenant.spec.password_matches(password))
+            .cloned()
+            .ok_or_else(|| CommandError::WrongPass.into())
+    }
+
+    /// Measure every tenant's keys and memory now
+    pub async fn measure(&self, store: &Store) {
+        let tenants = self.tenants.read().unwrap().clone();
+        let prefixes: Vec<Bytes> = tenants.iter().map(|tenant| tenant.prefix.clone()).collect();
+        let usage = store.prefix_usage(&prefixes).await;
+        for (tenant, usage) in tenants.iter().zip(usage) {
+            tenant.usage.keys.store(usage.keys, Ordering::Relaxed);
+            tenant.usage.bytes.store(usage.bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Start the background task measuring tenants' usage, which skips its
+    /// passes while there are no tenants
+    pub fn start_measurement(tenants: Arc<Tenants>, store: Store) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MEASURE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !tenants.is_empty() {
+                    tenants.measure(&store).await;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str, prefix: &str) -> TenantSpec {
+        TenantSpec {
+            name: name.to_string(),
+            password: format!("{}-secret", name),
+            prefix: prefix.to_string(),
+            max_keys: None,
+            max_memory: None,
+            max_ops: None,
+        }
+    }
+
+    fn bulk(value: &str) -> RespValue {
+        RespValue::BulkString(Some(Bytes::from(value.to_string())))
+    }
+
+    #[test]
+    fn authenticates_by_name_and_password() {
+        let tenants = Tenants::new(&[spec("default", "d:"), spec("team", "t:")]);
+
+        assert_eq!(
+            tenants
+                .authenticate(Some("team"), "team-secret")
+                .unwrap()
+                .name(),
+            "team"
+        );
+        assert_eq!(
+            tenants.authenticate(None, "default-secret").unwrap().name(),
+            "default"
+        );
+        assert!(
+            tenants
+                .authenticate(Some("team"), "default-secret")
+                .is_err()
+        );
+        assert!(tenants.authenticate(Some("nobody"), "team-secret").is_err());
+    }
+
+    #[test]
+    fn hashed_passwords_authenticate() {
+        let mut hashed = spec("team", "t:");
+        // Hex digits of either case
+        hashed.password = hash_password("team-secret").to_uppercase();
+        assert_eq!(hashed.hashed_password(), hash_password("team-secret"));
+        assert_eq!(
+            hash_password("foo"),
+            "#2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        );
+        let tenants = Tenants::new(&[hashed]);
+        assert!(tenants.authenticate(Some("team"), "team-secret").is_ok());
+        assert!(tenants.authenticate(Some("team"), "other").is_err());
+        // A hash is no password itself
+        let hash = hash_password("team-secret");
+        assert!(tenants.authenticate(Some("team"), &hash).is_err());
+
+        tenants.replace(&[spec("other", "o:")]);
+        assert!(tenants.authenticate(Some("team"), "team-secret").is_err());
+        assert_eq!(tenants.specs(), [spec("other", "o:")]);
+    }
+
+    #[test]
+    fn confines_keys_to_the_prefix() {
+        let stats = TenantStats::default();
+        let tenant = Tenant::new(spec("team", "t:"), stats.register("team"));
+
+        assert!(tenant.admit(&Command::Get(Bytes::from("t:a"))).is_ok());
+        let outside = Command::MGet(vec![Bytes::from("t:a"), Bytes::from("u:a")]);
+        assert!(
+            tenant
+                .admit(&outside)
+                .unwrap_err()
+                .to_string()
+                .starts_with("NOPERM")
+        );
+        assert!(tenant.admit(&Command::DbSize).is_ok());
+        let admin = Command::ConfigGet(vec!["*".to_string()]);
+        assert!(
+            tenant
+                .admit(&admin)
+                .unwrap_err()
+                .to_string()
+                .starts_with("NOPERM")
+        );
+
+        let keys = RespValue::Array(Some(vec![bulk("t:a"), bulk("u:a"), bulk("t:b")]));
+        assert_eq!(
+            tenant.confine(keys),
+            RespValue::Array(Some(vec![bulk("t:a"), bulk("t:b")]))
+        );
+        assert_eq!(
+            stats.info(),
+            "tenant_team:keys=0,memory=0,commands=2,rejected=2\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn enforces_quotas_on_measured_usage() {
+        let store = Store::new();
+        let mut limited = spec("team", "t:");
+        limited.max_keys = Some(2);
+        let tenants = Tenants::new(&[limited, spec("other", "o:")]);
+        let tenant = tenants.authenticate(Some("team"), "team-secret").unwrap();
+
+        store.set(Bytes::from("t:a"), Bytes::from("1")).await;
+        store.set(Bytes::from("t:b"), Bytes::from("2")).await;
+        store.set(Bytes::from("o:a"), Bytes::from("3")).await;
+        let set = Command::Set(Bytes::from("t:c"), Bytes::from("3"));
+        // Usage is only known once measured
+        assert!(tenant.admit(&set).is_ok());
+
+        tenants.measure(&store).await;
+        let refused = tenant.admit(&set).unwrap_err().to_string();
+        assert!(refused.starts_with("OOM") && refused.contains("max-keys"));
+        // Deleting still works, to get back under the quota
+        assert!(
+            tenant
+                .admit(&Command::Del(vec![Bytes::from("t:a")]))
+                .is_ok()
+        );
+        assert_eq!(
+            tenant.execute(&Command::DbSize, &store).await,
+            RespValue::Integer(2)
+        );
+        assert!(tenants.stats().info().contains("tenant_other:keys=1,"));
+    }
+
+    #[test]
+    fn limits_commands_per_second() {
+        let stats = TenantStats::default();
+        let mut limited = spec("team", "t:");
+        limited.max_ops = Some(2);
+        let tenant = Tenant::new(limited, stats.register("team"));
+
+        let ping = Command::Ping(None);
+        assert!(tenant.admit(&ping).is_ok());
+        assert!(tenant.admit(&ping).is_ok());
+        assert!(
+            tenant
+                .admit(&ping)
+                .unwrap_err()
+                .to_string()
+                .contains("max-ops")
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! TLS for the links between active-active peers, set up apart from the
+//! client listener, which stays plain TCP. With `crdt-tls`, this node
+//! connects to its `crdt-peers` over TLS; with `crdt-tls-port`, it takes
+//! their links over TLS on a port of its own, which answers `CRDT MERGE`
+//! and nothing else. Both ways are verified: each side presents the
+//! certificate in `crdt-tls-cert-file` and checks the other's against the
+//! CAs in `crdt-tls-ca-cert-file`, so only nodes holding a certificate from
+//! those CAs can replicate in. A peer's certificate must also name the host
+//! it is listed under in `crdt-peers`.
+
+use crate::config::Config;
+use crate::log::{notice, warning};
+use crate::resp::RespValue;
+use crate::server::Context;
+use anyhow::{Result, anyhow};
+use bytes::BytesMut;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector, client};
+
+/// Longest a peer may take over its TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// This node's certificate and the CAs it trusts, ready to connect to and
+/// accept peers with
+#[derive(Clone)]
+pub struct PeerTls {
+    connector: TlsConnector,
+    acceptor: TlsAcceptor,
+}
+
+impl std::fmt::Debug for PeerTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PeerTls")
+    }
+}
+
+impl PeerTls {
+    /// Read the certificate, key and CAs the `crdt-tls-*-file` directives
+    /// name
+    pub fn load(config: &Config) -> Result<Self> {
+        let file = |path: &Option<PathBuf>, directive: &str| {
+            path.clone()
+                .ok_or_else(|| anyhow!("TLS between peers needs {}", directive))
+        };
+        let chain = read_certs(&file(&config.crdt_tls_cert_file, "crdt-tls-cert-file")?)?;
+        let key = read_key(&file(&config.crdt_tls_key_file, "crdt-tls-key-file")?)?;
+        let ca_file = file(&config.crdt_tls_ca_cert_file, "crdt-tls-ca-cert-file")?;
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&ca_file)? {
+            roots
+                .add(cert)
+                .map_err(|e| anyhow!("Bad CA certificate in '{}': {}", ca_file.display(), e))?;
+        }
+        let roots = Arc::new(roots);
+
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots.clone())
+            .with_client_auth_cert(chain.clone(), key.clone_key())
+            .map_err(|e| anyhow!("Bad crdt-tls-key-file: {}", e))?;
+        let verifier = WebPkiClientVerifier::builder(roots)
+            .build()
+            .map_err(|e| anyhow!("Bad crdt-tls-ca-cert-file: {}", e))?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain, key)
+            .map_err(|e| anyhow!("Bad crdt-tls-key-file: {}", e))?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(client)),
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+        })
+    }
+
+    /// Open a TLS connection to the peer at `addr`, `host:port`, checking
+    /// its certificate names `host`
+    pub(crate) async fn connect(&self, addr: &str) -> Result<client::TlsStream<TcpStream>> {
+        let host = addr
+            .rsplit_once(':')
+            .map_or(addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|_| anyhow!("'{}' is not a host name TLS can verify", host))?;
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(self.connector.connect(name, stream).await?)
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in '{}'", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?
+        .ok_or_else(|| anyhow!("No private key in '{}'", path.display()))
+}
+
+/// Start taking peers' links on `crdt-tls-port`, if it is set. Fails if
+/// either `crdt-tls` or `crdt-tls-port` is set and the TLS files don't load.
+pub async fn spawn(context: &Context) -> Result<Option<JoinHandle<()>>> {
+    let config = &context.config;
+    if !config.crdt_tls && config.crdt_tls_port == 0 {
+        return Ok(None);
+    }
+    let tls = PeerTls::load(config)?;
+    let Some(addr) = config.crdt_tls_addr() else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|e| anyhow!("Can't bind peer listener on {}: {}", addr, e))?;
+    notice!("Taking crdt-peers over TLS on {}", listener.local_addr()?);
+
+    let context = context.clone();
+    Ok(Some(tokio::spawn(async move {
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warning!("Can't accept a peer: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = tls.acceptor.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_peer(acceptor, socket, peer, &context).await {
+                    warning!("Peer {} disconnected: {}", peer, e);
+                }
+            });
+        }
+    })))
+}
+
+/// Verify a peer, then merge the changes it sends until it hangs up
+async fn serve_peer(
+    acceptor: TlsAcceptor,
+    socket: TcpStream,
+    peer: SocketAddr,
+    context: &Context,
+) -> Result<()> {
+    socket.set_nodelay(true)?;
+    let mut stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+        .await
+        .map_err(|_| anyhow!("TLS handshake timed out"))?
+        .map_err(|e| anyhow!("TLS handshake failed: {}", e))?;
+    notice!("Peer {} connected over TLS", peer);
+    let mut input = BytesMut::with_capacity(4096);
+    let mut output = BytesMut::new();
+    loop {
+        while let Some((request, _)) =
+            RespValue::parse_with_limits(&mut input, &context.config.proto_limits)?
+        {
+            context
+                .peer_request(request)
+                .await
+                .serialize_into(&mut output);
+        }
+        if !output.is_empty() {
+            stream.write_all(&output).await?;
+            output.clear();
+        }
+        if stream.read_buf(&mut input).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::server::Server;
+    use bytes::Bytes;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    /// A CA and a certificate it issued for localhost, written to PEM files
+    /// in a new directory under `name`, set in a config as this node's
+    pub(crate) fn tls_config(name: &str) -> Config {
+        let dir = std::env::temp_dir().join(format!("rudis-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let node_key = KeyPair::generate().unwrap();
+        let node = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&node_key, &ca, &ca_key)
+            .unwrap();
+        let write = |file: &str, pem: String| {
+            let path = dir.join(file);
+            std::fs::write(&path, pem).unwrap();
+            Some(path)
+        };
+        Config {
+            crdt_tls_cert_file: write("node.crt", node.pem()),
+            crdt_tls_key_file: write("node.key", node_key.serialize_pem()),
+            crdt_tls_ca_cert_file: write("ca.crt", ca.pem()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn loads_certificates() {
+        let config = tls_config("load");
+        assert!(PeerTls::load(&config).is_ok());
+        let missing = Config {
+            crdt_tls_ca_cert_file: None,
+            ..config.clone()
+        };
+        let error = PeerTls::load(&missing).unwrap_err().to_string();
+        assert!(error.contains("crdt-tls-ca-cert-file"), "{}", error);
+        // A key file holding a certificate instead
+        let swapped = Config {
+            crdt_tls_key_file: config.crdt_tls_cert_file.clone(),
+            ..config.clone()
+        };
+        assert!(PeerTls::load(&swapped).is_err());
+    }
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn peers_replicate_over_verified_tls() {
+        let config = tls_config("peers");
+        let tls_ports = [free_port(), free_port()];
+        let mut servers = Vec::new();
+        for node in 0..2 {
+            let mut config = config.clone();
+            config.crdt_tls = true;
+            config.crdt_tls_port = tls_ports[node];
+            // Listed by the name on the certificate
+            config.crdt_peers = vec![format!("localhost:{}", tls_ports[1 - node])];
+            config.crdt_node_id = node as u64 + 1;
+            let server = Server::builder()
+                .port(0)
+                .config(move |c| *c = Config { port: 0, ..config })
+                .bind()
+                .await
+                .unwrap();
+            servers.push((server.local_addr().unwrap(), server.store().clone()));
+            tokio::spawn(async move { server.run().await });
+        }
+
+        let mut a = Client::connect(servers[0].0).await.unwrap();
+        a.set("greeting", "hello").await.unwrap();
+        let b = &servers[1].1;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while b.get(b"greeting").await != Some(Bytes::from("hello")) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the write never reached the other peer");
+
+        // The peer port takes merges only, and only from certified peers
+        let peer = format!("localhost:{}", tls_ports[0]);
+        let tls = PeerTls::load(&config).unwrap();
+        let mut trusted = Client::connect_tls(&peer, &tls).await.unwrap();
+        let reply = trusted.command(&["GET", "greeting"]).await.unwrap();
+        assert!(matches!(reply, RespValue::Error(e) if e.contains("only CRDT MERGE")));
+        let rogue = PeerTls::load(&tls_config("rogue")).unwrap();
+        let refused = async {
+            let mut client = Client::connect_tls(&peer, &rogue).await?;
+            client.command(&["CRDT", "MERGE"]).await
+        };
+        assert!(refused.await.is_err());
+        // Nor does this node trust a peer whose certificate names another host
+        let misnamed = format!("127.0.0.1:{}", tls_ports[0]);
+        assert!(Client::connect_tls(&misnamed, &tls).await.is_err());
+    }
+}
@@ -0,0 +1,459 @@
+//! Read-through caching in front of an upstream Redis, and write-behind
+//! mirroring to it. With `upstream` set, a GET that misses locally is fetched
+//! from the upstream server, kept locally for at most `upstream-ttl` and
+//! returned, so rudis can run as a near cache beside an application that
+//! shares a central Redis. With `upstream-write-behind`, every write command
+//! that succeeds locally is also sent upstream in the background, for shadow
+//! deployments and migrations.
+
+use crate::client::Client;
+use crate::log::{notice, warning};
+use crate::ratelimit::ByteThrottle;
+use crate::resp::RespValue;
+use crate::store::Store;
+use crate::tls::PeerTls;
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// A fetch taking longer than this counts as a miss, so a slow upstream
+/// slows clients down by at most this much
+const FETCH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Connections kept open for reuse between fetches
+const MAX_IDLE: usize = 16;
+/// Wait before retrying a write upstream, doubling up to `MAX_RETRY_DELAY`
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Counters for INFO upstream
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    /// Local misses looked up upstream
+    fetches: AtomicU64,
+    /// Lookups that found the key upstream and cached it
+    hits: AtomicU64,
+    /// Lookups that failed: connection errors, error replies and timeouts
+    errors: AtomicU64,
+    /// Writes waiting to be sent upstream
+    writes_pending: AtomicU64,
+    /// Writes the upstream server accepted
+    writes_sent: AtomicU64,
+    /// Attempts that failed to reach the upstream server and were retried
+    write_retries: AtomicU64,
+    /// Writes dropped because the queue was full; upstream diverged
+    writes_dropped: AtomicU64,
+    /// Writes the upstream server replied to with an error; upstream diverged
+    writes_rejected: AtomicU64,
+}
+
+impl UpstreamStats {
+    /// INFO upstream lines
+    pub fn info(&self) -> String {
+        let fetches = self.fetches.load(Ordering::Relaxed);
+        let hits = self.hits.load(Ordering::Relaxed);
+        let errors = self.errors.load(Ordering::Relaxed);
+        let mut info = String::new();
+        let _ = write!(
+            info,
+            "upstream_fetches:{}\r\nupstream_hits:{}\r\nupstream_misses:{}\r\nupstream_errors:{}\r\n",
+            fetches,
+            hits,
+            fetches - hits - errors,
+            errors
+        );
+        let dropped = self.writes_dropped.load(Ordering::Relaxed);
+        let rejected = self.writes_rejected.load(Ordering::Relaxed);
+        let _ = write!(
+            info,
+            "upstream_writes_pending:{}\r\nupstream_writes_sent:{}\r\n\
+             upstream_write_retries:{}\r\nupstream_writes_dropped:{}\r\n\
+             upstream_writes_rejected:{}\r\nupstream_writes_diverged:{}\r\n",
+            self.writes_pending.load(Ordering::Relaxed),
+            self.writes_sent.load(Ordering::Relaxed),
+            self.write_retries.load(Ordering::Relaxed),
+            dropped,
+            rejected,
+            dropped + rejected
+        );
+        info
+    }
+
+    /// The write queue's counters as one INFO value, for a peer
+    pub fn summary(&self) -> String {
+        format!(
+            "pending={},sent={},retries={},dropped={},rejected={}",
+            self.writes_pending.load(Ordering::Relaxed),
+            self.writes_sent.load(Ordering::Relaxed),
+            self.write_retries.load(Ordering::Relaxed),
+            self.writes_dropped.load(Ordering::Relaxed),
+            self.writes_rejected.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// The upstream server and the connections open to it
+#[derive(Debug)]
+pub struct Upstream {
+    addr: String,
+    /// Longest a fetched value is kept locally
+    ttl: Duration,
+    idle: Mutex<Vec<Client>>,
+    /// Shared with the write-behind queue to the same server, if any
+    stats: Arc<UpstreamStats>,
+}
+
+impl Upstream {
+    pub fn new(addr: String, ttl: Duration, stats: Arc<UpstreamStats>) -> Self {
+        Self {
+            addr,
+            ttl,
+            idle: Mutex::new(Vec::new()),
+            stats,
+        }
+    }
+
+    /// Read-through lookups, and write-behind writes if they share these
+    pub fn stats(&self) -> &UpstreamStats {
+        &self.stats
+    }
+
+    /// Answer a GET that missed in `store` from upstream, caching what is
+    /// found. An unreachable upstream is reported as a miss rather than an
+    /// error, as the cache is only ever an optimization.
+    pub async fn read_through(&self, store: &Store, key: &[u8]) -> RespValue {
+        let stats = &self.stats;
+        stats.fetches.fetch_add(1, Ordering::Relaxed);
+        let fetched = match tokio::time::timeout(FETCH_TIMEOUT, self.fetch(key)).await {
+            Ok(fetched) => fetched,
+            Err(_) => Err(anyhow!("timed out after {:?}", FETCH_TIMEOUT)),
+        };
+        match fetched {
+            Ok(Some((value, ttl))) => {
+                stats.hits.fetch_add(1, Ordering::Relaxed);
+                let seconds = ttl.min(self.ttl).as_secs().max(1);
+                // Values over the size limits are passed on but not kept
+                if store.check_size(key, Some(&value)).is_ok() {
+                    store
+                        .set_ex(Bytes::copy_from_slice(key), value.clone(), seconds)
+                        .await;
+                }
+                RespValue::BulkString(Some(value))
+            }
+            Ok(None) => RespValue::BulkString(None),
+            Err(e) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                warning!("Can't read through to upstream {}: {}", self.addr, e);
+                RespValue::BulkString(None)
+            }
+        }
+    }
+
+    /// GET and TTL of `key` upstream, on a pooled connection. Keys without
+    /// an expiry upstream are given the longest TTL.
+    async fn fetch(&self, key: &[u8]) -> Result<Option<(Bytes, Duration)>> {
+        let pooled = self.idle.lock().unwrap().pop();
+        let mut client = match pooled {
+            Some(client) => client,
+            None => Client::connect(&self.addr).await?,
+        };
+        client.send(&[&b"GET"[..], key]).await?;
+        client.send(&[&b"TTL"[..], key]).await?;
+        let (value, ttl) = (client.reply().await?, client.reply().await?);
+        // Only a connection that answered both is known to be in sync
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(client);
+        }
+        drop(idle);
+
+        let ttl = match ttl {
+            RespValue::Integer(seconds) if seconds > 0 => Duration::from_secs(seconds as u64),
+            _ => self.ttl,
+        };
+        match value {
+            RespValue::BulkString(Some(value)) => Ok(Some((value, ttl))),
+            RespValue::BulkString(None) => Ok(None),
+            RespValue::Error(e) => Err(anyhow!(e)),
+            other => Err(anyhow!("unexpected reply {:?}", other)),
+        }
+    }
+}
+
+/// The queue of writes on their way upstream
+#[derive(Debug, Clone)]
+pub struct WriteBehind {
+    writes: mpsc::Sender<Vec<Bytes>>,
+    stats: Arc<UpstreamStats>,
+}
+
+impl WriteBehind {
+    /// Start sending writes to `addr` in a background task, holding at most
+    /// `capacity` of them while the upstream server is behind or unreachable,
+    /// and sending at most `rate` bytes of them a second, or any amount for 0.
+    /// With `tls`, the connection is made over TLS. `hello` is sent first on
+    /// every new connection, and one it gets an error reply to is retried.
+    pub fn spawn(
+        addr: String,
+        capacity: usize,
+        rate: usize,
+        tls: Option<PeerTls>,
+        hello: Option<Vec<Bytes>>,
+        stats: Arc<UpstreamStats>,
+    ) -> Self {
+        let (writes, queue) = mpsc::channel(capacity.max(1));
+        let throttle = (rate > 0).then(|| ByteThrottle::new(rate));
+        tokio::spawn(send_writes(
+            addr,
+            queue,
+            throttle,
+            tls,
+            hello,
+            stats.clone(),
+        ));
+        Self { writes, stats }
+    }
+
+    /// Writes sent, retried and dropped, and read-through lookups if they
+    /// share these
+    pub fn stats(&self) -> &UpstreamStats {
+        &self.stats
+    }
+
+    /// Queue a write command that succeeded locally. A full queue drops it,
+    /// counting the divergence rather than holding up the client.
+    pub fn forward(&self, request: &RespValue) {
+        let RespValue::Array(Some(args)) = request else {
+            return;
+        };
+        // Copied, as the arguments are views into a request frame
+        let args: Option<Vec<Bytes>> = args
+            .iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(Some(arg)) => Some(Bytes::copy_from_slice(arg)),
+                RespValue::SimpleString(arg) => Some(Bytes::copy_from_slice(arg.as_bytes())),
+                _ => None,
+            })
+            .collect();
+        let Some(args) = args else {
+            return;
+        };
+        // Counted before sending, so the sender never sees it negative
+        self.stats.writes_pending.fetch_add(1, Ordering::Relaxed);
+        if self.writes.try_send(args).is_err() {
+            self.stats.writes_pending.fetch_sub(1, Ordering::Relaxed);
+            self.stats.writes_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Send queued writes upstream in order, each until it gets a reply.
+/// Connection failures are retried with backoff, so a write is sent at
+/// least once; one that timed out may have been applied already.
+async fn send_writes(
+    addr: String,
+    mut queue: mpsc::Receiver<Vec<Bytes>>,
+    throttle: Option<ByteThrottle>,
+    tls: Option<PeerTls>,
+    hello: Option<Vec<Bytes>>,
+    stats: Arc<UpstreamStats>,
+) {
+    let mut client = None;
+    // Only the first failure of an outage is logged
+    let mut failing = false;
+    while let Some(args) = queue.recv().await {
+        if let Some(throttle) = &throttle {
+            throttle.take(args.iter().map(Bytes::len).sum()).await;
+        }
+        let mut delay = MIN_RETRY_DELAY;
+        loop {
+            let sent = tokio::time::timeout(FETCH_TIMEOUT, async {
+                let connection = match client.as_mut() {
+                    Some(connection) => connection,
+                    None => {
+                        let mut connection = match &tls {
+                            Some(tls) => Client::connect_tls(&addr, tls).await?,
+                            None => Client::connect(&addr).await?,
+                        };
+                        if let Some(hello) = &hello
+                            && let RespValue::Error(e) = connection.command(hello).await?
+                        {
+                            return Err(anyhow!("{}", e));
+                        }
+                        client.insert(connection)
+                    }
+                };
+                connection.command(&args).await
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out after {:?}", FETCH_TIMEOUT)));
+            match sent {
+                Ok(reply) => {
+                    if failing {
+                        notice!("Writing behind to upstream {} again", addr);
+                        failing = false;
+                    }
+                    if let RespValue::Error(e) = reply {
+                        stats.writes_rejected.fetch_add(1, Ordering::Relaxed);
+                        warning!(
+                            "Upstream {} rejected {}: {}",
+                            addr,
+                            String::from_utf8_lossy(&args[0]),
+                            e
+                        );
+                    } else {
+                        stats.writes_sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                    break;
+                }
+                Err(e) => {
+                    if !failing {
+                        warning!("Can't write behind to upstream {}: {}", addr, e);
+                        failing = true;
+                    }
+                    client = None;
+                    stats.write_retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+        stats.writes_pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reads_through_and_caches() {
+        let central = Server::builder().port(0).bind().await.unwrap();
+        let addr = central.local_addr().unwrap();
+        let central_store = central.store().clone();
+        tokio::spawn(async move { central.run().await });
+        central_store
+            .set(Bytes::from("shared"), Bytes::from("from upstream"))
+            .await;
+        central_store
+            .set_ex(Bytes::from("short"), Bytes::from("lived"), 5)
+            .await;
+
+        let upstream = Upstream::new(addr.to_string(), Duration::from_secs(60), Arc::default());
+        let store = Store::new();
+        assert_eq!(
+            upstream.read_through(&store, b"shared").await,
+            RespValue::BulkString(Some(Bytes::from("from upstream")))
+        );
+        // Now cached locally, for no longer than either TTL
+        assert_eq!(
+            store.get(b"shared").await,
+            Some(Bytes::from("from upstream"))
+        );
+        assert!((59..=60).contains(&store.ttl(b"shared").await));
+        // TTL rounds down upstream and again locally
+        upstream.read_through(&store, b"short").await;
+        assert!((3..=5).contains(&store.ttl(b"short").await));
+
+        assert_eq!(
+            upstream.read_through(&store, b"missing").await,
+            RespValue::BulkString(None)
+        );
+        assert_eq!(store.get(b"missing").await, None);
+        let info = upstream.stats().info();
+        assert!(
+            info.starts_with("upstream_fetches:3\r\nupstream_hits:2\r\nupstream_misses:1\r\n"),
+            "{}",
+            info
+        );
+    }
+
+    fn request(args: &[&str]) -> RespValue {
+        RespValue::Array(Some(
+            args.iter()
+                .map(|arg| RespValue::BulkString(Some(Bytes::copy_from_slice(arg.as_bytes()))))
+                .collect(),
+        ))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn writes_behind_in_order() {
+        let central = Server::builder().port(0).bind().await.unwrap();
+        let addr = central.local_addr().unwrap();
+        let central_store = central.store().clone();
+        tokio::spawn(async move { central.run().await });
+
+        let stats = Arc::new(UpstreamStats::default());
+        let write_behind = WriteBehind::spawn(addr.to_string(), 100, 0, None, None, stats.clone());
+        write_behind.forward(&request(&["SET", "n", "1"]));
+        write_behind.forward(&request(&["INCRBY", "n", "41"]));
+        write_behind.forward(&request(&["SET", "text", "a"]));
+        // Accepted here but not upstream
+        write_behind.forward(&request(&["INCR", "text"]));
+
+        for _ in 0..100 {
+            if stats.writes_pending.load(Ordering::Relaxed) == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(central_store.get(b"n").await, Some(Bytes::from("42")));
+        let info = stats.info();
+        assert!(info.contains("upstream_writes_sent:3\r\n"), "{}", info);
+        assert!(info.contains("upstream_writes_rejected:1\r\n"), "{}", info);
+        assert!(info.contains("upstream_writes_diverged:1\r\n"), "{}", info);
+    }
+
+    #[tokio::test]
+    async fn full_write_queue_drops_writes() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let stats = Arc::new(UpstreamStats::default());
+        let write_behind = WriteBehind::spawn(
+            format!("127.0.0.1:{}", port),
+            2,
+            0,
+            None,
+            None,
+            stats.clone(),
+        );
+        for i in 0..10 {
+            write_behind.forward(&request(&["SET", "k", &i.to_string()]));
+        }
+        // One being retried and two queued; the rest are gone
+        let dropped = stats.writes_dropped.load(Ordering::Relaxed);
+        assert!((7..=8).contains(&dropped), "{}", dropped);
+        assert!(
+            stats
+                .info()
+                .contains(&format!("upstream_writes_diverged:{}\r\n", dropped))
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream_is_a_miss() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let upstream = Upstream::new(
+            format!("127.0.0.1:{}", port),
+            Duration::from_secs(60),
+            Arc::default(),
+        );
+        let store = Store::new();
+        assert_eq!(
+            upstream.read_through(&store, b"k").await,
+            RespValue::BulkString(None)
+        );
+        assert!(upstream.stats().info().contains("upstream_errors:1\r\n"));
+    }
+}
@@ -0,0 +1,244 @@
+//! io_uring networking backend, built with the `io-uring` feature on Linux.
+//!
+//! Each connection is served by one task on a single-threaded tokio-uring
+//! runtime: reads and vectored writes are submitted as io_uring operations,
+//! while parsing and command execution are shared with the Tokio backend
+//! through `server::Context`.
+
+use crate::bufpool::ReadSize;
+use crate::config::Config;
+use crate::log::{notice, warning};
+use crate::resp::ReplyBuffer;
+use crate::server::{
+    Admission, ConnectionContext, Context, Flow, MAX_IOVECS, configure_socket, drain,
+    shutdown_signal,
+};
+use crate::store::Store;
+use crate::{admin, probe, systemd, watchdog};
+use anyhow::{Result, anyhow};
+use bytes::{Buf, Bytes, BytesMut};
+use socket2::SockRef;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::time::{Duration, Instant};
+use tokio_uring::buf::IoBuf;
+use tokio_uring::net::{TcpListener, TcpStream};
+
+/// Serve clients on an io_uring runtime until SHUTDOWN or SIGTERM/SIGINT
+pub fn run(config: Config) -> Result<()> {
+    let addr = config.addr();
+    let socket_addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Can't resolve bind address '{}'", addr))?;
+
+    tokio_uring::start(async move {
+        let listener = TcpListener::bind(socket_addr)?;
+        notice!("Rudis server listening on {} (io_uring)", addr);
+
+        let context = Context::new(config);
+        context.restore().await?;
+        let _expiry = context.propagate_expiry();
+        let _probe = probe::spawn(&context)?;
+        let admin_handle = admin::spawn(&context).await?;
+        let expiration_handle = Store::start_active_expiration(context.store.clone());
+        let compaction_handle = Store::start_compaction(context.store.clone());
+        let tenants_handle = context.start_tenant_measurement();
+        let shedding_handle = context.start_load_shedding();
+        let backup_handle = context.start_backups();
+        let log_reopen_handle = crate::log::spawn_reopen_on_signal();
+        let _watchdog = watchdog::spawn(tokio::runtime::Handle::current());
+        let mut shutdown_rx = context.shutdown.subscribe();
+        context.set_ready(true);
+        systemd::notify(context.config.supervised, systemd::READY);
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted?;
+
+                    let slot = match context.admit(addr) {
+                        Admission::Accept(slot) => slot,
+                        Admission::Reject => continue,
+                        Admission::Deny(error) => {
+                            tokio_uring::spawn(async move {
+                                let (_, _) = stream.write_all(error.as_bytes()).await;
+                                let _ = stream.shutdown(std::net::Shutdown::Both);
+                            });
+                            continue;
+                        }
+                    };
+                    // SAFETY: the descriptor is owned by `stream`, which outlives the borrow
+                    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+                    if let Err(e) = configure_socket(SockRef::from(&fd), &context.config) {
+                        warning!("Failed to set socket options for {}: {}", addr, e);
+                    }
+
+                    let context = context.clone();
+                    tokio_uring::spawn(async move {
+                        if let Err(e) = handle_connection(stream, addr.ip(), slot.id(), context).await {
+                            warning!("Error handling client {}: {}", slot.id(), e);
+                        }
+                        drop(slot);
+                    });
+                }
+                _ = shutdown_rx.changed() => break,
+                signal = shutdown_signal() => {
+                    notice!("Received {}, shutting down", signal);
+                    context.shutdown.send_replace(true);
+                    break;
+                }
+            }
+        }
+
+        context.set_ready(false);
+        systemd::notify(context.config.supervised, systemd::STOPPING);
+        drop(listener);
+        drain(&context).await;
+        expiration_handle.abort();
+        compaction_handle.abort();
+        tenants_handle.abort();
+        shedding_handle.abort();
+        log_reopen_handle.abort();
+        if let Some(backup_handle) = backup_handle {
+            backup_handle.abort();
+        }
+        if let Some(admin_handle) = admin_handle {
+            admin_handle.abort();
+        }
+        notice!("Rudis is now ready to exit, bye bye...");
+        Ok(())
+    })
+}
+
+/// Serve one client: read, run every complete command, then send the
+/// pipeline's replies before reading again
+async fn handle_connection(
+    stream: TcpStream,
+    peer: IpAddr,
+    id: u64,
+    context: Context,
+) -> Result<()> {
+    let config = context.config.clone();
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut size = ReadSize::new(
+        config.client_read_buffer_low,
+        config.client_read_buffer_high,
+    );
+    let mut scratch = Vec::new();
+    let mut replies = ReplyBuffer::new();
+    let mut connection = ConnectionContext::new(id, peer);
+    let mut shutdown_rx = context.shutdown.subscribe();
+
+    loop {
+        // Stop reading from a client that keeps sending data we can't parse
+        let room = config
+            .client_query_buffer_limit
+            .saturating_sub(buffer.len());
+        if room == 0 {
+            warning!(
+                "Closing client {} after command {}, which reached max query buffer length ({} bytes)",
+                id,
+                connection.commands(),
+                buffer.len()
+            );
+            return Ok(());
+        }
+
+        // An io_uring read owns its buffer until it completes, so the scratch
+        // buffer is handed over and returned with the result. It is sized to
+        // the connection's read size, which grows under load and shrinks
+        // once the connection idles
+        let read_size = size.current();
+        if scratch.capacity() < read_size || scratch.capacity() > 2 * read_size {
+            scratch = Vec::with_capacity(read_size);
+        }
+        let room = room.min(read_size);
+        let Some(timeout) = connection.read_timeout(config.timeout) else {
+            context.close_unfinished_frame(&connection, buffer.len());
+            return Ok(());
+        };
+        let reading = Instant::now();
+        let read = read_with_timeout(&stream, scratch, room, timeout);
+        let n = tokio::select! {
+            read = read => match read {
+                Some((n, buf)) => {
+                    scratch = buf;
+                    let n = n?;
+                    size.idled(reading.elapsed());
+                    size.read(n, room);
+                    n
+                }
+                None if connection.frame_overdue() => {
+                    context.close_unfinished_frame(&connection, buffer.len());
+                    return Ok(());
+                }
+                // Idle for longer than `timeout`, close like Redis does
+                None => return Ok(()),
+            },
+            _ = shutdown_rx.changed() => return Ok(()),
+        };
+
+        if n == 0 {
+            // Connection closed
+            return Ok(());
+        }
+        buffer.extend_from_slice(&scratch[..n]);
+
+        let flow = context
+            .process(&mut connection, &mut buffer, &mut replies)
+            .await;
+        while !replies.is_empty() {
+            write_all_vectored(&stream, replies.take()).await?;
+        }
+        match flow {
+            Flow::Read => {}
+            Flow::Close | Flow::Drop => return Ok(()),
+            Flow::Shutdown => {
+                context.shutdown.send_replace(true);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Read at most `max` bytes into `scratch`, giving up with None once the
+/// connection has been idle for `timeout` (a zero timeout waits forever)
+async fn read_with_timeout(
+    stream: &TcpStream,
+    scratch: Vec<u8>,
+    max: usize,
+    timeout: Duration,
+) -> Option<(std::io::Result<usize>, Vec<u8>)> {
+    let read = async {
+        let (n, buf) = stream.read(scratch.slice(..max)).await;
+        (n, buf.into_inner())
+    };
+    if timeout.is_zero() {
+        return Some(read.await);
+    }
+    tokio::time::timeout(timeout, read).await.ok()
+}
+
+/// Write every chunk, submitting up to `MAX_IOVECS` of them per writev
+async fn write_all_vectored(stream: &TcpStream, mut chunks: Vec<Bytes>) -> std::io::Result<()> {
+    while !chunks.is_empty() {
+        let rest = chunks.split_off(chunks.len().min(MAX_IOVECS));
+        let (written, mut batch) = stream.writev(chunks).await;
+        let mut written = written?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+
+        // Drop what the kernel took and retry the remainder
+        batch.retain_mut(|chunk| {
+            let taken = written.min(chunk.len());
+            chunk.advance(taken);
+            written -= taken;
+            !chunk.is_empty()
+        });
+        batch.extend(rest);
+        chunks = batch;
+    }
+    Ok(())
+}
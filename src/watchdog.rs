@@ -0,0 +1,155 @@
+//! Tokio runtime metrics for INFO runtime, and a watchdog thread that notices
+//! workers which stopped parking, the sign of a task blocking its thread.
+//! Poll times and blocking-pool figures are only available in builds with
+//! `RUSTFLAGS="--cfg tokio_unstable"`.
+
+use crate::log::warning;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeMetrics};
+
+/// How often the watchdog samples the workers; a worker active for a whole
+/// interval without parking counts as stalled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Workers found stalled by the latest sample
+static STALLED_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Stops the watchdog thread when dropped
+#[derive(Debug)]
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Watch the workers of `runtime` from a thread of its own, so stalls are
+/// caught even when every worker is blocked
+pub fn spawn(runtime: Handle) -> Watchdog {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = std::thread::Builder::new()
+        .name("rudis-watchdog".to_string())
+        .spawn(move || {
+            let metrics = runtime.metrics();
+            let mut previous = park_counts(&metrics);
+            while !stopped.load(Ordering::Relaxed) {
+                std::thread::sleep(SAMPLE_INTERVAL);
+                let current = park_counts(&metrics);
+                let stalled = stalled_workers(&previous, &current);
+                let was_stalled = STALLED_WORKERS.swap(stalled, Ordering::Relaxed);
+                // Only the start of a stall is worth a log line
+                if stalled > was_stalled {
+                    warning!(
+                        "{} runtime worker(s) busy for over {:?} without parking; \
+                         a task may be blocking its thread",
+                        stalled,
+                        SAMPLE_INTERVAL
+                    );
+                }
+                previous = current;
+            }
+        });
+    if let Err(e) = thread {
+        warning!("Can't start the runtime watchdog: {}", e);
+    }
+    Watchdog { stop }
+}
+
+/// Each worker's park/unpark count; odd while parked, even while active
+fn park_counts(metrics: &RuntimeMetrics) -> Vec<u64> {
+    (0..metrics.num_workers())
+        .map(|worker| metrics.worker_park_unpark_count(worker))
+        .collect()
+}
+
+/// Workers active at both samples that never parked in between
+fn stalled_workers(previous: &[u64], current: &[u64]) -> usize {
+    previous
+        .iter()
+        .zip(current)
+        .filter(|(before, now)| before == now && *now % 2 == 0)
+        .count()
+}
+
+/// Workers found stalled by the latest sample
+pub fn stalled() -> usize {
+    STALLED_WORKERS.load(Ordering::Relaxed)
+}
+
+/// INFO runtime fields for the runtime running the caller
+pub fn info() -> String {
+    let metrics = Handle::current().metrics();
+    let workers = metrics.num_workers();
+    let busy: Duration = (0..workers)
+        .map(|worker| metrics.worker_total_busy_duration(worker))
+        .sum();
+    let parks: u64 = (0..workers)
+        .map(|worker| metrics.worker_park_count(worker))
+        .sum();
+
+    let mut section = String::new();
+    let _ = write!(
+        section,
+        "tokio_workers:{}\r\n\
+         tokio_alive_tasks:{}\r\n\
+         tokio_global_queue_depth:{}\r\n\
+         tokio_busy_usec:{}\r\n\
+         tokio_parks:{}\r\n\
+         tokio_stalled_workers:{}\r\n",
+        workers,
+        metrics.num_alive_tasks(),
+        metrics.global_queue_depth(),
+        busy.as_micros(),
+        parks,
+        stalled()
+    );
+    #[cfg(tokio_unstable)]
+    {
+        let mean_poll = (0..workers)
+            .map(|worker| metrics.worker_mean_poll_time(worker))
+            .max()
+            .unwrap_or_default();
+        let _ = write!(
+            section,
+            "tokio_spawned_tasks:{}\r\n\
+             tokio_max_mean_poll_usec:{}\r\n\
+             tokio_budget_forced_yields:{}\r\n\
+             tokio_blocking_threads:{}\r\n\
+             tokio_idle_blocking_threads:{}\r\n\
+             tokio_blocking_queue_depth:{}\r\n",
+            metrics.spawned_tasks_count(),
+            mean_poll.as_micros(),
+            metrics.budget_forced_yield_count(),
+            metrics.num_blocking_threads(),
+            metrics.num_idle_blocking_threads(),
+            metrics.blocking_queue_depth()
+        );
+    }
+    section
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalled_means_active_without_parking() {
+        // Worker 0 kept parking, 1 stayed parked, 2 stayed active
+        assert_eq!(stalled_workers(&[4, 7, 10], &[8, 7, 10]), 1);
+        assert_eq!(stalled_workers(&[4, 7, 10], &[5, 7, 12]), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reports_runtime_metrics() {
+        let section = info();
+        assert!(section.starts_with("tokio_workers:2\r\n"), "{}", section);
+        assert!(section.contains("tokio_stalled_workers:"));
+    }
+}
@@ -0,0 +1,98 @@
+//! In-process test harness: a rudis server on an ephemeral port inside the
+//! test's runtime, and the bundled client to talk to it over TCP.
+
+// Each test binary uses only some of these
+#![allow(dead_code)]
+
+use rudis::client::Client;
+use rudis::{RespValue, Server, ServerConfig, Store};
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+
+/// A server running until dropped
+pub struct TestServer {
+    addr: SocketAddr,
+    store: Store,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Start a server with the default configuration
+    pub async fn start() -> Self {
+        Self::with(Server::builder()).await
+    }
+
+    /// Start a server from `builder`, on a free port whatever it says
+    pub async fn with(builder: ServerConfig) -> Self {
+        let server = builder.address("127.0.0.1").port(0).bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let store = server.store().clone();
+        let task = tokio::spawn(async move {
+            server.run().await.unwrap();
+        });
+        Self { addr, store, task }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The server's keyspace, for checking state without a client
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Connect a new client
+    pub async fn client(&self) -> TestClient {
+        TestClient::connect(self.addr).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A `rudis::client::Client` that panics on connection errors, so tests
+/// can compare replies directly
+pub struct TestClient {
+    client: Client,
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> Self {
+        Self {
+            client: Client::connect(addr).await.unwrap(),
+        }
+    }
+
+    /// Send one command and wait for its reply
+    pub async fn command(&mut self, args: &[&str]) -> RespValue {
+        self.client.command(args).await.unwrap()
+    }
+
+    /// Send a command without waiting for the reply, for pipelining
+    pub async fn send(&mut self, args: &[&str]) {
+        self.client.send(args).await.unwrap();
+    }
+
+    /// Read the next reply; panics if the server closes the connection first
+    pub async fn reply(&mut self) -> RespValue {
+        self.client.reply().await.unwrap()
+    }
+}
+
+/// A bulk string reply
+pub fn bulk(value: &str) -> RespValue {
+    RespValue::BulkString(Some(value.to_string().into()))
+}
+
+/// The null bulk string reply of a missing key
+pub fn nil() -> RespValue {
+    RespValue::BulkString(None)
+}
+
+pub fn ok() -> RespValue {
+    RespValue::SimpleString("OK".to_string())
+}
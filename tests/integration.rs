@@ -0,0 +1,883 @@
+//! End-to-end tests over TCP against a server started inside the test process
+
+mod common;
+
+use bytes::Bytes;
+use common::{TestServer, bulk, nil, ok};
+use rudis::{ManualClock, RespValue, Server, Store};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn int(n: i64) -> RespValue {
+    RespValue::Integer(n)
+}
+
+/// Sorted bulk string contents of an array reply
+fn sorted_strings(reply: RespValue) -> Vec<String> {
+    let RespValue::Array(Some(values)) = reply else {
+        panic!("expected an array, got {:?}", reply);
+    };
+    let mut strings: Vec<String> = values
+        .into_iter()
+        .map(|value| match value {
+            RespValue::BulkString(Some(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            other => panic!("expected a bulk string, got {:?}", other),
+        })
+        .collect();
+    strings.sort();
+    strings
+}
+
+#[tokio::test]
+async fn test_ping() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let pong = RespValue::SimpleString("PONG".to_string());
+
+    assert_eq!(client.command(&["PING"]).await, pong);
+    assert_eq!(client.command(&["ping"]).await, pong);
+    assert_eq!(client.command(&["PING", "hello"]).await, bulk("hello"));
+    assert_eq!(
+        client.command(&["PING", "hello world"]).await,
+        bulk("hello world")
+    );
+    assert_eq!(client.command(&["PING", ""]).await, bulk(""));
+    for i in 0..5 {
+        let message = format!("message{}", i);
+        assert_eq!(client.command(&["PING", &message]).await, bulk(&message));
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_command() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    match client.command(&["NOTACOMMAND"]).await {
+        RespValue::Error(e) => assert!(e.starts_with("ERR unknown command"), "{}", e),
+        other => panic!("expected an error, got {:?}", other),
+    }
+    // The connection stays usable
+    assert_eq!(
+        client.command(&["PING", "still here"]).await,
+        bulk("still here")
+    );
+}
+
+#[tokio::test]
+async fn test_set_get_del() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    assert_eq!(client.command(&["SET", "testkey", "testvalue"]).await, ok());
+    assert_eq!(client.command(&["GET", "testkey"]).await, bulk("testvalue"));
+    assert_eq!(client.command(&["GET", "nonexistent"]).await, nil());
+
+    assert_eq!(
+        client.command(&["DEL", "testkey", "nonexistent"]).await,
+        int(1)
+    );
+    assert_eq!(client.command(&["GET", "testkey"]).await, nil());
+}
+
+#[tokio::test]
+async fn test_setnx() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["SETNX", "setnxkey", "first"]).await,
+        int(1)
+    );
+    assert_eq!(
+        client.command(&["SETNX", "setnxkey", "second"]).await,
+        int(0)
+    );
+    assert_eq!(client.command(&["GET", "setnxkey"]).await, bulk("first"));
+}
+
+#[tokio::test]
+async fn test_incr_decr() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    assert_eq!(client.command(&["INCR", "newcounter"]).await, int(1));
+
+    assert_eq!(client.command(&["SET", "counter", "10"]).await, ok());
+    assert_eq!(client.command(&["INCR", "counter"]).await, int(11));
+    assert_eq!(client.command(&["INCRBY", "counter", "5"]).await, int(16));
+    assert_eq!(client.command(&["DECR", "counter"]).await, int(15));
+    assert_eq!(client.command(&["DECRBY", "counter", "3"]).await, int(12));
+    assert_eq!(client.command(&["GET", "counter"]).await, bulk("12"));
+}
+
+#[tokio::test]
+async fn test_mset_mget() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let reply = client
+        .command(&["MSET", "mkey1", "mval1", "mkey2", "mval2", "mkey3", "mval3"])
+        .await;
+    assert_eq!(reply, ok());
+    assert_eq!(
+        client.command(&["MGET", "mkey1", "missing", "mkey3"]).await,
+        RespValue::Array(Some(vec![bulk("mval1"), nil(), bulk("mval3")]))
+    );
+}
+
+#[tokio::test]
+async fn test_setex_expire_ttl_persist() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["SETEX", "exkey", "10", "temporary"]).await,
+        ok()
+    );
+    assert_eq!(client.command(&["GET", "exkey"]).await, bulk("temporary"));
+
+    client.command(&["SET", "ttlkey", "value"]).await;
+    assert_eq!(client.command(&["TTL", "ttlkey"]).await, int(-1));
+    assert_eq!(client.command(&["EXPIRE", "ttlkey", "100"]).await, int(1));
+    match client.command(&["TTL", "ttlkey"]).await {
+        RespValue::Integer(ttl) => assert!((99..=100).contains(&ttl), "TTL was {}", ttl),
+        other => panic!("expected an integer, got {:?}", other),
+    }
+    assert_eq!(client.command(&["PERSIST", "ttlkey"]).await, int(1));
+    assert_eq!(client.command(&["TTL", "ttlkey"]).await, int(-1));
+    assert_eq!(client.command(&["TTL", "nonexistent"]).await, int(-2));
+
+    // A negative timeout deletes the key
+    client.command(&["SET", "negexpkey", "value"]).await;
+    assert_eq!(client.command(&["EXPIRE", "negexpkey", "-1"]).await, int(1));
+    assert_eq!(client.command(&["GET", "negexpkey"]).await, nil());
+}
+
+#[tokio::test]
+async fn test_keys_patterns() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    client
+        .command(&[
+            "MSET",
+            "keystest:a",
+            "1",
+            "keystest:b",
+            "2",
+            "keystest:c",
+            "3",
+            "other",
+            "4",
+            "k1",
+            "a",
+            "k2",
+            "b",
+            "k10",
+            "c",
+        ])
+        .await;
+    assert_eq!(
+        sorted_strings(client.command(&["KEYS", "keystest:*"]).await),
+        ["keystest:a", "keystest:b", "keystest:c"]
+    );
+    assert_eq!(
+        sorted_strings(client.command(&["KEYS", "k?"]).await),
+        ["k1", "k2"]
+    );
+}
+
+#[tokio::test]
+async fn test_active_expiration() {
+    let clock = Arc::new(ManualClock::new());
+    let store = Store::new().with_clock(clock.clone());
+    let server = TestServer::with(Server::builder().store(store)).await;
+    let mut client = server.client().await;
+
+    for i in 1..=5 {
+        let key = format!("active_short{}", i);
+        assert_eq!(client.command(&["SETEX", &key, "1", "short"]).await, ok());
+    }
+    for i in 1..=3 {
+        let key = format!("active_long{}", i);
+        assert_eq!(client.command(&["SETEX", &key, "60", "long"]).await, ok());
+    }
+    assert_eq!(
+        sorted_strings(client.command(&["KEYS", "active_*"]).await).len(),
+        8
+    );
+
+    // Without touching the keys, only the background task can delete them;
+    // it runs every 100ms
+    clock.advance(Duration::from_secs(2));
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.store().memory_stats().await.keys != 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("expired keys were not deleted");
+    assert_eq!(
+        sorted_strings(client.command(&["KEYS", "active_*"]).await),
+        ["active_long1", "active_long2", "active_long3"]
+    );
+}
+
+#[tokio::test]
+async fn test_pipelined_commands_reply_in_order() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    for _ in 0..100 {
+        client.send(&["INCR", "pipelined"]).await;
+    }
+    for i in 1..=100 {
+        assert_eq!(client.reply().await, int(i));
+    }
+}
+
+#[tokio::test]
+async fn test_long_replies_stream_in_order() {
+    let store = Store::new();
+    for i in 0..50_000 {
+        store
+            .set(format!("streamed:{:05}", i).into(), "v".into())
+            .await;
+    }
+    let server = TestServer::with(Server::builder().store(store)).await;
+    let mut client = server.client().await;
+
+    // Serialized a batch at a time, with the replies after it held back
+    client.send(&["KEYS", "streamed:*"]).await;
+    client.send(&["INCR", "after"]).await;
+    let keys = sorted_strings(client.reply().await);
+    assert_eq!(keys.len(), 50_000);
+    assert_eq!(keys[0], "streamed:00000");
+    assert_eq!(keys[49_999], "streamed:49999");
+    assert_eq!(client.reply().await, int(1));
+}
+
+#[tokio::test]
+async fn test_clients_share_the_keyspace() {
+    let store = Store::new();
+    store.set("seeded".into(), "from the test".into()).await;
+    let server = TestServer::with(Server::builder().store(store)).await;
+
+    let mut first = server.client().await;
+    let mut second = server.client().await;
+    assert_eq!(
+        first.command(&["GET", "seeded"]).await,
+        bulk("from the test")
+    );
+    assert_eq!(first.command(&["SET", "shared", "1"]).await, ok());
+    assert_eq!(second.command(&["GET", "shared"]).await, bulk("1"));
+    assert_eq!(
+        server.store().get(b"shared").await,
+        Some(bytes::Bytes::from("1"))
+    );
+}
+
+#[tokio::test]
+async fn test_quit_closes_the_connection() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["QUIT"]).await, ok());
+
+    // Other clients are unaffected
+    let mut other = server.client().await;
+    assert_eq!(other.command(&["PING", "hi"]).await, bulk("hi"));
+    assert!(server.addr().port() != 0);
+}
+
+/// Drive the server the way `redis-cli --bigkeys` and `--memkeys` do: DBSIZE,
+/// then SCAN pages, a pipelined TYPE for each key on a page, then a
+/// pipelined STRLEN (or MEMORY USAGE) for each, keeping the biggest
+#[tokio::test]
+async fn test_redis_cli_bigkeys_and_memkeys() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    for i in 0..200 {
+        let value = "x".repeat(i % 50);
+        client
+            .command(&["SET", &format!("key:{}", i), &value])
+            .await;
+    }
+    client
+        .command(&["SET", "the-biggest", &"y".repeat(4096)])
+        .await;
+    assert_eq!(client.command(&["DBSIZE"]).await, int(201));
+
+    for size_command in [&["STRLEN"][..], &["MEMORY", "USAGE"][..]] {
+        let mut seen = 0;
+        let mut biggest = (String::new(), 0);
+        let mut cursor = "0".to_string();
+        loop {
+            let RespValue::Array(Some(reply)) = client.command(&["SCAN", &cursor]).await else {
+                panic!("SCAN should reply with an array");
+            };
+            let [
+                RespValue::BulkString(Some(next)),
+                RespValue::Array(Some(keys)),
+            ] = &reply[..]
+            else {
+                panic!("unexpected SCAN reply {:?}", reply);
+            };
+            let keys: Vec<String> = keys
+                .iter()
+                .map(|key| match key {
+                    RespValue::BulkString(Some(key)) => String::from_utf8(key.to_vec()).unwrap(),
+                    other => panic!("expected a key, got {:?}", other),
+                })
+                .collect();
+
+            for key in &keys {
+                client.send(&["TYPE", key]).await;
+            }
+            for _ in &keys {
+                assert_eq!(
+                    client.reply().await,
+                    RespValue::SimpleString("string".to_string())
+                );
+            }
+            for key in &keys {
+                let mut args = size_command.to_vec();
+                args.push(key);
+                if size_command.len() > 1 {
+                    args.extend(["SAMPLES", "0"]);
+                }
+                client.send(&args).await;
+            }
+            for key in &keys {
+                let RespValue::Integer(size) = client.reply().await else {
+                    panic!("{:?} should reply with an integer", size_command);
+                };
+                if size > biggest.1 {
+                    biggest = (key.clone(), size);
+                }
+            }
+
+            seen += keys.len();
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(seen, 201, "{:?}", size_command);
+        assert_eq!(biggest.0, "the-biggest", "{:?}", size_command);
+        assert!(biggest.1 >= 4096, "{:?}", size_command);
+    }
+}
+
+/// memtier_benchmark's connection setup and default workloads: CONFIG GET
+/// and SELECT on connect, then deep pipelines of SETEX, GET and MSET, with
+/// WAIT when asked to wait for replicas
+#[tokio::test]
+async fn test_memtier_benchmark_workload() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "databases"]).await,
+        RespValue::Array(Some(vec![bulk("databases"), bulk("1")]))
+    );
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "save"]).await,
+        RespValue::Array(Some(vec![bulk("save"), bulk("")]))
+    );
+    assert_eq!(client.command(&["SELECT", "0"]).await, ok());
+    assert!(matches!(
+        client.command(&["SELECT", "1"]).await,
+        RespValue::Error(e) if e == "ERR DB index is out of range"
+    ));
+
+    // memtier's default --pipeline is 1, but load tests raise it
+    const PIPELINE: usize = 64;
+    for round in 0..10 {
+        for i in 0..PIPELINE {
+            let key = format!("memtier-{}", i);
+            client
+                .send(&["SETEX", &key, "60", &format!("{}", round)])
+                .await;
+            client.send(&["GET", &key]).await;
+        }
+        for _ in 0..PIPELINE {
+            assert_eq!(client.reply().await, ok());
+            assert_eq!(client.reply().await, bulk(&round.to_string()));
+        }
+    }
+    client
+        .send(&["MSET", "memtier-a", "1", "memtier-b", "2"])
+        .await;
+    client.send(&["WAIT", "1", "100"]).await;
+    assert_eq!(client.reply().await, ok());
+    assert_eq!(client.reply().await, int(0));
+    assert_eq!(client.command(&["DBSIZE"]).await, int(PIPELINE as i64 + 2));
+}
+
+#[tokio::test]
+async fn test_read_through_to_upstream() {
+    let central = TestServer::start().await;
+    central
+        .store()
+        .set("shared".into(), "from central".into())
+        .await;
+    let addr = central.addr().to_string();
+    let near = TestServer::with(Server::builder().config(|config| {
+        config.upstream = Some(addr);
+    }))
+    .await;
+    let mut client = near.client().await;
+
+    assert_eq!(
+        client.command(&["GET", "shared"]).await,
+        bulk("from central")
+    );
+    assert_eq!(
+        near.store().get(b"shared").await,
+        Some(bytes::Bytes::from("from central"))
+    );
+    // Kept for upstream-ttl, 60s by default
+    let RespValue::Integer(ttl) = client.command(&["TTL", "shared"]).await else {
+        panic!("TTL should reply with an integer");
+    };
+    assert!((59..=60).contains(&ttl), "{}", ttl);
+    assert_eq!(client.command(&["GET", "nowhere"]).await, nil());
+
+    // Local writes win, and aren't sent upstream
+    assert_eq!(client.command(&["SET", "shared", "local"]).await, ok());
+    assert_eq!(client.command(&["GET", "shared"]).await, bulk("local"));
+    assert_eq!(
+        central.store().get(b"shared").await,
+        Some(bytes::Bytes::from("from central"))
+    );
+}
+
+#[tokio::test]
+async fn test_write_behind_to_upstream() {
+    let central = TestServer::start().await;
+    central.store().set("stale".into(), "old".into()).await;
+    let addr = central.addr().to_string();
+    let near = TestServer::with(Server::builder().config(|config| {
+        config.upstream = Some(addr);
+        config.upstream_read_through = false;
+        config.upstream_write_behind = true;
+    }))
+    .await;
+    let mut client = near.client().await;
+
+    // Reads stay local without read-through
+    assert_eq!(client.command(&["GET", "stale"]).await, nil());
+    assert_eq!(client.command(&["SET", "counter", "10"]).await, ok());
+    assert_eq!(client.command(&["INCRBY", "counter", "5"]).await, int(15));
+    assert_eq!(client.command(&["SET", "stale", "new"]).await, ok());
+    assert_eq!(client.command(&["DEL", "stale"]).await, int(1));
+    // A write that fails here isn't sent on
+    assert_eq!(client.command(&["SET", "word", "abc"]).await, ok());
+    assert!(matches!(
+        client.command(&["INCR", "word"]).await,
+        RespValue::Error(_)
+    ));
+    assert_eq!(client.command(&["SET", "done", "1"]).await, ok());
+
+    let info = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let RespValue::BulkString(Some(info)) = client.command(&["INFO", "upstream"]).await
+            else {
+                panic!("INFO should reply with a bulk string");
+            };
+            let info = String::from_utf8(info.to_vec()).unwrap();
+            if info.contains("upstream_writes_pending:0\r\n") {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("writes were not sent upstream");
+    let upstream = central.store();
+    assert_eq!(upstream.get(b"counter").await, Some("15".into()));
+    assert_eq!(upstream.get(b"stale").await, None);
+    assert_eq!(upstream.get(b"word").await, Some("abc".into()));
+    assert_eq!(upstream.get(b"done").await, Some("1".into()));
+    assert!(info.contains("upstream_writes_sent:6\r\n"), "{}", info);
+    assert!(info.contains("upstream_writes_diverged:0\r\n"), "{}", info);
+}
+
+#[tokio::test]
+async fn test_max_value_size() {
+    let server = TestServer::with(Server::builder().config(|config| {
+        config.max_value_size = 1024;
+    }))
+    .await;
+    let mut client = server.client().await;
+
+    let large = "x".repeat(1025);
+    match client.command(&["SET", "blob", &large]).await {
+        RespValue::Error(e) => assert!(e.contains("max-value-size"), "{}", e),
+        reply => panic!("expected an error, got {:?}", reply),
+    }
+    assert_eq!(client.command(&["GET", "blob"]).await, nil());
+    assert_eq!(client.command(&["SET", "blob", &large[1..]]).await, ok());
+}
+
+#[tokio::test]
+async fn test_ttl_jitter_is_not_written_behind() {
+    let central = TestServer::start().await;
+    let addr = central.addr().to_string();
+    let near = TestServer::with(Server::builder().config(|config| {
+        config.upstream = Some(addr);
+        config.upstream_write_behind = true;
+        config.ttl_jitter = 50;
+    }))
+    .await;
+    let mut client = near.client().await;
+
+    for i in 0..20 {
+        let key = format!("session:{}", i);
+        assert_eq!(client.command(&["SETEX", &key, "1000", "v"]).await, ok());
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while central.store().get(b"session:19").await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("writes were not sent upstream");
+
+    // Jittered here, while upstream gets the TTL as the client sent it
+    let mut local = Vec::new();
+    for i in 0..20 {
+        let key = format!("session:{}", i);
+        local.push(near.store().ttl(key.as_bytes()).await);
+        let upstream = central.store().ttl(key.as_bytes()).await;
+        assert!((999..=1000).contains(&upstream), "{}", upstream);
+    }
+    assert!(
+        local.iter().all(|ttl| (500..=1500).contains(ttl)),
+        "{:?}",
+        local
+    );
+    assert!(local.iter().any(|ttl| *ttl != local[0]), "{:?}", local);
+}
+
+#[tokio::test]
+async fn test_tenant_namespaces_and_quotas() {
+    let server = TestServer::with(Server::builder().config(|config| {
+        config
+            .load_str("tenant-required yes\ntenant team s3cret team: max-keys 2\n")
+            .unwrap();
+    }))
+    .await;
+    server
+        .store()
+        .set("other:key".into(), "hidden".into())
+        .await;
+    let mut client = server.client().await;
+    let error = |reply: RespValue| match reply {
+        RespValue::Error(e) => e,
+        reply => panic!("expected an error, got {:?}", reply),
+    };
+
+    assert!(error(client.command(&["GET", "team:a"]).await).starts_with("NOAUTH"));
+    assert!(error(client.command(&["AUTH", "team", "wrong"]).await).starts_with("WRONGPASS"));
+    assert_eq!(client.command(&["AUTH", "team", "s3cret"]).await, ok());
+
+    // Confined to keys under team:
+    assert_eq!(client.command(&["SET", "team:a", "1"]).await, ok());
+    assert!(error(client.command(&["GET", "other:key"]).await).starts_with("NOPERM"));
+    assert!(error(client.command(&["CONFIG", "GET", "*"]).await).starts_with("NOPERM"));
+    assert_eq!(
+        client.command(&["KEYS", "*"]).await,
+        RespValue::Array(Some(vec![bulk("team:a")]))
+    );
+
+    // Writes stop once the measured usage reaches max-keys
+    assert_eq!(client.command(&["SET", "team:b", "2"]).await, ok());
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let RespValue::BulkString(Some(info)) = client.command(&["INFO", "tenants"]).await
+            else {
+                panic!("INFO should reply with a bulk string");
+            };
+            if String::from_utf8_lossy(&info).contains("tenant_team:keys=2,") {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("tenant usage was not measured");
+    assert!(error(client.command(&["SET", "team:c", "3"]).await).starts_with("OOM"));
+    assert_eq!(client.command(&["DEL", "team:a"]).await, int(1));
+    assert_eq!(client.command(&["DBSIZE"]).await, int(1));
+}
+
+#[tokio::test]
+async fn test_acl_save_and_load() {
+    let path = std::env::temp_dir().join(format!("rudis-it-{}.acl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("tenant team s3cret team:\naclfile {}\n", path.display());
+    let server = TestServer::with(Server::builder().config(move |c| {
+        c.load_str(&config).unwrap();
+    }))
+    .await;
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["ACL", "SAVE"]).await, ok());
+    let saved = std::fs::read_to_string(&path).unwrap();
+    assert!(saved.starts_with("tenant \"team\" #"), "{}", saved);
+    assert!(!saved.contains("s3cret"), "{}", saved);
+
+    // Users edited in the file take over once loaded
+    std::fs::write(&path, "tenant ops hunter2 ops:\n").unwrap();
+    assert_eq!(client.command(&["ACL", "LOAD"]).await, ok());
+    let mut other = server.client().await;
+    assert!(matches!(
+        other.command(&["AUTH", "team", "s3cret"]).await,
+        RespValue::Error(e) if e.starts_with("WRONGPASS")
+    ));
+    assert_eq!(other.command(&["AUTH", "ops", "hunter2"]).await, ok());
+    assert_eq!(other.command(&["SET", "ops:a", "1"]).await, ok());
+    // Managing users is for the administrator, not for tenants
+    assert!(matches!(
+        other.command(&["ACL", "LOAD"]).await,
+        RespValue::Error(e) if e.starts_with("NOPERM")
+    ));
+
+    // A file that doesn't parse leaves the users as they were
+    std::fs::write(&path, "tenant ops\n").unwrap();
+    assert!(matches!(
+        client.command(&["ACL", "LOAD"]).await,
+        RespValue::Error(e) if e.contains("line 1")
+    ));
+    assert_eq!(other.command(&["AUTH", "ops", "hunter2"]).await, ok());
+    std::fs::remove_file(&path).unwrap();
+
+    let plain = TestServer::start().await;
+    assert!(matches!(
+        plain.client().await.command(&["ACL", "SAVE"]).await,
+        RespValue::Error(e) if e.contains("not configured to use an ACL file")
+    ));
+}
+
+#[tokio::test]
+async fn test_command_time_budget() {
+    let server = TestServer::with(Server::builder().config(|config| {
+        config.command_time_budget = Duration::from_millis(1);
+    }))
+    .await;
+    let pairs: Vec<(Bytes, Bytes)> = (0..300_000)
+        .map(|i| (format!("key:{}", i).into(), "v".into()))
+        .collect();
+    server.store().mset(pairs).await;
+    let mut client = server.client().await;
+
+    match client.command(&["KEYS", "*"]).await {
+        RespValue::Error(e) => assert!(e.contains("command-time-budget (1 ms)"), "{}", e),
+        reply => panic!("expected an error, got {:?}", reply),
+    }
+    // Fast commands aren't held to it, and the connection carries on
+    assert_eq!(client.command(&["GET", "key:7"]).await, bulk("v"));
+    assert_eq!(client.command(&["DBSIZE"]).await, int(300_000));
+}
+
+#[tokio::test]
+async fn test_restore_to_before_a_mistake() {
+    let path = std::env::temp_dir().join(format!("rudis-pitr-{}.resp", std::process::id()));
+    let record_file = path.clone();
+    let server = TestServer::with(Server::builder().config(move |config| {
+        config.record_file = Some(record_file);
+    }))
+    .await;
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["SET", "a", "1"]).await, ok());
+    assert_eq!(client.command(&["INCR", "a"]).await, int(2));
+    assert_eq!(client.command(&["GET", "a"]).await, bulk("2"));
+    assert_eq!(client.command(&["DEL", "a"]).await, int(1));
+    drop(server);
+    // The recorder writes from a thread of its own
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while rudis::record::Recording::load(&path).map_or(true, |r| r.entries.len() < 4) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("commands were not recorded");
+
+    // Everything but the DEL
+    let restore_file = path.clone();
+    let server = TestServer::with(Server::builder().config(move |config| {
+        config.restore_file = Some(restore_file);
+        config.restore_until_offset = Some(3);
+    }))
+    .await;
+    // Clients are only served once the restore is done
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["GET", "a"]).await, bulk("2"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_restore_deletes_keys_that_had_expired() {
+    let path = std::env::temp_dir().join(format!("rudis-expired-{}.resp", std::process::id()));
+    let record_file = path.clone();
+    let server = TestServer::with(Server::builder().config(move |config| {
+        config.record_file = Some(record_file);
+    }))
+    .await;
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["SET", "session", "s"]).await, ok());
+    assert_eq!(client.command(&["EXPIRE", "session", "1"]).await, int(1));
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(client.command(&["GET", "session"]).await, nil());
+    drop(server);
+    // SET, EXPIRE, GET, and the DEL for the expiry, before or after the
+    // GET as the active expiration cycle or the GET got to it first
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while rudis::record::Recording::load(&path).map_or(true, |r| r.entries.len() < 4) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("expiry was not recorded");
+    let recording = rudis::record::Recording::load(&path).unwrap();
+    let expired: Vec<_> = recording
+        .entries
+        .iter()
+        .filter(|entry| entry.client == rudis::record::SERVER_CLIENT)
+        .map(|entry| entry.command.clone())
+        .collect();
+    assert_eq!(
+        expired,
+        [RespValue::Array(Some(vec![bulk("DEL"), bulk("session")]))]
+    );
+
+    // Replayed, the EXPIRE would give the key another second
+    let restore_file = path.clone();
+    let server = TestServer::with(Server::builder().config(move |config| {
+        config.restore_file = Some(restore_file);
+    }))
+    .await;
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["GET", "session"]).await, nil());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_seed_file_is_loaded_before_clients() {
+    let path = std::env::temp_dir().join(format!("rudis-seed-{}.json", std::process::id()));
+    std::fs::write(
+        &path,
+        "{\"db\":0,\"key\":\"user:1\",\"ttl\":-1,\"type\":\"string\",\"value\":\"ada\"}\n\
+         {\"db\":0,\"key\":\"session\",\"ttl\":300,\"type\":\"string\",\"value\":\"s\"}\n\
+         {\"db\":0,\"key\":\"roles\",\"ttl\":-1,\"type\":\"set\",\"value\":[\"admin\"]}\n",
+    )
+    .unwrap();
+    let seed_file = path.clone();
+    let server = TestServer::with(Server::builder().config(move |config| {
+        config.seed_file = Some(seed_file);
+    }))
+    .await;
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["GET", "user:1"]).await, bulk("ada"));
+    let ttl = client.command(&["TTL", "session"]).await;
+    assert!(matches!(ttl, RespValue::Integer(299..=300)), "{:?}", ttl);
+    assert_eq!(client.command(&["GET", "roles"]).await, nil());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_bgsave_writes_a_backup() {
+    let dir = std::env::temp_dir().join(format!("rudis-bgsave-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let backup_dir = dir.clone();
+    let server = TestServer::with(Server::builder().config(move |config| {
+        config.dir = backup_dir;
+    }))
+    .await;
+    let mut client = server.client().await;
+    assert_eq!(client.command(&["SET", "k", "v"]).await, ok());
+    assert_eq!(
+        client.command(&["BGSAVE"]).await,
+        RespValue::SimpleString("Background saving started".to_string())
+    );
+
+    let info = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let RespValue::BulkString(Some(info)) = client.command(&["INFO", "persistence"]).await
+            else {
+                panic!("INFO didn't reply with a bulk string");
+            };
+            let info = String::from_utf8(info.to_vec()).unwrap();
+            if info.contains("rdb_saves:1\r\n") {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the backup didn't finish");
+    assert!(info.contains("rdb_last_bgsave_status:ok\r\n"), "{}", info);
+
+    let backups: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(backups.len(), 1);
+    let data = std::fs::read(backups[0].as_ref().unwrap().path()).unwrap();
+    let snapshot = rudis::Snapshot::from_bytes(&data).unwrap();
+    assert_eq!(snapshot.entries[0].key, Bytes::from("k"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_crdt_peers_converge() {
+    let listeners = [
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let addrs: Vec<_> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap())
+        .collect();
+    let mut stores = Vec::new();
+    for (node, listener) in listeners.into_iter().enumerate() {
+        let peer = addrs[1 - node].to_string();
+        let server = Server::builder()
+            .config(move |config| {
+                config.crdt_peers = vec![peer];
+                config.crdt_node_id = node as u64 + 1;
+                config.crdt_secret = Some("edge".to_string());
+            })
+            .listener(listener);
+        stores.push(server.store().clone());
+        tokio::spawn(async move { server.run().await });
+    }
+    let converged = |key: &'static [u8], value: Option<&'static str>| {
+        let stores = stores.clone();
+        async move {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let (a, b) = (stores[0].get(key).await, stores[1].get(key).await);
+                    if a == b && a == value.map(Bytes::from) {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("{:?} didn't converge on {:?}", key, value));
+        }
+    };
+
+    let mut a = common::TestClient::connect(addrs[0]).await;
+    let mut b = common::TestClient::connect(addrs[1]).await;
+    assert_eq!(a.command(&["SET", "greeting", "hello"]).await, ok());
+    converged(b"greeting", Some("hello")).await;
+    assert_eq!(b.command(&["DEL", "greeting"]).await, int(1));
+    converged(b"greeting", None).await;
+
+    // Increments made on both sides all count
+    for _ in 0..2 {
+        a.command(&["INCR", "hits"]).await;
+    }
+    b.command(&["INCRBY", "hits", "3"]).await;
+    converged(b"hits", Some("5")).await;
+}
@@ -0,0 +1,278 @@
+//! Jepsen-style linearizability checks. Concurrent clients run SET, GET, INCR
+//! and EXPIRE against a few shared keys, recording when each command was
+//! sent and when its reply came back. Each key's history is then checked
+//! against a sequential model of one key with a Wing & Gong search: every
+//! reply must fit some single order of the commands that respects their
+//! real-time order, where a command that replied before another was sent
+//! comes first. Races in the keyspace backends show up as histories no such
+//! order explains.
+
+mod common;
+
+use common::TestServer;
+use rudis::client::Client;
+use rudis::{RespValue, Server};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const CLIENTS: u64 = 8;
+const OPS_PER_CLIENT: usize = 150;
+/// Few keys, so clients keep colliding on them
+const KEYS: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Set(i64),
+    Get,
+    Incr,
+    /// `EXPIRE key seconds`; 0 deletes the key, and the positive TTLs used
+    /// outlast the test
+    Expire(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Ok,
+    Value(Option<i64>),
+    Integer(i64),
+}
+
+/// One command as a client saw it: when it was sent and when the reply came,
+/// from the start of the run
+#[derive(Debug, Clone)]
+struct Event {
+    client: u64,
+    key: u64,
+    op: Op,
+    output: Output,
+    call: Duration,
+    ret: Duration,
+}
+
+/// What `op` replies on a key holding `value`, and what the key holds after
+fn step(value: Option<i64>, op: Op) -> (Option<i64>, Output) {
+    match op {
+        Op::Set(v) => (Some(v), Output::Ok),
+        Op::Get => (value, Output::Value(value)),
+        Op::Incr => {
+            let n = value.unwrap_or(0) + 1;
+            (Some(n), Output::Integer(n))
+        }
+        Op::Expire(seconds) if seconds <= 0 => (None, Output::Integer(value.is_some().into())),
+        Op::Expire(_) => (value, Output::Integer(value.is_some().into())),
+    }
+}
+
+/// Whether `history`, all on one initially missing key, is linearizable
+fn linearizable(history: &[Event]) -> bool {
+    search(
+        history,
+        &mut vec![false; history.len()],
+        None,
+        &mut HashSet::new(),
+    )
+}
+
+/// Try each command that could take effect next from `value`, given those in
+/// `done` already have; `seen` holds the dead ends found so far
+fn search(
+    history: &[Event],
+    done: &mut Vec<bool>,
+    value: Option<i64>,
+    seen: &mut HashSet<(Vec<bool>, Option<i64>)>,
+) -> bool {
+    // A command sent after some pending one replied can't go before it
+    let Some(deadline) = history
+        .iter()
+        .zip(done.iter())
+        .filter(|(_, done)| !**done)
+        .map(|(event, _)| event.ret)
+        .min()
+    else {
+        return true;
+    };
+    if !seen.insert((done.clone(), value)) {
+        return false;
+    }
+    for (i, event) in history.iter().enumerate() {
+        if done[i] || event.call > deadline {
+            continue;
+        }
+        let (next, output) = step(value, event.op);
+        if output != event.output {
+            continue;
+        }
+        done[i] = true;
+        if search(history, done, next, seen) {
+            return true;
+        }
+        done[i] = false;
+    }
+    false
+}
+
+/// xorshift64*, plenty for picking keys and commands
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    /// Uniform in `0..n`
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        ((self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) as u128 * n as u128) >> 64) as u64
+    }
+}
+
+fn output(reply: RespValue) -> Output {
+    match reply {
+        RespValue::SimpleString(s) if s == "OK" => Output::Ok,
+        RespValue::BulkString(None) => Output::Value(None),
+        RespValue::BulkString(Some(value)) => {
+            Output::Value(Some(std::str::from_utf8(&value).unwrap().parse().unwrap()))
+        }
+        RespValue::Integer(n) => Output::Integer(n),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+/// Run one client's share of the workload, returning what it saw
+async fn run_client(addr: SocketAddr, client: u64, start: Instant) -> Vec<Event> {
+    let mut connection = Client::connect(addr).await.unwrap();
+    let mut rng = Rng::new(client + 1);
+    let mut history = Vec::with_capacity(OPS_PER_CLIENT);
+    for _ in 0..OPS_PER_CLIENT {
+        let key = rng.below(KEYS);
+        let op = match rng.below(10) {
+            0..=2 => Op::Set(rng.below(100) as i64),
+            3..=5 => Op::Get,
+            6 | 7 => Op::Incr,
+            8 => Op::Expire(0),
+            _ => Op::Expire(60),
+        };
+        let name = format!("lin:{}", key);
+        let args = match op {
+            Op::Set(value) => vec!["SET".to_string(), name, value.to_string()],
+            Op::Get => vec!["GET".to_string(), name],
+            Op::Incr => vec!["INCR".to_string(), name],
+            Op::Expire(seconds) => vec!["EXPIRE".to_string(), name, seconds.to_string()],
+        };
+        let call = start.elapsed();
+        let reply = connection.command(&args).await.unwrap();
+        let ret = start.elapsed();
+        history.push(Event {
+            client,
+            key,
+            op,
+            output: output(reply),
+            call,
+            ret,
+        });
+    }
+    history
+}
+
+/// Run the workload against a server with `config` and check every key's
+/// history
+async fn check(config: &'static str) {
+    let server = TestServer::with(Server::builder().config(move |c| {
+        c.load_str(config).unwrap();
+    }))
+    .await;
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|client| tokio::spawn(run_client(server.addr(), client, start)))
+        .collect();
+    let mut history = Vec::new();
+    for client in clients {
+        history.extend(client.await.unwrap());
+    }
+
+    for key in 0..KEYS {
+        let mut events: Vec<Event> = history.iter().filter(|e| e.key == key).cloned().collect();
+        events.sort_by_key(|e| e.call);
+        assert!(
+            linearizable(&events),
+            "history of lin:{} under '{}' is not linearizable:\n{}",
+            key,
+            config.trim(),
+            events
+                .iter()
+                .map(|e| format!(
+                    "{:>10?} {:>10?} client {} {:?} -> {:?}",
+                    e.call, e.ret, e.client, e.op, e.output
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn sharded_keyspace_is_linearizable() {
+    check("keyspace-backend sharded\nkeyspace-shards 2\n").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn owned_keyspace_is_linearizable() {
+    check("keyspace-backend owned\nkeyspace-shards 2\n").await;
+}
+
+#[cfg(feature = "dashmap")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn dashmap_keyspace_is_linearizable() {
+    check("keyspace-backend dashmap\n").await;
+}
+
+#[test]
+fn checker_tells_linearizable_histories_apart() {
+    let event = |op, output, call: u64, ret: u64| Event {
+        client: 0,
+        key: 0,
+        op,
+        output,
+        call: Duration::from_millis(call),
+        ret: Duration::from_millis(ret),
+    };
+    let set = |value, call, ret| event(Op::Set(value), Output::Ok, call, ret);
+    let get = |value, call, ret| event(Op::Get, Output::Value(value), call, ret);
+
+    // A read of an overwritten value, after the overwrite replied
+    assert!(!linearizable(&[
+        set(1, 0, 1),
+        set(2, 2, 3),
+        get(Some(1), 4, 5)
+    ]));
+    // The same read, overlapping both writes
+    assert!(linearizable(&[
+        get(Some(1), 0, 5),
+        set(1, 1, 2),
+        set(2, 3, 4)
+    ]));
+    // Concurrent increments each count once
+    let incr = |n, call, ret| event(Op::Incr, Output::Integer(n), call, ret);
+    assert!(linearizable(&[
+        incr(2, 0, 3),
+        incr(1, 1, 2),
+        get(Some(2), 4, 5)
+    ]));
+    assert!(!linearizable(&[incr(1, 0, 3), incr(1, 1, 2)]));
+    // A deleted key reads as missing until written again
+    let delete = |existed, call, ret| event(Op::Expire(0), Output::Integer(existed), call, ret);
+    assert!(linearizable(&[
+        set(7, 0, 1),
+        delete(1, 2, 3),
+        get(None, 4, 5)
+    ]));
+    assert!(!linearizable(&[
+        set(7, 0, 1),
+        delete(1, 2, 3),
+        get(Some(7), 4, 5)
+    ]));
+}